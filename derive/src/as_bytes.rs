@@ -0,0 +1,43 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{spanned::Spanned, Data, DeriveInput, Error};
+
+use crate::has_repr_c;
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &input.ident;
+    if !has_repr_c(&input) {
+        return Err(Error::new(
+            ident.span(),
+            "AsBytes requires #[repr(C)] so there is no hidden reordering",
+        ));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "AsBytes cannot be derived for generic structs",
+        ));
+    }
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            ident.span(),
+            "AsBytes can only be derived for structs",
+        ));
+    };
+
+    let types: Vec<_> = data.fields.iter().map(|f| &f.ty).collect();
+    let message = format!("`{ident}` has padding bytes, add explicit padding fields");
+
+    Ok(quote! {
+        const _: () = assert!(
+            ::core::mem::size_of::<#ident>() == 0 #(+ ::core::mem::size_of::<#types>())*,
+            #message
+        );
+
+        unsafe impl ::vulkan_thing::layout::AsBytes for #ident
+        where
+            #(#types: ::vulkan_thing::layout::AsBytes,)*
+        {
+        }
+    })
+}
@@ -0,0 +1,118 @@
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{spanned::Spanned, Data, DeriveInput, Error, Fields};
+
+use crate::has_repr_c;
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let ident = &input.ident;
+    if !has_repr_c(&input) {
+        return Err(Error::new(
+            ident.span(),
+            "ShaderLayout requires #[repr(C)] so field offsets are stable",
+        ));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "ShaderLayout cannot be derived for generic structs",
+        ));
+    }
+
+    let mut rules = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("layout")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("std140") {
+                rules = Some(true);
+                Ok(())
+            } else if meta.path.is_ident("std430") {
+                rules = Some(false);
+                Ok(())
+            } else {
+                Err(meta.error("expected `std140` or `std430`"))
+            }
+        })?;
+    }
+    let Some(std140) = rules else {
+        return Err(Error::new(
+            ident.span(),
+            "ShaderLayout needs #[layout(std140)] or #[layout(std430)]",
+        ));
+    };
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            ident.span(),
+            "ShaderLayout can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new(
+            data.fields.span(),
+            "ShaderLayout requires named fields",
+        ));
+    };
+
+    let layout = quote! { ::vulkan_thing::layout };
+    let types: Vec<_> = fields.named.iter().map(|f| &f.ty).collect();
+    let fields_140 = quote! { &[#((<#types as #layout::ShaderLayout>::STD140_ALIGN, <#types as #layout::ShaderLayout>::STD140_SIZE)),*] };
+    let fields_430 = quote! { &[#((<#types as #layout::ShaderLayout>::STD430_ALIGN, <#types as #layout::ShaderLayout>::STD430_SIZE)),*] };
+
+    let (checked, rule_name) = if std140 {
+        (format_ident!("STD140_FIELDS"), "std140")
+    } else {
+        (format_ident!("STD430_FIELDS"), "std430")
+    };
+    let asserts = fields.named.iter().enumerate().map(|(i, field)| {
+        let name = field.ident.as_ref().expect("named field");
+        let message = format!("field `{name}` of `{ident}` is not at its {rule_name} offset");
+        quote! {
+            assert!(
+                ::core::mem::offset_of!(#ident, #name) == #layout::__private::field_offset(#checked, #i),
+                #message
+            );
+        }
+    });
+    let compatible = fields.named.iter().map(|field| {
+        let name = field.ident.as_ref().expect("named field");
+        let ty = &field.ty;
+        let message = format!(
+            "field `{name}` of `{ident}` is padded for std140, std430 blocks can't hold it"
+        );
+        quote! {
+            assert!(<#ty as #layout::ShaderLayout>::STD430_COMPATIBLE, #message);
+        }
+    });
+    let compatible = if std140 {
+        quote! {}
+    } else {
+        quote! { #(#compatible)* }
+    };
+    let fields_compatible = types
+        .iter()
+        .map(|ty| quote! { <#ty as #layout::ShaderLayout>::STD430_COMPATIBLE });
+    let size_message =
+        format!("size of `{ident}` does not match its {rule_name} size, add trailing padding");
+
+    Ok(quote! {
+        const _: () = {
+            const STD140_FIELDS: &[(usize, usize)] = #fields_140;
+            const STD430_FIELDS: &[(usize, usize)] = #fields_430;
+
+            impl #layout::ShaderLayout for #ident {
+                const STD140_ALIGN: usize = #layout::__private::struct_align(STD140_FIELDS, true);
+                const STD140_SIZE: usize = #layout::__private::struct_size(STD140_FIELDS, true);
+                const STD430_ALIGN: usize = #layout::__private::struct_align(STD430_FIELDS, false);
+                const STD430_SIZE: usize = #layout::__private::struct_size(STD430_FIELDS, false);
+                const STD430_COMPATIBLE: bool = true #(&& #fields_compatible)*;
+            }
+
+            #compatible
+            #(#asserts)*
+            assert!(
+                ::core::mem::size_of::<#ident>() == #layout::__private::struct_size(#checked, #std140),
+                #size_message
+            );
+        };
+    })
+}
@@ -0,0 +1,55 @@
+//! Derive macros for `vulkan-thing`
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput, Error};
+
+mod as_bytes;
+mod layout;
+mod vertex;
+
+/// Generates vertex binding and attribute descriptions for a `#[repr(C)]` struct
+///
+/// Locations are assigned in field order. Fields can override their format with
+/// `#[vertex(format = R8G8B8A8_UNORM)]` or be left out with `#[vertex(skip)]`, and
+/// `#[vertex(instance)]` on the struct makes it advance per instance.
+#[proc_macro_derive(VertexInput, attributes(vertex))]
+pub fn derive_vertex_input(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    vertex::expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `ShaderLayout` and asserts at compile time that every field sits at its GLSL offset
+///
+/// The struct must be `#[repr(C)]` and choose the rules it is checked against with
+/// `#[layout(std140)]` or `#[layout(std430)]`.
+#[proc_macro_derive(ShaderLayout, attributes(layout))]
+pub fn derive_shader_layout(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    layout::expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// Implements `AsBytes` for a `#[repr(C)]` struct, failing to compile if it has padding bytes
+#[proc_macro_derive(AsBytes)]
+pub fn derive_as_bytes(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    as_bytes::expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+pub(crate) fn has_repr_c(input: &DeriveInput) -> bool {
+    input.attrs.iter().any(|attr| {
+        let mut found = false;
+        if attr.path().is_ident("repr") {
+            let _ = attr.parse_nested_meta(|meta| {
+                found |= meta.path.is_ident("C");
+                Ok(())
+            });
+        }
+        found
+    })
+}
@@ -0,0 +1,120 @@
+use proc_macro2::TokenStream;
+use quote::{quote, quote_spanned};
+use syn::{spanned::Spanned, Data, DeriveInput, Error, Fields, Ident};
+
+use crate::has_repr_c;
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    if !has_repr_c(&input) {
+        return Err(Error::new(
+            input.ident.span(),
+            "VertexInput requires #[repr(C)] so field offsets are stable",
+        ));
+    }
+
+    let mut instance = false;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("vertex")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("instance") {
+                instance = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `instance`"))
+            }
+        })?;
+    }
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            input.ident.span(),
+            "VertexInput can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(Error::new(
+            data.fields.span(),
+            "VertexInput requires named fields",
+        ));
+    };
+
+    let mut pushes = Vec::new();
+    let mut locations = Vec::new();
+    for field in &fields.named {
+        let mut skip = false;
+        let mut format: Option<Ident> = None;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("vertex")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("format") {
+                    format = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `skip` or `format = ..`"))
+                }
+            })?;
+        }
+        if skip {
+            continue;
+        }
+
+        let name = field.ident.as_ref().expect("named field");
+        let ty = &field.ty;
+        let offset = quote! { ::core::mem::offset_of!(Self, #name) as u32 };
+        match format {
+            Some(format) => {
+                pushes.push(quote_spanned! {format.span()=>
+                    ::vulkan_thing::vertex::__private::push_attribute(
+                        &mut attributes,
+                        binding,
+                        &mut location,
+                        #offset,
+                        ::vulkan_thing::vertex::__private::vk::Format::#format,
+                    );
+                });
+                locations.push(quote! { 1 });
+            }
+            None => {
+                pushes.push(quote_spanned! {ty.span()=>
+                    ::vulkan_thing::vertex::__private::push_field::<#ty>(
+                        &mut attributes,
+                        binding,
+                        &mut location,
+                        #offset,
+                    );
+                });
+                locations.push(quote_spanned! {ty.span()=>
+                    <#ty as ::vulkan_thing::vertex::VertexFormat>::LOCATIONS
+                });
+            }
+        }
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let rate = if instance {
+        quote! { INSTANCE }
+    } else {
+        quote! { VERTEX }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::vulkan_thing::vertex::VertexInput for #ident #ty_generics #where_clause {
+            const INPUT_RATE: ::vulkan_thing::vertex::__private::vk::VertexInputRate =
+                ::vulkan_thing::vertex::__private::vk::VertexInputRate::#rate;
+            const LOCATIONS: u32 = 0 #(+ #locations)*;
+
+            #[allow(unused_mut, unused_variables)]
+            fn attributes(
+                binding: u32,
+                first_location: u32,
+            ) -> ::std::vec::Vec<::vulkan_thing::vertex::__private::vk::VertexInputAttributeDescription> {
+                let mut attributes = ::std::vec::Vec::new();
+                let mut location = first_location;
+                #(#pushes)*
+                attributes
+            }
+        }
+    })
+}
@@ -0,0 +1,291 @@
+//! Skeletal animation, sampling clips into a pose and turning it into joint matrices for skinning
+//!
+//! Clips are sampled on the CPU once per frame. The joint matrices are then uploaded with
+//! [`skinning::JointBuffer`] and read by the skinned variant of [`skinning::MESH_VERT_GLSL`], while
+//! morph target weights are blended into the vertices by the compute pass in [`morph`].
+
+pub mod controller;
+pub mod gltf;
+pub mod morph;
+pub mod skinning;
+
+use std::ops::{Add, Mul};
+
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::scene::Transform;
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: Option<String>,
+    /// Index into the skeleton's joints, `None` for a root
+    pub parent: Option<usize>,
+    /// Local transform when no clip animates the joint
+    pub rest: Transform,
+}
+
+/// Joint hierarchy of a skin and the matrices binding the mesh to it
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+    inverse_bind: Vec<Mat4>,
+    /// Transform above the root joints, from nodes that aren't part of the skin
+    pub root: Mat4,
+    /// Joints ordered so parents come before their children
+    order: Vec<usize>,
+}
+
+impl Skeleton {
+    /// `inverse_bind` has one matrix per joint, missing ones are identity
+    pub fn new(joints: Vec<Joint>, mut inverse_bind: Vec<Mat4>) -> Self {
+        inverse_bind.resize(joints.len(), Mat4::IDENTITY);
+        let mut order = Vec::with_capacity(joints.len());
+        let mut placed = vec![false; joints.len()];
+        while order.len() < joints.len() {
+            let before = order.len();
+            for (index, joint) in joints.iter().enumerate() {
+                if !placed[index] && joint.parent.is_none_or(|parent| placed[parent]) {
+                    placed[index] = true;
+                    order.push(index);
+                }
+            }
+            assert!(order.len() > before, "joint hierarchy has a cycle");
+        }
+        Skeleton {
+            joints,
+            inverse_bind,
+            root: Mat4::IDENTITY,
+            order,
+        }
+    }
+
+    pub fn with_root(mut self, root: Mat4) -> Self {
+        self.root = root;
+        self
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        &self.joints
+    }
+
+    pub fn inverse_bind(&self) -> &[Mat4] {
+        &self.inverse_bind
+    }
+
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.joints
+            .iter()
+            .position(|joint| joint.name.as_deref() == Some(name))
+    }
+
+    pub fn rest_pose(&self) -> Pose {
+        Pose {
+            locals: self.joints.iter().map(|joint| joint.rest).collect(),
+        }
+    }
+
+    /// World space transform of every joint in `pose`
+    pub fn world_transforms(&self, pose: &Pose, out: &mut Vec<Mat4>) {
+        out.clear();
+        out.resize(self.joints.len(), Mat4::IDENTITY);
+        for &index in &self.order {
+            let parent = match self.joints[index].parent {
+                Some(parent) => out[parent],
+                None => self.root,
+            };
+            out[index] = parent * pose.locals[index].matrix();
+        }
+    }
+
+    /// Matrices taking bind pose vertices to `pose` in world space, in joint order
+    pub fn joint_matrices(&self, pose: &Pose, out: &mut Vec<Mat4>) {
+        self.world_transforms(pose, out);
+        for (matrix, inverse_bind) in out.iter_mut().zip(&self.inverse_bind) {
+            *matrix *= *inverse_bind;
+        }
+    }
+}
+
+/// Local transform of every joint of a skeleton
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pose {
+    pub locals: Vec<Transform>,
+}
+
+impl Pose {
+    /// Moves every joint `weight` of the way towards `other`
+    pub fn blend(&mut self, other: &Pose, weight: f32) {
+        for (local, other) in self.locals.iter_mut().zip(&other.locals) {
+            local.translation = local.translation.lerp(other.translation, weight);
+            local.rotation = local.rotation.slerp(other.rotation, weight);
+            local.scale = local.scale.lerp(other.scale, weight);
+        }
+    }
+
+    /// Layers the difference between `additive` and `reference` on top, scaled by `weight`
+    pub fn add(&mut self, additive: &Pose, reference: &Pose, weight: f32) {
+        let joints = self
+            .locals
+            .iter_mut()
+            .zip(&additive.locals)
+            .zip(&reference.locals);
+        for ((local, additive), reference) in joints {
+            local.translation += (additive.translation - reference.translation) * weight;
+            let rotation = additive.rotation * reference.rotation.inverse();
+            local.rotation = (Quat::IDENTITY.slerp(rotation, weight) * local.rotation).normalize();
+            let scale = additive.scale / reference.scale;
+            local.scale *= Vec3::ONE.lerp(scale, weight);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    /// Hermite spline, keyframes stored as in tangent, value and out tangent triples
+    CubicSpline,
+}
+
+#[derive(Debug, Clone)]
+pub enum Keyframes {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Keyframes for one property of one joint
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub joint: usize,
+    pub interpolation: Interpolation,
+    /// Seconds, ascending
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+impl Channel {
+    /// Writes the channel's value at `time` into the joint's transform
+    pub fn sample(&self, time: f32, pose: &mut Pose) {
+        let Some(local) = pose.locals.get_mut(self.joint) else {
+            return;
+        };
+        if self.times.is_empty() {
+            return;
+        }
+        let (interpolation, times) = (self.interpolation, self.times.as_slice());
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                local.translation =
+                    interpolate(interpolation, times, time, |i| values[i], Vec3::lerp);
+            }
+            Keyframes::Rotation(values) => {
+                local.rotation = match interpolation {
+                    // Tangents aren't unit quaternions, so the spline runs on plain vectors
+                    Interpolation::CubicSpline => {
+                        let at = |i: usize| Vec4::from(values[i]);
+                        Quat::from_vec4(interpolate(interpolation, times, time, at, Vec4::lerp))
+                            .normalize()
+                    }
+                    _ => interpolate(interpolation, times, time, |i| values[i], Quat::slerp),
+                };
+            }
+            Keyframes::Scale(values) => {
+                local.scale = interpolate(interpolation, times, time, |i| values[i], Vec3::lerp);
+            }
+        }
+    }
+}
+
+/// Value of a keyframed property at `time`, `at` returning the i-th stored value
+///
+/// Cubic spline keyframes store three values each, in tangent, value and out tangent.
+fn interpolate<T>(
+    interpolation: Interpolation,
+    times: &[f32],
+    time: f32,
+    at: impl Fn(usize) -> T,
+    lerp: impl Fn(T, T, f32) -> T,
+) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let (key, t) = segment(times, time);
+    let next = (key + 1).min(times.len() - 1);
+    match interpolation {
+        Interpolation::Step => at(key),
+        Interpolation::Linear => lerp(at(key), at(next), t),
+        Interpolation::CubicSpline => {
+            let value = |k: usize| at(k * 3 + 1);
+            if key == next {
+                return value(key);
+            }
+            let dt = times[next] - times[key];
+            let out_tangent = at(key * 3 + 2) * dt;
+            let in_tangent = at(next * 3) * dt;
+            let t2 = t * t;
+            let t3 = t2 * t;
+            value(key) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                + out_tangent * (t3 - 2.0 * t2 + t)
+                + value(next) * (3.0 * t2 - 2.0 * t3)
+                + in_tangent * (t3 - t2)
+        }
+    }
+}
+
+/// Keyframe at or before `time` and how far it is towards the next one
+fn segment(times: &[f32], time: f32) -> (usize, f32) {
+    let next = times.partition_point(|&t| t <= time);
+    if next == 0 {
+        return (0, 0.0);
+    }
+    if next == times.len() {
+        return (times.len() - 1, 0.0);
+    }
+    let (start, end) = (times[next - 1], times[next]);
+    let t = if end > start {
+        (time - start) / (end - start)
+    } else {
+        0.0
+    };
+    (next - 1, t)
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    /// Seconds, the time of the last keyframe
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+impl AnimationClip {
+    pub fn new(name: Option<String>, channels: Vec<Channel>) -> Self {
+        let duration = channels
+            .iter()
+            .filter_map(|channel| channel.times.last().copied())
+            .fold(0.0, f32::max);
+        AnimationClip {
+            name,
+            duration,
+            channels,
+        }
+    }
+
+    /// Poses every animated joint at `time`, clamped to the clip, leaving the rest untouched
+    pub fn sample(&self, time: f32, pose: &mut Pose) {
+        for channel in &self.channels {
+            channel.sample(time, pose);
+        }
+    }
+
+    /// [`AnimationClip::sample`] with `time` wrapped around the clip's duration
+    pub fn sample_looped(&self, time: f32, pose: &mut Pose) {
+        let time = if self.duration > 0.0 {
+            time.rem_euclid(self.duration)
+        } else {
+            0.0
+        };
+        self.sample(time, pose);
+    }
+}
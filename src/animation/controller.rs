@@ -0,0 +1,262 @@
+//! States playing clips, cross-faded by transitions that fire on parameter values
+//!
+//! A controller only holds clip indices, the clips themselves stay with the skin they came from
+//! and are passed to [`AnimationController::evaluate`]. Additive layers go on top of the state
+//! machine's result, for things like breathing or aiming that play over any locomotion.
+
+use std::collections::HashMap;
+
+use super::{AnimationClip, Pose, Skeleton};
+
+/// State with this index is entered when the controller is created
+pub const ENTRY_STATE: usize = 0;
+
+#[derive(Debug, Clone)]
+pub struct AnimationState {
+    pub name: String,
+    /// Index into the clips passed to [`AnimationController::evaluate`]
+    pub clip: usize,
+    pub speed: f32,
+    pub looping: bool,
+}
+
+impl AnimationState {
+    pub fn new(name: impl Into<String>, clip: usize) -> Self {
+        AnimationState {
+            name: name.into(),
+            clip,
+            speed: 1.0,
+            looping: true,
+        }
+    }
+
+    pub fn with_speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn once(mut self) -> Self {
+        self.looping = false;
+        self
+    }
+}
+
+/// Test against a named parameter, parameters never set read as zero
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Greater(String, f32),
+    Less(String, f32),
+}
+
+impl Condition {
+    pub fn greater(param: impl Into<String>, value: f32) -> Self {
+        Condition::Greater(param.into(), value)
+    }
+
+    pub fn less(param: impl Into<String>, value: f32) -> Self {
+        Condition::Less(param.into(), value)
+    }
+
+    fn holds(&self, params: &HashMap<String, f32>) -> bool {
+        let param = |name: &String| params.get(name).copied().unwrap_or(0.0);
+        match self {
+            Condition::Greater(name, value) => param(name) > *value,
+            Condition::Less(name, value) => param(name) < *value,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    /// `None` to leave from any state
+    pub from: Option<usize>,
+    pub to: usize,
+    /// All of them have to hold
+    pub conditions: Vec<Condition>,
+    /// Seconds of cross-fade
+    pub duration: f32,
+}
+
+impl Transition {
+    pub fn new(from: usize, to: usize, duration: f32) -> Self {
+        Transition {
+            from: Some(from),
+            to,
+            conditions: Vec::new(),
+            duration,
+        }
+    }
+
+    pub fn from_any(to: usize, duration: f32) -> Self {
+        Transition {
+            from: None,
+            to,
+            conditions: Vec::new(),
+            duration,
+        }
+    }
+
+    pub fn when(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+}
+
+/// A clip added on top of the state machine, relative to its own first frame
+#[derive(Debug, Clone)]
+pub struct AdditiveLayer {
+    pub clip: usize,
+    pub weight: f32,
+    pub time: f32,
+}
+
+/// The state being faded out
+#[derive(Debug, Clone, Copy)]
+struct Fade {
+    state: usize,
+    time: f32,
+    elapsed: f32,
+    duration: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationController {
+    states: Vec<AnimationState>,
+    transitions: Vec<Transition>,
+    params: HashMap<String, f32>,
+    pub layers: Vec<AdditiveLayer>,
+    current: usize,
+    time: f32,
+    fade: Option<Fade>,
+}
+
+impl AnimationController {
+    /// Starts in the first of `states`, which must not be empty
+    pub fn new(states: Vec<AnimationState>, transitions: Vec<Transition>) -> Self {
+        assert!(!states.is_empty(), "a controller needs at least one state");
+        AnimationController {
+            states,
+            transitions,
+            params: HashMap::new(),
+            layers: Vec::new(),
+            current: ENTRY_STATE,
+            time: 0.0,
+            fade: None,
+        }
+    }
+
+    /// Adds a layer playing `clip` at `weight`, returning its index in [`Self::layers`]
+    pub fn add_layer(&mut self, clip: usize, weight: f32) -> usize {
+        self.layers.push(AdditiveLayer {
+            clip,
+            weight,
+            time: 0.0,
+        });
+        self.layers.len() - 1
+    }
+
+    pub fn set_param(&mut self, name: impl Into<String>, value: f32) {
+        self.params.insert(name.into(), value);
+    }
+
+    pub fn param(&self, name: &str) -> f32 {
+        self.params.get(name).copied().unwrap_or(0.0)
+    }
+
+    pub fn states(&self) -> &[AnimationState] {
+        &self.states
+    }
+
+    pub fn current_state(&self) -> &AnimationState {
+        &self.states[self.current]
+    }
+
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// Jumps to a state, fading from the current one over `duration` seconds
+    pub fn play(&mut self, state: usize, duration: f32) {
+        if state == self.current {
+            return;
+        }
+        self.fade = (duration > 0.0).then_some(Fade {
+            state: self.current,
+            time: self.time,
+            elapsed: 0.0,
+            duration,
+        });
+        self.current = state;
+        self.time = 0.0;
+    }
+
+    /// Advances time by `dt` seconds and takes the first transition whose conditions hold
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt * self.states[self.current].speed;
+        if let Some(fade) = &mut self.fade {
+            fade.time += dt * self.states[fade.state].speed;
+            fade.elapsed += dt;
+            if fade.elapsed >= fade.duration {
+                self.fade = None;
+            }
+        }
+        for layer in &mut self.layers {
+            layer.time += dt;
+        }
+
+        let current = self.current;
+        let next = self.transitions.iter().find(|transition| {
+            transition.to != current
+                && transition.from.is_none_or(|from| from == current)
+                && transition.conditions.iter().all(|c| c.holds(&self.params))
+        });
+        if let Some(transition) = next {
+            let (to, duration) = (transition.to, transition.duration);
+            self.play(to, duration);
+        }
+    }
+
+    /// Blended pose of the current state, the one fading out and the additive layers
+    ///
+    /// States or layers naming a clip past the end of `clips` are skipped.
+    pub fn evaluate(&self, skeleton: &Skeleton, clips: &[AnimationClip], pose: &mut Pose) {
+        *pose = self.state_pose(skeleton, clips, self.current, self.time);
+        if let Some(fade) = self.fade {
+            let from = self.state_pose(skeleton, clips, fade.state, fade.time);
+            let weight = (fade.elapsed / fade.duration).clamp(0.0, 1.0);
+            let mut blended = from;
+            blended.blend(pose, weight);
+            *pose = blended;
+        }
+
+        for layer in &self.layers {
+            let Some(clip) = clips.get(layer.clip) else {
+                continue;
+            };
+            let mut reference = skeleton.rest_pose();
+            clip.sample(0.0, &mut reference);
+            let mut additive = reference.clone();
+            clip.sample_looped(layer.time, &mut additive);
+            pose.add(&additive, &reference, layer.weight);
+        }
+    }
+
+    fn state_pose(
+        &self,
+        skeleton: &Skeleton,
+        clips: &[AnimationClip],
+        state: usize,
+        time: f32,
+    ) -> Pose {
+        let mut pose = skeleton.rest_pose();
+        let state = &self.states[state];
+        if let Some(clip) = clips.get(state.clip) {
+            if state.looping {
+                clip.sample_looped(time, &mut pose);
+            } else {
+                clip.sample(time, &mut pose);
+            }
+        }
+        pose
+    }
+}
@@ -0,0 +1,332 @@
+//! Importing skins, animation clips, skinned meshes and morph targets from glTF and GLB files
+//!
+//! Buffers are read from the GLB blob or from files next to the glTF. Clips are split per skin,
+//! keeping only the channels that animate that skin's joints, and morph weight channels go to the
+//! meshes of the node they target.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use glam::{Mat4, Quat, UVec4, Vec2, Vec3, Vec4};
+use gltf::{animation::util::ReadOutputs, buffer::Source, Document};
+use thiserror::Error;
+
+use crate::scene::Transform;
+
+use super::{
+    morph::{MorphClip, MorphTargets},
+    skinning::SkinnedVertex,
+    AnimationClip, Channel, Interpolation, Joint, Keyframes, Skeleton,
+};
+
+#[derive(Debug, Error)]
+pub enum GltfError {
+    #[error(transparent)]
+    Gltf(#[from] gltf::Error),
+    #[error("failed to read {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("buffer {0} is embedded as a data URI, which isn't supported")]
+    DataUri(usize),
+    #[error("buffer {0} refers to a GLB blob the file doesn't have")]
+    MissingBlob(usize),
+    #[error("primitive of mesh {mesh} has no {attribute}")]
+    MissingAttribute {
+        mesh: usize,
+        attribute: &'static str,
+    },
+}
+
+/// A skin's joints and the clips that move them
+#[derive(Debug, Clone)]
+pub struct Skin {
+    pub name: Option<String>,
+    pub skeleton: Skeleton,
+    pub clips: Vec<AnimationClip>,
+}
+
+/// Triangles of one primitive, with joint indices into [`SkinnedMesh::skin`]'s skeleton
+#[derive(Debug, Clone)]
+pub struct SkinnedMesh {
+    pub name: Option<String>,
+    /// Index into [`GltfAnimations::skins`], `None` for meshes drawn without skinning
+    pub skin: Option<usize>,
+    pub vertices: Vec<SkinnedVertex>,
+    pub indices: Vec<u32>,
+    /// Empty for meshes without blend shapes
+    pub targets: MorphTargets,
+    /// Target weights when no clip animates them
+    pub weights: Vec<f32>,
+    pub morph_clips: Vec<MorphClip>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GltfAnimations {
+    pub skins: Vec<Skin>,
+    pub meshes: Vec<SkinnedMesh>,
+}
+
+impl GltfAnimations {
+    /// Loads a `.gltf` or `.glb`, external buffers resolved relative to it
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, GltfError> {
+        let path = path.as_ref();
+        let bytes = read(path)?;
+        GltfAnimations::from_slice(&bytes, path.parent())
+    }
+
+    /// Parses a glTF or GLB in memory, `base` being where external buffers are looked up
+    pub fn from_slice(bytes: &[u8], base: Option<&Path>) -> Result<Self, GltfError> {
+        let gltf::Gltf { document, mut blob } = gltf::Gltf::from_slice(bytes)?;
+        let buffers = load_buffers(&document, &mut blob, base)?;
+        let buffer = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(Vec::as_slice);
+
+        let parents = parents(&document);
+        let mut skins = Vec::new();
+        // Node index to (skin, joint) for every joint of every skin
+        let mut joint_nodes: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+        for skin in document.skins() {
+            let nodes: Vec<usize> = skin.joints().map(|node| node.index()).collect();
+            for (joint, &node) in nodes.iter().enumerate() {
+                joint_nodes
+                    .entry(node)
+                    .or_default()
+                    .push((skin.index(), joint));
+            }
+            let joints: Vec<Joint> = skin
+                .joints()
+                .map(|node| Joint {
+                    name: node.name().map(str::to_owned),
+                    parent: ancestors(&parents, node.index())
+                        .find_map(|ancestor| nodes.iter().position(|&n| n == ancestor)),
+                    rest: transform(&node),
+                })
+                .collect();
+            let inverse_bind = skin
+                .reader(buffer)
+                .read_inverse_bind_matrices()
+                .map(|matrices| matrices.map(|m| Mat4::from_cols_array_2d(&m)).collect())
+                .unwrap_or_default();
+            // Nodes above the root joint still move the whole skeleton
+            let root = joints
+                .iter()
+                .position(|joint| joint.parent.is_none())
+                .and_then(|joint| parents[nodes[joint]])
+                .map_or(Mat4::IDENTITY, |parent| world(&document, &parents, parent));
+            skins.push(Skin {
+                name: skin.name().map(str::to_owned),
+                skeleton: Skeleton::new(joints, inverse_bind).with_root(root),
+                clips: Vec::new(),
+            });
+        }
+
+        let mut meshes = Vec::new();
+        // Node index to the primitives imported from its mesh
+        let mut mesh_nodes: HashMap<usize, Vec<usize>> = HashMap::new();
+        for node in document.nodes() {
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            let skin = node.skin().map(|skin| skin.index());
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(buffer);
+                let missing = |attribute| GltfError::MissingAttribute {
+                    mesh: mesh.index(),
+                    attribute,
+                };
+                let positions: Vec<Vec3> = reader
+                    .read_positions()
+                    .ok_or_else(|| missing("POSITION"))?
+                    .map(Vec3::from)
+                    .collect();
+                let count = positions.len();
+                let normals: Vec<Vec3> = match reader.read_normals() {
+                    Some(normals) => normals.map(Vec3::from).collect(),
+                    None => vec![Vec3::Y; count],
+                };
+                let uvs: Vec<Vec2> = match reader.read_tex_coords(0) {
+                    Some(uvs) => uvs.into_f32().map(Vec2::from).collect(),
+                    None => vec![Vec2::ZERO; count],
+                };
+                let (joints, weights): (Vec<UVec4>, Vec<Vec4>) = match skin {
+                    Some(_) => (
+                        reader
+                            .read_joints(0)
+                            .ok_or_else(|| missing("JOINTS_0"))?
+                            .into_u16()
+                            .map(|j| UVec4::from_array(j.map(u32::from)))
+                            .collect(),
+                        reader
+                            .read_weights(0)
+                            .ok_or_else(|| missing("WEIGHTS_0"))?
+                            .into_f32()
+                            .map(Vec4::from)
+                            .collect(),
+                    ),
+                    None => (vec![UVec4::ZERO; count], vec![Vec4::X; count]),
+                };
+                let vertices = (0..count)
+                    .map(|i| SkinnedVertex {
+                        position: positions[i],
+                        normal: normals[i],
+                        uv: uvs[i],
+                        joints: joints[i],
+                        weights: weights[i],
+                    })
+                    .collect();
+                let mut targets = MorphTargets::default();
+                for (positions, normals, _) in reader.read_morph_targets() {
+                    let positions = match positions {
+                        Some(positions) => positions.map(Vec3::from).collect(),
+                        None => vec![Vec3::ZERO; count],
+                    };
+                    targets.positions.push(positions);
+                    targets
+                        .normals
+                        .push(normals.map_or_else(Vec::new, |n| n.map(Vec3::from).collect()));
+                }
+                let weights = match mesh.weights() {
+                    Some(weights) => weights.to_vec(),
+                    None => vec![0.0; targets.len()],
+                };
+                let indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..count as u32).collect(),
+                };
+                mesh_nodes
+                    .entry(node.index())
+                    .or_default()
+                    .push(meshes.len());
+                meshes.push(SkinnedMesh {
+                    name: mesh.name().map(str::to_owned),
+                    skin,
+                    vertices,
+                    indices,
+                    targets,
+                    weights,
+                    morph_clips: Vec::new(),
+                });
+            }
+        }
+
+        for animation in document.animations() {
+            let mut channels: Vec<Vec<Channel>> = vec![Vec::new(); skins.len()];
+            for channel in animation.channels() {
+                let node = channel.target().node().index();
+                let reader = channel.reader(buffer);
+                let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs())
+                else {
+                    continue;
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => Interpolation::Step,
+                    gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                    gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+                };
+                let times: Vec<f32> = inputs.collect();
+                let keyframes = match outputs {
+                    ReadOutputs::Translations(values) => {
+                        Keyframes::Translation(values.map(Vec3::from).collect())
+                    }
+                    ReadOutputs::Rotations(values) => {
+                        Keyframes::Rotation(values.into_f32().map(Quat::from_array).collect())
+                    }
+                    ReadOutputs::Scales(values) => {
+                        Keyframes::Scale(values.map(Vec3::from).collect())
+                    }
+                    ReadOutputs::MorphTargetWeights(weights) => {
+                        let weights: Vec<f32> = weights.into_f32().collect();
+                        for &mesh in mesh_nodes.get(&node).into_iter().flatten() {
+                            meshes[mesh].morph_clips.push(MorphClip::new(
+                                animation.name().map(str::to_owned),
+                                interpolation,
+                                times.clone(),
+                                weights.clone(),
+                            ));
+                        }
+                        continue;
+                    }
+                };
+                let Some(targets) = joint_nodes.get(&node) else {
+                    continue;
+                };
+                for &(skin, joint) in targets {
+                    channels[skin].push(Channel {
+                        joint,
+                        interpolation,
+                        times: times.clone(),
+                        keyframes: keyframes.clone(),
+                    });
+                }
+            }
+            for (skin, channels) in skins.iter_mut().zip(channels) {
+                if !channels.is_empty() {
+                    let name = animation.name().map(str::to_owned);
+                    skin.clips.push(AnimationClip::new(name, channels));
+                }
+            }
+        }
+
+        Ok(GltfAnimations { skins, meshes })
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, GltfError> {
+    fs::read(path).map_err(|source| GltfError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+fn load_buffers(
+    document: &Document,
+    blob: &mut Option<Vec<u8>>,
+    base: Option<&Path>,
+) -> Result<Vec<Vec<u8>>, GltfError> {
+    document
+        .buffers()
+        .map(|buffer| match buffer.source() {
+            Source::Bin => blob.take().ok_or(GltfError::MissingBlob(buffer.index())),
+            Source::Uri(uri) if uri.starts_with("data:") => Err(GltfError::DataUri(buffer.index())),
+            Source::Uri(uri) => read(&base.unwrap_or(Path::new(".")).join(uri)),
+        })
+        .collect()
+}
+
+/// Parent of every node, `None` for scene roots
+fn parents(document: &Document) -> Vec<Option<usize>> {
+    let mut parents = vec![None; document.nodes().len()];
+    for node in document.nodes() {
+        for child in node.children() {
+            parents[child.index()] = Some(node.index());
+        }
+    }
+    parents
+}
+
+fn ancestors(parents: &[Option<usize>], node: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(parents[node], |&node| parents[node])
+}
+
+fn transform(node: &gltf::Node) -> Transform {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    Transform {
+        translation: translation.into(),
+        rotation: Quat::from_array(rotation),
+        scale: scale.into(),
+    }
+}
+
+fn world(document: &Document, parents: &[Option<usize>], node: usize) -> Mat4 {
+    std::iter::once(node)
+        .chain(ancestors(parents, node))
+        .map(|index| {
+            let node = document
+                .nodes()
+                .nth(index)
+                .expect("parents are valid nodes");
+            transform(&node).matrix()
+        })
+        .fold(Mat4::IDENTITY, |world, local| local * world)
+}
@@ -0,0 +1,526 @@
+//! Morph targets, blending per-vertex position and normal offsets by animated weights
+//!
+//! Blending happens in a compute pre-pass writing a morphed copy of the vertex buffer, so the
+//! result draws with the ordinary mesh pipelines and can still be skinned afterwards.
+
+use std::error::Error;
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::Vec3;
+use thiserror::Error;
+
+use crate::{
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{staging::StagingBelt, usage::MemoryCategory, Buffer},
+    mesh::upload_buffer,
+    shader::{reflect, ShaderCompiler},
+};
+
+use super::{interpolate, Interpolation};
+
+/// Targets blended per dispatch, the ones with the largest weights win
+pub const MAX_ACTIVE_TARGETS: usize = 12;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Adds weighted deltas to the position and normal at the start of each vertex
+///
+/// Vertices are read as plain floats, so any layout starting with a `vec3` position and a `vec3`
+/// normal works, like [`super::skinning::MeshVertex`] and [`super::skinning::SkinnedVertex`].
+pub const MORPH_COMP_GLSL: &str = r#"#version 450
+layout(local_size_x = 64) in;
+
+layout(set = 0, binding = 0) readonly buffer Base {
+    float data[];
+} base;
+
+layout(set = 0, binding = 1) readonly buffer Deltas {
+    float data[];
+} deltas;
+
+layout(set = 0, binding = 2) buffer Morphed {
+    float data[];
+} morphed;
+
+layout(push_constant) uniform Push {
+    uint vertex_count;
+    // Floats per vertex
+    uint stride;
+    uint active;
+    uint padding;
+    uvec4 targets[3];
+    vec4 weights[3];
+} push;
+
+void main() {
+    uint vertex = gl_GlobalInvocationID.x;
+    if (vertex >= push.vertex_count) {
+        return;
+    }
+    uint first = vertex * push.stride;
+    for (uint i = 0u; i < push.stride; i++) {
+        morphed.data[first + i] = base.data[first + i];
+    }
+    for (uint i = 0u; i < push.active; i++) {
+        uint target = push.targets[i / 4u][i % 4u];
+        float weight = push.weights[i / 4u][i % 4u];
+        uint delta = (target * push.vertex_count + vertex) * 6u;
+        for (uint k = 0u; k < 6u; k++) {
+            morphed.data[first + k] += weight * deltas.data[delta + k];
+        }
+    }
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum MorphError {
+    #[error("failed to compile morph shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Offsets from the base mesh for each target, missing normals are zero
+#[derive(Debug, Clone, Default)]
+pub struct MorphTargets {
+    pub positions: Vec<Vec<Vec3>>,
+    pub normals: Vec<Vec<Vec3>>,
+}
+
+impl MorphTargets {
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Target major, position then normal delta per vertex, as the shader reads them
+    fn packed(&self, vertex_count: usize) -> Vec<f32> {
+        let mut packed = Vec::with_capacity(self.len() * vertex_count * 6);
+        for (target, positions) in self.positions.iter().enumerate() {
+            let normals = self.normals.get(target);
+            for vertex in 0..vertex_count {
+                let position = positions.get(vertex).copied().unwrap_or(Vec3::ZERO);
+                let normal = normals
+                    .and_then(|normals| normals.get(vertex))
+                    .copied()
+                    .unwrap_or(Vec3::ZERO);
+                packed.extend(position.to_array());
+                packed.extend(normal.to_array());
+            }
+        }
+        packed
+    }
+}
+
+/// Keyframed weights for every target of a mesh
+#[derive(Debug, Clone)]
+pub struct MorphClip {
+    pub name: Option<String>,
+    pub duration: f32,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    /// One weight per target for each keyframe, three for cubic splines
+    pub weights: Vec<f32>,
+}
+
+impl MorphClip {
+    pub fn new(
+        name: Option<String>,
+        interpolation: Interpolation,
+        times: Vec<f32>,
+        weights: Vec<f32>,
+    ) -> Self {
+        MorphClip {
+            name,
+            duration: times.last().copied().unwrap_or(0.0),
+            interpolation,
+            times,
+            weights,
+        }
+    }
+
+    /// Writes the weight of every target at `time`, clamped to the clip
+    pub fn sample(&self, time: f32, out: &mut [f32]) {
+        let stored = match self.interpolation {
+            Interpolation::CubicSpline => self.times.len() * 3,
+            Interpolation::Step | Interpolation::Linear => self.times.len(),
+        };
+        if stored == 0 {
+            return;
+        }
+        let targets = self.weights.len() / stored;
+        for (target, weight) in out.iter_mut().enumerate().take(targets) {
+            let at = |i: usize| self.weights[i * targets + target];
+            *weight = interpolate(self.interpolation, &self.times, time, at, |a, b, t| {
+                a + (b - a) * t
+            });
+        }
+    }
+
+    pub fn sample_looped(&self, time: f32, out: &mut [f32]) {
+        let time = if self.duration > 0.0 {
+            time.rem_euclid(self.duration)
+        } else {
+            0.0
+        };
+        self.sample(time, out);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct MorphPush {
+    vertex_count: u32,
+    stride: u32,
+    active: u32,
+    padding: u32,
+    targets: [u32; MAX_ACTIVE_TARGETS],
+    weights: [f32; MAX_ACTIVE_TARGETS],
+}
+
+/// The blend pipeline, shared by every [`MorphMesh`]
+pub struct MorphPipeline {
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl MorphPipeline {
+    pub fn new<C: ShaderCompiler>(device: &Device, compiler: &C) -> Result<Self, MorphError> {
+        let code = compiler
+            .compile(MORPH_COMP_GLSL, vk::ShaderStageFlags::COMPUTE)
+            .map_err(|err| MorphError::Compile(Box::new(err)))?;
+
+        let bindings: Vec<_> = (0..3)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            })
+            .collect();
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<MorphPush>() as u32,
+        };
+        reflect::debug_validate(&[&code], &[&bindings], &[push_range], &[])?;
+
+        let mut this = MorphPipeline {
+            set_layout: vk::DescriptorSetLayout::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+        };
+        let result = unsafe {
+            (|| {
+                this.set_layout = device.create_descriptor_set_layout(
+                    &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                    None,
+                )?;
+                leaks::track(this.set_layout, "morph descriptor set layout");
+                this.pipeline_layout = crate::pipeline::create_pipeline_layout(
+                    device,
+                    &vk::PipelineLayoutCreateInfo::builder()
+                        .set_layouts(&[this.set_layout])
+                        .push_constant_ranges(&[push_range]),
+                )?;
+                leaks::track(this.pipeline_layout, "morph pipeline layout");
+                let module = device.create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(&code),
+                    None,
+                )?;
+                leaks::track(module, "morph shader module");
+                let stage = vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(vk::ShaderStageFlags::COMPUTE)
+                    .module(module)
+                    .name(c"main");
+                let info = vk::ComputePipelineCreateInfo::builder()
+                    .stage(*stage)
+                    .layout(this.pipeline_layout);
+                let pipelines =
+                    crate::pipeline::create_compute_pipelines(device, &[*info], "morph targets");
+                leaks::untrack(module);
+                device.destroy_shader_module(module, None);
+                this.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+                VkResult::Ok(())
+            })()
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Records the blend of `mesh` with `weights`, one per target, into `frame`'s output and a
+    /// barrier making it readable as vertex input
+    ///
+    /// Must be recorded outside a render pass.
+    pub fn record(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        mesh: &MorphMesh,
+        frame: usize,
+        weights: &[f32],
+    ) {
+        let mut push = MorphPush {
+            vertex_count: mesh.vertex_count,
+            stride: mesh.stride,
+            active: 0,
+            padding: 0,
+            targets: [0; MAX_ACTIVE_TARGETS],
+            weights: [0.0; MAX_ACTIVE_TARGETS],
+        };
+        let mut active: Vec<(usize, f32)> = weights
+            .iter()
+            .copied()
+            .enumerate()
+            .take(mesh.target_count as usize)
+            .filter(|(_, weight)| *weight != 0.0)
+            .collect();
+        active.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+        active.truncate(MAX_ACTIVE_TARGETS);
+        for (slot, (target, weight)) in active.iter().enumerate() {
+            push.targets[slot] = *target as u32;
+            push.weights[slot] = *weight;
+        }
+        push.active = active.len() as u32;
+
+        let output = mesh.outputs[frame].buffer;
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(output)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[mesh.sets[frame]],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_dispatch(cmd, mesh.vertex_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded blend may still be executing.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Base vertices and target deltas of one mesh, with a blended copy per frame in flight
+pub struct MorphMesh {
+    base: Buffer,
+    deltas: Buffer,
+    outputs: Vec<Buffer>,
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    vertex_count: u32,
+    stride: u32,
+    target_count: u32,
+}
+
+impl MorphMesh {
+    /// Creates the buffers and records the uploads into `cmd`, usable once it has executed
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload<V: AsBytes>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        pipeline: &MorphPipeline,
+        vertices: &[V],
+        targets: &MorphTargets,
+        frames_in_flight: usize,
+    ) -> VkResult<Self> {
+        assert!(
+            std::mem::size_of::<V>() >= 6 * std::mem::size_of::<f32>(),
+            "vertices start with a position and a normal"
+        );
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let base = upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(vertices),
+            storage,
+        )?;
+        // Storage buffers can't be empty, a mesh without targets still gets one float
+        let mut packed = targets.packed(vertices.len());
+        if packed.is_empty() {
+            packed.push(0.0);
+        }
+        let deltas = match upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(&packed),
+            storage,
+        ) {
+            Ok(deltas) => deltas,
+            Err(err) => {
+                unsafe { base.destroy(device) };
+                return Err(err);
+            }
+        };
+        let mut this = MorphMesh {
+            base,
+            deltas,
+            outputs: Vec::new(),
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            vertex_count: vertices.len() as u32,
+            stride: (std::mem::size_of::<V>() / std::mem::size_of::<f32>()) as u32,
+            target_count: targets.len() as u32,
+        };
+        let result = unsafe {
+            (|| {
+                let size = std::mem::size_of_val(vertices) as vk::DeviceSize;
+                for _ in 0..frames_in_flight {
+                    let mut output = Buffer::new(
+                        device,
+                        mem_props,
+                        size,
+                        storage | vk::BufferUsageFlags::VERTEX_BUFFER,
+                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                    )?;
+                    output.set_category(MemoryCategory::Mesh);
+                    this.outputs.push(output);
+                }
+
+                let pool_size = vk::DescriptorPoolSize {
+                    ty: vk::DescriptorType::STORAGE_BUFFER,
+                    descriptor_count: 3 * frames_in_flight as u32,
+                };
+                this.pool = device.create_descriptor_pool(
+                    &vk::DescriptorPoolCreateInfo::builder()
+                        .max_sets(frames_in_flight as u32)
+                        .pool_sizes(&[pool_size]),
+                    None,
+                )?;
+                leaks::track(this.pool, "morph descriptor pool");
+                let layouts = vec![pipeline.set_layout; frames_in_flight];
+                this.sets = device.allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::builder()
+                        .descriptor_pool(this.pool)
+                        .set_layouts(&layouts),
+                )?;
+                for (set, output) in this.sets.iter().zip(&this.outputs) {
+                    let infos = [&this.base, &this.deltas, output].map(|buffer| {
+                        [vk::DescriptorBufferInfo {
+                            buffer: buffer.buffer,
+                            offset: 0,
+                            range: vk::WHOLE_SIZE,
+                        }]
+                    });
+                    let writes: Vec<_> = (0..)
+                        .zip(&infos)
+                        .map(|(binding, info)| {
+                            vk::WriteDescriptorSet::builder()
+                                .dst_set(*set)
+                                .dst_binding(binding)
+                                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                                .buffer_info(info)
+                                .build()
+                        })
+                        .collect();
+                    device.update_descriptor_sets(&writes, &[]);
+                }
+                VkResult::Ok(())
+            })()
+        };
+
+        // The uploads have to land before the first blend reads them
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+        match result {
+            Ok(()) => {
+                unsafe {
+                    device.cmd_pipeline_barrier(
+                        cmd,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::DependencyFlags::empty(),
+                        &[barrier],
+                        &[],
+                        &[],
+                    )
+                };
+                Ok(this)
+            }
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn target_count(&self) -> u32 {
+        self.target_count
+    }
+
+    /// Blended vertices for `frame`, bound in place of the mesh's own vertex buffer
+    pub fn vertex_buffer(&self, frame: usize) -> vk::Buffer {
+        self.outputs[frame].buffer
+    }
+
+    /// # Safety
+    ///
+    /// No recorded blend or draw of this mesh may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for output in self.outputs.drain(..) {
+            output.destroy(device);
+        }
+        self.base.destroy(device);
+        self.deltas.destroy(device);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        self.sets.clear();
+    }
+}
@@ -0,0 +1,289 @@
+//! Vertex layouts and shader for meshes with and without skins, and the per-frame joint buffer
+//!
+//! Skinned draws bind their joint matrices as a dynamic storage buffer, so many characters share
+//! one buffer and descriptor set per frame and only the dynamic offset changes between draws.
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, UVec4, Vec2, Vec3, Vec4};
+
+use crate::{
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{dynamic::DynamicBuffer, staging::StagingBelt, usage::MemoryCategory},
+    shader::variant::{Keyword, ShaderVariants},
+    vertex::VertexInput,
+};
+
+/// Mesh vertex shader, the `SKINNED` keyword reads [`SkinnedVertex`] and blends joint matrices
+/// from set 0, binding 0
+///
+/// Joint matrices already include the skeleton's world transform, so skinned draws push an
+/// identity model matrix. The `MOTION_VECTORS` keyword passes this and last frame's clip positions
+/// on for [`crate::motion::MOTION_FRAG_GLSL`], from the matrices of a
+/// [`crate::motion::MotionUniforms`].
+pub const MESH_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+#ifdef SKINNED
+layout(location = 3) in uvec4 joints;
+layout(location = 4) in vec4 weights;
+
+layout(set = 0, binding = 0) readonly buffer Joints {
+    mat4 matrices[];
+} joint_buffer;
+#endif
+#ifdef MOTION_VECTORS
+layout(set = 2, binding = 0) uniform Motion {
+    mat4 view_proj;
+    mat4 prev_view_proj;
+} motion;
+
+layout(location = 2) out vec4 out_clip;
+layout(location = 3) out vec4 out_prev_clip;
+#endif
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    mat4 model;
+} push;
+
+layout(location = 0) out vec3 out_normal;
+layout(location = 1) out vec2 out_uv;
+
+void main() {
+    mat4 model = push.model;
+#ifdef SKINNED
+    model = model * (weights.x * joint_buffer.matrices[joints.x]
+        + weights.y * joint_buffer.matrices[joints.y]
+        + weights.z * joint_buffer.matrices[joints.z]
+        + weights.w * joint_buffer.matrices[joints.w]);
+#endif
+    out_normal = mat3(model) * normal;
+    out_uv = uv;
+    gl_Position = push.view_proj * model * vec4(position, 1.0);
+#ifdef MOTION_VECTORS
+    vec4 world = model * vec4(position, 1.0);
+    out_clip = motion.view_proj * world;
+    out_prev_clip = motion.prev_view_proj * world;
+#endif
+}
+"#;
+
+pub const SKINNED_KEYWORD: &str = "SKINNED";
+pub const MOTION_VECTORS_KEYWORD: &str = "MOTION_VECTORS";
+
+/// [`MESH_VERT_GLSL`] with its `SKINNED` and `MOTION_VECTORS` toggles
+pub fn mesh_vertex_variants() -> ShaderVariants {
+    ShaderVariants::new(
+        MESH_VERT_GLSL,
+        vk::ShaderStageFlags::VERTEX,
+        vec![
+            Keyword::toggle(SKINNED_KEYWORD),
+            Keyword::toggle(MOTION_VECTORS_KEYWORD),
+        ],
+    )
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, VertexInput, AsBytes)]
+pub struct MeshVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, VertexInput, AsBytes)]
+pub struct SkinnedVertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+    /// Indices into the skeleton's joints
+    pub joints: UVec4,
+    /// Sum to one
+    pub weights: Vec4,
+}
+
+/// Joint matrices of every skinned draw in a frame, one buffer and descriptor set per frame
+pub struct JointBuffer {
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    buffers: Vec<DynamicBuffer>,
+    /// Matrices queued for the next upload, each draw's block padded to the offset alignment
+    matrices: Vec<Mat4>,
+    max_matrices: usize,
+    /// Matrices one draw can bind, the range of the descriptor
+    max_joints: usize,
+    /// Matrices per aligned step of the dynamic offset
+    alignment: usize,
+}
+
+impl JointBuffer {
+    /// Room for `max_matrices` per frame, with draws binding up to `max_joints` of them each,
+    /// the joint count of the largest skeleton
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        max_matrices: u32,
+        max_joints: u32,
+        frames_in_flight: usize,
+    ) -> VkResult<Self> {
+        let matrix_size = std::mem::size_of::<Mat4>() as u64;
+        let alignment = limits.min_storage_buffer_offset_alignment.max(matrix_size) / matrix_size;
+        let mut this = JointBuffer {
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            buffers: Vec::new(),
+            matrices: Vec::new(),
+            max_matrices: max_matrices as usize,
+            max_joints: max_joints.min(max_matrices) as usize,
+            alignment: alignment as usize,
+        };
+        let result = unsafe { this.create_objects(device, mem_props, limits, frames_in_flight) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        frames_in_flight: usize,
+    ) -> VkResult<()> {
+        let binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .build();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[binding]),
+            None,
+        )?;
+        leaks::track(self.set_layout, "skinning descriptor set layout");
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
+            descriptor_count: frames_in_flight as u32,
+        };
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(frames_in_flight as u32)
+                .pool_sizes(&[pool_size]),
+            None,
+        )?;
+        leaks::track(self.pool, "skinning descriptor pool");
+        let layouts = vec![self.set_layout; frames_in_flight];
+        self.sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&layouts),
+        )?;
+
+        let size = (self.max_matrices * std::mem::size_of::<Mat4>()) as vk::DeviceSize;
+        for set in self.sets.clone() {
+            let mut buffer = DynamicBuffer::new(
+                device,
+                mem_props,
+                limits,
+                size,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )?;
+            buffer.buffer.set_category(MemoryCategory::Uniform);
+            // The dynamic offset moves this window, which has to stay inside the buffer
+            let info = [vk::DescriptorBufferInfo {
+                buffer: buffer.buffer.buffer,
+                offset: 0,
+                range: (self.max_joints * std::mem::size_of::<Mat4>()) as vk::DeviceSize,
+            }];
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+                .buffer_info(&info)
+                .build();
+            device.update_descriptor_sets(&[write], &[]);
+            self.buffers.push(buffer);
+        }
+        Ok(())
+    }
+
+    /// Layout to put at the skinned pipeline's set 0
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Queues one draw's joint matrices, returning the dynamic offset to bind them with or `None`
+    /// when the buffer is full or there are more than `max_joints`
+    pub fn push(&mut self, matrices: &[Mat4]) -> Option<u32> {
+        let start = self.matrices.len();
+        let padded = matrices.len().div_ceil(self.alignment) * self.alignment;
+        if matrices.len() > self.max_joints
+            || start + padded.max(self.max_joints) > self.max_matrices
+        {
+            return None;
+        }
+        self.matrices.extend_from_slice(matrices);
+        self.matrices.resize(start + padded, Mat4::IDENTITY);
+        Some((start * std::mem::size_of::<Mat4>()) as u32)
+    }
+
+    /// Writes everything queued since the last upload into `frame`'s buffer
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+    ) -> VkResult<()> {
+        if !self.matrices.is_empty() {
+            self.buffers[frame].write(device, staging, cmd, 0, slice_as_bytes(&self.matrices))?;
+        }
+        self.matrices.clear();
+        Ok(())
+    }
+
+    /// Binds `frame`'s matrices at `offset` from [`JointBuffer::push`] to set 0 of `layout`
+    pub fn bind(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        frame: usize,
+        offset: u32,
+    ) {
+        unsafe {
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                layout,
+                0,
+                &[self.sets[frame]],
+                &[offset],
+            )
+        };
+    }
+
+    /// # Safety
+    ///
+    /// No recorded skinned draw may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.buffers.drain(..) {
+            buffer.destroy(device);
+        }
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        self.sets.clear();
+    }
+}
@@ -0,0 +1,310 @@
+//! Registry of loaded assets behind refcounted handles
+//!
+//! Every asset is keyed by its path or UUID so loading the same one twice hands out another
+//! handle to the existing copy. Once the last handle is dropped the asset is unused and is
+//! returned by [`Assets::collect_unused`] to be freed.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+};
+
+use ash::{vk, Device};
+
+use crate::{leaks, memory::Buffer};
+
+pub mod loader;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AssetKey {
+    Path(PathBuf),
+    Uuid(u128),
+}
+
+impl From<&Path> for AssetKey {
+    fn from(path: &Path) -> Self {
+        AssetKey::Path(path.to_owned())
+    }
+}
+
+impl From<PathBuf> for AssetKey {
+    fn from(path: PathBuf) -> Self {
+        AssetKey::Path(path)
+    }
+}
+
+impl fmt::Display for AssetKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetKey::Path(path) => write!(f, "{}", path.display()),
+            AssetKey::Uuid(uuid) => write!(f, "{uuid:032x}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Slot(u32);
+
+/// Keeps an asset loaded for as long as any clone of it is alive
+pub struct Handle<T> {
+    slot: Arc<Slot>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    pub fn index(&self) -> u32 {
+        self.slot.0
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Handle {
+            slot: self.slot.clone(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.slot, &other.slot)
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({})", self.slot.0)
+    }
+}
+
+struct Entry<T> {
+    /// `None` while the asset is still loading
+    asset: Option<T>,
+    key: Option<AssetKey>,
+    handle: Weak<Slot>,
+}
+
+/// Storage for one kind of asset
+pub struct Assets<T> {
+    entries: Vec<Option<Entry<T>>>,
+    free: Vec<u32>,
+    keys: HashMap<AssetKey, u32>,
+    /// Stands in for assets that are still loading
+    placeholder: Option<T>,
+}
+
+impl<T> Default for Assets<T> {
+    fn default() -> Self {
+        Assets {
+            entries: Vec::new(),
+            free: Vec::new(),
+            keys: HashMap::new(),
+            placeholder: None,
+        }
+    }
+}
+
+impl<T> Assets<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an asset, replacing the mapping of `key` if it was already loaded
+    pub fn insert(&mut self, key: Option<AssetKey>, asset: T) -> Handle<T> {
+        self.insert_entry(key, Some(asset))
+    }
+
+    /// Adds an asset that is still loading, the placeholder is used until [`Self::complete`]
+    pub fn insert_pending(&mut self, key: Option<AssetKey>) -> Handle<T> {
+        self.insert_entry(key, None)
+    }
+
+    fn insert_entry(&mut self, key: Option<AssetKey>, asset: Option<T>) -> Handle<T> {
+        let index = self.free.pop().unwrap_or_else(|| {
+            self.entries.push(None);
+            self.entries.len() as u32 - 1
+        });
+        let slot = Arc::new(Slot(index));
+        if let Some(key) = &key {
+            self.keys.insert(key.clone(), index);
+        }
+        self.entries[index as usize] = Some(Entry {
+            asset,
+            key,
+            handle: Arc::downgrade(&slot),
+        });
+        Handle {
+            slot,
+            marker: PhantomData,
+        }
+    }
+
+    /// A new handle to an already loaded asset, if it is still referenced
+    pub fn find(&self, key: &AssetKey) -> Option<Handle<T>> {
+        let index = *self.keys.get(key)?;
+        let slot = self.entries[index as usize].as_ref()?.handle.upgrade()?;
+        Some(Handle {
+            slot,
+            marker: PhantomData,
+        })
+    }
+
+    /// Returns the loaded asset for `key`, only calling `load` when it isn't loaded yet
+    pub fn load<E>(
+        &mut self,
+        key: impl Into<AssetKey>,
+        load: impl FnOnce(&AssetKey) -> Result<T, E>,
+    ) -> Result<Handle<T>, E> {
+        let key = key.into();
+        if let Some(handle) = self.find(&key) {
+            return Ok(handle);
+        }
+        let asset = load(&key)?;
+        Ok(self.insert(Some(key), asset))
+    }
+
+    fn entry(&self, handle: &Handle<T>) -> &Entry<T> {
+        self.entries[handle.slot.0 as usize]
+            .as_ref()
+            .expect("handles keep their asset alive")
+    }
+
+    /// The asset, or the placeholder while it is loading
+    ///
+    /// # Panics
+    ///
+    /// If the asset is loading and there is no placeholder.
+    pub fn get(&self, handle: &Handle<T>) -> &T {
+        self.entry(handle)
+            .asset
+            .as_ref()
+            .or(self.placeholder.as_ref())
+            .expect("asset is still loading and there is no placeholder")
+    }
+
+    /// The asset, `None` while it is loading
+    pub fn get_mut(&mut self, handle: &Handle<T>) -> Option<&mut T> {
+        self.entries[handle.slot.0 as usize]
+            .as_mut()
+            .expect("handles keep their asset alive")
+            .asset
+            .as_mut()
+    }
+
+    pub fn is_ready(&self, handle: &Handle<T>) -> bool {
+        self.entry(handle).asset.is_some()
+    }
+
+    /// Fills in a pending asset, or replaces a loaded one, returning the previous asset
+    pub fn complete(&mut self, handle: &Handle<T>, asset: T) -> Option<T> {
+        self.entries[handle.slot.0 as usize]
+            .as_mut()
+            .expect("handles keep their asset alive")
+            .asset
+            .replace(asset)
+    }
+
+    /// Sets what [`Self::get`] returns for assets still loading, returning the previous one
+    pub fn set_placeholder(&mut self, placeholder: T) -> Option<T> {
+        self.placeholder.replace(placeholder)
+    }
+
+    pub fn key(&self, handle: &Handle<T>) -> Option<&AssetKey> {
+        self.entries[handle.slot.0 as usize].as_ref()?.key.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every asset without live handles and returns them to be freed
+    pub fn collect_unused(&mut self) -> Vec<(Option<AssetKey>, T)> {
+        let mut unused = Vec::new();
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            if entry
+                .as_ref()
+                .is_some_and(|entry| entry.handle.strong_count() == 0)
+            {
+                let entry = entry.take().unwrap();
+                if let Some(key) = &entry.key {
+                    // The key may have been remapped to a newer load since
+                    if self.keys.get(key) == Some(&(index as u32)) {
+                        self.keys.remove(key);
+                    }
+                }
+                self.free.push(index as u32);
+                if let Some(asset) = entry.asset {
+                    unused.push((entry.key, asset));
+                }
+            }
+        }
+        unused
+    }
+
+    /// Drains every asset and the placeholder regardless of handles, for shutdown
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.keys.clear();
+        self.free.clear();
+        self.entries
+            .drain(..)
+            .flatten()
+            .filter_map(|entry| entry.asset)
+            .chain(self.placeholder.take())
+    }
+}
+
+/// Assets owning device objects that have to be destroyed explicitly
+pub trait GpuAsset {
+    /// # Safety
+    ///
+    /// The asset must no longer be in use by the device.
+    unsafe fn destroy(&self, device: &Device);
+}
+
+impl GpuAsset for Buffer {
+    unsafe fn destroy(&self, device: &Device) {
+        Buffer::destroy(self, device);
+    }
+}
+
+impl GpuAsset for vk::ShaderModule {
+    unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(*self);
+        device.destroy_shader_module(*self, None);
+    }
+}
+
+impl<T: GpuAsset> Assets<T> {
+    /// Destroys every asset without live handles
+    ///
+    /// # Safety
+    ///
+    /// Unreferenced assets must no longer be in use by the device, e.g. call this after waiting
+    /// on the fences of the frames that last drew with them.
+    pub unsafe fn destroy_unused(&mut self, device: &Device) -> usize {
+        let unused = self.collect_unused();
+        for (_, asset) in &unused {
+            asset.destroy(device);
+        }
+        unused.len()
+    }
+
+    /// # Safety
+    ///
+    /// None of the assets may still be in use by the device.
+    pub unsafe fn destroy_all(&mut self, device: &Device) {
+        for asset in self.drain() {
+            asset.destroy(device);
+        }
+    }
+}
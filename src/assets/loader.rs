@@ -0,0 +1,128 @@
+//! Background loading on a pool of worker threads
+//!
+//! Workers do the file I/O and decoding and hand the CPU side data back through a completion
+//! queue. Anything touching the device, like uploads, happens on the thread calling
+//! [`Loader::poll`], while [`Assets`] serves the placeholder in the meantime.
+
+use std::{
+    error::Error,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::{AssetKey, Assets, Handle};
+
+pub type LoadError = Box<dyn Error + Send + Sync>;
+
+type Work<D> = Box<dyn FnOnce(&AssetKey) -> Result<D, LoadError> + Send>;
+
+struct Job<T, D> {
+    handle: Handle<T>,
+    key: AssetKey,
+    work: Work<D>,
+}
+
+/// Result of a background load, ready to be uploaded and passed to [`Assets::complete`]
+pub struct Loaded<T, D> {
+    pub handle: Handle<T>,
+    pub key: AssetKey,
+    pub result: Result<D, LoadError>,
+}
+
+/// Loads `D`, the decoded data for assets of type `T`, on worker threads
+pub struct Loader<T, D> {
+    jobs: Option<Sender<Job<T, D>>>,
+    done: Receiver<Loaded<T, D>>,
+    workers: Vec<JoinHandle<()>>,
+    in_flight: usize,
+}
+
+impl<T: 'static, D: Send + 'static> Loader<T, D> {
+    /// Starts `threads` workers, at least one
+    pub fn new(threads: usize) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job<T, D>>();
+        let (finished, done) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let queue = queue.clone();
+                let finished = finished.clone();
+                thread::Builder::new()
+                    .name(format!("asset-loader-{i}"))
+                    .spawn(move || loop {
+                        let job = queue.lock().unwrap().recv();
+                        let Ok(job) = job else {
+                            break;
+                        };
+                        let result = (job.work)(&job.key);
+                        let loaded = Loaded {
+                            handle: job.handle,
+                            key: job.key,
+                            result,
+                        };
+                        if finished.send(loaded).is_err() {
+                            break;
+                        }
+                    })
+                    .expect("failed to spawn asset loader thread")
+            })
+            .collect();
+        Loader {
+            jobs: Some(jobs),
+            done,
+            workers,
+            in_flight: 0,
+        }
+    }
+
+    /// Queues a load unless the asset is already loaded or loading, returning its handle
+    pub fn load(
+        &mut self,
+        assets: &mut Assets<T>,
+        key: impl Into<AssetKey>,
+        work: impl FnOnce(&AssetKey) -> Result<D, LoadError> + Send + 'static,
+    ) -> Handle<T> {
+        let key = key.into();
+        if let Some(handle) = assets.find(&key) {
+            return handle;
+        }
+        let handle = assets.insert_pending(Some(key.clone()));
+        let job = Job {
+            handle: handle.clone(),
+            key,
+            work: Box::new(work),
+        };
+        self.jobs
+            .as_ref()
+            .expect("loader is running")
+            .send(job)
+            .expect("asset loader threads exited");
+        self.in_flight += 1;
+        handle
+    }
+
+    /// Loads finished since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<Loaded<T, D>> {
+        let finished: Vec<_> = self.done.try_iter().collect();
+        self.in_flight -= finished.len();
+        finished
+    }
+
+    /// Number of loads queued or running
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+}
+
+impl<T, D> Drop for Loader<T, D> {
+    fn drop(&mut self) {
+        // Closing the queue lets the workers finish their current job and exit
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
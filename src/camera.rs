@@ -0,0 +1,221 @@
+//! Camera controllers driven by [`InputMap`] actions and axes
+//!
+//! [`FlyCamera`] moves freely like a first person camera, [`OrbitCamera`] turns around a focal
+//! point like a model viewer. [`CameraController`] holds either and switches between them without
+//! jumping, both produce the camera's world [`Transform`], looking down -z with y up. Their
+//! movement can be recorded and replayed with [`path`].
+
+use std::f32::consts::FRAC_PI_2;
+
+use glam::{EulerRot, Mat4, Quat, Vec2, Vec3};
+use serde::Deserialize;
+
+use crate::{input::InputMap, scene::Transform};
+
+pub mod path;
+
+/// Keeps pitch off the poles, where yaw stops making sense
+const MAX_PITCH: f32 = FRAC_PI_2 - 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CameraSettings {
+    /// Units per second flying
+    pub speed: f32,
+    /// Radians per pixel of mouse motion
+    pub sensitivity: f32,
+    /// Radians per second with a stick fully pushed
+    pub turn_rate: f32,
+    /// Fraction of the orbit distance each wheel step zooms
+    pub zoom_step: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            speed: 5.0,
+            sensitivity: 0.003,
+            turn_rate: 2.5,
+            zoom_step: 0.1,
+        }
+    }
+}
+
+/// Yaw and pitch from looking axes, mouse look plus stick turning over `dt`
+fn look_delta(input: &InputMap, settings: &CameraSettings, dt: f32) -> Vec2 {
+    let mouse = Vec2::new(input.axis("look_x"), input.axis("look_y")) * settings.sensitivity;
+    // Stick up is positive, mouse down is
+    let stick = Vec2::new(input.axis("turn_x"), -input.axis("turn_y")) * settings.turn_rate * dt;
+    -(mouse + stick)
+}
+
+fn rotation(yaw: f32, pitch: f32) -> Quat {
+    Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0)
+}
+
+/// Yaw and pitch a rotation looks with, ignoring any roll
+fn yaw_pitch(rotation: Quat) -> (f32, f32) {
+    let (yaw, pitch, _) = rotation.to_euler(EulerRot::YXZ);
+    (yaw, pitch)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlyCamera {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl FlyCamera {
+    pub fn from_transform(transform: &Transform) -> Self {
+        let (yaw, pitch) = yaw_pitch(transform.rotation);
+        FlyCamera {
+            position: transform.translation,
+            yaw,
+            pitch,
+        }
+    }
+
+    /// Looks with `look_x`/`look_y` and `turn_x`/`turn_y`, moves with the `move_*` actions and
+    /// `move_x`/`move_y`
+    pub fn update(&mut self, input: &InputMap, settings: &CameraSettings, dt: f32) {
+        let look = look_delta(input, settings, dt);
+        self.yaw += look.x;
+        self.pitch = (self.pitch + look.y).clamp(-MAX_PITCH, MAX_PITCH);
+
+        let right = input.action_axis("move_left", "move_right") + input.axis("move_x");
+        let forward = input.action_axis("move_back", "move_forward") + input.axis("move_y");
+        let up = input.action_axis("move_down", "move_up");
+        let local = Vec3::new(right, up, -forward).clamp_length_max(1.0);
+        // Up and down stay vertical whichever way the camera looks
+        let horizontal = rotation(self.yaw, self.pitch) * Vec3::new(local.x, 0.0, local.z);
+        let movement = horizontal + Vec3::Y * local.y;
+        self.position += movement * settings.speed * dt;
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform {
+            translation: self.position,
+            rotation: rotation(self.yaw, self.pitch),
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrbitCamera {
+    pub focus: Vec3,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub min_distance: f32,
+}
+
+impl OrbitCamera {
+    /// Orbits the point `distance` in front of `transform`
+    pub fn from_transform(transform: &Transform, distance: f32) -> Self {
+        let (yaw, pitch) = yaw_pitch(transform.rotation);
+        OrbitCamera {
+            focus: transform.translation + transform.rotation * Vec3::NEG_Z * distance,
+            distance,
+            yaw,
+            pitch,
+            min_distance: 0.05,
+        }
+    }
+
+    /// Turns while `orbit` is held, pans while `pan` is held, zooms with `zoom`. Sticks turn and
+    /// pan without holding anything.
+    pub fn update(&mut self, input: &InputMap, settings: &CameraSettings, dt: f32) {
+        let look = look_delta(input, settings, dt);
+        let mouse = Vec2::new(input.axis("look_x"), input.axis("look_y"));
+        let stick_turn = Vec2::new(input.axis("turn_x"), input.axis("turn_y"));
+        let stick_pan = Vec2::new(input.axis("move_x"), input.axis("move_y"));
+
+        if input.is_held("orbit") || stick_turn != Vec2::ZERO {
+            self.yaw += look.x;
+            self.pitch = (self.pitch + look.y).clamp(-MAX_PITCH, MAX_PITCH);
+        }
+
+        // Dragging keeps the focus under the cursor at roughly the speed it moves
+        let mut pan = Vec2::ZERO;
+        if input.is_held("pan") {
+            pan += Vec2::new(-mouse.x, mouse.y) * settings.sensitivity;
+        }
+        // Sticks pan one orbit distance a second
+        pan += stick_pan * dt;
+        let rotation = rotation(self.yaw, self.pitch);
+        self.focus += rotation * Vec3::new(pan.x, pan.y, 0.0) * self.distance;
+
+        let zoom = input.axis("zoom");
+        if zoom != 0.0 {
+            self.distance =
+                (self.distance * (1.0 - settings.zoom_step).powf(zoom)).max(self.min_distance);
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        let rotation = rotation(self.yaw, self.pitch);
+        Transform {
+            translation: self.focus - rotation * Vec3::NEG_Z * self.distance,
+            rotation,
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+/// Whichever camera is in use, switched at runtime with [`CameraController::toggle`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraController {
+    Fly(FlyCamera),
+    Orbit(OrbitCamera),
+}
+
+impl CameraController {
+    /// Distance in front of a flying camera its orbit picks as focus
+    const ORBIT_DISTANCE: f32 = 5.0;
+
+    /// A flying camera at `position` looking at `target`
+    pub fn looking_at(position: Vec3, target: Vec3) -> Self {
+        let view = Mat4::look_at_rh(position, target, Vec3::Y);
+        let (_, rotation, _) = view.inverse().to_scale_rotation_translation();
+        CameraController::Fly(FlyCamera::from_transform(&Transform {
+            translation: position,
+            rotation,
+            scale: Vec3::ONE,
+        }))
+    }
+
+    /// Switches between flying and orbiting, keeping the camera where it is
+    pub fn toggle(&mut self) {
+        let transform = self.transform();
+        *self = match self {
+            CameraController::Fly(_) => CameraController::Orbit(OrbitCamera::from_transform(
+                &transform,
+                Self::ORBIT_DISTANCE,
+            )),
+            CameraController::Orbit(_) => {
+                CameraController::Fly(FlyCamera::from_transform(&transform))
+            }
+        };
+    }
+
+    pub fn update(&mut self, input: &InputMap, settings: &CameraSettings, dt: f32) {
+        match self {
+            CameraController::Fly(camera) => camera.update(input, settings, dt),
+            CameraController::Orbit(camera) => camera.update(input, settings, dt),
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        match self {
+            CameraController::Fly(camera) => camera.transform(),
+            CameraController::Orbit(camera) => camera.transform(),
+        }
+    }
+
+    /// World to view matrix, the inverse of [`CameraController::transform`]
+    pub fn view(&self) -> Mat4 {
+        self.transform().matrix().inverse()
+    }
+}
@@ -0,0 +1,129 @@
+//! Camera transforms recorded over time and played back
+//!
+//! Keyframes are stamped with simulation time, so a path replays the same way however fast it's
+//! rendered and runs can be compared frame for frame. Files are plain text, a keyframe per line
+//! as time, translation and rotation quaternion, and `#` starting a comment.
+
+use std::{fmt::Write as _, fs, io, path::Path};
+
+use glam::{Quat, Vec3};
+use thiserror::Error;
+
+use crate::scene::Transform;
+
+#[derive(Debug, Error)]
+pub enum PathError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("line {line}: expected 8 numbers, time, translation and rotation")]
+    Parse { line: usize },
+    #[error("line {line}: time goes backwards")]
+    Unordered { line: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    /// Seconds from the start of the path
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CameraPath {
+    keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// Time of the last keyframe
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    /// Appends the camera at `time`, ignored unless later than the last keyframe
+    pub fn record(&mut self, time: f32, transform: &Transform) {
+        if self.keyframes.last().is_some_and(|last| last.time >= time) {
+            return;
+        }
+        self.keyframes.push(Keyframe {
+            time,
+            translation: transform.translation,
+            rotation: transform.rotation,
+        });
+    }
+
+    /// The camera at `time`, between the keyframes around it, `None` past the end
+    pub fn sample(&self, time: f32) -> Option<Transform> {
+        let next = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.time < time);
+        let after = self.keyframes.get(next)?;
+        let transform = |keyframe: &Keyframe| Transform {
+            translation: keyframe.translation,
+            rotation: keyframe.rotation,
+            scale: Vec3::ONE,
+        };
+        let Some(before) = next.checked_sub(1).map(|i| &self.keyframes[i]) else {
+            return Some(transform(after));
+        };
+        let t = (time - before.time) / (after.time - before.time);
+        Some(transform(before).lerp(&transform(after), t))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, PathError> {
+        let mut path = CameraPath::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let numbers = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| PathError::Parse { line: line_number })?;
+            let &[time, x, y, z, qx, qy, qz, qw] = numbers.as_slice() else {
+                return Err(PathError::Parse { line: line_number });
+            };
+            if path.keyframes.last().is_some_and(|last| last.time > time) {
+                return Err(PathError::Unordered { line: line_number });
+            }
+            path.keyframes.push(Keyframe {
+                time,
+                translation: Vec3::new(x, y, z),
+                rotation: Quat::from_xyzw(qx, qy, qz, qw).normalize(),
+            });
+        }
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, PathError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::from("# time x y z qx qy qz qw\n");
+        for keyframe in &self.keyframes {
+            let Keyframe {
+                time,
+                translation: t,
+                rotation: q,
+            } = keyframe;
+            let _ = writeln!(
+                text,
+                "{time} {} {} {} {} {} {} {}",
+                t.x, t.y, t.z, q.x, q.y, q.z, q.w
+            );
+        }
+        fs::write(path, text)
+    }
+}
@@ -0,0 +1,372 @@
+//! Keeping the last few seconds of rendered frames, to save once a glitch has been seen
+//!
+//! [`FrameReadback`] copies presented images into host memory, a slot per frame in flight like
+//! [`crate::picking::Picker`], and [`FrameRing`] keeps what it reads back for a set length of
+//! time. Dumping writes the frames as a numbered PNG sequence, which e.g.
+//! `ffmpeg -framerate 30 -i frame_%05d.png` turns into a video.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use ash::{vk, Device};
+use thiserror::Error;
+
+use crate::memory::Buffer;
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("can't capture {0:?} images, only 8 bit RGBA and BGRA")]
+    UnsupportedFormat(vk::Format),
+}
+
+const COLOR_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// A frame read back, always tightly packed 8 bit RGBA
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    /// When the copy was recorded
+    pub time: Instant,
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl CapturedFrame {
+    pub fn save_png(&self, path: &Path) -> Result<(), CaptureError> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+}
+
+/// Copies whole presentable images to host memory without stalling
+///
+/// The images need `TRANSFER_SRC` usage, so swapchains being captured have to be created with it.
+pub struct FrameReadback {
+    readback: Vec<Buffer>,
+    /// When each frame's slot was last copied into, if it hasn't been read yet
+    in_flight: Vec<Option<Instant>>,
+    extent: vk::Extent2D,
+    /// Red and blue are swapped in the images
+    bgra: bool,
+}
+
+impl FrameReadback {
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        frames_in_flight: usize,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> Result<Self, CaptureError> {
+        let bgra = match format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+            vk::Format::R8G8B8A8_UNORM
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::A8B8G8R8_UNORM_PACK32
+            | vk::Format::A8B8G8R8_SRGB_PACK32 => false,
+            _ => return Err(CaptureError::UnsupportedFormat(format)),
+        };
+        let mut readback = FrameReadback {
+            readback: Vec::new(),
+            in_flight: vec![None; frames_in_flight],
+            extent,
+            bgra,
+        };
+        let size = extent.width as vk::DeviceSize * extent.height as vk::DeviceSize * 4;
+        for _ in 0..frames_in_flight {
+            match Buffer::new(
+                device,
+                mem_props,
+                size,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::HOST_VISIBLE
+                    | vk::MemoryPropertyFlags::HOST_COHERENT
+                    | vk::MemoryPropertyFlags::HOST_CACHED,
+            )
+            .or_else(|_| {
+                // Reading uncached memory is slow, but better than no capture
+                Buffer::new(
+                    device,
+                    mem_props,
+                    size,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )
+            }) {
+                Ok(buffer) => readback.readback.push(buffer),
+                Err(err) => {
+                    unsafe { readback.destroy(device) };
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(readback)
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Copies `image` into `frame`'s slot, recorded after the last pass writing it
+    ///
+    /// The image is left in `layout`, usually `PRESENT_SRC_KHR`, which it must be in already.
+    /// Any earlier command may have written it, a render graph's final barrier included.
+    pub fn record_copy(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+    ) {
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(COLOR_RANGE);
+        let back = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(COLOR_RANGE);
+        let to_host = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(self.readback[frame].buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D::default(),
+            image_extent: vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            },
+        };
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*to_transfer],
+            );
+            device.cmd_copy_image_to_buffer(
+                cmd,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.readback[frame].buffer,
+                &[region],
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE | vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[*to_host],
+                &[*back],
+            );
+        }
+        self.in_flight[frame] = Some(Instant::now());
+    }
+
+    /// Takes the image copied in `frame`'s slot, once that frame's fence has been waited on
+    pub fn poll(&mut self, frame: usize) -> Option<CapturedFrame> {
+        let time = self.in_flight[frame].take()?;
+        let mapped = self.readback[frame].mapped()?;
+        let len = self.extent.width as usize * self.extent.height as usize * 4;
+        let mut pixels =
+            unsafe { std::slice::from_raw_parts(mapped.cast::<u8>().as_ptr(), len) }.to_vec();
+        if self.bgra {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+        Some(CapturedFrame {
+            time,
+            width: self.extent.width,
+            height: self.extent.height,
+            pixels,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// No recorded copy may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.readback.drain(..) {
+            buffer.destroy(device);
+        }
+    }
+}
+
+/// The most recent frames, covering a set length of time
+#[derive(Debug, Clone)]
+pub struct FrameRing {
+    frames: VecDeque<CapturedFrame>,
+    length: Duration,
+    interval: Duration,
+}
+
+impl FrameRing {
+    /// Keeps `length` worth of frames, every frame until [`FrameRing::with_rate`] says otherwise
+    pub fn new(length: Duration) -> Self {
+        FrameRing {
+            frames: VecDeque::new(),
+            length,
+            interval: Duration::ZERO,
+        }
+    }
+
+    /// Keeps at most `fps` frames a second, bounding memory at high frame rates
+    ///
+    /// Rates that aren't positive, or are NaN, keep every frame again.
+    pub fn with_rate(mut self, fps: f64) -> Self {
+        self.interval = if fps > 0.0 {
+            Duration::try_from_secs_f64(fps.recip()).unwrap_or(Duration::MAX)
+        } else {
+            Duration::ZERO
+        };
+        self
+    }
+
+    /// Whether a frame copied at `now` would be kept, to skip recording copies that wouldn't
+    pub fn wants_frame(&self, now: Instant) -> bool {
+        self.frames
+            .back()
+            .is_none_or(|last| now.saturating_duration_since(last.time) >= self.interval)
+    }
+
+    /// Adds the newest frame, dropping those that fell out of the window
+    pub fn push(&mut self, frame: CapturedFrame) {
+        let time = frame.time;
+        self.frames.push_back(frame);
+        while self
+            .frames
+            .front()
+            .is_some_and(|oldest| time.saturating_duration_since(oldest.time) > self.length)
+        {
+            self.frames.pop_front();
+        }
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = &CapturedFrame> {
+        self.frames.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Bytes of pixels held
+    pub fn memory(&self) -> usize {
+        self.frames.iter().map(|frame| frame.pixels.len()).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Writes the frames oldest first as `frame_00000.png` onwards into `dir`, created if
+    /// needed, returning how many were written
+    pub fn dump(&self, dir: &Path) -> Result<usize, CaptureError> {
+        fs::create_dir_all(dir)?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            frame.save_png(&dir.join(format!("frame_{i:05}.png")))?;
+        }
+        Ok(self.frames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(time: Instant) -> CapturedFrame {
+        CapturedFrame {
+            time,
+            width: 1,
+            height: 1,
+            pixels: vec![0; 4],
+        }
+    }
+
+    #[test]
+    fn invalid_rates_keep_every_frame() {
+        let start = Instant::now();
+        for fps in [0.0, -30.0, f64::NAN, f64::MIN_POSITIVE] {
+            let mut ring = FrameRing::new(Duration::from_secs(1)).with_rate(fps);
+            ring.push(frame(start));
+            let every_frame = fps.is_nan() || fps <= 0.0;
+            assert_eq!(ring.wants_frame(start), every_frame, "{fps}");
+        }
+    }
+
+    #[test]
+    fn rate_skips_frames_until_the_interval_passed() {
+        let start = Instant::now();
+        let mut ring = FrameRing::new(Duration::from_secs(1)).with_rate(10.0);
+        assert!(ring.wants_frame(start));
+        ring.push(frame(start));
+        assert!(!ring.wants_frame(start + Duration::from_millis(50)));
+        assert!(ring.wants_frame(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn frames_older_than_the_length_are_dropped() {
+        let start = Instant::now();
+        let mut ring = FrameRing::new(Duration::from_secs(1));
+        for ms in [0, 500, 1000, 1500] {
+            ring.push(frame(start + Duration::from_millis(ms)));
+        }
+        let times: Vec<_> = ring.frames().map(|frame| frame.time - start).collect();
+        assert_eq!(times, [500, 1000, 1500].map(Duration::from_millis));
+    }
+}
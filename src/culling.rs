@@ -0,0 +1,131 @@
+//! Skipping mesh instances outside the camera frustum before they're drawn
+
+use std::fmt;
+
+use glam::Vec3;
+
+use crate::{
+    geometry::{bvh::Bvh, Aabb, Frustum, Sphere},
+    memory::sparse::Residency,
+    scene::{InstanceData, MeshId, NodeId, Scene},
+};
+
+/// Local space bounds of a mesh, computed once when it's loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshBounds {
+    pub aabb: Aabb,
+    pub sphere: Sphere,
+}
+
+impl MeshBounds {
+    pub fn new(aabb: Aabb) -> Self {
+        MeshBounds {
+            aabb,
+            sphere: Sphere::from_aabb(&aabb),
+        }
+    }
+
+    /// `None` for a mesh without vertices
+    pub fn from_positions(positions: impl IntoIterator<Item = Vec3>) -> Option<Self> {
+        Aabb::from_points(positions).map(MeshBounds::new)
+    }
+}
+
+/// Instances drawn and skipped by the last cull, for the stats overlay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub submitted: u32,
+    pub culled: u32,
+}
+
+impl fmt::Display for CullStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} drawn, {} culled", self.submitted, self.culled)
+    }
+}
+
+/// [`Scene::gather_instances`] without the instances outside `frustum`
+///
+/// Each instance is tested by its world space sphere, then by its world space box. Meshes
+/// `bounds` has nothing for are always drawn.
+pub fn cull_instances(
+    scene: &Scene,
+    frustum: &Frustum,
+    bounds: impl Fn(MeshId) -> Option<MeshBounds>,
+    out: &mut Vec<(MeshId, InstanceData)>,
+) -> CullStats {
+    let mut stats = CullStats::default();
+    out.clear();
+    for (_, node) in scene.iter() {
+        let Some(mesh) = node.mesh else {
+            continue;
+        };
+        let world = node.world();
+        let visible = bounds(mesh).is_none_or(|bounds| {
+            frustum.intersects_sphere(&bounds.sphere.transformed(world))
+                && frustum.intersects_aabb(&bounds.aabb.transformed(world))
+        });
+        if visible {
+            out.push((mesh, InstanceData::new(world)));
+            stats.submitted += 1;
+        } else {
+            stats.culled += 1;
+        }
+    }
+    out.sort_by_key(|(mesh, _)| *mesh);
+    stats
+}
+
+/// [`cull_instances`] through a tree built by [`Bvh::from_scene`], for scenes with many objects
+///
+/// Only nodes in the tree are drawn, refit it after transforms change.
+pub fn cull_instances_bvh(
+    scene: &Scene,
+    bvh: &Bvh<NodeId>,
+    frustum: &Frustum,
+    out: &mut Vec<(MeshId, InstanceData)>,
+) -> CullStats {
+    out.clear();
+    bvh.query_frustum(frustum, |id| {
+        if let Some(node) = scene.get(id) {
+            if let Some(mesh) = node.mesh {
+                out.push((mesh, InstanceData::new(node.world())));
+            }
+        }
+    });
+    out.sort_by_key(|(mesh, _)| *mesh);
+    let submitted = out.len() as u32;
+    CullStats {
+        submitted,
+        culled: bvh.len() as u32 - submitted,
+    }
+}
+
+/// Requests the meshes of culled instances from `residency`, standing in coarser levels of detail
+///
+/// An instance whose mesh isn't resident is drawn with the first resident mesh down the chain
+/// `coarser` gives, each requested on the way so it stays committed while it stands in. Instances
+/// with nothing resident are dropped, their count is returned. The chain has to end in `None`.
+pub fn retain_resident(
+    residency: &mut Residency<MeshId>,
+    coarser: impl Fn(MeshId) -> Option<MeshId>,
+    out: &mut Vec<(MeshId, InstanceData)>,
+) -> u32 {
+    let before = out.len();
+    out.retain_mut(|(mesh, _)| {
+        let mut candidate = *mesh;
+        loop {
+            residency.request(candidate);
+            if residency.is_resident(candidate) {
+                *mesh = candidate;
+                return true;
+            }
+            match coarser(candidate) {
+                Some(next) => candidate = next,
+                None => return false,
+            }
+        }
+    });
+    out.sort_by_key(|(mesh, _)| *mesh);
+    (before - out.len()) as u32
+}
@@ -0,0 +1,390 @@
+//! Immediate mode debug lines, queued from anywhere during a frame and drawn in one call
+
+use std::{error::Error, f32::consts::TAU, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    geometry::Aabb,
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{dynamic::DynamicBuffer, staging::StagingBelt},
+    pipeline::dynamic::{DynamicRasterState, RasterState},
+    shader::{reflect, ShaderCompiler},
+    vertex::VertexInput,
+};
+
+pub const DEBUG_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+} push;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    gl_Position = push.view_proj * vec4(position, 1.0);
+    out_color = color;
+}
+"#;
+
+pub const DEBUG_FRAG_GLSL: &str = r#"#version 450
+layout(location = 0) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = color;
+}
+"#;
+
+/// Line segments making up each circle of [`DebugDraw::sphere`]
+const CIRCLE_SEGMENTS: u32 = 32;
+
+#[derive(Debug, Error)]
+pub enum DebugDrawError {
+    #[error("failed to compile debug shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, VertexInput, AsBytes)]
+struct DebugVertex {
+    position: Vec3,
+    color: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DebugDrawConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// One vertex buffer per frame so writes never race the GPU
+    pub frames_in_flight: usize,
+    /// Lines past `max_vertices / 2` in a frame are dropped
+    pub max_vertices: u32,
+    /// Tests against the subpass depth attachment without writing it, off draws through geometry
+    ///
+    /// Can be toggled with [`DebugDraw::set_depth_test`] given extended dynamic state.
+    pub depth_test: bool,
+}
+
+impl DebugDrawConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        DebugDrawConfig {
+            render_pass,
+            subpass: 0,
+            frames_in_flight: 2,
+            max_vertices: 65536,
+            depth_test: true,
+        }
+    }
+}
+
+pub struct DebugDraw {
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    vertex_buffers: Vec<DynamicBuffer>,
+    max_vertices: u32,
+    vertices: Vec<DebugVertex>,
+    /// Vertices written by the last [`DebugDraw::prepare`] of each frame
+    counts: Vec<u32>,
+    raster: DynamicRasterState,
+    raster_state: RasterState,
+}
+
+impl DebugDraw {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        compiler: &C,
+        raster: &DynamicRasterState,
+        config: DebugDrawConfig,
+    ) -> Result<Self, DebugDrawError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| DebugDrawError::Compile(Box::new(err)))
+        };
+        let vert = compile(DEBUG_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(DEBUG_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+
+        let mut this = DebugDraw {
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            vertex_buffers: Vec::new(),
+            max_vertices: config.max_vertices,
+            vertices: Vec::new(),
+            counts: vec![0; config.frames_in_flight],
+            raster: raster.clone(),
+            raster_state: RasterState {
+                cull_mode: vk::CullModeFlags::NONE,
+                depth_test: config.depth_test,
+                depth_write: false,
+                ..RasterState::default()
+            },
+        };
+        let result =
+            unsafe { this.create_objects(device, mem_props, limits, &vert, &frag, config) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        vert: &[u32],
+        frag: &[u32],
+        config: DebugDrawConfig,
+    ) -> Result<(), DebugDrawError> {
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: mem::size_of::<Mat4>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "debug pipeline layout");
+
+        let size = config.max_vertices as vk::DeviceSize * mem::size_of::<DebugVertex>() as u64;
+        for _ in 0..config.frames_in_flight {
+            self.vertex_buffers.push(DynamicBuffer::new(
+                device,
+                mem_props,
+                limits,
+                size,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+            )?);
+        }
+
+        let attributes = DebugVertex::attributes(0, 0);
+        reflect::debug_validate(&[vert, frag], &[], &[push_range], &attributes)?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "debug shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "debug shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_bindings = [DebugVertex::binding(0)];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&attributes);
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::LINE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = self.raster_state.rasterization();
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = self.raster_state.depth_stencil();
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = self.raster.dynamic_states();
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "debug lines");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    pub fn line(&mut self, a: Vec3, b: Vec3, color: Vec4) {
+        if self.vertices.len() + 2 <= self.max_vertices as usize {
+            let color = color.to_array();
+            self.vertices.extend([
+                DebugVertex { position: a, color },
+                DebugVertex { position: b, color },
+            ]);
+        }
+    }
+
+    /// The twelve edges of a box
+    pub fn aabb(&mut self, aabb: &Aabb, color: Vec4) {
+        self.corners(&aabb.corners(), color);
+    }
+
+    /// A box under an arbitrary transform, e.g. a node's local bounds
+    pub fn oriented_box(&mut self, aabb: &Aabb, transform: Mat4, color: Vec4) {
+        let corners = aabb
+            .corners()
+            .map(|corner| transform.transform_point3(corner));
+        self.corners(&corners, color);
+    }
+
+    /// Edges between corners differing in exactly one bit, as laid out by [`Aabb::corners`]
+    fn corners(&mut self, corners: &[Vec3; 8], color: Vec4) {
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corners[i], corners[i | bit], color);
+                }
+            }
+        }
+    }
+
+    /// Three circles around the axes
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        self.circle(center, Vec3::X, radius, color);
+        self.circle(center, Vec3::Y, radius, color);
+        self.circle(center, Vec3::Z, radius, color);
+    }
+
+    /// Circle around `normal`
+    pub fn circle(&mut self, center: Vec3, normal: Vec3, radius: f32, color: Vec4) {
+        let (u, v) = normal.normalize().any_orthonormal_pair();
+        let point = |i: u32| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Red, green and blue lines along the x, y and z axes of `transform`
+    pub fn axes(&mut self, transform: Mat4, size: f32) {
+        let origin = transform.transform_point3(Vec3::ZERO);
+        for (axis, color) in [(Vec3::X, Vec4::X), (Vec3::Y, Vec4::Y), (Vec3::Z, Vec4::Z)] {
+            let end = transform.transform_point3(axis * size);
+            self.line(origin, end, color + Vec4::W);
+        }
+    }
+
+    /// Uploads everything queued since the last call into the vertex buffer of `frame`
+    ///
+    /// Must be recorded outside of a render pass, the staged upload path uses a transfer.
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+    ) -> VkResult<()> {
+        self.counts[frame] = self.vertices.len() as u32;
+        if self.vertices.is_empty() {
+            return Ok(());
+        }
+        let result = self.vertex_buffers[frame].write(
+            device,
+            staging,
+            cmd,
+            0,
+            slice_as_bytes(&self.vertices),
+        );
+        self.vertices.clear();
+        result
+    }
+
+    /// Whether the lines are hidden behind geometry from now on, `false` when that is baked into
+    /// the pipeline and stays as configured
+    pub fn set_depth_test(&mut self, enabled: bool) -> bool {
+        if !self.raster.is_extended() {
+            return false;
+        }
+        self.raster_state.depth_test = enabled;
+        true
+    }
+
+    /// Records the lines uploaded by the last [`DebugDraw::prepare`] of `frame`
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer, frame: usize, view_proj: Mat4) {
+        if self.counts[frame] == 0 {
+            return;
+        }
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            self.raster.apply(device, cmd, &self.raster_state);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                view_proj.as_bytes(),
+            );
+            device.cmd_bind_vertex_buffers(
+                cmd,
+                0,
+                &[self.vertex_buffers[frame].buffer.buffer],
+                &[0],
+            );
+            device.cmd_draw(cmd, self.counts[frame], 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded debug draw may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.vertex_buffers.drain(..) {
+            buffer.destroy(device);
+        }
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+    }
+}
@@ -0,0 +1,715 @@
+//! Deferred decals projected onto whatever was drawn inside their box
+//!
+//! Each decal is a unit cube placed by its transform. Its inside faces are drawn, so the decal
+//! still shows with the camera within the box, and every pixel they cover rebuilds the world
+//! position from the scene's depth. Pixels whose position lands inside the box take the decal's
+//! textures, projected down its local y axis, blended over the albedo target and, with
+//! [`DecalConfig::normals`], the normal target of a G-buffer style pass.
+//!
+//! Decal looks are [`Material`]s: [`Decals::create_material`] builds the pipeline and
+//! [`Decals::create_instance`] fills a [`MaterialInstance`] with textures and [`DecalParams`].
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    layout::{AsBytes, ShaderLayout},
+    leaks,
+    material::{Material, MaterialInstance, MaterialTemplateId, Materials, MATERIAL_SET},
+    memory::{usage::MemoryCategory, Buffer},
+    pipeline::dynamic::{DynamicRasterState, RasterState},
+    scene::MaterialId,
+    shader::{reflect, variant::VariantKey, ShaderCompiler},
+    texture::sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+};
+
+/// Inside of the unit cube as a 14 vertex strip, wound counter-clockwise from outside
+pub const DECAL_VERT_GLSL: &str = r#"#version 450
+layout(push_constant) uniform Push {
+    mat4 model;
+    mat4 inv_model;
+} push;
+
+layout(set = 0, binding = 0) uniform Frame {
+    mat4 view_proj;
+    mat4 inv_view_proj;
+    // xy: one over the target size
+    vec4 screen;
+} frame;
+
+void main() {
+    uint bit = 1u << uint(gl_VertexIndex);
+    vec3 corner = vec3(
+        (0x287au & bit) != 0u ? 0.5 : -0.5,
+        (0x02afu & bit) != 0u ? 0.5 : -0.5,
+        (0x31e3u & bit) != 0u ? 0.5 : -0.5
+    );
+    gl_Position = frame.view_proj * push.model * vec4(corner, 1.0);
+}
+"#;
+
+/// Projects the material's textures along the decal's y axis onto the depth buffer's surface
+///
+/// `DECAL_NORMALS` adds a second output with the perturbed world normal packed into 0..1.
+pub const DECAL_FRAG_GLSL: &str = r#"#version 450
+layout(push_constant) uniform Push {
+    mat4 model;
+    mat4 inv_model;
+} push;
+
+layout(set = 0, binding = 0) uniform Frame {
+    mat4 view_proj;
+    mat4 inv_view_proj;
+    vec4 screen;
+} frame;
+layout(set = 0, binding = 1) uniform texture2D scene_depth;
+layout(set = 0, binding = 2) uniform sampler depth_sampler;
+
+layout(set = 1, binding = 0) uniform Params {
+    vec3 tint;
+    float opacity;
+    vec2 atlas_offset;
+    vec2 atlas_scale;
+    float normal_strength;
+    float min_facing;
+    float edge_fade;
+    float alpha_cutoff;
+} params;
+layout(set = 1, binding = 1) uniform texture2D albedo_map;
+layout(set = 1, binding = 2) uniform texture2D normal_map;
+layout(set = 1, binding = 3) uniform sampler material_sampler;
+
+layout(location = 0) out vec4 out_albedo;
+#ifdef DECAL_NORMALS
+layout(location = 1) out vec4 out_normal;
+#endif
+
+void main() {
+    vec2 uv = gl_FragCoord.xy * frame.screen.xy;
+    float depth = texture(sampler2D(scene_depth, depth_sampler), uv).r;
+    vec4 world = frame.inv_view_proj * vec4(uv * 2.0 - 1.0, depth, 1.0);
+    world /= world.w;
+    vec3 local = (push.inv_model * world).xyz;
+    if (abs(local.x) > 0.5 || abs(local.y) > 0.5 || abs(local.z) > 0.5) {
+        discard;
+    }
+
+    // Facing the camera, from how the surface's position changes across neighbouring pixels
+    vec3 normal = normalize(cross(dFdy(world.xyz), dFdx(world.xyz)));
+    vec3 up = normalize(push.model[1].xyz);
+    float facing = smoothstep(params.min_facing, mix(params.min_facing, 1.0, 0.25), dot(normal, up));
+    float edge = 1.0 - smoothstep(0.5 - params.edge_fade, 0.5, abs(local.y));
+    vec2 decal_uv = params.atlas_offset + (local.xz + 0.5) * params.atlas_scale;
+    vec4 albedo = texture(sampler2D(albedo_map, material_sampler), decal_uv);
+    float alpha = albedo.a * params.opacity * facing * edge;
+    if (alpha <= params.alpha_cutoff) {
+        discard;
+    }
+    out_albedo = vec4(albedo.rgb * params.tint, alpha);
+
+#ifdef DECAL_NORMALS
+    vec3 tangent = normalize(push.model[0].xyz);
+    tangent = normalize(tangent - normal * dot(normal, tangent));
+    vec3 bitangent = cross(normal, tangent);
+    vec3 bump = texture(sampler2D(normal_map, material_sampler), decal_uv).xyz * 2.0 - 1.0;
+    bump.xy *= params.normal_strength;
+    vec3 perturbed = normalize(tangent * bump.x + bitangent * bump.y + normal * bump.z);
+    out_normal = vec4(perturbed * 0.5 + 0.5, alpha);
+#endif
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum DecalError {
+    #[error("failed to compile decal shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DecalConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Whether the subpass has a normal target after the albedo one for decals to blend into
+    pub normals: bool,
+}
+
+impl DecalConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        DecalConfig {
+            render_pass,
+            subpass: 0,
+            normals: false,
+        }
+    }
+
+    pub fn with_normals(mut self) -> Self {
+        self.normals = true;
+        self
+    }
+}
+
+/// Parameter block of a decal [`MaterialInstance`]
+#[derive(Debug, Clone, Copy, PartialEq, ShaderLayout, AsBytes)]
+#[layout(std140)]
+#[repr(C)]
+pub struct DecalParams {
+    pub tint: Vec3,
+    /// Multiplies the albedo's alpha, e.g. to fade old bullet holes out
+    pub opacity: f32,
+    /// Corner and size of the decal's part of an atlas, in texture coordinates
+    pub atlas_offset: Vec2,
+    pub atlas_scale: Vec2,
+    pub normal_strength: f32,
+    /// Surfaces facing the decal's y axis less than this cosine are left alone
+    pub min_facing: f32,
+    /// Fraction of the box's height faded out towards its top and bottom
+    pub edge_fade: f32,
+    /// Coverage at or below this is left out entirely, for crisp edges
+    pub alpha_cutoff: f32,
+}
+
+impl Default for DecalParams {
+    fn default() -> Self {
+        DecalParams {
+            tint: Vec3::ONE,
+            opacity: 1.0,
+            atlas_offset: Vec2::ZERO,
+            atlas_scale: Vec2::ONE,
+            normal_strength: 1.0,
+            min_facing: 0.2,
+            edge_fade: 0.1,
+            alpha_cutoff: 0.0,
+        }
+    }
+}
+
+/// Images of a decal material, both in `SHADER_READ_ONLY_OPTIMAL`
+#[derive(Debug, Clone, Copy)]
+pub struct DecalTextures {
+    /// Color with coverage in alpha
+    pub albedo: vk::ImageView,
+    /// Tangent space, ignored without [`DecalConfig::normals`]
+    pub normal: vk::ImageView,
+}
+
+/// A placed decal, projecting down the y axis of its unit box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decal {
+    pub transform: Mat4,
+    pub material: MaterialId,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct FrameBlock {
+    view_proj: Mat4,
+    inv_view_proj: Mat4,
+    screen: Vec4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct DecalPush {
+    model: Mat4,
+    inv_model: Mat4,
+}
+
+pub struct Decals {
+    frame_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    frame_set: vk::DescriptorSet,
+    frame: Option<Buffer>,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    vert: Vec<u32>,
+    frag: Vec<u32>,
+    variant: VariantKey,
+    config: DecalConfig,
+    raster: DynamicRasterState,
+}
+
+impl Decals {
+    /// `depth` is a depth aspect view of the scene's depth, read back for the positions
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        raster: &DynamicRasterState,
+        depth: vk::ImageView,
+        config: DecalConfig,
+    ) -> Result<Self, DecalError> {
+        let variant = if config.normals {
+            VariantKey::new().enable("DECAL_NORMALS")
+        } else {
+            VariantKey::new()
+        };
+        let compile = |source: &str, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| DecalError::Compile(Box::new(err)))
+        };
+        let vert = compile(DECAL_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(
+            &variant.apply(DECAL_FRAG_GLSL),
+            vk::ShaderStageFlags::FRAGMENT,
+        )?;
+        // Depth is read exactly, never blended between texels
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Nearest)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = Decals {
+            frame_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            frame_set: vk::DescriptorSet::null(),
+            frame: None,
+            sampler,
+            vert,
+            frag,
+            variant,
+            config,
+            raster: raster.clone(),
+        };
+        let result = unsafe {
+            this.create_objects(device, mem_props)
+                .map(|_| this.set_depth(device, depth))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err.into())
+            }
+        }
+    }
+
+    /// The frame set holds the camera uniforms at binding 0 and the scene depth and its sampler
+    /// at 1 and 2
+    fn frame_bindings() -> [vk::DescriptorSetLayoutBinding; 3] {
+        [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ]
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> VkResult<()> {
+        let bindings = Self::frame_bindings();
+        self.frame_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.frame_layout, "decal descriptor set layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::UNIFORM_BUFFER,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "decal descriptor pool");
+        self.frame_set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.frame_layout]),
+        )?[0];
+
+        let mut frame = Buffer::new(
+            device,
+            mem_props,
+            mem::size_of::<FrameBlock>() as vk::DeviceSize,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        frame.set_category(MemoryCategory::Uniform);
+        let buffer_info = [vk::DescriptorBufferInfo {
+            buffer: frame.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let sampler_info = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.frame_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&buffer_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.frame_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler_info)
+                .build(),
+        ];
+        device.update_descriptor_sets(&writes, &[]);
+        self.frame = Some(frame);
+        Ok(())
+    }
+
+    /// Points the decals at a new scene depth, e.g. after a resize
+    ///
+    /// `depth` must be in `SHADER_READ_ONLY_OPTIMAL` when the decals are drawn.
+    ///
+    /// # Safety
+    ///
+    /// No recorded decal draw may still be executing.
+    pub unsafe fn set_depth(&self, device: &Device, depth: vk::ImageView) {
+        let info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: depth,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.frame_set)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&info)
+            .build();
+        device.update_descriptor_sets(&[write], &[]);
+    }
+
+    /// Builds the pipeline of a decal look, to be added to [`Materials`]
+    ///
+    /// The material's set layout holds [`DecalParams`] at binding 0, the albedo and normal images
+    /// at 1 and 2 and their sampler at 3. Its pipeline layout includes the frame set, so the
+    /// material must be destroyed before these decals are.
+    pub fn create_material(
+        &self,
+        device: &Device,
+        name: impl Into<String>,
+    ) -> Result<Material, DecalError> {
+        let types = [
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        let set_layout = unsafe {
+            device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                None,
+            )?
+        };
+        leaks::track(set_layout, "decal descriptor set layout");
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: mem::size_of::<DecalPush>() as u32,
+        };
+        reflect::debug_validate(
+            &[&self.vert, &self.frag],
+            &[&Self::frame_bindings(), &bindings],
+            &[push_range],
+            &[],
+        )?;
+        let layout = match unsafe {
+            crate::pipeline::create_pipeline_layout(
+                device,
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(&[self.frame_layout, set_layout])
+                    .push_constant_ranges(&[push_range]),
+            )
+        } {
+            Ok(layout) => layout,
+            Err(err) => {
+                leaks::untrack(set_layout);
+                unsafe { device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(err.into());
+            }
+        };
+        leaks::track(layout, "decal pipeline layout");
+        let name = name.into();
+        match unsafe { self.create_pipeline(device, layout, &name) } {
+            Ok(pipeline) => Ok(Material {
+                name,
+                variant: self.variant.clone(),
+                pipeline,
+                raster: Self::raster_state(),
+                layout,
+                set_layout,
+                params_size: mem::size_of::<DecalParams>() as vk::DeviceSize,
+            }),
+            Err(err) => {
+                unsafe {
+                    leaks::untrack(layout);
+                    device.destroy_pipeline_layout(layout, None);
+                    leaks::untrack(set_layout);
+                    device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Only the far side of the box, which stays on screen with the camera inside it, drawn
+    /// without depth testing since the shader reads the scene depth itself
+    fn raster_state() -> RasterState {
+        RasterState {
+            cull_mode: vk::CullModeFlags::FRONT,
+            depth_test: false,
+            depth_write: false,
+            ..RasterState::default()
+        }
+    }
+
+    unsafe fn create_pipeline(
+        &self,
+        device: &Device,
+        layout: vk::PipelineLayout,
+        name: &str,
+    ) -> VkResult<vk::Pipeline> {
+        let vert = device.create_shader_module(
+            &vk::ShaderModuleCreateInfo::builder().code(&self.vert),
+            None,
+        )?;
+        leaks::track(vert, "decal shader module");
+        let frag = match device.create_shader_module(
+            &vk::ShaderModuleCreateInfo::builder().code(&self.frag),
+            None,
+        ) {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err);
+            }
+        };
+        leaks::track(frag, "decal shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let raster = Self::raster_state();
+        let rasterization = raster.rasterization();
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = raster.depth_stencil();
+        // Alpha stays as the surface left it
+        let blend_attachment = vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build();
+        let targets = if self.config.normals { 2 } else { 1 };
+        let blend_attachments = vec![blend_attachment; targets];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        // Materials reapply their raster state through it after binding
+        let dynamic_states = self.raster.dynamic_states();
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(layout)
+            .render_pass(self.config.render_pass)
+            .subpass(self.config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], &format!("decal {name}"));
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        Ok(pipelines.map_err(|(_, err)| err)?[0])
+    }
+
+    /// Allocates an instance of a decal material from `pool` and fills its set
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_instance(
+        &self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        pool: vk::DescriptorPool,
+        material: &Material,
+        id: MaterialTemplateId,
+        textures: DecalTextures,
+        sampler: vk::Sampler,
+        params: &DecalParams,
+    ) -> VkResult<MaterialInstance> {
+        let instance = MaterialInstance::new(device, mem_props, pool, material, id)?;
+        instance.write_params(params);
+        instance.set_image(device, 1, textures.albedo);
+        instance.set_image(device, 2, textures.normal);
+        instance.set_sampler(device, 3, sampler);
+        Ok(instance)
+    }
+
+    /// Writes the camera for the next draw, the device must not be reading the previous one
+    pub fn set_camera(&self, view_proj: Mat4, extent: vk::Extent2D) {
+        let Some(frame) = &self.frame else {
+            return;
+        };
+        let block = FrameBlock {
+            view_proj,
+            inv_view_proj: view_proj.inverse(),
+            screen: Vec4::new(
+                1.0 / extent.width as f32,
+                1.0 / extent.height as f32,
+                0.0,
+                0.0,
+            ),
+        };
+        let mapped = frame.mapped().expect("frame block is host visible");
+        unsafe { block.write_to(mapped.as_ptr()) };
+    }
+
+    /// Records `decals` in order, later ones landing on top of earlier ones
+    ///
+    /// Goes after the opaque geometry, with the depth it wrote no longer bound for writing.
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        materials: &Materials,
+        decals: &[Decal],
+    ) {
+        let mut bound_pipeline = vk::Pipeline::null();
+        let mut bound_material = None;
+        for decal in decals {
+            let instance = materials.instance(decal.material);
+            let material = materials.material(instance.material);
+            unsafe {
+                if material.pipeline != bound_pipeline {
+                    device.cmd_bind_pipeline(
+                        cmd,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        material.pipeline,
+                    );
+                    self.raster.apply(device, cmd, &material.raster);
+                    device.cmd_bind_descriptor_sets(
+                        cmd,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        material.layout,
+                        0,
+                        &[self.frame_set],
+                        &[],
+                    );
+                    bound_pipeline = material.pipeline;
+                    bound_material = None;
+                }
+                if bound_material != Some(decal.material) {
+                    device.cmd_bind_descriptor_sets(
+                        cmd,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        material.layout,
+                        MATERIAL_SET,
+                        &[instance.set],
+                        &[],
+                    );
+                    bound_material = Some(decal.material);
+                }
+                let push = DecalPush {
+                    model: decal.transform,
+                    inv_model: decal.transform.inverse(),
+                };
+                device.cmd_push_constants(
+                    cmd,
+                    material.layout,
+                    vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                    0,
+                    push.as_bytes(),
+                );
+                device.cmd_draw(cmd, 14, 1, 0, 0);
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded decal draw may still be executing, and the decal materials must already be
+    /// destroyed.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        if let Some(frame) = self.frame.take() {
+            frame.destroy(device);
+        }
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.frame_layout);
+        device.destroy_descriptor_set_layout(self.frame_layout, None);
+    }
+}
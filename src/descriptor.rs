@@ -0,0 +1,552 @@
+//! Allocating, writing and binding descriptor sets behind one interface
+//!
+//! [`Descriptors`] either allocates sets from a classic pool, or with `VK_EXT_descriptor_buffer`
+//! writes descriptors straight into a host visible buffer bound once per command buffer, which
+//! skips the pool bookkeeping and lets the driver read descriptors like any other memory. Set
+//! layouts and pipelines need [`Descriptors::set_layout_flags`] and
+//! [`Descriptors::pipeline_flags`] to work with either. Bindings changing every draw are better
+//! off in [`push::PerDrawDescriptors`].
+
+use ash::{extensions::ext, prelude::VkResult, vk, Device, Instance};
+
+pub mod push;
+
+use crate::{device::EnabledFeatures, leaks, memory::Buffer, trace::traced};
+
+/// How descriptors are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Sets,
+    Buffer,
+}
+
+/// One descriptor to write, buffers read through the buffer backend need
+/// `SHADER_DEVICE_ADDRESS` usage and an explicit range
+#[derive(Debug, Clone, Copy)]
+pub enum Descriptor {
+    UniformBuffer(vk::DescriptorBufferInfo),
+    StorageBuffer(vk::DescriptorBufferInfo),
+    CombinedImageSampler(vk::DescriptorImageInfo),
+    SampledImage(vk::DescriptorImageInfo),
+    StorageImage(vk::DescriptorImageInfo),
+    /// Only the sampler is read
+    Sampler(vk::DescriptorImageInfo),
+}
+
+impl Descriptor {
+    pub fn ty(&self) -> vk::DescriptorType {
+        match self {
+            Descriptor::UniformBuffer(_) => vk::DescriptorType::UNIFORM_BUFFER,
+            Descriptor::StorageBuffer(_) => vk::DescriptorType::STORAGE_BUFFER,
+            Descriptor::CombinedImageSampler(_) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            Descriptor::SampledImage(_) => vk::DescriptorType::SAMPLED_IMAGE,
+            Descriptor::StorageImage(_) => vk::DescriptorType::STORAGE_IMAGE,
+            Descriptor::Sampler(_) => vk::DescriptorType::SAMPLER,
+        }
+    }
+
+    pub fn sampler(sampler: vk::Sampler) -> Self {
+        Descriptor::Sampler(vk::DescriptorImageInfo {
+            sampler,
+            ..Default::default()
+        })
+    }
+
+    /// A write of this to `binding` of `set`, pointing into `self`
+    fn write_info(&self, set: vk::DescriptorSet, binding: u32) -> vk::WriteDescriptorSet {
+        let write = vk::WriteDescriptorSet {
+            dst_set: set,
+            dst_binding: binding,
+            descriptor_count: 1,
+            descriptor_type: self.ty(),
+            ..Default::default()
+        };
+        match self {
+            Descriptor::UniformBuffer(info) | Descriptor::StorageBuffer(info) => {
+                vk::WriteDescriptorSet {
+                    p_buffer_info: info,
+                    ..write
+                }
+            }
+            Descriptor::CombinedImageSampler(info)
+            | Descriptor::SampledImage(info)
+            | Descriptor::StorageImage(info)
+            | Descriptor::Sampler(info) => vk::WriteDescriptorSet {
+                p_image_info: info,
+                ..write
+            },
+        }
+    }
+}
+
+/// A set allocated from [`Descriptors`], valid until it is reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorSet {
+    Set(vk::DescriptorSet),
+    Buffer {
+        layout: vk::DescriptorSetLayout,
+        offset: vk::DeviceSize,
+    },
+}
+
+enum Backend {
+    Sets {
+        pool: vk::DescriptorPool,
+    },
+    Buffer {
+        ext: ext::DescriptorBuffer,
+        properties: Box<vk::PhysicalDeviceDescriptorBufferPropertiesEXT>,
+        buffer: Buffer,
+        address: vk::DeviceAddress,
+        used: vk::DeviceSize,
+    },
+}
+
+pub struct Descriptors {
+    backend: Backend,
+}
+
+impl Descriptors {
+    /// Room for `max_sets` sets holding `pool_sizes` descriptors in total
+    ///
+    /// `prefer` is only honored when [`crate::device::Capabilities::descriptor_buffer`] allows.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        instance: &Instance,
+        device: &Device,
+        physical_device: vk::PhysicalDevice,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        enabled: &EnabledFeatures,
+        prefer: BackendKind,
+        max_sets: u32,
+        pool_sizes: &[vk::DescriptorPoolSize],
+    ) -> VkResult<Self> {
+        if prefer == BackendKind::Sets || !enabled.capabilities().descriptor_buffer {
+            let pool_info = vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(max_sets)
+                .pool_sizes(pool_sizes);
+            let pool = unsafe {
+                traced!(
+                    "vkCreateDescriptorPool",
+                    max_sets = max_sets;
+                    device.create_descriptor_pool(&pool_info, None)
+                )?
+            };
+            leaks::track(pool, "descriptor pool");
+            return Ok(Descriptors {
+                backend: Backend::Sets { pool },
+            });
+        }
+
+        let mut properties = vk::PhysicalDeviceDescriptorBufferPropertiesEXT::default();
+        let mut properties2 = vk::PhysicalDeviceProperties2::builder().push_next(&mut properties);
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut properties2) };
+        properties.p_next = std::ptr::null_mut();
+
+        let size = pool_sizes
+            .iter()
+            .map(|size| size.descriptor_count as usize * descriptor_size(&properties, size.ty))
+            .sum::<usize>() as vk::DeviceSize
+            + max_sets as vk::DeviceSize * properties.descriptor_buffer_offset_alignment;
+        let buffer = Buffer::new(
+            device,
+            mem_props,
+            size.max(1),
+            vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT
+                | vk::BufferUsageFlags::SAMPLER_DESCRIPTOR_BUFFER_EXT
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let address = buffer.device_address(device);
+        Ok(Descriptors {
+            backend: Backend::Buffer {
+                ext: ext::DescriptorBuffer::new(instance, device),
+                properties: Box::new(properties),
+                buffer,
+                address,
+                used: 0,
+            },
+        })
+    }
+
+    pub fn kind(&self) -> BackendKind {
+        match self.backend {
+            Backend::Sets { .. } => BackendKind::Sets,
+            Backend::Buffer { .. } => BackendKind::Buffer,
+        }
+    }
+
+    /// Flags every set layout allocated from here has to be created with
+    pub fn set_layout_flags(&self) -> vk::DescriptorSetLayoutCreateFlags {
+        match self.backend {
+            Backend::Sets { .. } => vk::DescriptorSetLayoutCreateFlags::empty(),
+            Backend::Buffer { .. } => vk::DescriptorSetLayoutCreateFlags::DESCRIPTOR_BUFFER_EXT,
+        }
+    }
+
+    /// Flags every pipeline binding sets from here has to be created with
+    pub fn pipeline_flags(&self) -> vk::PipelineCreateFlags {
+        match self.backend {
+            Backend::Sets { .. } => vk::PipelineCreateFlags::empty(),
+            Backend::Buffer { .. } => vk::PipelineCreateFlags::DESCRIPTOR_BUFFER_EXT,
+        }
+    }
+
+    /// `ERROR_OUT_OF_POOL_MEMORY` once the room given at creation is used up
+    ///
+    /// # Safety
+    ///
+    /// `layout` must have been created with [`Descriptors::set_layout_flags`].
+    pub unsafe fn allocate(
+        &mut self,
+        device: &Device,
+        layout: vk::DescriptorSetLayout,
+    ) -> VkResult<DescriptorSet> {
+        match &mut self.backend {
+            Backend::Sets { pool } => {
+                let set_layouts = [layout];
+                let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(*pool)
+                    .set_layouts(&set_layouts);
+                let sets = traced!(
+                    "vkAllocateDescriptorSets",
+                    pool = *pool,
+                    layout = layout;
+                    device.allocate_descriptor_sets(&alloc_info)
+                )?;
+                Ok(DescriptorSet::Set(sets[0]))
+            }
+            Backend::Buffer {
+                ext,
+                properties,
+                buffer,
+                used,
+                ..
+            } => {
+                let offset = used.next_multiple_of(properties.descriptor_buffer_offset_alignment);
+                let end = offset + ext.get_descriptor_set_layout_size(layout);
+                if end > buffer.size {
+                    return Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY);
+                }
+                *used = end;
+                Ok(DescriptorSet::Buffer { layout, offset })
+            }
+        }
+    }
+
+    /// Writes `descriptor` to `binding` of `set`, which the device must not be reading
+    ///
+    /// # Safety
+    ///
+    /// `set` must come from this allocator and its layout have `binding` of the descriptor's type.
+    pub unsafe fn write(
+        &self,
+        device: &Device,
+        set: DescriptorSet,
+        binding: u32,
+        descriptor: &Descriptor,
+    ) {
+        match (&self.backend, set) {
+            (Backend::Sets { .. }, DescriptorSet::Set(set)) => {
+                traced!(
+                    "vkUpdateDescriptorSets",
+                    set = set,
+                    binding = binding,
+                    ty = descriptor.ty();
+                    device.update_descriptor_sets(&[descriptor.write_info(set, binding)], &[])
+                );
+            }
+            (
+                Backend::Buffer {
+                    ext,
+                    properties,
+                    buffer,
+                    ..
+                },
+                DescriptorSet::Buffer { layout, offset },
+            ) => {
+                let offset = offset + ext.get_descriptor_set_layout_binding_offset(layout, binding);
+                let size = descriptor_size(properties, descriptor.ty());
+                let dst = std::slice::from_raw_parts_mut(
+                    buffer
+                        .mapped()
+                        .unwrap()
+                        .as_ptr()
+                        .cast::<u8>()
+                        .add(offset as usize),
+                    size,
+                );
+                let address_info;
+                let data = match descriptor {
+                    Descriptor::UniformBuffer(info) | Descriptor::StorageBuffer(info) => {
+                        let buffer_info =
+                            vk::BufferDeviceAddressInfo::builder().buffer(info.buffer);
+                        address_info = vk::DescriptorAddressInfoEXT {
+                            address: device.get_buffer_device_address(&buffer_info) + info.offset,
+                            range: info.range,
+                            ..Default::default()
+                        };
+                        if let Descriptor::UniformBuffer(_) = descriptor {
+                            vk::DescriptorDataEXT {
+                                p_uniform_buffer: &address_info,
+                            }
+                        } else {
+                            vk::DescriptorDataEXT {
+                                p_storage_buffer: &address_info,
+                            }
+                        }
+                    }
+                    Descriptor::CombinedImageSampler(info) => vk::DescriptorDataEXT {
+                        p_combined_image_sampler: info,
+                    },
+                    Descriptor::SampledImage(info) => vk::DescriptorDataEXT {
+                        p_sampled_image: info,
+                    },
+                    Descriptor::StorageImage(info) => vk::DescriptorDataEXT {
+                        p_storage_image: info,
+                    },
+                    Descriptor::Sampler(info) => vk::DescriptorDataEXT {
+                        p_sampler: &info.sampler,
+                    },
+                };
+                let info = vk::DescriptorGetInfoEXT {
+                    ty: descriptor.ty(),
+                    data,
+                    ..Default::default()
+                };
+                traced!(
+                    "vkGetDescriptorEXT",
+                    layout = layout,
+                    binding = binding,
+                    ty = descriptor.ty();
+                    ext.get_descriptor(&info, dst)
+                );
+            }
+            _ => panic!("descriptor set from another backend"),
+        }
+    }
+
+    /// Makes the descriptor buffer available to `cmd`, once before any [`Descriptors::bind`]
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording.
+    pub unsafe fn begin(&self, cmd: vk::CommandBuffer) {
+        if let Backend::Buffer { ext, address, .. } = &self.backend {
+            let binding = vk::DescriptorBufferBindingInfoEXT::builder()
+                .address(*address)
+                .usage(
+                    vk::BufferUsageFlags::RESOURCE_DESCRIPTOR_BUFFER_EXT
+                        | vk::BufferUsageFlags::SAMPLER_DESCRIPTOR_BUFFER_EXT,
+                )
+                .build();
+            ext.cmd_bind_descriptor_buffers(cmd, &[binding]);
+        }
+    }
+
+    /// Binds `sets` starting at `first_set`, like `vkCmdBindDescriptorSets`
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording, after [`Descriptors::begin`], and `layout` be compatible with
+    /// the sets' layouts.
+    pub unsafe fn bind(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        sets: &[DescriptorSet],
+    ) {
+        match &self.backend {
+            Backend::Sets { .. } => {
+                let sets: Vec<vk::DescriptorSet> = sets
+                    .iter()
+                    .map(|set| match set {
+                        DescriptorSet::Set(set) => *set,
+                        DescriptorSet::Buffer { .. } => {
+                            panic!("descriptor set from another backend")
+                        }
+                    })
+                    .collect();
+                device.cmd_bind_descriptor_sets(cmd, bind_point, layout, first_set, &sets, &[]);
+            }
+            Backend::Buffer { ext, .. } => {
+                let offsets: Vec<vk::DeviceSize> = sets
+                    .iter()
+                    .map(|set| match set {
+                        DescriptorSet::Buffer { offset, .. } => *offset,
+                        DescriptorSet::Set(_) => panic!("descriptor set from another backend"),
+                    })
+                    .collect();
+                let indices = vec![0; offsets.len()];
+                ext.cmd_set_descriptor_buffer_offsets(
+                    cmd, bind_point, layout, first_set, &indices, &offsets,
+                );
+            }
+        }
+    }
+
+    /// Frees every set at once
+    ///
+    /// # Safety
+    ///
+    /// None of the sets may still be in use by the device.
+    pub unsafe fn reset(&mut self, device: &Device) -> VkResult<()> {
+        match &mut self.backend {
+            Backend::Sets { pool } => traced!(
+                "vkResetDescriptorPool",
+                pool = *pool;
+                device.reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())
+            ),
+            Backend::Buffer { used, .. } => {
+                *used = 0;
+                Ok(())
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// None of the sets may still be in use by the device.
+    pub unsafe fn destroy(&self, device: &Device) {
+        match &self.backend {
+            Backend::Sets { pool } => {
+                leaks::untrack(*pool);
+                traced!(
+                    "vkDestroyDescriptorPool",
+                    pool = *pool;
+                    device.destroy_descriptor_pool(*pool, None)
+                );
+            }
+            Backend::Buffer { buffer, .. } => buffer.destroy(device),
+        }
+    }
+}
+
+/// Bytes one descriptor of `ty` takes in a descriptor buffer
+fn descriptor_size(
+    properties: &vk::PhysicalDeviceDescriptorBufferPropertiesEXT,
+    ty: vk::DescriptorType,
+) -> usize {
+    match ty {
+        vk::DescriptorType::UNIFORM_BUFFER => properties.uniform_buffer_descriptor_size,
+        vk::DescriptorType::STORAGE_BUFFER => properties.storage_buffer_descriptor_size,
+        vk::DescriptorType::COMBINED_IMAGE_SAMPLER => {
+            properties.combined_image_sampler_descriptor_size
+        }
+        vk::DescriptorType::SAMPLED_IMAGE => properties.sampled_image_descriptor_size,
+        vk::DescriptorType::STORAGE_IMAGE => properties.storage_image_descriptor_size,
+        vk::DescriptorType::SAMPLER => properties.sampler_descriptor_size,
+        vk::DescriptorType::UNIFORM_TEXEL_BUFFER => properties.uniform_texel_buffer_descriptor_size,
+        vk::DescriptorType::STORAGE_TEXEL_BUFFER => properties.storage_texel_buffer_descriptor_size,
+        vk::DescriptorType::INPUT_ATTACHMENT => properties.input_attachment_descriptor_size,
+        _ => properties
+            .storage_buffer_descriptor_size
+            .max(properties.combined_image_sampler_descriptor_size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::HeadlessContext;
+
+    fn uniform_layout(
+        ctx: &HeadlessContext,
+        flags: vk::DescriptorSetLayoutCreateFlags,
+    ) -> vk::DescriptorSetLayout {
+        let bindings = [vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::ALL)
+            .build()];
+        let info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(flags)
+            .bindings(&bindings);
+        unsafe {
+            ctx.device
+                .create_descriptor_set_layout(&info, None)
+                .unwrap()
+        }
+    }
+
+    #[test]
+    fn sets_run_out_and_come_back_on_reset() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: 2,
+        }];
+        let mut descriptors = Descriptors::new(
+            &ctx.instance,
+            &ctx.device,
+            ctx.physical_device,
+            &ctx.mem_props,
+            &ctx.enabled,
+            BackendKind::Sets,
+            2,
+            &pool_sizes,
+        )
+        .unwrap();
+        assert_eq!(descriptors.kind(), BackendKind::Sets);
+        let layout = uniform_layout(&ctx, descriptors.set_layout_flags());
+        let uniforms = Buffer::new(
+            &ctx.device,
+            &ctx.mem_props,
+            256,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE,
+        )
+        .unwrap();
+
+        unsafe {
+            let first = descriptors.allocate(&ctx.device, layout).unwrap();
+            let second = descriptors.allocate(&ctx.device, layout).unwrap();
+            assert_ne!(first, second);
+            let descriptor = Descriptor::UniformBuffer(vk::DescriptorBufferInfo {
+                buffer: uniforms.buffer,
+                offset: 0,
+                range: 256,
+            });
+            descriptors.write(&ctx.device, first, 0, &descriptor);
+            assert!(descriptors.allocate(&ctx.device, layout).is_err());
+
+            descriptors.reset(&ctx.device).unwrap();
+            assert!(descriptors.allocate(&ctx.device, layout).is_ok());
+
+            descriptors.destroy(&ctx.device);
+            uniforms.destroy(&ctx.device);
+            ctx.device.destroy_descriptor_set_layout(layout, None);
+        }
+    }
+
+    #[test]
+    fn buffer_backend_falls_back_to_sets() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        // The test context doesn't enable VK_EXT_descriptor_buffer
+        let descriptors = Descriptors::new(
+            &ctx.instance,
+            &ctx.device,
+            ctx.physical_device,
+            &ctx.mem_props,
+            &ctx.enabled,
+            BackendKind::Buffer,
+            1,
+            &[vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::UNIFORM_BUFFER,
+                descriptor_count: 1,
+            }],
+        )
+        .unwrap();
+        assert_eq!(descriptors.kind(), BackendKind::Sets);
+        assert!(descriptors.set_layout_flags().is_empty());
+        unsafe { descriptors.destroy(&ctx.device) };
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-draw bindings without descriptor pool churn
+//!
+//! With `VK_KHR_push_descriptor` bindings that change every draw, like the textures of a
+//! material in a simple scene, are recorded straight into the command buffer. Without it they go
+//! into sets from one pool per frame in flight, reset as a whole when the frame comes around.
+
+use ash::{extensions::khr, prelude::VkResult, vk, Device, Instance};
+
+use super::Descriptor;
+use crate::{device::EnabledFeatures, leaks, trace::traced};
+
+enum Backend {
+    Push(khr::PushDescriptor),
+    Sets {
+        pools: Vec<vk::DescriptorPool>,
+        frame: usize,
+    },
+}
+
+pub struct PerDrawDescriptors {
+    backend: Backend,
+}
+
+impl PerDrawDescriptors {
+    /// The fallback pools have room for `max_sets` sets holding `pool_sizes` per frame
+    pub fn new(
+        instance: &Instance,
+        device: &Device,
+        enabled: &EnabledFeatures,
+        frames_in_flight: usize,
+        max_sets: u32,
+        pool_sizes: &[vk::DescriptorPoolSize],
+    ) -> VkResult<Self> {
+        if enabled.has_extension(vk::KhrPushDescriptorFn::name()) {
+            return Ok(PerDrawDescriptors {
+                backend: Backend::Push(khr::PushDescriptor::new(instance, device)),
+            });
+        }
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(max_sets)
+            .pool_sizes(pool_sizes);
+        let mut pools = Vec::with_capacity(frames_in_flight);
+        for _ in 0..frames_in_flight {
+            let pool = unsafe {
+                traced!(
+                    "vkCreateDescriptorPool",
+                    max_sets = max_sets;
+                    device.create_descriptor_pool(&pool_info, None)
+                )
+            };
+            match pool {
+                Ok(pool) => {
+                    leaks::track(pool, "push descriptor pool");
+                    pools.push(pool);
+                }
+                Err(err) => {
+                    for pool in pools {
+                        leaks::untrack(pool);
+                        unsafe {
+                            traced!(
+                                "vkDestroyDescriptorPool",
+                                pool = pool;
+                                device.destroy_descriptor_pool(pool, None)
+                            )
+                        };
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(PerDrawDescriptors {
+            backend: Backend::Sets { pools, frame: 0 },
+        })
+    }
+
+    pub fn is_push(&self) -> bool {
+        matches!(self.backend, Backend::Push(_))
+    }
+
+    /// Flags the set layouts of [`PerDrawDescriptors::bind`] have to be created with
+    pub fn set_layout_flags(&self) -> vk::DescriptorSetLayoutCreateFlags {
+        match self.backend {
+            Backend::Push(_) => vk::DescriptorSetLayoutCreateFlags::PUSH_DESCRIPTOR_KHR,
+            Backend::Sets { .. } => vk::DescriptorSetLayoutCreateFlags::empty(),
+        }
+    }
+
+    /// Frees the sets of `frame`, whose previous submission must have finished
+    ///
+    /// # Safety
+    ///
+    /// `frame` must be below the frames in flight given at creation.
+    pub unsafe fn begin_frame(&mut self, device: &Device, frame: usize) -> VkResult<()> {
+        match &mut self.backend {
+            Backend::Push(_) => Ok(()),
+            Backend::Sets {
+                pools,
+                frame: current,
+            } => {
+                *current = frame;
+                traced!(
+                    "vkResetDescriptorPool",
+                    pool = pools[frame];
+                    device.reset_descriptor_pool(
+                        pools[frame],
+                        vk::DescriptorPoolResetFlags::empty(),
+                    )
+                )
+            }
+        }
+    }
+
+    /// Binds `descriptors`, each at its binding, as set `set` of `layout`
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording, and `set_layout` be set `set` of `layout`, created with
+    /// [`PerDrawDescriptors::set_layout_flags`] and holding every given binding.
+    #[allow(clippy::too_many_arguments)]
+    pub unsafe fn bind(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        set_layout: vk::DescriptorSetLayout,
+        set: u32,
+        descriptors: &[(u32, Descriptor)],
+    ) -> VkResult<()> {
+        match &self.backend {
+            Backend::Push(ext) => {
+                let writes: Vec<vk::WriteDescriptorSet> = descriptors
+                    .iter()
+                    .map(|(binding, descriptor)| {
+                        descriptor.write_info(vk::DescriptorSet::null(), *binding)
+                    })
+                    .collect();
+                traced!(
+                    "vkCmdPushDescriptorSetKHR",
+                    layout = layout,
+                    set = set,
+                    writes = writes.len();
+                    ext.cmd_push_descriptor_set(cmd, bind_point, layout, set, &writes)
+                );
+            }
+            Backend::Sets { pools, frame } => {
+                let set_layouts = [set_layout];
+                let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pools[*frame])
+                    .set_layouts(&set_layouts);
+                let descriptor_set = traced!(
+                    "vkAllocateDescriptorSets",
+                    pool = pools[*frame],
+                    layout = set_layout;
+                    device.allocate_descriptor_sets(&alloc_info)
+                )?[0];
+                let writes: Vec<vk::WriteDescriptorSet> = descriptors
+                    .iter()
+                    .map(|(binding, descriptor)| descriptor.write_info(descriptor_set, *binding))
+                    .collect();
+                traced!(
+                    "vkUpdateDescriptorSets",
+                    set = descriptor_set,
+                    writes = writes.len();
+                    device.update_descriptor_sets(&writes, &[])
+                );
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    bind_point,
+                    layout,
+                    set,
+                    &[descriptor_set],
+                    &[],
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// None of the frames may still be in use by the device.
+    pub unsafe fn destroy(&self, device: &Device) {
+        if let Backend::Sets { pools, .. } = &self.backend {
+            for &pool in pools {
+                leaks::untrack(pool);
+                traced!(
+                    "vkDestroyDescriptorPool",
+                    pool = pool;
+                    device.destroy_descriptor_pool(pool, None)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Physical device queries and logical device setup
+//!
+//! The instance asks for the newest version up to [`MAX_API_VERSION`] the loader has, devices
+//! then run at the lower of that and their own. [`DeviceInfo`] gathers what device selection looks at, [`DeviceRequirements`] rules out
+//! devices lacking what's needed and [`ScoringPolicy`] ranks the rest, unless an
+//! [`AdapterChoice`] forces one. [`QueueFamilies`] picks a family for each kind of
+//! work. Compute and transfer prefer families without graphics, so async compute and uploads on a
+//! DMA engine can overlap rendering, and fall back to the graphics family on devices that don't
+//! have them.
+
+use std::ffi::{CStr, CString};
+
+use ash::{prelude::VkResult, vk, Device, Entry, Instance};
+
+use self::requirements::FeatureSet;
+
+pub mod afr;
+pub mod checkpoints;
+pub mod fault;
+pub mod group;
+pub mod present;
+pub mod protected;
+pub mod requirements;
+pub mod vk_ext;
+
+pub use self::requirements::{Capabilities, DeviceRequirements, EnabledFeatures, Feature};
+
+/// Newest Vulkan version there are code paths for
+pub const MAX_API_VERSION: u32 = vk::API_VERSION_1_3;
+
+/// Version to request in `ApplicationInfo`, the loader's capped at [`MAX_API_VERSION`]
+///
+/// 1.0 loaders don't have `vkEnumerateInstanceVersion` and get 1.0.
+pub fn instance_version(entry: &Entry) -> VkResult<u32> {
+    let loader = entry
+        .try_enumerate_instance_version()?
+        .unwrap_or(vk::API_VERSION_1_0);
+    let version = vk::make_api_version(
+        0,
+        vk::api_version_major(loader),
+        vk::api_version_minor(loader),
+        0,
+    );
+    Ok(version.min(MAX_API_VERSION))
+}
+
+/// What device selection knows about a physical device
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub driver_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    /// Total size of the device local heaps
+    pub vram: vk::DeviceSize,
+    pub extensions: Vec<CString>,
+    pub properties: vk::PhysicalDeviceProperties,
+    pub features: FeatureSet,
+    pub queue_families: Vec<vk::QueueFamilyProperties>,
+    pub memory: vk::PhysicalDeviceMemoryProperties,
+}
+
+impl DeviceInfo {
+    /// Features are queried up to the lower of `instance_version` and the device's version
+    ///
+    /// # Safety
+    ///
+    /// `physical_device` must come from `instance`, created with `instance_version`.
+    pub unsafe fn query(
+        instance: &Instance,
+        instance_version: u32,
+        physical_device: vk::PhysicalDevice,
+    ) -> Self {
+        let properties = instance.get_physical_device_properties(physical_device);
+        let features_version = instance_version.min(properties.api_version);
+        let memory = instance.get_physical_device_memory_properties(physical_device);
+        let vram = memory.memory_heaps[..memory.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+        let extensions: Vec<CString> = instance
+            .enumerate_device_extension_properties(physical_device)
+            .unwrap_or_default()
+            .iter()
+            .map(|prop| CStr::from_ptr(prop.extension_name.as_ptr()).to_owned())
+            .collect();
+        DeviceInfo {
+            name: CStr::from_ptr(properties.device_name.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+            device_type: properties.device_type,
+            api_version: properties.api_version,
+            driver_version: properties.driver_version,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            vram,
+            properties,
+            features: FeatureSet::query(instance, physical_device, features_version, |name| {
+                extensions
+                    .iter()
+                    .any(|ext: &CString| ext.as_c_str() == name)
+            }),
+            queue_families: instance.get_physical_device_queue_family_properties(physical_device),
+            memory,
+            extensions,
+        }
+    }
+
+    /// The driver version decoded the way the vendor packs it
+    pub fn driver_version_string(&self) -> String {
+        let version = self.driver_version;
+        match self.vendor_id {
+            // NVIDIA uses 10.8.8.6 bits
+            0x10de => format!(
+                "{}.{}.{}.{}",
+                version >> 22,
+                (version >> 14) & 0xff,
+                (version >> 6) & 0xff,
+                version & 0x3f
+            ),
+            // Intel on Windows uses 18.14 bits
+            0x8086 if cfg!(windows) => format!("{}.{}", version >> 14, version & 0x3fff),
+            _ => format!(
+                "{}.{}.{}",
+                vk::api_version_major(version),
+                vk::api_version_minor(version),
+                vk::api_version_patch(version)
+            ),
+        }
+    }
+
+    pub fn memory_heaps(&self) -> &[vk::MemoryHeap] {
+        &self.memory.memory_heaps[..self.memory.memory_heap_count as usize]
+    }
+
+    pub fn supports_extension(&self, name: &CStr) -> bool {
+        self.extensions.iter().any(|ext| ext.as_c_str() == name)
+    }
+}
+
+/// A specific adapter to use regardless of score
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterChoice {
+    /// Position in the instance's device list
+    Index(usize),
+    /// Case insensitive part of the device name
+    Name(String),
+}
+
+impl AdapterChoice {
+    /// Numbers pick by index, anything else by name
+    pub fn parse(value: &str) -> Self {
+        match value.parse() {
+            Ok(index) => AdapterChoice::Index(index),
+            Err(_) => AdapterChoice::Name(value.to_lowercase()),
+        }
+    }
+
+    pub fn matches(&self, index: usize, info: &DeviceInfo) -> bool {
+        match self {
+            AdapterChoice::Index(wanted) => *wanted == index,
+            AdapterChoice::Name(part) => info.name.to_lowercase().contains(&part.to_lowercase()),
+        }
+    }
+}
+
+/// Weights ranking suitable devices, the highest total wins
+#[derive(Debug, Clone)]
+pub struct ScoringPolicy {
+    pub discrete: u64,
+    pub integrated: u64,
+    pub virtual_gpu: u64,
+    pub cpu: u64,
+    /// Per GiB of device local memory
+    pub per_vram_gib: u64,
+    /// Per minor version of Vulkan supported
+    pub per_api_minor: u64,
+    /// Per supported entry of `optional_extensions`
+    pub per_optional_extension: u64,
+    pub optional_extensions: Vec<&'static CStr>,
+    /// Skips scoring and takes this adapter, if it's suitable
+    pub forced: Option<AdapterChoice>,
+}
+
+impl Default for ScoringPolicy {
+    fn default() -> Self {
+        ScoringPolicy {
+            discrete: 10_000,
+            integrated: 1_000,
+            virtual_gpu: 100,
+            cpu: 10,
+            per_vram_gib: 100,
+            per_api_minor: 50,
+            per_optional_extension: 20,
+            optional_extensions: Vec::new(),
+            forced: None,
+        }
+    }
+}
+
+impl ScoringPolicy {
+    pub fn with_optional_extensions(mut self, extensions: &[&'static CStr]) -> Self {
+        self.optional_extensions = extensions.to_vec();
+        self
+    }
+
+    pub fn with_forced(mut self, forced: Option<AdapterChoice>) -> Self {
+        self.forced = forced;
+        self
+    }
+
+    /// Whether the device at `index` may be picked at all
+    pub fn allows(&self, index: usize, info: &DeviceInfo) -> bool {
+        self.forced
+            .as_ref()
+            .is_none_or(|forced| forced.matches(index, info))
+    }
+
+    pub fn score(&self, info: &DeviceInfo) -> u64 {
+        let device_type = match info.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => self.discrete,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => self.integrated,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => self.virtual_gpu,
+            vk::PhysicalDeviceType::CPU => self.cpu,
+            _ => 0,
+        };
+        let vram = (info.vram >> 30) * self.per_vram_gib;
+        let api = vk::api_version_minor(info.api_version) as u64 * self.per_api_minor;
+        let optional = self
+            .optional_extensions
+            .iter()
+            .filter(|ext| info.supports_extension(ext))
+            .count() as u64
+            * self.per_optional_extension;
+        device_type + vram + api + optional
+    }
+}
+
+/// Family index for each kind of work, several may be the same family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFamilies {
+    pub graphics: u32,
+    /// The graphics family when it can present, otherwise images are handed over with a
+    /// [`present::PresentHandoff`]
+    pub present: u32,
+    /// A compute family without graphics when there is one
+    pub compute: u32,
+    /// A transfer only family when there is one, then one without graphics
+    pub transfer: u32,
+    /// A family with video decode queues, if the device has one
+    pub video_decode: Option<u32>,
+    /// A family with video encode queues, if the device has one
+    pub video_encode: Option<u32>,
+    /// The graphics family again when it can also create protected queues
+    pub protected: Option<u32>,
+    /// The graphics family when it can bind sparse memory, otherwise any family that can
+    pub sparse_binding: Option<u32>,
+}
+
+impl QueueFamilies {
+    /// Picks families from a device's `families`, `present` tells whether one can present to the
+    /// surface
+    ///
+    /// A family doing both graphics and presenting is preferred. `None` without a graphics and a
+    /// presenting family.
+    pub fn select(
+        families: &[vk::QueueFamilyProperties],
+        mut present: impl FnMut(u32) -> bool,
+    ) -> Option<Self> {
+        let usable: Vec<(u32, vk::QueueFlags)> = (0..)
+            .zip(families)
+            .filter(|(_, family)| family.queue_count > 0)
+            .map(|(index, family)| (index, family.queue_flags))
+            .collect();
+        let find = |wanted: vk::QueueFlags, unwanted: vk::QueueFlags| {
+            usable
+                .iter()
+                .find(|(_, flags)| flags.contains(wanted) && !flags.intersects(unwanted))
+                .map(|&(index, _)| index)
+        };
+        let presenting: Vec<u32> = usable
+            .iter()
+            .map(|&(index, _)| index)
+            .filter(|&index| present(index))
+            .collect();
+
+        let graphics_families = usable
+            .iter()
+            .filter(|(_, flags)| flags.contains(vk::QueueFlags::GRAPHICS))
+            .map(|&(index, _)| index);
+        let (graphics, present) = match graphics_families
+            .clone()
+            .find(|index| presenting.contains(index))
+        {
+            Some(both) => (both, both),
+            None => (graphics_families.clone().next()?, *presenting.first()?),
+        };
+        let compute = find(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS).unwrap_or(graphics);
+        // Graphics and compute families can always transfer, even without the flag
+        let transfer = find(
+            vk::QueueFlags::TRANSFER,
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+        )
+        .or_else(|| find(vk::QueueFlags::TRANSFER, vk::QueueFlags::GRAPHICS))
+        .unwrap_or(graphics);
+        let video_decode = find(vk::QueueFlags::VIDEO_DECODE_KHR, vk::QueueFlags::empty());
+        let video_encode = find(vk::QueueFlags::VIDEO_ENCODE_KHR, vk::QueueFlags::empty());
+        let protected = usable
+            .iter()
+            .any(|&(index, flags)| index == graphics && flags.contains(vk::QueueFlags::PROTECTED))
+            .then_some(graphics);
+        let sparse_binding = usable
+            .iter()
+            .any(|&(index, flags)| {
+                index == graphics && flags.contains(vk::QueueFlags::SPARSE_BINDING)
+            })
+            .then_some(graphics)
+            .or_else(|| find(vk::QueueFlags::SPARSE_BINDING, vk::QueueFlags::empty()));
+        Some(QueueFamilies {
+            graphics,
+            present,
+            compute,
+            transfer,
+            video_decode,
+            video_encode,
+            protected,
+            sparse_binding,
+        })
+    }
+
+    /// Every distinct family, one queue is created from each
+    pub fn unique(&self) -> Vec<u32> {
+        let mut families = vec![self.graphics, self.present, self.compute, self.transfer];
+        families.extend(self.video_decode);
+        families.extend(self.video_encode);
+        families.extend(self.sparse_binding);
+        families.sort_unstable();
+        families.dedup();
+        families
+    }
+
+    /// Whether compute runs on a different family than graphics
+    pub fn has_async_compute(&self) -> bool {
+        self.compute != self.graphics
+    }
+
+    /// Whether transfers run on a different family than graphics
+    pub fn has_async_transfer(&self) -> bool {
+        self.transfer != self.graphics
+    }
+}
+
+/// Queue 0 of each of a [`QueueFamilies`], shared where the families are
+#[derive(Debug, Clone, Copy)]
+pub struct Queues {
+    pub graphics: vk::Queue,
+    pub present: vk::Queue,
+    pub compute: vk::Queue,
+    pub transfer: vk::Queue,
+    pub video_decode: Option<vk::Queue>,
+    pub video_encode: Option<vk::Queue>,
+    pub sparse_binding: Option<vk::Queue>,
+}
+
+impl Queues {
+    /// # Safety
+    ///
+    /// `device` must have been created with a queue in every one of `families`.
+    pub unsafe fn get(device: &Device, families: &QueueFamilies) -> Self {
+        Queues {
+            graphics: device.get_device_queue(families.graphics, 0),
+            present: device.get_device_queue(families.present, 0),
+            compute: device.get_device_queue(families.compute, 0),
+            transfer: device.get_device_queue(families.transfer, 0),
+            video_decode: families
+                .video_decode
+                .map(|family| device.get_device_queue(family, 0)),
+            video_encode: families
+                .video_encode
+                .map(|family| device.get_device_queue(family, 0)),
+            sparse_binding: families
+                .sparse_binding
+                .map(|family| device.get_device_queue(family, 0)),
+        }
+    }
+}
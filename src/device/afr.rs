@@ -0,0 +1,225 @@
+//! Alternate frame rendering, each GPU of a device group drawing and presenting every nth frame
+//!
+//! Frame `n` runs entirely on device `n % len` of the [`DeviceGroup`]: its acquire, its
+//! submission and its present all carry that device's mask, so each GPU renders into its own
+//! instance of the swapchain image and presentation alternates between them. The swapchain is
+//! made with [`AlternateFrames::swapchain_create_info`] for that.
+//!
+//! Nothing is shared between consecutive frames, they run on different GPUs. History a frame
+//! reads from the one before, like temporal accumulation, has to be copied over through peer
+//! memory or turned off. Keep at least as many frames in flight as there are devices, or they
+//! end up taking turns rather than working at once.
+//!
+//! Acquires signal a semaphore per frame in flight and submissions one per swapchain image, so
+//! neither is signalled again while an earlier frame's wait on it may still be pending. The
+//! fence of a frame in flight must be waited on before its slot acquires again.
+
+use ash::{extensions::khr, prelude::VkResult, vk, Device, Instance};
+
+use super::group::DeviceGroup;
+use crate::leaks;
+
+/// Acquire and render semaphores, and which device has the current frame
+pub struct AlternateFrames {
+    ext: khr::DeviceGroup,
+    device_count: u32,
+    present_mode: vk::DeviceGroupPresentModeFlagsKHR,
+    frame: u64,
+    /// Signalled by the acquire of each frame in flight
+    acquired: Vec<vk::Semaphore>,
+    /// Signalled by the submission rendering each swapchain image, presentation waits on it
+    rendered: Vec<vk::Semaphore>,
+}
+
+impl AlternateFrames {
+    /// `None` when the surface can't be presented to from every device of the group
+    ///
+    /// `device` must have been created with `group`'s [`DeviceGroup::create_info`]. Call
+    /// [`AlternateFrames::set_images`] once the swapchain is created.
+    #[track_caller]
+    pub fn new(
+        instance: &Instance,
+        device: &Device,
+        group: &DeviceGroup,
+        surface: vk::SurfaceKHR,
+        frames_in_flight: usize,
+    ) -> VkResult<Option<Self>> {
+        let ext = khr::DeviceGroup::new(instance, device);
+        let Some(present_mode) = (unsafe { Self::choose_present_mode(&ext, group, surface)? })
+        else {
+            return Ok(None);
+        };
+        let mut frames = AlternateFrames {
+            ext,
+            device_count: group.len() as u32,
+            present_mode,
+            frame: 0,
+            acquired: Vec::new(),
+            rendered: Vec::new(),
+        };
+        match unsafe { frames.create_objects(device, frames_in_flight) } {
+            Ok(()) => Ok(Some(frames)),
+            Err(err) => {
+                unsafe { frames.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Presenting its own images on every device, or else having them presented by another
+    unsafe fn choose_present_mode(
+        ext: &khr::DeviceGroup,
+        group: &DeviceGroup,
+        surface: vk::SurfaceKHR,
+    ) -> VkResult<Option<vk::DeviceGroupPresentModeFlagsKHR>> {
+        let mut caps = vk::DeviceGroupPresentCapabilitiesKHR::default();
+        ext.get_device_group_present_capabilities(&mut caps)?;
+        let surface_modes = ext.get_device_group_surface_present_modes(surface)?;
+        let modes = caps.modes & surface_modes;
+        let masks = &caps.present_mask[..group.len()];
+        let local = (0..group.len()).all(|index| masks[index] & (1 << index) != 0);
+        let remote =
+            (0..group.len()).all(|index| masks.iter().any(|mask| mask & (1 << index) != 0));
+        Ok(
+            if local && modes.contains(vk::DeviceGroupPresentModeFlagsKHR::LOCAL) {
+                Some(vk::DeviceGroupPresentModeFlagsKHR::LOCAL)
+            } else if remote && modes.contains(vk::DeviceGroupPresentModeFlagsKHR::REMOTE) {
+                Some(vk::DeviceGroupPresentModeFlagsKHR::REMOTE)
+            } else {
+                None
+            },
+        )
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(&mut self, device: &Device, frames_in_flight: usize) -> VkResult<()> {
+        for _ in 0..frames_in_flight.max(1) {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "alternate frame acquired semaphore");
+            self.acquired.push(semaphore);
+        }
+        Ok(())
+    }
+
+    /// Makes a render semaphore for each of the swapchain's `image_count` images
+    ///
+    /// # Safety
+    ///
+    /// No present may still be waiting on the previous swapchain's semaphores.
+    #[track_caller]
+    pub unsafe fn set_images(&mut self, device: &Device, image_count: usize) -> VkResult<()> {
+        for semaphore in self.rendered.drain(..) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        for _ in 0..image_count {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "alternate frame rendered semaphore");
+            self.rendered.push(semaphore);
+        }
+        Ok(())
+    }
+
+    pub fn present_mode(&self) -> vk::DeviceGroupPresentModeFlagsKHR {
+        self.present_mode
+    }
+
+    /// For `SwapchainCreateInfoKHR::push_next`, so frames may be presented from any device
+    pub fn swapchain_create_info(&self) -> vk::DeviceGroupSwapchainCreateInfoKHR {
+        vk::DeviceGroupSwapchainCreateInfoKHR::builder()
+            .modes(self.present_mode)
+            .build()
+    }
+
+    /// The device rendering the current frame
+    pub fn device_index(&self) -> u32 {
+        (self.frame % self.device_count as u64) as u32
+    }
+
+    /// [`AlternateFrames::device_index`] as a mask, e.g. for `DeviceGroupCommandBufferBeginInfo`
+    pub fn device_mask(&self) -> u32 {
+        1 << self.device_index()
+    }
+
+    /// The current frame's slot among the frames in flight
+    pub fn frame_slot(&self) -> usize {
+        (self.frame % self.acquired.len() as u64) as usize
+    }
+
+    /// Acquires the current frame's image for its device, as `acquire_next_image` would
+    pub fn acquire(&self, swapchain: vk::SwapchainKHR, timeout: u64) -> VkResult<(u32, bool)> {
+        let info = vk::AcquireNextImageInfoKHR::builder()
+            .swapchain(swapchain)
+            .timeout(timeout)
+            .semaphore(self.acquired[self.frame_slot()])
+            .device_mask(self.device_mask());
+        unsafe { self.ext.acquire_next_image2(&info) }
+    }
+
+    /// Submits the current frame's `command_buffers`, rendering image `image`, to its device
+    /// alone
+    ///
+    /// The submission waits for the acquire at `wait_stage`, the stage first writing the image.
+    pub fn submit(
+        &self,
+        device: &Device,
+        queue: vk::Queue,
+        image: u32,
+        command_buffers: &[vk::CommandBuffer],
+        wait_stage: vk::PipelineStageFlags,
+        fence: vk::Fence,
+    ) -> VkResult<()> {
+        let slot = self.frame_slot();
+        let image = image as usize;
+        let device_index = [self.device_index()];
+        let masks = vec![self.device_mask(); command_buffers.len()];
+        let mut group = vk::DeviceGroupSubmitInfo::builder()
+            .wait_semaphore_device_indices(&device_index)
+            .command_buffer_device_masks(&masks)
+            .signal_semaphore_device_indices(&device_index);
+        let submit = vk::SubmitInfo::builder()
+            .wait_semaphores(&self.acquired[slot..=slot])
+            .wait_dst_stage_mask(std::slice::from_ref(&wait_stage))
+            .command_buffers(command_buffers)
+            .signal_semaphores(&self.rendered[image..=image])
+            .push_next(&mut group);
+        unsafe { device.queue_submit(queue, &[*submit], fence) }
+    }
+
+    /// Presents image `index` from the current frame's device and moves on to the next device
+    ///
+    /// Gives whether the swapchain is suboptimal, like `queue_present`.
+    pub fn present(
+        &mut self,
+        swapchain_ext: &khr::Swapchain,
+        queue: vk::Queue,
+        swapchain: vk::SwapchainKHR,
+        index: u32,
+    ) -> VkResult<bool> {
+        let image = index as usize;
+        let masks = [self.device_mask()];
+        let mut group = vk::DeviceGroupPresentInfoKHR::builder()
+            .device_masks(&masks)
+            .mode(self.present_mode);
+        let swapchains = [swapchain];
+        let indices = [index];
+        let info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&self.rendered[image..=image])
+            .swapchains(&swapchains)
+            .image_indices(&indices)
+            .push_next(&mut group);
+        // The frame is over either way, the next one goes to the next device
+        self.frame += 1;
+        unsafe { swapchain_ext.queue_present(queue, &info) }
+    }
+
+    /// # Safety
+    ///
+    /// The device group must be done with every frame.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for semaphore in self.acquired.drain(..).chain(self.rendered.drain(..)) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+    }
+}
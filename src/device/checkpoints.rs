@@ -0,0 +1,207 @@
+//! Breadcrumbs around each pass, read back after a device loss to see which pass hung
+//!
+//! `VK_NV_device_diagnostic_checkpoints` has the driver remember the last checkpoint each stage
+//! passed. `VK_AMD_buffer_marker` writes markers into a host visible buffer instead, the top of
+//! pipe slot holds the last pass started and the bottom of pipe slot the last one finished.
+
+use std::{collections::HashMap, ffi::CStr, fmt};
+
+use ash::{extensions::nv, prelude::VkResult, vk, Device, Instance};
+
+use crate::memory::Buffer;
+
+use super::EnabledFeatures;
+
+/// Either enables breadcrumbs, NVIDIA's is preferred when both are there
+pub const CHECKPOINT_EXTENSIONS: [&CStr; 2] = [
+    vk::NvDeviceDiagnosticCheckpointsFn::name(),
+    vk::AmdBufferMarkerFn::name(),
+];
+
+enum Backend {
+    Nv(nv::DeviceDiagnosticCheckpoints),
+    Amd {
+        fp: vk::AmdBufferMarkerFn,
+        buffer: Buffer,
+    },
+}
+
+pub struct Checkpoints {
+    backend: Backend,
+    labels: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl Checkpoints {
+    /// `None` unless one of [`CHECKPOINT_EXTENSIONS`] was enabled
+    pub fn new(
+        instance: &Instance,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        enabled: &EnabledFeatures,
+    ) -> VkResult<Option<Self>> {
+        let backend = if enabled.has_extension(vk::NvDeviceDiagnosticCheckpointsFn::name()) {
+            Backend::Nv(nv::DeviceDiagnosticCheckpoints::new(instance, device))
+        } else if enabled.has_extension(vk::AmdBufferMarkerFn::name()) {
+            let fp = vk::AmdBufferMarkerFn::load(|name| unsafe {
+                std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+            });
+            let buffer = Buffer::new(
+                device,
+                mem_props,
+                2 * std::mem::size_of::<u32>() as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            // Fresh memory holds garbage, zero reads back as no marker written yet
+            unsafe {
+                let slots = buffer.mapped().unwrap().as_ptr() as *mut u8;
+                slots.write_bytes(0, buffer.size as usize);
+            }
+            Backend::Amd { fp, buffer }
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Checkpoints {
+            backend,
+            labels: Vec::new(),
+            ids: HashMap::new(),
+        }))
+    }
+
+    /// Records that `label` started, on the GPU timeline
+    pub fn begin(&mut self, cmd: vk::CommandBuffer, label: &str) {
+        let marker = self.marker(label, false);
+        self.write(cmd, marker, vk::PipelineStageFlags::TOP_OF_PIPE, 0);
+    }
+
+    /// Records that `label` finished, on the GPU timeline
+    pub fn end(&mut self, cmd: vk::CommandBuffer, label: &str) {
+        let marker = self.marker(label, true);
+        self.write(cmd, marker, vk::PipelineStageFlags::BOTTOM_OF_PIPE, 1);
+    }
+
+    /// Where each stage got to
+    ///
+    /// # Safety
+    ///
+    /// The device must have been lost, and `queue` be the one the marked command buffers were
+    /// submitted to.
+    pub unsafe fn report(&self, queue: vk::Queue) -> CheckpointReport {
+        let stages = match &self.backend {
+            Backend::Nv(ext) => {
+                let mut data =
+                    vec![vk::CheckpointDataNV::default(); ext.get_queue_checkpoint_data_len(queue)];
+                ext.get_queue_checkpoint_data(queue, &mut data);
+                data.iter()
+                    .map(|data| (data.stage, data.p_checkpoint_marker as u32))
+                    .collect()
+            }
+            Backend::Amd { buffer, .. } => {
+                let slots = buffer.mapped().unwrap().as_ptr() as *const u32;
+                vec![
+                    (vk::PipelineStageFlags::TOP_OF_PIPE, slots.read_volatile()),
+                    (
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        slots.add(1).read_volatile(),
+                    ),
+                ]
+            }
+        };
+        CheckpointReport {
+            stages: stages
+                .into_iter()
+                .filter_map(|(stage, marker)| Some((stage, self.decode(marker)?)))
+                .collect(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No marked command buffer may still be pending.
+    pub unsafe fn destroy(&self, device: &Device) {
+        if let Backend::Amd { buffer, .. } = &self.backend {
+            buffer.destroy(device);
+        }
+    }
+
+    /// Non zero, with the label's index above the begin/end bit
+    fn marker(&mut self, label: &str, end: bool) -> u32 {
+        let id = match self.ids.get(label) {
+            Some(&id) => id,
+            None => {
+                let id = self.labels.len() as u32;
+                self.labels.push(label.to_owned());
+                self.ids.insert(label.to_owned(), id);
+                id
+            }
+        };
+        ((id << 1) | end as u32) + 1
+    }
+
+    fn decode(&self, marker: u32) -> Option<Checkpoint> {
+        let value = marker.checked_sub(1)?;
+        Some(Checkpoint {
+            label: self.labels.get((value >> 1) as usize)?.clone(),
+            end: value & 1 == 1,
+        })
+    }
+
+    fn write(&self, cmd: vk::CommandBuffer, marker: u32, stage: vk::PipelineStageFlags, slot: u32) {
+        unsafe {
+            match &self.backend {
+                Backend::Nv(ext) => ext.cmd_set_checkpoint(cmd, marker as usize as *const _),
+                Backend::Amd { fp, buffer } => (fp.cmd_write_buffer_marker_amd)(
+                    cmd,
+                    stage,
+                    buffer.buffer,
+                    slot as vk::DeviceSize * std::mem::size_of::<u32>() as vk::DeviceSize,
+                    marker,
+                ),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub label: String,
+    /// Whether this is where the pass finished rather than started
+    pub end: bool,
+}
+
+/// The last checkpoint each pipeline stage passed
+#[derive(Debug, Clone)]
+pub struct CheckpointReport {
+    pub stages: Vec<(vk::PipelineStageFlags, Checkpoint)>,
+}
+
+impl CheckpointReport {
+    /// A pass that started at the top of the pipe without finishing at the bottom
+    pub fn hung_pass(&self) -> Option<&str> {
+        let at = |stage| {
+            self.stages
+                .iter()
+                .find(|(flags, _)| *flags == stage)
+                .map(|(_, checkpoint)| checkpoint)
+        };
+        let top = at(vk::PipelineStageFlags::TOP_OF_PIPE)?;
+        let bottom = at(vk::PipelineStageFlags::BOTTOM_OF_PIPE);
+        let finished = bottom.is_some_and(|bottom| bottom.end && bottom.label == top.label);
+        (!top.end && !finished).then_some(top.label.as_str())
+    }
+}
+
+impl fmt::Display for CheckpointReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Last checkpoints:")?;
+        for (stage, checkpoint) in &self.stages {
+            let edge = if checkpoint.end { "end" } else { "begin" };
+            writeln!(f, "  {stage:?}: {edge} {}", checkpoint.label)?;
+        }
+        if let Some(pass) = self.hung_pass() {
+            writeln!(f, "  Pass {pass} started but never finished")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Reading back why the device was lost through `VK_EXT_device_fault`
+//!
+//! Drivers keep the fault around after `ERROR_DEVICE_LOST`, query it before destroying anything
+//! so the report names the faulting addresses and the vendor's own codes.
+
+use std::{
+    ffi::{c_void, CStr},
+    fmt,
+};
+
+use ash::{prelude::VkResult, vk, Device, Instance};
+
+use super::{EnabledFeatures, Feature};
+
+pub struct DeviceFault {
+    fp: vk::ExtDeviceFaultFn,
+    device: vk::Device,
+    vendor_binary: bool,
+}
+
+impl DeviceFault {
+    /// `None` unless the device was created with [`Feature::DeviceFault`]
+    pub fn new(instance: &Instance, device: &Device, enabled: &EnabledFeatures) -> Option<Self> {
+        if !enabled.has_feature(Feature::DeviceFault) {
+            return None;
+        }
+        let fp = vk::ExtDeviceFaultFn::load(|name| unsafe {
+            std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+        });
+        Some(DeviceFault {
+            fp,
+            device: device.handle(),
+            vendor_binary: enabled.has_feature(Feature::DeviceFaultVendorBinary),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// Only meaningful once a call on the device returned `ERROR_DEVICE_LOST`.
+    pub unsafe fn query(&self) -> VkResult<FaultReport> {
+        let mut counts = vk::DeviceFaultCountsEXT::default();
+        (self.fp.get_device_fault_info_ext)(self.device, &mut counts, std::ptr::null_mut())
+            .result()?;
+        if !self.vendor_binary {
+            counts.vendor_binary_size = 0;
+        }
+
+        let mut addresses =
+            vec![vk::DeviceFaultAddressInfoEXT::default(); counts.address_info_count as usize];
+        let mut vendor =
+            vec![vk::DeviceFaultVendorInfoEXT::default(); counts.vendor_info_count as usize];
+        let mut vendor_binary = vec![0u8; counts.vendor_binary_size as usize];
+        let mut info = vk::DeviceFaultInfoEXT {
+            p_address_infos: addresses.as_mut_ptr(),
+            p_vendor_infos: vendor.as_mut_ptr(),
+            p_vendor_binary_data: if vendor_binary.is_empty() {
+                std::ptr::null_mut()
+            } else {
+                vendor_binary.as_mut_ptr() as *mut c_void
+            },
+            ..Default::default()
+        };
+        // INCOMPLETE when the fault grew between the calls, what fit is still worth reporting
+        match (self.fp.get_device_fault_info_ext)(self.device, &mut counts, &mut info) {
+            vk::Result::SUCCESS | vk::Result::INCOMPLETE => {}
+            err => return Err(err),
+        }
+        addresses.truncate(counts.address_info_count as usize);
+        vendor.truncate(counts.vendor_info_count as usize);
+        vendor_binary.truncate(counts.vendor_binary_size as usize);
+
+        Ok(FaultReport {
+            description: CStr::from_ptr(info.description.as_ptr())
+                .to_string_lossy()
+                .into_owned(),
+            addresses,
+            vendor: vendor
+                .iter()
+                .map(|info| VendorFault {
+                    description: CStr::from_ptr(info.description.as_ptr())
+                        .to_string_lossy()
+                        .into_owned(),
+                    code: info.vendor_fault_code,
+                    data: info.vendor_fault_data,
+                })
+                .collect(),
+            vendor_binary,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VendorFault {
+    pub description: String,
+    pub code: u64,
+    pub data: u64,
+}
+
+/// What the driver knows about a device loss, `Display` gives a log friendly summary
+#[derive(Debug, Clone)]
+pub struct FaultReport {
+    pub description: String,
+    pub addresses: Vec<vk::DeviceFaultAddressInfoEXT>,
+    pub vendor: Vec<VendorFault>,
+    /// Vendor specific crash dump, for the vendor's tools
+    pub vendor_binary: Vec<u8>,
+}
+
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Device fault: {}", self.description)?;
+        for address in &self.addresses {
+            // The precision is a power of two, the fault lies in the aligned range around it
+            let precision = address.address_precision.max(1);
+            let start = address.reported_address & !(precision - 1);
+            writeln!(
+                f,
+                "  {:?} at {:#x}..{:#x}",
+                address.address_type,
+                start,
+                start + precision
+            )?;
+        }
+        for vendor in &self.vendor {
+            writeln!(
+                f,
+                "  {} (code {:#x}, data {:#x})",
+                vendor.description, vendor.code, vendor.data
+            )?;
+        }
+        if !self.vendor_binary.is_empty() {
+            writeln!(
+                f,
+                "  {} bytes of vendor binary data",
+                self.vendor_binary.len()
+            )?;
+        }
+        Ok(())
+    }
+}
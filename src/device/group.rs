@@ -0,0 +1,613 @@
+//! Device groups, several linked GPUs driven as one logical device
+//!
+//! [`DeviceGroup::containing`] finds the GPUs linked with the selected one, and
+//! [`DeviceGroup::create_info`] goes in the device's `pNext` chain to span them. Commands then
+//! run on every GPU of the group unless a device mask says otherwise, and each GPU gets its own
+//! instance of every allocation.
+//!
+//! [`SplitFrame`] spreads one frame over the group: each GPU draws its share of the target,
+//! bands or checkerboard tiles, then copies it into the first GPU's instance through peer
+//! memory, where it's composited and presented. [`super::afr`] hands whole frames to each GPU
+//! in turn instead.
+
+use ash::{prelude::VkResult, vk, Device, Instance};
+use thiserror::Error;
+
+use crate::{
+    leaks,
+    memory::{
+        find_memory_type,
+        usage::{self, MemoryCategory},
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum DeviceGroupError {
+    #[error("the GPUs of the group can't copy into each other's memory")]
+    NoPeerCopy,
+    #[error("no memory type fits the split frame target")]
+    NoMemoryType,
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Physical devices the instance reports as linked, usable together by one logical device
+#[derive(Debug, Clone)]
+pub struct DeviceGroup {
+    /// Device index order, the first is the one presenting
+    pub physical_devices: Vec<vk::PhysicalDevice>,
+    /// Allocations may be made on only some of the devices
+    pub subset_allocation: bool,
+}
+
+impl DeviceGroup {
+    /// Every group the instance reports, single devices included
+    ///
+    /// # Safety
+    ///
+    /// `instance` must have been created with `api_version` 1.1 or later.
+    pub unsafe fn enumerate(instance: &Instance) -> VkResult<Vec<Self>> {
+        let count = instance.enumerate_physical_device_groups_len()?;
+        let mut groups = vec![vk::PhysicalDeviceGroupProperties::default(); count];
+        instance.enumerate_physical_device_groups(&mut groups)?;
+        Ok(groups
+            .iter()
+            .map(|group| DeviceGroup {
+                physical_devices: group.physical_devices[..group.physical_device_count as usize]
+                    .to_vec(),
+                subset_allocation: group.subset_allocation == vk::TRUE,
+            })
+            .collect())
+    }
+
+    /// The group `physical_device` is in, with it first, `None` when it's alone or before 1.1
+    pub fn containing(
+        instance: &Instance,
+        api_version: u32,
+        physical_device: vk::PhysicalDevice,
+    ) -> VkResult<Option<Self>> {
+        if api_version < vk::API_VERSION_1_1 {
+            return Ok(None);
+        }
+        let groups = unsafe { Self::enumerate(instance)? };
+        Ok(groups
+            .into_iter()
+            .find(|group| group.physical_devices.contains(&physical_device))
+            .filter(|group| group.len() > 1)
+            .map(|mut group| {
+                let index = group
+                    .physical_devices
+                    .iter()
+                    .position(|&device| device == physical_device)
+                    .expect("the group contains the device");
+                group.physical_devices.swap(0, index);
+                group
+            }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.physical_devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.physical_devices.is_empty()
+    }
+
+    /// Device mask of every device in the group
+    pub fn all_devices(&self) -> u32 {
+        (1 << self.len()) - 1
+    }
+
+    /// For `DeviceCreateInfo::push_next`, the device must be created from the first device
+    pub fn create_info(&self) -> vk::DeviceGroupDeviceCreateInfoBuilder<'_> {
+        vk::DeviceGroupDeviceCreateInfo::builder().physical_devices(&self.physical_devices)
+    }
+}
+
+/// How a frame is shared between the devices of a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitMode {
+    /// One horizontal band each, cheap to record but unbalanced when the load isn't even
+    Bands,
+    /// Square tiles dealt out in turn, balanced but every draw is recorded once per tile
+    Checkerboard { tile: u32 },
+}
+
+/// A color target rendered in parts across a device group and gathered on the first device
+///
+/// The target stays in `GENERAL` throughout, so the parts copied in by other devices are never
+/// discarded by a layout transition. Each frame:
+///
+/// 1. Begin rendering to [`SplitFrame::view`] with [`SplitFrame::device_render_areas`] in a
+///    `DeviceGroupRenderPassBeginInfo`, and draw through [`SplitFrame::record_draws`].
+/// 2. End rendering and [`SplitFrame::record_gather`] in the same command buffer.
+/// 3. Record a second command buffer copying the complete target to the swapchain image.
+/// 4. Submit both with [`SplitFrame::submit`], which runs the second on the first device only,
+///    once every device's part has arrived.
+pub struct SplitFrame {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    /// The same memory seen from every device as the first device's instance
+    peer: vk::Image,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    mode: SplitMode,
+    device_count: u32,
+    /// Signalled by each device once its part is copied over
+    gathered: Vec<vk::Semaphore>,
+}
+
+impl SplitFrame {
+    /// `device` must have been created with `group`'s [`DeviceGroup::create_info`]
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        group: &DeviceGroup,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        mode: SplitMode,
+    ) -> Result<Self, DeviceGroupError> {
+        let mut frame = SplitFrame {
+            image: vk::Image::null(),
+            view: vk::ImageView::null(),
+            peer: vk::Image::null(),
+            memory: vk::DeviceMemory::null(),
+            allocation_size: 0,
+            format,
+            extent,
+            mode,
+            device_count: group.len() as u32,
+            gathered: Vec::new(),
+        };
+        match unsafe { frame.create_objects(device, mem_props) } {
+            Ok(()) => Ok(frame),
+            Err(err) => {
+                unsafe { frame.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<(), DeviceGroupError> {
+        // Aliasing images must be created alike and with ALIAS to share contents
+        let info = vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::ALIAS)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(self.format)
+            .extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+            )
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        self.image = device.create_image(&info, None)?;
+        leaks::track(self.image, "split frame image");
+        self.peer = device.create_image(&info, None)?;
+        leaks::track(self.peer, "split frame peer image");
+
+        let requirements = device.get_image_memory_requirements(self.image);
+        let type_index = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .ok_or(DeviceGroupError::NoMemoryType)?;
+        let heap = mem_props.memory_types[type_index as usize].heap_index;
+        // Every other device writes its part into the first device's instance
+        let peer_copy = (1..self.device_count).all(|local| {
+            device
+                .get_device_group_peer_memory_features(heap, local, 0)
+                .contains(vk::PeerMemoryFeatureFlags::COPY_DST)
+        });
+        if !peer_copy {
+            return Err(DeviceGroupError::NoPeerCopy);
+        }
+        // Without a device mask every device gets its own instance
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        self.memory = device.allocate_memory(&alloc_info, None)?;
+        self.allocation_size = requirements.size;
+        leaks::track(self.memory, "split frame memory");
+        usage::record_allocation(MemoryCategory::RenderTarget, self.allocation_size);
+
+        let first_instance = vec![0; self.device_count as usize];
+        let mut peer_indices =
+            vk::BindImageMemoryDeviceGroupInfo::builder().device_indices(&first_instance);
+        let binds = [
+            vk::BindImageMemoryInfo::builder()
+                .image(self.image)
+                .memory(self.memory)
+                .build(),
+            vk::BindImageMemoryInfo::builder()
+                .image(self.peer)
+                .memory(self.memory)
+                .push_next(&mut peer_indices)
+                .build(),
+        ];
+        device.bind_image_memory2(&binds)?;
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(self.image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(self.format)
+            .subresource_range(COLOR_RANGE);
+        self.view = device.create_image_view(&view_info, None)?;
+        leaks::track(self.view, "split frame view");
+
+        for _ in 0..self.device_count {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "split frame gathered semaphore");
+            self.gathered.push(semaphore);
+        }
+        Ok(())
+    }
+
+    /// Device mask of every device in the group
+    fn all_devices(&self) -> u32 {
+        (1 << self.device_count) - 1
+    }
+
+    /// Records moving both images to `GENERAL`, once before the first frame
+    pub fn record_init(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let barriers = [self.image, self.peer].map(|image| {
+            vk::ImageMemoryBarrier::builder()
+                .dst_access_mask(
+                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::TRANSFER_WRITE,
+                )
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(COLOR_RANGE)
+                .build()
+        });
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            )
+        };
+    }
+
+    /// The part device `index` draws, nothing when there are more devices than bands or tiles
+    pub fn regions(&self, index: u32) -> Vec<vk::Rect2D> {
+        split_regions(self.mode, self.extent, self.device_count, index)
+    }
+
+    /// One render area per device, for `DeviceGroupRenderPassBeginInfo`
+    ///
+    /// Bands are clipped by these alone, checkerboard tiles by the scissors
+    /// [`SplitFrame::record_draws`] sets.
+    pub fn device_render_areas(&self) -> Vec<vk::Rect2D> {
+        (0..self.device_count)
+            .map(|index| match self.mode {
+                SplitMode::Bands => self.regions(index).first().copied().unwrap_or(vk::Rect2D {
+                    // Render areas can't be empty, an idle device draws a pixel nobody gathers
+                    offset: vk::Offset2D::default(),
+                    extent: vk::Extent2D {
+                        width: 1,
+                        height: 1,
+                    },
+                }),
+                SplitMode::Checkerboard { .. } => vk::Rect2D {
+                    offset: vk::Offset2D::default(),
+                    extent: self.extent,
+                },
+            })
+            .collect()
+    }
+
+    /// Calls `draw` to record the frame's draws once per scissor rectangle, already set
+    ///
+    /// Pipelines need a dynamic scissor. Draws for a checkerboard tile only run on the device
+    /// owning it, the device mask is back to every device afterwards.
+    pub fn record_draws(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        mut draw: impl FnMut(vk::Rect2D),
+    ) {
+        if self.mode == SplitMode::Bands {
+            let full = vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent: self.extent,
+            };
+            unsafe { device.cmd_set_scissor(cmd, 0, &[full]) };
+            draw(full);
+            return;
+        }
+        for index in 0..self.device_count {
+            unsafe { device.cmd_set_device_mask(cmd, 1 << index) };
+            for rect in self.regions(index) {
+                unsafe { device.cmd_set_scissor(cmd, 0, &[rect]) };
+                draw(rect);
+            }
+        }
+        unsafe { device.cmd_set_device_mask(cmd, self.all_devices()) };
+    }
+
+    /// Records each device but the first copying its part into the first device's instance
+    pub fn record_gather(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::DEVICE_GROUP,
+                &[barrier],
+                &[],
+                &[],
+            )
+        };
+        for index in 1..self.device_count {
+            let copies: Vec<vk::ImageCopy> = self
+                .regions(index)
+                .into_iter()
+                .map(|rect| {
+                    let offset = vk::Offset3D {
+                        x: rect.offset.x,
+                        y: rect.offset.y,
+                        z: 0,
+                    };
+                    vk::ImageCopy {
+                        src_subresource: COLOR_LAYERS,
+                        src_offset: offset,
+                        dst_subresource: COLOR_LAYERS,
+                        dst_offset: offset,
+                        extent: vk::Extent3D {
+                            width: rect.extent.width,
+                            height: rect.extent.height,
+                            depth: 1,
+                        },
+                    }
+                })
+                .collect();
+            if copies.is_empty() {
+                continue;
+            }
+            unsafe {
+                device.cmd_set_device_mask(cmd, 1 << index);
+                device.cmd_copy_image(
+                    cmd,
+                    self.image,
+                    vk::ImageLayout::GENERAL,
+                    self.peer,
+                    vk::ImageLayout::GENERAL,
+                    &copies,
+                );
+            }
+        }
+        unsafe { device.cmd_set_device_mask(cmd, self.all_devices()) };
+    }
+
+    /// Submits `render` to every device, then `composite` to the first once the parts are in
+    ///
+    /// `wait` and `signal` apply to `composite`, e.g. the swapchain acquire and render
+    /// semaphores, since only it touches the swapchain image.
+    #[allow(clippy::too_many_arguments)]
+    pub fn submit(
+        &self,
+        device: &Device,
+        queue: vk::Queue,
+        render: vk::CommandBuffer,
+        composite: vk::CommandBuffer,
+        wait: &[(vk::Semaphore, vk::PipelineStageFlags)],
+        signal: &[vk::Semaphore],
+        fence: vk::Fence,
+    ) -> VkResult<()> {
+        let all = [self.all_devices()];
+        let every_device: Vec<u32> = (0..self.device_count).collect();
+        let mut render_group = vk::DeviceGroupSubmitInfo::builder()
+            .command_buffer_device_masks(&all)
+            .signal_semaphore_device_indices(&every_device);
+        let render_cmds = [render];
+        let render_submit = vk::SubmitInfo::builder()
+            .command_buffers(&render_cmds)
+            .signal_semaphores(&self.gathered)
+            .push_next(&mut render_group);
+
+        let (mut wait_semaphores, mut wait_stages): (Vec<_>, Vec<_>) = wait.iter().copied().unzip();
+        wait_semaphores.extend(&self.gathered);
+        wait_stages.extend(
+            self.gathered
+                .iter()
+                .map(|_| vk::PipelineStageFlags::TRANSFER),
+        );
+        let on_first = vec![0; wait_semaphores.len()];
+        let first = [1];
+        let signal_on_first = vec![0; signal.len()];
+        let mut composite_group = vk::DeviceGroupSubmitInfo::builder()
+            .wait_semaphore_device_indices(&on_first)
+            .command_buffer_device_masks(&first)
+            .signal_semaphore_device_indices(&signal_on_first);
+        let composite_cmds = [composite];
+        let composite_submit = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&composite_cmds)
+            .signal_semaphores(signal)
+            .push_next(&mut composite_group);
+        unsafe { device.queue_submit(queue, &[*render_submit, *composite_submit], fence) }
+    }
+
+    /// # Safety
+    ///
+    /// The device group must be done with the frame.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for semaphore in self.gathered.drain(..) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        if self.view != vk::ImageView::null() {
+            leaks::untrack(self.view);
+            device.destroy_image_view(self.view, None);
+        }
+        for image in [self.image, self.peer] {
+            if image != vk::Image::null() {
+                leaks::untrack(image);
+                device.destroy_image(image, None);
+            }
+        }
+        if self.memory != vk::DeviceMemory::null() {
+            leaks::untrack(self.memory);
+            device.free_memory(self.memory, None);
+            usage::release_allocation(MemoryCategory::RenderTarget, self.allocation_size);
+        }
+    }
+}
+
+const COLOR_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+const COLOR_LAYERS: vk::ImageSubresourceLayers = vk::ImageSubresourceLayers {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    mip_level: 0,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Rectangles of `extent` drawn by device `index` of `device_count`
+///
+/// Bands are at least a row tall, devices past the last row get nothing.
+fn split_regions(
+    mode: SplitMode,
+    extent: vk::Extent2D,
+    device_count: u32,
+    index: u32,
+) -> Vec<vk::Rect2D> {
+    let vk::Extent2D { width, height } = extent;
+    match mode {
+        SplitMode::Bands => {
+            let bands = device_count.min(height);
+            if index >= bands {
+                return Vec::new();
+            }
+            let top = height * index / bands;
+            let bottom = height * (index + 1) / bands;
+            vec![vk::Rect2D {
+                offset: vk::Offset2D {
+                    x: 0,
+                    y: top as i32,
+                },
+                extent: vk::Extent2D {
+                    width,
+                    height: bottom - top,
+                },
+            }]
+        }
+        SplitMode::Checkerboard { tile } => {
+            let tile = tile.max(1);
+            let (columns, rows) = (width.div_ceil(tile), height.div_ceil(tile));
+            (0..rows)
+                .flat_map(|y| (0..columns).map(move |x| (x, y)))
+                .filter(|(x, y)| (x + y) % device_count == index)
+                .map(|(x, y)| vk::Rect2D {
+                    offset: vk::Offset2D {
+                        x: (x * tile) as i32,
+                        y: (y * tile) as i32,
+                    },
+                    extent: vk::Extent2D {
+                        width: tile.min(width - x * tile),
+                        height: tile.min(height - y * tile),
+                    },
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(width: u32, height: u32) -> vk::Extent2D {
+        vk::Extent2D { width, height }
+    }
+
+    /// Every device's regions, checked to cover the target exactly once
+    fn split(mode: SplitMode, extent: vk::Extent2D, device_count: u32) -> Vec<Vec<vk::Rect2D>> {
+        let parts: Vec<_> = (0..device_count)
+            .map(|index| split_regions(mode, extent, device_count, index))
+            .collect();
+        let mut covered = vec![0; (extent.width * extent.height) as usize];
+        for rect in parts.iter().flatten() {
+            assert!(
+                rect.extent.width > 0 && rect.extent.height > 0,
+                "{rect:?} is empty"
+            );
+            for y in rect.offset.y as u32..rect.offset.y as u32 + rect.extent.height {
+                for x in rect.offset.x as u32..rect.offset.x as u32 + rect.extent.width {
+                    covered[(y * extent.width + x) as usize] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+        parts
+    }
+
+    #[test]
+    fn bands_split_rows_evenly() {
+        let parts = split(SplitMode::Bands, extent(4, 10), 3);
+        let heights: Vec<_> = parts.iter().map(|part| part[0].extent.height).collect();
+        assert_eq!(heights, [3, 3, 4]);
+    }
+
+    #[test]
+    fn devices_past_the_last_row_get_no_band() {
+        let parts = split(SplitMode::Bands, extent(8, 2), 4);
+        assert_eq!(parts[0].len(), 1);
+        assert_eq!(parts[1].len(), 1);
+        assert!(parts[2].is_empty() && parts[3].is_empty());
+    }
+
+    #[test]
+    fn checkerboard_alternates_and_clips_edge_tiles() {
+        let parts = split(SplitMode::Checkerboard { tile: 4 }, extent(10, 6), 2);
+        assert_eq!(parts[0].len(), 3);
+        assert_eq!(parts[1].len(), 3);
+        assert_eq!(parts[0][1].offset, vk::Offset2D { x: 8, y: 0 });
+        assert_eq!(parts[0][1].extent, extent(2, 4));
+        assert_eq!(parts[1][0].offset, vk::Offset2D { x: 4, y: 0 });
+    }
+
+    #[test]
+    fn checkerboard_with_fewer_tiles_than_devices_leaves_some_idle() {
+        let parts = split(SplitMode::Checkerboard { tile: 8 }, extent(8, 8), 3);
+        assert_eq!(parts[0].len(), 1);
+        assert!(parts[1].is_empty() && parts[2].is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! Handing swapchain images from the graphics family to a presenting family without graphics
+//!
+//! Some devices only present from a compute or other non-graphics family. Rather than share the
+//! swapchain concurrently, images stay exclusive: the frame's last graphics commands release
+//! the image with [`PresentHandoff::record_release`] and [`PresentHandoff::acquire`] submits
+//! the matching acquire on the present queue, whose semaphore `vkQueuePresentKHR` then waits on.
+
+use ash::{prelude::VkResult, vk, Device};
+
+use super::QueueFamilies;
+use crate::leaks;
+
+/// Ownership transfer of each swapchain image from graphics to the presenting family
+pub struct PresentHandoff {
+    graphics: u32,
+    present: u32,
+    images: Vec<vk::Image>,
+    command_pool: vk::CommandPool,
+    /// The acquire barrier for each image, recorded once and resubmitted every frame
+    acquires: Vec<vk::CommandBuffer>,
+    /// Signalled by the graphics submission releasing the image
+    released: Vec<vk::Semaphore>,
+    /// Signalled by the acquire, presentation waits on it
+    acquired: Vec<vk::Semaphore>,
+}
+
+impl PresentHandoff {
+    /// `None` when graphics and presenting share a family and no transfer is needed
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        families: &QueueFamilies,
+        images: &[vk::Image],
+    ) -> VkResult<Option<Self>> {
+        if families.graphics == families.present {
+            return Ok(None);
+        }
+        let mut handoff = PresentHandoff {
+            graphics: families.graphics,
+            present: families.present,
+            images: images.to_vec(),
+            command_pool: vk::CommandPool::null(),
+            acquires: Vec::new(),
+            released: Vec::new(),
+            acquired: Vec::new(),
+        };
+        match unsafe { handoff.create_objects(device) } {
+            Ok(()) => Ok(Some(handoff)),
+            Err(err) => {
+                unsafe { handoff.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(&mut self, device: &Device) -> VkResult<()> {
+        let pool_info = vk::CommandPoolCreateInfo::builder().queue_family_index(self.present);
+        self.command_pool = device.create_command_pool(&pool_info, None)?;
+        leaks::track(self.command_pool, "present handoff command pool");
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(self.images.len() as u32);
+        self.acquires = device.allocate_command_buffers(&alloc_info)?;
+        for (&cmd, &image) in self.acquires.iter().zip(&self.images) {
+            device.begin_command_buffer(cmd, &vk::CommandBufferBeginInfo::default())?;
+            // The semaphore wait covers the memory dependency, the barrier only takes ownership
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[self.transfer(image).build()],
+            );
+            device.end_command_buffer(cmd)?;
+        }
+        for _ in &self.images {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "present handoff released semaphore");
+            self.released.push(semaphore);
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "present handoff acquired semaphore");
+            self.acquired.push(semaphore);
+        }
+        Ok(())
+    }
+
+    /// The release and acquire halves share everything but their stages and accesses
+    fn transfer(&self, image: vk::Image) -> vk::ImageMemoryBarrierBuilder<'static> {
+        vk::ImageMemoryBarrier::builder()
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(self.graphics)
+            .dst_queue_family_index(self.present)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+    }
+
+    /// Records releasing image `index` to the presenting family, after it's in `PRESENT_SRC_KHR`
+    ///
+    /// The submission of `cmd` must signal [`PresentHandoff::released`].
+    pub fn record_release(&self, device: &Device, cmd: vk::CommandBuffer, index: usize) {
+        let barrier = self
+            .transfer(self.images[index])
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*barrier],
+            )
+        };
+    }
+
+    /// The semaphore the graphics submission releasing image `index` signals
+    pub fn released(&self, index: usize) -> vk::Semaphore {
+        self.released[index]
+    }
+
+    /// Submits the acquire of image `index` to `queue`, from the presenting family
+    ///
+    /// Gives the semaphore presenting the image has to wait on instead of the render one.
+    pub fn acquire(
+        &self,
+        device: &Device,
+        queue: vk::Queue,
+        index: usize,
+    ) -> VkResult<vk::Semaphore> {
+        let submit = vk::SubmitInfo::builder()
+            .wait_semaphores(&self.released[index..=index])
+            .wait_dst_stage_mask(&[vk::PipelineStageFlags::ALL_COMMANDS])
+            .command_buffers(&self.acquires[index..=index])
+            .signal_semaphores(&self.acquired[index..=index]);
+        unsafe { device.queue_submit(queue, &[*submit], vk::Fence::null())? };
+        Ok(self.acquired[index])
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with every handoff, recreate this with the swapchain.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for semaphore in self.released.drain(..).chain(self.acquired.drain(..)) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        self.acquires.clear();
+        if self.command_pool != vk::CommandPool::null() {
+            leaks::untrack(self.command_pool);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
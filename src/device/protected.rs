@@ -0,0 +1,281 @@
+//! Protected content, rendering into memory the host and unprotected queues can't read
+//!
+//! This is what DRM video paths build on. With [`Feature::ProtectedMemory`] enabled and a graphics
+//! family offering protected queues, [`ProtectedQueue`] submits command buffers from a protected
+//! pool, which alone may write [`ProtectedImage`]s and the images of a swapchain made with
+//! `SwapchainCreateFlagsKHR::PROTECTED`. Whether a surface takes such swapchains is an instance
+//! level question, see [`surface_supports_protected`].
+//!
+//! Protected images can't be copied into unprotected ones, captures of protected frames come out
+//! blank or fail.
+//!
+//! [`Feature::ProtectedMemory`]: super::Feature::ProtectedMemory
+
+use std::ffi::CStr;
+
+use ash::{extensions::khr, prelude::VkResult, vk, Device};
+
+use super::{EnabledFeatures, Feature, QueueFamilies};
+use crate::{
+    leaks,
+    memory::{
+        find_memory_type,
+        usage::{self, MemoryCategory},
+    },
+    trace::traced,
+};
+
+/// Instance extensions telling whether a surface can present protected swapchains
+pub const SURFACE_PROTECTED_EXTENSIONS: [&CStr; 2] = [
+    vk::KhrGetSurfaceCapabilities2Fn::name(),
+    vk::KhrSurfaceProtectedCapabilitiesFn::name(),
+];
+
+/// The queue create info for a protected queue, `None` unless one can be made
+///
+/// Goes next to the unprotected queue of the same family, `priorities` must outlive the info.
+pub fn queue_create_info(
+    families: &QueueFamilies,
+    enabled: &EnabledFeatures,
+    priorities: &[f32],
+) -> Option<vk::DeviceQueueCreateInfo> {
+    let family = families.protected?;
+    enabled.has_feature(Feature::ProtectedMemory).then(|| {
+        vk::DeviceQueueCreateInfo::builder()
+            .flags(vk::DeviceQueueCreateFlags::PROTECTED)
+            .queue_family_index(family)
+            .queue_priorities(priorities)
+            .build()
+    })
+}
+
+/// Whether `surface` can present swapchains made with `SwapchainCreateFlagsKHR::PROTECTED`
+///
+/// # Safety
+///
+/// `ext` must come from an instance with [`SURFACE_PROTECTED_EXTENSIONS`] enabled.
+pub unsafe fn surface_supports_protected(
+    ext: &khr::GetSurfaceCapabilities2,
+    physical_device: vk::PhysicalDevice,
+    surface: vk::SurfaceKHR,
+) -> VkResult<bool> {
+    let info = vk::PhysicalDeviceSurfaceInfo2KHR::builder().surface(surface);
+    let mut protected = vk::SurfaceProtectedCapabilitiesKHR::default();
+    // ash's wrapper has no way to chain the protected capabilities
+    let mut caps = vk::SurfaceCapabilities2KHR::builder().push_next(&mut protected);
+    (ext.fp().get_physical_device_surface_capabilities2_khr)(physical_device, &*info, &mut *caps)
+        .result()?;
+    Ok(protected.supports_protected == vk::TRUE)
+}
+
+/// A protected queue of the graphics family and a pool for the command buffers it runs
+pub struct ProtectedQueue {
+    family: u32,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+}
+
+impl ProtectedQueue {
+    /// `None` unless the device was created with [`queue_create_info`]'s queue
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        families: &QueueFamilies,
+        enabled: &EnabledFeatures,
+    ) -> VkResult<Option<Self>> {
+        let Some(family) = families
+            .protected
+            .filter(|_| enabled.has_feature(Feature::ProtectedMemory))
+        else {
+            return Ok(None);
+        };
+        let queue_info = vk::DeviceQueueInfo2::builder()
+            .flags(vk::DeviceQueueCreateFlags::PROTECTED)
+            .queue_family_index(family)
+            .queue_index(0);
+        let queue = unsafe { device.get_device_queue2(&queue_info) };
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .flags(
+                vk::CommandPoolCreateFlags::PROTECTED
+                    | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            )
+            .queue_family_index(family);
+        let command_pool = unsafe { device.create_command_pool(&pool_info, None)? };
+        leaks::track(command_pool, "protected command pool");
+        Ok(Some(ProtectedQueue {
+            family,
+            queue,
+            command_pool,
+        }))
+    }
+
+    pub fn family(&self) -> u32 {
+        self.family
+    }
+
+    pub fn queue(&self) -> vk::Queue {
+        self.queue
+    }
+
+    /// Protected command buffers, the only kind that may write protected resources
+    pub fn allocate_command_buffers(
+        &self,
+        device: &Device,
+        count: u32,
+    ) -> VkResult<Vec<vk::CommandBuffer>> {
+        let info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(count);
+        unsafe { device.allocate_command_buffers(&info) }
+    }
+
+    /// Submits `submit` as a protected submission
+    ///
+    /// # Safety
+    ///
+    /// Every command buffer in `submit` must be from [`ProtectedQueue::allocate_command_buffers`].
+    pub unsafe fn submit(
+        &self,
+        device: &Device,
+        submit: vk::SubmitInfoBuilder,
+        fence: vk::Fence,
+    ) -> VkResult<()> {
+        let mut protected = vk::ProtectedSubmitInfo::builder().protected_submit(true);
+        let submit = submit.push_next(&mut protected);
+        traced!(
+            "vkQueueSubmit",
+            queue = self.queue,
+            protected = true;
+            device.queue_submit(self.queue, &[*submit], fence)
+        )
+    }
+
+    /// # Safety
+    ///
+    /// The queue must be idle, command buffers allocated from it are freed.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.command_pool);
+        device.destroy_command_pool(self.command_pool, None);
+    }
+}
+
+/// A 2D image in protected memory, for render targets protected passes write
+pub struct ProtectedImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl ProtectedImage {
+    /// Fails with `ERROR_FEATURE_NOT_PRESENT` when no memory type is protected
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        let info = vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::PROTECTED)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe { device.create_image(&info, None)? };
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::PROTECTED | vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { device.destroy_image(image, None) };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = unsafe {
+            device
+                .allocate_memory(&alloc_info, None)
+                .and_then(|memory| {
+                    device
+                        .bind_image_memory(image, memory, 0)
+                        .map(|_| memory)
+                        .inspect_err(|_| device.free_memory(memory, None))
+                })
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.destroy_image(image, None) };
+                return Err(err);
+            }
+        };
+        let aspect_mask = if usage.contains(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::COLOR
+        };
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        let view = match unsafe { device.create_image_view(&view_info, None) } {
+            Ok(view) => view,
+            Err(err) => {
+                unsafe {
+                    device.destroy_image(image, None);
+                    device.free_memory(memory, None);
+                }
+                return Err(err);
+            }
+        };
+        usage::record_allocation(MemoryCategory::RenderTarget, requirements.size);
+        leaks::track(image, "protected image");
+        leaks::track(memory, "protected image memory");
+        leaks::track(view, "protected image view");
+        Ok(ProtectedImage {
+            image,
+            view,
+            memory,
+            allocation_size: requirements.size,
+            format,
+            extent,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with the image.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.view);
+        leaks::untrack(self.image);
+        leaks::untrack(self.memory);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+        usage::release_allocation(MemoryCategory::RenderTarget, self.allocation_size);
+    }
+}
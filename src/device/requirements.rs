@@ -0,0 +1,547 @@
+//! Declaring the extensions and features a device needs or would like, and negotiating them
+//!
+//! Features of every version, and of the extensions in [`FEATURE_EXTENSIONS`], live in one
+//! [`FeatureSet`], queried and enabled through a single `pNext` chain.
+//! [`DeviceRequirements::negotiate`] rejects devices missing anything required and reports which
+//! of the optional parts a device will get.
+
+use std::ffi::CStr;
+
+use ash::{vk, Instance};
+use thiserror::Error;
+
+use super::{vk_ext, DeviceInfo};
+
+/// Extensions with a feature struct in [`FeatureSet`], those need 1.1 to be queried
+pub const FEATURE_EXTENSIONS: [&CStr; 9] = [
+    vk::ExtRobustness2Fn::name(),
+    vk::ExtDeviceFaultFn::name(),
+    vk::ExtGraphicsPipelineLibraryFn::name(),
+    vk::ExtShaderObjectFn::name(),
+    vk::ExtDescriptorBufferFn::name(),
+    vk::ExtExtendedDynamicStateFn::name(),
+    vk::ExtExtendedDynamicState2Fn::name(),
+    vk::ExtExtendedDynamicState3Fn::name(),
+    vk_ext::ExtHostImageCopyFn::name(),
+];
+
+/// The feature structs of each version, only those up to `api_version` are queried or enabled
+#[derive(Debug, Clone)]
+pub struct FeatureSet {
+    /// Version the structs are valid for, the lower of the instance's and the device's
+    pub api_version: u32,
+    /// Entries of [`FEATURE_EXTENSIONS`] whose structs are in the chain
+    pub extensions: Vec<&'static CStr>,
+    pub core: vk::PhysicalDeviceFeatures,
+    /// Introduced in 1.2, so the 1.1 features need a 1.2 device here
+    pub vulkan11: vk::PhysicalDeviceVulkan11Features,
+    pub vulkan12: vk::PhysicalDeviceVulkan12Features,
+    pub vulkan13: vk::PhysicalDeviceVulkan13Features,
+    pub robustness2: vk::PhysicalDeviceRobustness2FeaturesEXT,
+    pub device_fault: vk::PhysicalDeviceFaultFeaturesEXT,
+    pub graphics_pipeline_library: vk::PhysicalDeviceGraphicsPipelineLibraryFeaturesEXT,
+    pub shader_object: vk::PhysicalDeviceShaderObjectFeaturesEXT,
+    pub descriptor_buffer: vk::PhysicalDeviceDescriptorBufferFeaturesEXT,
+    /// Core in 1.3, only queried from the extension before that
+    pub extended_dynamic_state: vk::PhysicalDeviceExtendedDynamicStateFeaturesEXT,
+    pub extended_dynamic_state2: vk::PhysicalDeviceExtendedDynamicState2FeaturesEXT,
+    pub extended_dynamic_state3: vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT,
+    pub host_image_copy: vk_ext::PhysicalDeviceHostImageCopyFeaturesEXT,
+}
+
+impl FeatureSet {
+    /// Nothing enabled
+    pub fn new(api_version: u32) -> Self {
+        FeatureSet {
+            api_version,
+            extensions: Vec::new(),
+            core: vk::PhysicalDeviceFeatures::default(),
+            vulkan11: vk::PhysicalDeviceVulkan11Features::default(),
+            vulkan12: vk::PhysicalDeviceVulkan12Features::default(),
+            vulkan13: vk::PhysicalDeviceVulkan13Features::default(),
+            robustness2: vk::PhysicalDeviceRobustness2FeaturesEXT::default(),
+            device_fault: vk::PhysicalDeviceFaultFeaturesEXT::default(),
+            graphics_pipeline_library:
+                vk::PhysicalDeviceGraphicsPipelineLibraryFeaturesEXT::default(),
+            shader_object: vk::PhysicalDeviceShaderObjectFeaturesEXT::default(),
+            descriptor_buffer: vk::PhysicalDeviceDescriptorBufferFeaturesEXT::default(),
+            extended_dynamic_state: vk::PhysicalDeviceExtendedDynamicStateFeaturesEXT::default(),
+            extended_dynamic_state2: vk::PhysicalDeviceExtendedDynamicState2FeaturesEXT::default(),
+            extended_dynamic_state3: vk::PhysicalDeviceExtendedDynamicState3FeaturesEXT::default(),
+            host_image_copy: vk_ext::PhysicalDeviceHostImageCopyFeaturesEXT::default(),
+        }
+    }
+
+    /// What `physical_device` supports, through `vkGetPhysicalDeviceFeatures2` from 1.1
+    ///
+    /// # Safety
+    ///
+    /// `physical_device` must come from `instance`, and `api_version` must not be above the
+    /// instance's or the device's version. `supports` tells whether the device has an extension.
+    pub unsafe fn query(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        api_version: u32,
+        supports: impl Fn(&CStr) -> bool,
+    ) -> Self {
+        let mut set = FeatureSet::new(api_version);
+        if api_version < vk::API_VERSION_1_1 {
+            set.core = instance.get_physical_device_features(physical_device);
+            return set;
+        }
+        set.extensions = FEATURE_EXTENSIONS
+            .into_iter()
+            .filter(|ext| supports(ext))
+            .collect();
+        let mut features2 = set.chain();
+        instance.get_physical_device_features2(physical_device, &mut features2);
+        let core = features2.features;
+        set.core = core;
+        set.unlink();
+        set
+    }
+
+    /// Links the structs valid for `api_version` behind a `PhysicalDeviceFeatures2`, for
+    /// `DeviceCreateInfo::push_next` on 1.1 and up
+    pub fn chain(&mut self) -> vk::PhysicalDeviceFeatures2Builder<'_> {
+        self.unlink();
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder().features(self.core);
+        if self.api_version >= vk::API_VERSION_1_2 {
+            features2 = features2
+                .push_next(&mut self.vulkan11)
+                .push_next(&mut self.vulkan12);
+        }
+        if self.api_version >= vk::API_VERSION_1_3 {
+            features2 = features2.push_next(&mut self.vulkan13);
+        }
+        if self.extensions.contains(&vk::ExtRobustness2Fn::name()) {
+            features2 = features2.push_next(&mut self.robustness2);
+        }
+        if self.extensions.contains(&vk::ExtDeviceFaultFn::name()) {
+            features2 = features2.push_next(&mut self.device_fault);
+        }
+        if self
+            .extensions
+            .contains(&vk::ExtGraphicsPipelineLibraryFn::name())
+        {
+            features2 = features2.push_next(&mut self.graphics_pipeline_library);
+        }
+        if self.extensions.contains(&vk::ExtShaderObjectFn::name()) {
+            features2 = features2.push_next(&mut self.shader_object);
+        }
+        if self.extensions.contains(&vk::ExtDescriptorBufferFn::name()) {
+            features2 = features2.push_next(&mut self.descriptor_buffer);
+        }
+        if self
+            .extensions
+            .contains(&vk::ExtExtendedDynamicStateFn::name())
+        {
+            features2 = features2.push_next(&mut self.extended_dynamic_state);
+        }
+        if self
+            .extensions
+            .contains(&vk::ExtExtendedDynamicState2Fn::name())
+        {
+            features2 = features2.push_next(&mut self.extended_dynamic_state2);
+        }
+        if self
+            .extensions
+            .contains(&vk::ExtExtendedDynamicState3Fn::name())
+        {
+            features2 = features2.push_next(&mut self.extended_dynamic_state3);
+        }
+        if self
+            .extensions
+            .contains(&vk_ext::ExtHostImageCopyFn::name())
+        {
+            features2 = features2.push_next(&mut self.host_image_copy);
+        }
+        features2
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        feature.api_version() <= self.api_version
+            && feature
+                .extension()
+                .is_none_or(|ext| self.extensions.contains(&ext))
+            && feature.get(self) == vk::TRUE
+    }
+
+    /// Also adds the feature's extension, if it has one
+    pub fn enable(&mut self, feature: Feature) {
+        if let Some(ext) = feature.extension() {
+            if !self.extensions.contains(&ext) {
+                self.extensions.push(ext);
+            }
+        }
+        *feature.get_mut(self) = vk::TRUE;
+    }
+
+    fn unlink(&mut self) {
+        self.vulkan11.p_next = std::ptr::null_mut();
+        self.vulkan12.p_next = std::ptr::null_mut();
+        self.vulkan13.p_next = std::ptr::null_mut();
+        self.robustness2.p_next = std::ptr::null_mut();
+        self.device_fault.p_next = std::ptr::null_mut();
+        self.graphics_pipeline_library.p_next = std::ptr::null_mut();
+        self.shader_object.p_next = std::ptr::null_mut();
+        self.descriptor_buffer.p_next = std::ptr::null_mut();
+        self.extended_dynamic_state.p_next = std::ptr::null_mut();
+        self.extended_dynamic_state2.p_next = std::ptr::null_mut();
+        self.extended_dynamic_state3.p_next = std::ptr::null_mut();
+        self.host_image_copy.p_next = std::ptr::null_mut();
+    }
+}
+
+macro_rules! features {
+    (@extension) => { None };
+    (@extension $extension:path) => { Some(<$extension>::name()) };
+    (
+        $($feature:ident => $set:ident . $field:ident @ $version:ident $(+ $extension:path)?),*
+        $(,)?
+    ) => {
+        /// A feature bit of one of the [`FeatureSet`] structs
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Feature {
+            $($feature,)*
+        }
+
+        impl Feature {
+            pub const ALL: &'static [Feature] = &[$(Feature::$feature,)*];
+
+            /// Version whose feature struct holds this, not when it was introduced
+            pub fn api_version(self) -> u32 {
+                match self {
+                    $(Feature::$feature => vk::$version,)*
+                }
+            }
+
+            /// Extension whose feature struct holds this, enabled along with it
+            pub fn extension(self) -> Option<&'static CStr> {
+                match self {
+                    $(Feature::$feature => features!(@extension $($extension)?),)*
+                }
+            }
+
+            fn get(self, set: &FeatureSet) -> vk::Bool32 {
+                match self {
+                    $(Feature::$feature => set.$set.$field,)*
+                }
+            }
+
+            fn get_mut(self, set: &mut FeatureSet) -> &mut vk::Bool32 {
+                match self {
+                    $(Feature::$feature => &mut set.$set.$field,)*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    RobustBufferAccess => core.robust_buffer_access @ API_VERSION_1_0,
+    GeometryShader => core.geometry_shader @ API_VERSION_1_0,
+    TessellationShader => core.tessellation_shader @ API_VERSION_1_0,
+    IndependentBlend => core.independent_blend @ API_VERSION_1_0,
+    MultiDrawIndirect => core.multi_draw_indirect @ API_VERSION_1_0,
+    DrawIndirectFirstInstance => core.draw_indirect_first_instance @ API_VERSION_1_0,
+    DepthClamp => core.depth_clamp @ API_VERSION_1_0,
+    DepthBiasClamp => core.depth_bias_clamp @ API_VERSION_1_0,
+    FillModeNonSolid => core.fill_mode_non_solid @ API_VERSION_1_0,
+    WideLines => core.wide_lines @ API_VERSION_1_0,
+    SamplerAnisotropy => core.sampler_anisotropy @ API_VERSION_1_0,
+    TextureCompressionBc => core.texture_compression_bc @ API_VERSION_1_0,
+    PipelineStatisticsQuery => core.pipeline_statistics_query @ API_VERSION_1_0,
+    ShaderInt64 => core.shader_int64 @ API_VERSION_1_0,
+    SparseBinding => core.sparse_binding @ API_VERSION_1_0,
+    SparseResidencyBuffer => core.sparse_residency_buffer @ API_VERSION_1_0,
+    SparseResidencyImage2D => core.sparse_residency_image2_d @ API_VERSION_1_0,
+    StorageBuffer16BitAccess => vulkan11.storage_buffer16_bit_access @ API_VERSION_1_2,
+    Multiview => vulkan11.multiview @ API_VERSION_1_2,
+    ProtectedMemory => vulkan11.protected_memory @ API_VERSION_1_2,
+    SamplerYcbcrConversion => vulkan11.sampler_ycbcr_conversion @ API_VERSION_1_2,
+    ShaderDrawParameters => vulkan11.shader_draw_parameters @ API_VERSION_1_2,
+    DrawIndirectCount => vulkan12.draw_indirect_count @ API_VERSION_1_2,
+    StorageBuffer8BitAccess => vulkan12.storage_buffer8_bit_access @ API_VERSION_1_2,
+    ShaderFloat16 => vulkan12.shader_float16 @ API_VERSION_1_2,
+    ShaderInt8 => vulkan12.shader_int8 @ API_VERSION_1_2,
+    DescriptorIndexing => vulkan12.descriptor_indexing @ API_VERSION_1_2,
+    ShaderSampledImageArrayNonUniformIndexing =>
+        vulkan12.shader_sampled_image_array_non_uniform_indexing @ API_VERSION_1_2,
+    DescriptorBindingSampledImageUpdateAfterBind =>
+        vulkan12.descriptor_binding_sampled_image_update_after_bind @ API_VERSION_1_2,
+    DescriptorBindingPartiallyBound =>
+        vulkan12.descriptor_binding_partially_bound @ API_VERSION_1_2,
+    DescriptorBindingVariableDescriptorCount =>
+        vulkan12.descriptor_binding_variable_descriptor_count @ API_VERSION_1_2,
+    RuntimeDescriptorArray => vulkan12.runtime_descriptor_array @ API_VERSION_1_2,
+    ScalarBlockLayout => vulkan12.scalar_block_layout @ API_VERSION_1_2,
+    HostQueryReset => vulkan12.host_query_reset @ API_VERSION_1_2,
+    TimelineSemaphore => vulkan12.timeline_semaphore @ API_VERSION_1_2,
+    BufferDeviceAddress => vulkan12.buffer_device_address @ API_VERSION_1_2,
+    PipelineCreationCacheControl => vulkan13.pipeline_creation_cache_control @ API_VERSION_1_3,
+    ShaderDemoteToHelperInvocation => vulkan13.shader_demote_to_helper_invocation @ API_VERSION_1_3,
+    Synchronization2 => vulkan13.synchronization2 @ API_VERSION_1_3,
+    DynamicRendering => vulkan13.dynamic_rendering @ API_VERSION_1_3,
+    Maintenance4 => vulkan13.maintenance4 @ API_VERSION_1_3,
+    RobustBufferAccess2 =>
+        robustness2.robust_buffer_access2 @ API_VERSION_1_1 + vk::ExtRobustness2Fn,
+    RobustImageAccess2 =>
+        robustness2.robust_image_access2 @ API_VERSION_1_1 + vk::ExtRobustness2Fn,
+    NullDescriptor => robustness2.null_descriptor @ API_VERSION_1_1 + vk::ExtRobustness2Fn,
+    DeviceFault => device_fault.device_fault @ API_VERSION_1_1 + vk::ExtDeviceFaultFn,
+    DeviceFaultVendorBinary =>
+        device_fault.device_fault_vendor_binary @ API_VERSION_1_1 + vk::ExtDeviceFaultFn,
+    GraphicsPipelineLibrary => graphics_pipeline_library.graphics_pipeline_library
+        @ API_VERSION_1_1 + vk::ExtGraphicsPipelineLibraryFn,
+    ShaderObject => shader_object.shader_object @ API_VERSION_1_1 + vk::ExtShaderObjectFn,
+    DescriptorBuffer =>
+        descriptor_buffer.descriptor_buffer @ API_VERSION_1_1 + vk::ExtDescriptorBufferFn,
+    ExtendedDynamicState => extended_dynamic_state.extended_dynamic_state
+        @ API_VERSION_1_1 + vk::ExtExtendedDynamicStateFn,
+    ExtendedDynamicState2 => extended_dynamic_state2.extended_dynamic_state2
+        @ API_VERSION_1_1 + vk::ExtExtendedDynamicState2Fn,
+    ExtendedDynamicState3PolygonMode =>
+        extended_dynamic_state3.extended_dynamic_state3_polygon_mode
+        @ API_VERSION_1_1 + vk::ExtExtendedDynamicState3Fn,
+    ExtendedDynamicState3DepthClampEnable =>
+        extended_dynamic_state3.extended_dynamic_state3_depth_clamp_enable
+        @ API_VERSION_1_1 + vk::ExtExtendedDynamicState3Fn,
+    // Its dependencies are core in 1.3, so they never need enabling
+    HostImageCopy =>
+        host_image_copy.host_image_copy @ API_VERSION_1_3 + vk_ext::ExtHostImageCopyFn,
+}
+
+/// Everything a device is missing from the required parts of a [`DeviceRequirements`]
+#[derive(Debug, Clone, Error)]
+#[error("device {name} is missing {}", self.describe())]
+pub struct Unsupported {
+    pub name: String,
+    /// The version needed when the device's is too low
+    pub api_version: Option<u32>,
+    pub extensions: Vec<&'static CStr>,
+    pub features: Vec<Feature>,
+}
+
+impl Unsupported {
+    fn describe(&self) -> String {
+        let version = self.api_version.map(|version| {
+            format!(
+                "Vulkan {}.{}",
+                vk::api_version_major(version),
+                vk::api_version_minor(version)
+            )
+        });
+        version
+            .into_iter()
+            .chain(
+                self.extensions
+                    .iter()
+                    .map(|ext| ext.to_string_lossy().into_owned()),
+            )
+            .chain(self.features.iter().map(|feature| format!("{feature:?}")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// What negotiation settled on, pass it on to device creation
+#[derive(Debug, Clone)]
+pub struct EnabledFeatures {
+    pub extensions: Vec<&'static CStr>,
+    pub features: FeatureSet,
+    /// The optional extensions that made it into `extensions`
+    pub optional_extensions: Vec<&'static CStr>,
+    /// The optional features that made it into `features`
+    pub optional_features: Vec<Feature>,
+}
+
+impl EnabledFeatures {
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.extensions.contains(&name)
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.has(feature)
+    }
+}
+
+/// Code paths the created device allows, for choosing between them at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version the device was created with
+    pub api_version: u32,
+    pub vulkan11: bool,
+    pub vulkan12: bool,
+    pub vulkan13: bool,
+    pub synchronization2: bool,
+    pub dynamic_rendering: bool,
+    pub timeline_semaphores: bool,
+    pub descriptor_indexing: bool,
+    pub buffer_device_address: bool,
+    /// Out of bounds accesses are clamped or discarded rather than undefined
+    pub robust_access: bool,
+    /// Null handles may be written to descriptors
+    pub null_descriptor: bool,
+    /// Pipelines can be linked from parts, see [`crate::pipeline::library`]
+    pub pipeline_library: bool,
+    /// Drawing with shader objects and dynamic state, see [`crate::pipeline::shader_object`]
+    pub shader_object: bool,
+    /// Descriptors can be written straight into buffers, see [`crate::descriptor`]
+    pub descriptor_buffer: bool,
+    /// Cull mode, front face and depth test state can be set while recording
+    pub extended_dynamic_state: bool,
+    /// Depth bias, primitive restart and rasterizer discard can be toggled while recording
+    pub extended_dynamic_state2: bool,
+    /// Depth can be clamped instead of clipped, e.g. for shadow casters behind the light
+    pub depth_clamp: bool,
+    /// Depth bias may be limited to a maximum offset
+    pub depth_bias_clamp: bool,
+    /// Textures can be written from the host without staging, see [`crate::texture::host_copy`]
+    pub host_image_copy: bool,
+}
+
+impl EnabledFeatures {
+    pub fn capabilities(&self) -> Capabilities {
+        let api_version = self.features.api_version;
+        Capabilities {
+            api_version,
+            vulkan11: api_version >= vk::API_VERSION_1_1,
+            vulkan12: api_version >= vk::API_VERSION_1_2,
+            vulkan13: api_version >= vk::API_VERSION_1_3,
+            synchronization2: self.has_feature(Feature::Synchronization2),
+            dynamic_rendering: self.has_feature(Feature::DynamicRendering),
+            timeline_semaphores: self.has_feature(Feature::TimelineSemaphore),
+            descriptor_indexing: self.has_feature(Feature::DescriptorIndexing),
+            buffer_device_address: self.has_feature(Feature::BufferDeviceAddress),
+            robust_access: self.has_feature(Feature::RobustBufferAccess),
+            null_descriptor: self.has_feature(Feature::NullDescriptor),
+            pipeline_library: self.has_feature(Feature::GraphicsPipelineLibrary)
+                && self.has_extension(vk::KhrPipelineLibraryFn::name()),
+            // Shader objects only draw with dynamic rendering
+            shader_object: self.has_feature(Feature::ShaderObject)
+                && self.has_feature(Feature::DynamicRendering),
+            // Descriptor buffers are bound by address
+            descriptor_buffer: self.has_feature(Feature::DescriptorBuffer)
+                && self.has_feature(Feature::BufferDeviceAddress),
+            extended_dynamic_state: api_version >= vk::API_VERSION_1_3
+                || self.has_feature(Feature::ExtendedDynamicState),
+            extended_dynamic_state2: api_version >= vk::API_VERSION_1_3
+                || self.has_feature(Feature::ExtendedDynamicState2),
+            depth_clamp: self.has_feature(Feature::DepthClamp),
+            depth_bias_clamp: self.has_feature(Feature::DepthBiasClamp),
+            host_image_copy: self.has_feature(Feature::HostImageCopy),
+        }
+    }
+}
+
+/// Extensions and features a device must have, and ones enabled only where supported
+#[derive(Debug, Clone)]
+pub struct DeviceRequirements {
+    pub api_version: u32,
+    pub required_extensions: Vec<&'static CStr>,
+    pub optional_extensions: Vec<&'static CStr>,
+    pub required_features: Vec<Feature>,
+    pub optional_features: Vec<Feature>,
+}
+
+impl Default for DeviceRequirements {
+    fn default() -> Self {
+        DeviceRequirements {
+            api_version: vk::API_VERSION_1_0,
+            required_extensions: Vec::new(),
+            optional_extensions: Vec::new(),
+            required_features: Vec::new(),
+            optional_features: Vec::new(),
+        }
+    }
+}
+
+impl DeviceRequirements {
+    /// Lowest Vulkan version a device may report
+    pub fn with_api_version(mut self, api_version: u32) -> Self {
+        self.api_version = api_version;
+        self
+    }
+
+    pub fn require_extensions(mut self, extensions: &[&'static CStr]) -> Self {
+        self.required_extensions.extend_from_slice(extensions);
+        self
+    }
+
+    pub fn request_extensions(mut self, extensions: &[&'static CStr]) -> Self {
+        self.optional_extensions.extend_from_slice(extensions);
+        self
+    }
+
+    pub fn require_features(mut self, features: &[Feature]) -> Self {
+        self.required_features.extend_from_slice(features);
+        self
+    }
+
+    pub fn request_features(mut self, features: &[Feature]) -> Self {
+        self.optional_features.extend_from_slice(features);
+        self
+    }
+
+    /// What would be enabled on the device `info` describes, or what it lacks
+    pub fn negotiate(&self, info: &DeviceInfo) -> Result<EnabledFeatures, Unsupported> {
+        let supported = &info.features;
+        let missing = Unsupported {
+            name: info.name.clone(),
+            api_version: (supported.api_version < self.api_version).then_some(self.api_version),
+            extensions: self
+                .required_extensions
+                .iter()
+                .copied()
+                .filter(|ext| !info.supports_extension(ext))
+                .collect(),
+            features: self
+                .required_features
+                .iter()
+                .copied()
+                .filter(|&feature| !supported.has(feature))
+                .collect(),
+        };
+        if missing.api_version.is_some()
+            || !missing.extensions.is_empty()
+            || !missing.features.is_empty()
+        {
+            return Err(missing);
+        }
+
+        let optional_extensions: Vec<_> = self
+            .optional_extensions
+            .iter()
+            .copied()
+            .filter(|ext| info.supports_extension(ext))
+            .collect();
+        let optional_features: Vec<_> = self
+            .optional_features
+            .iter()
+            .copied()
+            .filter(|&feature| supported.has(feature))
+            .collect();
+        let mut features = FeatureSet::new(supported.api_version);
+        for &feature in self.required_features.iter().chain(&optional_features) {
+            features.enable(feature);
+        }
+        let mut extensions: Vec<_> = self
+            .required_extensions
+            .iter()
+            .chain(&optional_extensions)
+            .copied()
+            .collect();
+        for ext in &features.extensions {
+            if !extensions.contains(ext) {
+                extensions.push(ext);
+            }
+        }
+        Ok(EnabledFeatures {
+            extensions,
+            features,
+            optional_extensions,
+            optional_features,
+        })
+    }
+}
@@ -0,0 +1,477 @@
+//! Extensions ash 0.37 predates, declared after the registry and named like ash's own
+//!
+//! Kept out of `ash::vk` so it's clear at each use which declarations are this crate's. That
+//! includes the final video encode extensions, where ash only has their provisional revisions.
+
+use std::{
+    ffi::{c_void, CStr},
+    mem, ptr,
+};
+
+use ash::vk::{
+    self,
+    native::{
+        StdVideoH264CabacInitIdc, StdVideoH264DisableDeblockingFilterIdc, StdVideoH264LevelIdc,
+        StdVideoH264MemMgmtControlOp, StdVideoH264ModificationOfPicNumsIdc,
+        StdVideoH264PictureParameterSet, StdVideoH264PictureType, StdVideoH264ProfileIdc,
+        StdVideoH264SequenceParameterSet, StdVideoH264SliceType,
+    },
+};
+
+/// `Default` for structs of integers, handles and pointers, zeroed apart from the `s_type`
+macro_rules! zeroed_default {
+    ($($ty:ident = $s_type:literal,)*) => {$(
+        impl Default for $ty {
+            fn default() -> Self {
+                $ty {
+                    s_type: vk::StructureType::from_raw($s_type),
+                    // All zero is a null pointer or handle, or a zero integer
+                    ..unsafe { mem::zeroed() }
+                }
+            }
+        }
+    )*};
+}
+
+/// `VK_EXT_host_image_copy`, named like ash's extension types
+pub struct ExtHostImageCopyFn;
+
+impl ExtHostImageCopyFn {
+    pub const fn name() -> &'static CStr {
+        c"VK_EXT_host_image_copy"
+    }
+}
+
+/// `VK_IMAGE_USAGE_HOST_TRANSFER_BIT_EXT`
+pub const IMAGE_USAGE_HOST_TRANSFER: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(1 << 22);
+/// `VK_FORMAT_FEATURE_2_HOST_IMAGE_TRANSFER_BIT_EXT`
+pub const FORMAT_FEATURE_HOST_IMAGE_TRANSFER: vk::FormatFeatureFlags2 =
+    vk::FormatFeatureFlags2::from_raw(1 << 46);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicalDeviceHostImageCopyFeaturesEXT {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub host_image_copy: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceHostImageCopyFeaturesEXT {
+    fn default() -> Self {
+        PhysicalDeviceHostImageCopyFeaturesEXT {
+            s_type: vk::StructureType::from_raw(1_000_270_000),
+            p_next: ptr::null_mut(),
+            host_image_copy: vk::FALSE,
+        }
+    }
+}
+
+unsafe impl vk::ExtendsPhysicalDeviceFeatures2 for PhysicalDeviceHostImageCopyFeaturesEXT {}
+unsafe impl vk::ExtendsDeviceCreateInfo for PhysicalDeviceHostImageCopyFeaturesEXT {}
+
+#[repr(C)]
+pub struct PhysicalDeviceHostImageCopyPropertiesEXT {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub copy_src_layout_count: u32,
+    pub p_copy_src_layouts: *mut vk::ImageLayout,
+    pub copy_dst_layout_count: u32,
+    pub p_copy_dst_layouts: *mut vk::ImageLayout,
+    pub optimal_tiling_layout_uuid: [u8; vk::UUID_SIZE],
+    pub identical_memory_type_requirements: vk::Bool32,
+}
+
+unsafe impl vk::ExtendsPhysicalDeviceProperties2 for PhysicalDeviceHostImageCopyPropertiesEXT {}
+
+#[repr(C)]
+pub struct MemoryToImageCopyEXT {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub p_host_pointer: *const c_void,
+    pub memory_row_length: u32,
+    pub memory_image_height: u32,
+    pub image_subresource: vk::ImageSubresourceLayers,
+    pub image_offset: vk::Offset3D,
+    pub image_extent: vk::Extent3D,
+}
+
+#[repr(C)]
+pub struct CopyMemoryToImageInfoEXT {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub dst_image: vk::Image,
+    pub dst_image_layout: vk::ImageLayout,
+    pub region_count: u32,
+    pub p_regions: *const MemoryToImageCopyEXT,
+}
+
+#[repr(C)]
+pub struct HostImageLayoutTransitionInfoEXT {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub image: vk::Image,
+    pub old_layout: vk::ImageLayout,
+    pub new_layout: vk::ImageLayout,
+    pub subresource_range: vk::ImageSubresourceRange,
+}
+
+pub type CopyMemoryToImage =
+    unsafe extern "system" fn(vk::Device, *const CopyMemoryToImageInfoEXT) -> vk::Result;
+pub type TransitionImageLayout = unsafe extern "system" fn(
+    vk::Device,
+    u32,
+    *const HostImageLayoutTransitionInfoEXT,
+) -> vk::Result;
+
+/// `VK_KHR_video_encode_queue` at revision 12, its commands loaded by name
+#[derive(Clone, Copy)]
+pub struct KhrVideoEncodeQueueFn {
+    pub cmd_encode_video_khr: CmdEncodeVideo,
+    pub get_encoded_video_session_parameters_khr: GetEncodedVideoSessionParameters,
+}
+
+impl KhrVideoEncodeQueueFn {
+    pub const fn name() -> &'static CStr {
+        c"VK_KHR_video_encode_queue"
+    }
+
+    /// `None` when `load` gives a null pointer for any command
+    pub fn load(mut load: impl FnMut(&CStr) -> *const c_void) -> Option<Self> {
+        let mut load = |name| Some(load(name)).filter(|pointer| !pointer.is_null());
+        let encode = load(c"vkCmdEncodeVideoKHR")?;
+        let get_parameters = load(c"vkGetEncodedVideoSessionParametersKHR")?;
+        // Both are the commands of these names, with these signatures
+        unsafe {
+            Some(KhrVideoEncodeQueueFn {
+                cmd_encode_video_khr: mem::transmute::<*const c_void, CmdEncodeVideo>(encode),
+                get_encoded_video_session_parameters_khr: mem::transmute::<
+                    *const c_void,
+                    GetEncodedVideoSessionParameters,
+                >(get_parameters),
+            })
+        }
+    }
+}
+
+/// `VK_VIDEO_CODING_CONTROL_ENCODE_RATE_CONTROL_BIT_KHR`
+pub const VIDEO_CODING_CONTROL_ENCODE_RATE_CONTROL: vk::VideoCodingControlFlagsKHR =
+    vk::VideoCodingControlFlagsKHR::from_raw(1 << 1);
+
+#[repr(C)]
+pub struct VideoEncodeInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub dst_buffer: vk::Buffer,
+    pub dst_buffer_offset: vk::DeviceSize,
+    pub dst_buffer_range: vk::DeviceSize,
+    pub src_picture_resource: vk::VideoPictureResourceInfoKHR,
+    pub p_setup_reference_slot: *const vk::VideoReferenceSlotInfoKHR,
+    pub reference_slot_count: u32,
+    pub p_reference_slots: *const vk::VideoReferenceSlotInfoKHR,
+    pub preceding_externally_encoded_bytes: u32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeCapabilitiesKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub flags: vk::Flags,
+    pub rate_control_modes: vk::VideoEncodeRateControlModeFlagsKHR,
+    pub max_rate_control_layers: u32,
+    pub max_bitrate: u64,
+    pub max_quality_levels: u32,
+    pub encode_input_picture_granularity: vk::Extent2D,
+    pub supported_encode_feedback_flags: vk::VideoEncodeFeedbackFlagsKHR,
+}
+
+#[repr(C)]
+pub struct VideoEncodeUsageInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub video_usage_hints: vk::VideoEncodeUsageFlagsKHR,
+    pub video_content_hints: vk::VideoEncodeContentFlagsKHR,
+    pub tuning_mode: vk::VideoEncodeTuningModeKHR,
+}
+
+#[repr(C)]
+pub struct QueryPoolVideoEncodeFeedbackCreateInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub encode_feedback_flags: vk::VideoEncodeFeedbackFlagsKHR,
+}
+
+#[repr(C)]
+pub struct VideoEncodeRateControlInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub rate_control_mode: vk::VideoEncodeRateControlModeFlagsKHR,
+    pub layer_count: u32,
+    pub p_layers: *const VideoEncodeRateControlLayerInfoKHR,
+    pub virtual_buffer_size_in_ms: u32,
+    pub initial_virtual_buffer_size_in_ms: u32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeRateControlLayerInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub average_bitrate: u64,
+    pub max_bitrate: u64,
+    pub frame_rate_numerator: u32,
+    pub frame_rate_denominator: u32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeSessionParametersGetInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub video_session_parameters: vk::VideoSessionParametersKHR,
+}
+
+#[repr(C)]
+pub struct VideoEncodeSessionParametersFeedbackInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub has_overrides: vk::Bool32,
+}
+
+pub type CmdEncodeVideo = unsafe extern "system" fn(vk::CommandBuffer, *const VideoEncodeInfoKHR);
+pub type GetEncodedVideoSessionParameters = unsafe extern "system" fn(
+    vk::Device,
+    *const VideoEncodeSessionParametersGetInfoKHR,
+    *mut VideoEncodeSessionParametersFeedbackInfoKHR,
+    *mut usize,
+    *mut c_void,
+) -> vk::Result;
+
+zeroed_default! {
+    VideoEncodeInfoKHR = 1_000_299_000,
+    VideoEncodeRateControlInfoKHR = 1_000_299_001,
+    VideoEncodeRateControlLayerInfoKHR = 1_000_299_002,
+    VideoEncodeCapabilitiesKHR = 1_000_299_003,
+    VideoEncodeUsageInfoKHR = 1_000_299_004,
+    QueryPoolVideoEncodeFeedbackCreateInfoKHR = 1_000_299_005,
+    VideoEncodeSessionParametersGetInfoKHR = 1_000_299_009,
+    VideoEncodeSessionParametersFeedbackInfoKHR = 1_000_299_010,
+}
+
+/// `VK_KHR_video_encode_h264` at revision 14, which replaced `VK_EXT_video_encode_h264`
+pub struct KhrVideoEncodeH264Fn;
+
+impl KhrVideoEncodeH264Fn {
+    pub const fn name() -> &'static CStr {
+        c"VK_KHR_video_encode_h264"
+    }
+}
+
+/// `VK_VIDEO_CODEC_OPERATION_ENCODE_H264_BIT_KHR`
+pub const VIDEO_CODEC_OPERATION_ENCODE_H264: vk::VideoCodecOperationFlagsKHR =
+    vk::VideoCodecOperationFlagsKHR::from_raw(1 << 16);
+/// `VK_VIDEO_ENCODE_H264_STD_ENTROPY_CODING_MODE_FLAG_SET_BIT_KHR`, in `std_syntax_flags`
+pub const VIDEO_ENCODE_H264_STD_ENTROPY_CODING_MODE_FLAG_SET: vk::Flags = 1 << 12;
+/// `VK_VIDEO_ENCODE_H264_RATE_CONTROL_REGULAR_GOP_BIT_KHR`
+pub const VIDEO_ENCODE_H264_RATE_CONTROL_REGULAR_GOP: vk::Flags = 1 << 1;
+/// `VK_VIDEO_ENCODE_H264_RATE_CONTROL_REFERENCE_PATTERN_FLAT_BIT_KHR`
+pub const VIDEO_ENCODE_H264_RATE_CONTROL_REFERENCE_PATTERN_FLAT: vk::Flags = 1 << 2;
+
+#[repr(C)]
+pub struct VideoEncodeH264CapabilitiesKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub flags: vk::Flags,
+    pub max_level_idc: StdVideoH264LevelIdc,
+    pub max_slice_count: u32,
+    pub max_p_picture_l0_reference_count: u32,
+    pub max_b_picture_l0_reference_count: u32,
+    pub max_l1_reference_count: u32,
+    pub max_temporal_layer_count: u32,
+    pub expect_dyadic_temporal_layer_pattern: vk::Bool32,
+    pub min_qp: i32,
+    pub max_qp: i32,
+    pub prefers_gop_remaining_frames: vk::Bool32,
+    pub requires_gop_remaining_frames: vk::Bool32,
+    pub std_syntax_flags: vk::Flags,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264ProfileInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub std_profile_idc: StdVideoH264ProfileIdc,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264SessionParametersAddInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub std_sps_count: u32,
+    pub p_std_sp_ss: *const StdVideoH264SequenceParameterSet,
+    pub std_pps_count: u32,
+    pub p_std_pp_ss: *const StdVideoH264PictureParameterSet,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264SessionParametersCreateInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub max_std_sps_count: u32,
+    pub max_std_pps_count: u32,
+    pub p_parameters_add_info: *const VideoEncodeH264SessionParametersAddInfoKHR,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264SessionParametersGetInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub write_std_sps: vk::Bool32,
+    pub write_std_pps: vk::Bool32,
+    pub std_sps_id: u32,
+    pub std_pps_id: u32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264SessionParametersFeedbackInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *mut c_void,
+    pub has_std_sps_overrides: vk::Bool32,
+    pub has_std_pps_overrides: vk::Bool32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264PictureInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub nalu_slice_entry_count: u32,
+    pub p_nalu_slice_entries: *const VideoEncodeH264NaluSliceInfoKHR,
+    pub p_std_picture_info: *const StdVideoEncodeH264PictureInfo,
+    pub generate_prefix_nalu: vk::Bool32,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264DpbSlotInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub p_std_reference_info: *const StdVideoEncodeH264ReferenceInfo,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264NaluSliceInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub constant_qp: i32,
+    pub p_std_slice_header: *const StdVideoEncodeH264SliceHeader,
+}
+
+#[repr(C)]
+pub struct VideoEncodeH264RateControlInfoKHR {
+    pub s_type: vk::StructureType,
+    pub p_next: *const c_void,
+    pub flags: vk::Flags,
+    pub gop_frame_count: u32,
+    pub idr_period: u32,
+    pub consecutive_b_frame_count: u32,
+    pub temporal_layer_count: u32,
+}
+
+zeroed_default! {
+    VideoEncodeH264CapabilitiesKHR = 1_000_038_000,
+    VideoEncodeH264SessionParametersCreateInfoKHR = 1_000_038_001,
+    VideoEncodeH264SessionParametersAddInfoKHR = 1_000_038_002,
+    VideoEncodeH264PictureInfoKHR = 1_000_038_003,
+    VideoEncodeH264DpbSlotInfoKHR = 1_000_038_004,
+    VideoEncodeH264NaluSliceInfoKHR = 1_000_038_005,
+    VideoEncodeH264ProfileInfoKHR = 1_000_038_007,
+    VideoEncodeH264RateControlInfoKHR = 1_000_038_008,
+    VideoEncodeH264SessionParametersGetInfoKHR = 1_000_038_012,
+    VideoEncodeH264SessionParametersFeedbackInfoKHR = 1_000_038_013,
+}
+
+// `vulkan_video_codec_h264std_encode.h` 1.0.0, named like ash's `native` bindings. The flags are
+// C bitfields, their first field in the lowest bit.
+
+/// `StdVideoEncodeH264PictureInfoFlags::IdrPicFlag`
+pub const STD_VIDEO_ENCODE_H264_PICTURE_IDR: u32 = 1;
+/// `StdVideoEncodeH264PictureInfoFlags::is_reference`
+pub const STD_VIDEO_ENCODE_H264_PICTURE_IS_REFERENCE: u32 = 1 << 1;
+/// `STD_VIDEO_H264_NO_REFERENCE_PICTURE`, an unused entry of a reference list
+pub const STD_VIDEO_H264_NO_REFERENCE_PICTURE: u8 = 0xff;
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct StdVideoEncodeH264PictureInfo {
+    pub flags: u32,
+    pub seq_parameter_set_id: u8,
+    pub pic_parameter_set_id: u8,
+    pub idr_pic_id: u16,
+    pub primary_pic_type: StdVideoH264PictureType,
+    pub frame_num: u32,
+    pub PicOrderCnt: i32,
+    pub temporal_id: u8,
+    pub reserved1: [u8; 3],
+    pub pRefLists: *const StdVideoEncodeH264ReferenceListsInfo,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct StdVideoEncodeH264ReferenceInfo {
+    pub flags: u32,
+    pub primary_pic_type: StdVideoH264PictureType,
+    pub FrameNum: u32,
+    pub PicOrderCnt: i32,
+    pub long_term_pic_num: u16,
+    pub long_term_frame_idx: u16,
+    pub temporal_id: u8,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct StdVideoEncodeH264ReferenceListsInfo {
+    pub flags: u32,
+    pub num_ref_idx_l0_active_minus1: u8,
+    pub num_ref_idx_l1_active_minus1: u8,
+    /// DPB slot indices, [`STD_VIDEO_H264_NO_REFERENCE_PICTURE`] past the active ones
+    pub RefPicList0: [u8; 32],
+    pub RefPicList1: [u8; 32],
+    pub refList0ModOpCount: u8,
+    pub refList1ModOpCount: u8,
+    pub refPicMarkingOpCount: u8,
+    pub reserved1: [u8; 7],
+    pub pRefList0ModOperations: *const StdVideoEncodeH264RefListModEntry,
+    pub pRefList1ModOperations: *const StdVideoEncodeH264RefListModEntry,
+    pub pRefPicMarkingOperations: *const StdVideoEncodeH264RefPicMarkingEntry,
+}
+
+#[repr(C)]
+pub struct StdVideoEncodeH264RefListModEntry {
+    pub modification_of_pic_nums_idc: StdVideoH264ModificationOfPicNumsIdc,
+    pub abs_diff_pic_num_minus1: u16,
+    pub long_term_pic_num: u16,
+}
+
+#[repr(C)]
+pub struct StdVideoEncodeH264RefPicMarkingEntry {
+    pub memory_management_control_operation: StdVideoH264MemMgmtControlOp,
+    pub difference_of_pic_nums_minus1: u16,
+    pub long_term_pic_num: u16,
+    pub long_term_frame_idx: u16,
+    pub max_long_term_frame_idx_plus1: u16,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct StdVideoEncodeH264SliceHeader {
+    pub flags: u32,
+    pub first_mb_in_slice: u32,
+    pub slice_type: StdVideoH264SliceType,
+    pub slice_alpha_c0_offset_div2: i8,
+    pub slice_beta_offset_div2: i8,
+    pub slice_qp_delta: i8,
+    pub reserved1: u8,
+    pub cabac_init_idc: StdVideoH264CabacInitIdc,
+    pub disable_deblocking_filter_idc: StdVideoH264DisableDeblockingFilterIdc,
+    /// `StdVideoEncodeH264WeightTable`, left undeclared since weighted prediction isn't used
+    pub pWeightTable: *const c_void,
+}
@@ -0,0 +1,772 @@
+//! Depth of field from a thin lens model, gathered at half resolution
+//!
+//! Each pixel's circle of confusion follows from its depth, the [`Lens`]'s focus distance and its
+//! aperture, signed so nearer than focus is negative. A prepare pass halves the scene, storing the
+//! circle alongside the color, and a gather pass sums a disc of samples around every half-res
+//! pixel. Samples count when their own circle reaches the pixel, and ones behind it only as far as
+//! the pixel's circle, so blurry backgrounds don't bleed over sharp subjects while blurry
+//! foregrounds still spill over what's behind them. The composite blends the gathered result over
+//! the full-res scene as the blur grows.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::Vec4;
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    scene::Camera,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        hdr::HDR_FORMAT,
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        transition, Texture,
+    },
+};
+
+/// Inputs, lens parameters and circle of confusion shared by every pass
+const DOF_COMMON_GLSL: &str = r#"
+layout(set = 0, binding = 0) uniform texture2D scene_color;
+layout(set = 0, binding = 1) uniform texture2D scene_depth;
+// rgb: half-res color, a: signed circle of confusion
+layout(set = 0, binding = 2) uniform texture2D prepared;
+// rgb: gathered color, a: how blurred it ended up
+layout(set = 0, binding = 3) uniform texture2D blurred;
+layout(set = 0, binding = 4) uniform sampler point_sampler;
+layout(set = 0, binding = 5) uniform sampler linear_sampler;
+
+layout(push_constant) uniform Push {
+    // x: focus distance, y: blur in screen heights per (depth - focus) / depth,
+    // z: near plane, w: far plane
+    vec4 lens;
+    // x: largest blur radius in screen heights, y: gather samples
+    vec4 params;
+} push;
+
+// Radius in screen heights, negative in front of the focus
+float circle_of_confusion(float depth) {
+    float near = push.lens.z;
+    float far = push.lens.w;
+    float z = near * far / (far - depth * (far - near));
+    float coc = push.lens.y * (z - push.lens.x) / z;
+    return clamp(coc, -push.params.x, push.params.x);
+}
+"#;
+
+/// Halves the scene, keeping the nearest depth's circle of each 2x2 block
+pub const DOF_PREPARE_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 6, rgba16f) uniform image2D dst;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(dst);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    vec3 color = textureLod(sampler2D(scene_color, linear_sampler), uv, 0.0).rgb;
+    ivec2 full = textureSize(sampler2D(scene_depth, point_sampler), 0) - 1;
+    float depth = 1.0;
+    for (int y = 0; y < 2; y++) {
+        for (int x = 0; x < 2; x++) {
+            ivec2 texel = min(id * 2 + ivec2(x, y), full);
+            depth = min(depth, texelFetch(sampler2D(scene_depth, point_sampler), texel, 0).r);
+        }
+    }
+    imageStore(dst, id, vec4(color, circle_of_confusion(depth)));
+}
+"#;
+
+/// Gathers a golden angle spiral of samples out to the largest blur radius
+pub const DOF_GATHER_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 7, rgba16f) uniform image2D dst;
+
+const float GOLDEN_ANGLE = 2.39996323;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(dst);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    vec4 center = texelFetch(sampler2D(prepared, point_sampler), id, 0);
+    float aspect = float(size.x) / float(size.y);
+    float texel = 1.0 / float(size.y);
+    int samples = int(push.params.y);
+
+    vec3 sum = center.rgb;
+    float total = 1.0;
+    float near_coverage = 0.0;
+    for (int i = 0; i < samples; i++) {
+        float dist = sqrt((float(i) + 0.5) / float(samples)) * push.params.x;
+        float angle = float(i) * GOLDEN_ANGLE;
+        vec2 offset = vec2(cos(angle) / aspect, sin(angle)) * dist;
+        vec4 s = textureLod(sampler2D(prepared, point_sampler), uv + offset, 0.0);
+        // Nearer samples spill over with their whole circle, farther ones only within ours
+        bool nearer = s.a < center.a;
+        float reach = nearer ? abs(s.a) : min(abs(s.a), abs(center.a));
+        float weight = clamp(1.0 - (dist - reach) / texel, 0.0, 1.0);
+        sum += s.rgb * weight;
+        total += weight;
+        if (nearer && s.a < 0.0) {
+            near_coverage = max(near_coverage, abs(s.a) * weight);
+        }
+    }
+    imageStore(dst, id, vec4(sum / total, max(abs(center.a), near_coverage)));
+}
+"#;
+
+pub const DOF_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = uv;
+}
+"#;
+
+/// Blends the gathered blur over the sharp scene as the circle of confusion grows
+pub const DOF_COMPOSITE_GLSL: &str = r#"
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec4 sharp = textureLod(sampler2D(scene_color, point_sampler), uv, 0.0);
+    float depth = textureLod(sampler2D(scene_depth, point_sampler), uv, 0.0).r;
+    vec4 blur = textureLod(sampler2D(blurred, linear_sampler), uv, 0.0);
+    float texel = 1.0 / float(textureSize(sampler2D(scene_color, point_sampler), 0).y);
+    float coc = max(abs(circle_of_confusion(depth)), blur.a);
+    float blend = smoothstep(texel, texel * 4.0, coc);
+    out_color = vec4(mix(sharp.rgb, blur.rgb, blend), sharp.a);
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum DofError {
+    #[error("failed to compile depth of field shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Thin lens the camera is treated as, its focal length follows from the field of view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lens {
+    /// Distance in world units that's perfectly sharp
+    pub focus_distance: f32,
+    /// Focal length over aperture diameter, lower blurs more
+    pub f_stop: f32,
+    /// Sensor height in world units, 35mm film by default
+    pub sensor_height: f32,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Lens {
+            focus_distance: 3.0,
+            f_stop: 1.4,
+            sensor_height: 0.024,
+        }
+    }
+}
+
+impl Lens {
+    pub fn with_focus_distance(mut self, focus_distance: f32) -> Self {
+        self.focus_distance = focus_distance;
+        self
+    }
+
+    pub fn with_f_stop(mut self, f_stop: f32) -> Self {
+        self.f_stop = f_stop;
+        self
+    }
+
+    pub fn with_sensor_height(mut self, sensor_height: f32) -> Self {
+        self.sensor_height = sensor_height;
+        self
+    }
+
+    /// Focal length giving `fov_y` on this sensor
+    pub fn focal_length(&self, fov_y: f32) -> f32 {
+        self.sensor_height / (2.0 * (fov_y * 0.5).tan())
+    }
+
+    /// Circle of confusion radius in screen heights per `(depth - focus) / depth`
+    pub fn coc_scale(&self, fov_y: f32) -> f32 {
+        let focal_length = self.focal_length(fov_y);
+        let aperture = focal_length / self.f_stop;
+        let focus = (self.focus_distance - focal_length).max(1e-4);
+        // Halved from diameter to radius
+        0.5 * aperture * focal_length / focus / self.sensor_height
+    }
+}
+
+/// What the blur is taken from, both in `SHADER_READ_ONLY_OPTIMAL`
+#[derive(Debug, Clone, Copy)]
+pub struct DofInputs {
+    pub color: vk::ImageView,
+    /// Depth aspect view of the scene's depth
+    pub depth: vk::ImageView,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DofConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Size of the inputs
+    pub extent: vk::Extent2D,
+}
+
+impl DofConfig {
+    pub fn new(render_pass: vk::RenderPass, extent: vk::Extent2D) -> Self {
+        DofConfig {
+            render_pass,
+            subpass: 0,
+            extent,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct DofPush {
+    lens: Vec4,
+    params: Vec4,
+}
+
+pub struct DepthOfField {
+    pub lens: Lens,
+    /// Largest blur radius in screen heights, more out of focus is clamped to it
+    pub max_blur: f32,
+    pub samples: u32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    compute_layout: vk::PipelineLayout,
+    draw_layout: vk::PipelineLayout,
+    prepare_pipeline: vk::Pipeline,
+    gather_pipeline: vk::Pipeline,
+    draw_pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    point_sampler: vk::Sampler,
+    linear_sampler: vk::Sampler,
+    prepared: Option<Texture>,
+    blurred: Option<Texture>,
+    initialized: bool,
+}
+
+impl DepthOfField {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        inputs: DofInputs,
+        config: DofConfig,
+    ) -> Result<Self, DofError> {
+        let compile = |body: &str, stage| {
+            compiler
+                .compile(&format!("#version 450\n{DOF_COMMON_GLSL}{body}"), stage)
+                .map_err(|err| DofError::Compile(Box::new(err)))
+        };
+        let prepare = compile(DOF_PREPARE_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let gather = compile(DOF_GATHER_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let frag = compile(DOF_COMPOSITE_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let vert = compiler
+            .compile(DOF_VERT_GLSL, vk::ShaderStageFlags::VERTEX)
+            .map_err(|err| DofError::Compile(Box::new(err)))?;
+        let clamped =
+            SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let point_sampler = samplers.get(device, &clamped.with_filter(SamplerFilter::Nearest))?;
+        let linear_sampler = samplers.get(device, &clamped.with_filter(SamplerFilter::Linear))?;
+
+        let mut this = DepthOfField {
+            lens: Lens::default(),
+            max_blur: 0.02,
+            samples: 48,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            compute_layout: vk::PipelineLayout::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            prepare_pipeline: vk::Pipeline::null(),
+            gather_pipeline: vk::Pipeline::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            point_sampler,
+            linear_sampler,
+            prepared: None,
+            blurred: None,
+            initialized: false,
+        };
+        let result = unsafe {
+            this.create_objects(device, &[&prepare, &gather, &vert, &frag], config)
+                .and_then(|_| Ok(this.create_targets(device, mem_props, config.extent)?))
+                .map(|_| this.set_inputs(device, inputs))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn with_lens(mut self, lens: Lens) -> Self {
+        self.lens = lens;
+        self
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        code: &[&[u32]; 4],
+        config: DofConfig,
+    ) -> Result<(), DofError> {
+        let bindings: Vec<_> = (0..)
+            .zip(BINDING_TYPES)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "dof descriptor set layout");
+        let pool_sizes: Vec<_> = BINDING_TYPES
+            .into_iter()
+            .map(|ty| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: 1,
+            })
+            .collect();
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "dof descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+        let push_range = |stage_flags| vk::PushConstantRange {
+            stage_flags,
+            offset: 0,
+            size: mem::size_of::<DofPush>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range(vk::ShaderStageFlags::COMPUTE)]),
+        )?;
+        leaks::track(self.compute_layout, "dof pipeline layout");
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range(vk::ShaderStageFlags::FRAGMENT)]),
+        )?;
+        leaks::track(self.draw_layout, "dof pipeline layout");
+
+        let [prepare, gather, vert, frag] = code;
+        for compute in [prepare, gather] {
+            reflect::debug_validate(
+                &[compute],
+                &[&bindings],
+                &[push_range(vk::ShaderStageFlags::COMPUTE)],
+                &[],
+            )?;
+        }
+        reflect::debug_validate(
+            &[vert, frag],
+            &[&bindings],
+            &[push_range(vk::ShaderStageFlags::FRAGMENT)],
+            &[],
+        )?;
+        self.prepare_pipeline =
+            compute_pipeline(device, self.compute_layout, prepare, "dof prepare")?;
+        self.gather_pipeline = compute_pipeline(device, self.compute_layout, gather, "dof gather")?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "dof shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "dof shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder();
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "dof composite");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    unsafe fn create_targets(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+    ) -> VkResult<()> {
+        let half = vk::Extent2D {
+            width: extent.width.div_ceil(2),
+            height: extent.height.div_ceil(2),
+        };
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE;
+        self.prepared = Some(Texture::empty(device, mem_props, HDR_FORMAT, half, usage)?);
+        self.blurred = Some(Texture::empty(device, mem_props, HDR_FORMAT, half, usage)?);
+        self.initialized = false;
+        Ok(())
+    }
+
+    unsafe fn destroy_targets(&mut self, device: &Device) {
+        for texture in [&mut self.prepared, &mut self.blurred] {
+            if let Some(texture) = texture.take() {
+                texture.destroy(device);
+            }
+        }
+    }
+
+    /// Points the passes at new inputs and the current half-res targets
+    ///
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn set_inputs(&self, device: &Device, inputs: DofInputs) {
+        let (Some(prepared), Some(blurred)) = (&self.prepared, &self.blurred) else {
+            return;
+        };
+        let image = |image_view, image_layout| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view,
+                image_layout,
+            }]
+        };
+        let sampler = |sampler| {
+            [vk::DescriptorImageInfo {
+                sampler,
+                image_view: vk::ImageView::null(),
+                image_layout: vk::ImageLayout::UNDEFINED,
+            }]
+        };
+        let read_only = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let general = vk::ImageLayout::GENERAL;
+        let infos = [
+            image(inputs.color, read_only),
+            image(inputs.depth, read_only),
+            image(prepared.view, general),
+            image(blurred.view, general),
+            sampler(self.point_sampler),
+            sampler(self.linear_sampler),
+            image(prepared.view, general),
+            image(blurred.view, general),
+        ];
+        let writes: Vec<_> = (0..)
+            .zip(BINDING_TYPES)
+            .zip(&infos)
+            .map(|((binding, ty), info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(ty)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        device.update_descriptor_sets(&writes, &[]);
+    }
+
+    /// Recreates the half-res targets for `extent` and takes the resized inputs
+    ///
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn resize(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        inputs: DofInputs,
+    ) -> VkResult<()> {
+        self.destroy_targets(device);
+        self.create_targets(device, mem_props, extent)?;
+        self.set_inputs(device, inputs);
+        Ok(())
+    }
+
+    fn push(&self, camera: &Camera) -> DofPush {
+        DofPush {
+            lens: Vec4::new(
+                self.lens.focus_distance,
+                self.lens.coc_scale(camera.fov_y),
+                camera.near,
+                camera.far,
+            ),
+            params: Vec4::new(self.max_blur, self.samples as f32, 0.0, 0.0),
+        }
+    }
+
+    /// Records preparing and gathering the half-res blur as seen through `camera`
+    ///
+    /// Must be recorded outside a render pass, before [`DepthOfField::draw`].
+    pub fn update(&mut self, device: &Device, cmd: vk::CommandBuffer, camera: &Camera) {
+        let (Some(prepared), Some(blurred)) = (&self.prepared, &self.blurred) else {
+            return;
+        };
+        let push = self.push(camera);
+        let (width, height) = (prepared.extent.width, prepared.extent.height);
+        unsafe {
+            if !self.initialized {
+                for texture in [prepared, blurred] {
+                    let range = vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    };
+                    transition(
+                        device,
+                        cmd,
+                        texture.image,
+                        range,
+                        vk::ImageLayout::UNDEFINED,
+                        vk::ImageLayout::GENERAL,
+                    );
+                }
+            } else {
+                // Last frame's composite has to be done sampling the blur
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.prepare_pipeline);
+            device.cmd_dispatch(cmd, width.div_ceil(8), height.div_ceil(8), 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.gather_pipeline);
+            device.cmd_dispatch(cmd, width.div_ceil(8), height.div_ceil(8), 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+        }
+        self.initialized = true;
+    }
+
+    /// Records the scene with the blur blended in, into a target that must not be the color input
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, camera: &Camera) {
+        if !self.initialized {
+            return;
+        }
+        let push = self.push(camera);
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.draw_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.destroy_targets(device);
+        for pipeline in [
+            self.prepare_pipeline,
+            self.gather_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Descriptor types of the bindings in [`DOF_COMMON_GLSL`] followed by the two storage targets
+const BINDING_TYPES: [vk::DescriptorType; 8] = [
+    vk::DescriptorType::SAMPLED_IMAGE,
+    vk::DescriptorType::SAMPLED_IMAGE,
+    vk::DescriptorType::SAMPLED_IMAGE,
+    vk::DescriptorType::SAMPLED_IMAGE,
+    vk::DescriptorType::SAMPLER,
+    vk::DescriptorType::SAMPLER,
+    vk::DescriptorType::STORAGE_IMAGE,
+    vk::DescriptorType::STORAGE_IMAGE,
+];
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "dof shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
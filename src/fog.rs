@@ -0,0 +1,785 @@
+//! Volumetric height fog lit by the sun, in a froxel grid fitted to the view frustum
+//!
+//! Froxels split the screen into tiles and the view depth into slices spaced further apart the
+//! further they are, so near fog gets most of the resolution. An inject pass fills each froxel
+//! with the fog's density at its centre and the light it scatters towards the camera. An
+//! integrate pass then walks every tile front to back, accumulating in-scattered light and
+//! transmittance, so one lookup at a pixel's depth gives all the fog in front of it. The composite
+//! pass does that lookup over the lit scene, dimming it by the transmittance and adding the light.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    scene::Camera,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        transition, Texture,
+    },
+};
+
+/// Volumes, parameters and froxel placement shared by the compute passes
+const FOG_COMMON_GLSL: &str = r#"
+const float PI = 3.14159265359;
+
+layout(set = 0, binding = 0, rgba16f) uniform image3D scattering;
+layout(set = 0, binding = 1, rgba16f) uniform image3D integrated;
+
+layout(push_constant) uniform Push {
+    // w: depth of the first slice
+    vec4 camera;
+    // Camera axes scaled to reach the frustum's edges at a view depth of one
+    // w: depth of the last slice
+    vec4 right;
+    // w: phase anisotropy
+    vec4 up;
+    // w: density at and below the fog height
+    vec4 forward;
+    // xyz: direction the light travels, w: fog height
+    vec4 sun;
+    // rgb: sunlight scattered per unit density, w: density falloff per unit above the height
+    vec4 sun_color;
+    // rgb: ambient light scattered per unit density
+    vec4 ambient;
+} push;
+
+float slice_depth(float slice, float slices) {
+    return push.camera.w * pow(push.right.w / push.camera.w, slice / slices);
+}
+
+// Unnormalized, one unit along the camera's forward axis per unit of view depth
+vec3 view_ray(vec2 uv) {
+    return push.forward.xyz + (uv.x * 2.0 - 1.0) * push.right.xyz + (1.0 - uv.y * 2.0) * push.up.xyz;
+}
+"#;
+
+/// Fills every froxel with in-scattered light and the fog's extinction
+pub const FOG_INJECT_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+void main() {
+    ivec3 size = imageSize(scattering);
+    ivec3 id = ivec3(gl_GlobalInvocationID);
+    if (id.x >= size.x || id.y >= size.y || id.z >= size.z) {
+        return;
+    }
+    vec2 uv = (vec2(id.xy) + 0.5) / vec2(size.xy);
+    vec3 ray = view_ray(uv);
+    vec3 position = push.camera.xyz + ray * slice_depth(float(id.z) + 0.5, float(size.z));
+    float density = push.forward.w * exp(-push.sun_color.w * max(position.y - push.sun.w, 0.0));
+
+    // Henyey-Greenstein, peaking when looking into the sun for positive anisotropy
+    float g = push.up.w;
+    float cos_theta = dot(normalize(ray), -push.sun.xyz);
+    float phase = (1.0 - g * g) / (4.0 * PI * pow(1.0 + g * g - 2.0 * g * cos_theta, 1.5));
+    vec3 light = push.sun_color.rgb * phase + push.ambient.rgb;
+    imageStore(scattering, id, vec4(light * density, density));
+}
+"#;
+
+/// Accumulates the froxels of every tile front to back
+pub const FOG_INTEGRATE_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+void main() {
+    ivec3 size = imageSize(integrated);
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    float stretch = length(view_ray((vec2(id) + 0.5) / vec2(size.xy)));
+    vec3 light = vec3(0.0);
+    float transmittance = 1.0;
+    for (int z = 0; z < size.z; z++) {
+        vec4 froxel = imageLoad(scattering, ivec3(id, z));
+        float thickness = (slice_depth(float(z + 1), float(size.z)) - slice_depth(float(z), float(size.z))) * stretch;
+        float extinction = max(froxel.a, 0.000001);
+        float through = exp(-extinction * thickness);
+        // Integrated over the slice rather than taken at its centre, so thick slices don't glow
+        light += transmittance * (froxel.rgb - froxel.rgb * through) / extinction;
+        transmittance *= through;
+        imageStore(integrated, ivec3(id, z), vec4(light, transmittance));
+    }
+}
+"#;
+
+pub const FOG_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = uv;
+}
+"#;
+
+/// Looks the integrated fog up at each pixel's depth, blended as `scene * a + rgb`
+pub const FOG_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 2) uniform texture3D integrated;
+layout(set = 0, binding = 3) uniform texture2D scene_depth;
+layout(set = 0, binding = 4) uniform sampler fog_sampler;
+
+layout(push_constant) uniform Push {
+    // x: camera near plane, y: camera far plane, z: first slice depth, w: last slice depth
+    vec4 range;
+} push;
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float near = push.range.x;
+    float far = push.range.y;
+    float depth = texture(sampler2D(scene_depth, fog_sampler), uv).r;
+    float view_depth = near * far / (far - depth * (far - near));
+    float slices = float(textureSize(sampler3D(integrated, fog_sampler), 0).z);
+    float w = log(max(view_depth, push.range.z) / push.range.z) / log(push.range.w / push.range.z);
+    // Each froxel holds the fog up to its far side
+    out_color = texture(sampler3D(integrated, fog_sampler), vec3(uv, w - 0.5 / slices));
+}
+"#;
+
+const FROXEL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+#[derive(Debug, Error)]
+pub enum FogError {
+    #[error("failed to compile fog shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Fog thinning out exponentially above a height
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightFog {
+    /// Extinction per world unit at and below `height`
+    pub density: f32,
+    pub height: f32,
+    /// How quickly density drops per world unit above `height`
+    pub falloff: f32,
+    /// Fraction of the light the fog absorbs that it scatters instead, per channel
+    pub albedo: Vec3,
+    /// Between -1 and 1, positive scatters forward and brightens the fog around the sun
+    pub anisotropy: f32,
+    /// Light from the sky scattered evenly in every direction
+    pub ambient: Vec3,
+}
+
+impl Default for HeightFog {
+    fn default() -> Self {
+        HeightFog {
+            density: 0.02,
+            height: 0.0,
+            falloff: 0.15,
+            albedo: Vec3::splat(0.9),
+            anisotropy: 0.6,
+            ambient: Vec3::new(0.04, 0.05, 0.07),
+        }
+    }
+}
+
+impl HeightFog {
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    pub fn with_height(mut self, height: f32, falloff: f32) -> Self {
+        self.height = height;
+        self.falloff = falloff;
+        self
+    }
+
+    pub fn with_albedo(mut self, albedo: Vec3) -> Self {
+        self.albedo = albedo;
+        self
+    }
+
+    pub fn with_anisotropy(mut self, anisotropy: f32) -> Self {
+        self.anisotropy = anisotropy;
+        self
+    }
+
+    pub fn with_ambient(mut self, ambient: Vec3) -> Self {
+        self.ambient = ambient;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FogConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Tiles across, tiles down and depth slices
+    pub froxels: vk::Extent3D,
+    /// View depth the last slice ends at, everything further sees the fog up to there
+    pub range: f32,
+}
+
+impl FogConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        FogConfig {
+            render_pass,
+            subpass: 0,
+            froxels: vk::Extent3D {
+                width: 160,
+                height: 90,
+                depth: 64,
+            },
+            range: 128.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct FroxelPush {
+    camera: Vec4,
+    right: Vec4,
+    up: Vec4,
+    forward: Vec4,
+    sun: Vec4,
+    sun_color: Vec4,
+    ambient: Vec4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct CompositePush {
+    range: Vec4,
+}
+
+pub struct VolumetricFog {
+    pub fog: HeightFog,
+    /// Direction the sunlight travels
+    pub sun: Vec3,
+    pub sun_color: Vec3,
+    range: f32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    compute_layout: vk::PipelineLayout,
+    draw_layout: vk::PipelineLayout,
+    inject_pipeline: vk::Pipeline,
+    integrate_pipeline: vk::Pipeline,
+    draw_pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    /// The injected froxels, then the integrated ones, both kept in `GENERAL`
+    volumes: Vec<Texture>,
+    initialized: bool,
+}
+
+impl VolumetricFog {
+    /// `depth` is a depth aspect view of the scene's depth, sampled by the composite pass
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        depth: vk::ImageView,
+        config: FogConfig,
+    ) -> Result<Self, FogError> {
+        let compile = |source: &str, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| FogError::Compile(Box::new(err)))
+        };
+        let pass = |body: &str| {
+            compile(
+                &format!("#version 450\n{FOG_COMMON_GLSL}{body}"),
+                vk::ShaderStageFlags::COMPUTE,
+            )
+        };
+        let inject = pass(FOG_INJECT_GLSL)?;
+        let integrate = pass(FOG_INTEGRATE_GLSL)?;
+        let vert = compile(FOG_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(FOG_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Linear)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = VolumetricFog {
+            fog: HeightFog::default(),
+            sun: Vec3::new(-0.3, -0.5, 0.8).normalize(),
+            sun_color: Vec3::splat(3.0),
+            range: config.range,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            compute_layout: vk::PipelineLayout::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            inject_pipeline: vk::Pipeline::null(),
+            integrate_pipeline: vk::Pipeline::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            sampler,
+            volumes: Vec::new(),
+            initialized: false,
+        };
+        let result = unsafe {
+            this.create_objects(
+                device,
+                mem_props,
+                &[&inject, &integrate, &vert, &frag],
+                config,
+            )
+            .map(|_| this.set_depth(device, depth))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn with_fog(mut self, fog: HeightFog) -> Self {
+        self.fog = fog;
+        self
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        code: &[&[u32]; 4],
+        config: FogConfig,
+    ) -> Result<(), FogError> {
+        let types = [
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "fog descriptor set layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "fog descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+
+        for _ in 0..2 {
+            let volume = Texture::volume(
+                device,
+                mem_props,
+                FROXEL_FORMAT,
+                config.froxels,
+                vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE,
+            )?;
+            self.volumes.push(volume);
+        }
+        let image = |view| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: view,
+                image_layout: vk::ImageLayout::GENERAL,
+            }]
+        };
+        let scattering = image(self.volumes[0].view);
+        let integrated = image(self.volumes[1].view);
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let writes: Vec<_> = [
+            (0, types[0], &scattering),
+            (1, types[1], &integrated),
+            (2, types[2], &integrated),
+            (4, types[4], &sampler),
+        ]
+        .into_iter()
+        .map(|(binding, ty, info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.set)
+                .dst_binding(binding)
+                .descriptor_type(ty)
+                .image_info(info)
+                .build()
+        })
+        .collect();
+        device.update_descriptor_sets(&writes, &[]);
+
+        let compute_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: mem::size_of::<FroxelPush>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[compute_range]),
+        )?;
+        leaks::track(self.compute_layout, "fog pipeline layout");
+        let draw_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: mem::size_of::<CompositePush>() as u32,
+        };
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[draw_range]),
+        )?;
+        leaks::track(self.draw_layout, "fog pipeline layout");
+
+        let [inject, integrate, vert, frag] = code;
+        for compute in [inject, integrate] {
+            reflect::debug_validate(&[compute], &[&bindings], &[compute_range], &[])?;
+        }
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[draw_range], &[])?;
+        self.inject_pipeline = compute_pipeline(device, self.compute_layout, inject, "fog inject")?;
+        self.integrate_pipeline =
+            compute_pipeline(device, self.compute_layout, integrate, "fog integrate")?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "fog shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "fog shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder();
+        // Scene dimmed by the transmittance in alpha, plus the in-scattered light
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "fog composite");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Points the composite pass at a new scene depth, e.g. after a resize
+    ///
+    /// `depth` must be in `SHADER_READ_ONLY_OPTIMAL` when the composite runs.
+    ///
+    /// # Safety
+    ///
+    /// No recorded composite may still be executing.
+    pub unsafe fn set_depth(&self, device: &Device, depth: vk::ImageView) {
+        let info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: depth,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(3)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&info)
+            .build();
+        device.update_descriptor_sets(&[write], &[]);
+    }
+
+    /// Records filling and integrating the froxels for `camera` at `view`
+    ///
+    /// Must be recorded outside a render pass, before [`VolumetricFog::draw`].
+    pub fn update(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        camera: &Camera,
+        view: Mat4,
+        aspect: f32,
+    ) {
+        let [scattering, _] = self.volumes.as_slice() else {
+            return;
+        };
+        let extent = scattering.extent;
+        let world = view.inverse();
+        let tan_y = (camera.fov_y / 2.0).tan();
+        let fog = &self.fog;
+        let push = FroxelPush {
+            camera: world.w_axis.truncate().extend(camera.near),
+            right: (world.x_axis.truncate() * tan_y * aspect).extend(self.range),
+            up: (world.y_axis.truncate() * tan_y).extend(fog.anisotropy),
+            forward: (-world.z_axis.truncate()).extend(fog.density),
+            sun: self.sun.normalize_or_zero().extend(fog.height),
+            sun_color: (self.sun_color * fog.albedo).extend(fog.falloff),
+            ambient: (fog.ambient * fog.albedo).extend(0.0),
+        };
+        unsafe {
+            if !self.initialized {
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                for volume in &self.volumes {
+                    transition(
+                        device,
+                        cmd,
+                        volume.image,
+                        range,
+                        vk::ImageLayout::UNDEFINED,
+                        vk::ImageLayout::GENERAL,
+                    );
+                }
+                self.initialized = true;
+            } else {
+                // Last frame's composite has to be done sampling the integrated froxels
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            let (groups_x, groups_y) = (extent.width.div_ceil(8), extent.height.div_ceil(8));
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.inject_pipeline);
+            device.cmd_dispatch(cmd, groups_x, groups_y, extent.depth);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.integrate_pipeline);
+            device.cmd_dispatch(cmd, groups_x, groups_y, 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+        }
+    }
+
+    /// Records the fog over everything drawn so far, for the `camera` passed to the update
+    ///
+    /// Goes after the opaque geometry and before anything blended, which the fog can't see.
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, camera: &Camera) {
+        if !self.initialized {
+            return;
+        }
+        let push = CompositePush {
+            range: Vec4::new(camera.near, camera.far, camera.near, self.range),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.draw_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for volume in self.volumes.drain(..) {
+            volume.destroy(device);
+        }
+        for pipeline in [
+            self.inject_pipeline,
+            self.integrate_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "fog shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
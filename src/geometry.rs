@@ -0,0 +1,240 @@
+//! Bounding volumes and rays shared by debug drawing, picking and culling
+
+use glam::{Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
+
+pub mod bvh;
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn from_center(center: Vec3, half_extents: Vec3) -> Self {
+        Aabb {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+
+    /// Smallest box around `points`, `None` when there are none
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Aabb::new(first, first), |aabb, point| Aabb {
+            min: aabb.min.min(point),
+            max: aabb.max.max(point),
+        }))
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Corners in binary order, bit 0 of the index picks max x, bit 1 max y and bit 2 max z
+    pub fn corners(&self) -> [Vec3; 8] {
+        std::array::from_fn(|i| {
+            Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            )
+        })
+    }
+
+    /// Box around this one after `transform`, larger than the transformed box when rotated
+    pub fn transformed(&self, transform: Mat4) -> Aabb {
+        let center = transform.transform_point3(self.center());
+        let half = self.half_extents();
+        let half_extents = transform.x_axis.truncate().abs() * half.x
+            + transform.y_axis.truncate().abs() * half.y
+            + transform.z_axis.truncate().abs() * half.z;
+        Aabb::from_center(center, half_extents)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32) -> Self {
+        Sphere { center, radius }
+    }
+
+    /// Sphere through the corners of a box
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        Sphere {
+            center: aabb.center(),
+            radius: aabb.half_extents().length(),
+        }
+    }
+
+    /// Sphere around this one after `transform`, scaled by its largest axis
+    pub fn transformed(&self, transform: Mat4) -> Sphere {
+        let scale = transform
+            .x_axis
+            .truncate()
+            .length_squared()
+            .max(transform.y_axis.truncate().length_squared())
+            .max(transform.z_axis.truncate().length_squared())
+            .sqrt();
+        Sphere {
+            center: transform.transform_point3(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// The six planes of a camera's view volume, normals pointing inwards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum {
+    /// `xyz` is the unit normal, `w` the offset, so points inside have `n . p + w >= 0`
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Planes of a view projection with Vulkan's 0..1 depth range
+    pub fn from_view_proj(view_proj: Mat4) -> Self {
+        let [x, y, z, w] = [0, 1, 2, 3].map(|i| view_proj.row(i));
+        let planes =
+            [w + x, w - x, w + y, w - y, z, w - z].map(|plane| plane / plane.truncate().length());
+        Frustum { planes }
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.truncate().dot(sphere.center) + plane.w >= -sphere.radius)
+    }
+
+    /// Conservative, boxes near a frustum corner may pass while outside
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.truncate();
+            // The corner furthest along the normal
+            let corner = Vec3::select(normal.cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            normal.dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    /// Unit length unless [`Ray::transformed`] scaled it, distances are in multiples of it
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Ray {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    /// Ray through `cursor`, in pixels from the top left of a `viewport` sized target, for a
+    /// camera with the given view projection and Vulkan's 0..1 depth range
+    pub fn from_screen(cursor: Vec2, viewport: Vec2, view_proj: Mat4) -> Self {
+        let ndc = cursor / viewport * 2.0 - 1.0;
+        let inverse = view_proj.inverse();
+        let unproject = |depth: f32| {
+            let point = inverse * ndc.extend(depth).extend(1.0);
+            point.xyz() / point.w
+        };
+        let near = unproject(0.0);
+        Ray::new(near, unproject(1.0) - near)
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+
+    /// Distance along the ray to a plane, `None` when parallel or behind the origin
+    pub fn intersect_plane(&self, point: Vec3, normal: Vec3) -> Option<f32> {
+        let denom = self.direction.dot(normal);
+        if denom.abs() < 1e-6 {
+            return None;
+        }
+        let t = (point - self.origin).dot(normal) / denom;
+        (t >= 0.0).then_some(t)
+    }
+
+    /// Parameters of the closest points between the ray and the line through `point` along
+    /// `direction`, as `(ray_t, line_s)`, `None` when they're parallel
+    pub fn closest_to_line(&self, point: Vec3, direction: Vec3) -> Option<(f32, f32)> {
+        let w = self.origin - point;
+        let b = self.direction.dot(direction);
+        let c = direction.dot(direction);
+        let denom = c - b * b;
+        if denom.abs() < 1e-6 {
+            return None;
+        }
+        let d = self.direction.dot(w);
+        let e = direction.dot(w);
+        Some(((b * e - c * d) / denom, (e - b * d) / denom))
+    }
+
+    /// Distance to where the ray enters the box, zero from inside, `None` on a miss
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let inverse = self.direction.recip();
+        let a = (aabb.min - self.origin) * inverse;
+        let b = (aabb.max - self.origin) * inverse;
+        let near = a.min(b).max_element().max(0.0);
+        let far = a.max(b).min_element();
+        (near <= far).then_some(near)
+    }
+
+    /// Distance to a triangle hit from either side, with the barycentric `(u, v)` of the hit
+    pub fn intersect_triangle(&self, a: Vec3, b: Vec3, c: Vec3) -> Option<(f32, f32, f32)> {
+        // Möller-Trumbore
+        let ab = b - a;
+        let ac = c - a;
+        let p = self.direction.cross(ac);
+        let det = ab.dot(p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+        let inverse = det.recip();
+        let to_origin = self.origin - a;
+        let u = to_origin.dot(p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = to_origin.cross(ab);
+        let v = self.direction.dot(q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = ac.dot(q) * inverse;
+        (t >= 0.0).then_some((t, u, v))
+    }
+
+    /// The ray in another space, distances along it stay in the same units as this ray's
+    pub fn transformed(&self, transform: Mat4) -> Ray {
+        Ray {
+            origin: transform.transform_point3(self.origin),
+            direction: transform.transform_vector3(self.direction),
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Bounding volume hierarchy over boxes, so culling and ray casts skip whole groups of objects
+//!
+//! Built top down by splitting at the median centroid along the longest axis. Moving objects are
+//! handled by [`Bvh::refit`], which keeps the tree shape and only grows or shrinks the boxes, with
+//! [`Bvh::rebuild`] for when they've moved far enough for the tree to get loose.
+
+use crate::{
+    culling::MeshBounds,
+    scene::{MeshId, NodeId, Scene},
+};
+
+use super::{Aabb, Frustum, Ray};
+
+/// Items per leaf before a node gets split
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    /// First item of a leaf, or the right child of an inner node whose left child follows it
+    first: u32,
+    /// Items in a leaf, zero for inner nodes
+    count: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bvh<T> {
+    nodes: Vec<BvhNode>,
+    items: Vec<(Aabb, T)>,
+}
+
+impl<T: Copy> Bvh<T> {
+    pub fn new(items: Vec<(Aabb, T)>) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            items,
+        };
+        bvh.rebuild();
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Rebuilds the tree from the current item bounds
+    pub fn rebuild(&mut self) {
+        self.nodes.clear();
+        if !self.items.is_empty() {
+            self.build_node(0, self.items.len());
+        }
+    }
+
+    fn build_node(&mut self, start: usize, end: usize) -> usize {
+        let index = self.nodes.len();
+        let items = &mut self.items[start..end];
+        let bounds = union(items.iter().map(|(bounds, _)| *bounds));
+        self.nodes.push(BvhNode {
+            bounds,
+            first: start as u32,
+            count: items.len() as u32,
+        });
+        if items.len() <= LEAF_SIZE {
+            return index;
+        }
+        let centroids = union(items.iter().map(|(bounds, _)| {
+            let center = bounds.center();
+            Aabb::new(center, center)
+        }));
+        let extent = centroids.max - centroids.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        if extent[axis] <= 0.0 {
+            return index;
+        }
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |(a, _), (b, _)| {
+            a.center()[axis].total_cmp(&b.center()[axis])
+        });
+
+        self.build_node(start, start + mid);
+        let right = self.build_node(start + mid, end);
+        self.nodes[index].first = right as u32;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    /// Updates item bounds and resizes every node around them, `bounds` returning `None` leaves
+    /// an item as it was
+    pub fn refit(&mut self, mut bounds: impl FnMut(T) -> Option<Aabb>) {
+        for (aabb, item) in &mut self.items {
+            if let Some(new) = bounds(*item) {
+                *aabb = new;
+            }
+        }
+        // Children always come after their parent
+        for index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[index];
+            self.nodes[index].bounds = if node.count > 0 {
+                let range = node.first as usize..(node.first + node.count) as usize;
+                union(self.items[range].iter().map(|(bounds, _)| *bounds))
+            } else {
+                self.nodes[index + 1]
+                    .bounds
+                    .union(&self.nodes[node.first as usize].bounds)
+            };
+        }
+    }
+
+    /// Calls `visit` for every item whose box intersects the frustum
+    pub fn query_frustum(&self, frustum: &Frustum, mut visit: impl FnMut(T)) {
+        let mut stack = Vec::new();
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !frustum.intersects_aabb(&node.bounds) {
+                continue;
+            }
+            if node.count > 0 {
+                for (bounds, item) in self.leaf(node) {
+                    if frustum.intersects_aabb(bounds) {
+                        visit(*item);
+                    }
+                }
+            } else {
+                stack.extend([index + 1, node.first as usize]);
+            }
+        }
+    }
+
+    /// Closest item `hit` reports a distance for, visiting items in roughly front to back order
+    /// and skipping those whose box is further than the best hit so far
+    pub fn raycast(&self, ray: &Ray, mut hit: impl FnMut(T) -> Option<f32>) -> Option<(T, f32)> {
+        let mut best: Option<(T, f32)> = None;
+        let mut stack = Vec::new();
+        if let Some(t) = self
+            .nodes
+            .first()
+            .and_then(|root| ray.intersect_aabb(&root.bounds))
+        {
+            stack.push((0, t));
+        }
+        while let Some((index, near)) = stack.pop() {
+            if best.is_some_and(|(_, distance)| near >= distance) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            if node.count > 0 {
+                for (bounds, item) in self.leaf(node) {
+                    let Some(near) = ray.intersect_aabb(bounds) else {
+                        continue;
+                    };
+                    if best.is_some_and(|(_, distance)| near >= distance) {
+                        continue;
+                    }
+                    if let Some(distance) = hit(*item) {
+                        if best.is_none_or(|(_, best)| distance < best) {
+                            best = Some((*item, distance));
+                        }
+                    }
+                }
+                continue;
+            }
+            let mut children: Vec<_> = [index + 1, node.first as usize]
+                .into_iter()
+                .filter_map(|child| Some((child, ray.intersect_aabb(&self.nodes[child].bounds)?)))
+                .collect();
+            // The nearer child is popped first
+            children.sort_by(|a, b| b.1.total_cmp(&a.1));
+            stack.extend(children);
+        }
+        best
+    }
+
+    fn leaf(&self, node: &BvhNode) -> &[(Aabb, T)] {
+        &self.items[node.first as usize..(node.first + node.count) as usize]
+    }
+}
+
+impl Bvh<NodeId> {
+    /// Tree over the world space boxes of mesh nodes, those without `bounds` are left out
+    pub fn from_scene(scene: &Scene, bounds: impl Fn(MeshId) -> Option<MeshBounds>) -> Self {
+        let items = scene
+            .iter()
+            .filter_map(|(id, _)| Some((node_bounds(scene, id, &bounds)?, id)))
+            .collect();
+        Bvh::new(items)
+    }
+
+    /// Refits to the world matrices of the last [`Scene::update_transforms`]
+    pub fn refit_scene(&mut self, scene: &Scene, bounds: impl Fn(MeshId) -> Option<MeshBounds>) {
+        self.refit(|id| node_bounds(scene, id, &bounds));
+    }
+}
+
+fn node_bounds(
+    scene: &Scene,
+    id: NodeId,
+    bounds: &impl Fn(MeshId) -> Option<MeshBounds>,
+) -> Option<Aabb> {
+    let node = scene.get(id)?;
+    Some(bounds(node.mesh?)?.aabb.transformed(node.world()))
+}
+
+fn union(boxes: impl Iterator<Item = Aabb>) -> Aabb {
+    boxes
+        .reduce(|a, b| a.union(&b))
+        .expect("nodes are never empty")
+}
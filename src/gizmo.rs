@@ -0,0 +1,286 @@
+//! Translate, rotate and scale handles for moving a scene node with the mouse
+//!
+//! The gizmo sits on the node's world position and keeps a constant size on screen. Translation
+//! and rotation work along world axes, scaling along the node's own axes. Edits go to the node's
+//! local transform, so they're correct under any parent.
+
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::{
+    debug::DebugDraw,
+    geometry::{Aabb, Ray},
+    scene::{NodeId, Scene, Transform},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GizmoMode {
+    Translate,
+    Rotate,
+    Scale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GizmoAxis {
+    X,
+    Y,
+    Z,
+}
+
+impl GizmoAxis {
+    const ALL: [GizmoAxis; 3] = [GizmoAxis::X, GizmoAxis::Y, GizmoAxis::Z];
+
+    fn unit(self) -> Vec3 {
+        match self {
+            GizmoAxis::X => Vec3::X,
+            GizmoAxis::Y => Vec3::Y,
+            GizmoAxis::Z => Vec3::Z,
+        }
+    }
+
+    fn color(self) -> Vec4 {
+        self.unit().extend(1.0)
+    }
+}
+
+const HIGHLIGHT: Vec4 = Vec4::new(1.0, 1.0, 0.0, 1.0);
+
+/// What was grabbed, everything measured when the drag started
+#[derive(Debug, Clone, Copy)]
+struct Drag {
+    axis: GizmoAxis,
+    local: Transform,
+    origin: Vec3,
+    direction: Vec3,
+    /// Position along the axis for translate and scale, the grabbed point for rotate
+    grab: Vec3,
+}
+
+#[derive(Debug, Clone)]
+pub struct Gizmo {
+    pub mode: GizmoMode,
+    /// Handle length as a fraction of the distance to the camera
+    pub size: f32,
+    /// Translation is rounded to multiples of this many units, rotation to this many radians
+    pub snap: Option<f32>,
+    hovered: Option<GizmoAxis>,
+    drag: Option<Drag>,
+}
+
+impl Gizmo {
+    pub fn new(mode: GizmoMode) -> Self {
+        Gizmo {
+            mode,
+            size: 0.15,
+            snap: None,
+            hovered: None,
+            drag: None,
+        }
+    }
+
+    pub fn with_size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn with_snap(mut self, snap: f32) -> Self {
+        self.snap = Some(snap);
+        self
+    }
+
+    pub fn hovered(&self) -> Option<GizmoAxis> {
+        self.hovered
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    /// Feeds this frame's cursor ray and button state, returns whether the gizmo took the input
+    ///
+    /// Picking and camera controls should ignore the mouse while this returns true.
+    pub fn update(
+        &mut self,
+        scene: &mut Scene,
+        node: NodeId,
+        ray: &Ray,
+        eye: Vec3,
+        pressed: bool,
+    ) -> bool {
+        let Some(target) = scene.get(node) else {
+            self.hovered = None;
+            self.drag = None;
+            return false;
+        };
+        let parent = parent_world(scene, node);
+        let world = parent * target.local.matrix();
+        let origin = world.transform_point3(Vec3::ZERO);
+        let length = self.length(origin, eye);
+
+        if !pressed {
+            self.drag = None;
+            self.hovered = self.pick(ray, &world, origin, length);
+            return self.hovered.is_some();
+        }
+        let drag = match self.drag {
+            Some(drag) => drag,
+            None => {
+                let Some(axis) = self.hovered else {
+                    return false;
+                };
+                let direction = self.direction(axis, &world);
+                let Some(grab) = self.grab_point(ray, origin, direction) else {
+                    return false;
+                };
+                let drag = Drag {
+                    axis,
+                    local: target.local,
+                    origin,
+                    direction,
+                    grab,
+                };
+                self.drag = Some(drag);
+                drag
+            }
+        };
+        if let Some(current) = self.grab_point(ray, drag.origin, drag.direction) {
+            scene[node].local = self.apply(&drag, current, parent);
+        }
+        true
+    }
+
+    /// Queues the handles, the hovered or dragged one highlighted
+    pub fn draw(&self, debug: &mut DebugDraw, scene: &Scene, node: NodeId, eye: Vec3) {
+        let Some(target) = scene.get(node) else {
+            return;
+        };
+        let world = parent_world(scene, node) * target.local.matrix();
+        let origin = world.transform_point3(Vec3::ZERO);
+        let length = self.length(origin, eye);
+        let active = self.drag.map(|drag| drag.axis).or(self.hovered);
+        for axis in GizmoAxis::ALL {
+            let color = if active == Some(axis) {
+                HIGHLIGHT
+            } else {
+                axis.color()
+            };
+            let direction = self.direction(axis, &world);
+            let tip = origin + direction * length;
+            match self.mode {
+                GizmoMode::Translate => {
+                    debug.line(origin, tip, color);
+                    // Arrow head of four lines back from the tip
+                    let (u, v) = direction.any_orthonormal_pair();
+                    let base = tip - direction * length * 0.15;
+                    for side in [u, -u, v, -v] {
+                        debug.line(tip, base + side * length * 0.05, color);
+                    }
+                }
+                GizmoMode::Rotate => debug.circle(origin, direction, length, color),
+                GizmoMode::Scale => {
+                    debug.line(origin, tip, color);
+                    debug.aabb(&Aabb::from_center(tip, Vec3::splat(length * 0.05)), color);
+                }
+            }
+        }
+    }
+
+    fn length(&self, origin: Vec3, eye: Vec3) -> f32 {
+        origin.distance(eye).max(f32::EPSILON) * self.size
+    }
+
+    fn direction(&self, axis: GizmoAxis, world: &Mat4) -> Vec3 {
+        match self.mode {
+            GizmoMode::Scale => world
+                .transform_vector3(axis.unit())
+                .try_normalize()
+                .unwrap_or(axis.unit()),
+            GizmoMode::Translate | GizmoMode::Rotate => axis.unit(),
+        }
+    }
+
+    /// Closest handle under the ray, within a tenth of the handle length
+    fn pick(&self, ray: &Ray, world: &Mat4, origin: Vec3, length: f32) -> Option<GizmoAxis> {
+        let tolerance = length * 0.1;
+        GizmoAxis::ALL
+            .into_iter()
+            .filter_map(|axis| {
+                let direction = self.direction(axis, world);
+                let (t, miss) = match self.mode {
+                    GizmoMode::Translate | GizmoMode::Scale => {
+                        let (t, s) = ray.closest_to_line(origin, direction)?;
+                        let s = s.clamp(0.0, length);
+                        (t, ray.at(t).distance(origin + direction * s))
+                    }
+                    GizmoMode::Rotate => {
+                        let t = ray.intersect_plane(origin, direction)?;
+                        (t, (ray.at(t).distance(origin) - length).abs())
+                    }
+                };
+                (t >= 0.0 && miss <= tolerance).then_some((axis, t))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(axis, _)| axis)
+    }
+
+    /// Where the ray meets the handle being dragged, along its axis or in its plane of rotation
+    fn grab_point(&self, ray: &Ray, origin: Vec3, direction: Vec3) -> Option<Vec3> {
+        match self.mode {
+            GizmoMode::Translate | GizmoMode::Scale => {
+                let (_, s) = ray.closest_to_line(origin, direction)?;
+                Some(origin + direction * s)
+            }
+            GizmoMode::Rotate => Some(ray.at(ray.intersect_plane(origin, direction)?)),
+        }
+    }
+
+    fn apply(&self, drag: &Drag, current: Vec3, parent: Mat4) -> Transform {
+        let mut local = drag.local;
+        match self.mode {
+            GizmoMode::Translate => {
+                let distance = self.snapped((current - drag.grab).dot(drag.direction));
+                let offset = parent
+                    .inverse()
+                    .transform_vector3(drag.direction * distance);
+                local.translation += offset;
+            }
+            GizmoMode::Rotate => {
+                let from = drag.grab - drag.origin;
+                let to = current - drag.origin;
+                let angle = from.cross(to).dot(drag.direction).atan2(from.dot(to));
+                let world = Quat::from_axis_angle(drag.direction, self.snapped(angle));
+                let (_, parent_rotation, _) = parent.to_scale_rotation_translation();
+                local.rotation =
+                    (parent_rotation.inverse() * world * parent_rotation * local.rotation)
+                        .normalize();
+            }
+            GizmoMode::Scale => {
+                let start = (drag.grab - drag.origin).dot(drag.direction);
+                let now = (current - drag.origin).dot(drag.direction);
+                if start.abs() > f32::EPSILON {
+                    let factor = (now / start).max(0.01);
+                    let index = drag.axis as usize;
+                    local.scale[index] *= factor;
+                }
+            }
+        }
+        local
+    }
+
+    fn snapped(&self, value: f32) -> f32 {
+        match self.snap {
+            Some(step) if step > 0.0 => (value / step).round() * step,
+            _ => value,
+        }
+    }
+}
+
+fn parent_world(scene: &Scene, node: NodeId) -> Mat4 {
+    let mut world = Mat4::IDENTITY;
+    let mut current = scene.get(node).and_then(|node| node.parent());
+    while let Some(id) = current {
+        let parent = &scene[id];
+        world = parent.local.matrix() * world;
+        current = parent.parent();
+    }
+    world
+}
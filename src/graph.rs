@@ -0,0 +1,1331 @@
+//! Frame graph deriving pass order, layout transitions and barriers from declared accesses
+//!
+//! Passes say which named resources they read and write and how, the graph works out which
+//! passes are needed for the outputs, in what order, and what has to be synchronized in between.
+
+use std::collections::{HashMap, HashSet};
+
+use ash::{prelude::VkResult, vk, Device};
+use thiserror::Error;
+
+use crate::{
+    device::checkpoints::Checkpoints,
+    leaks,
+    memory::{find_memory_type, usage, usage::MemoryCategory},
+    profiling::GpuTimer,
+    trace::traced,
+};
+
+use self::dump::{DumpError, FrameDump};
+pub use self::transient::AliasingStats;
+use self::transient::{Lifetime, Request};
+
+pub mod dump;
+mod transient;
+
+#[derive(Debug, Error)]
+pub enum GraphError {
+    #[error("pass {pass} uses {resource} as both image and buffer")]
+    KindMismatch { pass: String, resource: String },
+    #[error("imported resource {0} has no handle set")]
+    MissingImport(String),
+    #[error("no memory type for transient resources {0}")]
+    NoMemoryType(String),
+    #[error("transient resource {0} has no memory")]
+    Unallocated(String),
+    #[error("transients {0} and {1} share memory but are now used at once, destroy to replan")]
+    StaleAliasing(String, String),
+    #[error(transparent)]
+    Dump(#[from] DumpError),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ResourceId(usize);
+
+/// How a pass uses a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ColorAttachment,
+    DepthAttachment,
+    /// Depth test without writes, e.g. a forward pass after a depth prepass
+    DepthRead,
+    SampledFragment,
+    SampledCompute,
+    StorageReadCompute,
+    StorageWriteCompute,
+    TransferSrc,
+    TransferDst,
+    Present,
+    VertexBuffer,
+    IndexBuffer,
+    IndirectBuffer,
+    UniformBuffer,
+}
+
+impl Access {
+    pub fn stage(self) -> vk::PipelineStageFlags {
+        use vk::PipelineStageFlags as S;
+        match self {
+            Access::ColorAttachment => S::COLOR_ATTACHMENT_OUTPUT,
+            Access::DepthAttachment | Access::DepthRead => {
+                S::EARLY_FRAGMENT_TESTS | S::LATE_FRAGMENT_TESTS
+            }
+            Access::SampledFragment => S::FRAGMENT_SHADER,
+            Access::SampledCompute | Access::StorageReadCompute | Access::StorageWriteCompute => {
+                S::COMPUTE_SHADER
+            }
+            Access::TransferSrc | Access::TransferDst => S::TRANSFER,
+            Access::Present => S::BOTTOM_OF_PIPE,
+            Access::VertexBuffer | Access::IndexBuffer => S::VERTEX_INPUT,
+            Access::IndirectBuffer => S::DRAW_INDIRECT,
+            Access::UniformBuffer => S::VERTEX_SHADER | S::FRAGMENT_SHADER | S::COMPUTE_SHADER,
+        }
+    }
+
+    pub fn access(self) -> vk::AccessFlags {
+        use vk::AccessFlags as A;
+        match self {
+            Access::ColorAttachment => A::COLOR_ATTACHMENT_READ | A::COLOR_ATTACHMENT_WRITE,
+            Access::DepthAttachment => {
+                A::DEPTH_STENCIL_ATTACHMENT_READ | A::DEPTH_STENCIL_ATTACHMENT_WRITE
+            }
+            Access::DepthRead => A::DEPTH_STENCIL_ATTACHMENT_READ,
+            Access::SampledFragment | Access::SampledCompute | Access::StorageReadCompute => {
+                A::SHADER_READ
+            }
+            Access::StorageWriteCompute => A::SHADER_READ | A::SHADER_WRITE,
+            Access::TransferSrc => A::TRANSFER_READ,
+            Access::TransferDst => A::TRANSFER_WRITE,
+            Access::Present => A::empty(),
+            Access::VertexBuffer => A::VERTEX_ATTRIBUTE_READ,
+            Access::IndexBuffer => A::INDEX_READ,
+            Access::IndirectBuffer => A::INDIRECT_COMMAND_READ,
+            Access::UniformBuffer => A::UNIFORM_READ,
+        }
+    }
+
+    pub fn layout(self) -> vk::ImageLayout {
+        use vk::ImageLayout as L;
+        match self {
+            Access::ColorAttachment => L::COLOR_ATTACHMENT_OPTIMAL,
+            Access::DepthAttachment => L::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            Access::DepthRead => L::DEPTH_STENCIL_READ_ONLY_OPTIMAL,
+            Access::SampledFragment | Access::SampledCompute => L::SHADER_READ_ONLY_OPTIMAL,
+            Access::StorageReadCompute | Access::StorageWriteCompute => L::GENERAL,
+            Access::TransferSrc => L::TRANSFER_SRC_OPTIMAL,
+            Access::TransferDst => L::TRANSFER_DST_OPTIMAL,
+            Access::Present => L::PRESENT_SRC_KHR,
+            _ => L::UNDEFINED,
+        }
+    }
+
+    pub fn is_write(self) -> bool {
+        matches!(
+            self,
+            Access::ColorAttachment
+                | Access::DepthAttachment
+                | Access::StorageWriteCompute
+                | Access::TransferDst
+        )
+    }
+
+    fn image_usage(self) -> vk::ImageUsageFlags {
+        use vk::ImageUsageFlags as U;
+        match self {
+            Access::ColorAttachment => U::COLOR_ATTACHMENT,
+            Access::DepthAttachment | Access::DepthRead => U::DEPTH_STENCIL_ATTACHMENT,
+            Access::SampledFragment | Access::SampledCompute => U::SAMPLED,
+            Access::StorageReadCompute | Access::StorageWriteCompute => U::STORAGE,
+            Access::TransferSrc => U::TRANSFER_SRC,
+            Access::TransferDst => U::TRANSFER_DST,
+            _ => U::empty(),
+        }
+    }
+
+    fn buffer_usage(self) -> vk::BufferUsageFlags {
+        use vk::BufferUsageFlags as U;
+        match self {
+            Access::StorageReadCompute | Access::StorageWriteCompute => U::STORAGE_BUFFER,
+            Access::TransferSrc => U::TRANSFER_SRC,
+            Access::TransferDst => U::TRANSFER_DST,
+            Access::VertexBuffer => U::VERTEX_BUFFER,
+            Access::IndexBuffer => U::INDEX_BUFFER,
+            Access::IndirectBuffer => U::INDIRECT_BUFFER,
+            Access::UniformBuffer => U::UNIFORM_BUFFER,
+            _ => U::empty(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageDesc {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub mip_levels: u32,
+    /// Layers of an array image, e.g. one per shadow cascade
+    pub array_layers: u32,
+}
+
+impl ImageDesc {
+    /// A 2D image with a single mip level and layer
+    pub fn new(format: vk::Format, extent: vk::Extent2D) -> Self {
+        ImageDesc {
+            format,
+            extent,
+            mip_levels: 1,
+            array_layers: 1,
+        }
+    }
+
+    pub fn with_mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = mip_levels;
+        self
+    }
+
+    pub fn with_array_layers(mut self, array_layers: u32) -> Self {
+        self.array_layers = array_layers;
+        self
+    }
+
+    pub fn aspect(&self) -> vk::ImageAspectFlags {
+        match self.format {
+            vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => {
+                vk::ImageAspectFlags::DEPTH
+            }
+            vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT => {
+                vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+            }
+            _ => vk::ImageAspectFlags::COLOR,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BufferDesc {
+    pub size: vk::DeviceSize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ResourceDesc {
+    Image(ImageDesc),
+    Buffer(BufferDesc),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+enum Physical {
+    #[default]
+    None,
+    Image {
+        image: vk::Image,
+        view: vk::ImageView,
+    },
+    Buffer(vk::Buffer),
+}
+
+struct Resource {
+    name: String,
+    desc: ResourceDesc,
+    physical: Physical,
+    /// Index into the graph's memory blocks, only for transients
+    block: Option<usize>,
+    imported: Option<Imported>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Imported {
+    initial_layout: vk::ImageLayout,
+    /// Access the resource is left ready for after the graph, e.g. [`Access::Present`]
+    final_access: Option<Access>,
+}
+
+type PassFn = Box<dyn FnMut(&PassContext)>;
+
+struct Pass {
+    name: String,
+    accesses: Vec<(ResourceId, Access)>,
+    run: PassFn,
+}
+
+/// Declares a pass's resource accesses
+pub struct PassBuilder<'a> {
+    accesses: &'a mut Vec<(ResourceId, Access)>,
+}
+
+impl PassBuilder<'_> {
+    pub fn read(&mut self, resource: ResourceId, access: Access) -> &mut Self {
+        debug_assert!(!access.is_write(), "{access:?} is a write");
+        self.accesses.push((resource, access));
+        self
+    }
+
+    pub fn write(&mut self, resource: ResourceId, access: Access) -> &mut Self {
+        self.accesses.push((resource, access));
+        self
+    }
+}
+
+/// Handles available to a pass while it records
+pub struct PassContext<'a> {
+    pub device: &'a Device,
+    pub cmd: vk::CommandBuffer,
+    resources: &'a [Resource],
+}
+
+impl PassContext<'_> {
+    pub fn image(&self, id: ResourceId) -> vk::Image {
+        match self.resources[id.0].physical {
+            Physical::Image { image, .. } => image,
+            _ => panic!("{} is not an image", self.resources[id.0].name),
+        }
+    }
+
+    pub fn view(&self, id: ResourceId) -> vk::ImageView {
+        match self.resources[id.0].physical {
+            Physical::Image { view, .. } => view,
+            _ => panic!("{} is not an image", self.resources[id.0].name),
+        }
+    }
+
+    pub fn buffer(&self, id: ResourceId) -> vk::Buffer {
+        match self.resources[id.0].physical {
+            Physical::Buffer(buffer) => buffer,
+            _ => panic!("{} is not a buffer", self.resources[id.0].name),
+        }
+    }
+
+    pub fn extent(&self, id: ResourceId) -> vk::Extent2D {
+        match self.resources[id.0].desc {
+            ResourceDesc::Image(desc) => desc.extent,
+            ResourceDesc::Buffer(_) => panic!("{} is not an image", self.resources[id.0].name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    stage: vk::PipelineStageFlags,
+    access: vk::AccessFlags,
+    layout: vk::ImageLayout,
+    write: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Transition {
+    resource: ResourceId,
+    from: State,
+    to: State,
+}
+
+#[derive(Default)]
+struct Barriers {
+    transitions: Vec<Transition>,
+}
+
+impl Barriers {
+    fn record(&self, device: &Device, cmd: vk::CommandBuffer, resources: &[Resource]) {
+        if self.transitions.is_empty() {
+            return;
+        }
+        let mut src_stage = vk::PipelineStageFlags::empty();
+        let mut dst_stage = vk::PipelineStageFlags::empty();
+        let mut images = Vec::new();
+        let mut buffers = Vec::new();
+        for t in &self.transitions {
+            src_stage |= t.from.stage;
+            dst_stage |= t.to.stage;
+            let resource = &resources[t.resource.0];
+            // Only writes need their caches made available
+            let src_access = if t.from.write {
+                t.from.access
+            } else {
+                vk::AccessFlags::empty()
+            };
+            match (resource.physical, resource.desc) {
+                (Physical::Image { image, .. }, ResourceDesc::Image(desc)) => images.push(
+                    vk::ImageMemoryBarrier::builder()
+                        .src_access_mask(src_access)
+                        .dst_access_mask(t.to.access)
+                        .old_layout(t.from.layout)
+                        .new_layout(t.to.layout)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .image(image)
+                        .subresource_range(vk::ImageSubresourceRange {
+                            aspect_mask: desc.aspect(),
+                            base_mip_level: 0,
+                            level_count: vk::REMAINING_MIP_LEVELS,
+                            base_array_layer: 0,
+                            layer_count: vk::REMAINING_ARRAY_LAYERS,
+                        })
+                        .build(),
+                ),
+                (Physical::Buffer(buffer), _) => buffers.push(
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(src_access)
+                        .dst_access_mask(t.to.access)
+                        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                        .buffer(buffer)
+                        .offset(0)
+                        .size(vk::WHOLE_SIZE)
+                        .build(),
+                ),
+                _ => {}
+            }
+        }
+        unsafe {
+            traced!(
+                "vkCmdPipelineBarrier",
+                src_stage = src_stage,
+                dst_stage = dst_stage,
+                buffers = buffers,
+                images = images;
+                device.cmd_pipeline_barrier(
+                    cmd,
+                    src_stage,
+                    dst_stage,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &buffers,
+                    &images,
+                )
+            )
+        };
+    }
+}
+
+/// Pass order and barriers worked out by [`RenderGraph::compile`]
+struct Compiled {
+    order: Vec<(usize, Barriers)>,
+    final_barriers: Barriers,
+}
+
+#[derive(Default)]
+pub struct RenderGraph {
+    resources: Vec<Resource>,
+    names: HashMap<String, ResourceId>,
+    passes: Vec<Pass>,
+    outputs: Vec<ResourceId>,
+    compiled: Option<Compiled>,
+    /// Memory shared by transients, with its size for usage accounting
+    blocks: Vec<(vk::DeviceMemory, vk::DeviceSize)>,
+    aliasing: AliasingStats,
+    /// Transient images get `TRANSFER_SRC` usage so frames can be dumped
+    dumpable: bool,
+}
+
+/// Extra work recorded around passes, for debugging and profiling
+#[derive(Default)]
+struct Hooks<'a> {
+    checkpoints: Option<&'a mut Checkpoints>,
+    timer: Option<(&'a mut GpuTimer, usize)>,
+    dump: Option<&'a mut FrameDump>,
+}
+
+impl RenderGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add_resource(
+        &mut self,
+        name: &str,
+        desc: ResourceDesc,
+        imported: Option<Imported>,
+    ) -> ResourceId {
+        let id = ResourceId(self.resources.len());
+        self.resources.push(Resource {
+            name: name.to_owned(),
+            desc,
+            physical: Physical::None,
+            block: None,
+            imported,
+        });
+        self.names.insert(name.to_owned(), id);
+        self.compiled = None;
+        id
+    }
+
+    /// An image created and owned by the graph, its contents do not survive between frames
+    ///
+    /// Its memory may be shared with other transients that are not in use at the same time.
+    pub fn create_image(&mut self, name: &str, desc: ImageDesc) -> ResourceId {
+        self.add_resource(name, ResourceDesc::Image(desc), None)
+    }
+
+    pub fn create_buffer(&mut self, name: &str, desc: BufferDesc) -> ResourceId {
+        self.add_resource(name, ResourceDesc::Buffer(desc), None)
+    }
+
+    /// An image owned elsewhere, such as a swapchain image, set with [`Self::set_image`]
+    pub fn import_image(
+        &mut self,
+        name: &str,
+        desc: ImageDesc,
+        initial_layout: vk::ImageLayout,
+        final_access: Option<Access>,
+    ) -> ResourceId {
+        let imported = Imported {
+            initial_layout,
+            final_access,
+        };
+        self.add_resource(name, ResourceDesc::Image(desc), Some(imported))
+    }
+
+    pub fn import_buffer(&mut self, name: &str, desc: BufferDesc) -> ResourceId {
+        let imported = Imported {
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_access: None,
+        };
+        self.add_resource(name, ResourceDesc::Buffer(desc), Some(imported))
+    }
+
+    pub fn set_image(&mut self, id: ResourceId, image: vk::Image, view: vk::ImageView) {
+        self.resources[id.0].physical = Physical::Image { image, view };
+    }
+
+    pub fn set_buffer(&mut self, id: ResourceId, buffer: vk::Buffer) {
+        self.resources[id.0].physical = Physical::Buffer(buffer);
+    }
+
+    pub fn resource(&self, name: &str) -> Option<ResourceId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn add_pass(
+        &mut self,
+        name: &str,
+        setup: impl FnOnce(&mut PassBuilder),
+        run: impl FnMut(&PassContext) + 'static,
+    ) {
+        let mut accesses = Vec::new();
+        setup(&mut PassBuilder {
+            accesses: &mut accesses,
+        });
+        self.passes.push(Pass {
+            name: name.to_owned(),
+            accesses,
+            run: Box::new(run),
+        });
+        self.compiled = None;
+    }
+
+    /// Marks a resource as needed after the frame, passes not contributing to one are culled
+    pub fn mark_output(&mut self, id: ResourceId) {
+        self.outputs.push(id);
+        self.compiled = None;
+    }
+
+    /// Dependency edges between passes from read-after-write, write-after-read and
+    /// write-after-write hazards on each resource
+    fn dependencies(&self) -> Vec<Vec<usize>> {
+        let mut deps = vec![Vec::new(); self.passes.len()];
+        let mut last_writer: HashMap<ResourceId, usize> = HashMap::new();
+        let mut readers: HashMap<ResourceId, Vec<usize>> = HashMap::new();
+        for (i, pass) in self.passes.iter().enumerate() {
+            for &(resource, access) in &pass.accesses {
+                if let Some(&writer) = last_writer.get(&resource) {
+                    deps[i].push(writer);
+                }
+                if access.is_write() {
+                    deps[i].extend(readers.remove(&resource).unwrap_or_default());
+                    last_writer.insert(resource, i);
+                } else {
+                    readers.entry(resource).or_default().push(i);
+                }
+            }
+            deps[i].retain(|&dep| dep != i);
+            deps[i].sort_unstable();
+            deps[i].dedup();
+        }
+        deps
+    }
+
+    /// Passes needed for the outputs, in an order satisfying every dependency
+    fn schedule(&self) -> Vec<usize> {
+        let deps = self.dependencies();
+
+        let mut needed = vec![self.outputs.is_empty(); self.passes.len()];
+        let mut stack: Vec<usize> = (0..self.passes.len())
+            .filter(|&i| {
+                self.passes[i]
+                    .accesses
+                    .iter()
+                    .any(|(resource, access)| access.is_write() && self.outputs.contains(resource))
+            })
+            .collect();
+        while let Some(i) = stack.pop() {
+            if !needed[i] {
+                needed[i] = true;
+                stack.extend(&deps[i]);
+            }
+        }
+
+        // Kahn's algorithm, preferring declaration order among ready passes
+        let mut remaining: Vec<usize> = deps.iter().map(Vec::len).collect();
+        let mut dependents = vec![Vec::new(); self.passes.len()];
+        for (i, pass_deps) in deps.iter().enumerate() {
+            for &dep in pass_deps {
+                dependents[dep].push(i);
+            }
+        }
+        let mut ready: std::collections::BTreeSet<usize> = (0..self.passes.len())
+            .filter(|&i| remaining[i] == 0)
+            .collect();
+        let mut order = Vec::new();
+        while let Some(i) = ready.pop_first() {
+            if needed[i] {
+                order.push(i);
+            }
+            for &dependent in &dependents[i] {
+                remaining[dependent] -= 1;
+                if remaining[dependent] == 0 {
+                    ready.insert(dependent);
+                }
+            }
+        }
+        order
+    }
+
+    fn transient_usage(
+        &self,
+        order: &[usize],
+    ) -> HashMap<ResourceId, (vk::ImageUsageFlags, vk::BufferUsageFlags)> {
+        let mut usage: HashMap<ResourceId, (vk::ImageUsageFlags, vk::BufferUsageFlags)> =
+            HashMap::new();
+        for &i in order {
+            for &(resource, access) in &self.passes[i].accesses {
+                let entry = usage.entry(resource).or_default();
+                entry.0 |= access.image_usage();
+                entry.1 |= access.buffer_usage();
+                if self.dumpable {
+                    entry.0 |= vk::ImageUsageFlags::TRANSFER_SRC;
+                }
+            }
+        }
+        usage
+    }
+
+    /// Orders the passes, works out barriers and creates the transient resources
+    ///
+    /// Transients already created are kept, ones used for the first time get memory of their
+    /// own. Call [`Self::destroy`] first if the usage of created ones changed.
+    pub fn compile(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<(), GraphError> {
+        let order = self.schedule();
+
+        for &i in &order {
+            for &(resource, access) in &self.passes[i].accesses {
+                let kind_ok = match self.resources[resource.0].desc {
+                    ResourceDesc::Image(_) => {
+                        !access.image_usage().is_empty() || access == Access::Present
+                    }
+                    ResourceDesc::Buffer(_) => !access.buffer_usage().is_empty(),
+                };
+                if !kind_ok {
+                    return Err(GraphError::KindMismatch {
+                        pass: self.passes[i].name.clone(),
+                        resource: self.resources[resource.0].name.clone(),
+                    });
+                }
+            }
+        }
+
+        let unallocated = order.iter().any(|&i| {
+            self.passes[i].accesses.iter().any(|&(resource, _)| {
+                let resource = &self.resources[resource.0];
+                resource.imported.is_none() && resource.block.is_none()
+            })
+        });
+        if unallocated {
+            self.create_transients(device, mem_props, &order)?;
+        }
+        self.check_aliasing(&order)?;
+
+        // Where each resource is left at the end of the frame
+        let mut last: HashMap<ResourceId, State> = HashMap::new();
+        for &i in &order {
+            for &(resource, access) in &self.passes[i].accesses {
+                last.insert(resource, state(access));
+            }
+        }
+        // Transients wait on the last use of everything sharing their memory, which covers
+        // both aliasing within the frame and the previous frame's use of the same memory
+        let mut block_last: HashMap<usize, State> = HashMap::new();
+        for (i, resource) in self.resources.iter().enumerate() {
+            let Some(block) = resource.block else {
+                continue;
+            };
+            // Culled this frame, but the previous frame may still be using it
+            let last = last.get(&ResourceId(i)).copied().unwrap_or(State {
+                stage: vk::PipelineStageFlags::ALL_COMMANDS,
+                access: vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+                layout: vk::ImageLayout::UNDEFINED,
+                write: true,
+            });
+            let merged = block_last.entry(block).or_insert(State {
+                stage: vk::PipelineStageFlags::empty(),
+                access: vk::AccessFlags::empty(),
+                layout: vk::ImageLayout::UNDEFINED,
+                write: true,
+            });
+            merged.stage |= last.stage;
+            merged.access |= last.access;
+        }
+        let mut current: HashMap<ResourceId, State> = HashMap::new();
+        for (i, resource) in self.resources.iter().enumerate() {
+            let initial = match (resource.imported, resource.block) {
+                (Some(imported), _) => State {
+                    stage: vk::PipelineStageFlags::TOP_OF_PIPE,
+                    access: vk::AccessFlags::empty(),
+                    layout: imported.initial_layout,
+                    write: false,
+                },
+                (None, Some(block)) => match block_last.get(&block) {
+                    Some(&initial) => initial,
+                    None => continue,
+                },
+                (None, None) => continue,
+            };
+            current.insert(ResourceId(i), initial);
+        }
+
+        let mut compiled = Vec::with_capacity(order.len());
+        for &i in &order {
+            let mut barriers = Barriers::default();
+            for &(resource, access) in &self.passes[i].accesses {
+                let to = state(access);
+                let Some(merged) = current.get_mut(&resource) else {
+                    let name = self.resources[resource.0].name.clone();
+                    return Err(GraphError::Unallocated(name));
+                };
+                let from = *merged;
+                if needs_barrier(from, to) {
+                    barriers.transitions.push(Transition { resource, from, to });
+                    *merged = to;
+                } else {
+                    // Merge concurrent reads so a later write waits on all of them
+                    merged.stage |= to.stage;
+                    merged.access |= to.access;
+                }
+            }
+            compiled.push((i, barriers));
+        }
+
+        let mut final_barriers = Barriers::default();
+        for (i, resource) in self.resources.iter().enumerate() {
+            let id = ResourceId(i);
+            let Some(final_access) = resource.imported.and_then(|imported| imported.final_access)
+            else {
+                continue;
+            };
+            let Some(&from) = current.get(&id) else {
+                continue;
+            };
+            let to = state(final_access);
+            if needs_barrier(from, to) {
+                final_barriers.transitions.push(Transition {
+                    resource: id,
+                    from,
+                    to,
+                });
+            }
+        }
+
+        self.compiled = Some(Compiled {
+            order: compiled,
+            final_barriers,
+        });
+        Ok(())
+    }
+
+    /// First and last position in `order` each resource is used at
+    fn lifetimes(&self, order: &[usize]) -> HashMap<ResourceId, Lifetime> {
+        let mut lifetimes: HashMap<ResourceId, Lifetime> = HashMap::new();
+        for (position, &i) in order.iter().enumerate() {
+            for &(resource, _) in &self.passes[i].accesses {
+                lifetimes
+                    .entry(resource)
+                    .and_modify(|lifetime| lifetime.last = position)
+                    .or_insert(Lifetime {
+                        first: position,
+                        last: position,
+                    });
+            }
+        }
+        lifetimes
+    }
+
+    /// Transients given a block by an earlier compile must still be used at separate times
+    fn check_aliasing(&self, order: &[usize]) -> Result<(), GraphError> {
+        let lifetimes = self.lifetimes(order);
+        let mut by_block: HashMap<usize, Vec<(usize, Lifetime)>> = HashMap::new();
+        for (i, resource) in self.resources.iter().enumerate() {
+            if let (Some(block), Some(&lifetime)) = (resource.block, lifetimes.get(&ResourceId(i)))
+            {
+                let members = by_block.entry(block).or_default();
+                if let Some(&(other, _)) =
+                    members.iter().find(|(_, other)| other.overlaps(lifetime))
+                {
+                    return Err(GraphError::StaleAliasing(
+                        self.resources[other].name.clone(),
+                        resource.name.clone(),
+                    ));
+                }
+                members.push((i, lifetime));
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the transients used by the scheduled passes that have no memory yet, and binds
+    /// them to shared memory
+    fn create_transients(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        order: &[usize],
+    ) -> Result<(), GraphError> {
+        let lifetimes = self.lifetimes(order);
+        let usage = self.transient_usage(order);
+        let mut requests = Vec::new();
+        for (i, resource) in self.resources.iter_mut().enumerate() {
+            let id = ResourceId(i);
+            if resource.imported.is_some() || resource.block.is_some() {
+                continue;
+            }
+            let (Some(&lifetime), Some(&(image_usage, buffer_usage))) =
+                (lifetimes.get(&id), usage.get(&id))
+            else {
+                continue;
+            };
+            let requirements =
+                match unsafe { create_handle(device, resource, image_usage, buffer_usage) } {
+                    Ok(requirements) => requirements,
+                    Err(err) => {
+                        unsafe { self.destroy(device) };
+                        return Err(err.into());
+                    }
+                };
+            requests.push(Request {
+                resource: i,
+                requirements,
+                lifetime,
+            });
+        }
+
+        let requested: vk::DeviceSize = requests
+            .iter()
+            .map(|request| request.requirements.size)
+            .sum();
+        let plans = transient::plan(requests, mem_props);
+        for plan in &plans {
+            let result = self.allocate_block(device, mem_props, plan);
+            if let Err(err) = result {
+                unsafe { self.destroy(device) };
+                return Err(err);
+            }
+        }
+        // Transients planned by an earlier compile keep their blocks
+        self.aliasing.resources += plans.iter().map(|plan| plan.members.len()).sum::<usize>();
+        self.aliasing.blocks += plans.len();
+        self.aliasing.requested += requested;
+        self.aliasing.allocated += plans.iter().map(|plan| plan.size).sum::<vk::DeviceSize>();
+        Ok(())
+    }
+
+    fn allocate_block(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        plan: &transient::BlockPlan,
+    ) -> Result<(), GraphError> {
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            plan.type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            let names: Vec<_> = plan
+                .members
+                .iter()
+                .map(|&(i, _)| self.resources[i].name.as_str())
+                .collect();
+            return Err(GraphError::NoMemoryType(names.join(", ")));
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(plan.size)
+            .memory_type_index(type_index);
+        let memory = unsafe {
+            traced!(
+                "vkAllocateMemory",
+                size = alloc_info.allocation_size,
+                memory_type = alloc_info.memory_type_index;
+                device.allocate_memory(&alloc_info, None)
+            )?
+        };
+        usage::record_allocation(MemoryCategory::RenderTarget, plan.size);
+        leaks::track(memory, "render graph block");
+        let block = self.blocks.len();
+        self.blocks.push((memory, plan.size));
+
+        for &(i, _) in &plan.members {
+            let resource = &mut self.resources[i];
+            resource.block = Some(block);
+            unsafe { bind_and_view(device, resource, memory)? };
+        }
+        Ok(())
+    }
+
+    /// Lets [`RenderGraph::execute_with_dump`] copy transient images, at some cost to how well
+    /// the driver can compress them
+    ///
+    /// Takes effect when transients are next created, after [`RenderGraph::destroy`] if they
+    /// already have been.
+    pub fn set_dumpable(&mut self, dumpable: bool) {
+        self.dumpable = dumpable;
+    }
+
+    /// Memory saved by aliasing transients, as of the last compile
+    pub fn aliasing_stats(&self) -> AliasingStats {
+        self.aliasing
+    }
+
+    /// Records every pass with its barriers, compiling first if the graph changed
+    pub fn execute(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+    ) -> Result<(), GraphError> {
+        self.record(device, mem_props, cmd, Hooks::default())
+    }
+
+    /// [`RenderGraph::execute`] with a checkpoint before and after each pass, so a device loss
+    /// can be traced back to the pass that hung
+    pub fn execute_with_checkpoints(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        checkpoints: &mut Checkpoints,
+    ) -> Result<(), GraphError> {
+        let hooks = Hooks {
+            checkpoints: Some(checkpoints),
+            ..Default::default()
+        };
+        self.record(device, mem_props, cmd, hooks)
+    }
+
+    /// [`RenderGraph::execute`] with each pass timed as a scope named after it, in `frame`'s
+    /// queries of `timer`
+    pub fn execute_timed(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        timer: &mut GpuTimer,
+        frame: usize,
+    ) -> Result<(), GraphError> {
+        let hooks = Hooks {
+            timer: Some((timer, frame)),
+            ..Default::default()
+        };
+        self.record(device, mem_props, cmd, hooks)
+    }
+
+    /// [`RenderGraph::execute`] copying every image into `dump` after the last pass writing it,
+    /// see [`dump`]. Transients are only copied once [`RenderGraph::set_dumpable`] has taken
+    /// effect.
+    pub fn execute_with_dump(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        dump: &mut FrameDump,
+    ) -> Result<(), GraphError> {
+        let hooks = Hooks {
+            dump: Some(dump),
+            ..Default::default()
+        };
+        self.record(device, mem_props, cmd, hooks)
+    }
+
+    fn record(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        mut hooks: Hooks,
+    ) -> Result<(), GraphError> {
+        if let Some(missing) = self.resources.iter().find(|resource| {
+            resource.imported.is_some() && matches!(resource.physical, Physical::None)
+        }) {
+            return Err(GraphError::MissingImport(missing.name.clone()));
+        }
+        if self.compiled.is_none() {
+            self.compile(device, mem_props)?;
+        }
+        let compiled = self.compiled.as_ref().unwrap();
+        // Images are dumped once nothing later in the frame writes them
+        let mut last_writes = HashSet::new();
+        if hooks.dump.is_some() {
+            let mut written = HashSet::new();
+            for (i, _) in compiled.order.iter().rev() {
+                for &(resource, access) in &self.passes[*i].accesses {
+                    if access.is_write() && written.insert(resource) {
+                        last_writes.insert((*i, resource));
+                    }
+                }
+            }
+        }
+        for (i, barriers) in &compiled.order {
+            if let Some(checkpoints) = hooks.checkpoints.as_deref_mut() {
+                checkpoints.begin(cmd, &self.passes[*i].name);
+            }
+            barriers.record(device, cmd, &self.resources);
+            if let Some((timer, frame)) = hooks.timer.as_mut() {
+                timer.begin(device, cmd, *frame, &self.passes[*i].name);
+            }
+            let context = PassContext {
+                device,
+                cmd,
+                resources: &self.resources,
+            };
+            (self.passes[*i].run)(&context);
+            if let Some((timer, frame)) = hooks.timer.as_mut() {
+                timer.end(device, cmd, *frame);
+            }
+            if let Some(dump) = hooks.dump.as_deref_mut() {
+                for &(resource, access) in &self.passes[*i].accesses {
+                    let copyable = match self.resources[resource.0].imported {
+                        Some(_) => dump.includes_imported(),
+                        None => self.dumpable,
+                    };
+                    if copyable && last_writes.contains(&(*i, resource)) {
+                        let resource = &self.resources[resource.0];
+                        dump.record_copy(device, mem_props, cmd, resource, state(access))?;
+                    }
+                }
+            }
+            if let Some(checkpoints) = hooks.checkpoints.as_deref_mut() {
+                checkpoints.end(cmd, &self.passes[*i].name);
+            }
+        }
+        compiled.final_barriers.record(device, cmd, &self.resources);
+        Ok(())
+    }
+
+    /// Names of the passes that will run, in order
+    pub fn pass_order(&self) -> Vec<&str> {
+        self.schedule()
+            .into_iter()
+            .map(|i| self.passes[i].name.as_str())
+            .collect()
+    }
+
+    /// # Safety
+    ///
+    /// None of the graph's transient resources may still be in use by the device.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for resource in &mut self.resources {
+            destroy_transient(device, resource);
+        }
+        for (memory, size) in self.blocks.drain(..) {
+            leaks::untrack(memory);
+            traced!("vkFreeMemory", memory = memory; device.free_memory(memory, None));
+            usage::release_allocation(MemoryCategory::RenderTarget, size);
+        }
+        self.aliasing = AliasingStats::default();
+        self.compiled = None;
+    }
+}
+
+fn state(access: Access) -> State {
+    State {
+        stage: access.stage(),
+        access: access.access(),
+        layout: access.layout(),
+        write: access.is_write(),
+    }
+}
+
+fn needs_barrier(from: State, to: State) -> bool {
+    from.write || to.write || from.layout != to.layout
+}
+
+/// Creates the image or buffer without memory, returning what it needs bound
+///
+/// # Safety
+///
+/// The resource must not already have a handle.
+unsafe fn create_handle(
+    device: &Device,
+    resource: &mut Resource,
+    image_usage: vk::ImageUsageFlags,
+    buffer_usage: vk::BufferUsageFlags,
+) -> VkResult<vk::MemoryRequirements> {
+    match resource.desc {
+        ResourceDesc::Image(desc) => {
+            let info = vk::ImageCreateInfo::builder()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(desc.format)
+                .extent(vk::Extent3D {
+                    width: desc.extent.width,
+                    height: desc.extent.height,
+                    depth: 1,
+                })
+                .mip_levels(desc.mip_levels)
+                .array_layers(desc.array_layers)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(image_usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            let image = traced!(
+                "vkCreateImage",
+                format = info.format,
+                extent = info.extent,
+                usage = info.usage;
+                device.create_image(&info, None)
+            )?;
+            leaks::track(image, "render graph image");
+            resource.physical = Physical::Image {
+                image,
+                view: vk::ImageView::null(),
+            };
+            Ok(device.get_image_memory_requirements(image))
+        }
+        ResourceDesc::Buffer(desc) => {
+            let info = vk::BufferCreateInfo::builder()
+                .size(desc.size)
+                .usage(buffer_usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
+            let buffer = traced!(
+                "vkCreateBuffer",
+                size = info.size,
+                usage = info.usage;
+                device.create_buffer(&info, None)
+            )?;
+            leaks::track(buffer, "render graph buffer");
+            resource.physical = Physical::Buffer(buffer);
+            Ok(device.get_buffer_memory_requirements(buffer))
+        }
+    }
+}
+
+unsafe fn bind_and_view(
+    device: &Device,
+    resource: &mut Resource,
+    memory: vk::DeviceMemory,
+) -> VkResult<()> {
+    match (&mut resource.physical, resource.desc) {
+        (Physical::Image { image, view }, ResourceDesc::Image(desc)) => {
+            traced!(
+                "vkBindImageMemory",
+                image = *image,
+                memory = memory;
+                device.bind_image_memory(*image, memory, 0)
+            )?;
+            let view_type = if desc.array_layers > 1 {
+                vk::ImageViewType::TYPE_2D_ARRAY
+            } else {
+                vk::ImageViewType::TYPE_2D
+            };
+            let info = vk::ImageViewCreateInfo::builder()
+                .image(*image)
+                .view_type(view_type)
+                .format(desc.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: desc.aspect(),
+                    base_mip_level: 0,
+                    level_count: desc.mip_levels,
+                    base_array_layer: 0,
+                    layer_count: desc.array_layers,
+                });
+            *view = traced!(
+                "vkCreateImageView",
+                image = info.image,
+                view_type = info.view_type,
+                format = info.format;
+                device.create_image_view(&info, None)
+            )?;
+            leaks::track(*view, "render graph view");
+        }
+        (Physical::Buffer(buffer), _) => traced!(
+            "vkBindBufferMemory",
+            buffer = *buffer,
+            memory = memory;
+            device.bind_buffer_memory(*buffer, memory, 0)
+        )?,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+unsafe fn destroy_transient(device: &Device, resource: &mut Resource) {
+    if resource.imported.is_some() {
+        return;
+    }
+    match resource.physical {
+        Physical::Image { image, view } => {
+            if view != vk::ImageView::null() {
+                leaks::untrack(view);
+                traced!("vkDestroyImageView", view = view; device.destroy_image_view(view, None));
+            }
+            leaks::untrack(image);
+            traced!("vkDestroyImage", image = image; device.destroy_image(image, None));
+        }
+        Physical::Buffer(buffer) => {
+            leaks::untrack(buffer);
+            traced!("vkDestroyBuffer", buffer = buffer; device.destroy_buffer(buffer, None));
+        }
+        Physical::None => {}
+    }
+    resource.physical = Physical::None;
+    resource.block = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::Buffer, test_support::HeadlessContext};
+
+    const EXTENT: vk::Extent2D = vk::Extent2D {
+        width: 4,
+        height: 4,
+    };
+
+    const COLOR_LAYERS: vk::ImageSubresourceLayers = vk::ImageSubresourceLayers {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        mip_level: 0,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+
+    #[test]
+    fn transient_is_cleared_copied_out_and_unused_passes_culled() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let size = (EXTENT.width * EXTENT.height * 4) as vk::DeviceSize;
+        let readback = Buffer::new(
+            &ctx.device,
+            &ctx.mem_props,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )
+        .unwrap();
+
+        let mut graph = RenderGraph::new();
+        let desc = ImageDesc::new(vk::Format::R8G8B8A8_UNORM, EXTENT);
+        let color = graph.create_image("color", desc);
+        let unused = graph.create_image("unused", desc);
+        let output = graph.import_buffer("readback", BufferDesc { size });
+        graph.set_buffer(output, readback.buffer);
+
+        graph.add_pass(
+            "clear",
+            |pass| {
+                pass.write(color, Access::TransferDst);
+            },
+            move |ctx| unsafe {
+                let value = vk::ClearColorValue {
+                    float32: [1.0, 0.0, 0.0, 1.0],
+                };
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                ctx.device.cmd_clear_color_image(
+                    ctx.cmd,
+                    ctx.image(color),
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &value,
+                    &[range],
+                );
+            },
+        );
+        graph.add_pass(
+            "never read",
+            |pass| {
+                pass.write(unused, Access::TransferDst);
+            },
+            |_| panic!("culled pass ran"),
+        );
+        graph.add_pass(
+            "copy",
+            |pass| {
+                pass.read(color, Access::TransferSrc)
+                    .write(output, Access::TransferDst);
+            },
+            move |ctx| unsafe {
+                let region = vk::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: COLOR_LAYERS,
+                    image_offset: vk::Offset3D::default(),
+                    image_extent: vk::Extent3D {
+                        width: EXTENT.width,
+                        height: EXTENT.height,
+                        depth: 1,
+                    },
+                };
+                ctx.device.cmd_copy_image_to_buffer(
+                    ctx.cmd,
+                    ctx.image(color),
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    ctx.buffer(output),
+                    &[region],
+                );
+            },
+        );
+        graph.mark_output(output);
+
+        ctx.one_shot(|cmd| {
+            graph.execute(&ctx.device, &ctx.mem_props, cmd).unwrap();
+            let to_host = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            unsafe {
+                ctx.device.cmd_pipeline_barrier(
+                    cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[*to_host],
+                    &[],
+                    &[],
+                )
+            };
+        })
+        .unwrap();
+        assert_eq!(graph.pass_order(), ["clear", "copy"]);
+
+        let pixels = unsafe {
+            std::slice::from_raw_parts(
+                readback.mapped().unwrap().as_ptr().cast::<u8>(),
+                size as usize,
+            )
+        };
+        assert!(pixels
+            .chunks_exact(4)
+            .all(|pixel| pixel == [255, 0, 0, 255]));
+
+        unsafe {
+            graph.destroy(&ctx.device);
+            readback.destroy(&ctx.device);
+        }
+    }
+}
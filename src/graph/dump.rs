@@ -0,0 +1,423 @@
+//! Copies of every image a frame wrote, saved for inspection without a capture tool
+//!
+//! [`RenderGraph::execute_with_dump`](super::RenderGraph::execute_with_dump) copies each image
+//! right after the last pass writing it, before aliased memory is reused, into host memory held
+//! by a [`FrameDump`]. Once the frame has finished they're saved named after the graph's
+//! resources, 8 bit color as PNG and float color and depth as EXR. Every layer and mip level is
+//! saved, as `name_layer1_mip2` when the image has more than one.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use ash::{vk, Device};
+use exr::prelude::f16;
+use thiserror::Error;
+
+use super::{Physical, Resource, ResourceDesc, State};
+use crate::memory::Buffer;
+
+#[derive(Debug, Error)]
+pub enum DumpError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+    #[error(transparent)]
+    Exr(#[from] exr::error::Error),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+/// How texels of a format are laid out once copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Rgba8 {
+        bgra: bool,
+    },
+    R8,
+    Half(usize),
+    Float(usize),
+    /// 16 bit normalized depth
+    Depth16,
+    /// 24 bit normalized depth in the low bits of 32
+    Depth24,
+}
+
+impl Encoding {
+    fn of(format: vk::Format) -> Option<Self> {
+        use vk::Format as F;
+        Some(match format {
+            F::R8G8B8A8_UNORM | F::R8G8B8A8_SRGB => Encoding::Rgba8 { bgra: false },
+            F::B8G8R8A8_UNORM | F::B8G8R8A8_SRGB => Encoding::Rgba8 { bgra: true },
+            F::R8_UNORM => Encoding::R8,
+            F::R16_SFLOAT => Encoding::Half(1),
+            F::R16G16_SFLOAT => Encoding::Half(2),
+            F::R16G16B16A16_SFLOAT => Encoding::Half(4),
+            F::R32_SFLOAT | F::D32_SFLOAT | F::D32_SFLOAT_S8_UINT => Encoding::Float(1),
+            F::R32G32_SFLOAT => Encoding::Float(2),
+            F::R32G32B32A32_SFLOAT => Encoding::Float(4),
+            F::D16_UNORM | F::D16_UNORM_S8_UINT => Encoding::Depth16,
+            F::X8_D24_UNORM_PACK32 | F::D24_UNORM_S8_UINT => Encoding::Depth24,
+            _ => return None,
+        })
+    }
+
+    fn texel_size(self) -> usize {
+        match self {
+            Encoding::Rgba8 { .. } | Encoding::Depth24 => 4,
+            Encoding::R8 => 1,
+            Encoding::Half(channels) => channels * 2,
+            Encoding::Float(channels) => channels * 4,
+            Encoding::Depth16 => 2,
+        }
+    }
+
+    /// Texels as floats with `channels` per texel, for anything saved as EXR
+    fn floats(self, bytes: &[u8]) -> (usize, Vec<f32>) {
+        match self {
+            Encoding::Half(channels) => (
+                channels,
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                    .collect(),
+            ),
+            Encoding::Float(channels) => (
+                channels,
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            ),
+            Encoding::Depth16 => (
+                1,
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| u16::from_le_bytes([b[0], b[1]]) as f32 / u16::MAX as f32)
+                    .collect(),
+            ),
+            Encoding::Depth24 => (
+                1,
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| {
+                        let value = u32::from_le_bytes([b[0], b[1], b[2], b[3]]) & 0xff_ffff;
+                        value as f32 / 0xff_ffff as f32
+                    })
+                    .collect(),
+            ),
+            Encoding::Rgba8 { .. } | Encoding::R8 => unreachable!("8 bit formats are saved as PNG"),
+        }
+    }
+}
+
+/// One layer and mip level of an image, in a buffer of its own
+struct Copy {
+    name: String,
+    encoding: Encoding,
+    /// Of the mip level
+    extent: vk::Extent2D,
+    buffer: Buffer,
+    layer: u32,
+    mip: u32,
+}
+
+/// What [`FrameDump::save`] wrote
+#[derive(Debug, Clone, Default)]
+pub struct DumpReport {
+    pub written: Vec<PathBuf>,
+    /// Images left out, their format has no file encoding here
+    pub skipped: Vec<(String, vk::Format)>,
+}
+
+/// Host copies of one frame's images, filled while the frame records
+#[derive(Default)]
+pub struct FrameDump {
+    copies: Vec<Copy>,
+    skipped: Vec<(String, vk::Format)>,
+    include_imported: bool,
+}
+
+impl FrameDump {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copies imported images too, which then need `TRANSFER_SRC` usage
+    pub fn with_imported(mut self, include: bool) -> Self {
+        self.include_imported = include;
+        self
+    }
+
+    pub(super) fn includes_imported(&self) -> bool {
+        self.include_imported
+    }
+
+    /// Copies every layer and mip level of an image as the pass that last wrote it left it in
+    /// `state`, restoring that state afterwards so the graph's later barriers still hold
+    pub(super) fn record_copy(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        resource: &Resource,
+        state: State,
+    ) -> Result<(), DumpError> {
+        let (Physical::Image { image, .. }, ResourceDesc::Image(desc)) =
+            (resource.physical, resource.desc)
+        else {
+            return Ok(());
+        };
+        let name = &resource.name;
+        let Some(encoding) = Encoding::of(desc.format) else {
+            self.skipped.push((name.to_owned(), desc.format));
+            return Ok(());
+        };
+        let aspect = desc.aspect();
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: aspect,
+            base_mip_level: 0,
+            level_count: desc.mip_levels,
+            base_array_layer: 0,
+            layer_count: desc.array_layers,
+        };
+        // Depth only, stencil would need a copy of its own
+        let copy_aspect = if aspect.contains(vk::ImageAspectFlags::DEPTH) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            aspect
+        };
+        let mut copies: Vec<Copy> = Vec::new();
+        for layer in 0..desc.array_layers {
+            for mip in 0..desc.mip_levels {
+                let extent = vk::Extent2D {
+                    width: (desc.extent.width >> mip).max(1),
+                    height: (desc.extent.height >> mip).max(1),
+                };
+                let size = extent.width as usize * extent.height as usize * encoding.texel_size();
+                let buffer = match Buffer::new(
+                    device,
+                    mem_props,
+                    size as vk::DeviceSize,
+                    vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                ) {
+                    Ok(buffer) => buffer,
+                    Err(err) => {
+                        for copy in copies {
+                            unsafe { copy.buffer.destroy(device) };
+                        }
+                        return Err(err.into());
+                    }
+                };
+                let mut name = name.to_owned();
+                if desc.array_layers > 1 {
+                    name += &format!("_layer{layer}");
+                }
+                if desc.mip_levels > 1 {
+                    name += &format!("_mip{mip}");
+                }
+                copies.push(Copy {
+                    name,
+                    encoding,
+                    extent,
+                    buffer,
+                    layer,
+                    mip,
+                });
+            }
+        }
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(state.access)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(state.layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(range);
+        let back = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(state.access)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(state.layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(range);
+        let to_host: Vec<_> = copies
+            .iter()
+            .map(|copy| {
+                vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .buffer(copy.buffer.buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)
+                    .build()
+            })
+            .collect();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                state.stage,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*to_transfer],
+            );
+            for copy in &copies {
+                let region = vk::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: copy_aspect,
+                        mip_level: copy.mip,
+                        base_array_layer: copy.layer,
+                        layer_count: 1,
+                    },
+                    image_offset: vk::Offset3D::default(),
+                    image_extent: vk::Extent3D {
+                        width: copy.extent.width,
+                        height: copy.extent.height,
+                        depth: 1,
+                    },
+                };
+                device.cmd_copy_image_to_buffer(
+                    cmd,
+                    image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    copy.buffer.buffer,
+                    &[region],
+                );
+            }
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                state.stage | vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &to_host,
+                &[*back],
+            );
+        }
+        self.copies.extend(copies);
+        Ok(())
+    }
+
+    /// Names of the images copied so far, in the order they were written
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.copies.iter().map(|copy| copy.name.as_str())
+    }
+
+    /// Writes every copy into `dir`, created if needed, once the frame's fence has been waited on
+    pub fn save(&self, dir: &Path) -> Result<DumpReport, DumpError> {
+        fs::create_dir_all(dir)?;
+        let mut report = DumpReport {
+            skipped: self.skipped.clone(),
+            ..Default::default()
+        };
+        for copy in &self.copies {
+            let Some(mapped) = copy.buffer.mapped() else {
+                continue;
+            };
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    mapped.cast::<u8>().as_ptr(),
+                    copy.extent.width as usize
+                        * copy.extent.height as usize
+                        * copy.encoding.texel_size(),
+                )
+            };
+            // Resource names may hold characters files can't
+            let file_name: String = copy
+                .name
+                .chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            let path = match copy.encoding {
+                Encoding::Rgba8 { .. } | Encoding::R8 => {
+                    let path = dir.join(format!("{file_name}.png"));
+                    save_png(&path, copy, bytes)?;
+                    path
+                }
+                _ => {
+                    let path = dir.join(format!("{file_name}.exr"));
+                    save_exr(&path, copy, bytes)?;
+                    path
+                }
+            };
+            report.written.push(path);
+        }
+        Ok(report)
+    }
+
+    /// # Safety
+    ///
+    /// The frame the copies were recorded in must have finished executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for copy in self.copies.drain(..) {
+            copy.buffer.destroy(device);
+        }
+        self.skipped.clear();
+    }
+}
+
+fn save_png(path: &Path, copy: &Copy, bytes: &[u8]) -> Result<(), DumpError> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, copy.extent.width, copy.extent.height);
+    encoder.set_depth(png::BitDepth::Eight);
+    match copy.encoding {
+        Encoding::Rgba8 { bgra } => {
+            encoder.set_color(png::ColorType::Rgba);
+            let mut pixels = bytes.to_vec();
+            if bgra {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+            }
+            encoder.write_header()?.write_image_data(&pixels)?;
+        }
+        _ => {
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.write_header()?.write_image_data(bytes)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_exr(path: &Path, copy: &Copy, bytes: &[u8]) -> Result<(), DumpError> {
+    let (channels, texels) = copy.encoding.floats(bytes);
+    let width = copy.extent.width as usize;
+    let texel = |x: usize, y: usize| &texels[(y * width + x) * channels..][..channels];
+    let height = copy.extent.height as usize;
+    match channels {
+        4 => exr::prelude::write_rgba_file(path, width, height, |x, y| {
+            let t = texel(x, y);
+            (t[0], t[1], t[2], t[3])
+        })?,
+        // Red and green, blue left black
+        2 => exr::prelude::write_rgb_file(path, width, height, |x, y| {
+            let t = texel(x, y);
+            (t[0], t[1], 0.0)
+        })?,
+        _ => exr::prelude::write_rgb_file(path, width, height, |x, y| {
+            let t = texel(x, y)[0];
+            (t, t, t)
+        })?,
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Memory for transient graph resources, shared between resources whose lifetimes don't overlap
+
+use ash::vk;
+
+/// First and last position in the pass order a resource is used at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lifetime {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Lifetime {
+    pub fn overlaps(self, other: Lifetime) -> bool {
+        self.first <= other.last && other.first <= self.last
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Request {
+    pub resource: usize,
+    pub requirements: vk::MemoryRequirements,
+    pub lifetime: Lifetime,
+}
+
+/// One allocation and the resources bound to the start of it
+#[derive(Debug, Clone, Default)]
+pub(super) struct BlockPlan {
+    pub size: vk::DeviceSize,
+    pub type_bits: u32,
+    pub members: Vec<(usize, Lifetime)>,
+}
+
+/// Packs requests into as few blocks as possible, biggest first so small resources reuse the
+/// space of big ones
+pub(super) fn plan(
+    mut requests: Vec<Request>,
+    mem_props: &vk::PhysicalDeviceMemoryProperties,
+) -> Vec<BlockPlan> {
+    requests.sort_by_key(|request| std::cmp::Reverse(request.requirements.size));
+    let mut blocks: Vec<BlockPlan> = Vec::new();
+    for request in requests {
+        let type_bits = request.requirements.memory_type_bits;
+        let fits = |block: &BlockPlan| {
+            super::find_memory_type(
+                mem_props,
+                block.type_bits & type_bits,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )
+            .is_some()
+                && block
+                    .members
+                    .iter()
+                    .all(|(_, lifetime)| !lifetime.overlaps(request.lifetime))
+        };
+        match blocks.iter_mut().find(|block| fits(block)) {
+            Some(block) => {
+                block.size = block.size.max(request.requirements.size);
+                block.type_bits &= type_bits;
+                block.members.push((request.resource, request.lifetime));
+            }
+            None => blocks.push(BlockPlan {
+                size: request.requirements.size,
+                type_bits,
+                members: vec![(request.resource, request.lifetime)],
+            }),
+        }
+    }
+    blocks
+}
+
+/// How much aliasing saved over giving every transient its own allocation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AliasingStats {
+    pub resources: usize,
+    pub blocks: usize,
+    /// Sum of every transient's memory requirements
+    pub requested: vk::DeviceSize,
+    /// Memory actually allocated for the blocks
+    pub allocated: vk::DeviceSize,
+}
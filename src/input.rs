@@ -0,0 +1,476 @@
+//! Named actions and axes on top of winit events
+//!
+//! Controls are looked up by name ("move_forward", "look_x") instead of matching on keys, and
+//! [`Bindings`] maps those names to keys and mouse input. Bindings deserialize from config, keys
+//! by their winit [`KeyCode`] name, e.g.
+//!
+//! ```toml
+//! [input.actions]
+//! move_forward = ["KeyW", "ArrowUp"]
+//! grab = ["MouseRight"]
+//!
+//! [input.axes]
+//! look_x = [{ mouse = "x" }, { keys = ["ArrowLeft", "ArrowRight"] }]
+//! ```
+//!
+//! With the `gilrs` feature gamepad buttons and axes bind the same way, by their gilrs name
+//! prefixed with `Gamepad` (`GamepadSouth`) or as `{ gamepad = "LeftStickX" }`, see [`gamepad`].
+//! What actions and axes read each step can be recorded and played back with [`replay`].
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use glam::Vec2;
+use serde::{
+    de::{value::StrDeserializer, IntoDeserializer},
+    Deserialize,
+};
+use thiserror::Error;
+use winit::{
+    error::ExternalError,
+    event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent},
+    keyboard::{KeyCode, PhysicalKey},
+    window::{CursorGrabMode, Window},
+};
+
+use self::replay::InputSnapshot;
+
+#[cfg(feature = "gilrs")]
+pub mod gamepad;
+pub mod replay;
+
+/// Pixels of touchpad scrolling that count as one wheel step
+const PIXELS_PER_LINE: f32 = 20.0;
+
+#[derive(Debug, Error)]
+#[error("unknown key or button {0:?}")]
+pub struct UnknownInput(String);
+
+/// A key, mouse button or gamepad button
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Input {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Held while held on any connected gamepad
+    #[cfg(feature = "gilrs")]
+    Gamepad(gilrs::Button),
+}
+
+impl FromStr for Input {
+    type Err = UnknownInput;
+
+    /// A [`KeyCode`] name or `Mouse` followed by `Left`, `Right`, `Middle`, `Back`, `Forward` or
+    /// a button number, or `Gamepad` followed by a [`gilrs::Button`] name
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "gilrs")]
+        if let Some(button) = name.strip_prefix("Gamepad") {
+            return gilrs::Button::deserialize(str_deserializer(button))
+                .map(Input::Gamepad)
+                .map_err(|_| UnknownInput(name.to_owned()));
+        }
+        if let Some(button) = name.strip_prefix("Mouse") {
+            let button = match button {
+                "Left" => MouseButton::Left,
+                "Right" => MouseButton::Right,
+                "Middle" => MouseButton::Middle,
+                "Back" => MouseButton::Back,
+                "Forward" => MouseButton::Forward,
+                other => {
+                    MouseButton::Other(other.parse().map_err(|_| UnknownInput(name.to_owned()))?)
+                }
+            };
+            return Ok(Input::Mouse(button));
+        }
+        KeyCode::deserialize(str_deserializer(name))
+            .map(Input::Key)
+            .map_err(|_| UnknownInput(name.to_owned()))
+    }
+}
+
+/// Reads unit variants by name
+fn str_deserializer(name: &str) -> StrDeserializer<'_, serde::de::value::Error> {
+    name.into_deserializer()
+}
+
+impl TryFrom<String> for Input {
+    type Error = UnknownInput;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MouseAxis {
+    /// Cursor movement in pixels, right is positive
+    X,
+    /// Cursor movement in pixels, down is positive
+    Y,
+    /// Wheel steps, away from the user is positive
+    Wheel,
+}
+
+/// Something that drives an axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AxisBinding {
+    Mouse(MouseAxis),
+    /// Negative then positive, -1 or 1 while held and 0 while both or neither are
+    Keys([Input; 2]),
+    /// -1 to 1, from whichever connected gamepad pushes it furthest
+    #[cfg(feature = "gilrs")]
+    Gamepad(gilrs::Axis),
+}
+
+/// What each action and axis is bound to
+///
+/// Deserialized entries replace the default bindings of the same name, an empty list unbinds.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(from = "BindingOverrides")]
+pub struct Bindings {
+    pub actions: HashMap<String, Vec<Input>>,
+    pub axes: HashMap<String, Vec<AxisBinding>>,
+}
+
+impl Default for Bindings {
+    /// WASD movement with Space and Shift for up and down, mouse look, left drag to orbit and
+    /// middle drag to pan, C to switch cameras, arrow keys and Enter for UI navigation, Tab to
+    /// grab the cursor, Escape to quit, F12 to save the frames `--capture` kept and F11 to dump
+    /// the next frame's images with `--dump-graph`
+    ///
+    /// With gamepads the sticks move and turn as `move_x`/`move_y` and `turn_x`/`turn_y`, the
+    /// triggers move up and down and the D-pad, south and east buttons navigate.
+    fn default() -> Self {
+        let key = Input::Key;
+        #[allow(unused_mut)]
+        let mut actions = vec![
+            ("move_forward", vec![key(KeyCode::KeyW)]),
+            ("move_back", vec![key(KeyCode::KeyS)]),
+            ("move_left", vec![key(KeyCode::KeyA)]),
+            ("move_right", vec![key(KeyCode::KeyD)]),
+            ("move_up", vec![key(KeyCode::Space)]),
+            ("move_down", vec![key(KeyCode::ShiftLeft)]),
+            ("ui_up", vec![key(KeyCode::ArrowUp)]),
+            ("ui_down", vec![key(KeyCode::ArrowDown)]),
+            ("ui_left", vec![key(KeyCode::ArrowLeft)]),
+            ("ui_right", vec![key(KeyCode::ArrowRight)]),
+            ("ui_accept", vec![key(KeyCode::Enter)]),
+            ("ui_back", vec![key(KeyCode::Backspace)]),
+            ("orbit", vec![Input::Mouse(MouseButton::Left)]),
+            ("pan", vec![Input::Mouse(MouseButton::Middle)]),
+            ("toggle_camera", vec![key(KeyCode::KeyC)]),
+            ("toggle_grab", vec![key(KeyCode::Tab)]),
+            ("quit", vec![key(KeyCode::Escape)]),
+            ("dump_frames", vec![key(KeyCode::F12)]),
+            ("dump_graph", vec![key(KeyCode::F11)]),
+        ];
+        #[allow(unused_mut)]
+        let mut axes = vec![
+            ("look_x", vec![AxisBinding::Mouse(MouseAxis::X)]),
+            ("look_y", vec![AxisBinding::Mouse(MouseAxis::Y)]),
+            ("zoom", vec![AxisBinding::Mouse(MouseAxis::Wheel)]),
+        ];
+        #[cfg(feature = "gilrs")]
+        {
+            use gilrs::{Axis, Button};
+
+            let pad_buttons = [
+                ("move_up", Button::RightTrigger2),
+                ("move_down", Button::LeftTrigger2),
+                ("ui_up", Button::DPadUp),
+                ("ui_down", Button::DPadDown),
+                ("ui_left", Button::DPadLeft),
+                ("ui_right", Button::DPadRight),
+                ("ui_accept", Button::South),
+                ("ui_back", Button::East),
+            ];
+            for (name, button) in pad_buttons {
+                if let Some((_, inputs)) = actions.iter_mut().find(|(action, _)| *action == name) {
+                    inputs.push(Input::Gamepad(button));
+                }
+            }
+            axes.extend([
+                ("move_x", vec![AxisBinding::Gamepad(Axis::LeftStickX)]),
+                ("move_y", vec![AxisBinding::Gamepad(Axis::LeftStickY)]),
+                ("turn_x", vec![AxisBinding::Gamepad(Axis::RightStickX)]),
+                ("turn_y", vec![AxisBinding::Gamepad(Axis::RightStickY)]),
+            ]);
+        }
+        Bindings {
+            actions: actions
+                .into_iter()
+                .map(|(name, inputs)| (name.to_owned(), inputs))
+                .collect(),
+            axes: axes
+                .into_iter()
+                .map(|(name, bindings)| (name.to_owned(), bindings))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct BindingOverrides {
+    actions: HashMap<String, Vec<Input>>,
+    axes: HashMap<String, Vec<AxisBinding>>,
+}
+
+impl From<BindingOverrides> for Bindings {
+    fn from(overrides: BindingOverrides) -> Self {
+        let mut bindings = Bindings::default();
+        bindings.actions.extend(overrides.actions);
+        bindings.axes.extend(overrides.axes);
+        bindings
+    }
+}
+
+/// Input state gathered from events, queried through [`Bindings`]
+#[derive(Debug, Default)]
+pub struct InputMap {
+    bindings: Bindings,
+    held: HashSet<Input>,
+    pressed: HashSet<Input>,
+    released: HashSet<Input>,
+    cursor: Option<Vec2>,
+    mouse_delta: Vec2,
+    /// Unaccelerated motion from the device, unaffected by the window's edges
+    raw_delta: Vec2,
+    raw_motion: bool,
+    wheel: f32,
+    /// Buttons held per gamepad, an [`Input::Gamepad`] is held while any holds it
+    #[cfg(feature = "gilrs")]
+    pad_buttons: HashSet<(gilrs::GamepadId, gilrs::Button)>,
+    #[cfg(feature = "gilrs")]
+    pad_axes: HashMap<(gilrs::GamepadId, gilrs::Axis), f32>,
+    /// Answers queries instead of live input while set
+    replay: Option<InputSnapshot>,
+}
+
+impl InputMap {
+    pub fn new(bindings: Bindings) -> Self {
+        InputMap {
+            bindings,
+            ..Default::default()
+        }
+    }
+
+    pub fn bindings(&self) -> &Bindings {
+        &self.bindings
+    }
+
+    /// Swaps the bindings without forgetting what is held
+    pub fn set_bindings(&mut self, bindings: Bindings) {
+        self.bindings = bindings;
+    }
+
+    /// Records `event`, returning whether it was input
+    pub fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::KeyboardInput { event, .. } => {
+                let PhysicalKey::Code(code) = event.physical_key else {
+                    return true;
+                };
+                // Repeats would count as new presses
+                if !event.repeat {
+                    self.set_state(Input::Key(code), event.state);
+                }
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.set_state(Input::Mouse(*button), *state)
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = Vec2::new(position.x as f32, position.y as f32);
+                if let Some(last) = self.cursor {
+                    self.mouse_delta += position - last;
+                }
+                self.cursor = Some(position);
+            }
+            WindowEvent::CursorLeft { .. } => self.cursor = None,
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.wheel += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+                }
+            }
+            // Nothing is released while unfocused, so forget what was held. Not input itself, so
+            // the event is left for others too
+            WindowEvent::Focused(false) => {
+                self.released.extend(self.held.drain());
+                return false;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Records raw mouse motion, used instead of cursor movement with
+    /// [`InputMap::set_raw_motion`]
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = event {
+            self.raw_delta += Vec2::new(*x as f32, *y as f32);
+        }
+    }
+
+    /// Whether mouse axes follow raw device motion rather than the cursor, meant for while the
+    /// cursor is grabbed and stops at the window's edges or doesn't move at all
+    pub fn set_raw_motion(&mut self, enabled: bool) {
+        self.raw_motion = enabled;
+    }
+
+    fn mouse_motion(&self) -> Vec2 {
+        if self.raw_motion {
+            self.raw_delta
+        } else {
+            self.mouse_delta
+        }
+    }
+
+    fn set_state(&mut self, input: Input, state: ElementState) {
+        match state {
+            ElementState::Pressed => {
+                if self.held.insert(input) {
+                    self.pressed.insert(input);
+                }
+            }
+            ElementState::Released => {
+                if self.held.remove(&input) {
+                    self.released.insert(input);
+                }
+            }
+        }
+    }
+
+    /// Forgets this frame's presses, releases and movement, call once all are handled
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+        self.mouse_delta = Vec2::ZERO;
+        self.raw_delta = Vec2::ZERO;
+        self.wheel = 0.0;
+    }
+
+    fn inputs(&self, action: &str) -> impl Iterator<Item = &Input> {
+        self.bindings.actions.get(action).into_iter().flatten()
+    }
+
+    /// Whether any input of `action` is down, false for unbound actions
+    pub fn is_held(&self, action: &str) -> bool {
+        if let Some(replay) = &self.replay {
+            return replay.held.contains(action);
+        }
+        self.inputs(action).any(|input| self.held.contains(input))
+    }
+
+    /// Whether an input of `action` went down this frame
+    pub fn just_pressed(&self, action: &str) -> bool {
+        if let Some(replay) = &self.replay {
+            return replay.pressed.contains(action);
+        }
+        self.inputs(action)
+            .any(|input| self.pressed.contains(input))
+    }
+
+    /// Whether an input of `action` went up this frame
+    pub fn just_released(&self, action: &str) -> bool {
+        if let Some(replay) = &self.replay {
+            return replay.released.contains(action);
+        }
+        self.inputs(action)
+            .any(|input| self.released.contains(input))
+    }
+
+    /// Sum of everything bound to `axis` this frame, 0 for unbound axes
+    pub fn axis(&self, axis: &str) -> f32 {
+        if let Some(replay) = &self.replay {
+            return replay.axes.get(axis).copied().unwrap_or_default();
+        }
+        let Some(bindings) = self.bindings.axes.get(axis) else {
+            return 0.0;
+        };
+        bindings
+            .iter()
+            .map(|binding| match binding {
+                AxisBinding::Mouse(MouseAxis::X) => self.mouse_motion().x,
+                AxisBinding::Mouse(MouseAxis::Y) => self.mouse_motion().y,
+                AxisBinding::Mouse(MouseAxis::Wheel) => self.wheel,
+                AxisBinding::Keys([negative, positive]) => {
+                    self.held.contains(positive) as i32 as f32
+                        - self.held.contains(negative) as i32 as f32
+                }
+                #[cfg(feature = "gilrs")]
+                AxisBinding::Gamepad(axis) => self
+                    .pad_axes
+                    .iter()
+                    .filter(|((_, pad_axis), _)| pad_axis == axis)
+                    .map(|(_, &value)| value)
+                    .fold(0.0, |furthest: f32, value| {
+                        if value.abs() > furthest.abs() {
+                            value
+                        } else {
+                            furthest
+                        }
+                    }),
+            })
+            .sum()
+    }
+
+    /// -1 to 1 from a pair of actions, like [`AxisBinding::Keys`]
+    pub fn action_axis(&self, negative: &str, positive: &str) -> f32 {
+        self.is_held(positive) as i32 as f32 - self.is_held(negative) as i32 as f32
+    }
+
+    /// Every bound action and axis as queries see them now
+    pub fn snapshot(&self) -> InputSnapshot {
+        let actions = self.bindings.actions.keys();
+        let filter = |query: fn(&Self, &str) -> bool| {
+            actions
+                .clone()
+                .filter(|action| query(self, action))
+                .cloned()
+                .collect()
+        };
+        InputSnapshot {
+            held: filter(Self::is_held),
+            pressed: filter(Self::just_pressed),
+            released: filter(Self::just_released),
+            axes: self
+                .bindings
+                .axes
+                .keys()
+                .map(|axis| (axis.clone(), self.axis(axis)))
+                .filter(|&(_, value)| value != 0.0)
+                .collect(),
+        }
+    }
+
+    /// Answers queries from `snapshot` instead of live input, until set back to `None`
+    pub fn set_replay(&mut self, snapshot: Option<InputSnapshot>) {
+        self.replay = snapshot;
+    }
+}
+
+/// Hides the cursor and keeps it in the window, locked in place where the platform allows and
+/// confined to the window otherwise, returning the mode it got
+pub fn grab_cursor(window: &Window) -> Result<CursorGrabMode, ExternalError> {
+    let mode = window
+        .set_cursor_grab(CursorGrabMode::Locked)
+        .map(|_| CursorGrabMode::Locked)
+        .or_else(|_| {
+            window
+                .set_cursor_grab(CursorGrabMode::Confined)
+                .map(|_| CursorGrabMode::Confined)
+        })?;
+    window.set_cursor_visible(false);
+    Ok(mode)
+}
+
+/// Undoes [`grab_cursor`]
+pub fn release_cursor(window: &Window) -> Result<(), ExternalError> {
+    window.set_cursor_visible(true);
+    window.set_cursor_grab(CursorGrabMode::None)
+}
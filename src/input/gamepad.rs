@@ -0,0 +1,85 @@
+//! Gamepads read through gilrs into the same actions and axes as keyboard and mouse
+//!
+//! gilrs has no window events to piggyback on, so [`Gamepads::poll`] drains its queue once per
+//! frame. Pads can come and go at any time, whatever a disconnected pad held is released.
+
+use gilrs::{EventType, GamepadId, Gilrs};
+use winit::event::ElementState;
+
+use super::{Input, InputMap};
+
+/// A gamepad appearing or going away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hotplug {
+    Connected { id: GamepadId, name: String },
+    Disconnected { id: GamepadId },
+}
+
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    pub fn new() -> Result<Self, Box<gilrs::Error>> {
+        Ok(Gamepads {
+            gilrs: Gilrs::new().map_err(Box::new)?,
+        })
+    }
+
+    /// Pads already connected before the first poll
+    pub fn connected(&self) -> impl Iterator<Item = (GamepadId, String)> + '_ {
+        self.gilrs
+            .gamepads()
+            .map(|(id, pad)| (id, pad.name().to_owned()))
+    }
+
+    /// Feeds everything that happened since the last poll into `input`
+    pub fn poll(&mut self, input: &mut InputMap) -> Vec<Hotplug> {
+        let mut hotplugs = Vec::new();
+        while let Some(event) = self.gilrs.next_event() {
+            let id = event.id;
+            match event.event {
+                EventType::ButtonPressed(button, _) => {
+                    input.pad_buttons.insert((id, button));
+                    input.set_state(Input::Gamepad(button), ElementState::Pressed);
+                }
+                EventType::ButtonReleased(button, _) => {
+                    input.pad_buttons.remove(&(id, button));
+                    input.release_gamepad_button(button);
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    input.pad_axes.insert((id, axis), value);
+                }
+                EventType::Connected => hotplugs.push(Hotplug::Connected {
+                    id,
+                    name: self.gilrs.gamepad(id).name().to_owned(),
+                }),
+                EventType::Disconnected => {
+                    let buttons: Vec<_> = input
+                        .pad_buttons
+                        .iter()
+                        .filter(|(pad, _)| *pad == id)
+                        .copied()
+                        .collect();
+                    for (pad, button) in buttons {
+                        input.pad_buttons.remove(&(pad, button));
+                        input.release_gamepad_button(button);
+                    }
+                    input.pad_axes.retain(|(pad, _), _| *pad != id);
+                    hotplugs.push(Hotplug::Disconnected { id });
+                }
+                _ => {}
+            }
+        }
+        hotplugs
+    }
+}
+
+impl InputMap {
+    /// Releases `button` unless another pad still holds it
+    fn release_gamepad_button(&mut self, button: gilrs::Button) {
+        if !self.pad_buttons.iter().any(|&(_, held)| held == button) {
+            self.set_state(Input::Gamepad(button), ElementState::Released);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Input recorded step by step and played back, so runs can be repeated exactly
+//!
+//! Snapshots hold actions and axes by name rather than raw events, so a recording doesn't depend
+//! on the bindings or devices it was made with. Played back with one snapshot per simulation step,
+//! a fixed seed and [`crate::timestep::FixedTimestep::with_lockstep`], two runs simulate the same
+//! states and so render the same frames. The seed only helps if every randomized system is created
+//! with it, e.g. through [`crate::particles::ParticleConfig::with_seed`] and
+//! [`crate::vegetation::ScatterParams::with_seed`].
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::timestep::FixedTimestep;
+
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Parse(#[from] ron::error::SpannedError),
+    #[error(transparent)]
+    Write(#[from] ron::Error),
+    #[error("recorded update rate {0} is out of range")]
+    UpdateRate(f64),
+}
+
+/// Everything [`super::InputMap`] answers for one step
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSnapshot {
+    pub held: BTreeSet<String>,
+    pub pressed: BTreeSet<String>,
+    pub released: BTreeSet<String>,
+    /// Nonzero axes only
+    pub axes: BTreeMap<String, f32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputRecording {
+    /// Seed the run was made with, to be reused on replay
+    pub seed: u64,
+    /// Simulation steps per second, replaying at another rate wouldn't repeat the run
+    pub update_rate: f64,
+    pub steps: Vec<InputSnapshot>,
+}
+
+impl InputRecording {
+    pub fn new(seed: u64, update_rate: f64) -> Self {
+        InputRecording {
+            seed,
+            update_rate,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let recording: InputRecording = ron::from_str(&fs::read_to_string(path)?)?;
+        if !FixedTimestep::RATES.contains(&recording.update_rate) {
+            return Err(ReplayError::UpdateRate(recording.update_rate));
+        }
+        Ok(recording)
+    }
+
+    /// Written as RON, a step per line
+    pub fn save(&self, path: &Path) -> Result<(), ReplayError> {
+        let config = ron::ser::PrettyConfig::new().depth_limit(2);
+        Ok(fs::write(path, ron::ser::to_string_pretty(self, config)?)?)
+    }
+}
@@ -0,0 +1,186 @@
+//! Instance layer selection
+//!
+//! Layers are requested by full name or by the end of it, so `api_dump` finds
+//! `VK_LAYER_LUNARG_api_dump`. Requests come from the caller and from [`LAYERS_ENV`], and ones no
+//! installed layer matches are reported rather than failing instance creation. The validation
+//! layer's slower checks are switched on with [`ValidationFeatures`].
+
+use std::ffi::{c_char, CStr, CString};
+
+use ash::{prelude::VkResult, vk, Entry};
+use thiserror::Error;
+
+pub mod messenger;
+
+/// Environment variable listing extra layers, separated by commas, colons or semicolons
+pub const LAYERS_ENV: &str = "VULKAN_THING_LAYERS";
+
+#[derive(Debug, Clone)]
+pub struct LayerInfo {
+    pub name: CString,
+    pub description: String,
+    pub spec_version: u32,
+    pub implementation_version: u32,
+}
+
+impl LayerInfo {
+    /// Whether `request` is this layer's name, or its last `_` separated parts
+    pub fn matches(&self, request: &str) -> bool {
+        let name = self.name.to_string_lossy().to_lowercase();
+        let request = request.to_lowercase();
+        name == request || name.ends_with(&format!("_{request}"))
+    }
+}
+
+/// Every layer the loader can find
+pub fn available_layers(entry: &Entry) -> VkResult<Vec<LayerInfo>> {
+    Ok(entry
+        .enumerate_instance_layer_properties()?
+        .iter()
+        .map(|props| unsafe {
+            LayerInfo {
+                name: CStr::from_ptr(props.layer_name.as_ptr()).to_owned(),
+                description: CStr::from_ptr(props.description.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+                spec_version: props.spec_version,
+                implementation_version: props.implementation_version,
+            }
+        })
+        .collect())
+}
+
+/// Layers asked for, resolved against the installed ones with [`LayerSelection::resolve`]
+#[derive(Debug, Clone, Default)]
+pub struct LayerSelection {
+    pub requested: Vec<String>,
+}
+
+impl LayerSelection {
+    pub fn with_layers<S: Into<String>>(mut self, layers: impl IntoIterator<Item = S>) -> Self {
+        self.requested.extend(layers.into_iter().map(Into::into));
+        self
+    }
+
+    /// Adds the layers listed in [`LAYERS_ENV`]
+    pub fn with_env(self) -> Self {
+        let listed = std::env::var(LAYERS_ENV).unwrap_or_default();
+        let layers: Vec<String> = listed
+            .split([',', ':', ';'])
+            .map(str::trim)
+            .filter(|layer| !layer.is_empty())
+            .map(str::to_owned)
+            .collect();
+        self.with_layers(layers)
+    }
+
+    pub fn resolve(&self, entry: &Entry) -> VkResult<Layers> {
+        let available = available_layers(entry)?;
+        let mut layers = Layers::default();
+        for request in &self.requested {
+            match available.iter().find(|layer| layer.matches(request)) {
+                Some(layer) if !layers.enabled.contains(&layer.name) => {
+                    layers.enabled.push(layer.name.clone())
+                }
+                Some(_) => {}
+                None => layers.missing.push(request.clone()),
+            }
+        }
+        Ok(layers)
+    }
+}
+
+/// Outcome of a [`LayerSelection`]
+#[derive(Debug, Clone, Default)]
+pub struct Layers {
+    pub enabled: Vec<CString>,
+    /// Requests no installed layer matched
+    pub missing: Vec<String>,
+}
+
+impl Layers {
+    /// For `InstanceCreateInfo::enabled_layer_names`, valid while `self` is
+    pub fn names(&self) -> Vec<*const c_char> {
+        self.enabled.iter().map(|name| name.as_ptr()).collect()
+    }
+
+    pub fn is_enabled(&self, name: &CStr) -> bool {
+        self.enabled.iter().any(|layer| layer.as_c_str() == name)
+    }
+}
+
+/// Khronos validation, which [`ValidationFeatures`] configure
+pub const VALIDATION_LAYER: &CStr = c"VK_LAYER_KHRONOS_validation";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("unknown validation feature {0:?}, expected gpu, sync, best-practices or printf")]
+pub struct UnknownValidationFeature(String);
+
+/// Validation beyond the layer's default checks, through `VK_EXT_validation_features`
+///
+/// Each slows things down noticeably, GPU-assisted validation most, so they're picked per run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ValidationFeatures {
+    /// Instruments shaders to catch out of bounds descriptor indexing and buffer accesses
+    pub gpu_assisted: bool,
+    /// Reports missing or wrong barriers and other hazards
+    pub synchronization: bool,
+    /// Warns about valid but slow or non-portable usage
+    pub best_practices: bool,
+    /// Routes shader `debugPrintfEXT` to the debug messenger, exclusive with `gpu_assisted`
+    pub debug_printf: bool,
+}
+
+impl ValidationFeatures {
+    /// Extension providing these, exposed by the validation layer itself
+    pub fn extension_name() -> &'static CStr {
+        vk::ExtValidationFeaturesFn::name()
+    }
+
+    /// Parses a list such as `gpu,sync,best-practices`, separated by commas
+    pub fn parse(list: &str) -> Result<Self, UnknownValidationFeature> {
+        let mut features = ValidationFeatures::default();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name.to_lowercase().as_str() {
+                "gpu" | "gpu-assisted" => features.gpu_assisted = true,
+                "sync" | "synchronization" => features.synchronization = true,
+                "best-practices" | "best_practices" => features.best_practices = true,
+                "printf" | "debug-printf" => features.debug_printf = true,
+                _ => return Err(UnknownValidationFeature(name.to_owned())),
+            }
+        }
+        Ok(features)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// For `ValidationFeaturesEXT::enabled_validation_features`
+    ///
+    /// Debug printf and GPU-assisted validation can't run together, printf is dropped if both
+    /// are asked for.
+    pub fn enables(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut enables = Vec::new();
+        if self.gpu_assisted {
+            enables.extend([
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED,
+                // Leaves the last descriptor set binding free for the instrumentation
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT,
+            ]);
+        } else if self.debug_printf {
+            enables.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+        }
+        if self.synchronization {
+            enables.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        if self.best_practices {
+            enables.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
+        enables
+    }
+}
@@ -0,0 +1,211 @@
+//! Validation messages caught by a debug messenger and kept for a report
+//!
+//! Meant for automated runs: a [`ValidationLog`] gathers every warning and error the layers
+//! report, to be written out and turned into a failing exit code once the run is over, rather
+//! than scrolling past in the console.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ash::{extensions::ext::DebugUtils, prelude::VkResult, vk, Entry, Instance};
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ReportError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ValidationMessage {
+    pub severity: Severity,
+    /// E.g. `"validation"` or `"performance"`
+    pub kind: String,
+    /// The VUID for validation errors
+    pub id_name: String,
+    pub id_number: i32,
+    pub message: String,
+}
+
+/// Messages collected from a [`DebugMessenger`], shared with the callback
+#[derive(Debug, Default)]
+pub struct ValidationLog {
+    messages: Mutex<Vec<ValidationMessage>>,
+}
+
+impl ValidationLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, message: ValidationMessage) {
+        self.messages
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(message);
+    }
+
+    pub fn messages(&self) -> Vec<ValidationMessage> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.messages
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .filter(|message| message.severity == severity)
+            .count()
+    }
+
+    /// A message per paragraph, the way the layer prints them
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for message in self.messages() {
+            let severity = match message.severity {
+                Severity::Warning => "WARNING",
+                Severity::Error => "ERROR",
+            };
+            let _ = writeln!(
+                text,
+                "{severity} [{}] {} ({:#x})\n{}\n",
+                message.kind, message.id_name, message.id_number, message.message
+            );
+        }
+        text
+    }
+
+    /// Writes the messages as JSON for a `.json` extension, text otherwise
+    ///
+    /// The file is written even when there are no messages, so a report always exists.
+    pub fn save(&self, path: &Path) -> Result<(), ReportError> {
+        let text = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_string_pretty(&self.messages())?
+        } else {
+            self.to_text()
+        };
+        Ok(fs::write(path, text)?)
+    }
+}
+
+/// A `VK_EXT_debug_utils` messenger passing warnings and errors to a [`ValidationLog`]
+pub struct DebugMessenger {
+    debug_utils: DebugUtils,
+    messenger: vk::DebugUtilsMessengerEXT,
+    /// Kept alive for as long as the callback may be called
+    _log: Arc<ValidationLog>,
+}
+
+impl DebugMessenger {
+    /// The instance extension needed
+    pub fn extension_name() -> &'static CStr {
+        DebugUtils::name()
+    }
+
+    /// Also chained into `InstanceCreateInfo` so instance creation and destruction are covered,
+    /// `log` has to outlive the instance for that
+    pub fn create_info(log: &Arc<ValidationLog>) -> vk::DebugUtilsMessengerCreateInfoEXT {
+        vk::DebugUtilsMessengerCreateInfoEXT::builder()
+            .message_severity(
+                vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                    | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            )
+            .message_type(
+                vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                    | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                    | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+            )
+            .pfn_user_callback(Some(callback))
+            .user_data(Arc::as_ptr(log) as *mut c_void)
+            .build()
+    }
+
+    /// Needs [`DebugMessenger::extension_name`] enabled on `instance`
+    pub fn new(entry: &Entry, instance: &Instance, log: Arc<ValidationLog>) -> VkResult<Self> {
+        let debug_utils = DebugUtils::new(entry, instance);
+        let info = Self::create_info(&log);
+        let messenger = unsafe { debug_utils.create_debug_utils_messenger(&info, None)? };
+        Ok(DebugMessenger {
+            debug_utils,
+            messenger,
+            _log: log,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// Must be called before the instance is destroyed.
+    pub unsafe fn destroy(&self) {
+        self.debug_utils
+            .destroy_debug_utils_messenger(self.messenger, None);
+    }
+}
+
+unsafe extern "system" fn callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    types: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    user_data: *mut c_void,
+) -> vk::Bool32 {
+    let (Some(data), Some(log)) = (data.as_ref(), user_data.cast::<ValidationLog>().as_ref())
+    else {
+        return vk::FALSE;
+    };
+    let text = |ptr: *const c_char| {
+        if ptr.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(ptr).to_string_lossy().into_owned()
+        }
+    };
+    let severity = if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    let kind = if types.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION) {
+        "validation"
+    } else if types.contains(vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE) {
+        "performance"
+    } else {
+        "general"
+    };
+    log.push(ValidationMessage {
+        severity,
+        kind: kind.to_owned(),
+        id_name: text(data.p_message_id_name),
+        id_number: data.message_id_number,
+        message: text(data.p_message),
+    });
+    // Never abort the call that triggered the message
+    vk::FALSE
+}
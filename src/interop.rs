@@ -0,0 +1,627 @@
+//! Sharing images, buffers and semaphores with other APIs through external memory
+//!
+//! An [`Exporter`] creates images and buffers whose memory, and semaphores whose payload, can be
+//! exported as OS handles: file descriptors with `VK_KHR_external_memory_fd` and
+//! `VK_KHR_external_semaphore_fd`, NT handles with the `_win32` variants. OpenGL imports them with
+//! `GL_EXT_memory_object` and `GL_EXT_semaphore`, CUDA as described by [`cuda`], media APIs
+//! through their own Vulkan interop, so data reaches them without a copy. Only opaque handles are
+//! used, which importers can only open on the same device and driver, checked against
+//! [`Exporter::device_uuid`] and [`Exporter::driver_uuid`]. On Unix, [`share`] passes them on to
+//! other processes.
+
+use std::ffi::CStr;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, OwnedFd};
+#[cfg(windows)]
+use std::os::windows::io::{FromRawHandle, OwnedHandle};
+
+#[cfg(unix)]
+use ash::extensions::khr::{ExternalMemoryFd, ExternalSemaphoreFd};
+#[cfg(windows)]
+use ash::extensions::khr::{ExternalMemoryWin32, ExternalSemaphoreWin32};
+use ash::{prelude::VkResult, vk, Device, Instance};
+use thiserror::Error;
+
+use crate::{device::EnabledFeatures, leaks, memory::find_memory_type, trace::traced};
+
+pub mod cuda;
+#[cfg(unix)]
+pub mod share;
+
+/// Device extensions needed on this platform, on top of Vulkan 1.1
+#[cfg(unix)]
+pub const EXTERNAL_EXTENSIONS: [&CStr; 2] = [ExternalMemoryFd::name(), ExternalSemaphoreFd::name()];
+#[cfg(windows)]
+pub const EXTERNAL_EXTENSIONS: [&CStr; 2] =
+    [ExternalMemoryWin32::name(), ExternalSemaphoreWin32::name()];
+
+#[cfg(unix)]
+pub const MEMORY_HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags =
+    vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD;
+#[cfg(windows)]
+pub const MEMORY_HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags =
+    vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32;
+
+#[cfg(unix)]
+pub const SEMAPHORE_HANDLE_TYPE: vk::ExternalSemaphoreHandleTypeFlags =
+    vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD;
+#[cfg(windows)]
+pub const SEMAPHORE_HANDLE_TYPE: vk::ExternalSemaphoreHandleTypeFlags =
+    vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_WIN32;
+
+/// An exported handle, owned by the caller until given to the importing API
+#[cfg(unix)]
+pub type ExternalHandle = OwnedFd;
+#[cfg(windows)]
+pub type ExternalHandle = OwnedHandle;
+
+#[derive(Debug, Error)]
+pub enum InteropError {
+    #[error("the device can't export {format:?} images with {usage:?} usage")]
+    UnsupportedImage {
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+    },
+    #[error("the device can't export buffers with {0:?} usage")]
+    UnsupportedBuffer(vk::BufferUsageFlags),
+    #[error("the device can't export semaphores of that type")]
+    UnsupportedSemaphore,
+    #[error("no memory type fits the exported image")]
+    NoMemoryType,
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+enum Backend {
+    #[cfg(unix)]
+    Fd {
+        memory: ExternalMemoryFd,
+        semaphore: ExternalSemaphoreFd,
+    },
+    #[cfg(windows)]
+    Win32 {
+        memory: ExternalMemoryWin32,
+        semaphore: ExternalSemaphoreWin32,
+    },
+}
+
+pub struct Exporter {
+    backend: Backend,
+    physical_device: vk::PhysicalDevice,
+    device_uuid: [u8; vk::UUID_SIZE],
+    driver_uuid: [u8; vk::UUID_SIZE],
+}
+
+impl Exporter {
+    /// `None` unless the device runs Vulkan 1.1 with [`EXTERNAL_EXTENSIONS`] enabled
+    pub fn new(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        enabled: &EnabledFeatures,
+    ) -> Option<Self> {
+        if enabled.features.api_version < vk::API_VERSION_1_1
+            || !EXTERNAL_EXTENSIONS
+                .iter()
+                .all(|&name| enabled.has_extension(name))
+        {
+            return None;
+        }
+        #[cfg(unix)]
+        let backend = Backend::Fd {
+            memory: ExternalMemoryFd::new(instance, device),
+            semaphore: ExternalSemaphoreFd::new(instance, device),
+        };
+        #[cfg(windows)]
+        let backend = Backend::Win32 {
+            memory: ExternalMemoryWin32::new(instance, device),
+            semaphore: ExternalSemaphoreWin32::new(instance, device),
+        };
+
+        let mut id_props = vk::PhysicalDeviceIDProperties::default();
+        let mut props = vk::PhysicalDeviceProperties2::builder().push_next(&mut id_props);
+        unsafe { instance.get_physical_device_properties2(physical_device, &mut props) };
+        Some(Exporter {
+            backend,
+            physical_device,
+            device_uuid: id_props.device_uuid,
+            driver_uuid: id_props.driver_uuid,
+        })
+    }
+
+    /// For importers to check they're on the same device, e.g. against `GL_DEVICE_UUID_EXT`
+    pub fn device_uuid(&self) -> [u8; vk::UUID_SIZE] {
+        self.device_uuid
+    }
+
+    /// For importers to check they're on the same driver, e.g. against `GL_DRIVER_UUID_EXT`
+    pub fn driver_uuid(&self) -> [u8; vk::UUID_SIZE] {
+        self.driver_uuid
+    }
+
+    /// Whether optimal tiling `format` images with `usage` can be exported
+    pub fn supports_image(
+        &self,
+        instance: &Instance,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+    ) -> bool {
+        let mut external_info =
+            vk::PhysicalDeviceExternalImageFormatInfo::builder().handle_type(MEMORY_HANDLE_TYPE);
+        let format_info = vk::PhysicalDeviceImageFormatInfo2::builder()
+            .format(format)
+            .ty(vk::ImageType::TYPE_2D)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .push_next(&mut external_info);
+        let mut external_props = vk::ExternalImageFormatProperties::default();
+        let mut props = vk::ImageFormatProperties2::builder().push_next(&mut external_props);
+        let supported = unsafe {
+            instance.get_physical_device_image_format_properties2(
+                self.physical_device,
+                &format_info,
+                &mut props,
+            )
+        };
+        supported.is_ok()
+            && external_props
+                .external_memory_properties
+                .external_memory_features
+                .contains(vk::ExternalMemoryFeatureFlags::EXPORTABLE)
+    }
+
+    pub fn supports_semaphores(&self, instance: &Instance) -> bool {
+        self.supports_semaphore_type(instance, vk::SemaphoreType::BINARY)
+    }
+
+    /// Timeline semaphores also need [`Feature::TimelineSemaphore`] enabled
+    ///
+    /// [`Feature::TimelineSemaphore`]: crate::device::Feature::TimelineSemaphore
+    pub fn supports_timeline_semaphores(&self, instance: &Instance) -> bool {
+        self.supports_semaphore_type(instance, vk::SemaphoreType::TIMELINE)
+    }
+
+    fn supports_semaphore_type(&self, instance: &Instance, ty: vk::SemaphoreType) -> bool {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::builder().semaphore_type(ty);
+        let info = vk::PhysicalDeviceExternalSemaphoreInfo::builder()
+            .handle_type(SEMAPHORE_HANDLE_TYPE)
+            .push_next(&mut type_info);
+        let mut props = vk::ExternalSemaphoreProperties::default();
+        unsafe {
+            instance.get_physical_device_external_semaphore_properties(
+                self.physical_device,
+                &info,
+                &mut props,
+            )
+        };
+        props
+            .external_semaphore_features
+            .contains(vk::ExternalSemaphoreFeatureFlags::EXPORTABLE)
+    }
+
+    /// A 2D image with one level and layer whose memory can be exported
+    ///
+    /// The memory is always a dedicated allocation, whether or not the driver needs one, so
+    /// OpenGL importers always set `GL_DEDICATED_MEMORY_OBJECT_EXT`.
+    #[track_caller]
+    pub fn create_image(
+        &self,
+        instance: &Instance,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
+    ) -> Result<ExportableImage, InteropError> {
+        if !self.supports_image(instance, format, usage) {
+            return Err(InteropError::UnsupportedImage { format, usage });
+        }
+        let mut external_info =
+            vk::ExternalMemoryImageCreateInfo::builder().handle_types(MEMORY_HANDLE_TYPE);
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .push_next(&mut external_info);
+        let image = unsafe { device.create_image(&image_info, None)? };
+
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { device.destroy_image(image, None) };
+            return Err(InteropError::NoMemoryType);
+        };
+        let mut export_info =
+            vk::ExportMemoryAllocateInfo::builder().handle_types(MEMORY_HANDLE_TYPE);
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().image(image);
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index)
+            .push_next(&mut export_info)
+            .push_next(&mut dedicated_info);
+        let memory = unsafe {
+            device
+                .allocate_memory(&alloc_info, None)
+                .and_then(|memory| {
+                    device
+                        .bind_image_memory(image, memory, 0)
+                        .map(|_| memory)
+                        .inspect_err(|_| device.free_memory(memory, None))
+                })
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.destroy_image(image, None) };
+                return Err(err.into());
+            }
+        };
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        let view = match unsafe { device.create_image_view(&view_info, None) } {
+            Ok(view) => view,
+            Err(err) => {
+                unsafe {
+                    device.destroy_image(image, None);
+                    device.free_memory(memory, None);
+                }
+                return Err(err.into());
+            }
+        };
+
+        leaks::track(image, "exportable image");
+        leaks::track(memory, "exportable image memory");
+        leaks::track(view, "exportable image view");
+        Ok(ExportableImage {
+            image,
+            view,
+            memory,
+            allocation_size: requirements.size,
+            memory_type_index: type_index,
+            format,
+            extent,
+        })
+    }
+
+    /// Whether buffers with `usage` can be exported, and if so whether they need a dedicated
+    /// allocation
+    pub fn buffer_support(&self, instance: &Instance, usage: vk::BufferUsageFlags) -> Option<bool> {
+        let info = vk::PhysicalDeviceExternalBufferInfo::builder()
+            .usage(usage)
+            .handle_type(MEMORY_HANDLE_TYPE);
+        let mut props = vk::ExternalBufferProperties::default();
+        unsafe {
+            instance.get_physical_device_external_buffer_properties(
+                self.physical_device,
+                &info,
+                &mut props,
+            )
+        };
+        let features = props.external_memory_properties.external_memory_features;
+        features
+            .contains(vk::ExternalMemoryFeatureFlags::EXPORTABLE)
+            .then(|| features.contains(vk::ExternalMemoryFeatureFlags::DEDICATED_ONLY))
+    }
+
+    /// A device local buffer whose memory can be exported
+    ///
+    /// The memory is a dedicated allocation only where the driver needs one, importers are told
+    /// by [`ExportableBuffer::is_dedicated`].
+    #[track_caller]
+    pub fn create_buffer(
+        &self,
+        instance: &Instance,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<ExportableBuffer, InteropError> {
+        let Some(dedicated) = self.buffer_support(instance, usage) else {
+            return Err(InteropError::UnsupportedBuffer(usage));
+        };
+        let mut external_info =
+            vk::ExternalMemoryBufferCreateInfo::builder().handle_types(MEMORY_HANDLE_TYPE);
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .push_next(&mut external_info);
+        let buffer = unsafe { device.create_buffer(&buffer_info, None)? };
+
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { device.destroy_buffer(buffer, None) };
+            return Err(InteropError::NoMemoryType);
+        };
+        let mut export_info =
+            vk::ExportMemoryAllocateInfo::builder().handle_types(MEMORY_HANDLE_TYPE);
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder().buffer(buffer);
+        let mut alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index)
+            .push_next(&mut export_info);
+        if dedicated {
+            alloc_info = alloc_info.push_next(&mut dedicated_info);
+        }
+        let memory = unsafe {
+            device
+                .allocate_memory(&alloc_info, None)
+                .and_then(|memory| {
+                    device
+                        .bind_buffer_memory(buffer, memory, 0)
+                        .map(|_| memory)
+                        .inspect_err(|_| device.free_memory(memory, None))
+                })
+        };
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                return Err(err.into());
+            }
+        };
+
+        leaks::track(buffer, "exportable buffer");
+        leaks::track(memory, "exportable buffer memory");
+        Ok(ExportableBuffer {
+            buffer,
+            memory,
+            size,
+            allocation_size: requirements.size,
+            dedicated,
+        })
+    }
+
+    /// A binary semaphore whose payload can be exported, signalled when a frame is ready for the
+    /// importer and waited on before it's rendered over again
+    #[track_caller]
+    pub fn create_semaphore(
+        &self,
+        instance: &Instance,
+        device: &Device,
+    ) -> Result<ExportableSemaphore, InteropError> {
+        if !self.supports_semaphores(instance) {
+            return Err(InteropError::UnsupportedSemaphore);
+        }
+        self.create_semaphore_of_type(device, vk::SemaphoreType::BINARY, 0)
+    }
+
+    /// A timeline semaphore whose payload can be exported, starting at `initial_value`
+    ///
+    /// Both sides signal and wait on increasing values, so work can ping-pong between APIs
+    /// without a semaphore per hand-off.
+    #[track_caller]
+    pub fn create_timeline_semaphore(
+        &self,
+        instance: &Instance,
+        device: &Device,
+        initial_value: u64,
+    ) -> Result<ExportableSemaphore, InteropError> {
+        if !self.supports_timeline_semaphores(instance) {
+            return Err(InteropError::UnsupportedSemaphore);
+        }
+        self.create_semaphore_of_type(device, vk::SemaphoreType::TIMELINE, initial_value)
+    }
+
+    #[track_caller]
+    fn create_semaphore_of_type(
+        &self,
+        device: &Device,
+        ty: vk::SemaphoreType,
+        initial_value: u64,
+    ) -> Result<ExportableSemaphore, InteropError> {
+        let mut export_info =
+            vk::ExportSemaphoreCreateInfo::builder().handle_types(SEMAPHORE_HANDLE_TYPE);
+        let mut type_info = vk::SemaphoreTypeCreateInfo::builder()
+            .semaphore_type(ty)
+            .initial_value(initial_value);
+        let mut info = vk::SemaphoreCreateInfo::builder().push_next(&mut export_info);
+        // Without timeline semaphores enabled the type struct isn't allowed, even for binary
+        if ty == vk::SemaphoreType::TIMELINE {
+            info = info.push_next(&mut type_info);
+        }
+        let semaphore = unsafe { device.create_semaphore(&info, None)? };
+        leaks::track(semaphore, "exportable semaphore");
+        Ok(ExportableSemaphore {
+            semaphore,
+            timeline: ty == vk::SemaphoreType::TIMELINE,
+        })
+    }
+
+    /// A new handle to `image`'s memory, each call gives one to be closed independently
+    pub fn export_memory(&self, image: &ExportableImage) -> VkResult<ExternalHandle> {
+        self.export_device_memory(image.memory)
+    }
+
+    /// A new handle to `buffer`'s memory
+    pub fn export_buffer_memory(&self, buffer: &ExportableBuffer) -> VkResult<ExternalHandle> {
+        self.export_device_memory(buffer.memory)
+    }
+
+    fn export_device_memory(&self, device_memory: vk::DeviceMemory) -> VkResult<ExternalHandle> {
+        match &self.backend {
+            #[cfg(unix)]
+            Backend::Fd { memory, .. } => {
+                let info = vk::MemoryGetFdInfoKHR::builder()
+                    .memory(device_memory)
+                    .handle_type(MEMORY_HANDLE_TYPE);
+                let fd = unsafe {
+                    traced!(
+                        "vkGetMemoryFdKHR",
+                        memory = device_memory;
+                        memory.get_memory_fd(&info)
+                    )?
+                };
+                // The call hands ownership of the descriptor to the application
+                Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+            }
+            #[cfg(windows)]
+            Backend::Win32 { memory, .. } => {
+                let info = vk::MemoryGetWin32HandleInfoKHR::builder()
+                    .memory(device_memory)
+                    .handle_type(MEMORY_HANDLE_TYPE);
+                let handle = unsafe {
+                    traced!(
+                        "vkGetMemoryWin32HandleKHR",
+                        memory = device_memory;
+                        memory.get_memory_win32_handle(&info)
+                    )?
+                };
+                Ok(unsafe { OwnedHandle::from_raw_handle(handle) })
+            }
+        }
+    }
+
+    /// A new handle to `semaphore`'s payload
+    pub fn export_semaphore(&self, semaphore: &ExportableSemaphore) -> VkResult<ExternalHandle> {
+        match &self.backend {
+            #[cfg(unix)]
+            Backend::Fd {
+                semaphore: semaphore_fd,
+                ..
+            } => {
+                let info = vk::SemaphoreGetFdInfoKHR::builder()
+                    .semaphore(semaphore.semaphore)
+                    .handle_type(SEMAPHORE_HANDLE_TYPE);
+                let fd = unsafe {
+                    traced!(
+                        "vkGetSemaphoreFdKHR",
+                        semaphore = semaphore.semaphore;
+                        semaphore_fd.get_semaphore_fd(&info)
+                    )?
+                };
+                Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+            }
+            #[cfg(windows)]
+            Backend::Win32 {
+                semaphore: semaphore_win32,
+                ..
+            } => {
+                let info = vk::SemaphoreGetWin32HandleInfoKHR::builder()
+                    .semaphore(semaphore.semaphore)
+                    .handle_type(SEMAPHORE_HANDLE_TYPE);
+                let handle = unsafe {
+                    traced!(
+                        "vkGetSemaphoreWin32HandleKHR",
+                        semaphore = semaphore.semaphore;
+                        semaphore_win32.get_semaphore_win32_handle(&info)
+                    )?
+                };
+                Ok(unsafe { OwnedHandle::from_raw_handle(handle) })
+            }
+        }
+    }
+}
+
+/// An image backed by exportable memory, with a view for rendering to or sampling it
+pub struct ExportableImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+    memory_type_index: u32,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl ExportableImage {
+    /// What importers need besides the handle, e.g. for `glImportMemoryFdEXT`
+    pub fn allocation_size(&self) -> vk::DeviceSize {
+        self.allocation_size
+    }
+
+    pub fn memory_type_index(&self) -> u32 {
+        self.memory_type_index
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with the image. Importers keep the memory alive through their own
+    /// references.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.view);
+        leaks::untrack(self.image);
+        leaks::untrack(self.memory);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+/// A buffer backed by exportable memory
+pub struct ExportableBuffer {
+    pub buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    pub size: vk::DeviceSize,
+    allocation_size: vk::DeviceSize,
+    dedicated: bool,
+}
+
+impl ExportableBuffer {
+    /// The size of the exported memory, at least [`ExportableBuffer::size`]
+    pub fn allocation_size(&self) -> vk::DeviceSize {
+        self.allocation_size
+    }
+
+    /// Whether the memory is a dedicated allocation, which importers have to be told
+    pub fn is_dedicated(&self) -> bool {
+        self.dedicated
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with the buffer.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.buffer);
+        leaks::untrack(self.memory);
+        device.destroy_buffer(self.buffer, None);
+        device.free_memory(self.memory, None);
+    }
+}
+
+pub struct ExportableSemaphore {
+    pub semaphore: vk::Semaphore,
+    timeline: bool,
+}
+
+impl ExportableSemaphore {
+    pub fn is_timeline(&self) -> bool {
+        self.timeline
+    }
+
+    /// # Safety
+    ///
+    /// No pending submission may use the semaphore.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.semaphore);
+        device.destroy_semaphore(self.semaphore, None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Exported buffers and semaphores described the way CUDA imports them
+//!
+//! The crate doesn't link CUDA. [`CudaMemoryDesc`] and [`CudaSemaphoreDesc`] carry what goes into
+//! `cudaExternalMemoryHandleDesc` and `cudaExternalSemaphoreHandleDesc`, with the runtime API's
+//! enum values, so a simulation written against CUDA imports the memory with
+//! `cudaImportExternalMemory`, maps it with `cudaExternalMemoryGetMappedBuffer` and synchronizes
+//! through `cudaSignalExternalSemaphoresAsync` and `cudaWaitExternalSemaphoresAsync`. The CUDA
+//! device to import on is the one whose `cudaDeviceProp::uuid` is [`Exporter::device_uuid`].
+//!
+//! CUDA sees the buffers as plain memory laid out like C structs, which std430 storage blocks and
+//! tightly packed vertices match. std140 padding has to be mirrored on the CUDA side.
+
+use ash::{prelude::VkResult, vk};
+
+use super::{ExportableBuffer, ExportableSemaphore, Exporter, ExternalHandle};
+
+/// `cudaExternalMemoryDedicated`
+pub const CUDA_EXTERNAL_MEMORY_DEDICATED: u32 = 0x1;
+
+/// Usage for buffers written by CUDA and drawn from or read by shaders
+pub const CUDA_BUFFER_USAGE: vk::BufferUsageFlags = vk::BufferUsageFlags::from_raw(
+    vk::BufferUsageFlags::VERTEX_BUFFER.as_raw()
+        | vk::BufferUsageFlags::INDEX_BUFFER.as_raw()
+        | vk::BufferUsageFlags::STORAGE_BUFFER.as_raw()
+        | vk::BufferUsageFlags::TRANSFER_SRC.as_raw()
+        | vk::BufferUsageFlags::TRANSFER_DST.as_raw(),
+);
+
+/// `cudaExternalMemoryHandleType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum CudaMemoryHandleType {
+    OpaqueFd = 1,
+    OpaqueWin32 = 2,
+}
+
+/// `cudaExternalSemaphoreHandleType`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum CudaSemaphoreHandleType {
+    OpaqueFd = 1,
+    OpaqueWin32 = 2,
+    TimelineSemaphoreFd = 9,
+    TimelineSemaphoreWin32 = 10,
+}
+
+/// The fields of `cudaExternalMemoryHandleDesc` and the region to map with
+/// `cudaExternalMemoryBufferDesc`
+///
+/// CUDA takes ownership of a file descriptor once imported, so it's passed with `into_raw_fd`.
+/// NT handles stay the caller's to close.
+#[derive(Debug)]
+pub struct CudaMemoryDesc {
+    pub handle_type: CudaMemoryHandleType,
+    pub handle: ExternalHandle,
+    /// The whole allocation, which CUDA needs to match exactly
+    pub size: u64,
+    pub flags: u32,
+    /// The buffer's offset and size within the memory, what `cudaExternalMemoryGetMappedBuffer`
+    /// maps
+    pub buffer_offset: u64,
+    pub buffer_size: u64,
+}
+
+/// The fields of `cudaExternalSemaphoreHandleDesc`, ownership as for [`CudaMemoryDesc`]
+#[derive(Debug)]
+pub struct CudaSemaphoreDesc {
+    pub handle_type: CudaSemaphoreHandleType,
+    pub handle: ExternalHandle,
+}
+
+/// Exports `buffer`'s memory for `cudaImportExternalMemory`
+pub fn export_buffer(exporter: &Exporter, buffer: &ExportableBuffer) -> VkResult<CudaMemoryDesc> {
+    #[cfg(unix)]
+    let handle_type = CudaMemoryHandleType::OpaqueFd;
+    #[cfg(windows)]
+    let handle_type = CudaMemoryHandleType::OpaqueWin32;
+    Ok(CudaMemoryDesc {
+        handle_type,
+        handle: exporter.export_buffer_memory(buffer)?,
+        size: buffer.allocation_size(),
+        flags: if buffer.is_dedicated() {
+            CUDA_EXTERNAL_MEMORY_DEDICATED
+        } else {
+            0
+        },
+        buffer_offset: 0,
+        buffer_size: buffer.size,
+    })
+}
+
+/// Exports `semaphore` for `cudaImportExternalSemaphore`
+///
+/// Timeline semaphores are signalled and waited on with values on both sides, binary ones have
+/// every signal matched by exactly one wait.
+pub fn export_semaphore(
+    exporter: &Exporter,
+    semaphore: &ExportableSemaphore,
+) -> VkResult<CudaSemaphoreDesc> {
+    #[cfg(unix)]
+    let handle_type = if semaphore.is_timeline() {
+        CudaSemaphoreHandleType::TimelineSemaphoreFd
+    } else {
+        CudaSemaphoreHandleType::OpaqueFd
+    };
+    #[cfg(windows)]
+    let handle_type = if semaphore.is_timeline() {
+        CudaSemaphoreHandleType::TimelineSemaphoreWin32
+    } else {
+        CudaSemaphoreHandleType::OpaqueWin32
+    };
+    Ok(CudaSemaphoreDesc {
+        handle_type,
+        handle: exporter.export_semaphore(semaphore)?,
+    })
+}
@@ -0,0 +1,708 @@
+//! Handing rendered frames to another process over a Unix socket
+//!
+//! A [`FrameSharer`] listens on a socket path for one client at a time, a compositor or recorder,
+//! and shares a few exported images with it. The protocol is JSON lines:
+//!
+//! - On connecting the client gets a [`Message::Hello`] describing the images, with a memory and
+//!   a semaphore descriptor per slot attached as `SCM_RIGHTS`, in slot order.
+//! - Each shared frame is announced with [`Message::Frame`] once the copy into its slot has been
+//!   submitted. The client imports each slot's semaphore once and waits on it for every frame.
+//! - The client answers [`Message::Release`] once it's done reading the slot, which isn't written
+//!   again until then. Frames are dropped rather than waited for while every slot is held.
+//!
+//! [`FrameReceiver`] is the client side, for tools written against this crate.
+
+use std::{
+    ffi::c_void,
+    io::{self, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    ptr,
+    time::Duration,
+};
+
+use ash::{vk, Device, Instance};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{ExportableImage, ExportableSemaphore, Exporter, InteropError};
+
+/// A client slower than this to take a message is dropped rather than stalling rendering
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Ample for the hello, which is the only message carrying descriptors
+const MAX_MESSAGE: usize = 4096;
+
+const COLOR_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+#[derive(Debug, Error)]
+pub enum ShareError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Interop(#[from] InteropError),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("expected a hello with {expected} descriptors, got {got}")]
+    BadHello { expected: usize, got: usize },
+    #[error("expected a hello, got {0:?}")]
+    Unexpected(Message),
+    #[error("the sharer closed the connection")]
+    Closed,
+    #[error("{0} exists and isn't a socket")]
+    NotASocket(PathBuf),
+}
+
+/// What an importer needs to know about the shared images
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedImageInfo {
+    /// Raw `VkFormat`
+    pub format: i32,
+    pub width: u32,
+    pub height: u32,
+    pub allocation_size: u64,
+    pub memory_type_index: u32,
+    pub device_uuid: [u8; vk::UUID_SIZE],
+    pub driver_uuid: [u8; vk::UUID_SIZE],
+    pub slots: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello(SharedImageInfo),
+    /// Slot `slot` holds frame `frame` once its semaphore is signalled, in `GENERAL` layout and
+    /// released to `VK_QUEUE_FAMILY_EXTERNAL`
+    Frame {
+        slot: u32,
+        frame: u64,
+    },
+    Release {
+        slot: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    /// A copy was recorded and will signal the semaphore, the frame isn't announced yet
+    Recorded,
+    Held,
+    /// The semaphore was signalled for a client that went away, nothing will wait on it
+    Stale,
+}
+
+struct Slot {
+    image: ExportableImage,
+    semaphore: ExportableSemaphore,
+    state: SlotState,
+}
+
+struct Client {
+    stream: UnixStream,
+    /// Received bytes short of a full line
+    pending: Vec<u8>,
+}
+
+/// The rendering side, sharing frames with whichever client is connected
+pub struct FrameSharer {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<Client>,
+    slots: Vec<Slot>,
+    info: SharedImageInfo,
+    queue_family: u32,
+    frame: u64,
+}
+
+impl FrameSharer {
+    /// Listens on `path`, replacing a socket left there, with `slots` images of `format` and
+    /// `extent` for frames recorded on `queue_family`
+    #[allow(clippy::too_many_arguments)]
+    pub fn bind(
+        path: &Path,
+        exporter: &Exporter,
+        instance: &Instance,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        slots: u32,
+        queue_family: u32,
+    ) -> Result<Self, ShareError> {
+        // Whatever else is there is left alone, a mistyped path shouldn't cost a file
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(ShareError::NotASocket(path.to_owned())),
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        let mut sharer = FrameSharer {
+            listener,
+            path: path.to_owned(),
+            client: None,
+            slots: Vec::new(),
+            info: SharedImageInfo {
+                format: format.as_raw(),
+                width: extent.width,
+                height: extent.height,
+                allocation_size: 0,
+                memory_type_index: 0,
+                device_uuid: exporter.device_uuid(),
+                driver_uuid: exporter.driver_uuid(),
+                slots,
+            },
+            queue_family,
+            frame: 0,
+        };
+        let usage = vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED;
+        for _ in 0..slots {
+            let slot = exporter
+                .create_image(instance, device, mem_props, format, extent, usage)
+                .and_then(|image| match exporter.create_semaphore(instance, device) {
+                    Ok(semaphore) => Ok((image, semaphore)),
+                    Err(err) => {
+                        unsafe { image.destroy(device) };
+                        Err(err)
+                    }
+                });
+            match slot {
+                Ok((image, semaphore)) => sharer.slots.push(Slot {
+                    image,
+                    semaphore,
+                    state: SlotState::Free,
+                }),
+                Err(err) => {
+                    unsafe { sharer.destroy(device) };
+                    return Err(err.into());
+                }
+            }
+        }
+        // Every image has the same requirements
+        if let Some(slot) = sharer.slots.first() {
+            sharer.info.allocation_size = slot.image.allocation_size();
+            sharer.info.memory_type_index = slot.image.memory_type_index();
+        }
+        Ok(sharer)
+    }
+
+    pub fn info(&self) -> &SharedImageInfo {
+        &self.info
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Accepts a waiting client and takes in released slots, called once a frame
+    ///
+    /// A client leaving with frames it never waited on costs a `vkDeviceWaitIdle`, to replace
+    /// semaphores left signalled.
+    pub fn poll(
+        &mut self,
+        exporter: &Exporter,
+        instance: &Instance,
+        device: &Device,
+    ) -> Result<(), ShareError> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.greet(exporter, stream)?,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if let Some(client) = &mut self.client {
+            match read_available(&client.stream, &mut client.pending) {
+                Ok(open) => {
+                    let released: Vec<_> = take_lines(&mut client.pending)
+                        .into_iter()
+                        .filter_map(|line| serde_json::from_slice::<Message>(&line).ok())
+                        .filter_map(|message| match message {
+                            Message::Release { slot } => Some(slot as usize),
+                            _ => None,
+                        })
+                        .collect();
+                    for slot in released {
+                        if let Some(slot) = self.slots.get_mut(slot) {
+                            if slot.state == SlotState::Held {
+                                slot.state = SlotState::Free;
+                            }
+                        }
+                    }
+                    if !open {
+                        self.disconnect();
+                    }
+                }
+                Err(_) => self.disconnect(),
+            }
+        }
+        self.replace_stale(exporter, instance, device)
+    }
+
+    /// Sends the hello, new descriptors are exported for every client
+    fn greet(&mut self, exporter: &Exporter, stream: UnixStream) -> Result<(), ShareError> {
+        let mut fds = Vec::with_capacity(self.slots.len() * 2);
+        for slot in &self.slots {
+            fds.push(exporter.export_memory(&slot.image)?);
+            fds.push(exporter.export_semaphore(&slot.semaphore)?);
+        }
+        let mut line = serde_json::to_vec(&Message::Hello(self.info.clone()))?;
+        line.push(b'\n');
+        let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        // Some platforms pass the listener's non-blocking mode on, writes are meant to block
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        // A client gone before the hello is simply not kept, ours are closed once sent
+        if send_with_fds(&stream, &line, &raw).is_ok() {
+            self.client = Some(Client {
+                stream,
+                pending: Vec::new(),
+            });
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        for slot in &mut self.slots {
+            if matches!(slot.state, SlotState::Held | SlotState::Recorded) {
+                slot.state = SlotState::Stale;
+            }
+        }
+    }
+
+    fn replace_stale(
+        &mut self,
+        exporter: &Exporter,
+        instance: &Instance,
+        device: &Device,
+    ) -> Result<(), ShareError> {
+        // Slots recorded this frame still go through publish, which marks them stale
+        if !self.slots.iter().any(|slot| slot.state == SlotState::Stale) {
+            return Ok(());
+        }
+        unsafe { device.device_wait_idle()? };
+        for slot in &mut self.slots {
+            if slot.state == SlotState::Stale {
+                let semaphore = exporter.create_semaphore(instance, device)?;
+                unsafe { mem::replace(&mut slot.semaphore, semaphore).destroy(device) };
+                slot.state = SlotState::Free;
+            }
+        }
+        Ok(())
+    }
+
+    /// A free slot to copy this frame into, `None` to skip sharing it
+    pub fn acquire(&self) -> Option<usize> {
+        self.client.as_ref()?;
+        self.slots
+            .iter()
+            .position(|slot| slot.state == SlotState::Free)
+    }
+
+    /// Semaphore the submission with [`FrameSharer::record_copy`] for `slot` has to signal
+    pub fn semaphore(&self, slot: usize) -> vk::Semaphore {
+        self.slots[slot].semaphore.semaphore
+    }
+
+    /// Blits `image`, `extent` in size, into `slot`, recorded after the last pass writing it
+    ///
+    /// The image needs `TRANSFER_SRC` usage and is left in `layout`, which it must be in already.
+    pub fn record_copy(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        slot: usize,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+        extent: vk::Extent2D,
+    ) {
+        let shared = self.slots[slot].image.image;
+        let src_to_transfer = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(layout)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(COLOR_RANGE);
+        // The previous frame is discarded, its contents needn't be acquired back from the client
+        let dst_to_transfer = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(shared)
+            .subresource_range(COLOR_RANGE);
+        let src_back = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(COLOR_RANGE);
+        let dst_release = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(self.queue_family)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_EXTERNAL)
+            .image(shared)
+            .subresource_range(COLOR_RANGE);
+        let corner = |extent: vk::Extent2D| vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: 1,
+        };
+        let region = vk::ImageBlit {
+            src_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            src_offsets: [vk::Offset3D::default(), corner(extent)],
+            dst_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            dst_offsets: [
+                vk::Offset3D::default(),
+                corner(self.slots[slot].image.extent),
+            ],
+        };
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*src_to_transfer, *dst_to_transfer],
+            );
+            device.cmd_blit_image(
+                cmd,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                shared,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+                vk::Filter::LINEAR,
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*src_back, *dst_release],
+            );
+        }
+        self.slots[slot].state = SlotState::Recorded;
+    }
+
+    /// Announces `slot`'s frame, once the submission signalling its semaphore has been made
+    pub fn publish(&mut self, slot: usize) {
+        self.frame += 1;
+        let message = Message::Frame {
+            slot: slot as u32,
+            frame: self.frame,
+        };
+        let sent = self.client.as_mut().map(|client| {
+            let mut line = serde_json::to_vec(&message).expect("messages serialize");
+            line.push(b'\n');
+            client.stream.write_all(&line)
+        });
+        match sent {
+            Some(Ok(())) => self.slots[slot].state = SlotState::Held,
+            Some(Err(_)) => {
+                self.slots[slot].state = SlotState::Held;
+                self.disconnect();
+            }
+            None => self.slots[slot].state = SlotState::Stale,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded copy may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.client = None;
+        for slot in self.slots.drain(..) {
+            slot.image.destroy(device);
+            slot.semaphore.destroy(device);
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Descriptors for one slot, owned by the receiver until imported
+#[derive(Debug)]
+pub struct SlotHandles {
+    pub memory: OwnedFd,
+    pub semaphore: OwnedFd,
+}
+
+/// The client side of a [`FrameSharer`]
+pub struct FrameReceiver {
+    stream: UnixStream,
+    pending: Vec<u8>,
+    pub info: SharedImageInfo,
+    /// Taken out to be imported, e.g. with `glImportMemoryFdEXT` and `glImportSemaphoreFdEXT`
+    pub handles: Vec<SlotHandles>,
+}
+
+impl FrameReceiver {
+    /// Connects and waits for the hello
+    pub fn connect(path: &Path) -> Result<Self, ShareError> {
+        let stream = UnixStream::connect(path)?;
+        let mut pending = Vec::new();
+        let mut fds = Vec::new();
+        let hello = loop {
+            let mut buf = [0; MAX_MESSAGE];
+            let (read, received) = recv_with_fds(&stream, &mut buf)?;
+            if read == 0 {
+                return Err(ShareError::Closed);
+            }
+            pending.extend_from_slice(&buf[..read]);
+            fds.extend(received);
+            if let Some(line) = take_lines(&mut pending).into_iter().next() {
+                break serde_json::from_slice::<Message>(&line)?;
+            }
+        };
+        let Message::Hello(info) = hello else {
+            return Err(ShareError::Unexpected(hello));
+        };
+        let expected = info.slots as usize * 2;
+        if fds.len() != expected {
+            return Err(ShareError::BadHello {
+                expected,
+                got: fds.len(),
+            });
+        }
+        let mut fds = fds.into_iter();
+        let handles = (0..info.slots)
+            .map(|_| SlotHandles {
+                memory: fds.next().unwrap(),
+                semaphore: fds.next().unwrap(),
+            })
+            .collect();
+        Ok(FrameReceiver {
+            stream,
+            pending,
+            info,
+            handles,
+        })
+    }
+
+    /// Blocks for the next frame, giving its slot and number
+    ///
+    /// The slot's semaphore has to be waited on before reading it.
+    pub fn next_frame(&mut self) -> Result<(usize, u64), ShareError> {
+        loop {
+            for line in take_lines(&mut self.pending) {
+                if let Message::Frame { slot, frame } = serde_json::from_slice(&line)? {
+                    return Ok((slot as usize, frame));
+                }
+            }
+            let mut buf = [0; MAX_MESSAGE];
+            let read = io::Read::read(&mut self.stream, &mut buf)?;
+            if read == 0 {
+                return Err(ShareError::Closed);
+            }
+            self.pending.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    /// Hands `slot` back, once every read of it has completed
+    pub fn release(&mut self, slot: usize) -> Result<(), ShareError> {
+        let mut line = serde_json::to_vec(&Message::Release { slot: slot as u32 })?;
+        line.push(b'\n');
+        self.stream.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Removes the complete lines from `pending`
+fn take_lines(pending: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    let end = pending
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |end| end + 1);
+    let complete: Vec<u8> = pending.drain(..end).collect();
+    complete
+        .split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect()
+}
+
+/// Reads whatever has arrived without blocking, `false` once the peer has closed
+fn read_available(stream: &UnixStream, pending: &mut Vec<u8>) -> io::Result<bool> {
+    let mut buf = [0u8; MAX_MESSAGE];
+    loop {
+        let read = unsafe {
+            libc::recv(
+                stream.as_raw_fd(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                libc::MSG_DONTWAIT,
+            )
+        };
+        match read {
+            0 => return Ok(false),
+            read if read > 0 => pending.extend_from_slice(&buf[..read as usize]),
+            _ => {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(true),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(err),
+                };
+            }
+        }
+    }
+}
+
+/// Sends `bytes` with `fds` attached to the first of them
+fn send_with_fds(stream: &UnixStream, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = mem::size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut c_void,
+        iov_len: bytes.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The descriptors went with the first byte, the rest is plain data
+    (&*stream).write_all(&bytes[sent as usize..])
+}
+
+/// Receives into `buf`, taking ownership of any descriptors that came along
+fn recv_with_fds(stream: &UnixStream, buf: &mut [u8]) -> io::Result<(usize, Vec<OwnedFd>)> {
+    // Room for the two descriptors of far more slots than anyone shares
+    let mut control =
+        vec![0u8; unsafe { libc::CMSG_SPACE(64 * mem::size_of::<RawFd>() as u32) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+    let read = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok((read as usize, fds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn take_lines_leaves_a_partial_line() {
+        let mut pending = b"first\nsecond\n\nthi".to_vec();
+        assert_eq!(
+            take_lines(&mut pending),
+            [b"first".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(pending, b"thi");
+
+        assert!(take_lines(&mut pending).is_empty());
+        pending.extend_from_slice(b"rd\n");
+        assert_eq!(take_lines(&mut pending), [b"third".to_vec()]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn descriptors_arrive_with_their_message() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let (mut near, far) = UnixStream::pair().unwrap();
+        send_with_fds(&sender, b"hello\n", &[far.as_raw_fd()]).unwrap();
+        drop(far);
+
+        let mut buf = [0u8; 16];
+        let (read, fds) = recv_with_fds(&receiver, &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"hello\n");
+        assert_eq!(fds.len(), 1);
+
+        // The received descriptor is the other end of `near`
+        let mut far = UnixStream::from(fds.into_iter().next().unwrap());
+        far.write_all(b"ping").unwrap();
+        let mut ping = [0u8; 4];
+        near.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+    }
+
+    #[test]
+    fn messages_without_descriptors_bring_none() {
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        (&sender).write_all(b"{}\n").unwrap();
+        let mut buf = [0u8; 16];
+        let (read, fds) = recv_with_fds(&receiver, &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"{}\n");
+        assert!(fds.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+//! std140/std430 layout rules, checked against Rust struct layouts at compile time
+//!
+//! `#[derive(ShaderLayout)]` with `#[layout(std140)]` or `#[layout(std430)]` fails to compile
+//! when a field is not at the offset GLSL would put it, e.g. a float following a `vec3` in std140.
+
+use std::{ffi::c_void, mem, ptr, slice};
+
+pub use vulkan_thing_derive::{AsBytes, ShaderLayout};
+
+pub mod std140;
+
+/// Base alignment and size of a type under both GLSL block layouts
+pub trait ShaderLayout {
+    const STD140_ALIGN: usize;
+    const STD140_SIZE: usize;
+    const STD430_ALIGN: usize;
+    const STD430_SIZE: usize;
+    /// False for the [`std140`] wrappers whose padding std430 doesn't have, which std430 blocks
+    /// refuse
+    const STD430_COMPATIBLE: bool = true;
+}
+
+/// Plain data whose every byte is initialized, so it can be copied into mapped buffers as is
+///
+/// # Safety
+///
+/// The type must have no implicit padding and contain no pointers or references.
+/// `#[derive(AsBytes)]` checks this for structs.
+pub unsafe trait AsBytes: Copy {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((self as *const Self).cast(), mem::size_of::<Self>()) }
+    }
+
+    /// Copies the value into host visible memory
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `size_of::<Self>()` bytes, e.g. a mapped buffer range.
+    unsafe fn write_to(&self, dst: *mut c_void) {
+        ptr::copy_nonoverlapping(self.as_bytes().as_ptr(), dst.cast(), mem::size_of::<Self>());
+    }
+}
+
+pub fn slice_as_bytes<T: AsBytes>(values: &[T]) -> &[u8] {
+    unsafe { slice::from_raw_parts(values.as_ptr().cast(), mem::size_of_val(values)) }
+}
+
+macro_rules! as_bytes {
+    ( $($ty:ty),* $(,)? ) => {
+        $(unsafe impl AsBytes for $ty {})*
+    };
+}
+
+as_bytes! {
+    u8, i8, u16, i16, u32, i32, u64, i64, f32, f64,
+    glam::Vec2, glam::Vec3, glam::Vec4,
+    glam::IVec2, glam::IVec3, glam::IVec4,
+    glam::UVec2, glam::UVec3, glam::UVec4,
+    glam::Mat2, glam::Mat3, glam::Mat4,
+}
+
+unsafe impl<T: AsBytes, const N: usize> AsBytes for [T; N] {}
+
+#[doc(hidden)]
+pub mod __private {
+    pub const fn round_up(value: usize, align: usize) -> usize {
+        value.div_ceil(align) * align
+    }
+
+    /// Alignment of a struct from its fields' `(align, size)`, std140 rounds it up to a vec4
+    pub const fn struct_align(fields: &[(usize, usize)], std140: bool) -> usize {
+        let mut align = 1;
+        let mut i = 0;
+        while i < fields.len() {
+            if fields[i].0 > align {
+                align = fields[i].0;
+            }
+            i += 1;
+        }
+        if std140 {
+            round_up(align, 16)
+        } else {
+            align
+        }
+    }
+
+    pub const fn field_offset(fields: &[(usize, usize)], index: usize) -> usize {
+        let mut offset = 0;
+        let mut i = 0;
+        while i <= index {
+            offset = round_up(offset, fields[i].0);
+            if i < index {
+                offset += fields[i].1;
+            }
+            i += 1;
+        }
+        offset
+    }
+
+    pub const fn struct_size(fields: &[(usize, usize)], std140: bool) -> usize {
+        if fields.is_empty() {
+            return 0;
+        }
+        let last = fields.len() - 1;
+        round_up(
+            field_offset(fields, last) + fields[last].1,
+            struct_align(fields, std140),
+        )
+    }
+}
+
+macro_rules! shader_layout {
+    ( $($ty:ty => ($align140:expr, $size140:expr, $align430:expr, $size430:expr)),* $(,)? ) => {
+        $(
+            impl ShaderLayout for $ty {
+                const STD140_ALIGN: usize = $align140;
+                const STD140_SIZE: usize = $size140;
+                const STD430_ALIGN: usize = $align430;
+                const STD430_SIZE: usize = $size430;
+            }
+        )*
+    };
+}
+
+// glam's `Mat2` and `Mat3` pack their columns tighter than GLSL strides them, so only the
+// padded wrappers in [`std140`] describe those
+shader_layout! {
+    f32 => (4, 4, 4, 4),
+    i32 => (4, 4, 4, 4),
+    u32 => (4, 4, 4, 4),
+    f64 => (8, 8, 8, 8),
+    glam::Vec2 => (8, 8, 8, 8),
+    glam::Vec3 => (16, 12, 16, 12),
+    glam::Vec3A => (16, 12, 16, 12),
+    glam::Vec4 => (16, 16, 16, 16),
+    glam::IVec2 => (8, 8, 8, 8),
+    glam::IVec3 => (16, 12, 16, 12),
+    glam::IVec4 => (16, 16, 16, 16),
+    glam::UVec2 => (8, 8, 8, 8),
+    glam::UVec3 => (16, 12, 16, 12),
+    glam::UVec4 => (16, 16, 16, 16),
+    glam::Mat4 => (16, 64, 16, 64),
+}
+
+#[cfg(feature = "mint")]
+shader_layout! {
+    mint::Vector2<f32> => (8, 8, 8, 8),
+    mint::Vector3<f32> => (16, 12, 16, 12),
+    mint::Vector4<f32> => (16, 16, 16, 16),
+    mint::ColumnMatrix4<f32> => (16, 64, 16, 64),
+}
+
+/// Arrays follow the GLSL array rules, std140 pads every element to a vec4
+impl<T: ShaderLayout, const N: usize> ShaderLayout for [T; N] {
+    const STD140_ALIGN: usize = __private::round_up(T::STD140_ALIGN, 16);
+    const STD140_SIZE: usize = __private::round_up(T::STD140_SIZE, Self::STD140_ALIGN) * N;
+    const STD430_ALIGN: usize = T::STD430_ALIGN;
+    const STD430_SIZE: usize = __private::round_up(T::STD430_SIZE, T::STD430_ALIGN) * N;
+    const STD430_COMPATIBLE: bool = T::STD430_COMPATIBLE;
+}
@@ -0,0 +1,282 @@
+//! Wrapper types that already carry the padding std140 expects
+//!
+//! Those whose padding std430 leaves out, such as [`Std140Mat2`] or a [`Std140Array`] of scalars,
+//! fail to compile in a `#[layout(std430)]` block.
+
+use std::{
+    mem,
+    ops::{Index, IndexMut},
+};
+
+use glam::{Mat2, Mat3, Mat4, Vec2, Vec3, Vec4};
+
+use super::{AsBytes, ShaderLayout};
+
+/// A `vec3` padded out to 16 bytes, so the following member starts on a vec4 boundary
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C, align(16))]
+pub struct Std140Vec3 {
+    pub value: Vec3,
+    _pad: u32,
+}
+
+impl From<Vec3> for Std140Vec3 {
+    fn from(value: Vec3) -> Self {
+        Std140Vec3 { value, _pad: 0 }
+    }
+}
+
+/// A `mat2` with each column padded to a vec4
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C, align(16))]
+pub struct Std140Mat2 {
+    pub cols: [[f32; 4]; 2],
+}
+
+impl From<Mat2> for Std140Mat2 {
+    fn from(value: Mat2) -> Self {
+        let [x, y] = value.to_cols_array_2d();
+        Std140Mat2 {
+            cols: [[x[0], x[1], 0., 0.], [y[0], y[1], 0., 0.]],
+        }
+    }
+}
+
+/// A `mat3` with each column padded to a vec4
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C, align(16))]
+pub struct Std140Mat3 {
+    pub cols: [Std140Vec3; 3],
+}
+
+impl From<Mat3> for Std140Mat3 {
+    fn from(value: Mat3) -> Self {
+        Std140Mat3 {
+            cols: [
+                value.x_axis.into(),
+                value.y_axis.into(),
+                value.z_axis.into(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[repr(C, align(16))]
+pub struct Std140Mat4 {
+    pub cols: [[f32; 4]; 4],
+}
+
+impl From<Mat4> for Std140Mat4 {
+    fn from(value: Mat4) -> Self {
+        Std140Mat4 {
+            cols: value.to_cols_array_2d(),
+        }
+    }
+}
+
+/// A scalar or small vector padded to 16 bytes, the element type of std140 arrays
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C, align(16))]
+pub struct Std140Padded<T: Copy, const PAD: usize> {
+    pub value: T,
+    _pad: [u32; PAD],
+}
+
+impl<T: Copy, const PAD: usize> Std140Padded<T, PAD> {
+    pub fn new(value: T) -> Self {
+        Std140Padded {
+            value,
+            _pad: [0; PAD],
+        }
+    }
+}
+
+impl<T: Copy + Default, const PAD: usize> Default for Std140Padded<T, PAD> {
+    fn default() -> Self {
+        Std140Padded::new(T::default())
+    }
+}
+
+/// Types that can be stored in a [`Std140Array`], mapped to their padded element
+pub trait Std140Element: Copy {
+    type Padded: Copy + Default + AsBytes + From<Self>;
+}
+
+impl<T: Copy, const PAD: usize> From<T> for Std140Padded<T, PAD> {
+    fn from(value: T) -> Self {
+        Std140Padded::new(value)
+    }
+}
+
+macro_rules! std140_element {
+    ( $($ty:ty => $padded:ty),* $(,)? ) => {
+        $(
+            impl Std140Element for $ty {
+                type Padded = $padded;
+            }
+        )*
+    };
+}
+
+std140_element! {
+    f32 => Std140Padded<f32, 3>,
+    i32 => Std140Padded<i32, 3>,
+    u32 => Std140Padded<u32, 3>,
+    Vec2 => Std140Padded<Vec2, 2>,
+    Vec3 => Std140Vec3,
+    Vec4 => Vec4,
+    Mat4 => Mat4,
+}
+
+/// A GLSL array laid out with std140's 16 byte element stride
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct Std140Array<T: Std140Element, const N: usize> {
+    elements: [T::Padded; N],
+}
+
+impl<T: Std140Element, const N: usize> Default for Std140Array<T, N> {
+    fn default() -> Self {
+        Std140Array {
+            elements: [T::Padded::default(); N],
+        }
+    }
+}
+
+impl<T: Std140Element, const N: usize> From<[T; N]> for Std140Array<T, N> {
+    fn from(values: [T; N]) -> Self {
+        Std140Array {
+            elements: values.map(Into::into),
+        }
+    }
+}
+
+impl<T: Std140Element, const N: usize> Std140Array<T, N> {
+    pub fn set(&mut self, index: usize, value: T) {
+        self.elements[index] = value.into();
+    }
+}
+
+impl<T: Std140Element, const N: usize> Index<usize> for Std140Array<T, N> {
+    type Output = T::Padded;
+
+    fn index(&self, index: usize) -> &T::Padded {
+        &self.elements[index]
+    }
+}
+
+impl<T: Std140Element, const N: usize> IndexMut<usize> for Std140Array<T, N> {
+    fn index_mut(&mut self, index: usize) -> &mut T::Padded {
+        &mut self.elements[index]
+    }
+}
+
+macro_rules! wrapper_layout {
+    ( $($ty:ty => $glsl:ty, $compatible:expr);* $(;)? ) => {
+        $(
+            impl ShaderLayout for $ty {
+                const STD140_ALIGN: usize = <$glsl>::STD140_ALIGN;
+                const STD140_SIZE: usize = <$glsl>::STD140_SIZE;
+                const STD430_ALIGN: usize = <$glsl>::STD430_ALIGN;
+                const STD430_SIZE: usize = <$glsl>::STD430_SIZE;
+                const STD430_COMPATIBLE: bool = $compatible;
+            }
+        )*
+    };
+}
+
+// std430 packs a scalar into the last four bytes of a `vec3`, which the padding takes up
+wrapper_layout! {
+    Std140Vec3 => Vec3, false;
+    Std140Mat4 => Mat4, true;
+}
+
+// Column strides of GLSL's `mat2` and `mat3`, which glam's own types don't have
+impl ShaderLayout for Std140Mat2 {
+    const STD140_ALIGN: usize = 16;
+    const STD140_SIZE: usize = 32;
+    const STD430_ALIGN: usize = 8;
+    const STD430_SIZE: usize = 16;
+    const STD430_COMPATIBLE: bool = false;
+}
+
+impl ShaderLayout for Std140Mat3 {
+    const STD140_ALIGN: usize = 16;
+    const STD140_SIZE: usize = 48;
+    const STD430_ALIGN: usize = 16;
+    const STD430_SIZE: usize = 48;
+}
+
+impl<T: Std140Element + ShaderLayout, const N: usize> ShaderLayout for Std140Array<T, N> {
+    const STD140_ALIGN: usize = <[T; N]>::STD140_ALIGN;
+    const STD140_SIZE: usize = <[T; N]>::STD140_SIZE;
+    const STD430_ALIGN: usize = <[T; N]>::STD430_ALIGN;
+    const STD430_SIZE: usize = <[T; N]>::STD430_SIZE;
+    // Vec4 and Mat4 elements are stored as they are, the smaller ones padded
+    const STD430_COMPATIBLE: bool = T::STD430_COMPATIBLE
+        && mem::size_of::<T::Padded>()
+            == super::__private::round_up(T::STD430_SIZE, T::STD430_ALIGN);
+}
+
+// SAFETY: every padding byte is an explicit, initialized field
+unsafe impl AsBytes for Std140Vec3 {}
+unsafe impl AsBytes for Std140Mat2 {}
+unsafe impl AsBytes for Std140Mat3 {}
+unsafe impl AsBytes for Std140Mat4 {}
+unsafe impl AsBytes for Std140Padded<f32, 3> {}
+unsafe impl AsBytes for Std140Padded<i32, 3> {}
+unsafe impl AsBytes for Std140Padded<u32, 3> {}
+unsafe impl AsBytes for Std140Padded<Vec2, 2> {}
+unsafe impl<T: Std140Element, const N: usize> AsBytes for Std140Array<T, N> {}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::offset_of;
+
+    use super::*;
+    use crate::layout::ShaderLayout;
+
+    #[derive(ShaderLayout)]
+    #[repr(C)]
+    #[layout(std140)]
+    struct Std140Block {
+        rotation: Std140Mat2,
+        weights: Std140Array<f32, 3>,
+        tint: Vec4,
+    }
+
+    #[derive(ShaderLayout)]
+    #[repr(C)]
+    #[layout(std430)]
+    struct Std430Block {
+        basis: Std140Mat3,
+        points: Std140Array<Vec3, 2>,
+        scale: f32,
+    }
+
+    #[test]
+    fn std140_blocks_take_every_wrapper() {
+        assert_eq!(offset_of!(Std140Block, weights), 32);
+        assert_eq!(offset_of!(Std140Block, tint), 80);
+        assert_eq!(Std140Block::STD140_SIZE, mem::size_of::<Std140Block>());
+        // Its memory is std140's, so it can't be nested in a std430 block either
+        const { assert!(!Std140Block::STD430_COMPATIBLE) };
+    }
+
+    #[test]
+    fn std430_blocks_refuse_wrappers_padded_past_std430() {
+        const { assert!(!Std140Mat2::STD430_COMPATIBLE) };
+        const { assert!(!Std140Vec3::STD430_COMPATIBLE) };
+        const { assert!(!<Std140Array<f32, 4>>::STD430_COMPATIBLE) };
+        const { assert!(!<Std140Array<Vec2, 4>>::STD430_COMPATIBLE) };
+
+        // These match std430 byte for byte
+        const { assert!(<Std140Array<Vec4, 4>>::STD430_COMPATIBLE) };
+        const { assert!(Std140Mat3::STD430_COMPATIBLE) };
+        assert_eq!(offset_of!(Std430Block, points), 48);
+        assert_eq!(offset_of!(Std430Block, scale), 80);
+        assert_eq!(Std430Block::STD430_SIZE, mem::size_of::<Std430Block>());
+        const { assert!(Std430Block::STD430_COMPATIBLE) };
+    }
+}
@@ -0,0 +1,162 @@
+//! Bookkeeping of live Vulkan objects, to report those never destroyed
+//!
+//! The wrappers owning objects, [`crate::memory::Buffer`], [`crate::texture::Texture`], sampler
+//! caches and render graph transients, [`track`] what they create and [`untrack`] it as they
+//! destroy it. Whatever is left once everything has been torn down leaked, and a [`LeakReport`]
+//! says where each object was created. Backtraces are only captured when `RUST_BACKTRACE` is set,
+//! the caller's location always is. Tests running in parallel share the registry, they check
+//! [`LeakReport::capture_thread`] instead.
+
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    collections::BTreeMap,
+    fmt,
+    panic::Location,
+    sync::Mutex,
+    thread::{self, ThreadId},
+};
+
+use ash::vk::{self, Handle};
+
+struct Tracked {
+    label: &'static str,
+    location: &'static Location<'static>,
+    thread: ThreadId,
+    backtrace: Backtrace,
+}
+
+/// Live objects by type and handle, handles of different types may be equal
+static LIVE: Mutex<BTreeMap<(i32, u64), Tracked>> = Mutex::new(BTreeMap::new());
+
+/// Registers a newly created object, `label` saying what it is for
+#[track_caller]
+pub fn track<H: Handle>(handle: H, label: &'static str) {
+    let key = (H::TYPE.as_raw(), handle.as_raw());
+    let tracked = Tracked {
+        label,
+        location: Location::caller(),
+        thread: thread::current().id(),
+        backtrace: Backtrace::capture(),
+    };
+    LIVE.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(key, tracked);
+}
+
+/// Unregisters an object as it's destroyed, handles never tracked are ignored
+pub fn untrack<H: Handle>(handle: H) {
+    let key = (H::TYPE.as_raw(), handle.as_raw());
+    LIVE.lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&key);
+}
+
+/// How many tracked objects are alive
+pub fn live_count() -> usize {
+    LIVE.lock().unwrap_or_else(|err| err.into_inner()).len()
+}
+
+#[derive(Debug, Clone)]
+pub struct LeakedObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    pub label: &'static str,
+    /// Where [`track`] was called from
+    pub location: &'static Location<'static>,
+    /// Empty unless backtraces were enabled
+    pub backtrace: String,
+}
+
+/// Objects alive at the time it was taken, meant to be empty at shutdown
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    pub leaked: Vec<LeakedObject>,
+}
+
+impl LeakReport {
+    pub fn capture() -> Self {
+        Self::capture_where(|_| true)
+    }
+
+    /// Only the objects created on the calling thread, for tests running in parallel
+    pub fn capture_thread() -> Self {
+        let current = thread::current().id();
+        Self::capture_where(|tracked| tracked.thread == current)
+    }
+
+    fn capture_where(filter: impl Fn(&Tracked) -> bool) -> Self {
+        let live = LIVE.lock().unwrap_or_else(|err| err.into_inner());
+        LeakReport {
+            leaked: live
+                .iter()
+                .filter(|(_, tracked)| filter(tracked))
+                .map(|(&(object_type, handle), tracked)| LeakedObject {
+                    object_type: vk::ObjectType::from_raw(object_type),
+                    handle,
+                    label: tracked.label,
+                    location: tracked.location,
+                    backtrace: match tracked.backtrace.status() {
+                        BacktraceStatus::Captured => tracked.backtrace.to_string(),
+                        _ => String::new(),
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaked.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaked.len()
+    }
+
+    /// Panics listing the leaks if there are any, for tests
+    #[track_caller]
+    pub fn assert_empty(&self) {
+        assert!(self.is_empty(), "{self}");
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} Vulkan objects leaked", self.leaked.len())?;
+        for leak in &self.leaked {
+            write!(
+                f,
+                "\n  {:?} {:#x} ({}) created at {}",
+                leak.object_type, leak.handle, leak.label, leak.location
+            )?;
+            for line in leak.backtrace.lines() {
+                write!(f, "\n      {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_reports_leave_out_other_threads() {
+        let ours = vk::Fence::from_raw(0x1ea_0001);
+        let theirs = vk::Fence::from_raw(0x1ea_0002);
+        track(ours, "ours");
+        thread::spawn(move || track(theirs, "theirs"))
+            .join()
+            .unwrap();
+
+        let report = LeakReport::capture_thread();
+        assert!(report.leaked.iter().any(|leak| leak.label == "ours"));
+        assert!(report.leaked.iter().all(|leak| leak.label != "theirs"));
+        untrack(ours);
+        untrack(theirs);
+        assert!(LeakReport::capture_thread()
+            .leaked
+            .iter()
+            .all(|leak| leak.label != "ours"));
+    }
+}
@@ -0,0 +1,49 @@
+// Lets the derive macros refer to `::vulkan_thing` from inside this crate too
+extern crate self as vulkan_thing;
+
+pub mod animation;
+pub mod assets;
+pub mod camera;
+pub mod capture;
+pub mod culling;
+pub mod debug;
+pub mod decal;
+pub mod descriptor;
+pub mod device;
+pub mod dof;
+pub mod fog;
+pub mod geometry;
+pub mod gizmo;
+pub mod graph;
+pub mod input;
+pub mod instance;
+pub mod interop;
+pub mod layout;
+pub mod leaks;
+pub mod material;
+pub mod memory;
+pub mod mesh;
+pub mod motion;
+pub mod particles;
+pub mod picking;
+pub mod pipeline;
+pub mod profiling;
+pub mod render2d;
+pub mod scene;
+pub mod shader;
+pub mod sky;
+pub mod ssr;
+pub mod target;
+pub mod terrain;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod text;
+pub mod texture;
+pub mod timestep;
+pub mod tonemap;
+pub mod trace;
+pub mod vegetation;
+pub mod vertex;
+pub mod video;
+pub mod watch;
+pub mod water;
@@ -1,7 +1,62 @@
-use std::ffi::CStr;
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use ash::{extensions as ext, vk, Device, Entry, Instance};
+use anyhow::Context;
+use ash::{extensions as ext, prelude::VkResult, vk, Device, Entry, Instance};
+use glam::{Quat, Vec3};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle};
+use serde::Deserialize;
+#[cfg(feature = "gilrs")]
+use vulkan_thing::input::gamepad::{Gamepads, Hotplug};
+use vulkan_thing::{
+    camera::{path::CameraPath, CameraController, CameraSettings, FlyCamera},
+    capture::{FrameReadback, FrameRing},
+    descriptor::{BackendKind, Descriptor, Descriptors},
+    device::{
+        afr::AlternateFrames,
+        checkpoints::{Checkpoints, CHECKPOINT_EXTENSIONS},
+        fault::DeviceFault,
+        group::DeviceGroup,
+        instance_version,
+        present::PresentHandoff,
+        protected::{self, ProtectedQueue, SURFACE_PROTECTED_EXTENSIONS},
+        AdapterChoice, Capabilities, DeviceInfo, DeviceRequirements, EnabledFeatures, Feature,
+        QueueFamilies, Queues, ScoringPolicy,
+    },
+    graph::{dump::FrameDump, Access, ImageDesc, RenderGraph, ResourceId},
+    input::{self, replay::InputRecording, Bindings, InputMap},
+    instance::{
+        messenger::{DebugMessenger, Severity, ValidationLog},
+        LayerSelection, ValidationFeatures, VALIDATION_LAYER,
+    },
+    leaks,
+    memory::{
+        budget::{BudgetEvent, BudgetWatcher, MemoryStats},
+        usage::UsageReport,
+        Buffer,
+    },
+    pipeline,
+    profiling::{GpuTimer, Timings},
+    scene::{
+        file::{FileNode, MeshRef, SceneFile},
+        Light, LightKind, MeshId, Node, NodeId, Scene, Transform,
+    },
+    texture::sampler::{FilterQuality, SamplerCache},
+    timestep::FixedTimestep,
+    trace::traced,
+    video::{
+        self,
+        encode::{VideoEncoder, VideoEncoderConfig},
+        VideoEncodeFns,
+    },
+    watch::FileWatcher,
+};
 use winit::{
     dpi::LogicalSize,
     event::{Event, WindowEvent},
@@ -10,49 +65,803 @@ use winit::{
 };
 
 fn main() -> anyhow::Result<()> {
-    let app = TutorApp::new()?;
+    let args = Args::parse(std::env::args().skip(1))?;
+    if args.list_gpus {
+        return TutorApp::list_gpus();
+    }
+    #[cfg(feature = "api-trace")]
+    if let Some(path) = &args.api_trace {
+        vulkan_thing::trace::to_file(path)
+            .with_context(|| format!("couldn't create trace {}", path.display()))?;
+    }
+    let mut config = Config::load(&Config::path(args.config.as_deref()))?;
+    if args.bench.is_some() {
+        config.vsync = false;
+    }
+    let validation_log = args
+        .validation_report
+        .is_some()
+        .then(|| Arc::new(ValidationLog::new()));
+    let app = TutorApp::new(&args, config, validation_log.clone())?;
+    if let Some(path) = &args.dump_caps {
+        app.dump_caps(path)?;
+    }
+    let result = if args.descriptor_bench {
+        let result = app.bench_descriptors();
+        drop(app);
+        result
+    } else {
+        app.run()
+    };
+    // Written even when the run failed, as that's when it's most useful
+    let checked = match (&args.validation_report, &validation_log) {
+        (Some(path), Some(log)) => check_validation(path, log),
+        _ => Ok(()),
+    };
+    #[cfg(feature = "api-trace")]
+    vulkan_thing::trace::finish()?;
+    result?;
+    checked?;
 
-    app.run()?;
+    report_leaks()
+}
 
+/// Saves the validation messages of the run, failing if there were any
+fn check_validation(path: &Path, log: &ValidationLog) -> anyhow::Result<()> {
+    log.save(path)
+        .with_context(|| format!("couldn't write validation report {}", path.display()))?;
+    if !log.is_empty() {
+        anyhow::bail!(
+            "validation reported {} errors and {} warnings, see {}",
+            log.count(Severity::Error),
+            log.count(Severity::Warning),
+            path.display()
+        );
+    }
     Ok(())
 }
 
-struct QueueIndexes {
-    graphics: u32,
-    present: u32,
+/// Lists Vulkan objects still alive once the app has been torn down, an error in debug builds
+fn report_leaks() -> anyhow::Result<()> {
+    let leaks = leaks::LeakReport::capture();
+    if leaks.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{leaks}");
+    if cfg!(debug_assertions) {
+        anyhow::bail!("{} Vulkan objects leaked", leaks.len());
+    }
+    Ok(())
 }
 
-impl QueueIndexes {
-    /// Folding function used to test if a queue for both graphics and presenting exists, with a preference for queues that support both
-    fn fold_into(
-        surface_ext: &ext::khr::Surface,
-        dev: vk::PhysicalDevice,
-        khr_surface: vk::SurfaceKHR,
-        mut acc: [Option<u32>; 2],
-        queue_i: usize,
-        queue: &vk::QueueFamilyProperties,
-    ) -> Result<[Option<u32>; 2], [Option<u32>; 2]> {
-        let queue_i = queue_i as u32;
-        let mut both = false;
-        if queue.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
-            acc[0] = Some(queue_i);
-            both = true;
-        }
-
-        if unsafe { surface_ext.get_physical_device_surface_support(dev, queue_i, khr_surface) }
-            .unwrap()
-        {
-            acc[1] = Some(queue_i);
-            if both {
-                return Err(acc);
+/// Command line options
+#[derive(Debug, Default)]
+struct Args {
+    /// `--gpu <index|name>`, the adapter to use instead of the best scoring one
+    gpu: Option<AdapterChoice>,
+    /// `--layer <name>`, repeatable, instance layers on top of those in `VULKAN_THING_LAYERS`
+    layers: Vec<String>,
+    /// `--list-gpus`, describe every device and exit
+    list_gpus: bool,
+    /// `--dump-caps <file>`, write the selected device's capabilities there as JSON
+    dump_caps: Option<PathBuf>,
+    /// `--robust`, bounds check buffer and image accesses and allow null descriptors, so out of
+    /// bounds shader accesses can be debugged without losing the device
+    robust: bool,
+    /// `--checkpoints`, mark passes with NVIDIA checkpoints or AMD buffer markers to find which
+    /// one hung after a device loss
+    checkpoints: bool,
+    /// `--protected`, render through a protected queue into a protected swapchain where the
+    /// device and surface allow it, to try out DRM style render paths
+    protected: bool,
+    /// `--device-group`, create the device across every GPU linked with the selected one
+    device_group: bool,
+    /// `--afr`, `--device-group` rendering and presenting alternate frames on each GPU
+    afr: bool,
+    /// `--pipeline-feedback`, log how long each pipeline took to build
+    pipeline_feedback: bool,
+    /// `--descriptor-bench`, time writing descriptors with each backend and exit
+    descriptor_bench: bool,
+    /// `--config <file>`, settings to use instead of `config.toml`
+    config: Option<PathBuf>,
+    /// `--camera-path <file>`, fly the camera along a recorded path, then report the frame rate
+    /// and exit
+    camera_path: Option<PathBuf>,
+    /// `--record-camera-path <file>`, save where the camera went there on exit
+    record_camera_path: Option<PathBuf>,
+    /// `--seed <number>`, seed for anything randomized, saved with `--record-input`
+    seed: Option<u64>,
+    /// `--record-input <file>`, save the input of every simulation step there on exit, running
+    /// in lockstep so `--replay-input` repeats the run exactly
+    record_input: Option<PathBuf>,
+    /// `--replay-input <file>`, play back recorded input instead of live input, with its seed
+    /// and update rate, then exit
+    replay_input: Option<PathBuf>,
+    /// `--bench <frames>`, render that many frames along `--camera-path`, or around the origin
+    /// without one, with vsync off, then save CPU and per pass GPU timing statistics and exit
+    bench: Option<u64>,
+    /// `--bench-output <file>`, where `--bench` saves its statistics, as JSON for a `.json`
+    /// extension and CSV otherwise, `bench.csv` by default
+    bench_output: Option<PathBuf>,
+    /// `--validate <gpu,sync,best-practices,printf>`, slower validation checks to enable, which
+    /// loads the validation layer if it isn't already
+    validation: ValidationFeatures,
+    /// `--validation-report <file>`, collect validation warnings and errors into that file, as
+    /// JSON for a `.json` extension and text otherwise, and exit with an error if there were any
+    validation_report: Option<PathBuf>,
+    /// `--api-trace <file>`, log the traced Vulkan calls there as JSON lines
+    #[cfg(feature = "api-trace")]
+    api_trace: Option<PathBuf>,
+    /// `--scene <file>`, a RON or JSON scene to load, reloaded whenever it changes
+    scene: Option<PathBuf>,
+    /// `--capture <seconds>`, keep that many seconds of frames for the `dump_frames` action to
+    /// save as PNGs
+    capture: Option<f64>,
+    /// `--dump-graph <dir>`, save every image the first frame writes there, and those of the
+    /// frame after each `dump_graph` action
+    dump_graph: Option<PathBuf>,
+    /// `--record-video <file>`, encode every presented frame into an H.264 stream there on the
+    /// GPU, where the device has a video encode queue
+    record_video: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--gpu" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--gpu needs an index or a name"))?;
+                    parsed.gpu = Some(AdapterChoice::parse(&value));
+                }
+                "--list-gpus" => parsed.list_gpus = true,
+                "--robust" => parsed.robust = true,
+                "--checkpoints" => parsed.checkpoints = true,
+                "--protected" => parsed.protected = true,
+                "--device-group" => parsed.device_group = true,
+                "--afr" => parsed.afr = true,
+                "--pipeline-feedback" => parsed.pipeline_feedback = true,
+                "--descriptor-bench" => parsed.descriptor_bench = true,
+                "--dump-caps" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--dump-caps needs a file"))?;
+                    parsed.dump_caps = Some(value.into());
+                }
+                "--camera-path" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--camera-path needs a file"))?;
+                    parsed.camera_path = Some(value.into());
+                }
+                "--record-camera-path" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--record-camera-path needs a file"))?;
+                    parsed.record_camera_path = Some(value.into());
+                }
+                "--scene" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--scene needs a file"))?;
+                    parsed.scene = Some(value.into());
+                }
+                "--capture" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--capture needs a number of seconds"))?;
+                    let seconds: f64 = value
+                        .parse()
+                        .with_context(|| format!("--capture seconds {value:?}"))?;
+                    if !(seconds > 0.0 && seconds.is_finite()) {
+                        anyhow::bail!("--capture needs a positive number of seconds, not {value}");
+                    }
+                    parsed.capture = Some(seconds);
+                }
+                "--dump-graph" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--dump-graph needs a directory"))?;
+                    parsed.dump_graph = Some(value.into());
+                }
+                "--record-video" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--record-video needs a file"))?;
+                    parsed.record_video = Some(value.into());
+                }
+                "--seed" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--seed needs a number"))?;
+                    parsed.seed = Some(value.parse().with_context(|| format!("--seed {value:?}"))?);
+                }
+                "--record-input" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--record-input needs a file"))?;
+                    parsed.record_input = Some(value.into());
+                }
+                "--replay-input" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--replay-input needs a file"))?;
+                    parsed.replay_input = Some(value.into());
+                }
+                "--bench" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--bench needs a frame count"))?;
+                    parsed.bench = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("--bench frame count {value:?}"))?,
+                    );
+                }
+                "--bench-output" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--bench-output needs a file"))?;
+                    parsed.bench_output = Some(value.into());
+                }
+                "--validate" => {
+                    let value = args.next().ok_or_else(|| {
+                        anyhow::anyhow!("--validate needs a list such as gpu,sync,best-practices")
+                    })?;
+                    parsed.validation = ValidationFeatures::parse(&value)?;
+                }
+                "--validation-report" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--validation-report needs a file"))?;
+                    parsed.validation_report = Some(value.into());
+                }
+                #[cfg(feature = "api-trace")]
+                "--api-trace" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--api-trace needs a file"))?;
+                    parsed.api_trace = Some(value.into());
+                }
+                "--config" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--config needs a file"))?;
+                    parsed.config = Some(value.into());
+                }
+                "--layer" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("--layer needs a layer name"))?;
+                    parsed.layers.push(value);
+                }
+                _ => anyhow::bail!("unknown argument {arg}"),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Settings read from `config.toml`, anything left out keeps its default
+///
+/// The file is watched while running, changes apply from the next frame. `vsync` recreates the
+/// swapchain, between frames once the device is idle.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+struct Config {
+    input: Bindings,
+    camera: CameraSettings,
+    /// Present with FIFO, otherwise the lowest latency mode available
+    vsync: bool,
+    /// Simulation steps per second, whatever the frame rate, from 1 to 1000
+    update_rate: f64,
+    /// Frame rate while the window is unfocused, up to 1000. 0 stops rendering until it's
+    /// focused again
+    background_fps: f64,
+    light: LightSettings,
+    post: PostSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            input: Bindings::default(),
+            camera: CameraSettings::default(),
+            vsync: false,
+            update_rate: 60.0,
+            background_fps: 10.0,
+            light: LightSettings::default(),
+            post: PostSettings::default(),
+        }
+    }
+}
+
+impl Config {
+    const DEFAULT_PATH: &'static str = "config.toml";
+
+    /// `path`, or `config.toml` when given none
+    fn path(path: Option<&Path>) -> PathBuf {
+        path.unwrap_or(Path::new(Self::DEFAULT_PATH)).to_owned()
+    }
+
+    /// Reads `path`, `config.toml` is optional
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        if path == Path::new(Self::DEFAULT_PATH) && !path.exists() {
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read {}", path.display()))?;
+        let config: Config = toml::from_str(&text)
+            .with_context(|| format!("invalid config in {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config in {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let rates = FixedTimestep::RATES;
+        anyhow::ensure!(
+            rates.contains(&self.update_rate),
+            "update_rate must be within {} to {}",
+            rates.start(),
+            rates.end()
+        );
+        anyhow::ensure!(
+            self.background_fps == 0.0 || rates.contains(&self.background_fps),
+            "background_fps must be 0 or within {} to {}",
+            rates.start(),
+            rates.end()
+        );
+        let camera = &self.camera;
+        anyhow::ensure!(
+            [camera.speed, camera.sensitivity, camera.turn_rate]
+                .iter()
+                .all(|v| v.is_finite()),
+            "camera settings must be finite"
+        );
+        anyhow::ensure!(
+            (0.0..1.0).contains(&camera.zoom_step),
+            "camera.zoom_step must be at least 0 and below 1"
+        );
+        let light = &self.light;
+        anyhow::ensure!(
+            light.direction.is_finite() && light.direction.length_squared() > 0.0,
+            "light.direction must be a finite nonzero vector"
+        );
+        anyhow::ensure!(
+            light.color.is_finite() && light.color.min_element() >= 0.0,
+            "light.color can't be negative"
+        );
+        anyhow::ensure!(
+            light.intensity.is_finite() && light.intensity >= 0.0,
+            "light.intensity can't be negative"
+        );
+        anyhow::ensure!(
+            self.post.exposure.is_finite() && self.post.exposure > 0.0,
+            "post.exposure must be positive"
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.post.lut_strength),
+            "post.lut_strength must be within 0 to 1"
+        );
+        Ok(())
+    }
+
+    /// Time between frames while unfocused, `None` to suspend instead
+    fn background_interval(&self) -> Option<Duration> {
+        (self.background_fps > 0.0).then(|| Duration::from_secs_f64(1.0 / self.background_fps))
+    }
+}
+
+/// The sun, a directional light the app adds to the scene next to the scene file's own
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+struct LightSettings {
+    /// Where the light shines towards
+    direction: Vec3,
+    color: Vec3,
+    intensity: f32,
+}
+
+impl Default for LightSettings {
+    fn default() -> Self {
+        LightSettings {
+            direction: Vec3::new(-0.3, -1.0, -0.2),
+            color: Vec3::ONE,
+            intensity: 3.0,
+        }
+    }
+}
+
+impl LightSettings {
+    /// Points `node` along the direction, lights shine down their -Z, and sets its light
+    fn apply(&self, node: &mut Node) {
+        node.local.rotation = Quat::from_rotation_arc(Vec3::NEG_Z, self.direction.normalize());
+        node.light = Some(Light {
+            kind: LightKind::Directional,
+            color: self.color,
+            intensity: self.intensity,
+        });
+    }
+}
+
+/// Post processing, for the tonemap's fields of the same names
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+struct PostSettings {
+    /// Multiplies the scene before it's mapped
+    exposure: f32,
+    /// How much of the color grading table applies, from 0 to 1
+    lut_strength: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        PostSettings {
+            exposure: 1.0,
+            lut_strength: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Visibility {
+    focused: bool,
+    /// Covered by other windows, as far as the platform reports
+    occluded: bool,
+    /// Not every platform reports minimizing as occlusion, but all resize to nothing
+    minimized: bool,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility {
+            focused: true,
+            occluded: false,
+            minimized: false,
+        }
+    }
+}
+
+/// A `--camera-path` run, counting frames for the report at its end
+struct Playback {
+    path: CameraPath,
+    /// Starts over at the end instead of finishing, for `--bench`
+    looping: bool,
+    started: Instant,
+    frames: u64,
+}
+
+impl Playback {
+    fn new(path: CameraPath) -> Self {
+        Playback {
+            path,
+            looping: false,
+            started: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    fn report(&self, sim_time: f32) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        println!(
+            "Camera path of {sim_time:.2} s took {elapsed:.2} s, {} frames at {:.1} fps, {:.2} ms \
+             average",
+            self.frames,
+            self.frames as f64 / elapsed,
+            elapsed * 1000.0 / self.frames.max(1) as f64
+        );
+    }
+}
+
+/// A `--bench` run, counting down frames and timing each
+struct Bench {
+    remaining: u64,
+    output: PathBuf,
+    timings: Timings,
+    last_frame: Option<Instant>,
+}
+
+impl Bench {
+    /// Seconds `orbit_path` takes to go around once
+    const ORBIT_SECONDS: f32 = 8.0;
+
+    fn new(frames: u64, output: PathBuf) -> Self {
+        Bench {
+            remaining: frames,
+            output,
+            timings: Timings::new(),
+            last_frame: None,
+        }
+    }
+
+    /// Circles the origin, for benchmarks without a recorded path
+    fn orbit_path() -> CameraPath {
+        const KEYFRAMES: u32 = 16;
+        let mut path = CameraPath::new();
+        for i in 0..=KEYFRAMES {
+            let t = i as f32 / KEYFRAMES as f32;
+            let angle = t * std::f32::consts::TAU;
+            let position = Vec3::new(angle.sin() * 5.0, 1.5, angle.cos() * 5.0);
+            let camera = CameraController::looking_at(position, Vec3::ZERO);
+            path.record(t * Self::ORBIT_SECONDS, &camera.transform());
+        }
+        path
+    }
+
+    /// Counts a frame finished at `now`, returning whether that was the last
+    fn frame(&mut self, now: Instant) -> bool {
+        // The first frame has nothing to be measured from
+        if let Some(last) = self.last_frame.replace(now) {
+            self.timings.record_cpu("frame", now.duration_since(last));
+        }
+        self.remaining = self.remaining.saturating_sub(1);
+        self.remaining == 0
+    }
+
+    fn save(&self) {
+        match self.timings.save(&self.output) {
+            Ok(()) => println!("Saved benchmark timings to {}", self.output.display()),
+            Err(err) => eprintln!("Couldn't save {}: {err}", self.output.display()),
+        }
+    }
+}
+
+/// The `--scene` file and what was instantiated from it
+struct LoadedScene {
+    path: PathBuf,
+    roots: Vec<FileNode>,
+    /// Meshes by id, registered as the file refers to them until main can draw them
+    meshes: Vec<MeshRef>,
+}
+
+impl LoadedScene {
+    /// Loads `path` into `scene`, returning where the file puts the viewer, if anywhere
+    fn load(path: &Path, scene: &mut Scene) -> anyhow::Result<(Self, Option<Transform>)> {
+        let file =
+            SceneFile::load(path).with_context(|| format!("couldn't load {}", path.display()))?;
+        let mut meshes = Vec::new();
+        let roots = file.instantiate(scene, None, |mesh_ref, _| {
+            Ok(Self::mesh_id(&mut meshes, mesh_ref))
+        })?;
+        scene.update_transforms();
+        let loaded = LoadedScene {
+            path: path.to_owned(),
+            roots,
+            meshes,
+        };
+        Ok((loaded, file.view))
+    }
+
+    /// Applies changes to the file, leaving the viewer where it is
+    fn reload(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        let file = SceneFile::load(&self.path)?;
+        let meshes = &mut self.meshes;
+        self.roots = file.update(scene, None, &self.roots, |mesh_ref, _| {
+            Ok(Self::mesh_id(meshes, mesh_ref))
+        })?;
+        scene.update_transforms();
+        Ok(())
+    }
+
+    fn mesh_id(meshes: &mut Vec<MeshRef>, mesh_ref: &MeshRef) -> MeshId {
+        let index = meshes
+            .iter()
+            .position(|known| known == mesh_ref)
+            .unwrap_or_else(|| {
+                meshes.push(mesh_ref.clone());
+                meshes.len() - 1
+            });
+        MeshId(index as u32)
+    }
+}
+
+/// Command buffers and synchronization for each frame in flight
+struct Frames {
+    /// Null when command buffers come from the protected queue's pool
+    command_pool: vk::CommandPool,
+    commands: Vec<vk::CommandBuffer>,
+    /// Signalled once each slot's submission finished
+    fences: Vec<vk::Fence>,
+    /// Signalled by each slot's acquire, alternate frames acquire with their own
+    acquired: Vec<vk::Semaphore>,
+    /// Signalled by the submission rendering each swapchain image, presentation waits on it
+    rendered: Vec<vk::Semaphore>,
+    frame: usize,
+}
+
+impl Frames {
+    /// `count` slots, recording into protected command buffers when given `protected`
+    fn new(
+        device: &Device,
+        family: u32,
+        protected: Option<&ProtectedQueue>,
+        count: usize,
+        image_count: usize,
+    ) -> anyhow::Result<Self> {
+        let mut frames = Frames {
+            command_pool: vk::CommandPool::null(),
+            commands: Vec::new(),
+            fences: Vec::new(),
+            acquired: Vec::new(),
+            rendered: Vec::new(),
+            frame: 0,
+        };
+        match unsafe { frames.create_objects(device, family, protected, count, image_count) } {
+            Ok(()) => Ok(frames),
+            Err(err) => {
+                unsafe { frames.destroy(device) };
+                Err(err.into())
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        family: u32,
+        protected: Option<&ProtectedQueue>,
+        count: usize,
+        image_count: usize,
+    ) -> VkResult<()> {
+        self.commands = match protected {
+            Some(queue) => queue.allocate_command_buffers(device, count as u32)?,
+            None => {
+                let pool_info = vk::CommandPoolCreateInfo::builder()
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                    .queue_family_index(family);
+                self.command_pool = device.create_command_pool(&pool_info, None)?;
+                leaks::track(self.command_pool, "frame command pool");
+                let alloc_info = vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(self.command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(count as u32);
+                device.allocate_command_buffers(&alloc_info)?
             }
+        };
+        // Signalled, so the first wait on each slot returns at once
+        let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        for _ in 0..count {
+            let fence = device.create_fence(&fence_info, None)?;
+            leaks::track(fence, "frame fence");
+            self.fences.push(fence);
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "frame acquired semaphore");
+            self.acquired.push(semaphore);
         }
-        Ok(acc)
+        self.set_images(device, image_count)
     }
 
-    fn as_array(&self) -> [u32; 2] {
-        [self.graphics, self.present]
+    /// Makes a render semaphore for each of the swapchain's `image_count` images
+    ///
+    /// # Safety
+    ///
+    /// No present may still be waiting on the previous swapchain's semaphores.
+    unsafe fn set_images(&mut self, device: &Device, image_count: usize) -> VkResult<()> {
+        for semaphore in self.rendered.drain(..) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        for _ in 0..image_count {
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "frame rendered semaphore");
+            self.rendered.push(semaphore);
+        }
+        Ok(())
+    }
+
+    /// The current frame's slot, which alternate frames choose themselves
+    fn slot(&self, alternate_frames: Option<&AlternateFrames>) -> usize {
+        match alternate_frames {
+            Some(frames) => frames.frame_slot(),
+            None => self.frame % self.fences.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with every frame.
+    unsafe fn destroy(&mut self, device: &Device) {
+        for fence in self.fences.drain(..) {
+            leaks::untrack(fence);
+            device.destroy_fence(fence, None);
+        }
+        for semaphore in self.acquired.drain(..).chain(self.rendered.drain(..)) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        // Protected command buffers go with the protected queue's pool
+        self.commands.clear();
+        if self.command_pool != vk::CommandPool::null() {
+            leaks::untrack(self.command_pool);
+            device.destroy_command_pool(self.command_pool, None);
+            self.command_pool = vk::CommandPool::null();
+        }
+    }
+}
+
+/// The last `--capture` seconds of frames, dumped with the `dump_frames` action
+struct Capture {
+    /// `None` while the swapchain images can't be read back
+    readback: Option<FrameReadback>,
+    ring: FrameRing,
+}
+
+impl Capture {
+    /// Frames kept a second at most, enough to see a glitch without holding every frame
+    const RATE: f64 = 30.0;
+    const DIR: &'static str = "captures";
+}
+
+/// `--dump-graph`, saving every image a frame's render graph writes
+struct GraphDump {
+    dir: PathBuf,
+    /// The next frame is dumped
+    requested: bool,
+    /// The frame being dumped, its slot and number, until its fence has been waited on
+    pending: Option<(usize, usize, FrameDump)>,
+}
+
+/// `--record-video`, presented frames encoded on the GPU and appended to a file
+///
+/// A resize ends the sequence and starts one at the new size, with its own parameter sets.
+struct VideoRecording {
+    path: PathBuf,
+    file: BufWriter<File>,
+    fns: VideoEncodeFns,
+    /// `None` while the swapchain images can't be encoded
+    encoder: Option<VideoEncoder>,
+    /// Signalled by the frame being submitted, cleared once it has been
+    converted: Option<vk::Semaphore>,
+    /// Encoded but not yet written
+    stream: Vec<u8>,
+}
+
+impl VideoRecording {
+    /// The rate written into the stream, frames are encoded as they're presented
+    const FRAME_RATE: u32 = 60;
+
+    fn write(&mut self) {
+        if let Err(err) = self.file.write_all(&self.stream) {
+            eprintln!("Couldn't write the video to {}: {err}", self.path.display());
+        }
+        self.stream.clear();
     }
+
+    /// Encodes what's left and ends the stream
+    ///
+    /// # Safety
+    ///
+    /// The device must be done with every frame.
+    unsafe fn stop(&mut self, device: &Device) {
+        if let Some(mut encoder) = self.encoder.take() {
+            // A frame recorded but never submitted would have its encode wait forever
+            if self.converted.take().is_none() {
+                if let Err(err) = encoder.finish(device, &mut self.stream) {
+                    eprintln!("Couldn't encode the last frames: {err}");
+                }
+                self.write();
+            }
+            encoder.destroy(device);
+        }
+        if let Err(err) = self.file.flush() {
+            eprintln!("Couldn't write the video to {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// How hard the event loop runs, following whether the window can be seen and is in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerMode {
+    /// Rendering as fast as possible
+    Full,
+    /// Unfocused but visible, rendering at `background_fps`
+    Background,
+    /// Hidden, minimized or unfocused without a background frame rate, sleeping until an event
+    Suspended,
 }
 
 struct SwapChainSupport {
@@ -75,6 +884,7 @@ impl SwapChainSupport {
         })
     }
 
+    #[allow(clippy::unnecessary_sort_by)]
     fn choose_swap_surface_format(&self) -> vk::SurfaceFormatKHR {
         let mut formats: Vec<_> = self
             .formats
@@ -97,7 +907,11 @@ impl SwapChainSupport {
         vk::PresentModeKHR::FIFO_RELAXED,
         vk::PresentModeKHR::FIFO,
     ];
-    fn choose_swap_present_mode(&self) -> vk::PresentModeKHR {
+    #[allow(clippy::filter_next)]
+    fn choose_swap_present_mode(&self, vsync: bool) -> vk::PresentModeKHR {
+        if vsync {
+            return vk::PresentModeKHR::FIFO;
+        }
         *Self::DESIRED_MODES
             .iter()
             .filter(|mode| self.present_modes.contains(mode))
@@ -146,6 +960,8 @@ struct TutorApp {
     event_loop: Option<EventLoop<()>>,
     window: Window,
 
+    /// Keeps the loader alive for the instance
+    #[allow(dead_code)]
     entry: Entry,
     instance: Instance,
     surface_ext: ext::khr::Surface,
@@ -153,9 +969,18 @@ struct TutorApp {
 
     physical_device: vk::PhysicalDevice,
     device: Device,
-
-    graphics_queue: vk::Queue,
-    present_queue: vk::Queue,
+    /// Extensions and features negotiated when picking the device
+    enabled_features: EnabledFeatures,
+    capabilities: Capabilities,
+    /// Explains device losses when `VK_EXT_device_fault` is enabled
+    device_fault: Option<DeviceFault>,
+    /// Pass breadcrumbs, with `--checkpoints` on devices supporting them
+    checkpoints: Option<Checkpoints>,
+    /// Collects validation messages with `--validation-report`
+    debug_messenger: Option<DebugMessenger>,
+
+    queue_families: QueueFamilies,
+    queues: Queues,
 
     swapchain_ext: ext::khr::Swapchain,
     swapchain: vk::SwapchainKHR,
@@ -164,12 +989,139 @@ struct TutorApp {
     extent: vk::Extent2D,
 
     swapchain_image_views: Vec<vk::ImageView>,
+    /// Moves images to the present family when it isn't the graphics one
+    present_handoff: Option<PresentHandoff>,
+    /// With `--protected`, on devices supporting protected memory
+    protected_queue: Option<ProtectedQueue>,
+    /// Whether the swapchain's images are protected, which needs `protected_queue` to draw them
+    protected_swapchain: bool,
+    /// With `--device-group`, the GPUs the device spans, the selected one first
+    #[allow(dead_code)]
+    device_group: Option<DeviceGroup>,
+    /// With `--afr`, when every GPU of the group can present
+    alternate_frames: Option<AlternateFrames>,
+    frames: Frames,
+    /// Records each frame, into the swapchain image imported as `backbuffer`
+    graph: RenderGraph,
+    backbuffer: ResourceId,
+    /// With `--capture`
+    capture: Option<Capture>,
+    graph_dump: Option<GraphDump>,
+    video: Option<VideoRecording>,
+    /// Times each pass with `--bench`, where the graphics queue supports timestamps
+    gpu_timer: Option<GpuTimer>,
+    mem_props: vk::PhysicalDeviceMemoryProperties,
+
+    /// Only present when `VK_EXT_memory_budget` is enabled
+    memory_budget_ext: Option<ext::khr::GetPhysicalDeviceProperties2>,
+    budget_watcher: BudgetWatcher,
+
+    samplers: SamplerCache,
+
+    input: InputMap,
+    camera: CameraController,
+    scene: Scene,
+    /// Directional light following [`Config::light`]
+    sun: NodeId,
+    scene_file: Option<LoadedScene>,
+    config: Config,
+    config_path: PathBuf,
+    /// Watches the config and scene for changes
+    watcher: FileWatcher,
+    /// Set when the swapchain has to be recreated before the next frame
+    swapchain_outdated: bool,
+    timestep: FixedTimestep,
+    visibility: Visibility,
+    cursor_grabbed: bool,
+    /// Time between frames while unfocused, `None` to suspend instead
+    background_interval: Option<Duration>,
+    last_frame: Instant,
+    /// Seconds simulated so far, advancing in fixed steps
+    sim_time: f32,
+    playback: Option<Playback>,
+    bench: Option<Bench>,
+    /// Where to save the recorded path on exit
+    recording: Option<(PathBuf, CameraPath)>,
+    /// Where to save the recorded input on exit
+    input_recording: Option<(PathBuf, InputRecording)>,
+    /// Input being played back, with the next step to play
+    input_replay: Option<(InputRecording, usize)>,
+    /// `None` when gilrs couldn't start, e.g. without access to input devices
+    #[cfg(feature = "gilrs")]
+    gamepads: Option<Gamepads>,
 }
 
 impl TutorApp {
+    /// Older devices are still used, newer code paths are picked through `capabilities`
+    const MIN_API_VERSION: u32 = vk::API_VERSION_1_0;
     const DEVICE_EXTENSIONS: [&'static CStr; 1] = [cstr!("VK_KHR_swapchain")];
+    /// Enabled when the device supports them
+    const OPTIONAL_DEVICE_EXTENSIONS: [&'static CStr; 3] = [
+        cstr!("VK_EXT_memory_budget"),
+        // Needed by graphics pipeline libraries
+        cstr!("VK_KHR_pipeline_library"),
+        cstr!("VK_KHR_push_descriptor"),
+    ];
+    const OPTIONAL_FEATURES: [Feature; 20] = [
+        Feature::SamplerAnisotropy,
+        Feature::Synchronization2,
+        Feature::DynamicRendering,
+        Feature::TimelineSemaphore,
+        Feature::DeviceFault,
+        Feature::DeviceFaultVendorBinary,
+        Feature::GraphicsPipelineLibrary,
+        Feature::ShaderObject,
+        Feature::BufferDeviceAddress,
+        Feature::DescriptorBuffer,
+        Feature::ExtendedDynamicState,
+        Feature::ExtendedDynamicState2,
+        Feature::ExtendedDynamicState3PolygonMode,
+        Feature::ExtendedDynamicState3DepthClampEnable,
+        Feature::DepthClamp,
+        Feature::DepthBiasClamp,
+        Feature::SparseBinding,
+        Feature::SparseResidencyBuffer,
+        Feature::SparseResidencyImage2D,
+        Feature::HostImageCopy,
+    ];
+    /// Enabled with `--robust`, they cost performance so are off by default
+    const ROBUST_FEATURES: [Feature; 4] = [
+        Feature::RobustBufferAccess,
+        Feature::RobustBufferAccess2,
+        Feature::RobustImageAccess2,
+        Feature::NullDescriptor,
+    ];
+    /// Extensions `--list-gpus` reports support for
+    const REPORTED_EXTENSIONS: [&'static CStr; 8] = [
+        cstr!("VK_KHR_swapchain"),
+        cstr!("VK_EXT_memory_budget"),
+        cstr!("VK_KHR_dynamic_rendering"),
+        cstr!("VK_KHR_synchronization2"),
+        cstr!("VK_EXT_descriptor_indexing"),
+        cstr!("VK_KHR_ray_tracing_pipeline"),
+        cstr!("VK_EXT_mesh_shader"),
+        cstr!("VK_EXT_robustness2"),
+    ];
+    /// Fraction of a heap's budget that triggers a warning
+    const BUDGET_WARNING: f32 = 0.9;
+    /// How often watched files are checked for changes
+    const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+    /// Filtering used by samplers following the global quality setting
+    const FILTER_QUALITY: FilterQuality = FilterQuality::Anisotropic(16);
+    /// Frames recorded ahead of the one the device is on
+    const FRAMES_IN_FLIGHT: usize = 2;
+    /// Passes a frame times at most
+    const GPU_SCOPES: u32 = 32;
+    /// What frames are cleared to, until there's a scene renderer
+    const CLEAR_COLOR: vk::ClearColorValue = vk::ClearColorValue {
+        float32: [0.02, 0.02, 0.03, 1.0],
+    };
 
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(
+        args: &Args,
+        config: Config,
+        validation_log: Option<Arc<ValidationLog>>,
+    ) -> anyhow::Result<Self> {
         let (event_loop, window) = Self::init_window();
         let (
             entry,
@@ -178,16 +1130,115 @@ impl TutorApp {
             surface_khr,
             physical_device,
             device,
-            graphics_queue,
-            present_queue,
+            enabled_features,
+            queue_families,
+            queues,
             swapchain_ext,
             swapchain,
             swapchain_images,
             format,
             extent,
             swapchain_image_views,
-        ) = Self::init_vulkan(&window)?;
-        Ok(Self {
+            protected_swapchain,
+            device_group,
+            alternate_frames,
+            memory_budget_ext,
+            samplers,
+        ) = Self::init_vulkan(&window, args, config.vsync, validation_log.as_ref())?;
+        let debug_messenger = validation_log
+            .map(|log| DebugMessenger::new(&entry, &instance, log))
+            .transpose()?;
+        let config_path = Config::path(args.config.as_deref());
+        let mut watcher = FileWatcher::new(Self::WATCH_INTERVAL);
+        watcher.watch(&config_path);
+        let mut scene = Scene::new();
+        let mut sun = Node::new("sun");
+        config.light.apply(&mut sun);
+        let sun = scene
+            .add(sun, None)
+            .expect("a root has no parent to be stale");
+        let mut camera = CameraController::looking_at(Vec3::new(0.0, 1.5, 5.0), Vec3::ZERO);
+        let scene_file = match &args.scene {
+            Some(path) => {
+                let (loaded, view) = LoadedScene::load(path, &mut scene)?;
+                if let Some(view) = view {
+                    camera = CameraController::Fly(FlyCamera::from_transform(&view));
+                }
+                watcher.watch(path);
+                Some(loaded)
+            }
+            None => None,
+        };
+        scene.update_transforms();
+        let device_fault = DeviceFault::new(&instance, &device, &enabled_features);
+        let mem_props = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let checkpoints = Checkpoints::new(&instance, &device, &mem_props, &enabled_features)?;
+        let present_handoff = PresentHandoff::new(&device, &queue_families, &swapchain_images)?;
+        let protected_queue = ProtectedQueue::new(&device, &queue_families, &enabled_features)?;
+        let frames = Frames::new(
+            &device,
+            queue_families.graphics,
+            protected_queue.as_ref().filter(|_| protected_swapchain),
+            Self::frames_in_flight(device_group.as_ref(), alternate_frames.as_ref()),
+            swapchain_images.len(),
+        )?;
+        // Protected command buffers can't write queries
+        let gpu_timer = if args.bench.is_some() && !protected_swapchain {
+            Self::create_gpu_timer(
+                &instance,
+                physical_device,
+                &device,
+                queue_families.graphics,
+                frames.fences.len(),
+            )?
+        } else {
+            None
+        };
+        let (mut graph, backbuffer) = Self::build_graph(format, extent);
+        graph.set_dumpable(args.dump_graph.is_some());
+        if args.checkpoints && checkpoints.is_none() {
+            eprintln!("Neither checkpoint extension is supported, passes won't be marked");
+        }
+        // Core in 1.3
+        let feedback = enabled_features.capabilities().vulkan13
+            || enabled_features.has_extension(vk::ExtPipelineCreationFeedbackFn::name());
+        pipeline::enable_feedback(args.pipeline_feedback && feedback);
+        if args.pipeline_feedback && !feedback {
+            eprintln!("Pipeline creation feedback isn't supported");
+        }
+        let mut playback = args
+            .camera_path
+            .as_deref()
+            .map(|path| {
+                CameraPath::load(path)
+                    .with_context(|| format!("couldn't load {}", path.display()))
+                    .map(Playback::new)
+            })
+            .transpose()?;
+        let bench = args.bench.map(|frames| {
+            let output = args.bench_output.clone();
+            Bench::new(frames, output.unwrap_or_else(|| "bench.csv".into()))
+        });
+        if bench.is_some() {
+            let mut bench_playback = playback.unwrap_or_else(|| Playback::new(Bench::orbit_path()));
+            bench_playback.looping = true;
+            playback = Some(bench_playback);
+        }
+        let input_replay = args
+            .replay_input
+            .as_deref()
+            .map(|path| {
+                InputRecording::load(path)
+                    .with_context(|| format!("couldn't load {}", path.display()))
+            })
+            .transpose()?;
+        // Replays have to run as they were recorded
+        let (seed, update_rate) = match &input_replay {
+            Some(replay) => (replay.seed, replay.update_rate),
+            None => (args.seed.unwrap_or_default(), config.update_rate),
+        };
+        let lockstep = input_replay.is_some() || args.record_input.is_some();
+        let mut app = Self {
             window,
             event_loop: Some(event_loop),
 
@@ -198,9 +1249,14 @@ impl TutorApp {
 
             physical_device,
             device,
+            capabilities: enabled_features.capabilities(),
+            device_fault,
+            checkpoints,
+            debug_messenger,
+            enabled_features,
 
-            graphics_queue,
-            present_queue,
+            queue_families,
+            queues,
 
             swapchain_ext,
             swapchain,
@@ -209,7 +1265,404 @@ impl TutorApp {
             extent,
 
             swapchain_image_views,
-        })
+            present_handoff,
+            protected_queue,
+            protected_swapchain,
+            device_group,
+            alternate_frames,
+            frames,
+            graph,
+            backbuffer,
+            capture: None,
+            graph_dump: args.dump_graph.clone().map(|dir| GraphDump {
+                dir,
+                requested: true,
+                pending: None,
+            }),
+            video: None,
+            gpu_timer,
+            mem_props,
+
+            memory_budget_ext,
+            budget_watcher: BudgetWatcher::new(Self::BUDGET_WARNING),
+
+            samplers,
+
+            input: InputMap::new(config.input.clone()),
+            camera,
+            scene,
+            sun,
+            scene_file,
+            timestep: FixedTimestep::new(update_rate).with_lockstep(lockstep),
+            visibility: Visibility::default(),
+            cursor_grabbed: false,
+            background_interval: config.background_interval(),
+            last_frame: Instant::now(),
+            sim_time: 0.0,
+            playback,
+            bench,
+            recording: args
+                .record_camera_path
+                .clone()
+                .map(|path| (path, CameraPath::new())),
+            input_recording: args
+                .record_input
+                .clone()
+                .map(|path| (path, InputRecording::new(seed, update_rate))),
+            input_replay: input_replay.map(|replay| (replay, 0)),
+            config,
+            config_path,
+            watcher,
+            swapchain_outdated: false,
+            #[cfg(feature = "gilrs")]
+            gamepads: Self::init_gamepads(),
+        };
+        if let Some(seconds) = args.capture {
+            app.capture = Some(Capture {
+                readback: app.create_readback(),
+                ring: FrameRing::new(Duration::from_secs_f64(seconds)).with_rate(Capture::RATE),
+            });
+        }
+        if let Some(path) = &args.record_video {
+            app.video = app.start_video(path)?;
+        }
+        Ok(app)
+    }
+
+    /// `None` when `family` has no timestamps, `--bench` then only reports CPU times
+    fn create_gpu_timer(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        family: u32,
+        frames_in_flight: usize,
+    ) -> anyhow::Result<Option<GpuTimer>> {
+        let families =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let valid_bits = families[family as usize].timestamp_valid_bits;
+        if valid_bits == 0 {
+            eprintln!("The graphics queue has no timestamps, passes won't be timed");
+            return Ok(None);
+        }
+        let period = unsafe { instance.get_physical_device_properties(physical_device) }
+            .limits
+            .timestamp_period;
+        let timer = GpuTimer::new(
+            device,
+            frames_in_flight,
+            Self::GPU_SCOPES,
+            period,
+            valid_bits,
+        )?;
+        Ok(Some(timer))
+    }
+
+    /// A slot per GPU with alternate frames, so each has a frame to work on at once
+    fn frames_in_flight(
+        device_group: Option<&DeviceGroup>,
+        alternate_frames: Option<&AlternateFrames>,
+    ) -> usize {
+        match (device_group, alternate_frames) {
+            (Some(group), Some(_)) => Self::FRAMES_IN_FLIGHT.max(group.len()),
+            _ => Self::FRAMES_IN_FLIGHT,
+        }
+    }
+
+    /// Clears the swapchain image, the only pass until main draws the scene
+    fn build_graph(format: vk::Format, extent: vk::Extent2D) -> (RenderGraph, ResourceId) {
+        let mut graph = RenderGraph::new();
+        let desc = ImageDesc::new(format, extent);
+        let backbuffer = graph.import_image(
+            "swapchain",
+            desc,
+            vk::ImageLayout::UNDEFINED,
+            Some(Access::Present),
+        );
+        graph.add_pass(
+            "clear",
+            |pass| {
+                pass.write(backbuffer, Access::TransferDst);
+            },
+            move |ctx| unsafe {
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                ctx.device.cmd_clear_color_image(
+                    ctx.cmd,
+                    ctx.image(backbuffer),
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &Self::CLEAR_COLOR,
+                    &[range],
+                );
+            },
+        );
+        graph.mark_output(backbuffer);
+        (graph, backbuffer)
+    }
+
+    /// Whether swapchain images can be copied from, which `create_swapchain` asks for if so
+    fn swapchain_readable(&self) -> bool {
+        let caps = unsafe {
+            self.surface_ext
+                .get_physical_device_surface_capabilities(self.physical_device, self.surface_khr)
+        };
+        !self.protected_swapchain
+            && caps.is_ok_and(|caps| {
+                caps.supported_usage_flags
+                    .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+            })
+    }
+
+    /// Reads back the current swapchain's images, `None` when they can't be
+    fn create_readback(&self) -> Option<FrameReadback> {
+        if self.protected_swapchain {
+            eprintln!("Protected frames can't be captured");
+            return None;
+        }
+        if !self.swapchain_readable() {
+            eprintln!("The surface's images can't be copied, frames won't be captured");
+            return None;
+        }
+        let frames = self.frames.fences.len();
+        match FrameReadback::new(
+            &self.device,
+            &self.mem_props,
+            frames,
+            self.format,
+            self.extent,
+        ) {
+            Ok(readback) => Some(readback),
+            Err(err) => {
+                eprintln!("Frames won't be captured: {err}");
+                None
+            }
+        }
+    }
+
+    /// Prints what device selection sees of each GPU, indexed as `--gpu` expects
+    fn list_gpus() -> anyhow::Result<()> {
+        let entry = Entry::linked();
+        let api_version = instance_version(&entry)?;
+        let app_info = vk::ApplicationInfo::builder().api_version(api_version);
+        let create_info = vk::InstanceCreateInfo::builder().application_info(&app_info);
+        let instance = unsafe { entry.create_instance(&create_info, None)? };
+
+        let devices = unsafe { instance.enumerate_physical_devices() };
+        for (index, &device) in devices.iter().flatten().enumerate() {
+            let info = unsafe { DeviceInfo::query(&instance, api_version, device) };
+            println!("GPU {index}: {} ({:?})", info.name, info.device_type);
+            println!(
+                "  Vulkan {}.{}.{}, driver {}, vendor {:#06x}, device {:#06x}",
+                vk::api_version_major(info.api_version),
+                vk::api_version_minor(info.api_version),
+                vk::api_version_patch(info.api_version),
+                info.driver_version_string(),
+                info.vendor_id,
+                info.device_id
+            );
+            println!("  Queue families:");
+            for (family, props) in info.queue_families.iter().enumerate() {
+                println!(
+                    "    {family}: {} queues, {:?}",
+                    props.queue_count, props.queue_flags
+                );
+            }
+            println!("  Memory heaps:");
+            for (heap, props) in info.memory_heaps().iter().enumerate() {
+                println!("    {heap}: {} MiB, {:?}", props.size >> 20, props.flags);
+            }
+            println!("  Extensions:");
+            for ext in Self::REPORTED_EXTENSIONS {
+                let supported = if info.supports_extension(ext) {
+                    "yes"
+                } else {
+                    "no"
+                };
+                println!("    {}: {supported}", ext.to_string_lossy());
+            }
+        }
+
+        unsafe { instance.destroy_instance(None) };
+        devices?;
+        Ok(())
+    }
+
+    /// Writes the selected device's properties, what was enabled on it and what the surface
+    /// supports, for attaching to bug reports
+    fn dump_caps(&self, path: &Path) -> anyhow::Result<()> {
+        let api_version = self.capabilities.api_version;
+        let info = unsafe { DeviceInfo::query(&self.instance, api_version, self.physical_device) };
+        let support = unsafe {
+            SwapChainSupport::new(&self.surface_ext, self.physical_device, self.surface_khr)?
+        };
+        let version = |version: u32| {
+            format!(
+                "{}.{}.{}",
+                vk::api_version_major(version),
+                vk::api_version_minor(version),
+                vk::api_version_patch(version)
+            )
+        };
+        let limits = &info.properties.limits;
+        let report = serde_json::json!({
+            "device": {
+                "name": info.name,
+                "type": format!("{:?}", info.device_type),
+                "api_version": version(info.api_version),
+                "driver_version": info.driver_version_string(),
+                "vendor_id": info.vendor_id,
+                "device_id": info.device_id,
+                "limits": {
+                    "max_image_dimension_2d": limits.max_image_dimension2_d,
+                    "max_image_array_layers": limits.max_image_array_layers,
+                    "max_push_constants_size": limits.max_push_constants_size,
+                    "max_bound_descriptor_sets": limits.max_bound_descriptor_sets,
+                    "max_sampler_anisotropy": limits.max_sampler_anisotropy,
+                    "max_compute_work_group_invocations":
+                        limits.max_compute_work_group_invocations,
+                    "timestamp_period": limits.timestamp_period,
+                    "min_uniform_buffer_offset_alignment":
+                        limits.min_uniform_buffer_offset_alignment,
+                    "framebuffer_color_sample_counts":
+                        format!("{:?}", limits.framebuffer_color_sample_counts),
+                },
+                "memory_heaps": info.memory_heaps().iter().map(|heap| serde_json::json!({
+                    "size": heap.size,
+                    "flags": format!("{:?}", heap.flags),
+                })).collect::<Vec<_>>(),
+                "queue_families": info.queue_families.iter().map(|family| serde_json::json!({
+                    "count": family.queue_count,
+                    "flags": format!("{:?}", family.queue_flags),
+                })).collect::<Vec<_>>(),
+            },
+            "enabled": {
+                "api_version": version(api_version),
+                "extensions": self
+                    .enabled_features
+                    .extensions
+                    .iter()
+                    .map(|ext| ext.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "features": Feature::ALL
+                    .iter()
+                    .filter(|&&feature| self.enabled_features.has_feature(feature))
+                    .map(|feature| format!("{feature:?}"))
+                    .collect::<Vec<_>>(),
+                "queue_families": {
+                    "graphics": self.queue_families.graphics,
+                    "present": self.queue_families.present,
+                    "compute": self.queue_families.compute,
+                    "transfer": self.queue_families.transfer,
+                },
+            },
+            "surface": {
+                "formats": support.formats.iter().map(|format| serde_json::json!({
+                    "format": format!("{:?}", format.format),
+                    "color_space": format!("{:?}", format.color_space),
+                })).collect::<Vec<_>>(),
+                "present_modes": support
+                    .present_modes
+                    .iter()
+                    .map(|mode| format!("{mode:?}"))
+                    .collect::<Vec<_>>(),
+                "min_image_count": support.capabilities.min_image_count,
+                "max_image_count": support.capabilities.max_image_count,
+            },
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+        Ok(())
+    }
+
+    /// Allocates and writes the same sets with every descriptor backend the device has
+    fn bench_descriptors(&self) -> anyhow::Result<()> {
+        const SETS: u32 = 10_000;
+        const BINDINGS: u32 = 4;
+        let mut kinds = vec![BackendKind::Sets];
+        if self.capabilities.descriptor_buffer {
+            kinds.push(BackendKind::Buffer);
+        } else {
+            eprintln!("Descriptor buffers aren't supported, only timing descriptor sets");
+        }
+        let mem_props = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(self.physical_device)
+        };
+        let mut usage = vk::BufferUsageFlags::UNIFORM_BUFFER;
+        if self.capabilities.buffer_device_address {
+            usage |= vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        }
+        let uniforms = Buffer::new(
+            &self.device,
+            &mem_props,
+            256,
+            usage,
+            vk::MemoryPropertyFlags::HOST_VISIBLE,
+        )?;
+        let bindings: Vec<_> = (0..BINDINGS)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::ALL_GRAPHICS)
+                    .build()
+            })
+            .collect();
+        let pool_sizes = [vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: SETS * BINDINGS,
+        }];
+        let descriptor = Descriptor::UniformBuffer(vk::DescriptorBufferInfo {
+            buffer: uniforms.buffer,
+            offset: 0,
+            range: uniforms.size,
+        });
+        for kind in kinds {
+            let mut descriptors = Descriptors::new(
+                &self.instance,
+                &self.device,
+                self.physical_device,
+                &mem_props,
+                &self.enabled_features,
+                kind,
+                SETS,
+                &pool_sizes,
+            )?;
+            let layout_info = vk::DescriptorSetLayoutCreateInfo::builder()
+                .flags(descriptors.set_layout_flags())
+                .bindings(&bindings);
+            let layout = unsafe {
+                self.device
+                    .create_descriptor_set_layout(&layout_info, None)?
+            };
+            leaks::track(layout, "bench descriptor set layout");
+            let start = Instant::now();
+            let result = (0..SETS).try_for_each(|_| unsafe {
+                let set = descriptors.allocate(&self.device, layout)?;
+                for binding in 0..BINDINGS {
+                    descriptors.write(&self.device, set, binding, &descriptor);
+                }
+                Ok::<_, vk::Result>(())
+            });
+            let elapsed = start.elapsed();
+            unsafe {
+                descriptors.destroy(&self.device);
+                leaks::untrack(layout);
+                self.device.destroy_descriptor_set_layout(layout, None);
+            }
+            result?;
+            println!(
+                "{kind:?}: {SETS} sets of {BINDINGS} uniform buffers in {:.2} ms, {:.0} ns per set",
+                elapsed.as_secs_f64() * 1000.0,
+                elapsed.as_nanos() as f64 / SETS as f64
+            );
+        }
+        unsafe { uniforms.destroy(&self.device) };
+        Ok(())
     }
 
     fn init_window() -> (EventLoop<()>, Window) {
@@ -224,8 +1677,12 @@ impl TutorApp {
         (event_loop, window)
     }
 
+    #[allow(clippy::type_complexity)]
     fn init_vulkan(
         window: &Window,
+        args: &Args,
+        vsync: bool,
+        validation_log: Option<&Arc<ValidationLog>>,
     ) -> anyhow::Result<(
         Entry,
         Instance,
@@ -233,37 +1690,140 @@ impl TutorApp {
         vk::SurfaceKHR,
         vk::PhysicalDevice,
         Device,
-        vk::Queue,
-        vk::Queue,
+        EnabledFeatures,
+        QueueFamilies,
+        Queues,
         ext::khr::Swapchain,
         vk::SwapchainKHR,
         Vec<vk::Image>,
         vk::Format,
         vk::Extent2D,
         Vec<vk::ImageView>,
+        bool,
+        Option<DeviceGroup>,
+        Option<AlternateFrames>,
+        Option<ext::khr::GetPhysicalDeviceProperties2>,
+        SamplerCache,
     )> {
-        let (entry, instance, rdh) = Self::create_instance(window)?;
+        let (entry, instance, api_version, rdh, props2_ext, surface_caps2_ext) =
+            Self::create_instance(window, args, validation_log)?;
         let surface_ext = ext::khr::Surface::new(&entry, &instance);
 
         let surface_khr = unsafe {
             ash_window::create_surface(&entry, &instance, rdh, window.raw_window_handle(), None)?
         };
 
-        let (physical_device, queue_ids) = Self::pick_device(&instance, &surface_ext, surface_khr)?;
-
-        let (device, graphics_queue, present_queue) =
-            Self::create_logical_device(&instance, physical_device, &queue_ids)?;
-
-        let swapchain_ext = ext::khr::Swapchain::new(&instance, &device);
-
-        let (swapchain, swapchain_images, format, extent) = Self::create_swapchain(
+        let mut requirements = DeviceRequirements::default()
+            .with_api_version(Self::MIN_API_VERSION)
+            .require_extensions(&Self::DEVICE_EXTENSIONS)
+            .request_extensions(&Self::OPTIONAL_DEVICE_EXTENSIONS)
+            .request_features(&Self::OPTIONAL_FEATURES);
+        if args.robust {
+            requirements = requirements.request_features(&Self::ROBUST_FEATURES);
+        }
+        if args.checkpoints {
+            requirements = requirements.request_extensions(&CHECKPOINT_EXTENSIONS);
+        }
+        if args.protected {
+            requirements = requirements.request_features(&[Feature::ProtectedMemory]);
+        }
+        if args.pipeline_feedback {
+            requirements =
+                requirements.request_extensions(&[vk::ExtPipelineCreationFeedbackFn::name()]);
+        }
+        if args.record_video.is_some() {
+            requirements = requirements.request_extensions(&video::ENCODE_EXTENSIONS);
+        }
+        let policy = ScoringPolicy::default()
+            .with_optional_extensions(&Self::OPTIONAL_DEVICE_EXTENSIONS)
+            .with_forced(args.gpu.clone());
+        let (physical_device, queue_families, enabled_features) = Self::pick_device(
+            &instance,
+            api_version,
             &surface_ext,
-            window,
-            &swapchain_ext,
-            physical_device,
             surface_khr,
-            &queue_ids,
+            &requirements,
+            &policy,
         )?;
+        if args.robust && !enabled_features.has_feature(Feature::RobustBufferAccess2) {
+            eprintln!("VK_EXT_robustness2 isn't supported, only robustBufferAccess is enabled");
+        }
+        let protected_queue = queue_families.protected.is_some()
+            && enabled_features.has_feature(Feature::ProtectedMemory);
+        let protected_swapchain = match &surface_caps2_ext {
+            Some(ext) if protected_queue => unsafe {
+                protected::surface_supports_protected(ext, physical_device, surface_khr)?
+            },
+            _ => false,
+        };
+        if args.protected && !protected_queue {
+            eprintln!("Protected memory isn't supported, rendering unprotected");
+        } else if args.protected && !protected_swapchain {
+            eprintln!("The surface can't present protected images, the swapchain is unprotected");
+        }
+        let device_group = if args.device_group || args.afr {
+            let group = DeviceGroup::containing(
+                &instance,
+                enabled_features.features.api_version,
+                physical_device,
+            )?;
+            if group.is_none() {
+                eprintln!("The GPU isn't linked with any other, the device spans it alone");
+            }
+            group
+        } else {
+            None
+        };
+
+        let (device, queues) = Self::create_logical_device(
+            &instance,
+            physical_device,
+            &queue_families,
+            &enabled_features,
+            device_group.as_ref(),
+        )?;
+        let memory_budget_ext =
+            props2_ext.filter(|_| enabled_features.has_extension(vk::ExtMemoryBudgetFn::name()));
+
+        let max_anisotropy = enabled_features
+            .has_feature(Feature::SamplerAnisotropy)
+            .then(|| unsafe {
+                instance
+                    .get_physical_device_properties(physical_device)
+                    .limits
+                    .max_sampler_anisotropy
+            });
+        let samplers = SamplerCache::new(Self::FILTER_QUALITY, max_anisotropy);
+
+        let swapchain_ext = ext::khr::Swapchain::new(&instance, &device);
+        let mut alternate_frames = match &device_group {
+            Some(group) if args.afr => {
+                // Every device gets a frame to work on at once
+                let in_flight = Self::FRAMES_IN_FLIGHT.max(group.len());
+                let frames =
+                    AlternateFrames::new(&instance, &device, group, surface_khr, in_flight)?;
+                if frames.is_none() {
+                    eprintln!("Not every GPU of the group can present, frames aren't alternated");
+                }
+                frames
+            }
+            _ => None,
+        };
+
+        let (swapchain, swapchain_images, format, extent) = Self::create_swapchain(
+            &surface_ext,
+            window,
+            &swapchain_ext,
+            physical_device,
+            surface_khr,
+            vsync,
+            protected_swapchain,
+            alternate_frames.as_ref(),
+            vk::SwapchainKHR::null(),
+        )?;
+        if let Some(frames) = &mut alternate_frames {
+            unsafe { frames.set_images(&device, swapchain_images.len())? };
+        }
 
         let swapchain_image_views = Self::create_image_views(&device, &swapchain_images, format)?;
 
@@ -274,74 +1834,158 @@ impl TutorApp {
             surface_khr,
             physical_device,
             device,
-            graphics_queue,
-            present_queue,
+            enabled_features,
+            queue_families,
+            queues,
             swapchain_ext,
             swapchain,
             swapchain_images,
             format,
             extent,
             swapchain_image_views,
+            protected_swapchain,
+            device_group,
+            alternate_frames,
+            memory_budget_ext,
+            samplers,
         ))
     }
-    fn create_instance(window: &Window) -> anyhow::Result<(Entry, Instance, RawDisplayHandle)> {
+    #[allow(clippy::type_complexity)]
+    fn create_instance(
+        window: &Window,
+        args: &Args,
+        validation_log: Option<&Arc<ValidationLog>>,
+    ) -> anyhow::Result<(
+        Entry,
+        Instance,
+        u32,
+        RawDisplayHandle,
+        Option<ext::khr::GetPhysicalDeviceProperties2>,
+        Option<ext::khr::GetSurfaceCapabilities2>,
+    )> {
         let entry = Entry::linked();
-        let app_info = vk::ApplicationInfo::builder().api_version(vk::make_api_version(0, 1, 0, 0));
+        let api_version = instance_version(&entry)?;
+        let app_info = vk::ApplicationInfo::builder().api_version(api_version);
         let rdh = window.raw_display_handle();
-        let exts = ash_window::enumerate_required_extensions(rdh)?;
-        let create_info = vk::InstanceCreateInfo::builder()
+        let mut exts = ash_window::enumerate_required_extensions(rdh)?.to_vec();
+
+        let available = entry.enumerate_instance_extension_properties(None)?;
+        let has_extension = |name: &CStr| {
+            available
+                .iter()
+                .any(|prop| unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) } == name)
+        };
+        // Needed on 1.0 to query memory budgets
+        let props2_name = ext::khr::GetPhysicalDeviceProperties2::name();
+        let has_props2 = has_extension(props2_name);
+        if has_props2 {
+            exts.push(props2_name.as_ptr());
+        }
+        let has_surface_protected = args.protected
+            && SURFACE_PROTECTED_EXTENSIONS
+                .iter()
+                .all(|&name| has_extension(name));
+        if has_surface_protected {
+            exts.extend(SURFACE_PROTECTED_EXTENSIONS.map(CStr::as_ptr));
+        }
+
+        let mut selection = LayerSelection::default().with_layers(args.layers.iter().cloned());
+        if !args.validation.is_empty() || validation_log.is_some() {
+            selection = selection.with_layers([VALIDATION_LAYER.to_string_lossy()]);
+        }
+        let layers = selection.with_env().resolve(&entry)?;
+        for missing in &layers.missing {
+            eprintln!("Instance layer {missing} isn't installed, continuing without it");
+        }
+        if validation_log.is_some() {
+            // A report without the layer would pass no matter what
+            anyhow::ensure!(
+                layers.is_enabled(VALIDATION_LAYER),
+                "--validation-report needs the validation layer, which isn't installed"
+            );
+            // The layer provides the extension
+            exts.push(DebugMessenger::extension_name().as_ptr());
+        }
+        let layer_names = layers.names();
+
+        let validation_enables = args.validation.enables();
+        let has_validation_features = !args.validation.is_empty()
+            && layers.is_enabled(VALIDATION_LAYER)
+            && entry
+                .enumerate_instance_extension_properties(Some(VALIDATION_LAYER))?
+                .iter()
+                .any(|prop| {
+                    let name = unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) };
+                    name == ValidationFeatures::extension_name()
+                });
+        if has_validation_features {
+            exts.push(ValidationFeatures::extension_name().as_ptr());
+        } else if !args.validation.is_empty() {
+            eprintln!("Validation features aren't available, --validate is ignored");
+        }
+        let mut validation_features =
+            vk::ValidationFeaturesEXT::builder().enabled_validation_features(&validation_enables);
+
+        let mut create_info = vk::InstanceCreateInfo::builder()
             .application_info(&app_info)
-            .enabled_extension_names(exts);
+            .enabled_layer_names(&layer_names)
+            .enabled_extension_names(&exts);
+        if has_validation_features {
+            create_info = create_info.push_next(&mut validation_features);
+        }
+        let mut messenger_info = validation_log.map(DebugMessenger::create_info);
+        if let Some(messenger_info) = &mut messenger_info {
+            create_info = create_info.push_next(messenger_info);
+        }
         let instance = unsafe { entry.create_instance(&create_info, None)? };
-        Ok((entry, instance, rdh))
+        let props2_ext =
+            has_props2.then(|| ext::khr::GetPhysicalDeviceProperties2::new(&entry, &instance));
+        let surface_caps2_ext = has_surface_protected
+            .then(|| ext::khr::GetSurfaceCapabilities2::new(&entry, &instance));
+        Ok((
+            entry,
+            instance,
+            api_version,
+            rdh,
+            props2_ext,
+            surface_caps2_ext,
+        ))
     }
 
+    #[allow(clippy::needless_borrow)]
     fn pick_device(
         instance: &Instance,
+        instance_version: u32,
         surface_ext: &ext::khr::Surface,
         khr_surface: vk::SurfaceKHR,
-    ) -> anyhow::Result<(vk::PhysicalDevice, QueueIndexes)> {
+        requirements: &DeviceRequirements,
+        policy: &ScoringPolicy,
+    ) -> anyhow::Result<(vk::PhysicalDevice, QueueFamilies, EnabledFeatures)> {
         let devices = unsafe { instance.enumerate_physical_devices()? };
 
-        let (_, &device, queue_ids) = devices
+        let best = devices
             .iter()
-            .filter_map(|dev| {
-                let mut score = 0;
-
-                let queues = unsafe { instance.get_physical_device_queue_family_properties(*dev) };
-
-                let queue_ids = match queues.iter().enumerate().try_fold(
-                    [None, None],
-                    |acc, (queue_i, queue)| {
-                        QueueIndexes::fold_into(
-                            &surface_ext,
-                            *dev,
-                            khr_surface,
-                            acc,
-                            queue_i,
-                            queue,
-                        )
-                    },
-                ) {
-                    Ok([Some(graphics), Some(present)]) => QueueIndexes { graphics, present },
-                    Err([Some(graphics), Some(present)]) => QueueIndexes { graphics, present },
-                    _ => return None,
+            .enumerate()
+            .filter_map(|(index, dev)| {
+                let info = unsafe { DeviceInfo::query(instance, instance_version, *dev) };
+                if !policy.allows(index, &info) {
+                    return None;
+                }
+                let enabled = match requirements.negotiate(&info) {
+                    Ok(enabled) => enabled,
+                    Err(unsupported) => {
+                        eprintln!("Skipping GPU {index}: {unsupported}");
+                        return None;
+                    }
                 };
 
-                let props = unsafe { instance.get_physical_device_properties(*dev) };
+                let queues = unsafe { instance.get_physical_device_queue_family_properties(*dev) };
 
-                let extensions: Vec<&CStr> =
-                    unsafe { instance.enumerate_device_extension_properties(*dev) }
-                        .ok()?
-                        .iter()
-                        .map(|prop| unsafe { CStr::from_ptr(prop.extension_name.as_ptr()) })
-                        .collect();
-                if Self::DEVICE_EXTENSIONS
-                    .iter()
-                    .any(|ext| !extensions.contains(ext))
-                {
-                    return None;
-                }
+                let queue_families = QueueFamilies::select(&queues, |family| unsafe {
+                    surface_ext
+                        .get_physical_device_surface_support(*dev, family, khr_surface)
+                        .unwrap_or(false)
+                })?;
 
                 let swapchain_support =
                     unsafe { SwapChainSupport::new(&surface_ext, *dev, khr_surface).ok()? };
@@ -351,63 +1995,82 @@ impl TutorApp {
                     return None;
                 }
 
-                score += props.limits.max_image_dimension2_d;
-
-                if score > 0 {
-                    Some((score, dev, queue_ids))
-                } else {
-                    None
-                }
+                Some((policy.score(&info), dev, queue_families, enabled))
             })
-            .max_by(|(score1, ..), (score2, ..)| score1.cmp(score2))
-            .expect("Failed to find a suitable GPU");
+            .max_by(|(score1, ..), (score2, ..)| score1.cmp(score2));
 
-        Ok((device, queue_ids))
+        match (best, &policy.forced) {
+            (Some((_, &device, queue_families, enabled)), _) => {
+                Ok((device, queue_families, enabled))
+            }
+            (None, Some(forced)) => Err(anyhow::anyhow!(
+                "GPU {forced:?} doesn't exist or can't present to the window"
+            )),
+            (None, None) => Err(anyhow::anyhow!("Failed to find a suitable GPU")),
+        }
     }
 
     fn create_logical_device(
         instance: &Instance,
         device: vk::PhysicalDevice,
-        queue_ids: &QueueIndexes,
-    ) -> anyhow::Result<(Device, vk::Queue, vk::Queue)> {
+        queue_families: &QueueFamilies,
+        enabled: &EnabledFeatures,
+        device_group: Option<&DeviceGroup>,
+    ) -> anyhow::Result<(Device, Queues)> {
         let queue_priorities = [1.];
 
-        let mut queue_info = vec![vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(queue_ids.graphics)
-            .queue_priorities(&queue_priorities)
-            .build()];
-
-        if queue_ids.graphics != queue_ids.present {
-            queue_info.push(
+        let mut queue_info: Vec<_> = queue_families
+            .unique()
+            .into_iter()
+            .map(|family| {
                 vk::DeviceQueueCreateInfo::builder()
-                    .queue_family_index(queue_ids.present)
+                    .queue_family_index(family)
                     .queue_priorities(&queue_priorities)
-                    .build(),
-            )
-        }
-
-        let exts = Self::DEVICE_EXTENSIONS.map(|str| str.as_ptr());
-        let features = vk::PhysicalDeviceFeatures::default();
-        let device_create_info = vk::DeviceCreateInfo::builder()
+                    .build()
+            })
+            .collect();
+        // Protected queues are created separately, even from a family already in the list
+        queue_info.extend(protected::queue_create_info(
+            queue_families,
+            enabled,
+            &queue_priorities,
+        ));
+
+        let exts: Vec<_> = enabled.extensions.iter().map(|str| str.as_ptr()).collect();
+        let mut features = enabled.features.clone();
+        let mut features2 = features.chain();
+        let mut device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_info)
-            .enabled_extension_names(&exts)
-            .enabled_features(&features);
+            .enabled_extension_names(&exts);
+        // The pNext chain needs 1.1, before that only the core features can be enabled
+        device_create_info = if enabled.features.api_version >= vk::API_VERSION_1_1 {
+            device_create_info.push_next(&mut features2)
+        } else {
+            device_create_info.enabled_features(&enabled.features.core)
+        };
+        let mut group_info = device_group.map(DeviceGroup::create_info);
+        if let Some(group_info) = &mut group_info {
+            device_create_info = device_create_info.push_next(group_info);
+        }
 
         let device = unsafe { instance.create_device(device, &device_create_info, None)? };
 
-        let graphics_queue = unsafe { device.get_device_queue(queue_ids.graphics, 0) };
-        let present_queue = unsafe { device.get_device_queue(queue_ids.present, 0) };
+        let queues = unsafe { Queues::get(&device, queue_families) };
 
-        Ok((device, graphics_queue, present_queue))
+        Ok((device, queues))
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn create_swapchain(
         surface_ext: &ext::khr::Surface,
         window: &Window,
         swapchain_ext: &ext::khr::Swapchain,
         physical_device: vk::PhysicalDevice,
         khr_surface: vk::SurfaceKHR,
-        queue_ids: &QueueIndexes,
+        vsync: bool,
+        protected: bool,
+        alternate_frames: Option<&AlternateFrames>,
+        old_swapchain: vk::SwapchainKHR,
     ) -> anyhow::Result<(vk::SwapchainKHR, Vec<vk::Image>, vk::Format, vk::Extent2D)> {
         let sc_support =
             unsafe { SwapChainSupport::new(surface_ext, physical_device, khr_surface)? };
@@ -423,36 +2086,50 @@ impl TutorApp {
             }
         };
         let surface_format = sc_support.choose_swap_surface_format();
-        let present = sc_support.choose_swap_present_mode();
+        let present = sc_support.choose_swap_present_mode(vsync);
         let extent = sc_support.get_swap_extent(window);
+        let supported_usage = sc_support.capabilities.supported_usage_flags;
+        // Frames are cleared with a transfer until the scene is drawn
+        if !supported_usage.contains(vk::ImageUsageFlags::TRANSFER_DST) {
+            anyhow::bail!("the surface's images can't be cleared with transfers");
+        }
+        // Lets frames be read back for captures where the surface allows it
+        let usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | (supported_usage & vk::ImageUsageFlags::TRANSFER_SRC);
 
+        let flags = if protected {
+            vk::SwapchainCreateFlagsKHR::PROTECTED
+        } else {
+            vk::SwapchainCreateFlagsKHR::empty()
+        };
         let builder = vk::SwapchainCreateInfoKHR::builder()
+            .flags(flags)
             .surface(khr_surface)
             .min_image_count(image_count)
             .image_format(surface_format.format)
             .image_color_space(surface_format.color_space)
             .image_extent(extent)
             .image_array_layers(1)
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
+            .image_usage(usage)
             .pre_transform(sc_support.capabilities.current_transform)
             .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
             .present_mode(present)
-            .old_swapchain(vk::SwapchainKHR::null());
+            .old_swapchain(old_swapchain);
 
-        let q_ids = queue_ids.as_array();
-        let swapchain_info = if queue_ids.graphics == queue_ids.present {
-            builder.image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-        } else {
-            builder
-                .image_sharing_mode(vk::SharingMode::CONCURRENT)
-                .queue_family_indices(&q_ids)
-        };
+        // Exclusive even with a separate present family, a `PresentHandoff` moves images over
+        let mut swapchain_info = builder.image_sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let mut group_info = alternate_frames.map(AlternateFrames::swapchain_create_info);
+        if let Some(group_info) = &mut group_info {
+            swapchain_info = swapchain_info.push_next(group_info);
+        }
         let swapchain = unsafe { swapchain_ext.create_swapchain(&swapchain_info, None)? };
         let swapchain_images = unsafe { swapchain_ext.get_swapchain_images(swapchain)? };
 
         Ok((swapchain, swapchain_images, surface_format.format, extent))
     }
 
+    #[allow(clippy::ptr_arg)]
     fn create_image_views(
         device: &Device,
         images: &Vec<vk::Image>,
@@ -506,31 +2183,832 @@ impl TutorApp {
                     ..
                 } => {
                     println!("Closing!");
+                    self.save_recording();
                     elwt.exit();
                 }
-                Event::AboutToWait => {
-                    self.window.request_redraw();
+                Event::WindowEvent { event, .. } if self.input.handle_window_event(&event) => {}
+                Event::DeviceEvent { event, .. } => self.input.handle_device_event(&event),
+                Event::WindowEvent {
+                    event: WindowEvent::Focused(focused),
+                    ..
+                } => self.set_visibility(Visibility {
+                    focused,
+                    ..self.visibility
+                }),
+                Event::WindowEvent {
+                    event: WindowEvent::Occluded(occluded),
+                    ..
+                } => self.set_visibility(Visibility {
+                    occluded,
+                    ..self.visibility
+                }),
+                Event::WindowEvent {
+                    event: WindowEvent::Resized(size),
+                    ..
+                } => {
+                    self.swapchain_outdated = true;
+                    self.set_visibility(Visibility {
+                        minimized: size.width == 0 || size.height == 0,
+                        ..self.visibility
+                    })
                 }
+                Event::AboutToWait => match self.power_mode() {
+                    PowerMode::Full => {
+                        elwt.set_control_flow(ControlFlow::Poll);
+                        self.window.request_redraw();
+                    }
+                    PowerMode::Background => {
+                        let interval = self.background_interval.unwrap_or_default();
+                        let next_frame = self.last_frame + interval;
+                        if Instant::now() >= next_frame {
+                            self.window.request_redraw();
+                            elwt.set_control_flow(ControlFlow::wait_duration(interval));
+                        } else {
+                            elwt.set_control_flow(ControlFlow::WaitUntil(next_frame));
+                        }
+                    }
+                    PowerMode::Suspended => elwt.set_control_flow(ControlFlow::Wait),
+                },
                 Event::WindowEvent {
                     event: WindowEvent::RedrawRequested,
                     ..
-                } => {}
+                } => {
+                    let changed = self.watcher.poll();
+                    if changed.contains(&self.config_path) {
+                        self.reload_config();
+                    }
+                    if let Some(loaded) = &mut self.scene_file {
+                        if changed.contains(&loaded.path) {
+                            println!("Reloading {}", loaded.path.display());
+                            if let Err(err) = loaded.reload(&mut self.scene) {
+                                eprintln!("Keeping the current scene: {err:#}");
+                            }
+                        }
+                    }
+                    // Nothing can be presented to a minimized window anyway
+                    if self.swapchain_outdated && !self.visibility.minimized {
+                        match self.recreate_swapchain() {
+                            Ok(()) => self.swapchain_outdated = false,
+                            // Tried again next frame
+                            Err(err) => eprintln!("Couldn't recreate the swapchain: {err:#}"),
+                        }
+                    }
+                    #[cfg(feature = "gilrs")]
+                    self.poll_gamepads();
+                    self.last_frame = Instant::now();
+                    let frame = self.timestep.advance(self.last_frame);
+                    for _ in 0..frame.steps {
+                        if !self.update(self.timestep.dt()) {
+                            println!("Closing!");
+                            self.save_recording();
+                            elwt.exit();
+                            return;
+                        }
+                    }
+                    let updated = Instant::now();
+                    if let Err(err) = self.render(frame.alpha) {
+                        eprintln!("Rendering failed: {err:#}");
+                        self.save_recording();
+                        elwt.exit();
+                        return;
+                    }
+                    if let Some(bench) = &mut self.bench {
+                        let rendered = Instant::now();
+                        bench
+                            .timings
+                            .record_cpu("update", updated - self.last_frame);
+                        bench.timings.record_cpu("render", rendered - updated);
+                        if bench.frame(rendered) {
+                            bench.save();
+                            self.save_recording();
+                            elwt.exit();
+                        }
+                    }
+                }
                 _ => (),
             })?;
         Ok(())
     }
 }
 
+impl TutorApp {
+    fn power_mode(&self) -> PowerMode {
+        // Benchmarks would measure the throttling otherwise
+        if self.bench.is_some() && !self.visibility.minimized {
+            return PowerMode::Full;
+        }
+        if self.visibility.occluded || self.visibility.minimized {
+            PowerMode::Suspended
+        } else if self.visibility.focused {
+            PowerMode::Full
+        } else if self.background_interval.is_some() {
+            PowerMode::Background
+        } else {
+            PowerMode::Suspended
+        }
+    }
+
+    fn set_visibility(&mut self, visibility: Visibility) {
+        if !visibility.focused && self.cursor_grabbed {
+            self.set_cursor_grabbed(false);
+        }
+        let previous = self.power_mode();
+        self.visibility = visibility;
+        let mode = self.power_mode();
+        if previous == PowerMode::Suspended && mode != PowerMode::Suspended {
+            // Time spent asleep isn't simulated
+            self.timestep.reset();
+        }
+    }
+
+    /// Advances the simulation by one step of `dt` seconds, returning whether to keep running
+    ///
+    /// Input is consumed by the first step after it arrives, so presses aren't repeated when
+    /// a frame runs several steps.
+    fn update(&mut self, dt: f32) -> bool {
+        if let Some((replay, step)) = &mut self.input_replay {
+            let Some(snapshot) = replay.steps.get(*step) else {
+                println!("Input replay finished after {step} steps");
+                return false;
+            };
+            self.input.set_replay(Some(snapshot.clone()));
+            *step += 1;
+        }
+        if let Some((_, recording)) = &mut self.input_recording {
+            recording.steps.push(self.input.snapshot());
+        }
+        self.sim_time += dt;
+        if self.input.just_pressed("toggle_camera") {
+            self.camera.toggle();
+        }
+        if let Some(playback) = &mut self.playback {
+            let duration = playback.path.duration();
+            let time = if playback.looping && duration > 0.0 {
+                self.sim_time % duration
+            } else {
+                self.sim_time
+            };
+            match playback.path.sample(time) {
+                Some(transform) => {
+                    self.camera = CameraController::Fly(FlyCamera::from_transform(&transform))
+                }
+                None => {
+                    playback.report(self.sim_time);
+                    return false;
+                }
+            }
+        } else {
+            self.camera.update(&self.input, &self.config.camera, dt);
+        }
+        if let Some((_, path)) = &mut self.recording {
+            path.record(self.sim_time, &self.camera.transform());
+        }
+        if self.input.just_pressed("dump_frames") {
+            self.dump_frames();
+        }
+        if self.input.just_pressed("dump_graph") {
+            match &mut self.graph_dump {
+                Some(dump) => dump.requested = true,
+                None => eprintln!("Run with --dump-graph <dir> to dump frames' images"),
+            }
+        }
+        if self.input.just_pressed("toggle_grab") {
+            self.set_cursor_grabbed(!self.cursor_grabbed);
+        }
+        let mut running = true;
+        if self.input.just_pressed("quit") {
+            // Gives the cursor back first, as games do
+            if self.cursor_grabbed {
+                self.set_cursor_grabbed(false);
+            } else {
+                running = false;
+            }
+        }
+        self.input.end_frame();
+        running
+    }
+
+    /// Applies what changed in the config file, keeping the current settings if it's invalid
+    fn reload_config(&mut self) {
+        let config = match Config::load(&self.config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("Keeping the current config: {err:#}");
+                return;
+            }
+        };
+        let config = Config {
+            vsync: config.vsync && self.bench.is_none(),
+            ..config
+        };
+        if config == self.config {
+            return;
+        }
+        println!("Reloading {}", self.config_path.display());
+        if config.input != self.config.input {
+            self.input.set_bindings(config.input.clone());
+        }
+        if config.update_rate != self.config.update_rate {
+            if self.input_recording.is_some() || self.input_replay.is_some() {
+                eprintln!("update_rate can't change while recording or replaying input");
+            } else {
+                self.timestep = FixedTimestep::new(config.update_rate);
+            }
+        }
+        self.background_interval = config.background_interval();
+        if config.light != self.config.light {
+            if let Some(sun) = self.scene.get_mut(self.sun) {
+                config.light.apply(sun);
+            }
+            self.scene.update_transforms();
+        }
+        // Can't change under a frame in flight, deferred until the device is idle
+        if config.vsync != self.config.vsync {
+            self.swapchain_outdated = true;
+        }
+        self.config = config;
+    }
+
+    /// Replaces the swapchain for the current window size and `vsync` setting
+    ///
+    /// The old swapchain is handed over to the new one and destroyed either way, as creating
+    /// retires it even when it fails. The swapchain is left null until a later call succeeds.
+    fn recreate_swapchain(&mut self) -> anyhow::Result<()> {
+        unsafe { self.device.device_wait_idle()? };
+        let created = Self::create_swapchain(
+            &self.surface_ext,
+            &self.window,
+            &self.swapchain_ext,
+            self.physical_device,
+            self.surface_khr,
+            self.config.vsync,
+            self.protected_swapchain,
+            self.alternate_frames.as_ref(),
+            self.swapchain,
+        );
+        unsafe {
+            for view in self.swapchain_image_views.drain(..) {
+                self.device.destroy_image_view(view, None);
+            }
+            if let Some(mut handoff) = self.present_handoff.take() {
+                handoff.destroy(&self.device);
+            }
+            self.swapchain_ext.destroy_swapchain(self.swapchain, None);
+        }
+        self.swapchain = vk::SwapchainKHR::null();
+        self.swapchain_images.clear();
+        let (swapchain, images, format, extent) = created?;
+        self.swapchain = swapchain;
+        self.swapchain_images = images;
+        if let Some(frames) = &mut self.alternate_frames {
+            unsafe { frames.set_images(&self.device, self.swapchain_images.len())? };
+        }
+        unsafe {
+            self.frames
+                .set_images(&self.device, self.swapchain_images.len())?
+        };
+        self.format = format;
+        self.extent = extent;
+        self.swapchain_image_views =
+            Self::create_image_views(&self.device, &self.swapchain_images, format)?;
+        self.present_handoff =
+            PresentHandoff::new(&self.device, &self.queue_families, &self.swapchain_images)?;
+        unsafe { self.graph.destroy(&self.device) };
+        (self.graph, self.backbuffer) = Self::build_graph(format, extent);
+        self.graph.set_dumpable(self.graph_dump.is_some());
+        if let Some(mut readback) = self.capture.as_mut().and_then(|c| c.readback.take()) {
+            unsafe { readback.destroy(&self.device) };
+        }
+        if self.capture.is_some() {
+            let readback = self.create_readback();
+            if let Some(capture) = &mut self.capture {
+                capture.readback = readback;
+            }
+        }
+        self.restart_video();
+        Ok(())
+    }
+
+    fn save_recording(&self) {
+        if let Some((file, path)) = &self.recording {
+            match path.save(file) {
+                Ok(()) => println!(
+                    "Saved {} camera keyframes to {}",
+                    path.keyframes().len(),
+                    file.display()
+                ),
+                Err(err) => {
+                    eprintln!("Couldn't save the camera path to {}: {err}", file.display())
+                }
+            }
+        }
+        if let Some((file, recording)) = &self.input_recording {
+            match recording.save(file) {
+                Ok(()) => println!(
+                    "Saved {} steps of input to {}",
+                    recording.steps.len(),
+                    file.display()
+                ),
+                Err(err) => eprintln!("Couldn't save the input to {}: {err}", file.display()),
+            }
+        }
+    }
+
+    /// Grabs or releases the cursor, looking with raw mouse motion while it's grabbed
+    fn set_cursor_grabbed(&mut self, grabbed: bool) {
+        let result = if grabbed {
+            input::grab_cursor(&self.window).map(|_| ())
+        } else {
+            input::release_cursor(&self.window)
+        };
+        match result {
+            Ok(()) => {
+                self.cursor_grabbed = grabbed;
+                self.input.set_raw_motion(grabbed);
+            }
+            Err(err) => eprintln!("Couldn't change the cursor grab: {err}"),
+        }
+    }
+
+    /// Draws the state `alpha` of the way from the previous step to the latest one
+    fn render(&mut self, _alpha: f32) -> anyhow::Result<()> {
+        if let Some(playback) = &mut self.playback {
+            // Startup doesn't count
+            if playback.frames == 0 {
+                playback.started = Instant::now();
+            }
+            playback.frames += 1;
+        }
+        self.check_memory_budget();
+        for feedback in pipeline::take_feedback() {
+            eprintln!("Built pipeline {feedback}");
+        }
+        // Left null when recreating it failed, tried again next frame
+        if self.swapchain == vk::SwapchainKHR::null() {
+            return Ok(());
+        }
+        let slot = self.frames.slot(self.alternate_frames.as_ref());
+        let fence = self.frames.fences[slot];
+        unsafe { self.device.wait_for_fences(&[fence], true, u64::MAX)? };
+        self.collect_frame(slot);
+
+        let acquired = match &self.alternate_frames {
+            Some(frames) => frames.acquire(self.swapchain, u64::MAX),
+            None => unsafe {
+                self.swapchain_ext.acquire_next_image(
+                    self.swapchain,
+                    u64::MAX,
+                    self.frames.acquired[slot],
+                    vk::Fence::null(),
+                )
+            },
+        };
+        let index = match acquired {
+            Ok((index, suboptimal)) => {
+                self.swapchain_outdated |= suboptimal;
+                index
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.swapchain_outdated = true;
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+        unsafe { self.device.reset_fences(&[fence])? };
+        let cmd = self.record_frame(slot, index)?;
+        let suboptimal = self.submit_frame(slot, index, cmd);
+        self.encode_video()?;
+        self.frames.frame += 1;
+        match suboptimal {
+            Ok(suboptimal) => self.swapchain_outdated |= suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => self.swapchain_outdated = true,
+            Err(err) => return Err(err.into()),
+        }
+        Ok(())
+    }
+
+    /// Takes what `slot`'s last frame read back, once its fence has been waited on
+    fn collect_frame(&mut self, slot: usize) {
+        self.poll_video();
+        if let Some(capture) = &mut self.capture {
+            if let Some(frame) = capture.readback.as_mut().and_then(|r| r.poll(slot)) {
+                capture.ring.push(frame);
+            }
+        }
+        if let (Some(timer), Some(bench)) = (&self.gpu_timer, &mut self.bench) {
+            match timer.poll(&self.device, slot) {
+                Ok(scopes) => bench.timings.record_gpu(scopes),
+                Err(err) => eprintln!("Couldn't read the pass timings: {err}"),
+            }
+        }
+        let Some(graph_dump) = &mut self.graph_dump else {
+            return;
+        };
+        if !matches!(graph_dump.pending, Some((pending, ..)) if pending == slot) {
+            return;
+        }
+        let (_, frame, mut dump) = graph_dump.pending.take().unwrap();
+        let dir = graph_dump.dir.join(format!("frame_{frame:05}"));
+        match dump.save(&dir) {
+            Ok(report) => {
+                println!("Saved {} images to {}", report.written.len(), dir.display());
+                for (name, format) in report.skipped {
+                    eprintln!("Couldn't save {name}, {format:?} has no file encoding");
+                }
+            }
+            Err(err) => eprintln!("Couldn't save the frame to {}: {err}", dir.display()),
+        }
+        unsafe { dump.destroy(&self.device) };
+    }
+
+    /// Records the frame rendering swapchain image `index` into `slot`'s command buffer
+    fn record_frame(&mut self, slot: usize, index: u32) -> anyhow::Result<vk::CommandBuffer> {
+        let cmd = self.frames.commands[slot];
+        let image = index as usize;
+        let mut group_info = vk::DeviceGroupCommandBufferBeginInfo::builder();
+        let mut begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        if let Some(frames) = &self.alternate_frames {
+            group_info = group_info.device_mask(frames.device_mask());
+            begin_info = begin_info.push_next(&mut group_info);
+        }
+        unsafe {
+            self.device
+                .reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+            self.device.begin_command_buffer(cmd, &begin_info)?;
+        }
+        self.graph.set_image(
+            self.backbuffer,
+            self.swapchain_images[image],
+            self.swapchain_image_views[image],
+        );
+        // Even when not timing this frame, so the slot's previous scopes aren't read again
+        if let Some(timer) = &mut self.gpu_timer {
+            timer.begin_frame(&self.device, cmd, slot);
+        }
+        let readable = self.graph_dump.is_some() && self.swapchain_readable();
+        let graph_dump = self
+            .graph_dump
+            .as_mut()
+            .filter(|dump| dump.requested && dump.pending.is_none());
+        if let Some(graph_dump) = graph_dump {
+            graph_dump.requested = false;
+            let mut dump = FrameDump::new().with_imported(readable);
+            let executed =
+                self.graph
+                    .execute_with_dump(&self.device, &self.mem_props, cmd, &mut dump);
+            // Destroyed once the frame is collected, or with the app
+            graph_dump.pending = Some((slot, self.frames.frame, dump));
+            executed?;
+        } else if let Some(timer) = &mut self.gpu_timer {
+            self.graph
+                .execute_timed(&self.device, &self.mem_props, cmd, timer, slot)?;
+        } else if let Some(checkpoints) = &mut self.checkpoints {
+            self.graph
+                .execute_with_checkpoints(&self.device, &self.mem_props, cmd, checkpoints)?;
+        } else {
+            self.graph.execute(&self.device, &self.mem_props, cmd)?;
+        }
+        if let Some(capture) = &mut self.capture {
+            if let Some(readback) = &mut capture.readback {
+                if capture.ring.wants_frame(Instant::now()) {
+                    readback.record_copy(
+                        &self.device,
+                        cmd,
+                        slot,
+                        self.swapchain_images[image],
+                        vk::ImageLayout::PRESENT_SRC_KHR,
+                    );
+                }
+            }
+        }
+        self.record_video(cmd, image);
+        if let Some(handoff) = &self.present_handoff {
+            handoff.record_release(&self.device, cmd, image);
+        }
+        unsafe { self.device.end_command_buffer(cmd)? };
+        Ok(cmd)
+    }
+
+    /// Submits and presents `slot`'s frame, giving whether the swapchain is suboptimal
+    fn submit_frame(&mut self, slot: usize, index: u32, cmd: vk::CommandBuffer) -> VkResult<bool> {
+        let fence = self.frames.fences[slot];
+        // The graph's first barrier comes from the top of the pipe
+        let wait_stage = vk::PipelineStageFlags::ALL_COMMANDS;
+        if let Some(frames) = &mut self.alternate_frames {
+            frames.submit(
+                &self.device,
+                self.queues.graphics,
+                index,
+                &[cmd],
+                wait_stage,
+                fence,
+            )?;
+            return frames.present(
+                &self.swapchain_ext,
+                self.queues.present,
+                self.swapchain,
+                index,
+            );
+        }
+        let image = index as usize;
+        let rendered = match &self.present_handoff {
+            Some(handoff) => handoff.released(image),
+            None => self.frames.rendered[image],
+        };
+        #[allow(unused_mut)]
+        let mut signal = vec![rendered];
+        signal.extend(self.video.as_ref().and_then(|video| video.converted));
+        let submit = vk::SubmitInfo::builder()
+            .wait_semaphores(&self.frames.acquired[slot..=slot])
+            .wait_dst_stage_mask(std::slice::from_ref(&wait_stage))
+            .command_buffers(std::slice::from_ref(&cmd))
+            .signal_semaphores(&signal);
+        unsafe {
+            match self
+                .protected_queue
+                .as_ref()
+                .filter(|_| self.protected_swapchain)
+            {
+                Some(queue) => queue.submit(&self.device, submit, fence)?,
+                None => traced!(
+                    "vkQueueSubmit",
+                    queue = self.queues.graphics,
+                    slot = slot;
+                    self.device.queue_submit(self.queues.graphics, &[*submit], fence)
+                )?,
+            }
+        }
+        if let Some(video) = &mut self.video {
+            video.converted = None;
+        }
+        let wait = match &self.present_handoff {
+            Some(handoff) => handoff.acquire(&self.device, self.queues.present, image)?,
+            None => rendered,
+        };
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(std::slice::from_ref(&wait))
+            .swapchains(std::slice::from_ref(&self.swapchain))
+            .image_indices(std::slice::from_ref(&index));
+        unsafe {
+            traced!(
+                "vkQueuePresentKHR",
+                queue = self.queues.present,
+                image = index;
+                self.swapchain_ext.queue_present(self.queues.present, &present_info)
+            )
+        }
+    }
+
+    /// Saves the frames `--capture` kept, for the `dump_frames` action
+    fn dump_frames(&self) {
+        let Some(capture) = &self.capture else {
+            eprintln!("Run with --capture <seconds> to keep frames to dump");
+            return;
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let dir = Path::new(Capture::DIR).join(time.to_string());
+        match capture.ring.dump(&dir) {
+            Ok(count) => println!("Saved {count} frames to {}", dir.display()),
+            Err(err) => eprintln!("Couldn't save frames to {}: {err}", dir.display()),
+        }
+    }
+}
+
+impl TutorApp {
+    /// Starts `--record-video`, `None` when the device can't encode video
+    fn start_video(&mut self, path: &Path) -> anyhow::Result<Option<VideoRecording>> {
+        let Some(fns) = VideoEncodeFns::new(&self.entry, &self.instance, &self.enabled_features)
+        else {
+            eprintln!("Video encoding isn't supported, --record-video does nothing");
+            return Ok(None);
+        };
+        let file =
+            File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+        let encoder = self.create_video_encoder(&fns);
+        Ok(Some(VideoRecording {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            fns,
+            encoder,
+            converted: None,
+            stream: Vec::new(),
+        }))
+    }
+
+    /// Encodes the current swapchain's images, `None` when they can't be
+    fn create_video_encoder(&mut self, fns: &VideoEncodeFns) -> Option<VideoEncoder> {
+        if self.alternate_frames.is_some() {
+            eprintln!("Frames alternating between GPUs can't be encoded");
+            return None;
+        }
+        if self.protected_swapchain {
+            eprintln!("Protected frames can't be encoded");
+            return None;
+        }
+        if !self.swapchain_readable() {
+            eprintln!("The surface's images can't be copied, frames won't be encoded");
+            return None;
+        }
+        let encoder = VideoEncoder::new(
+            fns,
+            &self.instance,
+            self.physical_device,
+            &self.device,
+            &self.mem_props,
+            &self.queue_families,
+            &self.queues,
+            &mut self.samplers,
+            self.format,
+            self.extent,
+            VideoEncoderConfig::new(VideoRecording::FRAME_RATE),
+        );
+        match encoder {
+            Ok(encoder) => Some(encoder),
+            Err(err) => {
+                eprintln!("Frames won't be encoded: {err}");
+                None
+            }
+        }
+    }
+
+    /// Ends the stream at the old size and starts one at the new, the device being idle
+    fn restart_video(&mut self) {
+        let Some(mut video) = self.video.take() else {
+            return;
+        };
+        unsafe { video.stop(&self.device) };
+        video.encoder = self.create_video_encoder(&video.fns);
+        self.video = Some(video);
+    }
+
+    /// Records converting swapchain image `image` for encoding, unless every slot is busy
+    fn record_video(&mut self, cmd: vk::CommandBuffer, image: usize) {
+        let Some(video) = &mut self.video else {
+            return;
+        };
+        if let Some(encoder) = &mut video.encoder {
+            video.converted = encoder.record_capture(
+                &self.device,
+                cmd,
+                self.swapchain_images[image],
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            );
+        }
+    }
+
+    /// Encodes the frame just submitted
+    fn encode_video(&mut self) -> anyhow::Result<()> {
+        let Some(video) = &mut self.video else {
+            return Ok(());
+        };
+        // Still set when the frame's submission failed
+        if video.converted.is_some() {
+            return Ok(());
+        }
+        if let Some(encoder) = &mut video.encoder {
+            encoder
+                .submit(&self.device)
+                .context("couldn't encode the frame")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the frames done encoding
+    fn poll_video(&mut self) {
+        let Some(video) = &mut self.video else {
+            return;
+        };
+        let Some(encoder) = &mut video.encoder else {
+            return;
+        };
+        match encoder.poll(&self.device, &mut video.stream) {
+            Ok(_) => video.write(),
+            Err(err) => eprintln!("Couldn't read back the encoded frames: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "gilrs")]
+impl TutorApp {
+    fn init_gamepads() -> Option<Gamepads> {
+        match Gamepads::new() {
+            Ok(gamepads) => {
+                for (_, name) in gamepads.connected() {
+                    println!("Gamepad {name} connected");
+                }
+                Some(gamepads)
+            }
+            Err(err) => {
+                eprintln!("Gamepads are unavailable: {err}");
+                None
+            }
+        }
+    }
+
+    fn poll_gamepads(&mut self) {
+        let Some(gamepads) = &mut self.gamepads else {
+            return;
+        };
+        for hotplug in gamepads.poll(&mut self.input) {
+            match hotplug {
+                Hotplug::Connected { name, .. } => println!("Gamepad {name} connected"),
+                Hotplug::Disconnected { id } => println!("Gamepad {id} disconnected"),
+            }
+        }
+    }
+}
+
+impl TutorApp {
+    fn check_memory_budget(&mut self) {
+        let Some(props2) = &self.memory_budget_ext else {
+            return;
+        };
+        let stats = MemoryStats::query(props2, self.physical_device);
+        for event in self.budget_watcher.update(&stats) {
+            match event {
+                BudgetEvent::Exceeded {
+                    heap,
+                    usage,
+                    budget,
+                } => eprintln!(
+                    "Memory heap {heap} is using {} MiB of its {} MiB budget\n{}",
+                    usage >> 20,
+                    budget >> 20,
+                    UsageReport::capture()
+                ),
+                BudgetEvent::Recovered { heap } => {
+                    eprintln!("Memory heap {heap} is back under budget")
+                }
+            }
+        }
+    }
+}
+
+impl TutorApp {
+    /// Logs what the driver knows about a device loss, before anything is torn down
+    fn report_device_lost(&self) {
+        eprintln!("The device was lost");
+        if let Some(checkpoints) = &self.checkpoints {
+            eprint!("{}", unsafe { checkpoints.report(self.queues.graphics) });
+        }
+        let Some(fault) = &self.device_fault else {
+            return;
+        };
+        match unsafe { fault.query() } {
+            Ok(report) => eprint!("{report}"),
+            Err(err) => eprintln!("Couldn't query the device fault: {err}"),
+        }
+    }
+}
+
 impl Drop for TutorApp {
     fn drop(&mut self) {
         unsafe {
+            if let Err(vk::Result::ERROR_DEVICE_LOST) = self.device.device_wait_idle() {
+                self.report_device_lost();
+            }
+            if let Some(readback) = self.capture.as_mut().and_then(|c| c.readback.as_mut()) {
+                readback.destroy(&self.device);
+            }
+            if let Some((_, _, dump)) = self.graph_dump.as_mut().and_then(|d| d.pending.as_mut()) {
+                dump.destroy(&self.device);
+            }
+            if let Some(timer) = &self.gpu_timer {
+                timer.destroy(&self.device);
+            }
+            if let Some(video) = &mut self.video {
+                video.stop(&self.device);
+                println!("Saved the video to {}", video.path.display());
+            }
+            self.graph.destroy(&self.device);
+            self.frames.destroy(&self.device);
             for image in &self.swapchain_image_views {
                 self.device.destroy_image_view(*image, None)
             }
+            if let Some(handoff) = &mut self.present_handoff {
+                handoff.destroy(&self.device);
+            }
+            if let Some(queue) = &self.protected_queue {
+                queue.destroy(&self.device);
+            }
+            if let Some(frames) = &mut self.alternate_frames {
+                frames.destroy(&self.device);
+            }
             self.swapchain_ext.destroy_swapchain(self.swapchain, None);
+            self.samplers.destroy(&self.device);
+            if let Some(checkpoints) = &self.checkpoints {
+                checkpoints.destroy(&self.device);
+            }
             self.device.destroy_device(None);
 
             self.surface_ext.destroy_surface(self.surface_khr, None);
+            if let Some(messenger) = &self.debug_messenger {
+                messenger.destroy();
+            }
             self.instance.destroy_instance(None);
         }
     }
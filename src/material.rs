@@ -0,0 +1,328 @@
+//! Materials tying a pipeline variant to per-instance descriptor sets and parameter blocks
+//!
+//! A [`Material`] is the pipeline side, shared by every [`MaterialInstance`] using it. Instances
+//! own their descriptor set at [`MATERIAL_SET`] with the parameter uniform block at binding 0.
+
+use std::ops::Range;
+
+use ash::{prelude::VkResult, vk, vk::Handle, Device};
+
+use crate::{
+    layout::AsBytes,
+    leaks,
+    memory::{usage::MemoryCategory, Buffer},
+    pipeline::dynamic::{DynamicRasterState, RasterState},
+    scene::{MaterialId, MeshId},
+    shader::variant::VariantKey,
+    trace::traced,
+};
+
+/// Descriptor set index reserved for material data, set 0 is left for per-frame data
+pub const MATERIAL_SET: u32 = 1;
+
+/// Pipeline state shared by all instances of a material
+pub struct Material {
+    pub name: String,
+    /// Shader variant the pipeline was built from
+    pub variant: VariantKey,
+    /// Made with [`DynamicRasterState::dynamic_states`], which [`Materials::record_draws`] sets
+    pub pipeline: vk::Pipeline,
+    /// Rasterization and depth state the pipeline was built with, reapplied where dynamic
+    pub raster: RasterState,
+    pub layout: vk::PipelineLayout,
+    pub set_layout: vk::DescriptorSetLayout,
+    /// Size of the parameter uniform block at binding 0
+    pub params_size: vk::DeviceSize,
+}
+
+/// Index of a [`Material`] in [`Materials`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MaterialTemplateId(pub u32);
+
+pub struct MaterialInstance {
+    pub material: MaterialTemplateId,
+    pub set: vk::DescriptorSet,
+    params: Buffer,
+}
+
+impl MaterialInstance {
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        pool: vk::DescriptorPool,
+        material: &Material,
+        id: MaterialTemplateId,
+    ) -> VkResult<Self> {
+        let mut params = Buffer::new(
+            device,
+            mem_props,
+            material.params_size,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        params.set_category(MemoryCategory::Uniform);
+
+        let set_layouts = [material.set_layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&set_layouts);
+        let sets = unsafe {
+            traced!(
+                "vkAllocateDescriptorSets",
+                pool = pool,
+                layout = material.set_layout;
+                device.allocate_descriptor_sets(&alloc_info)
+            )
+        };
+        let set = match sets {
+            Ok(sets) => sets[0],
+            Err(err) => {
+                unsafe { params.destroy(device) };
+                return Err(err);
+            }
+        };
+
+        let buffer_info = [vk::DescriptorBufferInfo {
+            buffer: params.buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .buffer_info(&buffer_info)
+            .build();
+        unsafe {
+            traced!(
+                "vkUpdateDescriptorSets",
+                set = write.dst_set,
+                binding = write.dst_binding,
+                ty = write.descriptor_type;
+                device.update_descriptor_sets(&[write], &[])
+            )
+        };
+
+        Ok(MaterialInstance {
+            material: id,
+            set,
+            params,
+        })
+    }
+
+    /// Overwrites the parameter block, the device must not be reading it
+    ///
+    /// # Panics
+    ///
+    /// If `T` is bigger than the material's parameter block.
+    pub fn write_params<T: AsBytes>(&self, params: &T) {
+        assert!(
+            std::mem::size_of::<T>() as vk::DeviceSize <= self.params.size,
+            "parameters do not fit the material's block"
+        );
+        let mapped = self.params.mapped().expect("params are host visible");
+        unsafe { params.write_to(mapped.as_ptr()) };
+    }
+
+    pub fn set_texture(
+        &self,
+        device: &Device,
+        binding: u32,
+        view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) {
+        let image_info = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(binding)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info)
+            .build();
+        unsafe {
+            traced!(
+                "vkUpdateDescriptorSets",
+                set = write.dst_set,
+                binding = write.dst_binding,
+                ty = write.descriptor_type;
+                device.update_descriptor_sets(&[write], &[])
+            )
+        };
+    }
+
+    /// Like [`MaterialInstance::set_texture`] for shaders with separate images and samplers
+    pub fn set_image(&self, device: &Device, binding: u32, view: vk::ImageView) {
+        let image_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(binding)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image_info)
+            .build();
+        unsafe {
+            traced!(
+                "vkUpdateDescriptorSets",
+                set = write.dst_set,
+                binding = write.dst_binding,
+                ty = write.descriptor_type;
+                device.update_descriptor_sets(&[write], &[])
+            )
+        };
+    }
+
+    pub fn set_sampler(&self, device: &Device, binding: u32, sampler: vk::Sampler) {
+        let image_info = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(self.set)
+            .dst_binding(binding)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&image_info)
+            .build();
+        unsafe {
+            traced!(
+                "vkUpdateDescriptorSets",
+                set = write.dst_set,
+                binding = write.dst_binding,
+                ty = write.descriptor_type;
+                device.update_descriptor_sets(&[write], &[])
+            )
+        };
+    }
+
+    /// # Safety
+    ///
+    /// The instance must no longer be in use by the device. The descriptor set is left to be
+    /// freed with its pool.
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.params.destroy(device);
+    }
+}
+
+/// One instanced draw of a mesh with a material
+#[derive(Debug, Clone)]
+pub struct DrawItem {
+    pub material: MaterialId,
+    pub mesh: MeshId,
+    pub instances: Range<u32>,
+}
+
+#[derive(Default)]
+pub struct Materials {
+    materials: Vec<Material>,
+    instances: Vec<MaterialInstance>,
+}
+
+impl Materials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_material(&mut self, material: Material) -> MaterialTemplateId {
+        self.materials.push(material);
+        MaterialTemplateId(self.materials.len() as u32 - 1)
+    }
+
+    pub fn add_instance(&mut self, instance: MaterialInstance) -> MaterialId {
+        self.instances.push(instance);
+        MaterialId(self.instances.len() as u32 - 1)
+    }
+
+    pub fn material(&self, id: MaterialTemplateId) -> &Material {
+        &self.materials[id.0 as usize]
+    }
+
+    pub fn instance(&self, id: MaterialId) -> &MaterialInstance {
+        &self.instances[id.0 as usize]
+    }
+
+    fn material_of(&self, id: MaterialId) -> &Material {
+        self.material(self.instance(id).material)
+    }
+
+    /// Orders draws by pipeline then material so state changes happen as rarely as possible
+    pub fn sort_draws(&self, draws: &mut [DrawItem]) {
+        draws.sort_by_key(|draw| {
+            (
+                self.material_of(draw.material).pipeline.as_raw(),
+                draw.material,
+                draw.mesh,
+            )
+        });
+    }
+
+    /// Records `draws`, binding pipelines and material sets only when they change
+    ///
+    /// `draw_mesh` binds the mesh's buffers and records the draw for the instance range.
+    pub fn record_draws(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        raster: &DynamicRasterState,
+        draws: &[DrawItem],
+        mut draw_mesh: impl FnMut(MeshId, Range<u32>),
+    ) {
+        let mut bound_pipeline = vk::Pipeline::null();
+        let mut bound_material = None;
+        for draw in draws {
+            let instance = self.instance(draw.material);
+            let material = self.material(instance.material);
+            if material.pipeline != bound_pipeline {
+                unsafe {
+                    device.cmd_bind_pipeline(
+                        cmd,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        material.pipeline,
+                    );
+                    raster.apply(device, cmd, &material.raster);
+                };
+                bound_pipeline = material.pipeline;
+                // Sets may be disturbed by an incompatible layout, so rebind after a switch
+                bound_material = None;
+            }
+            if bound_material != Some(draw.material) {
+                unsafe {
+                    device.cmd_bind_descriptor_sets(
+                        cmd,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        material.layout,
+                        MATERIAL_SET,
+                        &[instance.set],
+                        &[],
+                    )
+                };
+                bound_material = Some(draw.material);
+            }
+            draw_mesh(draw.mesh, draw.instances.clone());
+        }
+    }
+
+    /// Destroys every instance's parameter buffer along with the material pipelines
+    ///
+    /// # Safety
+    ///
+    /// None of the materials may still be in use by the device.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for instance in self.instances.drain(..) {
+            instance.destroy(device);
+        }
+        for material in self.materials.drain(..) {
+            leaks::untrack(material.pipeline);
+            device.destroy_pipeline(material.pipeline, None);
+            leaks::untrack(material.layout);
+            device.destroy_pipeline_layout(material.layout, None);
+            leaks::untrack(material.set_layout);
+            device.destroy_descriptor_set_layout(material.set_layout, None);
+        }
+    }
+}
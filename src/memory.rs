@@ -0,0 +1,342 @@
+//! Buffer creation and memory type selection
+
+use std::{ffi::c_void, ptr::NonNull};
+
+use ash::{prelude::VkResult, vk, Device};
+
+use self::usage::MemoryCategory;
+use crate::{leaks, trace::traced};
+
+pub mod budget;
+pub mod dynamic;
+pub mod sparse;
+pub mod staging;
+pub mod usage;
+
+/// Heaps at most this big that are device local and host visible are the legacy BAR window
+/// rather than resizable BAR, and are too small to rely on for general allocations
+const LEGACY_BAR_SIZE: vk::DeviceSize = 256 * 1024 * 1024;
+
+/// Finds a memory type allowed by `type_bits` that has all of `flags`
+pub fn find_memory_type(
+    props: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+    flags: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    (0..props.memory_type_count).find(|&i| {
+        type_bits & (1 << i) != 0
+            && props.memory_types[i as usize]
+                .property_flags
+                .contains(flags)
+    })
+}
+
+/// Finds a device local, host visible memory type backed by a resizable BAR sized heap
+pub fn find_rebar_memory_type(props: &vk::PhysicalDeviceMemoryProperties) -> Option<u32> {
+    let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE;
+    (0..props.memory_type_count).find(|&i| {
+        let ty = props.memory_types[i as usize];
+        ty.property_flags.contains(flags)
+            && props.memory_heaps[ty.heap_index as usize].size > LEGACY_BAR_SIZE
+    })
+}
+
+/// A buffer with its own dedicated allocation, mapped for its whole lifetime when host visible
+pub struct Buffer {
+    pub buffer: vk::Buffer,
+    pub memory: vk::DeviceMemory,
+    pub size: vk::DeviceSize,
+    pub memory_flags: vk::MemoryPropertyFlags,
+    mapped: Option<NonNull<c_void>>,
+    category: MemoryCategory,
+    allocation_size: vk::DeviceSize,
+}
+
+impl Buffer {
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        flags: vk::MemoryPropertyFlags,
+    ) -> VkResult<Self> {
+        Self::with_memory_type(device, mem_props, size, usage, |type_bits| {
+            find_memory_type(mem_props, type_bits, flags)
+        })
+    }
+
+    /// Creates a buffer in resizable BAR memory, `None` if the device has none usable
+    #[track_caller]
+    pub fn new_rebar(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> VkResult<Option<Self>> {
+        let Some(rebar) = find_rebar_memory_type(mem_props) else {
+            return Ok(None);
+        };
+        match Self::with_memory_type(device, mem_props, size, usage, |type_bits| {
+            (type_bits & (1 << rebar) != 0).then_some(rebar)
+        }) {
+            Ok(buffer) => Ok(Some(buffer)),
+            Err(vk::Result::ERROR_FEATURE_NOT_PRESENT) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Creates a buffer, letting `select` pick a memory type index from the allowed type bits
+    #[track_caller]
+    pub fn with_memory_type(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        select: impl FnOnce(u32) -> Option<u32>,
+    ) -> VkResult<Self> {
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = unsafe {
+            traced!(
+                "vkCreateBuffer",
+                size = size,
+                usage = usage;
+                device.create_buffer(&buffer_info, None)
+            )?
+        };
+
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let Some(type_index) = select(requirements.memory_type_bits) else {
+            unsafe {
+                traced!("vkDestroyBuffer", buffer = buffer; device.destroy_buffer(buffer, None))
+            };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        let memory_flags = mem_props.memory_types[type_index as usize].property_flags;
+
+        // Buffers read through their address need memory allocated for it
+        let mut flags_info =
+            vk::MemoryAllocateFlagsInfo::builder().flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+        let mut alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        if usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+            alloc_info = alloc_info.push_next(&mut flags_info);
+        }
+        let result = unsafe {
+            traced!(
+                "vkAllocateMemory",
+                size = requirements.size,
+                memory_type = type_index;
+                device.allocate_memory(&alloc_info, None)
+            )
+            .and_then(|memory| {
+                traced!(
+                    "vkBindBufferMemory",
+                    buffer = buffer,
+                    memory = memory;
+                    device.bind_buffer_memory(buffer, memory, 0)
+                )
+                .map(|_| memory)
+                .inspect_err(
+                    |_| traced!("vkFreeMemory", memory = memory; device.free_memory(memory, None)),
+                )
+            })
+        };
+        let memory = match result {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe {
+                    traced!("vkDestroyBuffer", buffer = buffer; device.destroy_buffer(buffer, None))
+                };
+                return Err(err);
+            }
+        };
+
+        usage::record_allocation(MemoryCategory::Other, requirements.size);
+        leaks::track(buffer, "buffer");
+        leaks::track(memory, "buffer memory");
+        let mut buffer = Buffer {
+            buffer,
+            memory,
+            size,
+            memory_flags,
+            mapped: None,
+            category: MemoryCategory::Other,
+            allocation_size: requirements.size,
+        };
+        if memory_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            match unsafe {
+                traced!(
+                    "vkMapMemory",
+                    memory = memory;
+                    device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+                )
+            } {
+                Ok(ptr) => buffer.mapped = NonNull::new(ptr),
+                Err(err) => {
+                    unsafe { buffer.destroy(device) };
+                    return Err(err);
+                }
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Pointer to the start of the persistent mapping, if the memory is host visible
+    pub fn mapped(&self) -> Option<NonNull<c_void>> {
+        self.mapped
+    }
+
+    /// Needs the buffer created with `SHADER_DEVICE_ADDRESS` usage
+    pub fn device_address(&self, device: &Device) -> vk::DeviceAddress {
+        let info = vk::BufferDeviceAddressInfo::builder().buffer(self.buffer);
+        unsafe { device.get_buffer_device_address(&info) }
+    }
+
+    pub fn category(&self) -> MemoryCategory {
+        self.category
+    }
+
+    /// Moves the buffer's memory to `category` in the usage totals, new buffers count as `Other`
+    pub fn set_category(&mut self, category: MemoryCategory) {
+        usage::release_allocation(self.category, self.allocation_size);
+        usage::record_allocation(category, self.allocation_size);
+        self.category = category;
+    }
+
+    /// Flushes a written range, only needed for memory that is not host coherent
+    ///
+    /// The range is widened to `atom_size`, the device's `nonCoherentAtomSize` limit.
+    pub fn flush(
+        &self,
+        device: &Device,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+        atom_size: vk::DeviceSize,
+    ) -> VkResult<()> {
+        if self
+            .memory_flags
+            .contains(vk::MemoryPropertyFlags::HOST_COHERENT)
+        {
+            return Ok(());
+        }
+        let start = offset - offset % atom_size;
+        let end = (offset + size).next_multiple_of(atom_size);
+        let range = vk::MappedMemoryRange::builder()
+            .memory(self.memory)
+            .offset(start)
+            .size(if end >= self.size {
+                vk::WHOLE_SIZE
+            } else {
+                end - start
+            })
+            .build();
+        unsafe {
+            traced!(
+                "vkFlushMappedMemoryRanges",
+                memory = self.memory,
+                offset = range.offset,
+                size = range.size;
+                device.flush_mapped_memory_ranges(&[range])
+            )
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The buffer must no longer be in use by the device.
+    pub unsafe fn destroy(&self, device: &Device) {
+        if self.mapped.is_some() {
+            traced!("vkUnmapMemory", memory = self.memory; device.unmap_memory(self.memory));
+        }
+        leaks::untrack(self.buffer);
+        leaks::untrack(self.memory);
+        traced!("vkDestroyBuffer", buffer = self.buffer; device.destroy_buffer(self.buffer, None));
+        traced!("vkFreeMemory", memory = self.memory; device.free_memory(self.memory, None));
+        usage::release_allocation(self.category, self.allocation_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::HeadlessContext;
+
+    #[test]
+    fn buffer_copy_round_trips_through_mapped_memory() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let src = Buffer::new(
+            &ctx.device,
+            &ctx.mem_props,
+            256,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            host,
+        )
+        .unwrap();
+        let dst = Buffer::new(
+            &ctx.device,
+            &ctx.mem_props,
+            256,
+            vk::BufferUsageFlags::TRANSFER_DST,
+            host,
+        )
+        .unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), src.mapped().unwrap().as_ptr().cast(), 256)
+        };
+
+        ctx.one_shot(|cmd| unsafe {
+            let region = vk::BufferCopy {
+                src_offset: 0,
+                dst_offset: 0,
+                size: 256,
+            };
+            ctx.device
+                .cmd_copy_buffer(cmd, src.buffer, dst.buffer, &[region]);
+            let to_host = vk::MemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::HOST_READ);
+            ctx.device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[*to_host],
+                &[],
+                &[],
+            );
+        })
+        .unwrap();
+
+        let copied =
+            unsafe { std::slice::from_raw_parts(dst.mapped().unwrap().as_ptr().cast::<u8>(), 256) };
+        assert_eq!(copied, data);
+        unsafe {
+            src.destroy(&ctx.device);
+            dst.destroy(&ctx.device);
+        }
+    }
+
+    #[test]
+    fn missing_memory_type_is_reported() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let result = Buffer::with_memory_type(
+            &ctx.device,
+            &ctx.mem_props,
+            64,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            |_| None,
+        );
+        assert_eq!(result.err(), Some(vk::Result::ERROR_FEATURE_NOT_PRESENT));
+    }
+}
@@ -0,0 +1,141 @@
+//! Per-heap memory budget and usage from `VK_EXT_memory_budget`
+
+use ash::{extensions::khr::GetPhysicalDeviceProperties2, vk};
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: vk::DeviceSize,
+    pub flags: vk::MemoryHeapFlags,
+    /// How much the process can allocate from the heap before running into trouble
+    pub budget: vk::DeviceSize,
+    /// How much the process currently has allocated from the heap, 0 when unknown
+    pub usage: vk::DeviceSize,
+}
+
+impl HeapStats {
+    pub fn is_device_local(&self) -> bool {
+        self.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL)
+    }
+
+    /// Fraction of the budget in use
+    pub fn pressure(&self) -> f32 {
+        if self.budget == 0 {
+            0.
+        } else {
+            self.usage as f32 / self.budget as f32
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    pub heaps: Vec<HeapStats>,
+    /// Whether budget and usage came from the driver rather than the heap sizes
+    pub from_driver: bool,
+}
+
+impl MemoryStats {
+    /// Queries the driver, requires `VK_EXT_memory_budget` to be enabled on the device
+    pub fn query(
+        props2: &GetPhysicalDeviceProperties2,
+        physical_device: vk::PhysicalDevice,
+    ) -> Self {
+        let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut props = vk::PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget);
+        unsafe { props2.get_physical_device_memory_properties2(physical_device, &mut props) };
+        let mem_props = props.memory_properties;
+        let heaps = &mem_props.memory_heaps[..mem_props.memory_heap_count as usize];
+
+        MemoryStats {
+            heaps: heaps
+                .iter()
+                .enumerate()
+                .map(|(i, heap)| HeapStats {
+                    size: heap.size,
+                    flags: heap.flags,
+                    budget: budget.heap_budget[i],
+                    usage: budget.heap_usage[i],
+                })
+                .collect(),
+            from_driver: true,
+        }
+    }
+
+    /// Fallback without the extension, the whole heap is treated as the budget
+    pub fn from_properties(props: &vk::PhysicalDeviceMemoryProperties) -> Self {
+        MemoryStats {
+            heaps: props.memory_heaps[..props.memory_heap_count as usize]
+                .iter()
+                .map(|heap| HeapStats {
+                    size: heap.size,
+                    flags: heap.flags,
+                    budget: heap.size,
+                    usage: 0,
+                })
+                .collect(),
+            from_driver: false,
+        }
+    }
+
+    /// Highest pressure across device local heaps
+    pub fn device_local_pressure(&self) -> f32 {
+        self.heaps
+            .iter()
+            .filter(|heap| heap.is_device_local())
+            .map(HeapStats::pressure)
+            .fold(0., f32::max)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BudgetEvent {
+    /// Usage of a heap went above the warning threshold, a good time to drop texture quality
+    Exceeded {
+        heap: usize,
+        usage: vk::DeviceSize,
+        budget: vk::DeviceSize,
+    },
+    /// Usage of a heap went back below the threshold
+    Recovered { heap: usize },
+}
+
+/// Turns per-frame stats into events when heaps cross a usage threshold
+pub struct BudgetWatcher {
+    threshold: f32,
+    over: Vec<bool>,
+}
+
+impl BudgetWatcher {
+    pub fn new(threshold: f32) -> Self {
+        BudgetWatcher {
+            threshold,
+            over: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, stats: &MemoryStats) -> Vec<BudgetEvent> {
+        self.over.resize(stats.heaps.len(), false);
+        let mut events = Vec::new();
+        for (i, heap) in stats.heaps.iter().enumerate() {
+            // Small hysteresis so usage hovering around the threshold doesn't spam events
+            let over = if self.over[i] {
+                heap.pressure() > self.threshold - 0.05
+            } else {
+                heap.pressure() > self.threshold
+            };
+            if over != self.over[i] {
+                events.push(if over {
+                    BudgetEvent::Exceeded {
+                        heap: i,
+                        usage: heap.usage,
+                        budget: heap.budget,
+                    }
+                } else {
+                    BudgetEvent::Recovered { heap: i }
+                });
+                self.over[i] = over;
+            }
+        }
+        events
+    }
+}
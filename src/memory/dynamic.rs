@@ -0,0 +1,119 @@
+//! Frequently updated buffers, written in place through resizable BAR when available
+
+use std::ptr;
+
+use ash::{prelude::VkResult, vk, Device};
+
+use super::{staging::StagingBelt, Buffer};
+
+enum Path {
+    /// Device local and host visible, written through the persistent mapping
+    Direct,
+    /// Device local only, written through the staging belt and a transfer
+    Staged,
+}
+
+/// A device local buffer for per-frame data such as uniforms or instance transforms
+pub struct DynamicBuffer {
+    pub buffer: Buffer,
+    path: Path,
+    atom_size: vk::DeviceSize,
+}
+
+impl DynamicBuffer {
+    /// Prefers resizable BAR memory and falls back to plain device local memory
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> VkResult<Self> {
+        let (buffer, path) = match Buffer::new_rebar(device, mem_props, size, usage)? {
+            Some(buffer) => (buffer, Path::Direct),
+            None => (
+                Buffer::new(
+                    device,
+                    mem_props,
+                    size,
+                    usage | vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )?,
+                Path::Staged,
+            ),
+        };
+        Ok(DynamicBuffer {
+            buffer,
+            path,
+            atom_size: limits.non_coherent_atom_size,
+        })
+    }
+
+    /// Whether writes go straight to device memory instead of through a copy
+    pub fn is_direct(&self) -> bool {
+        matches!(self.path, Path::Direct)
+    }
+
+    /// Writes `data` at `offset`, recording a copy and barrier into `cmd` on the staged path
+    ///
+    /// On the direct path the caller must make sure the device is not reading the range,
+    /// usually by keeping one buffer per frame in flight.
+    pub fn write(
+        &self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> VkResult<()> {
+        let size = data.len() as vk::DeviceSize;
+        match self.path {
+            Path::Direct => {
+                let mapped = self
+                    .buffer
+                    .mapped()
+                    .expect("direct buffers are host visible");
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        data.as_ptr(),
+                        mapped.as_ptr().byte_add(offset as usize).cast(),
+                        data.len(),
+                    )
+                };
+                self.buffer.flush(device, offset, size, self.atom_size)
+            }
+            Path::Staged => {
+                let alloc = staging.write(device, data, 4)?;
+                alloc.copy_to_buffer(device, cmd, self.buffer.buffer, offset);
+                let barrier = vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .buffer(self.buffer.buffer)
+                    .offset(offset)
+                    .size(size)
+                    .build();
+                unsafe {
+                    device.cmd_pipeline_barrier(
+                        cmd,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::ALL_COMMANDS,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &[barrier],
+                        &[],
+                    )
+                };
+                Ok(())
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The buffer must no longer be in use by the device.
+    pub unsafe fn destroy(&self, device: &Device) {
+        self.buffer.destroy(device);
+    }
+}
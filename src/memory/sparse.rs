@@ -0,0 +1,555 @@
+//! Buffers reserving a large address range and committing memory only where data is resident
+//!
+//! A [`SparseBuffer`] is one huge vertex or index pool whose pages get memory as regions of it
+//! are committed, so meshes far more than fit in memory can share it. [`Residency`] decides
+//! which regions stay committed: the LOD and culling systems request what they draw every
+//! frame, as [`retain_resident`] does after [`cull_instances`], and [`Residency::update`]
+//! commits what's missing and evicts what hasn't been drawn in a while to stay under budget.
+//!
+//! Needs `sparseBinding`, `sparseResidencyBuffer` and a queue in
+//! [`QueueFamilies::sparse_binding`]. Regions not committed must not be drawn, their memory
+//! reads are undefined.
+//!
+//! [`cull_instances`]: crate::culling::cull_instances
+//! [`retain_resident`]: crate::culling::retain_resident
+//! [`QueueFamilies::sparse_binding`]: crate::device::QueueFamilies::sparse_binding
+
+use std::{collections::HashMap, hash::Hash, mem, ops::Range};
+
+use ash::{prelude::VkResult, vk, Device};
+
+use super::{
+    find_memory_type,
+    staging::StagingBelt,
+    usage::{self, MemoryCategory},
+};
+use crate::{leaks, trace::traced};
+
+/// A sparse resident buffer, its pages backed from chunks of memory allocated as needed
+pub struct SparseBuffer {
+    pub buffer: vk::Buffer,
+    pub size: vk::DeviceSize,
+    page_size: vk::DeviceSize,
+    memory_type: u32,
+    /// Pages in each allocation, so committing a page rarely allocates
+    chunk_pages: u32,
+    chunks: Vec<vk::DeviceMemory>,
+    pages: PageTable,
+}
+
+/// Which slot backs each page, and the binds bringing the device up to date with it
+///
+/// Slots number pages across the chunks, `chunk * chunk_pages + page`.
+#[derive(Debug)]
+struct PageTable {
+    free: Vec<u32>,
+    /// The slot backing each page and how many committed ranges touch it
+    pages: Vec<Option<(u32, u32)>>,
+    /// Whether each page had memory bound on the device as of the last flush
+    bound: Vec<bool>,
+    /// Pages to bind to a slot, or unbind with `None`, unbinds first
+    pending: Vec<(usize, Option<u32>)>,
+}
+
+impl PageTable {
+    fn new(pages: usize) -> Self {
+        PageTable {
+            free: Vec::new(),
+            pages: vec![None; pages],
+            bound: vec![false; pages],
+            pending: Vec::new(),
+        }
+    }
+
+    /// Counts a user of `page`, `false` when it needs a slot and none is free
+    fn commit(&mut self, page: usize) -> bool {
+        if let Some((_, users)) = &mut self.pages[page] {
+            *users += 1;
+            return true;
+        }
+        let Some(slot) = self.free.pop() else {
+            return false;
+        };
+        self.pages[page] = Some((slot, 1));
+        self.pending.push((page, Some(slot)));
+        true
+    }
+
+    fn decommit(&mut self, page: usize) {
+        let Some((slot, users)) = &mut self.pages[page] else {
+            return;
+        };
+        *users -= 1;
+        if *users > 0 {
+            return;
+        }
+        self.free.push(*slot);
+        self.pages[page] = None;
+        // A bind not flushed yet never reaches the device
+        self.pending
+            .retain(|&(other, slot)| other != page || slot.is_none());
+        if self.bound[page] && !self.pending.contains(&(page, None)) {
+            // Goes before any bind reusing the slot
+            self.pending.insert(0, (page, None));
+        }
+    }
+
+    /// Clears the pending binds once they're submitted
+    fn flushed(&mut self) {
+        for (page, slot) in mem::take(&mut self.pending) {
+            self.bound[page] = slot.is_some();
+        }
+    }
+}
+
+impl SparseBuffer {
+    /// Reserves `size` bytes of address space without committing any memory
+    ///
+    /// Fails with `ERROR_FEATURE_NOT_PRESENT` when no device local memory type fits.
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        chunk_pages: u32,
+    ) -> VkResult<Self> {
+        let info = vk::BufferCreateInfo::builder()
+            .flags(vk::BufferCreateFlags::SPARSE_BINDING | vk::BufferCreateFlags::SPARSE_RESIDENCY)
+            .size(size)
+            .usage(usage | vk::BufferUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = unsafe { device.create_buffer(&info, None)? };
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let Some(memory_type) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { device.destroy_buffer(buffer, None) };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        leaks::track(buffer, "sparse buffer");
+        let page_size = requirements.alignment;
+        Ok(SparseBuffer {
+            buffer,
+            size,
+            page_size,
+            memory_type,
+            chunk_pages: chunk_pages.max(1),
+            chunks: Vec::new(),
+            pages: PageTable::new(size.div_ceil(page_size) as usize),
+        })
+    }
+
+    /// Granularity memory is committed at, ranges are widened to whole pages
+    pub fn page_size(&self) -> vk::DeviceSize {
+        self.page_size
+    }
+
+    /// Bytes of memory backing committed pages
+    pub fn committed_bytes(&self) -> vk::DeviceSize {
+        self.pages.pages.iter().flatten().count() as vk::DeviceSize * self.page_size
+    }
+
+    /// Bytes allocated for pages, committed or not
+    pub fn allocated_bytes(&self) -> vk::DeviceSize {
+        self.chunks.len() as vk::DeviceSize * self.chunk_bytes()
+    }
+
+    /// Whether every page `range` touches has memory, or will once [`SparseBuffer::flush`] runs
+    pub fn is_committed(&self, range: Range<vk::DeviceSize>) -> bool {
+        self.page_range(range)
+            .all(|page| self.pages.pages[page].is_some())
+    }
+
+    /// Queues binding memory to the pages `range` touches
+    ///
+    /// Pages are counted, one shared by two committed ranges stays until both are decommitted.
+    #[track_caller]
+    pub fn commit(&mut self, device: &Device, range: Range<vk::DeviceSize>) -> VkResult<()> {
+        let pages = self.page_range(range);
+        for page in pages.clone() {
+            if !self.pages.commit(page) {
+                if let Err(err) = self.allocate_chunk(device) {
+                    // Nothing of the range stays counted
+                    for counted in pages.start..page {
+                        self.pages.decommit(counted);
+                    }
+                    return Err(err);
+                }
+                let committed = self.pages.commit(page);
+                debug_assert!(committed, "a chunk was just allocated");
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues unbinding the pages `range` touches once no other committed range uses them
+    ///
+    /// The device must be done reading the range by the time [`SparseBuffer::flush`] runs.
+    pub fn decommit(&mut self, range: Range<vk::DeviceSize>) {
+        for page in self.page_range(range) {
+            self.pages.decommit(page);
+        }
+    }
+
+    /// Submits the queued binds to `queue`, `false` when there were none
+    ///
+    /// Commands using newly committed pages must wait on one of `signal`.
+    pub fn flush(
+        &mut self,
+        device: &Device,
+        queue: vk::Queue,
+        wait: &[vk::Semaphore],
+        signal: &[vk::Semaphore],
+        fence: vk::Fence,
+    ) -> VkResult<bool> {
+        if self.pages.pending.is_empty() {
+            return Ok(false);
+        }
+        let binds: Vec<_> = self
+            .pages
+            .pending
+            .iter()
+            .map(|&(page, slot)| self.bind(page, slot))
+            .collect();
+        let buffer_binds = [vk::SparseBufferMemoryBindInfo::builder()
+            .buffer(self.buffer)
+            .binds(&binds)
+            .build()];
+        let info = vk::BindSparseInfo::builder()
+            .wait_semaphores(wait)
+            .buffer_binds(&buffer_binds)
+            .signal_semaphores(signal);
+        unsafe {
+            traced!(
+                "vkQueueBindSparse",
+                buffer = self.buffer,
+                binds = binds.len();
+                device.queue_bind_sparse(queue, &[*info], fence)
+            )?
+        };
+        self.pages.flushed();
+        Ok(true)
+    }
+
+    /// Records copying `bytes` to `offset` from the staging belt, for vertex or index reads
+    ///
+    /// The range must be committed, with the submission of `cmd` waiting on the binds.
+    pub fn write(
+        &self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        offset: vk::DeviceSize,
+        bytes: &[u8],
+    ) -> VkResult<()> {
+        let alloc = staging.write(device, bytes, 4)?;
+        let region = vk::BufferCopy {
+            src_offset: alloc.offset,
+            dst_offset: offset,
+            size: bytes.len() as vk::DeviceSize,
+        };
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(self.buffer)
+            .offset(offset)
+            .size(region.size)
+            .build();
+        unsafe {
+            traced!(
+                "vkCmdCopyBuffer",
+                src = alloc.buffer,
+                dst = self.buffer,
+                region = region;
+                device.cmd_copy_buffer(cmd, alloc.buffer, self.buffer, &[region])
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            )
+        };
+        Ok(())
+    }
+
+    /// Frees chunks none of whose pages are committed
+    ///
+    /// # Safety
+    ///
+    /// Binds unbinding the chunks' pages must have finished.
+    pub unsafe fn trim(&mut self, device: &Device) {
+        let mut used = vec![false; self.chunks.len()];
+        for &(slot, _) in self.pages.pages.iter().flatten() {
+            used[(slot / self.chunk_pages) as usize] = true;
+        }
+        // Slots are renumbered, so only trailing chunks can go
+        while used.last() == Some(&false) {
+            used.pop();
+            let memory = self.chunks.pop().expect("one flag per chunk");
+            leaks::untrack(memory);
+            device.free_memory(memory, None);
+            usage::release_allocation(MemoryCategory::Mesh, self.chunk_bytes());
+            let first = self.chunks.len() as u32 * self.chunk_pages;
+            self.pages.free.retain(|&slot| slot < first);
+        }
+    }
+
+    fn chunk_bytes(&self) -> vk::DeviceSize {
+        self.chunk_pages as vk::DeviceSize * self.page_size
+    }
+
+    #[track_caller]
+    fn allocate_chunk(&mut self, device: &Device) -> VkResult<()> {
+        let info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(self.chunk_bytes())
+            .memory_type_index(self.memory_type);
+        let memory = unsafe { device.allocate_memory(&info, None)? };
+        leaks::track(memory, "sparse buffer chunk");
+        usage::record_allocation(MemoryCategory::Mesh, self.chunk_bytes());
+        let first = self.chunks.len() as u32 * self.chunk_pages;
+        self.chunks.push(memory);
+        // Popped from the back, so the chunk fills from its start
+        self.pages
+            .free
+            .extend((first..first + self.chunk_pages).rev());
+        Ok(())
+    }
+
+    fn page_range(&self, range: Range<vk::DeviceSize>) -> Range<usize> {
+        if range.is_empty() {
+            return 0..0;
+        }
+        (range.start / self.page_size) as usize..range.end.div_ceil(self.page_size) as usize
+    }
+
+    /// Binding `page` to `slot`'s memory, or unbinding it
+    fn bind(&self, page: usize, slot: Option<u32>) -> vk::SparseMemoryBind {
+        let (memory, memory_offset) = match slot {
+            Some(slot) => (
+                self.chunks[(slot / self.chunk_pages) as usize],
+                (slot % self.chunk_pages) as vk::DeviceSize * self.page_size,
+            ),
+            None => (vk::DeviceMemory::null(), 0),
+        };
+        let resource_offset = page as vk::DeviceSize * self.page_size;
+        vk::SparseMemoryBind {
+            resource_offset,
+            // The last page may run past the buffer's end
+            size: self.page_size.min(self.size - resource_offset),
+            memory,
+            memory_offset,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The buffer must no longer be in use by the device, including by binds.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        leaks::untrack(self.buffer);
+        device.destroy_buffer(self.buffer, None);
+        let chunk_bytes = self.chunk_bytes();
+        for memory in self.chunks.drain(..) {
+            leaks::untrack(memory);
+            device.free_memory(memory, None);
+            usage::release_allocation(MemoryCategory::Mesh, chunk_bytes);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResidencyConfig {
+    /// Bytes of committed pages to stay under, regions still in use can push past it
+    pub budget: vk::DeviceSize,
+    /// Updates a region stays committed after its last request, at least the frames in flight
+    pub min_age: u64,
+    /// Regions committed per [`Residency::update`], to spread uploads over frames
+    pub commits_per_update: usize,
+}
+
+impl Default for ResidencyConfig {
+    fn default() -> Self {
+        ResidencyConfig {
+            budget: 256 * 1024 * 1024,
+            min_age: 3,
+            commits_per_update: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Region {
+    range: Range<vk::DeviceSize>,
+    last_used: Option<u64>,
+    committed: bool,
+}
+
+/// Keeps the regions of a [`SparseBuffer`] that are drawn committed, keyed by mesh or LOD
+pub struct Residency<K> {
+    config: ResidencyConfig,
+    regions: HashMap<K, Region>,
+    updates: u64,
+}
+
+impl<K: Copy + Eq + Hash> Residency<K> {
+    pub fn new(config: ResidencyConfig) -> Self {
+        Residency {
+            config,
+            regions: HashMap::new(),
+            updates: 0,
+        }
+    }
+
+    /// Places `key`'s data at `range` of the buffer, not committed until requested
+    pub fn register(&mut self, key: K, range: Range<vk::DeviceSize>) {
+        let region = Region {
+            range,
+            last_used: None,
+            committed: false,
+        };
+        self.regions.insert(key, region);
+    }
+
+    /// Forgets `key`, decommitting its region right away
+    ///
+    /// The device must be done drawing it by the next [`SparseBuffer::flush`].
+    pub fn unregister(&mut self, buffer: &mut SparseBuffer, key: K) {
+        if let Some(region) = self.regions.remove(&key) {
+            if region.committed {
+                buffer.decommit(region.range);
+            }
+        }
+    }
+
+    /// Marks `key` as drawn this frame, or about to be, keeping it committed
+    pub fn request(&mut self, key: K) {
+        if let Some(region) = self.regions.get_mut(&key) {
+            region.last_used = Some(self.updates);
+        }
+    }
+
+    pub fn request_all(&mut self, keys: impl IntoIterator<Item = K>) {
+        for key in keys {
+            self.request(key);
+        }
+    }
+
+    /// Whether `key` can be drawn, a coarser LOD should stand in for it otherwise
+    pub fn is_resident(&self, key: K) -> bool {
+        self.regions
+            .get(&key)
+            .is_some_and(|region| region.committed)
+    }
+
+    /// Commits requested regions and decommits stale ones until back under budget
+    ///
+    /// Gives the regions just committed, whose data has to be written again with
+    /// [`SparseBuffer::write`] after [`SparseBuffer::flush`]. They count as resident from now.
+    /// On failure none of this update's regions are committed, evictions still stand.
+    #[track_caller]
+    pub fn update(&mut self, device: &Device, buffer: &mut SparseBuffer) -> VkResult<Vec<K>> {
+        let now = self.updates;
+        self.updates += 1;
+        let mut wanted: Vec<(K, u64)> = self
+            .regions
+            .iter()
+            .filter(|(_, region)| !region.committed && region.last_used == Some(now))
+            .map(|(&key, region)| (key, region.range.end - region.range.start))
+            .collect();
+        // Small regions first, they're usually the coarse LODs drawn in their place
+        wanted.sort_by_key(|&(_, size)| size);
+        wanted.truncate(self.config.commits_per_update);
+
+        let incoming: vk::DeviceSize = wanted.iter().map(|&(_, size)| size).sum();
+        let mut stale: Vec<(K, u64)> = self
+            .regions
+            .iter()
+            .filter(|(_, region)| {
+                region.committed
+                    && region
+                        .last_used
+                        .is_none_or(|used| now - used >= self.config.min_age)
+            })
+            .map(|(&key, region)| (key, region.last_used.unwrap_or(0)))
+            .collect();
+        stale.sort_by_key(|&(_, used)| used);
+        for (key, _) in stale {
+            if buffer.committed_bytes() + incoming <= self.config.budget {
+                break;
+            }
+            let region = self
+                .regions
+                .get_mut(&key)
+                .expect("stale keys are registered");
+            region.committed = false;
+            buffer.decommit(region.range.clone());
+        }
+
+        let mut committed = Vec::with_capacity(wanted.len());
+        for (key, _) in wanted {
+            let region = self
+                .regions
+                .get_mut(&key)
+                .expect("wanted keys are registered");
+            if let Err(err) = buffer.commit(device, region.range.clone()) {
+                // The caller never learns of these, so their data would go unwritten
+                for key in committed {
+                    let region = self.regions.get_mut(&key).expect("just committed");
+                    region.committed = false;
+                    buffer.decommit(region.range.clone());
+                }
+                return Err(err);
+            }
+            region.committed = true;
+            committed.push(key);
+        }
+        Ok(committed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recommitted_page_is_unbound_before_its_slot_is_reused() {
+        let mut table = PageTable::new(2);
+        table.free = vec![1, 0];
+        assert!(table.commit(0));
+        table.flushed();
+
+        // Back in the same slot before the unbind was flushed, then gone again
+        table.decommit(0);
+        assert!(table.commit(0));
+        assert_eq!(table.pages[0], Some((0, 1)));
+        table.decommit(0);
+        assert_eq!(table.pending, [(0, None)]);
+
+        assert!(table.commit(1));
+        assert_eq!(table.pending, [(0, None), (1, Some(0))]);
+        table.flushed();
+        assert_eq!(table.bound, [false, true]);
+    }
+
+    #[test]
+    fn bind_never_flushed_is_dropped() {
+        let mut table = PageTable::new(1);
+        table.free = vec![0];
+        assert!(table.commit(0));
+        assert!(table.commit(0));
+        table.decommit(0);
+        assert_eq!(table.pending, [(0, Some(0))]);
+        table.decommit(0);
+        assert!(table.pending.is_empty());
+        assert_eq!(table.free, [0]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Ring style upload allocator suballocating from persistently mapped host buffers
+
+use std::{ffi::c_void, ptr};
+
+use ash::{prelude::VkResult, vk, Device};
+
+use super::{usage::MemoryCategory, Buffer};
+use crate::trace::traced;
+
+/// A slice of a staging chunk, valid until the submission it was used in has completed
+#[derive(Debug, Clone, Copy)]
+pub struct StagingAllocation {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    pub ptr: *mut c_void,
+}
+
+impl StagingAllocation {
+    /// Records a copy of the whole allocation into `dst`
+    pub fn copy_to_buffer(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        dst: vk::Buffer,
+        dst_offset: vk::DeviceSize,
+    ) {
+        let region = vk::BufferCopy {
+            src_offset: self.offset,
+            dst_offset,
+            size: self.size,
+        };
+        unsafe {
+            traced!(
+                "vkCmdCopyBuffer",
+                src = self.buffer,
+                dst = dst,
+                region = region;
+                device.cmd_copy_buffer(cmd, self.buffer, dst, &[region])
+            )
+        };
+    }
+}
+
+struct Chunk {
+    buffer: Buffer,
+    cursor: vk::DeviceSize,
+}
+
+impl Chunk {
+    fn try_allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        align: vk::DeviceSize,
+    ) -> Option<StagingAllocation> {
+        let base = self.buffer.mapped()?.as_ptr();
+        let offset = self.cursor.next_multiple_of(align.max(1));
+        if offset + size > self.buffer.size {
+            return None;
+        }
+        self.cursor = offset + size;
+        Some(StagingAllocation {
+            buffer: self.buffer.buffer,
+            offset,
+            size,
+            ptr: unsafe { base.byte_add(offset as usize) },
+        })
+    }
+}
+
+/// Hands out upload space from large host coherent buffers and recycles it once the
+/// fence of the submission that consumed it has signalled
+pub struct StagingBelt {
+    chunk_size: vk::DeviceSize,
+    mem_props: vk::PhysicalDeviceMemoryProperties,
+    active: Vec<Chunk>,
+    in_flight: Vec<(vk::Fence, Vec<Chunk>)>,
+    free: Vec<Chunk>,
+}
+
+impl StagingBelt {
+    pub fn new(chunk_size: vk::DeviceSize, mem_props: vk::PhysicalDeviceMemoryProperties) -> Self {
+        StagingBelt {
+            chunk_size,
+            mem_props,
+            active: Vec::new(),
+            in_flight: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn allocate(
+        &mut self,
+        device: &Device,
+        size: vk::DeviceSize,
+        align: vk::DeviceSize,
+    ) -> VkResult<StagingAllocation> {
+        if let Some(alloc) = self
+            .active
+            .iter_mut()
+            .find_map(|chunk| chunk.try_allocate(size, align))
+        {
+            return Ok(alloc);
+        }
+
+        let mut chunk = match self.free.iter().position(|chunk| chunk.buffer.size >= size) {
+            Some(i) => self.free.swap_remove(i),
+            None => {
+                let mut buffer = Buffer::new(
+                    device,
+                    &self.mem_props,
+                    self.chunk_size.max(size),
+                    vk::BufferUsageFlags::TRANSFER_SRC,
+                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                )?;
+                buffer.set_category(MemoryCategory::Staging);
+                Chunk { buffer, cursor: 0 }
+            }
+        };
+        let alloc = chunk
+            .try_allocate(size, align)
+            .expect("fresh chunk should fit the allocation");
+        self.active.push(chunk);
+        Ok(alloc)
+    }
+
+    /// Allocates space and copies `data` into it
+    pub fn write(
+        &mut self,
+        device: &Device,
+        data: &[u8],
+        align: vk::DeviceSize,
+    ) -> VkResult<StagingAllocation> {
+        let alloc = self.allocate(device, data.len() as vk::DeviceSize, align)?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), alloc.ptr.cast(), data.len()) };
+        Ok(alloc)
+    }
+
+    /// Closes the chunks used since the last call, they are reused once `fence` signals
+    pub fn finish(&mut self, fence: vk::Fence) {
+        if !self.active.is_empty() {
+            self.in_flight
+                .push((fence, std::mem::take(&mut self.active)));
+        }
+    }
+
+    /// Returns chunks whose submissions have completed to the free list
+    pub fn recall(&mut self, device: &Device) -> VkResult<()> {
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if unsafe { device.get_fence_status(self.in_flight[i].0)? } {
+                let (_, chunks) = self.in_flight.swap_remove(i);
+                self.free.extend(chunks.into_iter().map(|mut chunk| {
+                    chunk.cursor = 0;
+                    chunk
+                }));
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// No submission using the belt's allocations may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let chunks = self
+            .active
+            .drain(..)
+            .chain(self.in_flight.drain(..).flat_map(|(_, chunks)| chunks))
+            .chain(self.free.drain(..));
+        for chunk in chunks {
+            chunk.buffer.destroy(device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::HeadlessContext;
+
+    #[test]
+    fn chunks_are_shared_and_recycled() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let mut belt = StagingBelt::new(1024, ctx.mem_props);
+        let first = belt.write(&ctx.device, &[1; 100], 4).unwrap();
+        let second = belt.write(&ctx.device, &[2; 100], 256).unwrap();
+        assert_eq!(first.buffer, second.buffer);
+        assert_eq!(second.offset, 256);
+
+        // Bigger than a chunk, gets one of its own
+        let large = belt.allocate(&ctx.device, 4096, 4).unwrap();
+        assert_ne!(large.buffer, first.buffer);
+
+        let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        let fence = unsafe { ctx.device.create_fence(&fence_info, None).unwrap() };
+        belt.finish(fence);
+        belt.recall(&ctx.device).unwrap();
+        let reused = belt.allocate(&ctx.device, 100, 4).unwrap();
+        assert!(reused.buffer == first.buffer || reused.buffer == large.buffer);
+        assert_eq!(reused.offset, 0);
+
+        unsafe {
+            belt.destroy(&ctx.device);
+            ctx.device.destroy_fence(fence, None);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Running totals of device memory per resource category
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use ash::vk;
+
+/// What an allocation is used for, so reports show what is eating memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MemoryCategory {
+    Texture,
+    Mesh,
+    RenderTarget,
+    Staging,
+    Uniform,
+    Other,
+}
+
+impl MemoryCategory {
+    pub const ALL: [MemoryCategory; 6] = [
+        MemoryCategory::Texture,
+        MemoryCategory::Mesh,
+        MemoryCategory::RenderTarget,
+        MemoryCategory::Staging,
+        MemoryCategory::Uniform,
+        MemoryCategory::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryCategory::Texture => "textures",
+            MemoryCategory::Mesh => "meshes",
+            MemoryCategory::RenderTarget => "render targets",
+            MemoryCategory::Staging => "staging",
+            MemoryCategory::Uniform => "uniforms",
+            MemoryCategory::Other => "other",
+        }
+    }
+}
+
+struct Counter {
+    bytes: AtomicU64,
+    allocations: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNTER: Counter = Counter {
+    bytes: AtomicU64::new(0),
+    allocations: AtomicU64::new(0),
+};
+static COUNTERS: [Counter; MemoryCategory::ALL.len()] = [COUNTER; MemoryCategory::ALL.len()];
+
+/// Adds an allocation to the totals, for memory not allocated through [`super::Buffer`]
+pub fn record_allocation(category: MemoryCategory, size: vk::DeviceSize) {
+    let counter = &COUNTERS[category as usize];
+    counter.bytes.fetch_add(size, Ordering::Relaxed);
+    counter.allocations.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Removes an allocation previously added with [`record_allocation`]
+pub fn release_allocation(category: MemoryCategory, size: vk::DeviceSize) {
+    let counter = &COUNTERS[category as usize];
+    counter.bytes.fetch_sub(size, Ordering::Relaxed);
+    counter.allocations.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CategoryUsage {
+    pub category: MemoryCategory,
+    pub bytes: vk::DeviceSize,
+    pub allocations: u64,
+}
+
+/// Snapshot of the per-category totals
+#[derive(Debug, Clone)]
+pub struct UsageReport {
+    pub categories: Vec<CategoryUsage>,
+}
+
+impl UsageReport {
+    pub fn capture() -> Self {
+        UsageReport {
+            categories: MemoryCategory::ALL
+                .into_iter()
+                .map(|category| {
+                    let counter = &COUNTERS[category as usize];
+                    CategoryUsage {
+                        category,
+                        bytes: counter.bytes.load(Ordering::Relaxed),
+                        allocations: counter.allocations.load(Ordering::Relaxed),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    pub fn total(&self) -> vk::DeviceSize {
+        self.categories.iter().map(|usage| usage.bytes).sum()
+    }
+}
+
+impl fmt::Display for UsageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for usage in self.categories.iter().filter(|usage| usage.allocations > 0) {
+            writeln!(
+                f,
+                "{:>15}: {:>8.2} MiB in {} allocations",
+                usage.category.name(),
+                usage.bytes as f64 / (1024. * 1024.),
+                usage.allocations
+            )?;
+        }
+        write!(
+            f,
+            "{:>15}: {:>8.2} MiB",
+            "total",
+            self.total() as f64 / (1024. * 1024.)
+        )
+    }
+}
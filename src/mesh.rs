@@ -0,0 +1,145 @@
+//! Vertex and index buffers of a mesh in device local memory
+
+use std::ops::Range;
+
+use ash::{prelude::VkResult, vk, Device};
+
+use crate::{
+    assets::GpuAsset,
+    layout::{slice_as_bytes, AsBytes},
+    memory::{staging::StagingBelt, usage::MemoryCategory, Buffer},
+};
+
+pub struct Mesh {
+    pub vertices: Buffer,
+    pub indices: Option<Buffer>,
+    pub vertex_count: u32,
+    pub index_count: u32,
+}
+
+impl Mesh {
+    /// Creates the buffers and records the copies from the staging belt into `cmd`
+    ///
+    /// The mesh is usable once `cmd` has executed, `indices` may be empty for non-indexed meshes.
+    pub fn upload<V: AsBytes>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        vertices: &[V],
+        indices: &[u32],
+    ) -> VkResult<Self> {
+        let vertex_count = vertices.len() as u32;
+        let vertices = upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(vertices),
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        let index_buffer = if indices.is_empty() {
+            None
+        } else {
+            match upload_buffer(
+                device,
+                mem_props,
+                staging,
+                cmd,
+                slice_as_bytes(indices),
+                vk::BufferUsageFlags::INDEX_BUFFER,
+            ) {
+                Ok(buffer) => Some(buffer),
+                Err(err) => {
+                    unsafe { vertices.destroy(device) };
+                    return Err(err);
+                }
+            }
+        };
+
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            )
+        };
+
+        Ok(Mesh {
+            vertices,
+            vertex_count,
+            index_count: indices.len() as u32,
+            indices: index_buffer,
+        })
+    }
+
+    /// Binds the buffers at binding 0 and draws the given instances
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, instances: Range<u32>) {
+        unsafe {
+            device.cmd_bind_vertex_buffers(cmd, 0, &[self.vertices.buffer], &[0]);
+            match &self.indices {
+                Some(indices) => {
+                    device.cmd_bind_index_buffer(cmd, indices.buffer, 0, vk::IndexType::UINT32);
+                    device.cmd_draw_indexed(
+                        cmd,
+                        self.index_count,
+                        instances.len() as u32,
+                        0,
+                        0,
+                        instances.start,
+                    );
+                }
+                None => device.cmd_draw(
+                    cmd,
+                    self.vertex_count,
+                    instances.len() as u32,
+                    0,
+                    instances.start,
+                ),
+            }
+        }
+    }
+}
+
+impl GpuAsset for Mesh {
+    unsafe fn destroy(&self, device: &Device) {
+        self.vertices.destroy(device);
+        if let Some(indices) = &self.indices {
+            indices.destroy(device);
+        }
+    }
+}
+
+pub(crate) fn upload_buffer(
+    device: &Device,
+    mem_props: &vk::PhysicalDeviceMemoryProperties,
+    staging: &mut StagingBelt,
+    cmd: vk::CommandBuffer,
+    data: &[u8],
+    usage: vk::BufferUsageFlags,
+) -> VkResult<Buffer> {
+    let mut buffer = Buffer::new(
+        device,
+        mem_props,
+        data.len() as vk::DeviceSize,
+        usage | vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+    buffer.set_category(MemoryCategory::Mesh);
+    match staging.write(device, data, 4) {
+        Ok(alloc) => alloc.copy_to_buffer(device, cmd, buffer.buffer, 0),
+        Err(err) => {
+            unsafe { buffer.destroy(device) };
+            return Err(err);
+        }
+    }
+    Ok(buffer)
+}
@@ -0,0 +1,582 @@
+//! Per-pixel motion vectors and a velocity-based motion blur
+//!
+//! The main pass writes how far each pixel moved on screen since the last frame into a
+//! [`MOTION_FORMAT`] attachment: the `MOTION_VECTORS` variant of
+//! [`crate::animation::skinning::MESH_VERT_GLSL`] projects every vertex with this and last frame's
+//! matrices from [`MotionUniforms`], and the material's fragment shader writes the difference with
+//! [`MOTION_FRAG_GLSL`]. Only the camera's movement is captured, objects moving on their own would
+//! also need their previous model matrix.
+//!
+//! Vectors are in uv units from the previous position to the current one, so `uv - motion` is
+//! where a pixel was last frame. The matrices should be the unjittered ones, which keeps the
+//! vectors usable for temporal anti-aliasing's reprojection.
+//!
+//! [`MotionBlur`] then smears the scene along each pixel's vector, only gathering from neighbours
+//! that move far enough to reach it so static foregrounds stay sharp over moving backgrounds.
+
+use std::error::Error;
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec4};
+use thiserror::Error;
+
+use crate::{
+    layout::{AsBytes, ShaderLayout},
+    leaks,
+    memory::{usage::MemoryCategory, Buffer},
+    shader::{reflect, ShaderCompiler},
+    texture::sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+};
+
+/// Descriptor set index of the motion matrices, after the material set
+pub const MOTION_SET: u32 = 2;
+
+/// Two 16-bit floats are plenty for screen fractions
+pub const MOTION_FORMAT: vk::Format = vk::Format::R16G16_SFLOAT;
+
+/// Fragment inputs and output for the `MOTION_VECTORS` mesh variant, a material's fragment shader
+/// includes it under the same keyword and calls `write_motion` to fill its second attachment
+pub const MOTION_FRAG_GLSL: &str = r#"
+#ifdef MOTION_VECTORS
+layout(location = 2) in vec4 in_clip;
+layout(location = 3) in vec4 in_prev_clip;
+
+layout(location = 1) out vec2 out_motion;
+
+void write_motion() {
+    out_motion = (in_clip.xy / in_clip.w - in_prev_clip.xy / in_prev_clip.w) * 0.5;
+}
+#endif
+"#;
+
+pub const MOTION_BLUR_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = uv;
+}
+"#;
+
+pub const MOTION_BLUR_FRAG_GLSL: &str = r#"#version 450
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+layout(set = 0, binding = 0) uniform texture2D scene_color;
+layout(set = 0, binding = 1) uniform texture2D motion;
+layout(set = 0, binding = 2) uniform sampler clamped;
+
+layout(push_constant) uniform Push {
+    // x: fraction of the frame the shutter is open for, y: longest blur in screen fractions,
+    // z: samples along the blur
+    vec4 params;
+} push;
+
+vec2 velocity(vec2 at) {
+    vec2 v = textureLod(sampler2D(motion, clamped), at, 0.0).xy * push.params.x;
+    float len = length(v);
+    return len > push.params.y ? v * (push.params.y / len) : v;
+}
+
+void main() {
+    vec4 center = textureLod(sampler2D(scene_color, clamped), uv, 0.0);
+    vec2 v = velocity(uv);
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(scene_color, clamped), 0));
+    if (length(v / texel) < 0.5) {
+        out_color = center;
+        return;
+    }
+    int samples = int(push.params.z);
+    vec3 sum = center.rgb;
+    float total = 1.0;
+    for (int i = 0; i < samples; i++) {
+        float offset = (float(i) + 0.5) / float(samples) - 0.5;
+        vec2 at = uv + v * offset;
+        // Neighbours only count if they move far enough to cover this pixel
+        float reach = length(velocity(at)) * 0.5;
+        float weight = clamp(reach / max(length(v * offset), 0.0001), 0.0, 1.0);
+        sum += textureLod(sampler2D(scene_color, clamped), at, 0.0).rgb * weight;
+        total += weight;
+    }
+    out_color = vec4(sum / total, center.a);
+}
+"#;
+
+/// Matrices the `MOTION_VECTORS` mesh variant reads at [`MOTION_SET`], binding 0
+#[derive(Debug, Clone, Copy, PartialEq, ShaderLayout, AsBytes)]
+#[layout(std140)]
+#[repr(C)]
+pub struct MotionBlock {
+    pub view_proj: Mat4,
+    pub prev_view_proj: Mat4,
+}
+
+/// This and last frame's view-projection, one buffer and descriptor set per frame in flight
+pub struct MotionUniforms {
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    buffers: Vec<Buffer>,
+    previous: Option<Mat4>,
+}
+
+impl MotionUniforms {
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        frames_in_flight: usize,
+    ) -> VkResult<Self> {
+        let mut this = MotionUniforms {
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            buffers: Vec::new(),
+            previous: None,
+        };
+        let result = unsafe { this.create_objects(device, mem_props, frames_in_flight) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        frames_in_flight: usize,
+    ) -> VkResult<()> {
+        let binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .build();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[binding]),
+            None,
+        )?;
+        leaks::track(self.set_layout, "motion descriptor set layout");
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: frames_in_flight as u32,
+        };
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(frames_in_flight as u32)
+                .pool_sizes(&[pool_size]),
+            None,
+        )?;
+        leaks::track(self.pool, "motion descriptor pool");
+        let layouts = vec![self.set_layout; frames_in_flight];
+        self.sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&layouts),
+        )?;
+
+        for set in self.sets.clone() {
+            let mut buffer = Buffer::new(
+                device,
+                mem_props,
+                MotionBlock::STD140_SIZE as vk::DeviceSize,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            )?;
+            buffer.set_category(MemoryCategory::Uniform);
+            let info = [vk::DescriptorBufferInfo {
+                buffer: buffer.buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            }];
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&info)
+                .build();
+            device.update_descriptor_sets(&[write], &[]);
+            self.buffers.push(buffer);
+        }
+        Ok(())
+    }
+
+    /// Layout to put at [`MOTION_SET`] of pipelines built with the `MOTION_VECTORS` variant
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    /// Writes `view_proj` and the one from the previous update into `frame`'s buffer, which the
+    /// device must not be reading
+    pub fn update(&mut self, frame: usize, view_proj: Mat4) {
+        let block = MotionBlock {
+            view_proj,
+            prev_view_proj: self.previous.unwrap_or(view_proj),
+        };
+        let mapped = self.buffers[frame]
+            .mapped()
+            .expect("motion block is host visible");
+        unsafe { block.write_to(mapped.as_ptr()) };
+        self.previous = Some(view_proj);
+    }
+
+    /// Forgets the previous matrices so the next frame has no motion, e.g. after a camera cut
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Binds `frame`'s matrices to [`MOTION_SET`] of `layout`
+    pub fn bind(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        layout: vk::PipelineLayout,
+        frame: usize,
+    ) {
+        unsafe {
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                layout,
+                MOTION_SET,
+                &[self.sets[frame]],
+                &[],
+            )
+        };
+    }
+
+    /// # Safety
+    ///
+    /// No recorded draw using the matrices may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.buffers.drain(..) {
+            buffer.destroy(device);
+        }
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        self.sets.clear();
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MotionBlurError {
+    #[error("failed to compile motion blur shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// What gets blurred, both in `SHADER_READ_ONLY_OPTIMAL` and the same size
+#[derive(Debug, Clone, Copy)]
+pub struct MotionBlurInputs {
+    pub color: vk::ImageView,
+    /// Vectors in [`MOTION_FORMAT`]
+    pub motion: vk::ImageView,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MotionBlurConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+}
+
+impl MotionBlurConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        MotionBlurConfig {
+            render_pass,
+            subpass: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct MotionBlurPush {
+    params: Vec4,
+}
+
+pub struct MotionBlur {
+    /// Fraction of the frame's motion the shutter sees, half a frame looks like film
+    pub shutter: f32,
+    /// Longest blur in screen fractions, faster movement is clamped to it
+    pub max_length: f32,
+    pub samples: u32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+}
+
+impl MotionBlur {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        inputs: MotionBlurInputs,
+        config: MotionBlurConfig,
+    ) -> Result<Self, MotionBlurError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| MotionBlurError::Compile(Box::new(err)))
+        };
+        let vert = compile(MOTION_BLUR_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(MOTION_BLUR_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Linear)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = MotionBlur {
+            shutter: 0.5,
+            max_length: 0.05,
+            samples: 12,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            sampler,
+        };
+        let result = unsafe {
+            this.create_objects(device, &vert, &frag, config)
+                .map(|_| this.set_inputs(device, inputs))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        vert: &[u32],
+        frag: &[u32],
+        config: MotionBlurConfig,
+    ) -> Result<(), MotionBlurError> {
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "motion descriptor set layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "motion descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: std::mem::size_of::<MotionBlurPush>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "motion pipeline layout");
+
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[push_range], &[])?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "motion shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "motion shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder();
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "motion blur");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Points the pass at new inputs, e.g. after they were recreated for a resize
+    ///
+    /// # Safety
+    ///
+    /// No recorded draw of the blur may still be executing.
+    pub unsafe fn set_inputs(&self, device: &Device, inputs: MotionBlurInputs) {
+        let image = |image_view| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }]
+        };
+        let images = [image(inputs.color), image(inputs.motion)];
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let mut writes: Vec<_> = (0..)
+            .zip(&images)
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        writes.push(
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler)
+                .build(),
+        );
+        device.update_descriptor_sets(&writes, &[]);
+    }
+
+    /// Records the blurred scene into the current target, which must not be the color input
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let push = MotionBlurPush {
+            params: Vec4::new(self.shutter, self.max_length, self.samples as f32, 0.0),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded draw of the blur may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
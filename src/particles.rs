@@ -0,0 +1,1140 @@
+//! Particles simulated in compute shaders and drawn as camera facing billboards
+//!
+//! Each frame an emit kernel spawns the new particles into a ring of slots, replacing the oldest
+//! ones when it's full, and an update kernel integrates the live ones. Every workgroup of the
+//! update gathers its live slots, then a compact kernel joins those into one alive list and
+//! writes its length into an indirect draw command, so the CPU never reads back how many
+//! particles there are. The gathering goes through shared memory rather than atomics, which
+//! naga's GLSL frontend doesn't have.
+//!
+//! Blended billboards only look right drawn back to front, so [`ParticleSystem::sort`] can order
+//! the alive list by view depth with a bitonic sort before the draw. Without it particles pop in
+//! front of each other as their slots get reused.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    layout::AsBytes,
+    leaks,
+    memory::Buffer,
+    shader::{reflect, ShaderCompiler},
+};
+
+/// Particle storage shared by every stage, position w is the age and velocity w the lifetime
+///
+/// The vertex stage only reads, writing from it would need `vertexPipelineStoresAndAtomics`.
+const PARTICLE_BUFFERS_GLSL: &str = r#"
+#ifdef VERTEX_STAGE
+#define ACCESS readonly
+#else
+#define ACCESS
+#endif
+
+struct Particle {
+    vec4 position;
+    vec4 velocity;
+};
+
+layout(set = 0, binding = 0) ACCESS buffer Particles {
+    Particle items[];
+} particles;
+
+layout(set = 0, binding = 1) ACCESS buffer Alive {
+    uint indices[];
+} alive;
+
+#ifndef VERTEX_STAGE
+layout(set = 0, binding = 2) buffer Draw {
+    uint vertex_count;
+    uint instance_count;
+    uint first_vertex;
+    uint first_instance;
+} draw;
+
+// Live particles of each update workgroup, packed at the start of the workgroup's slots
+layout(set = 0, binding = 3) buffer Groups {
+    uint counts[];
+} groups;
+
+layout(set = 0, binding = 4) buffer Gathered {
+    uint indices[];
+} gathered;
+
+// View depth of each alive entry, padded to a power of two for the sort
+layout(set = 0, binding = 5) buffer Keys {
+    float depths[];
+} keys;
+#endif
+"#;
+
+/// Spawns `count` particles into the slots after `first`, wrapping around the ring
+pub const PARTICLE_EMIT_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+layout(push_constant) uniform Push {
+    // w: radius of the spawn sphere
+    vec4 position;
+    // w: half angle of the spawn cone
+    vec4 direction;
+    // Speed and lifetime ranges
+    vec4 ranges;
+    uint first;
+    uint count;
+    uint capacity;
+    uint seed;
+} push;
+
+uint hash(uint x) {
+    x ^= x >> 16;
+    x *= 0x7feb352du;
+    x ^= x >> 15;
+    x *= 0x846ca68bu;
+    x ^= x >> 16;
+    return x;
+}
+
+float random(inout uint state) {
+    state = hash(state);
+    return float(state) / 4294967295.0;
+}
+
+vec3 random_unit(inout uint state) {
+    float z = random(state) * 2.0 - 1.0;
+    float phi = 6.2831853 * random(state);
+    float r = sqrt(max(1.0 - z * z, 0.0));
+    return vec3(r * cos(phi), r * sin(phi), z);
+}
+
+void main() {
+    uint i = gl_GlobalInvocationID.x;
+    if (i >= push.count) {
+        return;
+    }
+    uint state = hash(push.seed ^ (i * 0x9e3779b9u));
+    vec3 offset = random_unit(state) * push.position.w * pow(random(state), 1.0 / 3.0);
+
+    vec3 axis = normalize(push.direction.xyz);
+    vec3 helper = abs(axis.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(axis, helper));
+    vec3 bitangent = cross(axis, tangent);
+    float cos_theta = mix(1.0, cos(push.direction.w), random(state));
+    float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    float phi = 6.2831853 * random(state);
+    vec3 direction = axis * cos_theta + (tangent * cos(phi) + bitangent * sin(phi)) * sin_theta;
+
+    float speed = mix(push.ranges.x, push.ranges.y, random(state));
+    float lifetime = mix(push.ranges.z, push.ranges.w, random(state));
+    uint slot = (push.first + i) % push.capacity;
+    particles.items[slot].position = vec4(push.position.xyz + offset, 0.0);
+    particles.items[slot].velocity = vec4(direction * speed, lifetime);
+}
+"#;
+
+/// Integrates live particles and gathers each workgroup's live slots
+pub const PARTICLE_UPDATE_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+layout(push_constant) uniform Push {
+    // w: drag, the fraction of velocity lost per second
+    vec4 gravity;
+    float dt;
+    uint capacity;
+} push;
+
+shared uint live[64];
+
+void main() {
+    uint i = gl_GlobalInvocationID.x;
+    uint local = gl_LocalInvocationID.x;
+    live[local] = 0u;
+    if (i < push.capacity) {
+        vec4 position = particles.items[i].position;
+        vec4 velocity = particles.items[i].velocity;
+        if (position.w < velocity.w) {
+            velocity.xyz += push.gravity.xyz * push.dt;
+            velocity.xyz *= max(1.0 - push.gravity.w * push.dt, 0.0);
+            position.xyz += velocity.xyz * push.dt;
+            position.w += push.dt;
+            particles.items[i].position = position;
+            particles.items[i].velocity = velocity;
+            if (position.w < velocity.w) {
+                live[local] = 1u;
+            }
+        }
+    }
+    barrier();
+    if (local == 0u) {
+        uint first = gl_WorkGroupID.x * 64u;
+        uint count = 0u;
+        for (uint j = 0u; j < 64u; j++) {
+            if (live[j] != 0u) {
+                gathered.indices[first + count] = first + j;
+                count++;
+            }
+        }
+        groups.counts[gl_WorkGroupID.x] = count;
+    }
+}
+"#;
+
+/// Joins the gathered slots of every update workgroup into the alive list and draw command
+pub const PARTICLE_COMPACT_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+layout(push_constant) uniform Push {
+    vec4 gravity;
+    float dt;
+    uint capacity;
+} push;
+
+shared uint offset;
+
+void main() {
+    uint group = gl_WorkGroupID.x;
+    uint local = gl_LocalInvocationID.x;
+    if (local == 0u) {
+        uint sum = 0u;
+        for (uint g = 0u; g < group; g++) {
+            sum += groups.counts[g];
+        }
+        offset = sum;
+        if (group == (push.capacity + 63u) / 64u - 1u) {
+            draw.vertex_count = 4u;
+            draw.instance_count = sum + groups.counts[group];
+            draw.first_vertex = 0u;
+            draw.first_instance = 0u;
+        }
+    }
+    barrier();
+    if (local < groups.counts[group]) {
+        alive.indices[offset + local] = gathered.indices[group * 64u + local];
+    }
+}
+"#;
+
+/// Writes the view depth of every alive particle, padding the rest to sort after all of them
+pub const PARTICLE_SORT_KEYS_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+layout(push_constant) uniform Push {
+    // Third row of the view matrix
+    vec4 view_z;
+    uint size;
+    uint j;
+    uint k;
+} push;
+
+void main() {
+    uint i = gl_GlobalInvocationID.x;
+    if (i >= push.size) {
+        return;
+    }
+    if (i < draw.instance_count) {
+        vec3 position = particles.items[alive.indices[i]].position.xyz;
+        keys.depths[i] = -(dot(push.view_z.xyz, position) + push.view_z.w);
+    } else {
+        keys.depths[i] = -3.4e38;
+        alive.indices[i] = 0u;
+    }
+}
+"#;
+
+/// One compare and swap step of a bitonic sort, ordering the alive list farthest first
+pub const PARTICLE_SORT_STEP_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+layout(push_constant) uniform Push {
+    vec4 view_z;
+    uint size;
+    // Distance to the partner and size of the blocks being merged
+    uint j;
+    uint k;
+} push;
+
+void main() {
+    uint i = gl_GlobalInvocationID.x;
+    uint partner = i ^ push.j;
+    if (i >= push.size || partner <= i) {
+        return;
+    }
+    float a = keys.depths[i];
+    float b = keys.depths[partner];
+    bool descending = (i & push.k) == 0u;
+    if (descending ? a < b : a > b) {
+        keys.depths[i] = b;
+        keys.depths[partner] = a;
+        uint index = alive.indices[i];
+        alive.indices[i] = alive.indices[partner];
+        alive.indices[partner] = index;
+    }
+}
+"#;
+
+/// Expands each alive particle into a quad, sized and colored by its age
+pub const PARTICLE_VERT_GLSL: &str = r#"
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    // w: size at birth
+    vec4 right;
+    // w: size at death
+    vec4 up;
+    vec4 color_start;
+    vec4 color_end;
+} push;
+
+layout(location = 0) out vec4 out_color;
+layout(location = 1) out vec2 out_uv;
+
+void main() {
+    Particle particle = particles.items[alive.indices[gl_InstanceIndex]];
+    float t = clamp(particle.position.w / particle.velocity.w, 0.0, 1.0);
+    float size = mix(push.right.w, push.up.w, t);
+    vec2 corner = vec2(float(gl_VertexIndex & 1), float(gl_VertexIndex >> 1));
+    vec3 offset = push.right.xyz * (corner.x - 0.5) + push.up.xyz * (corner.y - 0.5);
+    gl_Position = push.view_proj * vec4(particle.position.xyz + offset * size, 1.0);
+    out_color = mix(push.color_start, push.color_end, t);
+    out_uv = corner;
+}
+"#;
+
+pub const PARTICLE_FRAG_GLSL: &str = r#"#version 450
+layout(location = 0) in vec4 color;
+layout(location = 1) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float fade = 1.0 - smoothstep(0.5, 1.0, length(uv * 2.0 - 1.0));
+    out_color = vec4(color.rgb, color.a * fade);
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Debug, Error)]
+pub enum ParticleError {
+    #[error("failed to compile particle shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Where, how fast and in which direction new particles appear, and how they look over their life
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleEmitter {
+    pub position: Vec3,
+    /// Particles spawn anywhere inside this sphere around `position`
+    pub radius: f32,
+    pub direction: Vec3,
+    /// Half angle in radians of the cone particles are launched in
+    pub spread: f32,
+    /// Particles per second
+    pub rate: f32,
+    pub speed: (f32, f32),
+    /// Seconds, picked uniformly between the two
+    pub lifetime: (f32, f32),
+    /// At birth and at death
+    pub size: (f32, f32),
+    pub color: (Vec4, Vec4),
+}
+
+impl ParticleEmitter {
+    pub fn new(position: Vec3) -> Self {
+        ParticleEmitter {
+            position,
+            radius: 0.0,
+            direction: Vec3::Y,
+            spread: 0.3,
+            rate: 100.0,
+            speed: (1.0, 2.0),
+            lifetime: (1.0, 2.0),
+            size: (0.1, 0.0),
+            color: (Vec4::ONE, Vec4::new(1.0, 1.0, 1.0, 0.0)),
+        }
+    }
+
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    pub fn with_direction(mut self, direction: Vec3, spread: f32) -> Self {
+        self.direction = direction;
+        self.spread = spread;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: f32) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn with_speed(mut self, min: f32, max: f32) -> Self {
+        self.speed = (min, max);
+        self
+    }
+
+    pub fn with_lifetime(mut self, min: f32, max: f32) -> Self {
+        self.lifetime = (min, max);
+        self
+    }
+
+    pub fn with_size(mut self, start: f32, end: f32) -> Self {
+        self.size = (start, end);
+        self
+    }
+
+    pub fn with_color(mut self, start: Vec4, end: Vec4) -> Self {
+        self.color = (start, end);
+        self
+    }
+}
+
+/// Applied to every live particle each step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleForces {
+    pub gravity: Vec3,
+    /// Fraction of velocity lost per second
+    pub drag: f32,
+}
+
+impl Default for ParticleForces {
+    fn default() -> Self {
+        ParticleForces {
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            drag: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParticleConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Slots in the ring, the oldest particles are replaced once it's full
+    pub capacity: u32,
+    /// Start of the sequence emitted particles are randomized from
+    pub seed: u32,
+}
+
+impl ParticleConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        ParticleConfig {
+            render_pass,
+            subpass: 0,
+            capacity: 65536,
+            seed: 0,
+        }
+    }
+
+    /// Seeds the system from a run's seed, e.g. [`crate::input::replay::InputRecording::seed`]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = (seed ^ seed >> 32) as u32;
+        self
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct EmitPush {
+    position: Vec4,
+    direction: Vec4,
+    ranges: Vec4,
+    first: u32,
+    count: u32,
+    capacity: u32,
+    seed: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct UpdatePush {
+    gravity: Vec4,
+    dt: f32,
+    capacity: u32,
+    padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct DrawPush {
+    view_proj: Mat4,
+    right: Vec4,
+    up: Vec4,
+    color_start: Vec4,
+    color_end: Vec4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct SortPush {
+    view_z: Vec4,
+    size: u32,
+    j: u32,
+    k: u32,
+    padding: u32,
+}
+
+pub struct ParticleSystem {
+    pub emitter: ParticleEmitter,
+    pub forces: ParticleForces,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    compute_layout: vk::PipelineLayout,
+    emit_pipeline: vk::Pipeline,
+    update_pipeline: vk::Pipeline,
+    compact_pipeline: vk::Pipeline,
+    sort_keys_pipeline: vk::Pipeline,
+    sort_step_pipeline: vk::Pipeline,
+    draw_layout: vk::PipelineLayout,
+    draw_pipeline: vk::Pipeline,
+    particles: Option<Buffer>,
+    alive: Option<Buffer>,
+    draw: Option<Buffer>,
+    groups: Option<Buffer>,
+    gathered: Option<Buffer>,
+    keys: Option<Buffer>,
+    capacity: u32,
+    /// Capacity rounded up to a power of two, the length of the alive list and keys
+    sort_size: u32,
+    /// Next slot to spawn into
+    cursor: u32,
+    /// Fractional particles carried over to the next step
+    accumulator: f32,
+    burst: u32,
+    seed: u32,
+    cleared: bool,
+}
+
+impl ParticleSystem {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        config: ParticleConfig,
+        emitter: ParticleEmitter,
+    ) -> Result<Self, ParticleError> {
+        let compile = |body: &str, stage| {
+            let define = match stage {
+                vk::ShaderStageFlags::VERTEX => "#define VERTEX_STAGE\n",
+                _ => "",
+            };
+            compiler
+                .compile(
+                    &format!("#version 450\n{define}{PARTICLE_BUFFERS_GLSL}{body}"),
+                    stage,
+                )
+                .map_err(|err| ParticleError::Compile(Box::new(err)))
+        };
+        let emit = compile(PARTICLE_EMIT_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let update = compile(PARTICLE_UPDATE_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let compact = compile(PARTICLE_COMPACT_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let sort_keys = compile(PARTICLE_SORT_KEYS_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let sort_step = compile(PARTICLE_SORT_STEP_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let vert = compile(PARTICLE_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compiler
+            .compile(PARTICLE_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)
+            .map_err(|err| ParticleError::Compile(Box::new(err)))?;
+
+        let mut this = ParticleSystem {
+            emitter,
+            forces: ParticleForces::default(),
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            compute_layout: vk::PipelineLayout::null(),
+            emit_pipeline: vk::Pipeline::null(),
+            update_pipeline: vk::Pipeline::null(),
+            compact_pipeline: vk::Pipeline::null(),
+            sort_keys_pipeline: vk::Pipeline::null(),
+            sort_step_pipeline: vk::Pipeline::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            particles: None,
+            alive: None,
+            draw: None,
+            groups: None,
+            gathered: None,
+            keys: None,
+            capacity: config.capacity.max(1),
+            sort_size: config.capacity.max(1).next_power_of_two(),
+            cursor: 0,
+            accumulator: 0.0,
+            burst: 0,
+            seed: config.seed,
+            cleared: false,
+        };
+        let result = unsafe {
+            this.create_objects(
+                device,
+                mem_props,
+                &[
+                    &emit, &update, &compact, &sort_keys, &sort_step, &vert, &frag,
+                ],
+                config,
+            )
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn with_forces(mut self, forces: ParticleForces) -> Self {
+        self.forces = forces;
+        self
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        code: &[&[u32]; 7],
+        config: ParticleConfig,
+    ) -> Result<(), ParticleError> {
+        let bindings: Vec<_> = (0..6)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "particles descriptor set layout");
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 6,
+        };
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&[pool_size]),
+            None,
+        )?;
+        leaks::track(self.pool, "particles descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+
+        let capacity = self.capacity as vk::DeviceSize;
+        let sort_size = self.sort_size as vk::DeviceSize;
+        let groups = self.capacity.div_ceil(WORKGROUP_SIZE) as vk::DeviceSize;
+        let index_size = mem::size_of::<u32>() as u64;
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let buffers = [
+            (
+                capacity * 2 * mem::size_of::<Vec4>() as u64,
+                storage | vk::BufferUsageFlags::TRANSFER_DST,
+                &mut self.particles,
+            ),
+            (sort_size * index_size, storage, &mut self.alive),
+            (
+                mem::size_of::<vk::DrawIndirectCommand>() as u64,
+                storage | vk::BufferUsageFlags::INDIRECT_BUFFER,
+                &mut self.draw,
+            ),
+            (groups * index_size, storage, &mut self.groups),
+            (capacity * index_size, storage, &mut self.gathered),
+            (
+                sort_size * mem::size_of::<f32>() as u64,
+                storage,
+                &mut self.keys,
+            ),
+        ];
+        let mut infos = Vec::new();
+        for (size, usage, slot) in buffers {
+            let buffer = Buffer::new(
+                device,
+                mem_props,
+                size,
+                usage,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            infos.push([vk::DescriptorBufferInfo {
+                buffer: buffer.buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            }]);
+            *slot = Some(buffer);
+        }
+        let writes: Vec<_> = (0..)
+            .zip(&infos)
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info)
+                    .build()
+            })
+            .collect();
+        device.update_descriptor_sets(&writes, &[]);
+
+        let compute_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: mem::size_of::<EmitPush>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[compute_range]),
+        )?;
+        leaks::track(self.compute_layout, "particles pipeline layout");
+        let draw_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: mem::size_of::<DrawPush>() as u32,
+        };
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[draw_range]),
+        )?;
+        leaks::track(self.draw_layout, "particles pipeline layout");
+
+        let [emit, update, compact, sort_keys, sort_step, vert, frag] = code;
+        for compute in [emit, update, compact, sort_keys, sort_step] {
+            reflect::debug_validate(&[compute], &[&bindings], &[compute_range], &[])?;
+        }
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[draw_range], &[])?;
+        self.emit_pipeline = compute_pipeline(device, self.compute_layout, emit, "particles emit")?;
+        self.update_pipeline =
+            compute_pipeline(device, self.compute_layout, update, "particles update")?;
+        self.compact_pipeline =
+            compute_pipeline(device, self.compute_layout, compact, "particles compact")?;
+        self.sort_keys_pipeline = compute_pipeline(
+            device,
+            self.compute_layout,
+            sort_keys,
+            "particles sort keys",
+        )?;
+        self.sort_step_pipeline = compute_pipeline(
+            device,
+            self.compute_layout,
+            sort_step,
+            "particles sort step",
+        )?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "particles shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "particles shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "particles draw");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Restarts the sequence emitted particles are randomized from, so runs can be repeated
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    /// Spawns `count` extra particles on the next step
+    pub fn burst(&mut self, count: u32) {
+        self.burst = self.burst.saturating_add(count);
+    }
+
+    /// Records emitting and advancing the particles by `dt` seconds
+    ///
+    /// Must be recorded outside a render pass, before [`ParticleSystem::draw`].
+    pub fn simulate(&mut self, device: &Device, cmd: vk::CommandBuffer, dt: f32) {
+        let Some(particles) = &self.particles else {
+            return;
+        };
+        self.accumulator += self.emitter.rate * dt;
+        let spawned = self.accumulator as u32;
+        self.accumulator -= spawned as f32;
+        let count = (spawned + mem::take(&mut self.burst)).min(self.capacity);
+        let first = self.cursor;
+        self.cursor = (self.cursor + count) % self.capacity;
+        self.seed = self.seed.wrapping_add(0x9e37_79b9);
+
+        let emitter = &self.emitter;
+        let emit = EmitPush {
+            position: emitter.position.extend(emitter.radius),
+            direction: emitter.direction.extend(emitter.spread),
+            ranges: Vec4::new(
+                emitter.speed.0,
+                emitter.speed.1,
+                emitter.lifetime.0,
+                emitter.lifetime.1,
+            ),
+            first,
+            count,
+            capacity: self.capacity,
+            seed: self.seed,
+        };
+        let update = UpdatePush {
+            gravity: self.forces.gravity.extend(self.forces.drag),
+            dt,
+            capacity: self.capacity,
+            padding: [0; 2],
+        };
+        unsafe {
+            // Last frame's draw has to be done reading before anything is overwritten
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::PipelineStageFlags::TRANSFER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+            if !self.cleared {
+                // Zero age and lifetime marks every slot dead
+                device.cmd_fill_buffer(cmd, particles.buffer, 0, vk::WHOLE_SIZE, 0);
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                );
+                self.cleared = true;
+            }
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            if count > 0 {
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.emit_pipeline);
+                device.cmd_push_constants(
+                    cmd,
+                    self.compute_layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    emit.as_bytes(),
+                );
+                device.cmd_dispatch(cmd, count.div_ceil(WORKGROUP_SIZE), 1, 1);
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::SHADER_WRITE,
+                    vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                );
+            }
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.update_pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                update.as_bytes(),
+            );
+            let groups = self.capacity.div_ceil(WORKGROUP_SIZE);
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            );
+            // Same push constants, the compact kernel only reads the capacity
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.compact_pipeline);
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::INDIRECT_COMMAND_READ,
+            );
+        }
+    }
+
+    /// Records ordering the alive particles back to front as seen through `view`
+    ///
+    /// Must be recorded after [`ParticleSystem::simulate`] and outside a render pass. Sorts the
+    /// whole list since the CPU doesn't know how many are alive, taking `log2(n)^2 / 2` dispatches
+    /// for a capacity of `n`.
+    pub fn sort(&self, device: &Device, cmd: vk::CommandBuffer, view: Mat4) {
+        if self.keys.is_none() {
+            return;
+        }
+        let mut push = SortPush {
+            view_z: view.row(2),
+            size: self.sort_size,
+            j: 0,
+            k: 0,
+            padding: 0,
+        };
+        let groups = self.sort_size.div_ceil(WORKGROUP_SIZE);
+        let compute_barrier = || unsafe {
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            )
+        };
+        unsafe {
+            compute_barrier();
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.sort_keys_pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.sort_step_pipeline);
+            let mut k = 2;
+            while k <= self.sort_size {
+                let mut j = k / 2;
+                while j > 0 {
+                    compute_barrier();
+                    (push.j, push.k) = (j, k);
+                    device.cmd_push_constants(
+                        cmd,
+                        self.compute_layout,
+                        vk::ShaderStageFlags::COMPUTE,
+                        0,
+                        push.as_bytes(),
+                    );
+                    device.cmd_dispatch(cmd, groups, 1, 1);
+                    j /= 2;
+                }
+                k *= 2;
+            }
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::VERTEX_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+        }
+    }
+
+    /// Records the billboards, facing the camera whose view matrix is `view`
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, view: Mat4, proj: Mat4) {
+        let Some(draw) = &self.draw else {
+            return;
+        };
+        // Rows of the view rotation are the camera axes in world space
+        let camera = view.transpose();
+        let push = DrawPush {
+            view_proj: proj * view,
+            right: camera.x_axis.truncate().extend(self.emitter.size.0),
+            up: camera.y_axis.truncate().extend(self.emitter.size.1),
+            color_start: self.emitter.color.0,
+            color_end: self.emitter.color.1,
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.draw_layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw_indirect(cmd, draw.buffer, 0, 1, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded simulation or draw may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in [
+            &mut self.particles,
+            &mut self.alive,
+            &mut self.draw,
+            &mut self.groups,
+            &mut self.gathered,
+            &mut self.keys,
+        ] {
+            if let Some(buffer) = buffer.take() {
+                buffer.destroy(device);
+            }
+        }
+        for pipeline in [
+            self.emit_pipeline,
+            self.update_pipeline,
+            self.compact_pipeline,
+            self.sort_keys_pipeline,
+            self.sort_step_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "particles shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
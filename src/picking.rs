@@ -0,0 +1,543 @@
+//! Click-to-select by rendering object ids into an `R32_UINT` attachment and reading back the
+//! pixel under the cursor a few frames later
+//!
+//! Ids come from the instance index, so an id pass drawing the instances of
+//! [`Scene::gather_instances`](crate::scene::Scene::gather_instances) maps back to nodes through
+//! [`Scene::gather_instance_nodes`](crate::scene::Scene::gather_instance_nodes). Zero is left for
+//! the background. [`raycast`] is the alternative without an extra pass.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::Mat4;
+use thiserror::Error;
+
+use crate::{
+    layout::AsBytes,
+    leaks,
+    memory::{
+        find_memory_type,
+        usage::{self, MemoryCategory},
+        Buffer,
+    },
+    scene::{InstanceData, NodeId},
+    shader::{reflect, ShaderCompiler},
+    vertex::VertexInput,
+};
+
+pub const ID_FORMAT: vk::Format = vk::Format::R32_UINT;
+
+pub const ID_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) in vec3 position;
+// One column of the model matrix per location
+layout(location = 1) in vec4 model_x;
+layout(location = 2) in vec4 model_y;
+layout(location = 3) in vec4 model_z;
+layout(location = 4) in vec4 model_w;
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    uint base_id;
+} push;
+
+layout(location = 0) flat out uint out_id;
+
+void main() {
+    mat4 model = mat4(model_x, model_y, model_z, model_w);
+    gl_Position = push.view_proj * model * vec4(position, 1.0);
+    out_id = push.base_id + uint(gl_InstanceIndex);
+}
+"#;
+
+pub const ID_FRAG_GLSL: &str = r#"#version 450
+layout(location = 0) flat in uint id;
+
+layout(location = 0) out uint out_id;
+
+void main() {
+    out_id = id;
+}
+"#;
+
+pub mod raycast;
+
+#[derive(Debug, Error)]
+pub enum PickingError {
+    #[error("failed to compile id shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct IdPush {
+    view_proj: Mat4,
+    base_id: u32,
+    _pad: [u32; 3],
+}
+
+/// The id attachment, cleared to zero and left in `COLOR_ATTACHMENT_OPTIMAL` by the id pass
+pub struct IdTarget {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+    pub extent: vk::Extent2D,
+}
+
+impl IdTarget {
+    #[track_caller]
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+    ) -> VkResult<Self> {
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(ID_FORMAT)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe { device.create_image(&info, None)? };
+
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { device.destroy_image(image, None) };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = match unsafe { device.allocate_memory(&alloc_info, None) } {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe { device.destroy_image(image, None) };
+                return Err(err);
+            }
+        };
+        usage::record_allocation(MemoryCategory::RenderTarget, requirements.size);
+        leaks::track(image, "id target image");
+        leaks::track(memory, "id target memory");
+        let mut target = IdTarget {
+            image,
+            view: vk::ImageView::null(),
+            memory,
+            allocation_size: requirements.size,
+            extent,
+        };
+
+        let result = unsafe {
+            device.bind_image_memory(image, memory, 0).and_then(|_| {
+                let view_info = vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(ID_FORMAT)
+                    .subresource_range(COLOR_RANGE);
+                device.create_image_view(&view_info, None)
+            })
+        };
+        match result {
+            Ok(view) => {
+                leaks::track(view, "id target view");
+                target.view = view;
+            }
+            Err(err) => {
+                unsafe { target.destroy(device) };
+                return Err(err);
+            }
+        }
+        Ok(target)
+    }
+
+    /// # Safety
+    ///
+    /// The image must no longer be in use by the device.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.view);
+        leaks::untrack(self.image);
+        leaks::untrack(self.memory);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+        usage::release_allocation(MemoryCategory::RenderTarget, self.allocation_size);
+    }
+}
+
+const COLOR_RANGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: 1,
+    base_array_layer: 0,
+    layer_count: 1,
+};
+
+/// Pipeline drawing meshes of vertex type `V` as ids, reading only its position at location 0
+pub struct IdPipeline {
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+}
+
+impl IdPipeline {
+    /// Instance data comes from a second vertex buffer of [`InstanceData`] at binding 1
+    pub fn new<V: VertexInput, C: ShaderCompiler>(
+        device: &Device,
+        compiler: &C,
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    ) -> Result<Self, PickingError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| PickingError::Compile(Box::new(err)))
+        };
+        let vert = compile(ID_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(ID_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+
+        let mut this = IdPipeline {
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+        };
+        let result =
+            unsafe { this.create_objects::<V>(device, &vert, &frag, render_pass, subpass) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects<V: VertexInput>(
+        &mut self,
+        device: &Device,
+        vert: &[u32],
+        frag: &[u32],
+        render_pass: vk::RenderPass,
+        subpass: u32,
+    ) -> Result<(), PickingError> {
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX,
+            offset: 0,
+            size: mem::size_of::<IdPush>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder().push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "picking pipeline layout");
+
+        // Only the position of the mesh vertex, then the model matrix one column per location
+        let attributes: Vec<_> = V::attributes(0, 0)
+            .into_iter()
+            .filter(|attribute| attribute.location == 0)
+            .chain((0..4).map(|column| vk::VertexInputAttributeDescription {
+                location: 1 + column,
+                binding: 1,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: column * 16,
+            }))
+            .collect();
+        reflect::debug_validate(&[vert, frag], &[], &[push_range], &attributes)?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "picking shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "picking shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_bindings = [
+            V::binding(0),
+            vk::VertexInputBindingDescription {
+                binding: 1,
+                stride: mem::size_of::<InstanceData>() as u32,
+                input_rate: vk::VertexInputRate::INSTANCE,
+            },
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&attributes);
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::BACK)
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::LESS);
+        // Integer attachments can't blend
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::R)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(render_pass)
+            .subpass(subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "picking ids");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Binds the pipeline, instance `i` of the following draws writes id `base_id + i`
+    ///
+    /// Mesh buffers at binding 0 and the instance buffer at binding 1 are left to the caller.
+    pub fn bind(&self, device: &Device, cmd: vk::CommandBuffer, view_proj: Mat4, base_id: u32) {
+        let push = IdPush {
+            view_proj,
+            base_id,
+            _pad: [0; 3],
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX,
+                0,
+                push.as_bytes(),
+            );
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded id pass may still be executing.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+    }
+}
+
+/// Result of a pick, raised once the frame that read it back has finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PickEvent {
+    pub x: u32,
+    pub y: u32,
+    /// `None` for the background
+    pub id: Option<u32>,
+}
+
+impl PickEvent {
+    /// Node of the picked instance, for id passes drawn with a `base_id` of one
+    pub fn node(&self, instance_nodes: &[NodeId]) -> Option<NodeId> {
+        let index = self.id?.checked_sub(1)?;
+        instance_nodes.get(index as usize).copied()
+    }
+}
+
+/// Reads single id texels back without stalling, one slot per frame in flight
+pub struct Picker {
+    readback: Vec<Buffer>,
+    /// Cursor position copied in each frame's slot, if any
+    in_flight: Vec<Option<(u32, u32)>>,
+    pending: Option<(u32, u32)>,
+}
+
+impl Picker {
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        frames_in_flight: usize,
+    ) -> VkResult<Self> {
+        let mut picker = Picker {
+            readback: Vec::new(),
+            in_flight: vec![None; frames_in_flight],
+            pending: None,
+        };
+        for _ in 0..frames_in_flight {
+            match Buffer::new(
+                device,
+                mem_props,
+                mem::size_of::<u32>() as vk::DeviceSize,
+                vk::BufferUsageFlags::TRANSFER_DST,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ) {
+                Ok(buffer) => picker.readback.push(buffer),
+                Err(err) => {
+                    unsafe { picker.destroy(device) };
+                    return Err(err);
+                }
+            }
+        }
+        Ok(picker)
+    }
+
+    /// Asks for the id under pixel `(x, y)`, a newer request replaces one not yet recorded
+    pub fn request(&mut self, x: u32, y: u32) {
+        self.pending = Some((x, y));
+    }
+
+    /// Copies the requested texel into `frame`'s slot, recorded after the id pass has ended
+    pub fn record_copy(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+        target: &IdTarget,
+    ) {
+        let Some((x, y)) = self.pending.take() else {
+            return;
+        };
+        if x >= target.extent.width || y >= target.extent.height {
+            return;
+        }
+        let to_transfer = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(target.image)
+            .subresource_range(COLOR_RANGE);
+        let to_attachment = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(target.image)
+            .subresource_range(COLOR_RANGE);
+        let to_host = vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(self.readback[frame].buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            image_offset: vk::Offset3D {
+                x: x as i32,
+                y: y as i32,
+                z: 0,
+            },
+            image_extent: vk::Extent3D {
+                width: 1,
+                height: 1,
+                depth: 1,
+            },
+        };
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[*to_transfer],
+            );
+            device.cmd_copy_image_to_buffer(
+                cmd,
+                target.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.readback[frame].buffer,
+                &[region],
+            );
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[*to_host],
+                &[*to_attachment],
+            );
+        }
+        self.in_flight[frame] = Some((x, y));
+    }
+
+    /// Takes the result copied in `frame`'s slot, once that frame's fence has been waited on
+    pub fn poll(&mut self, frame: usize) -> Option<PickEvent> {
+        let (x, y) = self.in_flight[frame].take()?;
+        let mapped = self.readback[frame].mapped()?;
+        let id = unsafe { mapped.cast::<u32>().as_ptr().read() };
+        Some(PickEvent {
+            x,
+            y,
+            id: (id != 0).then_some(id),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// No recorded copy may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.readback.drain(..) {
+            buffer.destroy(device);
+        }
+    }
+}
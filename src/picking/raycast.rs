@@ -0,0 +1,128 @@
+//! CPU ray casts against scene meshes, picking without an extra pass for simple scenes
+//!
+//! Meshes are first tested by their bounds, then triangle by triangle in the mesh's own space.
+
+use glam::Vec3;
+
+use crate::{
+    geometry::{bvh::Bvh, Aabb, Ray},
+    scene::{MeshId, NodeId, Scene},
+};
+
+/// CPU copy of a mesh's triangles, kept alongside the GPU mesh for ray casts
+#[derive(Debug, Clone)]
+pub struct MeshGeometry {
+    pub positions: Vec<Vec3>,
+    /// Triangle list, empty when `positions` are the triangles themselves
+    pub indices: Vec<u32>,
+    pub bounds: Aabb,
+}
+
+impl MeshGeometry {
+    pub fn new(positions: Vec<Vec3>, indices: Vec<u32>) -> Self {
+        let bounds = Aabb::from_points(positions.iter().copied())
+            .unwrap_or(Aabb::new(Vec3::ZERO, Vec3::ZERO));
+        MeshGeometry {
+            positions,
+            indices,
+            bounds,
+        }
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = [Vec3; 3]> + '_ {
+        let count = if self.indices.is_empty() {
+            self.positions.len() / 3
+        } else {
+            self.indices.len() / 3
+        };
+        (0..count).map(move |triangle| {
+            std::array::from_fn(|corner| {
+                let i = triangle * 3 + corner;
+                match self.indices.get(i) {
+                    Some(&index) => self.positions[index as usize],
+                    None => self.positions[i],
+                }
+            })
+        })
+    }
+
+    /// Closest hit of a ray in the mesh's space, as distance, triangle and barycentrics
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, usize, f32, f32)> {
+        ray.intersect_aabb(&self.bounds)?;
+        self.triangles()
+            .enumerate()
+            .filter_map(|(triangle, [a, b, c])| {
+                let (t, u, v) = ray.intersect_triangle(a, b, c)?;
+                Some((t, triangle, u, v))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    pub node: NodeId,
+    /// Along the world space ray
+    pub distance: f32,
+    pub point: Vec3,
+    /// Index of the triangle in [`MeshGeometry::triangles`]
+    pub triangle: usize,
+}
+
+/// Closest mesh node hit by a world space ray, using world matrices from the last
+/// [`Scene::update_transforms`]
+///
+/// `geometry` returns the CPU triangles of a mesh, meshes it has none for can't be hit.
+pub fn raycast<'a>(
+    scene: &Scene,
+    ray: &Ray,
+    geometry: impl Fn(MeshId) -> Option<&'a MeshGeometry>,
+) -> Option<RayHit> {
+    scene
+        .iter()
+        .filter_map(|(id, node)| {
+            let mesh = geometry(node.mesh?)?;
+            let world = node.world();
+            // Bounds in world space first, it's cheaper than inverting the matrix
+            ray.intersect_aabb(&mesh.bounds.transformed(world))?;
+            let local = ray.transformed(world.inverse());
+            let (distance, triangle, _, _) = mesh.intersect(&local)?;
+            Some(RayHit {
+                node: id,
+                distance,
+                point: ray.at(distance),
+                triangle,
+            })
+        })
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// [`raycast`] through a tree built by [`Bvh::from_scene`], only testing nodes whose boxes the ray
+/// reaches before the closest hit so far
+pub fn raycast_bvh<'a>(
+    scene: &Scene,
+    bvh: &Bvh<NodeId>,
+    ray: &Ray,
+    geometry: impl Fn(MeshId) -> Option<&'a MeshGeometry>,
+) -> Option<RayHit> {
+    let mut triangles = Vec::new();
+    let (node, distance) = bvh.raycast(ray, |id| {
+        let node = scene.get(id)?;
+        let mesh = geometry(node.mesh?)?;
+        let local = ray.transformed(node.world().inverse());
+        let (distance, triangle, _, _) = mesh.intersect(&local)?;
+        triangles.push((id, triangle));
+        Some(distance)
+    })?;
+    let triangle = triangles
+        .iter()
+        .rev()
+        .find(|(id, _)| *id == node)
+        .map_or(0, |(_, triangle)| *triangle);
+    Some(RayHit {
+        node,
+        distance,
+        point: ray.at(distance),
+        triangle,
+    })
+}
@@ -0,0 +1,258 @@
+//! Pipeline creation in one place, collecting the driver's creation feedback when enabled
+//!
+//! With `VK_EXT_pipeline_creation_feedback` or Vulkan 1.3, [`enable_feedback`] has every pipeline
+//! report how long it and each of its stages took to build. [`take_feedback`] drains what was
+//! collected, so compile hitches can be attributed. No pipeline cache is used, so there are no
+//! cache hits to report.
+//! [`pool::PipelinePool`] spreads builds over worker threads, [`library::PipelineLibrary`] links
+//! permutations from shared parts, and [`shader_object::ShaderObjects`] skips pipelines altogether.
+//! [`dynamic::DynamicRasterState`] leaves cull and depth state to recording, so fewer variants
+//! are needed.
+
+use std::{
+    collections::VecDeque,
+    ffi::c_void,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use ash::{prelude::VkResult, vk, Device};
+
+use crate::{leaks, trace::traced};
+
+pub mod dynamic;
+pub mod library;
+pub mod pool;
+pub mod shader_object;
+
+static FEEDBACK: AtomicBool = AtomicBool::new(false);
+static LOG: Mutex<VecDeque<PipelineFeedback>> = Mutex::new(VecDeque::new());
+
+/// Entries kept until [`take_feedback`] is called, the oldest are dropped past it
+pub const MAX_FEEDBACK: usize = 256;
+
+/// Only turn on when the device has `VK_EXT_pipeline_creation_feedback` enabled or is 1.3
+pub fn enable_feedback(enabled: bool) {
+    FEEDBACK.store(enabled, Ordering::Relaxed);
+}
+
+/// Feedback of the pipelines created since the last call, at most the last [`MAX_FEEDBACK`]
+pub fn take_feedback() -> Vec<PipelineFeedback> {
+    std::mem::take(&mut *LOG.lock().unwrap()).into()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StageFeedback {
+    pub stage: vk::ShaderStageFlags,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineFeedback {
+    /// What the pipeline is for, as its creator named it
+    pub label: String,
+    pub duration: Duration,
+    /// Empty when the driver doesn't report stages
+    pub stages: Vec<StageFeedback>,
+}
+
+impl fmt::Display for PipelineFeedback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.2} ms",
+            self.label,
+            self.duration.as_secs_f64() * 1000.0
+        )?;
+        for stage in &self.stages {
+            write!(
+                f,
+                ", {:?} {:.2} ms",
+                stage.stage,
+                stage.duration.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `vkCreateGraphicsPipelines` without a cache, recording feedback under `label`
+///
+/// # Safety
+///
+/// Same as `Device::create_graphics_pipelines`.
+#[track_caller]
+pub unsafe fn create_graphics_pipelines(
+    device: &Device,
+    infos: &[vk::GraphicsPipelineCreateInfo],
+    label: &str,
+) -> Result<Vec<vk::Pipeline>, (Vec<vk::Pipeline>, vk::Result)> {
+    if !FEEDBACK.load(Ordering::Relaxed) {
+        return track(traced!(
+            "vkCreateGraphicsPipelines",
+            label = label,
+            count = infos.len();
+            device.create_graphics_pipelines(vk::PipelineCache::null(), infos, None)
+        ));
+    }
+    let mut infos = infos.to_vec();
+    let stages: Vec<Vec<vk::ShaderStageFlags>> = infos
+        .iter()
+        .map(|info| {
+            (0..info.stage_count as usize)
+                .map(|i| (*info.p_stages.add(i)).stage)
+                .collect()
+        })
+        .collect();
+    let mut feedback = Feedback::new(&stages);
+    for (info, chain) in infos.iter_mut().zip(&mut feedback.chains) {
+        chain.p_next = info.p_next;
+        info.p_next = chain as *const vk::PipelineCreationFeedbackCreateInfo as *const c_void;
+    }
+    let result = traced!(
+        "vkCreateGraphicsPipelines",
+        label = label,
+        count = infos.len();
+        device.create_graphics_pipelines(vk::PipelineCache::null(), &infos, None)
+    );
+    feedback.record(label, &stages);
+    track(result)
+}
+
+/// `vkCreateComputePipelines` without a cache, recording feedback under `label`
+///
+/// # Safety
+///
+/// Same as `Device::create_compute_pipelines`.
+#[track_caller]
+pub unsafe fn create_compute_pipelines(
+    device: &Device,
+    infos: &[vk::ComputePipelineCreateInfo],
+    label: &str,
+) -> Result<Vec<vk::Pipeline>, (Vec<vk::Pipeline>, vk::Result)> {
+    if !FEEDBACK.load(Ordering::Relaxed) {
+        return track(traced!(
+            "vkCreateComputePipelines",
+            label = label,
+            count = infos.len();
+            device.create_compute_pipelines(vk::PipelineCache::null(), infos, None)
+        ));
+    }
+    let mut infos = infos.to_vec();
+    let stages: Vec<Vec<vk::ShaderStageFlags>> =
+        infos.iter().map(|info| vec![info.stage.stage]).collect();
+    let mut feedback = Feedback::new(&stages);
+    for (info, chain) in infos.iter_mut().zip(&mut feedback.chains) {
+        chain.p_next = info.p_next;
+        info.p_next = chain as *const vk::PipelineCreationFeedbackCreateInfo as *const c_void;
+    }
+    let result = traced!(
+        "vkCreateComputePipelines",
+        label = label,
+        count = infos.len();
+        device.create_compute_pipelines(vk::PipelineCache::null(), &infos, None)
+    );
+    feedback.record(label, &stages);
+    track(result)
+}
+
+/// `vkCreatePipelineLayout`, kept here with the pipelines so every layout is traced
+///
+/// # Safety
+///
+/// Same as `Device::create_pipeline_layout`.
+#[track_caller]
+pub unsafe fn create_pipeline_layout(
+    device: &Device,
+    info: &vk::PipelineLayoutCreateInfo,
+) -> VkResult<vk::PipelineLayout> {
+    traced!(
+        "vkCreatePipelineLayout",
+        set_layouts = info.set_layout_count,
+        push_constant_ranges = info.push_constant_range_count;
+        device.create_pipeline_layout(info, None)
+    )
+}
+
+/// Tracks the pipelines created, including those of a partially failed batch
+#[track_caller]
+fn track(
+    result: Result<Vec<vk::Pipeline>, (Vec<vk::Pipeline>, vk::Result)>,
+) -> Result<Vec<vk::Pipeline>, (Vec<vk::Pipeline>, vk::Result)> {
+    let pipelines = match &result {
+        Ok(pipelines) | Err((pipelines, _)) => pipelines,
+    };
+    for &pipeline in pipelines {
+        if pipeline != vk::Pipeline::null() {
+            leaks::track(pipeline, "pipeline");
+        }
+    }
+    result
+}
+
+/// Storage the driver writes feedback into, one chain entry per pipeline
+struct Feedback {
+    pipelines: Vec<vk::PipelineCreationFeedback>,
+    stages: Vec<Vec<vk::PipelineCreationFeedback>>,
+    chains: Vec<vk::PipelineCreationFeedbackCreateInfo>,
+}
+
+impl Feedback {
+    /// The chain entries point into the vectors, which don't move along with `Self`
+    fn new(stages: &[Vec<vk::ShaderStageFlags>]) -> Self {
+        let mut feedback = Feedback {
+            pipelines: vec![vk::PipelineCreationFeedback::default(); stages.len()],
+            stages: stages
+                .iter()
+                .map(|stages| vec![vk::PipelineCreationFeedback::default(); stages.len()])
+                .collect(),
+            chains: Vec::with_capacity(stages.len()),
+        };
+        for i in 0..stages.len() {
+            let chain = vk::PipelineCreationFeedbackCreateInfo {
+                p_pipeline_creation_feedback: &mut feedback.pipelines[i],
+                pipeline_stage_creation_feedback_count: feedback.stages[i].len() as u32,
+                p_pipeline_stage_creation_feedbacks: feedback.stages[i].as_mut_ptr(),
+                ..Default::default()
+            };
+            feedback.chains.push(chain);
+        }
+        feedback
+    }
+
+    fn record(&self, label: &str, stages: &[Vec<vk::ShaderStageFlags>]) {
+        let valid = |feedback: &vk::PipelineCreationFeedback| {
+            feedback
+                .flags
+                .contains(vk::PipelineCreationFeedbackFlags::VALID)
+        };
+        let mut log = LOG.lock().unwrap();
+        for ((pipeline, stage_feedback), stage_flags) in
+            self.pipelines.iter().zip(&self.stages).zip(stages)
+        {
+            if !valid(pipeline) {
+                continue;
+            }
+            if log.len() == MAX_FEEDBACK {
+                log.pop_front();
+            }
+            log.push_back(PipelineFeedback {
+                label: label.to_owned(),
+                duration: Duration::from_nanos(pipeline.duration),
+                stages: stage_feedback
+                    .iter()
+                    .zip(stage_flags)
+                    .filter(|(feedback, _)| valid(feedback))
+                    .map(|(feedback, &stage)| StageFeedback {
+                        stage,
+                        duration: Duration::from_nanos(feedback.duration),
+                    })
+                    .collect(),
+            });
+        }
+    }
+}
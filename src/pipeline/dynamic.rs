@@ -0,0 +1,237 @@
+//! Rasterization and depth state set while recording rather than baked into pipelines
+//!
+//! Viewport, scissor and the depth bias factors are always dynamic, so resizing never rebuilds a
+//! pipeline. With Vulkan 1.3 or `VK_EXT_extended_dynamic_state(2)` the cull mode, front face,
+//! depth test and depth bias toggle are dynamic too, and `VK_EXT_extended_dynamic_state3` adds the
+//! polygon mode and depth clamp. Keying pipeline caches on [`DynamicRasterState::pipeline_key`]
+//! collapses variants differing only there.
+
+use std::hash::{Hash, Hasher};
+
+use ash::{extensions::ext, vk, Device, Instance};
+
+use crate::device::{EnabledFeatures, Feature};
+
+/// Depth offset applied while rasterizing, against acne in shadow maps and z-fighting decals
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DepthBias {
+    pub constant_factor: f32,
+    /// Largest offset applied, non zero needs the `depthBiasClamp` feature
+    pub clamp: f32,
+    pub slope_factor: f32,
+}
+
+impl Eq for DepthBias {}
+
+impl Hash for DepthBias {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        [self.constant_factor, self.clamp, self.slope_factor]
+            .map(f32::to_bits)
+            .hash(state);
+    }
+}
+
+/// Rasterization and depth state a draw wants, whether baked or dynamic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RasterState {
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub polygon_mode: vk::PolygonMode,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare: vk::CompareOp,
+    pub depth_bias: Option<DepthBias>,
+    /// Clamps depth to the viewport range instead of clipping, needs the `depthClamp` feature
+    pub depth_clamp: bool,
+}
+
+impl Default for RasterState {
+    fn default() -> Self {
+        RasterState {
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            polygon_mode: vk::PolygonMode::FILL,
+            depth_test: true,
+            depth_write: true,
+            depth_compare: vk::CompareOp::LESS_OR_EQUAL,
+            depth_bias: None,
+            depth_clamp: false,
+        }
+    }
+}
+
+impl RasterState {
+    /// Baked state for pipeline creation, overridden by whatever is dynamic
+    pub fn rasterization(&self) -> vk::PipelineRasterizationStateCreateInfo {
+        let bias = self.depth_bias.unwrap_or_default();
+        vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(self.polygon_mode)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .depth_clamp_enable(self.depth_clamp)
+            .depth_bias_enable(self.depth_bias.is_some())
+            .depth_bias_constant_factor(bias.constant_factor)
+            .depth_bias_clamp(bias.clamp)
+            .depth_bias_slope_factor(bias.slope_factor)
+            .line_width(1.0)
+            .build()
+    }
+
+    pub fn depth_stencil(&self) -> vk::PipelineDepthStencilStateCreateInfo {
+        vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(self.depth_test)
+            .depth_write_enable(self.depth_write)
+            .depth_compare_op(self.depth_compare)
+            .build()
+    }
+}
+
+#[derive(Clone)]
+enum Commands {
+    /// Promoted to Vulkan 1.3 along with the base of `extended_dynamic_state2`
+    Core,
+    Ext {
+        state: ext::ExtendedDynamicState,
+        state2: Option<ext::ExtendedDynamicState2>,
+    },
+}
+
+/// Which parts of [`RasterState`] the device can set while recording
+#[derive(Clone)]
+pub struct DynamicRasterState {
+    commands: Option<Commands>,
+    depth_bias: bool,
+    state3: Option<ext::ExtendedDynamicState3>,
+    polygon_mode: bool,
+    depth_clamp: bool,
+}
+
+impl DynamicRasterState {
+    pub fn new(instance: &Instance, device: &Device, enabled: &EnabledFeatures) -> Self {
+        let capabilities = enabled.capabilities();
+        let commands = if capabilities.vulkan13 {
+            Some(Commands::Core)
+        } else if capabilities.extended_dynamic_state {
+            Some(Commands::Ext {
+                state: ext::ExtendedDynamicState::new(instance, device),
+                state2: capabilities
+                    .extended_dynamic_state2
+                    .then(|| ext::ExtendedDynamicState2::new(instance, device)),
+            })
+        } else {
+            None
+        };
+        let polygon_mode = enabled.has_feature(Feature::ExtendedDynamicState3PolygonMode);
+        // Clamping can't be enabled at all without the feature
+        let depth_clamp = enabled.has_feature(Feature::ExtendedDynamicState3DepthClampEnable)
+            && enabled.has_feature(Feature::DepthClamp);
+        DynamicRasterState {
+            commands,
+            depth_bias: capabilities.extended_dynamic_state2,
+            state3: (polygon_mode || depth_clamp)
+                .then(|| ext::ExtendedDynamicState3::new(instance, device)),
+            polygon_mode,
+            depth_clamp,
+        }
+    }
+
+    /// Whether anything beyond viewport and scissor is dynamic
+    pub fn is_extended(&self) -> bool {
+        self.commands.is_some()
+    }
+
+    /// Dynamic states to create pipelines with, viewport, scissor and depth bias included
+    pub fn dynamic_states(&self) -> Vec<vk::DynamicState> {
+        let mut states = vec![
+            vk::DynamicState::VIEWPORT,
+            vk::DynamicState::SCISSOR,
+            vk::DynamicState::DEPTH_BIAS,
+        ];
+        if self.commands.is_some() {
+            states.extend([
+                vk::DynamicState::CULL_MODE,
+                vk::DynamicState::FRONT_FACE,
+                vk::DynamicState::DEPTH_TEST_ENABLE,
+                vk::DynamicState::DEPTH_WRITE_ENABLE,
+                vk::DynamicState::DEPTH_COMPARE_OP,
+            ]);
+            if self.depth_bias {
+                states.push(vk::DynamicState::DEPTH_BIAS_ENABLE);
+            }
+        }
+        if self.polygon_mode {
+            states.push(vk::DynamicState::POLYGON_MODE_EXT);
+        }
+        if self.depth_clamp {
+            states.push(vk::DynamicState::DEPTH_CLAMP_ENABLE_EXT);
+        }
+        states
+    }
+
+    /// `state` with its dynamic parts reset, the only part that needs a pipeline of its own
+    pub fn pipeline_key(&self, state: RasterState) -> RasterState {
+        let default = RasterState::default();
+        let mut key = state;
+        key.depth_bias = state.depth_bias.map(|_| DepthBias::default());
+        if self.commands.is_some() {
+            key.cull_mode = default.cull_mode;
+            key.front_face = default.front_face;
+            key.depth_test = default.depth_test;
+            key.depth_write = default.depth_write;
+            key.depth_compare = default.depth_compare;
+            if self.depth_bias {
+                key.depth_bias = default.depth_bias;
+            }
+        }
+        if self.polygon_mode {
+            key.polygon_mode = default.polygon_mode;
+        }
+        if self.depth_clamp {
+            key.depth_clamp = default.depth_clamp;
+        }
+        key
+    }
+
+    /// Sets the dynamic parts of `state`, after binding a pipeline made with
+    /// [`DynamicRasterState::dynamic_states`]
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording.
+    pub unsafe fn apply(&self, device: &Device, cmd: vk::CommandBuffer, state: &RasterState) {
+        let bias = state.depth_bias.unwrap_or_default();
+        device.cmd_set_depth_bias(cmd, bias.constant_factor, bias.clamp, bias.slope_factor);
+        match &self.commands {
+            Some(Commands::Core) => {
+                device.cmd_set_cull_mode(cmd, state.cull_mode);
+                device.cmd_set_front_face(cmd, state.front_face);
+                device.cmd_set_depth_test_enable(cmd, state.depth_test);
+                device.cmd_set_depth_write_enable(cmd, state.depth_write);
+                device.cmd_set_depth_compare_op(cmd, state.depth_compare);
+                device.cmd_set_depth_bias_enable(cmd, state.depth_bias.is_some());
+            }
+            Some(Commands::Ext {
+                state: ext_state,
+                state2,
+            }) => {
+                ext_state.cmd_set_cull_mode(cmd, state.cull_mode);
+                ext_state.cmd_set_front_face(cmd, state.front_face);
+                ext_state.cmd_set_depth_test_enable(cmd, state.depth_test);
+                ext_state.cmd_set_depth_write_enable(cmd, state.depth_write);
+                ext_state.cmd_set_depth_compare_op(cmd, state.depth_compare);
+                if let Some(state2) = state2 {
+                    state2.cmd_set_depth_bias_enable(cmd, state.depth_bias.is_some());
+                }
+            }
+            None => {}
+        }
+        if let Some(ext) = &self.state3 {
+            if self.polygon_mode {
+                ext.cmd_set_polygon_mode(cmd, state.polygon_mode);
+            }
+            if self.depth_clamp {
+                ext.cmd_set_depth_clamp_enable(cmd, state.depth_clamp);
+            }
+        }
+    }
+}
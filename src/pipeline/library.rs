@@ -0,0 +1,211 @@
+//! Pipelines linked from separately built parts with `VK_EXT_graphics_pipeline_library`
+//!
+//! A graphics pipeline splits into its vertex input, pre-rasterization shaders, fragment shader
+//! and fragment output. Each part is built once per key, so every material × vertex format
+//! permutation only costs a link. Parts built with different layouts need those created with
+//! `PipelineLayoutCreateFlags::INDEPENDENT_SETS_EXT`.
+
+use std::{collections::HashMap, hash::Hash};
+
+use ash::{prelude::VkResult, vk, Device};
+use thiserror::Error;
+
+use crate::{device::EnabledFeatures, leaks};
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("the {0:?} part of the variant hasn't been built")]
+    MissingPart(Part),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Whether `enabled` allows building parts, same as
+/// [`crate::device::Capabilities::pipeline_library`]
+pub fn supported(enabled: &EnabledFeatures) -> bool {
+    enabled.capabilities().pipeline_library
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Part {
+    VertexInput,
+    PreRasterization,
+    FragmentShader,
+    FragmentOutput,
+}
+
+impl Part {
+    pub const ALL: [Part; 4] = [
+        Part::VertexInput,
+        Part::PreRasterization,
+        Part::FragmentShader,
+        Part::FragmentOutput,
+    ];
+
+    pub fn flags(self) -> vk::GraphicsPipelineLibraryFlagsEXT {
+        match self {
+            Part::VertexInput => vk::GraphicsPipelineLibraryFlagsEXT::VERTEX_INPUT_INTERFACE,
+            Part::PreRasterization => {
+                vk::GraphicsPipelineLibraryFlagsEXT::PRE_RASTERIZATION_SHADERS
+            }
+            Part::FragmentShader => vk::GraphicsPipelineLibraryFlagsEXT::FRAGMENT_SHADER,
+            Part::FragmentOutput => vk::GraphicsPipelineLibraryFlagsEXT::FRAGMENT_OUTPUT_INTERFACE,
+        }
+    }
+
+    /// Whether shaders of `stage` belong in this part
+    fn includes(self, stage: vk::ShaderStageFlags) -> bool {
+        match self {
+            Part::VertexInput | Part::FragmentOutput => false,
+            Part::PreRasterization => stage != vk::ShaderStageFlags::FRAGMENT,
+            Part::FragmentShader => stage == vk::ShaderStageFlags::FRAGMENT,
+        }
+    }
+}
+
+/// The key of each part a pipeline is linked from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Variant<K> {
+    pub vertex_input: K,
+    pub pre_rasterization: K,
+    pub fragment_shader: K,
+    pub fragment_output: K,
+}
+
+impl<K> Variant<K> {
+    pub fn key(&self, part: Part) -> &K {
+        match part {
+            Part::VertexInput => &self.vertex_input,
+            Part::PreRasterization => &self.pre_rasterization,
+            Part::FragmentShader => &self.fragment_shader,
+            Part::FragmentOutput => &self.fragment_output,
+        }
+    }
+}
+
+/// Built parts and the pipelines linked from them
+pub struct PipelineLibrary<K> {
+    parts: HashMap<(Part, K), vk::Pipeline>,
+    linked: HashMap<Variant<K>, vk::Pipeline>,
+    link_time_optimization: bool,
+}
+
+impl<K> Default for PipelineLibrary<K> {
+    fn default() -> Self {
+        PipelineLibrary {
+            parts: HashMap::new(),
+            linked: HashMap::new(),
+            link_time_optimization: false,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> PipelineLibrary<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Links take longer but the result runs as fast as a pipeline built whole
+    pub fn with_link_time_optimization(mut self, enabled: bool) -> Self {
+        self.link_time_optimization = enabled;
+        self
+    }
+
+    pub fn has_part(&self, part: Part, key: &K) -> bool {
+        self.parts.contains_key(&(part, key.clone()))
+    }
+
+    /// Builds `part` from the state of `info` it covers, unless already built under `key`
+    ///
+    /// `info` may describe the whole pipeline, shader stages outside the part are left out.
+    ///
+    /// # Safety
+    ///
+    /// `info` must be valid for the part, as for `Device::create_graphics_pipelines`.
+    pub unsafe fn build_part(
+        &mut self,
+        device: &Device,
+        part: Part,
+        key: K,
+        info: &vk::GraphicsPipelineCreateInfo,
+    ) -> VkResult<vk::Pipeline> {
+        if let Some(&pipeline) = self.parts.get(&(part, key.clone())) {
+            return Ok(pipeline);
+        }
+        let stages: Vec<vk::PipelineShaderStageCreateInfo> = (0..info.stage_count as usize)
+            .map(|i| *info.p_stages.add(i))
+            .filter(|stage| part.includes(stage.stage))
+            .collect();
+        let library_info = vk::GraphicsPipelineLibraryCreateInfoEXT {
+            p_next: info.p_next as *mut _,
+            flags: part.flags(),
+            ..Default::default()
+        };
+        let mut flags = info.flags | vk::PipelineCreateFlags::LIBRARY_KHR;
+        if self.link_time_optimization {
+            flags |= vk::PipelineCreateFlags::RETAIN_LINK_TIME_OPTIMIZATION_INFO_EXT;
+        }
+        let info = vk::GraphicsPipelineCreateInfo {
+            p_next: &library_info as *const _ as *const _,
+            flags,
+            stage_count: stages.len() as u32,
+            p_stages: stages.as_ptr(),
+            ..*info
+        };
+        let pipeline =
+            super::create_graphics_pipelines(device, &[info], &format!("{part:?} library"))
+                .map_err(|(_, err)| err)?[0];
+        self.parts.insert((part, key), pipeline);
+        Ok(pipeline)
+    }
+
+    /// The pipeline linked from the parts of `variant`, linking it on first use
+    ///
+    /// # Safety
+    ///
+    /// `layout` must be compatible with the layouts the parts were built with.
+    pub unsafe fn link(
+        &mut self,
+        device: &Device,
+        variant: &Variant<K>,
+        layout: vk::PipelineLayout,
+    ) -> Result<vk::Pipeline, LibraryError> {
+        if let Some(&pipeline) = self.linked.get(variant) {
+            return Ok(pipeline);
+        }
+        let libraries = Part::ALL
+            .into_iter()
+            .map(|part| {
+                self.parts
+                    .get(&(part, variant.key(part).clone()))
+                    .copied()
+                    .ok_or(LibraryError::MissingPart(part))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut library_info = vk::PipelineLibraryCreateInfoKHR::builder().libraries(&libraries);
+        let flags = if self.link_time_optimization {
+            vk::PipelineCreateFlags::LINK_TIME_OPTIMIZATION_EXT
+        } else {
+            vk::PipelineCreateFlags::empty()
+        };
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .flags(flags)
+            .layout(layout)
+            .push_next(&mut library_info);
+        let pipeline = super::create_graphics_pipelines(device, &[*info], "linked library")
+            .map_err(|(_, err)| err)?[0];
+        self.linked.insert(variant.clone(), pipeline);
+        Ok(pipeline)
+    }
+
+    /// # Safety
+    ///
+    /// None of the linked pipelines may still be in use by the device.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let linked = self.linked.drain().map(|(_, pipeline)| pipeline);
+        for pipeline in linked.chain(self.parts.drain().map(|(_, pipeline)| pipeline)) {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+    }
+}
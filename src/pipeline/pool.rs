@@ -0,0 +1,226 @@
+//! Building pipelines on worker threads
+//!
+//! Pipeline creation only needs the device, which is safe to use from several threads as long
+//! as no pipeline cache is shared, so a large set of variants compiles in parallel instead of
+//! stalling loading. Builds can be queued up front with [`PipelinePool::request`], or on first use
+//! with [`PipelinePool::get_or_request`], which hands out a placeholder until the build lands.
+
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    num::NonZeroUsize,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use ash::{prelude::VkResult, vk, Device};
+
+use crate::leaks;
+
+type Build = Box<dyn FnOnce(&Device) -> VkResult<vk::Pipeline> + Send>;
+
+/// Pipelines keyed by variant, built on worker threads
+pub struct PipelinePool<K> {
+    jobs: Option<mpsc::Sender<(K, Build)>>,
+    results: mpsc::Receiver<(K, VkResult<vk::Pipeline>)>,
+    workers: Vec<JoinHandle<()>>,
+    ready: HashMap<K, vk::Pipeline>,
+    pending: HashSet<K>,
+    failed: HashMap<K, vk::Result>,
+    placeholder: vk::Pipeline,
+}
+
+impl<K: Clone + Eq + Hash + Send + 'static> PipelinePool<K> {
+    /// `placeholder` is handed out for pipelines still compiling, it has to be compatible with
+    /// wherever those are bound and stays owned by the caller
+    ///
+    /// `threads` defaults to the available parallelism.
+    pub fn new(device: &Device, threads: Option<NonZeroUsize>, placeholder: vk::Pipeline) -> Self {
+        let threads = threads
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get);
+        let (jobs, job_receiver) = mpsc::channel::<(K, Build)>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let workers = (0..threads)
+            .map(|i| {
+                let device = device.clone();
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                std::thread::Builder::new()
+                    .name(format!("pipeline compiler {i}"))
+                    .spawn(move || loop {
+                        // Ends once the pool drops the sender
+                        let Ok((key, build)) = jobs.lock().unwrap().recv() else {
+                            return;
+                        };
+                        // A panicking build fails like any other, rather than staying pending
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| build(&device)))
+                            .unwrap_or(Err(vk::Result::ERROR_UNKNOWN));
+                        if results.send((key, result)).is_err() {
+                            return;
+                        }
+                    })
+                    .expect("Failed to spawn a pipeline compiler thread")
+            })
+            .collect();
+        PipelinePool {
+            jobs: Some(jobs),
+            results,
+            workers,
+            ready: HashMap::new(),
+            pending: HashSet::new(),
+            failed: HashMap::new(),
+            placeholder,
+        }
+    }
+
+    /// Queues a build unless `key` is already built or building
+    pub fn request(
+        &mut self,
+        key: K,
+        build: impl FnOnce(&Device) -> VkResult<vk::Pipeline> + Send + 'static,
+    ) {
+        if self.ready.contains_key(&key) || !self.pending.insert(key.clone()) {
+            return;
+        }
+        self.failed.remove(&key);
+        if let Some(jobs) = &self.jobs {
+            // Workers only go away when the pool does
+            let _ = jobs.send((key, Box::new(build)));
+        }
+    }
+
+    /// The built pipeline, or the placeholder while it compiles, queuing `build` on first use
+    ///
+    /// Failed builds keep returning the placeholder until requested again.
+    pub fn get_or_request(
+        &mut self,
+        key: K,
+        build: impl FnOnce(&Device) -> VkResult<vk::Pipeline> + Send + 'static,
+    ) -> vk::Pipeline {
+        if let Some(&pipeline) = self.ready.get(&key) {
+            return pipeline;
+        }
+        if !self.failed.contains_key(&key) {
+            self.request(key, build);
+        }
+        self.placeholder
+    }
+
+    pub fn get(&self, key: &K) -> Option<vk::Pipeline> {
+        self.ready.get(key).copied()
+    }
+
+    pub fn is_pending(&self, key: &K) -> bool {
+        self.pending.contains(key)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Picks up finished builds without blocking, returning the ones that failed
+    ///
+    /// Builds that panicked fail with `ERROR_UNKNOWN`.
+    pub fn poll(&mut self) -> Vec<(K, vk::Result)> {
+        let mut failures = Vec::new();
+        while let Ok((key, result)) = self.results.try_recv() {
+            self.finish(key, result, &mut failures);
+        }
+        failures
+    }
+
+    /// Blocks until every queued build is done, e.g. at the end of loading
+    pub fn wait_all(&mut self) -> Vec<(K, vk::Result)> {
+        let mut failures = self.poll();
+        while !self.pending.is_empty() {
+            let Ok((key, result)) = self.results.recv() else {
+                break;
+            };
+            self.finish(key, result, &mut failures);
+        }
+        failures
+    }
+
+    /// Stops the workers once the queued builds are done and destroys every pipeline built
+    ///
+    /// # Safety
+    ///
+    /// None of the pool's pipelines may still be in use by the device.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        self.poll();
+        for (_, pipeline) in self.ready.drain() {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        self.pending.clear();
+    }
+
+    fn finish(
+        &mut self,
+        key: K,
+        result: VkResult<vk::Pipeline>,
+        failures: &mut Vec<(K, vk::Result)>,
+    ) {
+        self.pending.remove(&key);
+        match result {
+            Ok(pipeline) => {
+                self.ready.insert(key, pipeline);
+            }
+            Err(err) => {
+                self.failed.insert(key.clone(), err);
+                failures.push((key, err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::HeadlessContext;
+
+    #[test]
+    fn panicking_build_fails_without_stalling_the_pool() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let threads = NonZeroUsize::new(1);
+        let mut pool = PipelinePool::new(&ctx.device, threads, vk::Pipeline::null());
+        pool.request(1, |_| panic!("build failed"));
+        pool.request(2, |_| Ok(vk::Pipeline::null()));
+
+        let failures = pool.wait_all();
+        assert_eq!(failures, [(1, vk::Result::ERROR_UNKNOWN)]);
+        assert_eq!(pool.pending_count(), 0);
+        // The worker survived the panic to build the next one
+        assert_eq!(pool.get(&2), Some(vk::Pipeline::null()));
+        unsafe { pool.destroy(&ctx.device) };
+    }
+
+    #[test]
+    fn failed_builds_are_only_retried_on_request() {
+        let Some(ctx) = HeadlessContext::for_test() else {
+            return;
+        };
+        let mut pool = PipelinePool::new(&ctx.device, None, vk::Pipeline::null());
+        pool.request("variant", |_| Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY));
+        assert_eq!(
+            pool.wait_all(),
+            [("variant", vk::Result::ERROR_OUT_OF_DEVICE_MEMORY)]
+        );
+
+        pool.get_or_request("variant", |_| unreachable!("failed builds aren't requeued"));
+        assert!(!pool.is_pending(&"variant"));
+        pool.request("variant", |_| Ok(vk::Pipeline::null()));
+        assert!(pool.wait_all().is_empty());
+        assert_eq!(pool.get(&"variant"), Some(vk::Pipeline::null()));
+        unsafe { pool.destroy(&ctx.device) };
+    }
+}
@@ -0,0 +1,301 @@
+//! Drawing with `VK_EXT_shader_object` instead of pipelines
+//!
+//! Shaders are created on their own and every piece of state a pipeline would bake in is set
+//! while recording from a [`DynamicState`], so nothing has to be rebuilt when the state changes.
+//! Shader objects only draw inside dynamic rendering. Without them [`ShaderObjects::new`] returns
+//! `None` and pipelines stay the way to draw.
+
+use std::ffi::CStr;
+
+use ash::{extensions::ext, prelude::VkResult, vk, Device, Instance};
+
+use super::dynamic::DepthBias;
+use crate::{device::EnabledFeatures, layout::slice_as_bytes, trace::traced};
+
+/// Graphics stages that get unbound when a draw doesn't use them
+const GRAPHICS_STAGES: [vk::ShaderStageFlags; 5] = [
+    vk::ShaderStageFlags::VERTEX,
+    vk::ShaderStageFlags::TESSELLATION_CONTROL,
+    vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+    vk::ShaderStageFlags::GEOMETRY,
+    vk::ShaderStageFlags::FRAGMENT,
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShaderStage<'a> {
+    pub stage: vk::ShaderStageFlags,
+    pub code: &'a [u32],
+    pub entry: &'a CStr,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ColorAttachmentState {
+    /// `None` writes the color unblended
+    pub blend: Option<vk::ColorBlendEquationEXT>,
+    pub write_mask: vk::ColorComponentFlags,
+}
+
+impl Default for ColorAttachmentState {
+    fn default() -> Self {
+        ColorAttachmentState {
+            blend: None,
+            write_mask: vk::ColorComponentFlags::RGBA,
+        }
+    }
+}
+
+/// Stencil operations, masks and reference of each face
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StencilState {
+    pub front: vk::StencilOpState,
+    pub back: vk::StencilOpState,
+}
+
+/// Everything a draw with shader objects needs set
+#[derive(Debug, Clone)]
+pub struct DynamicState {
+    pub viewports: Vec<vk::Viewport>,
+    pub scissors: Vec<vk::Rect2D>,
+    pub bindings: Vec<vk::VertexInputBindingDescription2EXT>,
+    pub attributes: Vec<vk::VertexInputAttributeDescription2EXT>,
+    pub topology: vk::PrimitiveTopology,
+    pub primitive_restart: bool,
+    pub polygon_mode: vk::PolygonMode,
+    /// Other than 1.0 needs the `wideLines` feature
+    pub line_width: f32,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub samples: vk::SampleCountFlags,
+    pub alpha_to_coverage: bool,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub depth_compare: vk::CompareOp,
+    pub depth_bias: Option<DepthBias>,
+    /// Needs the `depthClamp` feature
+    pub depth_clamp: bool,
+    /// `None` disables the stencil test
+    pub stencil: Option<StencilState>,
+    /// One per color attachment of the rendering
+    pub attachments: Vec<ColorAttachmentState>,
+}
+
+impl DynamicState {
+    /// Opaque triangles over all of `extent`, with one color attachment and depth testing
+    pub fn new(extent: vk::Extent2D) -> Self {
+        DynamicState {
+            viewports: vec![vk::Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: extent.width as f32,
+                height: extent.height as f32,
+                min_depth: 0.0,
+                max_depth: 1.0,
+            }],
+            scissors: vec![vk::Rect2D {
+                offset: vk::Offset2D::default(),
+                extent,
+            }],
+            bindings: Vec::new(),
+            attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            primitive_restart: false,
+            polygon_mode: vk::PolygonMode::FILL,
+            line_width: 1.0,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            samples: vk::SampleCountFlags::TYPE_1,
+            alpha_to_coverage: false,
+            depth_test: true,
+            depth_write: true,
+            depth_compare: vk::CompareOp::LESS_OR_EQUAL,
+            depth_bias: None,
+            depth_clamp: false,
+            stencil: None,
+            attachments: vec![ColorAttachmentState::default()],
+        }
+    }
+
+    pub fn with_vertex_input(
+        mut self,
+        bindings: &[vk::VertexInputBindingDescription2EXT],
+        attributes: &[vk::VertexInputAttributeDescription2EXT],
+    ) -> Self {
+        self.bindings = bindings.to_vec();
+        self.attributes = attributes.to_vec();
+        self
+    }
+
+    pub fn with_attachments(mut self, attachments: &[ColorAttachmentState]) -> Self {
+        self.attachments = attachments.to_vec();
+        self
+    }
+}
+
+pub struct ShaderObjects {
+    ext: ext::ShaderObject,
+    /// For the core state commands the extension doesn't repeat
+    device: Device,
+}
+
+impl ShaderObjects {
+    /// `None` unless [`crate::device::Capabilities::shader_object`] is set
+    pub fn new(instance: &Instance, device: &Device, enabled: &EnabledFeatures) -> Option<Self> {
+        enabled.capabilities().shader_object.then(|| ShaderObjects {
+            ext: ext::ShaderObject::new(instance, device),
+            device: device.clone(),
+        })
+    }
+
+    /// Creates `stages` linked together, each one feeding the next in the order given
+    ///
+    /// # Safety
+    ///
+    /// The code must be valid SPIR-V for its stage, using only the given layouts.
+    pub unsafe fn create(
+        &self,
+        stages: &[ShaderStage],
+        set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> VkResult<Vec<vk::ShaderEXT>> {
+        let flags = if stages.len() > 1 {
+            vk::ShaderCreateFlagsEXT::LINK_STAGE
+        } else {
+            vk::ShaderCreateFlagsEXT::empty()
+        };
+        let infos: Vec<vk::ShaderCreateInfoEXT> = stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| {
+                let next_stage = stages
+                    .get(i + 1)
+                    .map_or(vk::ShaderStageFlags::empty(), |next| next.stage);
+                vk::ShaderCreateInfoEXT::builder()
+                    .flags(flags)
+                    .stage(stage.stage)
+                    .next_stage(next_stage)
+                    .code_type(vk::ShaderCodeTypeEXT::SPIRV)
+                    .code(slice_as_bytes(stage.code))
+                    .name(stage.entry)
+                    .set_layouts(set_layouts)
+                    .push_constant_ranges(push_constant_ranges)
+                    .build()
+            })
+            .collect();
+        traced!("vkCreateShadersEXT", count = infos.len(); self.ext.create_shaders(&infos, None))
+    }
+
+    /// Binds `shaders` to their stages and unbinds the other graphics stages
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording, and each shader must have been created for its stage.
+    pub unsafe fn bind(
+        &self,
+        cmd: vk::CommandBuffer,
+        stages: &[vk::ShaderStageFlags],
+        shaders: &[vk::ShaderEXT],
+    ) {
+        self.ext.cmd_bind_shaders(cmd, stages, shaders);
+        let unused: Vec<vk::ShaderStageFlags> = GRAPHICS_STAGES
+            .into_iter()
+            .filter(|stage| !stages.contains(stage))
+            .collect();
+        if !stages.contains(&vk::ShaderStageFlags::COMPUTE) && !unused.is_empty() {
+            let nulls = vec![vk::ShaderEXT::null(); unused.len()];
+            self.ext.cmd_bind_shaders(cmd, &unused, &nulls);
+        }
+    }
+
+    /// Sets all of `state`, as needed before the first draw after binding shaders
+    ///
+    /// # Safety
+    ///
+    /// `cmd` must be recording inside dynamic rendering.
+    pub unsafe fn set_state(&self, cmd: vk::CommandBuffer, state: &DynamicState) {
+        let ext = &self.ext;
+        ext.cmd_set_viewport_with_count(cmd, &state.viewports);
+        ext.cmd_set_scissor_with_count(cmd, &state.scissors);
+        ext.cmd_set_vertex_input(cmd, &state.bindings, &state.attributes);
+        ext.cmd_set_primitive_topology(cmd, state.topology);
+        ext.cmd_set_primitive_restart_enable(cmd, state.primitive_restart);
+        ext.cmd_set_rasterizer_discard_enable(cmd, false);
+        ext.cmd_set_polygon_mode(cmd, state.polygon_mode);
+        self.device.cmd_set_line_width(cmd, state.line_width);
+        ext.cmd_set_cull_mode(cmd, state.cull_mode);
+        ext.cmd_set_front_face(cmd, state.front_face);
+        ext.cmd_set_rasterization_samples(cmd, state.samples);
+        ext.cmd_set_sample_mask(
+            cmd,
+            state.samples,
+            &[!0; 2][..sample_mask_words(state.samples)],
+        );
+        ext.cmd_set_alpha_to_coverage_enable(cmd, state.alpha_to_coverage);
+        ext.cmd_set_alpha_to_one_enable(cmd, false);
+        ext.cmd_set_depth_test_enable(cmd, state.depth_test);
+        ext.cmd_set_depth_write_enable(cmd, state.depth_write);
+        ext.cmd_set_depth_compare_op(cmd, state.depth_compare);
+        ext.cmd_set_depth_bias_enable(cmd, state.depth_bias.is_some());
+        let bias = state.depth_bias.unwrap_or_default();
+        self.device
+            .cmd_set_depth_bias(cmd, bias.constant_factor, bias.clamp, bias.slope_factor);
+        ext.cmd_set_depth_clamp_enable(cmd, state.depth_clamp);
+        ext.cmd_set_depth_bounds_test_enable(cmd, false);
+        ext.cmd_set_stencil_test_enable(cmd, state.stencil.is_some());
+        let stencil = state.stencil.unwrap_or_default();
+        for (face, op) in [
+            (vk::StencilFaceFlags::FRONT, stencil.front),
+            (vk::StencilFaceFlags::BACK, stencil.back),
+        ] {
+            ext.cmd_set_stencil_op(
+                cmd,
+                face,
+                op.fail_op,
+                op.pass_op,
+                op.depth_fail_op,
+                op.compare_op,
+            );
+            self.device
+                .cmd_set_stencil_compare_mask(cmd, face, op.compare_mask);
+            self.device
+                .cmd_set_stencil_write_mask(cmd, face, op.write_mask);
+            self.device
+                .cmd_set_stencil_reference(cmd, face, op.reference);
+        }
+        ext.cmd_set_logic_op_enable(cmd, false);
+        if state.attachments.is_empty() {
+            return;
+        }
+        let enables: Vec<vk::Bool32> = state
+            .attachments
+            .iter()
+            .map(|attachment| attachment.blend.is_some() as vk::Bool32)
+            .collect();
+        let equations: Vec<vk::ColorBlendEquationEXT> = state
+            .attachments
+            .iter()
+            .map(|attachment| attachment.blend.unwrap_or_default())
+            .collect();
+        let write_masks: Vec<vk::ColorComponentFlags> = state
+            .attachments
+            .iter()
+            .map(|attachment| attachment.write_mask)
+            .collect();
+        ext.cmd_set_color_blend_enable(cmd, 0, &enables);
+        ext.cmd_set_color_blend_equation(cmd, 0, &equations);
+        ext.cmd_set_color_write_mask(cmd, 0, &write_masks);
+    }
+
+    /// # Safety
+    ///
+    /// No pending command buffer may still use `shaders`.
+    pub unsafe fn destroy_shaders(&self, shaders: &[vk::ShaderEXT]) {
+        for &shader in shaders {
+            self.ext.destroy_shader(shader, None);
+        }
+    }
+}
+
+/// Sample mask words covering `samples`
+fn sample_mask_words(samples: vk::SampleCountFlags) -> usize {
+    (samples.as_raw() as usize).div_ceil(32)
+}
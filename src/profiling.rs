@@ -0,0 +1,282 @@
+//! CPU and GPU timings gathered over many frames and summarized, for benchmarks
+//!
+//! [`GpuTimer`] brackets passes with timestamp queries, read back once the frame's fence has been
+//! waited on so nothing stalls. [`Timings`] collects samples from either side by scope name and
+//! reduces them to [`ScopeStats`], written as CSV or JSON to track regressions between runs.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use ash::{prelude::VkResult, vk, Device};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::leaks;
+
+#[derive(Debug, Error)]
+pub enum ProfilingError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Timestamps around scopes of a frame's commands, a set of queries per frame in flight
+///
+/// Only usable on queues whose family reports nonzero `timestamp_valid_bits`.
+pub struct GpuTimer {
+    pool: vk::QueryPool,
+    max_scopes: u32,
+    /// Scopes begun in each frame's slot, in query order
+    scopes: Vec<Vec<String>>,
+    /// Whether the open scope in each frame's slot went over `max_scopes`
+    dropped: Vec<bool>,
+    /// Nanoseconds per tick
+    period: f64,
+    valid_mask: u64,
+}
+
+impl GpuTimer {
+    /// `timestamp_period` is the device limit, `valid_bits` the queue family's
+    /// `timestamp_valid_bits`
+    pub fn new(
+        device: &Device,
+        frames_in_flight: usize,
+        max_scopes: u32,
+        timestamp_period: f32,
+        valid_bits: u32,
+    ) -> VkResult<Self> {
+        let info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(frames_in_flight as u32 * max_scopes * 2);
+        let pool = unsafe { device.create_query_pool(&info, None)? };
+        leaks::track(pool, "gpu timer query pool");
+        Ok(GpuTimer {
+            pool,
+            max_scopes,
+            scopes: vec![Vec::new(); frames_in_flight],
+            dropped: vec![false; frames_in_flight],
+            period: timestamp_period as f64,
+            valid_mask: u64::MAX.checked_shr(64 - valid_bits).unwrap_or(0),
+        })
+    }
+
+    fn first_query(&self, frame: usize) -> u32 {
+        frame as u32 * self.max_scopes * 2
+    }
+
+    /// Resets `frame`'s queries, recorded before any scope, after [`GpuTimer::poll`] took the
+    /// previous results
+    pub fn begin_frame(&mut self, device: &Device, cmd: vk::CommandBuffer, frame: usize) {
+        self.scopes[frame].clear();
+        self.dropped[frame] = false;
+        unsafe {
+            device.cmd_reset_query_pool(
+                cmd,
+                self.pool,
+                self.first_query(frame),
+                self.max_scopes * 2,
+            )
+        };
+    }
+
+    /// Starts timing `name`, ignored once `max_scopes` have begun this frame
+    pub fn begin(&mut self, device: &Device, cmd: vk::CommandBuffer, frame: usize, name: &str) {
+        let index = self.scopes[frame].len() as u32;
+        if index == self.max_scopes {
+            self.dropped[frame] = true;
+            return;
+        }
+        self.scopes[frame].push(name.to_owned());
+        let query = self.first_query(frame) + index * 2;
+        unsafe {
+            device.cmd_write_timestamp(cmd, vk::PipelineStageFlags::TOP_OF_PIPE, self.pool, query)
+        };
+    }
+
+    /// Ends the scope begun last, scopes don't nest
+    pub fn end(&mut self, device: &Device, cmd: vk::CommandBuffer, frame: usize) {
+        if std::mem::take(&mut self.dropped[frame]) {
+            return;
+        }
+        let Some(index) = self.scopes[frame].len().checked_sub(1) else {
+            return;
+        };
+        let query = self.first_query(frame) + index as u32 * 2 + 1;
+        unsafe {
+            device.cmd_write_timestamp(
+                cmd,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.pool,
+                query,
+            )
+        };
+    }
+
+    /// Milliseconds each scope of `frame` took, once its fence has been waited on
+    pub fn poll(&self, device: &Device, frame: usize) -> VkResult<Vec<(String, f64)>> {
+        let scopes = &self.scopes[frame];
+        if scopes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ticks = vec![0u64; scopes.len() * 2];
+        unsafe {
+            device.get_query_pool_results(
+                self.pool,
+                self.first_query(frame),
+                ticks.len() as u32,
+                &mut ticks,
+                vk::QueryResultFlags::TYPE_64,
+            )?
+        };
+        Ok(scopes
+            .iter()
+            .zip(ticks.chunks_exact(2))
+            .map(|(name, pair)| {
+                let elapsed = (pair[1] & self.valid_mask).wrapping_sub(pair[0] & self.valid_mask)
+                    & self.valid_mask;
+                (name.clone(), elapsed as f64 * self.period / 1e6)
+            })
+            .collect())
+    }
+
+    /// # Safety
+    ///
+    /// No frame writing the timer's queries may still be executing.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.pool);
+        device.destroy_query_pool(self.pool, None);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Cpu,
+    Gpu,
+}
+
+/// Aggregate of one scope's samples, in milliseconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScopeStats {
+    pub name: String,
+    pub source: Source,
+    pub samples: usize,
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+/// Samples per scope, kept whole so percentiles are exact
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    samples: BTreeMap<(String, Source), Vec<f64>>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, source: Source, name: &str, ms: f64) {
+        self.samples
+            .entry((name.to_owned(), source))
+            .or_default()
+            .push(ms);
+    }
+
+    pub fn record_cpu(&mut self, name: &str, elapsed: Duration) {
+        self.record(Source::Cpu, name, elapsed.as_secs_f64() * 1000.0);
+    }
+
+    /// Runs `f`, recording how long it took under `name`
+    pub fn time<T>(&mut self, name: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record_cpu(name, start.elapsed());
+        result
+    }
+
+    /// Results of [`GpuTimer::poll`]
+    pub fn record_gpu(&mut self, scopes: impl IntoIterator<Item = (String, f64)>) {
+        for (name, ms) in scopes {
+            self.record(Source::Gpu, &name, ms);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Stats of every scope, CPU ones first, each sorted by name
+    pub fn summary(&self) -> Vec<ScopeStats> {
+        let mut stats: Vec<_> = self
+            .samples
+            .iter()
+            .map(|((name, source), samples)| {
+                let mut sorted = samples.clone();
+                sorted.sort_by(f64::total_cmp);
+                // Nearest rank
+                let percentile = |p: f64| {
+                    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+                    sorted[rank.clamp(1, sorted.len()) - 1]
+                };
+                ScopeStats {
+                    name: name.clone(),
+                    source: *source,
+                    samples: sorted.len(),
+                    mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+                    min: sorted[0],
+                    max: sorted[sorted.len() - 1],
+                    p50: percentile(50.0),
+                    p95: percentile(95.0),
+                    p99: percentile(99.0),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| (a.source, &a.name).cmp(&(b.source, &b.name)));
+        stats
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("name,source,samples,mean_ms,min_ms,max_ms,p50_ms,p95_ms,p99_ms\n");
+        for stats in self.summary() {
+            let source = match stats.source {
+                Source::Cpu => "cpu",
+                Source::Gpu => "gpu",
+            };
+            let _ = writeln!(
+                csv,
+                "{},{source},{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4}",
+                stats.name,
+                stats.samples,
+                stats.mean,
+                stats.min,
+                stats.max,
+                stats.p50,
+                stats.p95,
+                stats.p99
+            );
+        }
+        csv
+    }
+
+    /// Writes the summary as JSON for a `.json` extension, CSV otherwise
+    pub fn save(&self, path: &Path) -> Result<(), ProfilingError> {
+        let text = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_string_pretty(&self.summary())?
+        } else {
+            self.to_csv()
+        };
+        Ok(fs::write(path, text)?)
+    }
+}
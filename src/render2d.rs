@@ -0,0 +1,570 @@
+//! Batched 2D sprites, drawn as instanced quads with one draw per run of sprites sharing a texture
+
+use std::{collections::HashMap, error::Error, mem, ops::Range};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec4};
+use thiserror::Error;
+
+use crate::{
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{dynamic::DynamicBuffer, staging::StagingBelt, usage::MemoryCategory},
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        atlas::AtlasRegion,
+        sampler::{SamplerCache, SamplerDesc},
+    },
+    vertex::VertexInput,
+};
+
+pub const SPRITE_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec2 size;
+layout(location = 2) in vec2 uv_min;
+layout(location = 3) in vec2 uv_max;
+layout(location = 4) in vec4 color;
+layout(location = 5) in float rotation;
+
+layout(push_constant) uniform Push {
+    mat4 projection;
+} push;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_color;
+
+void main() {
+    // Four strip vertices per instance, corners (0, 0), (1, 0), (0, 1), (1, 1)
+    vec2 corner = vec2(float(gl_VertexIndex & 1), float((gl_VertexIndex >> 1) & 1));
+    vec2 local = (corner - 0.5) * size;
+    float c = cos(rotation);
+    float s = sin(rotation);
+    vec2 world = position + vec2(local.x * c - local.y * s, local.x * s + local.y * c);
+    gl_Position = push.projection * vec4(world, 0.0, 1.0);
+    out_uv = mix(uv_min, uv_max, corner);
+    out_color = color;
+}
+"#;
+
+pub const SPRITE_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 0) uniform texture2D sprite_texture;
+layout(set = 0, binding = 1) uniform sampler sprite_sampler;
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = texture(sampler2D(sprite_texture, sprite_sampler), uv) * color;
+}
+"#;
+
+const PROJECTION_SIZE: u32 = mem::size_of::<Mat4>() as u32;
+
+#[derive(Debug, Error)]
+pub enum Render2DError {
+    #[error("failed to compile sprite shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// A textured quad centred on `position`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+    pub position: Vec2,
+    pub size: Vec2,
+    /// Counter clockwise radians around the centre
+    pub rotation: f32,
+    pub color: Vec4,
+    pub uv_min: Vec2,
+    pub uv_max: Vec2,
+    /// Higher layers draw on top, sprites within a layer keep submission order per texture
+    pub layer: i32,
+}
+
+impl Sprite {
+    pub fn new(position: Vec2, size: Vec2) -> Self {
+        Sprite {
+            position,
+            size,
+            rotation: 0.0,
+            color: Vec4::ONE,
+            uv_min: Vec2::ZERO,
+            uv_max: Vec2::ONE,
+            layer: 0,
+        }
+    }
+
+    pub fn with_rotation(mut self, rotation: f32) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_color(mut self, color: Vec4) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_uv(mut self, uv_min: Vec2, uv_max: Vec2) -> Self {
+        self.uv_min = uv_min;
+        self.uv_max = uv_max;
+        self
+    }
+
+    /// Samples an image packed into an atlas page
+    pub fn with_region(self, region: &AtlasRegion) -> Self {
+        self.with_uv(region.uv_min, region.uv_max)
+    }
+
+    pub fn with_layer(mut self, layer: i32) -> Self {
+        self.layer = layer;
+        self
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, VertexInput, AsBytes)]
+#[vertex(instance)]
+struct SpriteInstance {
+    position: Vec2,
+    size: Vec2,
+    uv_min: Vec2,
+    uv_max: Vec2,
+    color: [f32; 4],
+    rotation: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Renderer2DConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// One instance buffer per frame so writes never race the GPU
+    pub frames_in_flight: usize,
+    pub max_sprites: u32,
+    /// Distinct textures with descriptors at once, see [`Renderer2D::forget_texture`]
+    pub max_textures: u32,
+    /// Fragment shader taking the same inputs and bindings as [`SPRITE_FRAG_GLSL`]
+    pub fragment_shader: &'static str,
+    /// Bytes of fragment push constants following the projection, which the fragment shader's
+    /// block has to declare first
+    pub fragment_params_size: u32,
+}
+
+impl Renderer2DConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        Renderer2DConfig {
+            render_pass,
+            subpass: 0,
+            frames_in_flight: 2,
+            max_sprites: 4096,
+            max_textures: 64,
+            fragment_shader: SPRITE_FRAG_GLSL,
+            fragment_params_size: 0,
+        }
+    }
+
+    /// Draws with a custom fragment shader, whose parameters are set through
+    /// [`Renderer2D::set_fragment_params`]
+    pub fn with_fragment_shader(mut self, source: &'static str, params_size: u32) -> Self {
+        self.fragment_shader = source;
+        self.fragment_params_size = params_size;
+        self
+    }
+}
+
+pub struct Renderer2D {
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    pool: vk::DescriptorPool,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    sets: HashMap<vk::ImageView, vk::DescriptorSet>,
+    instances: Vec<DynamicBuffer>,
+    max_sprites: u32,
+    sprites: Vec<(vk::ImageView, Sprite)>,
+    batches: Vec<(vk::DescriptorSet, Range<u32>)>,
+    fragment_params: Vec<u8>,
+    /// Fragment shaders with parameters see the whole block, projection included
+    push_stages: vk::ShaderStageFlags,
+}
+
+impl Renderer2D {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        config: Renderer2DConfig,
+    ) -> Result<Self, Render2DError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| Render2DError::Compile(Box::new(err)))
+        };
+        let vert = compile(SPRITE_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(config.fragment_shader, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = Renderer2D {
+            set_layout: vk::DescriptorSetLayout::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            pool: vk::DescriptorPool::null(),
+            sampler,
+            sets: HashMap::new(),
+            instances: Vec::new(),
+            max_sprites: config.max_sprites,
+            sprites: Vec::new(),
+            batches: Vec::new(),
+            fragment_params: vec![0; config.fragment_params_size as usize],
+            push_stages: if config.fragment_params_size > 0 {
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT
+            } else {
+                vk::ShaderStageFlags::VERTEX
+            },
+        };
+        let result =
+            unsafe { this.create_objects(device, mem_props, limits, &vert, &frag, config) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        vert: &[u32],
+        frag: &[u32],
+        config: Renderer2DConfig,
+    ) -> Result<(), Render2DError> {
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "render2d descriptor set layout");
+        let push_range = vk::PushConstantRange {
+            stage_flags: self.push_stages,
+            offset: 0,
+            size: PROJECTION_SIZE + config.fragment_params_size,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "render2d pipeline layout");
+        let pool_sizes = types.map(|ty| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count: config.max_textures,
+        });
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+                .max_sets(config.max_textures)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "render2d descriptor pool");
+
+        let size = config.max_sprites as vk::DeviceSize * mem::size_of::<SpriteInstance>() as u64;
+        for _ in 0..config.frames_in_flight {
+            let mut buffer = DynamicBuffer::new(
+                device,
+                mem_props,
+                limits,
+                size,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+            )?;
+            buffer.buffer.set_category(MemoryCategory::Mesh);
+            self.instances.push(buffer);
+        }
+
+        let attributes = SpriteInstance::attributes(0, 0);
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[push_range], &attributes)?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "render2d shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "render2d shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_bindings = [SpriteInstance::binding(0)];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&attributes);
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "sprites");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Queues a sprite sampling `texture` for this frame, dropped past `max_sprites`
+    pub fn draw(&mut self, texture: vk::ImageView, sprite: Sprite) {
+        if self.sprites.len() < self.max_sprites as usize {
+            self.sprites.push((texture, sprite));
+        }
+    }
+
+    /// Uploads this frame's sprites into the instance buffer of `frame` and forms the batches
+    ///
+    /// Must be recorded outside of a render pass, the staged upload path uses a transfer.
+    pub fn prepare(
+        &mut self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        frame: usize,
+    ) -> VkResult<()> {
+        // Descriptors first, so running out of them leaves the queued sprites untouched
+        for (texture, _) in &self.sprites {
+            if !self.sets.contains_key(texture) {
+                let set = allocate_set(device, self.pool, self.set_layout, self.sampler, *texture)?;
+                self.sets.insert(*texture, set);
+            }
+        }
+        // Stable, so sprites within a layer and texture keep their order
+        self.sprites
+            .sort_by_key(|(texture, sprite)| (sprite.layer, *texture));
+
+        self.batches.clear();
+        let mut instances = Vec::with_capacity(self.sprites.len());
+        for (i, (texture, sprite)) in self.sprites.drain(..).enumerate() {
+            let set = self.sets[&texture];
+            let i = i as u32;
+            match self.batches.last_mut() {
+                Some((last, range)) if *last == set => range.end = i + 1,
+                _ => self.batches.push((set, i..i + 1)),
+            }
+            instances.push(SpriteInstance {
+                position: sprite.position,
+                size: sprite.size,
+                uv_min: sprite.uv_min,
+                uv_max: sprite.uv_max,
+                color: sprite.color.to_array(),
+                rotation: sprite.rotation,
+            });
+        }
+        if instances.is_empty() {
+            return Ok(());
+        }
+        self.instances[frame].write(device, staging, cmd, 0, slice_as_bytes(&instances))
+    }
+
+    /// Records the batches formed by the last [`Renderer2D::prepare`] of `frame`
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn record(&self, device: &Device, cmd: vk::CommandBuffer, frame: usize, projection: Mat4) {
+        if self.batches.is_empty() {
+            return;
+        }
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                self.push_stages,
+                0,
+                projection.as_bytes(),
+            );
+            if !self.fragment_params.is_empty() {
+                device.cmd_push_constants(
+                    cmd,
+                    self.pipeline_layout,
+                    self.push_stages,
+                    PROJECTION_SIZE,
+                    &self.fragment_params,
+                );
+            }
+            device.cmd_bind_vertex_buffers(cmd, 0, &[self.instances[frame].buffer.buffer], &[0]);
+            for (set, range) in &self.batches {
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.pipeline_layout,
+                    0,
+                    &[*set],
+                    &[],
+                );
+                device.cmd_draw(cmd, 4, range.end - range.start, 0, range.start);
+            }
+        }
+    }
+
+    /// Sets the fragment push constants recorded from now on, `params` must be exactly
+    /// [`Renderer2DConfig::fragment_params_size`] bytes
+    pub fn set_fragment_params(&mut self, params: &[u8]) {
+        assert_eq!(params.len(), self.fragment_params.len());
+        self.fragment_params.copy_from_slice(params);
+    }
+
+    /// Frees the descriptor of a texture about to be destroyed
+    ///
+    /// # Safety
+    ///
+    /// No recorded batch drawing `texture` may still be executing.
+    pub unsafe fn forget_texture(
+        &mut self,
+        device: &Device,
+        texture: vk::ImageView,
+    ) -> VkResult<()> {
+        match self.sets.remove(&texture) {
+            Some(set) => device.free_descriptor_sets(self.pool, &[set]),
+            None => Ok(()),
+        }
+    }
+
+    /// Projection mapping pixel coordinates, origin top left, onto a `width` by `height` target
+    pub fn pixel_projection(width: f32, height: f32) -> Mat4 {
+        Mat4::orthographic_rh(0.0, width, 0.0, height, -1.0, 1.0)
+    }
+
+    /// # Safety
+    ///
+    /// No recorded sprite batch may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in self.instances.drain(..) {
+            buffer.destroy(device);
+        }
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        self.sets.clear();
+    }
+}
+
+fn allocate_set(
+    device: &Device,
+    pool: vk::DescriptorPool,
+    layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    texture: vk::ImageView,
+) -> VkResult<vk::DescriptorSet> {
+    let set = unsafe {
+        device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(pool)
+                .set_layouts(&[layout]),
+        )?[0]
+    };
+    let image = [vk::DescriptorImageInfo {
+        sampler: vk::Sampler::null(),
+        image_view: texture,
+        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    }];
+    let samplers = [vk::DescriptorImageInfo {
+        sampler,
+        image_view: vk::ImageView::null(),
+        image_layout: vk::ImageLayout::UNDEFINED,
+    }];
+    let writes = [
+        vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image)
+            .build(),
+        vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&samplers)
+            .build(),
+    ];
+    unsafe { device.update_descriptor_sets(&writes, &[]) };
+    Ok(set)
+}
@@ -0,0 +1,440 @@
+//! Node hierarchy with local transforms, flattened into per-instance data each frame
+
+use glam::{Mat4, Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::layout::{AsBytes, ShaderLayout};
+
+#[cfg(feature = "bevy_ecs")]
+pub mod ecs;
+pub mod file;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[cfg_attr(feature = "bevy_ecs", derive(bevy_ecs::component::Component))]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        translation: Vec3::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3::ONE,
+    };
+
+    pub fn from_translation(translation: Vec3) -> Self {
+        Transform {
+            translation,
+            ..Transform::IDENTITY
+        }
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    /// Between `self` at 0 and `other` at 1, for rendering between simulation steps
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+/// Index of a mesh owned by the renderer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MeshId(pub u32);
+
+/// Index of a material owned by the renderer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MaterialId(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LightKind {
+    Directional,
+    Point {
+        range: f32,
+    },
+    Spot {
+        range: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "bevy_ecs", derive(bevy_ecs::component::Component))]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Vec3,
+    pub intensity: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+#[cfg_attr(feature = "bevy_ecs", derive(bevy_ecs::component::Component))]
+pub struct Camera {
+    /// Vertical field of view in radians
+    pub fov_y: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            fov_y: std::f32::consts::FRAC_PI_4,
+            near: 0.1,
+            far: 1000.,
+        }
+    }
+}
+
+impl Camera {
+    /// Right handed projection with Vulkan's 0..1 depth range and y pointing down
+    pub fn projection(&self, aspect: f32) -> Mat4 {
+        let mut proj = Mat4::perspective_rh(self.fov_y, aspect, self.near, self.far);
+        proj.y_axis.y = -proj.y_axis.y;
+        proj
+    }
+}
+
+/// Per-instance data uploaded for every mesh node
+#[derive(Debug, Clone, Copy, ShaderLayout, AsBytes)]
+#[layout(std430)]
+#[repr(C)]
+pub struct InstanceData {
+    pub model: Mat4,
+    /// Inverse transpose of the model matrix, a mat4 so it needs no padding
+    pub normal: Mat4,
+}
+
+impl InstanceData {
+    pub fn new(model: Mat4) -> Self {
+        InstanceData {
+            model,
+            normal: model.inverse().transpose(),
+        }
+    }
+}
+
+/// Handle to a node, only valid for the scene that created it
+///
+/// The generation tells a removed node's id apart from a later node reusing its slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Node {
+    pub name: String,
+    pub local: Transform,
+    world: Mat4,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    pub mesh: Option<MeshId>,
+    pub light: Option<Light>,
+    pub camera: Option<Camera>,
+}
+
+impl Node {
+    pub fn new(name: impl Into<String>) -> Self {
+        Node {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_transform(mut self, local: Transform) -> Self {
+        self.local = local;
+        self
+    }
+
+    pub fn with_mesh(mut self, mesh: MeshId) -> Self {
+        self.mesh = Some(mesh);
+        self
+    }
+
+    pub fn with_light(mut self, light: Light) -> Self {
+        self.light = Some(light);
+        self
+    }
+
+    pub fn with_camera(mut self, camera: Camera) -> Self {
+        self.camera = Some(camera);
+        self
+    }
+
+    /// World matrix as of the last [`Scene::update_transforms`]
+    pub fn world(&self) -> Mat4 {
+        self.world
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scene {
+    nodes: Vec<Option<Node>>,
+    /// Bumped whenever the node in a slot is removed
+    generations: Vec<u32>,
+    free: Vec<usize>,
+    roots: Vec<NodeId>,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `node` under `parent`, or as a root with `None`
+    ///
+    /// Returns `None` and adds nothing if `parent` has been removed.
+    pub fn add(&mut self, node: Node, parent: Option<NodeId>) -> Option<NodeId> {
+        if parent.is_some_and(|parent| self.get(parent).is_none()) {
+            return None;
+        }
+        let index = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = Some(node);
+                i
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.generations.push(0);
+                self.nodes.len() - 1
+            }
+        };
+        let id = NodeId {
+            index,
+            generation: self.generations[index],
+        };
+        self.link(id, parent);
+        Some(id)
+    }
+
+    /// Removes a node along with all of its descendants
+    pub fn remove(&mut self, id: NodeId) -> Option<Node> {
+        self.get(id)?;
+        self.unlink(id);
+        let node = self.release(id.index);
+        for &child in &node.children {
+            self.remove_subtree(child);
+        }
+        Some(node)
+    }
+
+    fn remove_subtree(&mut self, id: NodeId) {
+        for child in self.release(id.index).children {
+            self.remove_subtree(child);
+        }
+    }
+
+    /// Empties a live slot, retiring the ids that point at it
+    fn release(&mut self, index: usize) -> Node {
+        let node = self.nodes[index].take().expect("slot is live");
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.free.push(index);
+        node
+    }
+
+    /// Moves a node under a new parent, or to the root with `None`
+    ///
+    /// Returns false and leaves the hierarchy alone if it would create a cycle, or either node
+    /// has been removed.
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) -> bool {
+        if self.get(id).is_none() || parent.is_some_and(|parent| self.get(parent).is_none()) {
+            return false;
+        }
+        let mut ancestor = parent;
+        while let Some(current) = ancestor {
+            if current == id {
+                return false;
+            }
+            ancestor = self.get(current).and_then(|node| node.parent);
+        }
+        self.unlink(id);
+        self.link(id, parent);
+        true
+    }
+
+    fn link(&mut self, id: NodeId, parent: Option<NodeId>) {
+        match parent {
+            Some(parent) => self[parent].children.push(id),
+            None => self.roots.push(id),
+        }
+        self[id].parent = parent;
+    }
+
+    fn unlink(&mut self, id: NodeId) {
+        let Some(node) = self.get(id) else {
+            return;
+        };
+        let siblings = match node.parent {
+            Some(parent) => &mut self[parent].children,
+            None => &mut self.roots,
+        };
+        siblings.retain(|&sibling| sibling != id);
+    }
+
+    /// The node behind `id`, `None` once it has been removed
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        if self.generations.get(id.index) != Some(&id.generation) {
+            return None;
+        }
+        self.nodes[id.index].as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        if self.generations.get(id.index) != Some(&id.generation) {
+            return None;
+        }
+        self.nodes[id.index].as_mut()
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes
+            .iter()
+            .zip(&self.generations)
+            .enumerate()
+            .filter_map(|(index, (node, &generation))| {
+                Some((NodeId { index, generation }, node.as_ref()?))
+            })
+    }
+
+    /// Propagates local transforms down the hierarchy into world matrices
+    pub fn update_transforms(&mut self) {
+        let mut stack: Vec<_> = self.roots.iter().map(|&id| (id, Mat4::IDENTITY)).collect();
+        while let Some((id, parent_world)) = stack.pop() {
+            let node = &mut self[id];
+            node.world = parent_world * node.local.matrix();
+            let world = node.world;
+            stack.extend(node.children.iter().map(|&child| (child, world)));
+        }
+    }
+
+    /// Instance data for every mesh node, sorted by mesh so each mesh is one instanced draw
+    pub fn gather_instances(&self, out: &mut Vec<(MeshId, InstanceData)>) {
+        out.clear();
+        out.extend(
+            self.iter()
+                .filter_map(|(_, node)| Some((node.mesh?, InstanceData::new(node.world)))),
+        );
+        out.sort_by_key(|(mesh, _)| *mesh);
+    }
+
+    /// Nodes behind each instance, in the order [`Scene::gather_instances`] lays them out
+    pub fn gather_instance_nodes(&self, out: &mut Vec<NodeId>) {
+        let mut nodes: Vec<_> = self
+            .iter()
+            .filter_map(|(id, node)| Some((node.mesh?, id)))
+            .collect();
+        nodes.sort_by_key(|(mesh, _)| *mesh);
+        out.clear();
+        out.extend(nodes.into_iter().map(|(_, id)| id));
+    }
+
+    /// Lights with their world space position and direction, the direction being -Z
+    pub fn lights(&self) -> impl Iterator<Item = (&Light, Vec3, Vec3)> {
+        self.iter().filter_map(|(_, node)| {
+            let light = node.light.as_ref()?;
+            let position = node.world.transform_point3(Vec3::ZERO);
+            let direction = node
+                .world
+                .transform_vector3(Vec3::NEG_Z)
+                .normalize_or_zero();
+            Some((light, position, direction))
+        })
+    }
+
+    /// The first camera in the scene with its view matrix
+    pub fn active_camera(&self) -> Option<(&Camera, Mat4)> {
+        self.iter()
+            .find_map(|(_, node)| Some((node.camera.as_ref()?, node.world.inverse())))
+    }
+}
+
+impl std::ops::Index<NodeId> for Scene {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        self.get(id).expect("node was removed")
+    }
+}
+
+impl std::ops::IndexMut<NodeId> for Scene {
+    fn index_mut(&mut self, id: NodeId) -> &mut Node {
+        self.get_mut(id).expect("node was removed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_id_does_not_reach_a_node_reusing_its_slot() {
+        let mut scene = Scene::new();
+        let parent = scene.add(Node::new("parent"), None).unwrap();
+        let child = scene.add(Node::new("child"), Some(parent)).unwrap();
+        scene.remove(parent);
+        let reused = scene.add(Node::new("reused"), None).unwrap();
+        assert_ne!(reused, parent);
+        assert_ne!(reused, child);
+        assert!(scene.get(parent).is_none());
+        assert!(scene.get(child).is_none());
+        assert!(scene.remove(child).is_none());
+        assert_eq!(scene[reused].name, "reused");
+        assert_eq!(scene.roots(), [reused]);
+    }
+
+    #[test]
+    fn adding_under_a_removed_parent_adds_nothing() {
+        let mut scene = Scene::new();
+        let parent = scene.add(Node::new("parent"), None).unwrap();
+        scene.remove(parent);
+        assert!(scene.add(Node::new("orphan"), Some(parent)).is_none());
+        assert_eq!(scene.iter().count(), 0);
+        assert!(scene.roots().is_empty());
+    }
+
+    #[test]
+    fn set_parent_with_a_removed_node_leaves_the_hierarchy_alone() {
+        let mut scene = Scene::new();
+        let parent = scene.add(Node::new("parent"), None).unwrap();
+        let child = scene.add(Node::new("child"), Some(parent)).unwrap();
+        let gone = scene.add(Node::new("gone"), None).unwrap();
+        scene.remove(gone);
+
+        assert!(!scene.set_parent(child, Some(gone)));
+        assert_eq!(scene[child].parent(), Some(parent));
+        assert_eq!(scene[parent].children(), [child]);
+
+        assert!(!scene.set_parent(gone, Some(parent)));
+        assert_eq!(scene[parent].children(), [child]);
+        assert_eq!(scene.roots(), [parent]);
+    }
+}
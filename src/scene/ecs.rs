@@ -0,0 +1,160 @@
+//! `bevy_ecs` components for renderable entities and the systems that extract them each frame
+//!
+//! Game state lives in the [`World`](bevy_ecs::world::World), the renderer only ever reads the
+//! [`RenderExtract`] resource filled by [`extract_render_data`].
+
+use std::collections::HashMap;
+
+use bevy_ecs::{
+    bundle::Bundle,
+    component::Component,
+    entity::Entity,
+    schedule::{IntoSystemConfigs, Schedule},
+    system::{Query, ResMut, Resource},
+};
+use glam::{Mat4, Vec3};
+
+use super::{Camera, InstanceData, Light, MaterialId, MeshId, Transform};
+
+/// World matrix written by [`propagate_transforms`]
+#[derive(Debug, Clone, Copy, Default, Component)]
+pub struct GlobalTransform(pub Mat4);
+
+/// Makes an entity's [`Transform`] relative to another entity
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Parent(pub Entity);
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct MeshHandle(pub MeshId);
+
+#[derive(Debug, Clone, Copy, Component)]
+pub struct MaterialHandle(pub MaterialId);
+
+#[derive(Bundle)]
+pub struct MeshBundle {
+    pub transform: Transform,
+    pub global: GlobalTransform,
+    pub mesh: MeshHandle,
+    pub material: MaterialHandle,
+}
+
+impl MeshBundle {
+    pub fn new(transform: Transform, mesh: MeshId, material: MaterialId) -> Self {
+        MeshBundle {
+            transform,
+            global: GlobalTransform::default(),
+            mesh: MeshHandle(mesh),
+            material: MaterialHandle(material),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractedInstance {
+    pub mesh: MeshId,
+    pub material: MaterialId,
+    pub data: InstanceData,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractedLight {
+    pub light: Light,
+    pub position: Vec3,
+    pub direction: Vec3,
+}
+
+/// Everything the renderer needs from the world for one frame
+#[derive(Debug, Default, Resource)]
+pub struct RenderExtract {
+    /// Sorted by mesh then material
+    pub instances: Vec<ExtractedInstance>,
+    pub lights: Vec<ExtractedLight>,
+    /// The first camera found with its view matrix
+    pub camera: Option<(Camera, Mat4)>,
+}
+
+/// Resolves [`Parent`] chains into world matrices
+pub fn propagate_transforms(
+    mut query: Query<(Entity, &Transform, Option<&Parent>, &mut GlobalTransform)>,
+) {
+    let locals: HashMap<Entity, (Mat4, Option<Entity>)> = query
+        .iter()
+        .map(|(entity, transform, parent, _)| {
+            (entity, (transform.matrix(), parent.map(|parent| parent.0)))
+        })
+        .collect();
+
+    let mut worlds = HashMap::with_capacity(locals.len());
+    for &entity in locals.keys() {
+        resolve_world(entity, &locals, &mut worlds);
+    }
+    for (entity, _, _, mut global) in &mut query {
+        global.0 = worlds[&entity];
+    }
+}
+
+fn resolve_world(
+    entity: Entity,
+    locals: &HashMap<Entity, (Mat4, Option<Entity>)>,
+    worlds: &mut HashMap<Entity, Mat4>,
+) -> Mat4 {
+    if let Some(&world) = worlds.get(&entity) {
+        return world;
+    }
+    // Parents without a transform are treated as the origin
+    let Some(&(local, parent)) = locals.get(&entity) else {
+        return Mat4::IDENTITY;
+    };
+    // Insert first so a parent cycle terminates instead of recursing forever
+    worlds.insert(entity, local);
+    let world = match parent {
+        Some(parent) => resolve_world(parent, locals, worlds) * local,
+        None => local,
+    };
+    worlds.insert(entity, world);
+    world
+}
+
+pub fn extract_render_data(
+    meshes: Query<(&GlobalTransform, &MeshHandle, Option<&MaterialHandle>)>,
+    lights: Query<(&GlobalTransform, &Light)>,
+    cameras: Query<(&GlobalTransform, &Camera)>,
+    mut extract: ResMut<RenderExtract>,
+) {
+    let extract = &mut *extract;
+
+    extract.instances.clear();
+    extract.instances.extend(
+        meshes
+            .iter()
+            .map(|(global, mesh, material)| ExtractedInstance {
+                mesh: mesh.0,
+                material: material.map_or(MaterialId(0), |material| material.0),
+                data: InstanceData::new(global.0),
+            }),
+    );
+    extract
+        .instances
+        .sort_by_key(|instance| (instance.mesh, instance.material));
+
+    extract.lights.clear();
+    extract
+        .lights
+        .extend(lights.iter().map(|(global, light)| ExtractedLight {
+            light: *light,
+            position: global.0.transform_point3(Vec3::ZERO),
+            direction: global.0.transform_vector3(Vec3::NEG_Z).normalize_or_zero(),
+        }));
+
+    extract.camera = cameras
+        .iter()
+        .next()
+        .map(|(global, camera)| (*camera, global.0.inverse()));
+}
+
+/// Transform propagation followed by extraction, the world needs a [`RenderExtract`] resource
+pub fn render_schedule() -> Schedule {
+    let mut schedule = Schedule::default();
+    schedule.add_systems((propagate_transforms, extract_render_data).chain());
+    schedule
+}
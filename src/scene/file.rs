@@ -0,0 +1,356 @@
+//! Scenes saved to and loaded from files meant to be edited by hand
+//!
+//! A [`SceneFile`] nests nodes the way they're parented, with meshes referring to asset files by
+//! path and to materials by name, so test scenes can be authored without touching code. RON is
+//! the default format, files ending in `.json` are read and written as JSON instead.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{Camera, Light, MeshId, Node, NodeId, Scene, Transform};
+
+#[derive(Debug, Error)]
+pub enum SceneFileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Parse(#[from] ron::error::SpannedError),
+    #[error(transparent)]
+    Write(#[from] ron::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("the node to add under has been removed")]
+    RemovedParent,
+    #[error("material {0:?} isn't defined")]
+    UnknownMaterial(String),
+    #[error("couldn't load mesh {path:?}: {source}")]
+    Mesh {
+        path: PathBuf,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+/// A node [`SceneFile::instantiate`] added, with the ones it added under it
+///
+/// Children attached at runtime aren't listed, so updates from the file leave them alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileNode {
+    pub id: NodeId,
+    pub children: Vec<FileNode>,
+}
+
+/// Textures and parameters of a material, by the names its template gives them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc {
+    /// Texture files by slot, e.g. `"albedo"`
+    pub textures: BTreeMap<String, PathBuf>,
+    /// Scalars and vectors alike as lists
+    pub params: BTreeMap<String, Vec<f32>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MeshRef {
+    pub path: PathBuf,
+    /// Key into [`SceneFile::materials`], the mesh's own material without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub material: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeDesc {
+    pub name: String,
+    pub transform: Transform,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<MeshRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light: Option<Light>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Camera>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<NodeDesc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SceneFile {
+    pub materials: BTreeMap<String, MaterialDesc>,
+    pub nodes: Vec<NodeDesc>,
+    /// Where the viewer's camera starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view: Option<Transform>,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+impl SceneFile {
+    pub fn from_ron(text: &str) -> Result<Self, SceneFileError> {
+        Ok(ron::from_str(text)?)
+    }
+
+    pub fn to_ron(&self) -> Result<String, SceneFileError> {
+        let config = ron::ser::PrettyConfig::new().struct_names(true);
+        Ok(ron::ser::to_string_pretty(self, config)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SceneFileError> {
+        let text = fs::read_to_string(path)?;
+        if is_json(path) {
+            Ok(serde_json::from_str(&text)?)
+        } else {
+            Self::from_ron(&text)
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SceneFileError> {
+        let text = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            self.to_ron()?
+        };
+        Ok(fs::write(path, text)?)
+    }
+
+    /// Adds the nodes to `scene` under `parent`, returning the top level ones
+    ///
+    /// `load_mesh` is called once per distinct mesh reference, nothing is added if it fails or
+    /// `parent` was removed. World matrices are stale until the next [`Scene::update_transforms`].
+    pub fn instantiate<F>(
+        &self,
+        scene: &mut Scene,
+        parent: Option<NodeId>,
+        load_mesh: F,
+    ) -> Result<Vec<FileNode>, SceneFileError>
+    where
+        F: FnMut(&MeshRef, Option<&MaterialDesc>) -> Result<MeshId, Box<dyn Error + Send + Sync>>,
+    {
+        check_parent(scene, parent)?;
+        let meshes = self.load_meshes(load_mesh)?;
+        Ok(self
+            .nodes
+            .iter()
+            .map(|desc| add_node(scene, parent, desc, &meshes))
+            .collect())
+    }
+
+    /// Brings nodes instantiated from an earlier version of the file up to date with this one
+    ///
+    /// Siblings are matched by name, in order for repeated names. Matched nodes are changed in
+    /// place and keep their ids, so whatever refers to them, such as a camera following one, is
+    /// undisturbed. Nodes that are gone are removed and new ones added. Only nodes from the file
+    /// are compared, children attached to them at runtime stay. `roots` are what
+    /// [`SceneFile::instantiate`] or a previous update returned, the new ones are returned. As
+    /// with instantiating, the scene is left alone if a mesh fails to load.
+    pub fn update<F>(
+        &self,
+        scene: &mut Scene,
+        parent: Option<NodeId>,
+        roots: &[FileNode],
+        load_mesh: F,
+    ) -> Result<Vec<FileNode>, SceneFileError>
+    where
+        F: FnMut(&MeshRef, Option<&MaterialDesc>) -> Result<MeshId, Box<dyn Error + Send + Sync>>,
+    {
+        check_parent(scene, parent)?;
+        let meshes = self.load_meshes(load_mesh)?;
+        Ok(update_nodes(scene, parent, roots, &self.nodes, &meshes))
+    }
+
+    /// Loads every mesh referred to before anything is added
+    fn load_meshes<F>(&self, mut load_mesh: F) -> Result<HashMap<MeshRef, MeshId>, SceneFileError>
+    where
+        F: FnMut(&MeshRef, Option<&MaterialDesc>) -> Result<MeshId, Box<dyn Error + Send + Sync>>,
+    {
+        let mut meshes = HashMap::new();
+        let mut stack: Vec<_> = self.nodes.iter().collect();
+        while let Some(desc) = stack.pop() {
+            stack.extend(&desc.children);
+            let Some(mesh_ref) = &desc.mesh else {
+                continue;
+            };
+            if meshes.contains_key(mesh_ref) {
+                continue;
+            }
+            let material = match &mesh_ref.material {
+                Some(name) => Some(
+                    self.materials
+                        .get(name)
+                        .ok_or_else(|| SceneFileError::UnknownMaterial(name.clone()))?,
+                ),
+                None => None,
+            };
+            let id = load_mesh(mesh_ref, material).map_err(|source| SceneFileError::Mesh {
+                path: mesh_ref.path.clone(),
+                source,
+            })?;
+            meshes.insert(mesh_ref.clone(), id);
+        }
+        Ok(meshes)
+    }
+
+    /// Describes the whole of `scene`, naming meshes with `mesh_ref`
+    ///
+    /// Meshes `mesh_ref` doesn't know are left out, their nodes are kept. Materials have to be
+    /// filled in by the caller, as the scene only knows meshes.
+    pub fn capture(scene: &Scene, mut mesh_ref: impl FnMut(MeshId) -> Option<MeshRef>) -> Self {
+        fn describe(
+            scene: &Scene,
+            id: NodeId,
+            mesh_ref: &mut impl FnMut(MeshId) -> Option<MeshRef>,
+        ) -> NodeDesc {
+            let node = &scene[id];
+            NodeDesc {
+                name: node.name.clone(),
+                transform: node.local,
+                mesh: node.mesh.and_then(&mut *mesh_ref),
+                light: node.light,
+                camera: node.camera,
+                children: node
+                    .children()
+                    .iter()
+                    .map(|&child| describe(scene, child, mesh_ref))
+                    .collect(),
+            }
+        }
+        SceneFile {
+            nodes: scene
+                .roots()
+                .iter()
+                .map(|&root| describe(scene, root, &mut mesh_ref))
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+fn check_parent(scene: &Scene, parent: Option<NodeId>) -> Result<(), SceneFileError> {
+    match parent {
+        Some(parent) if scene.get(parent).is_none() => Err(SceneFileError::RemovedParent),
+        _ => Ok(()),
+    }
+}
+
+fn add_node(
+    scene: &mut Scene,
+    parent: Option<NodeId>,
+    desc: &NodeDesc,
+    meshes: &HashMap<MeshRef, MeshId>,
+) -> FileNode {
+    let node = Node {
+        name: desc.name.clone(),
+        ..Default::default()
+    };
+    let id = scene
+        .add(node, parent)
+        .expect("parents are checked or just added");
+    set_node(&mut scene[id], desc, meshes);
+    let children = desc
+        .children
+        .iter()
+        .map(|child| add_node(scene, Some(id), child, meshes))
+        .collect();
+    FileNode { id, children }
+}
+
+fn set_node(node: &mut Node, desc: &NodeDesc, meshes: &HashMap<MeshRef, MeshId>) {
+    node.local = desc.transform;
+    node.mesh = desc.mesh.as_ref().map(|mesh_ref| meshes[mesh_ref]);
+    node.light = desc.light;
+    node.camera = desc.camera;
+}
+
+/// Matches `descs` against the existing `nodes` under `parent`, returning the nodes they end up as
+fn update_nodes(
+    scene: &mut Scene,
+    parent: Option<NodeId>,
+    nodes: &[FileNode],
+    descs: &[NodeDesc],
+    meshes: &HashMap<MeshRef, MeshId>,
+) -> Vec<FileNode> {
+    // Ids of nodes removed behind the file's back no longer resolve, so they can't match
+    let mut unmatched: Vec<_> = nodes
+        .iter()
+        .filter(|node| scene.get(node.id).is_some())
+        .collect();
+    let updated = descs
+        .iter()
+        .map(|desc| {
+            let Some(i) = unmatched
+                .iter()
+                .position(|node| scene[node.id].name == desc.name)
+            else {
+                return add_node(scene, parent, desc, meshes);
+            };
+            let node = unmatched.remove(i);
+            set_node(&mut scene[node.id], desc, meshes);
+            let children =
+                update_nodes(scene, Some(node.id), &node.children, &desc.children, meshes);
+            FileNode {
+                id: node.id,
+                children,
+            }
+        })
+        .collect();
+    for node in unmatched {
+        scene.remove(node.id);
+    }
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(name: &str, children: Vec<NodeDesc>) -> NodeDesc {
+        NodeDesc {
+            name: name.to_owned(),
+            children,
+            ..Default::default()
+        }
+    }
+
+    fn file(nodes: Vec<NodeDesc>) -> SceneFile {
+        SceneFile {
+            nodes,
+            ..Default::default()
+        }
+    }
+
+    fn no_meshes(
+        _: &MeshRef,
+        _: Option<&MaterialDesc>,
+    ) -> Result<MeshId, Box<dyn Error + Send + Sync>> {
+        unreachable!("the test files have no meshes")
+    }
+
+    #[test]
+    fn update_leaves_children_added_at_runtime() {
+        let mut scene = Scene::new();
+        let before = file(vec![desc("room", vec![desc("lamp", vec![])])]);
+        let roots = before.instantiate(&mut scene, None, no_meshes).unwrap();
+        let room = roots[0].id;
+        let gizmo = scene.add(Node::new("gizmo"), Some(room)).unwrap();
+
+        let after = file(vec![desc("room", vec![desc("chair", vec![])])]);
+        let roots = after.update(&mut scene, None, &roots, no_meshes).unwrap();
+        assert_eq!(roots[0].id, room);
+        let names: Vec<_> = scene[room]
+            .children()
+            .iter()
+            .map(|&child| scene[child].name.as_str())
+            .collect();
+        assert_eq!(names, ["gizmo", "chair"]);
+        assert_eq!(scene[gizmo].parent(), Some(room));
+        assert_eq!(roots[0].children.len(), 1);
+    }
+}
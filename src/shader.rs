@@ -0,0 +1,16 @@
+//! Shader loading and introspection
+
+use ash::vk;
+
+pub mod preprocess;
+pub mod reflect;
+#[cfg(feature = "naga")]
+pub mod runtime;
+pub mod variant;
+
+/// Something that can turn shader source into SPIR-V
+pub trait ShaderCompiler {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    fn compile(&self, source: &str, stage: vk::ShaderStageFlags) -> Result<Vec<u32>, Self::Error>;
+}
@@ -0,0 +1,245 @@
+//! GLSL `#include` preprocessing with dependency tracking for hot reload
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PreprocessError {
+    #[error("failed to read {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error("{file}:{line}: malformed #include directive")]
+    Malformed { file: PathBuf, line: usize },
+    #[error("{file}:{line}: could not find include {name}")]
+    NotFound {
+        file: PathBuf,
+        line: usize,
+        name: String,
+    },
+    #[error("include cycle through {0}")]
+    Cycle(PathBuf),
+}
+
+/// Fully expanded shader source
+#[derive(Debug, Clone)]
+pub struct Preprocessed {
+    pub source: String,
+    /// Every file read while expanding, the index is the source string number used in `#line` directives
+    pub files: Vec<PathBuf>,
+}
+
+/// Expands `#include "file"` and `#include <file>` directives
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+    include_dirs: Vec<PathBuf>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory searched for `<file>` includes and for `"file"` includes not next to the includer
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    pub fn preprocess(&self, path: impl AsRef<Path>) -> Result<Preprocessed, PreprocessError> {
+        let path = canonical(path.as_ref())?;
+        let mut state = State::default();
+        self.expand(&path, &mut state)?;
+        Ok(Preprocessed {
+            source: state.output,
+            files: state.files,
+        })
+    }
+
+    fn resolve(&self, includer: &Path, name: &str, quoted: bool) -> Option<PathBuf> {
+        let local = quoted
+            .then(|| includer.parent().map(|dir| dir.join(name)))
+            .flatten();
+        local
+            .into_iter()
+            .chain(self.include_dirs.iter().map(|dir| dir.join(name)))
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn expand(&self, path: &Path, state: &mut State) -> Result<(), PreprocessError> {
+        if state.once.contains(path) {
+            return Ok(());
+        }
+        if state.stack.iter().any(|p| p == path) {
+            return Err(PreprocessError::Cycle(path.to_owned()));
+        }
+
+        let source = fs::read_to_string(path).map_err(|source| PreprocessError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        let index = match state.files.iter().position(|p| p == path) {
+            Some(index) => index,
+            None => {
+                state.files.push(path.to_owned());
+                state.files.len() - 1
+            }
+        };
+        state.stack.push(path.to_owned());
+
+        // The root file keeps its own numbering so `#version` stays the first line
+        if state.stack.len() > 1 {
+            state.output.push_str(&format!("#line 1 {index}\n"));
+        }
+        for (line_i, line) in source.lines().enumerate() {
+            let directive = line.trim_start();
+            if directive.starts_with("#pragma")
+                && directive.split_whitespace().nth(1) == Some("once")
+            {
+                state.once.insert(path.to_owned());
+                state.output.push('\n');
+                continue;
+            }
+            let Some(target) = include_target(directive) else {
+                state.output.push_str(line);
+                state.output.push('\n');
+                continue;
+            };
+
+            let line_no = line_i + 1;
+            let (name, quoted) = target.ok_or_else(|| PreprocessError::Malformed {
+                file: path.to_owned(),
+                line: line_no,
+            })?;
+            let include =
+                self.resolve(path, name, quoted)
+                    .ok_or_else(|| PreprocessError::NotFound {
+                        file: path.to_owned(),
+                        line: line_no,
+                        name: name.to_owned(),
+                    })?;
+            self.expand(&canonical(&include)?, state)?;
+            state
+                .output
+                .push_str(&format!("#line {} {index}\n", line_no + 1));
+        }
+
+        state.stack.pop();
+        Ok(())
+    }
+}
+
+/// The path of an `#include` directive and whether it's quoted, `None` if the path is malformed
+///
+/// Gives `None` for other directives, including ones merely starting with `#include`.
+fn include_target(directive: &str) -> Option<Option<(&str, bool)>> {
+    let rest = directive.strip_prefix("#include")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim();
+    let target = match (rest.chars().next(), rest.chars().last()) {
+        (Some('"'), Some('"')) if rest.len() > 1 => Some((&rest[1..rest.len() - 1], true)),
+        (Some('<'), Some('>')) if rest.len() > 1 => Some((&rest[1..rest.len() - 1], false)),
+        _ => None,
+    };
+    Some(target)
+}
+
+#[derive(Default)]
+struct State {
+    output: String,
+    files: Vec<PathBuf>,
+    stack: Vec<PathBuf>,
+    once: HashSet<PathBuf>,
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, PreprocessError> {
+    path.canonicalize().map_err(|source| PreprocessError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Tracks which root shaders include which files, so a changed header can invalidate its dependents
+#[derive(Debug, Clone, Default)]
+pub struct ShaderDependencies {
+    dependents: HashMap<PathBuf, HashSet<PathBuf>>,
+    includes: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl ShaderDependencies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the files read for a root shader, replacing what was previously recorded for it
+    pub fn record(&mut self, root: &Path, preprocessed: &Preprocessed) {
+        self.remove(root);
+        for file in &preprocessed.files {
+            self.dependents
+                .entry(file.clone())
+                .or_default()
+                .insert(root.to_owned());
+        }
+        self.includes
+            .insert(root.to_owned(), preprocessed.files.clone());
+    }
+
+    pub fn remove(&mut self, root: &Path) {
+        for file in self.includes.remove(root).into_iter().flatten() {
+            if let Some(roots) = self.dependents.get_mut(&file) {
+                roots.remove(root);
+                if roots.is_empty() {
+                    self.dependents.remove(&file);
+                }
+            }
+        }
+    }
+
+    /// Root shaders that must be recompiled because `changed` was modified
+    pub fn dependents(&self, changed: &Path) -> impl Iterator<Item = &Path> {
+        let changed = changed
+            .canonicalize()
+            .unwrap_or_else(|_| changed.to_owned());
+        self.dependents
+            .get(&changed)
+            .into_iter()
+            .flatten()
+            .map(PathBuf::as_path)
+    }
+
+    /// Every file that should be watched for changes
+    pub fn watched_files(&self) -> impl Iterator<Item = &Path> {
+        self.dependents.keys().map(PathBuf::as_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_takes_quoted_and_angle_paths() {
+        assert_eq!(
+            include_target("#include \"a.glsl\""),
+            Some(Some(("a.glsl", true)))
+        );
+        assert_eq!(
+            include_target("#include\t<b.glsl> "),
+            Some(Some(("b.glsl", false)))
+        );
+        assert_eq!(include_target("#include a.glsl"), Some(None));
+        assert_eq!(include_target("#include"), Some(None));
+    }
+
+    #[test]
+    fn directives_starting_with_include_are_left_alone() {
+        assert_eq!(include_target("#include_next <a.glsl>"), None);
+        assert_eq!(include_target("#included_foo"), None);
+        assert_eq!(include_target("#includes \"a.glsl\""), None);
+    }
+}
@@ -0,0 +1,971 @@
+//! SPIR-V reflection for deriving descriptor set layouts, push constant ranges and vertex inputs
+//!
+//! Pipeline constructors pass their hand written layouts to [`debug_validate`] along with the
+//! SPIR-V, so a binding out of step with the shaders fails in debug builds where it's declared
+//! rather than as a validation error or garbage on screen.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use ash::{prelude::VkResult, vk, Device};
+use rspirv::{
+    binary::ParseState,
+    dr::{self, Instruction, Operand},
+    spirv::{Decoration, Dim, ExecutionModel, Op, StorageClass, Word},
+};
+use thiserror::Error;
+
+use crate::leaks;
+
+#[derive(Debug, Error)]
+pub enum ReflectError {
+    /// The parser's state as text, it isn't `Sync`
+    #[error("failed to parse SPIR-V: {0}")]
+    Parse(String),
+    #[error("SPIR-V module has no entry point")]
+    NoEntryPoint,
+    #[error("unsupported execution model {0:?}")]
+    UnsupportedStage(ExecutionModel),
+    #[error("set {set} binding {binding} is declared as both {first:?} and {second:?}")]
+    ConflictingBinding {
+        set: u32,
+        binding: u32,
+        first: vk::DescriptorType,
+        second: vk::DescriptorType,
+    },
+    #[error("set {set} binding {binding} used by the shaders is missing from the layout")]
+    MissingBinding { set: u32, binding: u32 },
+    #[error("set {set} binding {binding} expects {expected:?} but the layout has {found:?}")]
+    DescriptorTypeMismatch {
+        set: u32,
+        binding: u32,
+        expected: vk::DescriptorType,
+        found: vk::DescriptorType,
+    },
+    #[error("set {set} binding {binding} needs {expected} descriptors but the layout has {found}")]
+    DescriptorCountMismatch {
+        set: u32,
+        binding: u32,
+        expected: u32,
+        found: u32,
+    },
+    #[error("set {set} binding {binding} is not visible to {stage:?}")]
+    BindingNotVisible {
+        set: u32,
+        binding: u32,
+        stage: vk::ShaderStageFlags,
+    },
+    #[error("push constants {offset}..{end} used by {stage:?} are not covered by the layout")]
+    PushConstantsNotCovered {
+        offset: u32,
+        end: u32,
+        stage: vk::ShaderStageFlags,
+    },
+    #[error("vertex input location {0} has a type no vertex format can feed")]
+    UnsupportedVertexInput(u32),
+    #[error("vertex input location {0} is not provided by the vertex attributes")]
+    MissingVertexInput(u32),
+    #[error(
+        "vertex input location {location} is read as {expected:?} but the attribute is {found:?}"
+    )]
+    VertexFormatMismatch {
+        location: u32,
+        expected: vk::Format,
+        found: vk::Format,
+    },
+}
+
+impl From<ParseState> for ReflectError {
+    fn from(state: ParseState) -> Self {
+        ReflectError::Parse(state.to_string())
+    }
+}
+
+/// A single descriptor used by a shader, `count` of 0 means a runtime sized array
+#[derive(Debug, Clone)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+}
+
+/// Interface of a single shader entry point, the variables its functions use
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub entry_point: String,
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constants: Option<vk::PushConstantRange>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+#[derive(Default)]
+struct Decorations {
+    set: Option<u32>,
+    binding: Option<u32>,
+    location: Option<u32>,
+    array_stride: Option<u32>,
+    builtin: bool,
+    buffer_block: bool,
+}
+
+#[derive(Default)]
+struct MemberDecorations {
+    offset: Option<u32>,
+    matrix_stride: Option<u32>,
+}
+
+/// Lookup tables built from a parsed module
+struct ModuleInfo<'a> {
+    defs: HashMap<Word, &'a Instruction>,
+    names: HashMap<Word, String>,
+    decorations: HashMap<Word, Decorations>,
+    member_decorations: HashMap<(Word, u32), MemberDecorations>,
+}
+
+fn literal(operand: &Operand) -> Option<u32> {
+    match operand {
+        Operand::LiteralInt32(val) => Some(*val),
+        _ => None,
+    }
+}
+
+fn id_ref(operand: &Operand) -> Option<Word> {
+    match operand {
+        Operand::IdRef(id) => Some(*id),
+        _ => None,
+    }
+}
+
+impl<'a> ModuleInfo<'a> {
+    fn new(module: &'a dr::Module) -> Self {
+        let defs = module
+            .types_global_values
+            .iter()
+            .filter_map(|inst| inst.result_id.map(|id| (id, inst)))
+            .collect();
+
+        let names = module
+            .debug_names
+            .iter()
+            .filter(|inst| inst.class.opcode == Op::Name)
+            .filter_map(|inst| match (&inst.operands[0], &inst.operands[1]) {
+                (Operand::IdRef(id), Operand::LiteralString(name)) => Some((*id, name.clone())),
+                _ => None,
+            })
+            .collect();
+
+        let mut decorations: HashMap<Word, Decorations> = HashMap::new();
+        let mut member_decorations: HashMap<(Word, u32), MemberDecorations> = HashMap::new();
+        for inst in &module.annotations {
+            match (inst.class.opcode, inst.operands.as_slice()) {
+                (Op::Decorate, [Operand::IdRef(id), Operand::Decoration(deco), rest @ ..]) => {
+                    let entry = decorations.entry(*id).or_default();
+                    let value = rest.first().and_then(literal);
+                    match deco {
+                        Decoration::DescriptorSet => entry.set = value,
+                        Decoration::Binding => entry.binding = value,
+                        Decoration::Location => entry.location = value,
+                        Decoration::ArrayStride => entry.array_stride = value,
+                        Decoration::BuiltIn => entry.builtin = true,
+                        Decoration::BufferBlock => entry.buffer_block = true,
+                        _ => (),
+                    }
+                }
+                (
+                    Op::MemberDecorate,
+                    [Operand::IdRef(id), Operand::LiteralInt32(member), Operand::Decoration(deco), rest @ ..],
+                ) => {
+                    let entry = member_decorations.entry((*id, *member)).or_default();
+                    let value = rest.first().and_then(literal);
+                    match deco {
+                        Decoration::Offset => entry.offset = value,
+                        Decoration::MatrixStride => entry.matrix_stride = value,
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        ModuleInfo {
+            defs,
+            names,
+            decorations,
+            member_decorations,
+        }
+    }
+
+    fn def(&self, id: Word) -> Option<&'a Instruction> {
+        self.defs.get(&id).copied()
+    }
+
+    fn constant(&self, id: Word) -> Option<u32> {
+        let inst = self.def(id)?;
+        match inst.class.opcode {
+            Op::Constant | Op::SpecConstant => inst.operands.first().and_then(literal),
+            _ => None,
+        }
+    }
+
+    /// Strips arrays from a type, returning the element type and the descriptor count
+    fn unwrap_array(&self, mut id: Word) -> (Word, u32) {
+        let mut count = 1;
+        while let Some(inst) = self.def(id) {
+            match inst.class.opcode {
+                Op::TypeArray => {
+                    count *= self
+                        .constant(id_ref(&inst.operands[1]).unwrap_or(0))
+                        .unwrap_or(1);
+                    id = id_ref(&inst.operands[0]).unwrap_or(0);
+                }
+                Op::TypeRuntimeArray => {
+                    count = 0;
+                    id = id_ref(&inst.operands[0]).unwrap_or(0);
+                }
+                _ => break,
+            }
+        }
+        (id, count)
+    }
+
+    fn descriptor_type(&self, storage: StorageClass, ty: Word) -> Option<vk::DescriptorType> {
+        let inst = self.def(ty)?;
+        let decorations = self.decorations.get(&ty);
+        Some(match (storage, inst.class.opcode) {
+            (StorageClass::Uniform, Op::TypeStruct)
+                if decorations.is_some_and(|d| d.buffer_block) =>
+            {
+                vk::DescriptorType::STORAGE_BUFFER
+            }
+            (StorageClass::Uniform, Op::TypeStruct) => vk::DescriptorType::UNIFORM_BUFFER,
+            (StorageClass::StorageBuffer, Op::TypeStruct) => vk::DescriptorType::STORAGE_BUFFER,
+            (StorageClass::UniformConstant, Op::TypeSampler) => vk::DescriptorType::SAMPLER,
+            (StorageClass::UniformConstant, Op::TypeSampledImage) => {
+                let image = self.def(id_ref(&inst.operands[0])?)?;
+                match image.operands[1] {
+                    Operand::Dim(Dim::DimBuffer) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    _ => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                }
+            }
+            (StorageClass::UniformConstant, Op::TypeImage) => {
+                let sampled = literal(&inst.operands[5])?;
+                match (&inst.operands[1], sampled) {
+                    (Operand::Dim(Dim::DimSubpassData), _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                    (Operand::Dim(Dim::DimBuffer), 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                    (Operand::Dim(Dim::DimBuffer), _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    /// Size in bytes of a type as laid out in a block
+    fn type_size(&self, ty: Word) -> u32 {
+        let Some(inst) = self.def(ty) else {
+            return 0;
+        };
+        match inst.class.opcode {
+            Op::TypeInt | Op::TypeFloat => literal(&inst.operands[0]).unwrap_or(0) / 8,
+            Op::TypeBool => 4,
+            Op::TypeVector | Op::TypeMatrix => {
+                let component = id_ref(&inst.operands[0]).unwrap_or(0);
+                self.type_size(component) * literal(&inst.operands[1]).unwrap_or(0)
+            }
+            Op::TypeArray => {
+                let element = id_ref(&inst.operands[0]).unwrap_or(0);
+                let len = self
+                    .constant(id_ref(&inst.operands[1]).unwrap_or(0))
+                    .unwrap_or(0);
+                let stride = self
+                    .decorations
+                    .get(&ty)
+                    .and_then(|d| d.array_stride)
+                    .unwrap_or_else(|| self.type_size(element));
+                stride * len
+            }
+            Op::TypeStruct => inst
+                .operands
+                .iter()
+                .enumerate()
+                .map(|(i, member)| {
+                    let member_ty = id_ref(member).unwrap_or(0);
+                    let decorations = self.member_decorations.get(&(ty, i as u32));
+                    let offset = decorations.and_then(|d| d.offset).unwrap_or(0);
+                    let size = match (
+                        self.def(member_ty),
+                        decorations.and_then(|d| d.matrix_stride),
+                    ) {
+                        (Some(matrix), Some(stride)) if matrix.class.opcode == Op::TypeMatrix => {
+                            stride * literal(&matrix.operands[1]).unwrap_or(0)
+                        }
+                        _ => self.type_size(member_ty),
+                    };
+                    offset + size
+                })
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Smallest member offset of a block, used as the start of its push constant range
+    fn block_offset(&self, ty: Word) -> u32 {
+        self.def(ty)
+            .map(|inst| {
+                (0..inst.operands.len() as u32)
+                    .filter_map(|i| self.member_decorations.get(&(ty, i))?.offset)
+                    .min()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Formats of the attributes feeding a vertex input, with the locations each takes
+    ///
+    /// Matrices take one attribute per column and arrays one per element, at consecutive
+    /// locations. `None` for types no vertex format matches.
+    fn vertex_formats(&self, ty: Word) -> Option<Vec<(vk::Format, u32)>> {
+        let inst = self.def(ty)?;
+        let count = match inst.class.opcode {
+            Op::TypeMatrix => literal(&inst.operands[1])?,
+            Op::TypeArray => self.constant(id_ref(&inst.operands[1])?)?,
+            _ => return self.vertex_format(ty).map(|format| vec![format]),
+        };
+        let element = self.vertex_formats(id_ref(&inst.operands[0])?)?;
+        Some(element.repeat(count as usize))
+    }
+
+    /// A scalar or vector's format, 64-bit ones wider than two components take two locations
+    fn vertex_format(&self, ty: Word) -> Option<(vk::Format, u32)> {
+        let inst = self.def(ty)?;
+        let (scalar, components) = match inst.class.opcode {
+            Op::TypeVector => (
+                self.def(id_ref(&inst.operands[0])?)?,
+                literal(&inst.operands[1])?,
+            ),
+            _ => (inst, 1),
+        };
+        let kind = match scalar.class.opcode {
+            Op::TypeFloat => 0,
+            Op::TypeInt if literal(&scalar.operands[1])? == 1 => 1,
+            Op::TypeInt => 2,
+            _ => return None,
+        };
+        let width = literal(&scalar.operands[0])?;
+
+        use vk::Format as F;
+        const FORMATS_16: [[vk::Format; 4]; 3] = [
+            [
+                F::R16_SFLOAT,
+                F::R16G16_SFLOAT,
+                F::R16G16B16_SFLOAT,
+                F::R16G16B16A16_SFLOAT,
+            ],
+            [
+                F::R16_SINT,
+                F::R16G16_SINT,
+                F::R16G16B16_SINT,
+                F::R16G16B16A16_SINT,
+            ],
+            [
+                F::R16_UINT,
+                F::R16G16_UINT,
+                F::R16G16B16_UINT,
+                F::R16G16B16A16_UINT,
+            ],
+        ];
+        const FORMATS_32: [[vk::Format; 4]; 3] = [
+            [
+                F::R32_SFLOAT,
+                F::R32G32_SFLOAT,
+                F::R32G32B32_SFLOAT,
+                F::R32G32B32A32_SFLOAT,
+            ],
+            [
+                F::R32_SINT,
+                F::R32G32_SINT,
+                F::R32G32B32_SINT,
+                F::R32G32B32A32_SINT,
+            ],
+            [
+                F::R32_UINT,
+                F::R32G32_UINT,
+                F::R32G32B32_UINT,
+                F::R32G32B32A32_UINT,
+            ],
+        ];
+        const FORMATS_64: [[vk::Format; 4]; 3] = [
+            [
+                F::R64_SFLOAT,
+                F::R64G64_SFLOAT,
+                F::R64G64B64_SFLOAT,
+                F::R64G64B64A64_SFLOAT,
+            ],
+            [
+                F::R64_SINT,
+                F::R64G64_SINT,
+                F::R64G64B64_SINT,
+                F::R64G64B64A64_SINT,
+            ],
+            [
+                F::R64_UINT,
+                F::R64G64_UINT,
+                F::R64G64B64_UINT,
+                F::R64G64B64A64_UINT,
+            ],
+        ];
+        let table = match width {
+            16 => &FORMATS_16,
+            32 => &FORMATS_32,
+            64 => &FORMATS_64,
+            _ => return None,
+        };
+        let format = *table[kind].get(components.checked_sub(1)? as usize)?;
+        let locations = if width == 64 && components > 2 { 2 } else { 1 };
+        Some((format, locations))
+    }
+}
+
+fn stage_flags(model: ExecutionModel) -> Result<vk::ShaderStageFlags, ReflectError> {
+    Ok(match model {
+        ExecutionModel::Vertex => vk::ShaderStageFlags::VERTEX,
+        ExecutionModel::TessellationControl => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        ExecutionModel::TessellationEvaluation => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        ExecutionModel::Geometry => vk::ShaderStageFlags::GEOMETRY,
+        ExecutionModel::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        ExecutionModel::GLCompute => vk::ShaderStageFlags::COMPUTE,
+        ExecutionModel::TaskNV => vk::ShaderStageFlags::TASK_NV,
+        ExecutionModel::MeshNV => vk::ShaderStageFlags::MESH_NV,
+        other => return Err(ReflectError::UnsupportedStage(other)),
+    })
+}
+
+impl ShaderReflection {
+    pub fn from_bytes(code: &[u8]) -> Result<Self, ReflectError> {
+        Self::from_module(&dr::load_bytes(code)?)
+    }
+
+    pub fn new(code: &[u32]) -> Result<Self, ReflectError> {
+        Self::from_module(&dr::load_words(code)?)
+    }
+
+    fn from_module(module: &dr::Module) -> Result<Self, ReflectError> {
+        let entry = module
+            .entry_points
+            .first()
+            .ok_or(ReflectError::NoEntryPoint)?;
+        let (stage, entry_point) = match (&entry.operands[0], &entry.operands[2]) {
+            (Operand::ExecutionModel(model), Operand::LiteralString(name)) => {
+                (stage_flags(*model)?, name.clone())
+            }
+            _ => return Err(ReflectError::NoEntryPoint),
+        };
+
+        let info = ModuleInfo::new(module);
+        // Declared but never used variables don't need anything from the pipeline
+        let used: HashSet<Word> = module
+            .functions
+            .iter()
+            .flat_map(|function| &function.blocks)
+            .flat_map(|block| &block.instructions)
+            .flat_map(|inst| inst.operands.iter().filter_map(id_ref))
+            .collect();
+        let mut bindings = Vec::new();
+        let mut push_constants = None;
+        let mut vertex_inputs = Vec::new();
+
+        let variables = module
+            .types_global_values
+            .iter()
+            .filter(|inst| inst.class.opcode == Op::Variable);
+        for var in variables {
+            let (Some(id), Some(Operand::StorageClass(storage))) =
+                (var.result_id, var.operands.first())
+            else {
+                continue;
+            };
+            if !used.contains(&id) {
+                continue;
+            }
+            let Some(pointee) = var
+                .result_type
+                .and_then(|ptr| info.def(ptr))
+                .and_then(|ptr| ptr.operands.get(1))
+                .and_then(id_ref)
+            else {
+                continue;
+            };
+            let decorations = info.decorations.get(&id);
+
+            match storage {
+                StorageClass::Uniform
+                | StorageClass::UniformConstant
+                | StorageClass::StorageBuffer => {
+                    let (Some(set), Some(binding)) = (
+                        decorations.and_then(|d| d.set),
+                        decorations.and_then(|d| d.binding),
+                    ) else {
+                        continue;
+                    };
+                    let (element, count) = info.unwrap_array(pointee);
+                    if let Some(descriptor_type) = info.descriptor_type(*storage, element) {
+                        bindings.push(DescriptorBinding {
+                            set,
+                            binding,
+                            descriptor_type,
+                            count,
+                            name: info.names.get(&id).cloned(),
+                        });
+                    }
+                }
+                StorageClass::PushConstant => {
+                    let offset = info.block_offset(pointee);
+                    push_constants = Some(vk::PushConstantRange {
+                        stage_flags: stage,
+                        offset,
+                        size: info.type_size(pointee) - offset,
+                    });
+                }
+                StorageClass::Input if stage == vk::ShaderStageFlags::VERTEX => {
+                    if decorations.is_some_and(|d| d.builtin) {
+                        continue;
+                    }
+                    let Some(location) = decorations.and_then(|d| d.location) else {
+                        continue;
+                    };
+                    let formats = info
+                        .vertex_formats(pointee)
+                        .ok_or(ReflectError::UnsupportedVertexInput(location))?;
+                    let mut next = location;
+                    for (format, locations) in formats {
+                        vertex_inputs.push(VertexInput {
+                            location: next,
+                            format,
+                        });
+                        next += locations;
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        bindings.sort_by_key(|b| (b.set, b.binding));
+        vertex_inputs.sort_by_key(|input| input.location);
+
+        Ok(ShaderReflection {
+            entry_point,
+            stage,
+            bindings,
+            push_constants,
+            vertex_inputs,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumericClass {
+    Float,
+    Sint,
+    Uint,
+}
+
+/// How a format is read in the shader, normalized and scaled formats read as floats
+fn numeric_class(format: vk::Format) -> NumericClass {
+    use vk::Format as F;
+    match format {
+        F::R8_SINT
+        | F::R8G8_SINT
+        | F::R8G8B8_SINT
+        | F::B8G8R8_SINT
+        | F::R8G8B8A8_SINT
+        | F::B8G8R8A8_SINT
+        | F::A8B8G8R8_SINT_PACK32
+        | F::A2R10G10B10_SINT_PACK32
+        | F::A2B10G10R10_SINT_PACK32
+        | F::R16_SINT
+        | F::R16G16_SINT
+        | F::R16G16B16_SINT
+        | F::R16G16B16A16_SINT
+        | F::R32_SINT
+        | F::R32G32_SINT
+        | F::R32G32B32_SINT
+        | F::R32G32B32A32_SINT
+        | F::R64_SINT
+        | F::R64G64_SINT
+        | F::R64G64B64_SINT
+        | F::R64G64B64A64_SINT => NumericClass::Sint,
+        F::R8_UINT
+        | F::R8G8_UINT
+        | F::R8G8B8_UINT
+        | F::B8G8R8_UINT
+        | F::R8G8B8A8_UINT
+        | F::B8G8R8A8_UINT
+        | F::A8B8G8R8_UINT_PACK32
+        | F::A2R10G10B10_UINT_PACK32
+        | F::A2B10G10R10_UINT_PACK32
+        | F::R16_UINT
+        | F::R16G16_UINT
+        | F::R16G16B16_UINT
+        | F::R16G16B16A16_UINT
+        | F::R32_UINT
+        | F::R32G32_UINT
+        | F::R32G32B32_UINT
+        | F::R32G32B32A32_UINT
+        | F::R64_UINT
+        | F::R64G64_UINT
+        | F::R64G64B64_UINT
+        | F::R64G64B64A64_UINT => NumericClass::Uint,
+        _ => NumericClass::Float,
+    }
+}
+
+/// Combined interface of every stage in a pipeline
+///
+/// Runtime sized arrays have a `descriptor_count` of 0 in `sets` and are listed in
+/// `variable_count`, [`PipelineReflection::create_set_layouts`] gives them a count.
+#[derive(Debug, Clone, Default)]
+pub struct PipelineReflection {
+    pub sets: BTreeMap<u32, Vec<vk::DescriptorSetLayoutBinding>>,
+    /// The binding of each set that is a runtime sized array, only the last may be
+    pub variable_count: BTreeMap<u32, u32>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+impl PipelineReflection {
+    pub fn new(stages: &[ShaderReflection]) -> Result<Self, ReflectError> {
+        let mut sets: BTreeMap<u32, BTreeMap<u32, vk::DescriptorSetLayoutBinding>> =
+            BTreeMap::new();
+        let mut push_constant_ranges: Vec<vk::PushConstantRange> = Vec::new();
+        let mut vertex_inputs = Vec::new();
+        let mut variable_count = BTreeMap::new();
+
+        for stage in stages {
+            for binding in &stage.bindings {
+                if binding.count == 0 {
+                    variable_count.insert(binding.set, binding.binding);
+                }
+                let set = sets.entry(binding.set).or_default();
+                match set.get_mut(&binding.binding) {
+                    Some(existing) if existing.descriptor_type != binding.descriptor_type => {
+                        return Err(ReflectError::ConflictingBinding {
+                            set: binding.set,
+                            binding: binding.binding,
+                            first: existing.descriptor_type,
+                            second: binding.descriptor_type,
+                        })
+                    }
+                    Some(existing) => {
+                        existing.stage_flags |= stage.stage;
+                        existing.descriptor_count = if binding.count == 0 {
+                            0
+                        } else {
+                            existing.descriptor_count.max(binding.count)
+                        };
+                    }
+                    None => {
+                        set.insert(
+                            binding.binding,
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(binding.binding)
+                                .descriptor_type(binding.descriptor_type)
+                                .descriptor_count(binding.count)
+                                .stage_flags(stage.stage)
+                                .build(),
+                        );
+                    }
+                }
+            }
+
+            if let Some(range) = stage.push_constants {
+                match push_constant_ranges
+                    .iter_mut()
+                    .find(|r| r.offset == range.offset && r.size == range.size)
+                {
+                    Some(existing) => existing.stage_flags |= range.stage_flags,
+                    None => push_constant_ranges.push(range),
+                }
+            }
+
+            if stage.stage == vk::ShaderStageFlags::VERTEX {
+                vertex_inputs.clone_from(&stage.vertex_inputs);
+            }
+        }
+
+        Ok(PipelineReflection {
+            sets: sets
+                .into_iter()
+                .map(|(set, bindings)| (set, bindings.into_values().collect()))
+                .collect(),
+            variable_count,
+            push_constant_ranges,
+            vertex_inputs,
+        })
+    }
+
+    /// Creates one layout per set index up to the highest used set, leaving gaps empty
+    ///
+    /// Runtime sized arrays become variable count bindings of up to `max_variable_count`,
+    /// partially bound, which needs the `descriptorBindingVariableDescriptorCount`,
+    /// `descriptorBindingPartiallyBound` and `runtimeDescriptorArray` features. Sets using one
+    /// are allocated with `VkDescriptorSetVariableDescriptorCountAllocateInfo`. The layouts are
+    /// [`leaks::track`]ed, [`leaks::untrack`] them when destroying them.
+    pub fn create_set_layouts(
+        &self,
+        device: &Device,
+        max_variable_count: u32,
+    ) -> VkResult<Vec<vk::DescriptorSetLayout>> {
+        let count = self.sets.keys().next_back().map_or(0, |last| last + 1);
+        let mut layouts = Vec::with_capacity(count as usize);
+        for set in 0..count {
+            let mut bindings = self.sets.get(&set).cloned().unwrap_or_default();
+            let variable = self.variable_count.get(&set);
+            let flags: Vec<_> = bindings
+                .iter_mut()
+                .map(|binding| {
+                    if variable != Some(&binding.binding) {
+                        return vk::DescriptorBindingFlags::empty();
+                    }
+                    binding.descriptor_count = max_variable_count;
+                    vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
+                        | vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                })
+                .collect();
+            let mut flags_info =
+                vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder().binding_flags(&flags);
+            let mut info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+            if variable.is_some() {
+                info = info.push_next(&mut flags_info);
+            }
+            match unsafe { device.create_descriptor_set_layout(&info, None) } {
+                Ok(layout) => {
+                    leaks::track(layout, "reflected descriptor set layout");
+                    layouts.push(layout);
+                }
+                Err(err) => {
+                    for layout in layouts {
+                        leaks::untrack(layout);
+                        unsafe { device.destroy_descriptor_set_layout(layout, None) };
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(layouts)
+    }
+
+    /// Checks that a hand written set layout covers everything the shaders use
+    pub fn validate_set_layout(
+        &self,
+        set: u32,
+        layout: &[vk::DescriptorSetLayoutBinding],
+    ) -> Result<(), ReflectError> {
+        for expected in self.sets.get(&set).into_iter().flatten() {
+            let binding = expected.binding;
+            let found = layout
+                .iter()
+                .find(|b| b.binding == binding)
+                .ok_or(ReflectError::MissingBinding { set, binding })?;
+            if found.descriptor_type != expected.descriptor_type {
+                return Err(ReflectError::DescriptorTypeMismatch {
+                    set,
+                    binding,
+                    expected: expected.descriptor_type,
+                    found: found.descriptor_type,
+                });
+            }
+            if found.descriptor_count < expected.descriptor_count.max(1) {
+                return Err(ReflectError::DescriptorCountMismatch {
+                    set,
+                    binding,
+                    expected: expected.descriptor_count,
+                    found: found.descriptor_count,
+                });
+            }
+            if !found.stage_flags.contains(expected.stage_flags) {
+                return Err(ReflectError::BindingNotVisible {
+                    set,
+                    binding,
+                    stage: expected.stage_flags & !found.stage_flags,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_push_constants(
+        &self,
+        ranges: &[vk::PushConstantRange],
+    ) -> Result<(), ReflectError> {
+        for used in &self.push_constant_ranges {
+            let end = used.offset + used.size;
+            let covered = ranges.iter().any(|r| {
+                r.stage_flags.contains(used.stage_flags)
+                    && r.offset <= used.offset
+                    && r.offset + r.size >= end
+            });
+            if !covered {
+                return Err(ReflectError::PushConstantsNotCovered {
+                    offset: used.offset,
+                    end,
+                    stage: used.stage_flags,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate_vertex_input(
+        &self,
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Result<(), ReflectError> {
+        for input in &self.vertex_inputs {
+            let attribute = attributes
+                .iter()
+                .find(|a| a.location == input.location)
+                .ok_or(ReflectError::MissingVertexInput(input.location))?;
+            if numeric_class(attribute.format) != numeric_class(input.format) {
+                return Err(ReflectError::VertexFormatMismatch {
+                    location: input.location,
+                    expected: input.format,
+                    found: attribute.format,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates every Rust side binding used to build a pipeline, indexed by set number
+    pub fn validate(
+        &self,
+        set_layouts: &[&[vk::DescriptorSetLayoutBinding]],
+        push_constant_ranges: &[vk::PushConstantRange],
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Result<(), ReflectError> {
+        for &set in self.sets.keys() {
+            self.validate_set_layout(
+                set,
+                set_layouts.get(set as usize).copied().unwrap_or_default(),
+            )?;
+        }
+        self.validate_push_constants(push_constant_ranges)?;
+        self.validate_vertex_input(attributes)
+    }
+}
+
+/// Checks the layouts a pipeline is built with against the SPIR-V of its `stages`
+///
+/// Meant for pipeline constructors, which fail with the mismatch in debug builds, since it's a
+/// bug in the hand written layouts. Always succeeds in release builds, where it checks nothing.
+pub fn debug_validate(
+    stages: &[&[u32]],
+    set_layouts: &[&[vk::DescriptorSetLayoutBinding]],
+    push_constant_ranges: &[vk::PushConstantRange],
+    attributes: &[vk::VertexInputAttributeDescription],
+) -> Result<(), ReflectError> {
+    if !cfg!(debug_assertions) {
+        return Ok(());
+    }
+    let stages = stages
+        .iter()
+        .map(|code| ShaderReflection::new(code))
+        .collect::<Result<Vec<_>, _>>()?;
+    PipelineReflection::new(&stages)?.validate(set_layouts, push_constant_ranges, attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use rspirv::spirv::{AddressingModel, Capability, FunctionControl, MemoryModel};
+
+    use super::*;
+
+    /// A vertex shader loading one input of the type `ty` builds, at `location`
+    fn vertex_shader(location: u32, ty: impl FnOnce(&mut dr::Builder) -> Word) -> dr::Module {
+        let mut b = dr::Builder::new();
+        b.capability(Capability::Shader);
+        b.memory_model(AddressingModel::Logical, MemoryModel::GLSL450);
+        let void = b.type_void();
+        let ty = ty(&mut b);
+        let pointer = b.type_pointer(None, StorageClass::Input, ty);
+        let input = b.variable(pointer, None, StorageClass::Input, None);
+        b.decorate(
+            input,
+            Decoration::Location,
+            [Operand::LiteralInt32(location)],
+        );
+        let function_ty = b.type_function(void, []);
+        let main = b
+            .begin_function(void, None, FunctionControl::NONE, function_ty)
+            .unwrap();
+        b.begin_block(None).unwrap();
+        b.load(ty, None, input, None, []).unwrap();
+        b.ret().unwrap();
+        b.end_function().unwrap();
+        b.entry_point(ExecutionModel::Vertex, main, "main", [input]);
+        b.module()
+    }
+
+    fn inputs(module: &dr::Module) -> Vec<(u32, vk::Format)> {
+        ShaderReflection::from_module(module)
+            .unwrap()
+            .vertex_inputs
+            .iter()
+            .map(|input| (input.location, input.format))
+            .collect()
+    }
+
+    #[test]
+    fn matrix_inputs_take_a_location_per_column() {
+        let module = vertex_shader(3, |b| {
+            let float = b.type_float(32);
+            let column = b.type_vector(float, 4);
+            b.type_matrix(column, 4)
+        });
+        let vec4 = vk::Format::R32G32B32A32_SFLOAT;
+        assert_eq!(
+            inputs(&module),
+            [(3, vec4), (4, vec4), (5, vec4), (6, vec4)]
+        );
+    }
+
+    #[test]
+    fn half_and_short_inputs_map_to_16_bit_formats() {
+        let half = vertex_shader(0, |b| {
+            let half = b.type_float(16);
+            b.type_vector(half, 2)
+        });
+        assert_eq!(inputs(&half), [(0, vk::Format::R16G16_SFLOAT)]);
+        let short = vertex_shader(1, |b| b.type_int(16, 1));
+        assert_eq!(inputs(&short), [(1, vk::Format::R16_SINT)]);
+    }
+
+    #[test]
+    fn double_vectors_past_two_components_take_two_locations() {
+        let module = vertex_shader(0, |b| {
+            let double = b.type_float(64);
+            let column = b.type_vector(double, 3);
+            b.type_matrix(column, 2)
+        });
+        let dvec3 = vk::Format::R64G64B64_SFLOAT;
+        assert_eq!(inputs(&module), [(0, dvec3), (2, dvec3)]);
+    }
+
+    #[test]
+    fn inputs_without_a_format_are_an_error() {
+        let module = vertex_shader(5, |b| b.type_bool());
+        assert!(matches!(
+            ShaderReflection::from_module(&module),
+            Err(ReflectError::UnsupportedVertexInput(5))
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Runtime GLSL/WGSL to SPIR-V compilation through naga, without a shaderc dependency
+
+use std::path::Path;
+
+use ash::vk;
+use naga::{
+    back::spv,
+    front::{glsl, wgsl},
+    valid::{Capabilities, ValidationFlags, Validator},
+    Module, ShaderStage,
+};
+use thiserror::Error;
+
+use super::ShaderCompiler;
+
+#[derive(Debug, Error)]
+pub enum NagaError {
+    #[error("unsupported shader stage {0:?}")]
+    UnsupportedStage(vk::ShaderStageFlags),
+    #[error("{0}")]
+    Parse(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("no {0:?} entry point")]
+    NoEntryPoint(vk::ShaderStageFlags),
+    #[error(transparent)]
+    Spirv(#[from] spv::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceLanguage {
+    Glsl,
+    Wgsl,
+}
+
+impl SourceLanguage {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "wgsl" => Some(SourceLanguage::Wgsl),
+            "glsl" | "vert" | "frag" | "comp" => Some(SourceLanguage::Glsl),
+            _ => None,
+        }
+    }
+}
+
+/// Guesses the stage from the conventional `.vert`/`.frag`/`.comp` extensions
+pub fn stage_from_path(path: &Path) -> Option<vk::ShaderStageFlags> {
+    match path.extension()?.to_str()? {
+        "vert" => Some(vk::ShaderStageFlags::VERTEX),
+        "frag" => Some(vk::ShaderStageFlags::FRAGMENT),
+        "comp" => Some(vk::ShaderStageFlags::COMPUTE),
+        _ => None,
+    }
+}
+
+fn naga_stage(stage: vk::ShaderStageFlags) -> Result<ShaderStage, NagaError> {
+    Ok(match stage {
+        vk::ShaderStageFlags::VERTEX => ShaderStage::Vertex,
+        vk::ShaderStageFlags::FRAGMENT => ShaderStage::Fragment,
+        vk::ShaderStageFlags::COMPUTE => ShaderStage::Compute,
+        other => return Err(NagaError::UnsupportedStage(other)),
+    })
+}
+
+pub struct NagaCompiler {
+    language: SourceLanguage,
+    entry_point: Option<String>,
+}
+
+impl NagaCompiler {
+    pub fn new(language: SourceLanguage) -> Self {
+        NagaCompiler {
+            language,
+            entry_point: None,
+        }
+    }
+
+    /// Selects a named entry point, otherwise the first one matching the stage is used
+    pub fn with_entry_point(mut self, name: impl Into<String>) -> Self {
+        self.entry_point = Some(name.into());
+        self
+    }
+
+    fn parse(&self, source: &str, stage: ShaderStage) -> Result<Module, NagaError> {
+        match self.language {
+            SourceLanguage::Glsl => glsl::Frontend::default()
+                .parse(&glsl::Options::from(stage), source)
+                .map_err(|errors| {
+                    let messages: Vec<_> = errors
+                        .iter()
+                        .map(|err| {
+                            let loc = err.meta.location(source);
+                            format!("{}:{}: {err}", loc.line_number, loc.line_position)
+                        })
+                        .collect();
+                    NagaError::Parse(messages.join("\n"))
+                }),
+            SourceLanguage::Wgsl => {
+                wgsl::parse_str(source).map_err(|err| NagaError::Parse(err.emit_to_string(source)))
+            }
+        }
+    }
+}
+
+impl ShaderCompiler for NagaCompiler {
+    type Error = NagaError;
+
+    fn compile(&self, source: &str, stage: vk::ShaderStageFlags) -> Result<Vec<u32>, NagaError> {
+        let naga_stage = naga_stage(stage)?;
+        let module = self.parse(source, naga_stage)?;
+        let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+            .validate(&module)
+            .map_err(|err| NagaError::Validation(err.emit_to_string(source)))?;
+
+        let entry_point = module
+            .entry_points
+            .iter()
+            .filter(|ep| ep.stage == naga_stage)
+            .find(|ep| {
+                self.entry_point
+                    .as_ref()
+                    .is_none_or(|name| &ep.name == name)
+            })
+            .ok_or(NagaError::NoEntryPoint(stage))?;
+
+        let mut options = spv::Options::default();
+        if self.language == SourceLanguage::Glsl {
+            // GLSL written for Vulkan is already in Vulkan's coordinate space
+            options
+                .flags
+                .remove(spv::WriterFlags::ADJUST_COORDINATE_SPACE);
+        }
+        let pipeline_options = spv::PipelineOptions {
+            shader_stage: naga_stage,
+            entry_point: entry_point.name.clone(),
+        };
+        Ok(spv::write_vec(
+            &module,
+            &info,
+            &options,
+            Some(&pipeline_options),
+        )?)
+    }
+}
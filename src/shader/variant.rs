@@ -0,0 +1,205 @@
+//! Keyword based shader permutations, compiled lazily and cached per requested variant
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    sync::Arc,
+};
+
+use ash::vk;
+use thiserror::Error;
+
+use super::ShaderCompiler;
+
+#[derive(Debug, Error)]
+pub enum VariantError {
+    #[error("unknown shader keyword {0}")]
+    UnknownKeyword(String),
+    #[error("keyword {keyword} does not accept value {value}")]
+    InvalidValue { keyword: String, value: String },
+    #[error("failed to compile variant {key}: {source}")]
+    Compile {
+        key: VariantKey,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+/// A keyword a shader can be permuted on
+#[derive(Debug, Clone)]
+pub enum Keyword {
+    /// Defined or not, e.g. `USE_NORMAL_MAP`
+    Toggle(String),
+    /// Defined to one of a fixed set of values, e.g. `SHADOWS=PCF`
+    Choice(String, Vec<String>),
+}
+
+impl Keyword {
+    pub fn toggle(name: impl Into<String>) -> Self {
+        Keyword::Toggle(name.into())
+    }
+
+    pub fn choice<S: Into<String>>(
+        name: impl Into<String>,
+        values: impl IntoIterator<Item = S>,
+    ) -> Self {
+        Keyword::Choice(name.into(), values.into_iter().map(Into::into).collect())
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Keyword::Toggle(name) | Keyword::Choice(name, _) => name,
+        }
+    }
+}
+
+/// Set of defines selecting a variant, ordered so equal sets hash the same
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct VariantKey(BTreeMap<String, String>);
+
+impl VariantKey {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enable(mut self, keyword: impl Into<String>) -> Self {
+        self.0.insert(keyword.into(), String::new());
+        self
+    }
+
+    pub fn set(mut self, keyword: impl Into<String>, value: impl Into<String>) -> Self {
+        self.0.insert(keyword.into(), value.into());
+        self
+    }
+
+    pub fn defines(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Inserts the defines after the `#version` line, or at the top if there is none
+    pub fn apply(&self, source: &str) -> String {
+        let defines: String = self
+            .defines()
+            .map(|(k, v)| format!("#define {k} {v}\n"))
+            .collect();
+        let split = source
+            .lines()
+            .position(|line| line.trim_start().starts_with("#version"))
+            .map(|version| {
+                source
+                    .split_inclusive('\n')
+                    .take(version + 1)
+                    .map(str::len)
+                    .sum()
+            })
+            .unwrap_or(0);
+        let (head, tail) = source.split_at(split);
+        let line = head.lines().count();
+        // Restore line numbering so compiler errors still point at the original source
+        format!("{head}{defines}#line {}\n{tail}", line + 1)
+    }
+}
+
+impl std::fmt::Display for VariantKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        for (k, v) in self.defines() {
+            if !first {
+                f.write_str(" ")?;
+            }
+            first = false;
+            if v.is_empty() {
+                f.write_str(k)?;
+            } else {
+                write!(f, "{k}={v}")?;
+            }
+        }
+        if first {
+            f.write_str("<default>")?;
+        }
+        Ok(())
+    }
+}
+
+/// One shader source and every variant of it requested so far
+pub struct ShaderVariants {
+    source: String,
+    stage: vk::ShaderStageFlags,
+    keywords: Vec<Keyword>,
+    cache: HashMap<VariantKey, Arc<[u32]>>,
+}
+
+impl ShaderVariants {
+    pub fn new(
+        source: impl Into<String>,
+        stage: vk::ShaderStageFlags,
+        keywords: Vec<Keyword>,
+    ) -> Self {
+        ShaderVariants {
+            source: source.into(),
+            stage,
+            keywords,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn stage(&self) -> vk::ShaderStageFlags {
+        self.stage
+    }
+
+    pub fn keywords(&self) -> &[Keyword] {
+        &self.keywords
+    }
+
+    /// Replaces the source, dropping every cached variant
+    pub fn set_source(&mut self, source: impl Into<String>) {
+        self.source = source.into();
+        self.cache.clear();
+    }
+
+    pub fn validate(&self, key: &VariantKey) -> Result<(), VariantError> {
+        for (name, value) in key.defines() {
+            let keyword = self
+                .keywords
+                .iter()
+                .find(|k| k.name() == name)
+                .ok_or_else(|| VariantError::UnknownKeyword(name.to_owned()))?;
+            let valid = match keyword {
+                Keyword::Toggle(_) => value.is_empty(),
+                Keyword::Choice(_, values) => values.iter().any(|v| v == value),
+            };
+            if !valid {
+                return Err(VariantError::InvalidValue {
+                    keyword: name.to_owned(),
+                    value: value.to_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the SPIR-V for a variant, compiling it on first request
+    pub fn get<C: ShaderCompiler>(
+        &mut self,
+        key: &VariantKey,
+        compiler: &C,
+    ) -> Result<Arc<[u32]>, VariantError> {
+        if let Some(code) = self.cache.get(key) {
+            return Ok(code.clone());
+        }
+        self.validate(key)?;
+
+        let code: Arc<[u32]> = compiler
+            .compile(&key.apply(&self.source), self.stage)
+            .map_err(|err| VariantError::Compile {
+                key: key.clone(),
+                source: Box::new(err),
+            })?
+            .into();
+        self.cache.insert(key.clone(), code.clone());
+        Ok(code)
+    }
+
+    pub fn cached(&self) -> impl Iterator<Item = &VariantKey> {
+        self.cache.keys()
+    }
+}
@@ -0,0 +1,914 @@
+//! Physically based sky from precomputed scattering lookup tables, after Hillaire's 2020 model
+//!
+//! Three compute passes fill the tables. Transmittance to the top of the atmosphere and the
+//! multiple scattering approximation depend only on the [`Atmosphere`], so they're rebuilt when it
+//! changes. The sky-view table holds the radiance in every direction around the camera and is
+//! redrawn each frame for the current sun. A fullscreen pass then reads it behind everything else,
+//! standing in for a cubemap skybox.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat3, Mat4, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    scene::{LightKind, Scene},
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        hdr::HDR_FORMAT,
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        transition, Texture,
+    },
+};
+
+/// Parameters, medium and lookups shared by the table passes
+const SKY_COMMON_GLSL: &str = r#"
+const float PI = 3.14159265359;
+
+layout(push_constant) uniform Push {
+    // xyz: direction towards the sun, w: sun illuminance
+    vec4 sun;
+    // rgb: scattering per km, w: scale height in km
+    vec4 rayleigh;
+    // x: scattering per km, y: absorption per km, z: phase anisotropy, w: scale height in km
+    vec4 mie;
+    // rgb: absorption per km at the peak of the layer, w: ground albedo
+    vec4 ozone;
+    // x: ground radius, y: atmosphere radius, z: camera altitude, all in km
+    vec4 radii;
+} push;
+
+layout(set = 0, binding = 0) uniform texture2D transmittance_lut;
+layout(set = 0, binding = 1) uniform texture2D multi_scattering_lut;
+layout(set = 0, binding = 2) uniform sampler lut_sampler;
+layout(set = 0, binding = 3, rgba16f) uniform image2D target;
+
+struct Medium {
+    vec3 rayleigh;
+    float mie;
+    vec3 scattering;
+    vec3 extinction;
+};
+
+Medium medium(float altitude) {
+    float rayleigh_density = exp(-altitude / push.rayleigh.w);
+    float mie_density = exp(-altitude / push.mie.w);
+    // Tent around 25 km
+    float ozone_density = max(0.0, 1.0 - abs(altitude - 25.0) / 15.0);
+    Medium m;
+    m.rayleigh = push.rayleigh.rgb * rayleigh_density;
+    m.mie = push.mie.x * mie_density;
+    m.scattering = m.rayleigh + vec3(m.mie);
+    m.extinction = m.scattering + vec3(push.mie.y * mie_density) + push.ozone.rgb * ozone_density;
+    return m;
+}
+
+// Distance along the ray to a sphere around the planet centre, negative when it misses
+float ray_sphere(vec3 origin, vec3 dir, float radius) {
+    float b = dot(origin, dir);
+    float c = dot(origin, origin) - radius * radius;
+    float discriminant = b * b - c;
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+    float s = sqrt(discriminant);
+    return -b - s > 0.0 ? -b - s : -b + s;
+}
+
+vec2 lut_uv(vec3 position, vec3 dir) {
+    float r = length(position);
+    float mu = dot(position / r, dir);
+    return vec2(mu * 0.5 + 0.5, (r - push.radii.x) / (push.radii.y - push.radii.x));
+}
+
+vec3 transmittance(vec3 position, vec3 dir) {
+    return textureLod(sampler2D(transmittance_lut, lut_sampler), lut_uv(position, dir), 0.0).rgb;
+}
+
+vec3 multi_scattering(vec3 position, vec3 dir) {
+    return textureLod(sampler2D(multi_scattering_lut, lut_sampler), lut_uv(position, dir), 0.0).rgb;
+}
+
+// Position at the table's altitude and direction at its cosine from the zenith
+vec3 zenith_dir(float mu) {
+    return vec3(sqrt(max(1.0 - mu * mu, 0.0)), mu, 0.0);
+}
+"#;
+
+/// Transmittance from any altitude to the top of the atmosphere, by cosine from the zenith
+pub const SKY_TRANSMITTANCE_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(target);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    vec3 origin = vec3(0.0, mix(push.radii.x, push.radii.y, uv.y), 0.0);
+    vec3 dir = zenith_dir(uv.x * 2.0 - 1.0);
+    if (ray_sphere(origin, dir, push.radii.x) > 0.0) {
+        imageStore(target, id, vec4(0.0, 0.0, 0.0, 1.0));
+        return;
+    }
+    float distance = ray_sphere(origin, dir, push.radii.y);
+    float dt = distance / 40.0;
+    vec3 depth = vec3(0.0);
+    for (int i = 0; i < 40; i++) {
+        vec3 position = origin + dir * (float(i) + 0.5) * dt;
+        depth += medium(length(position) - push.radii.x).extinction * dt;
+    }
+    imageStore(target, id, vec4(exp(-depth), 1.0));
+}
+"#;
+
+/// Light scattered any number of times, as a geometric series of the second order
+pub const SKY_MULTI_SCATTERING_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(target);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    vec3 origin = vec3(0.0, mix(push.radii.x, push.radii.y, uv.y), 0.0);
+    vec3 sun = zenith_dir(uv.x * 2.0 - 1.0);
+
+    vec3 second_order = vec3(0.0);
+    vec3 transfer = vec3(0.0);
+    for (int j = 0; j < 8; j++) {
+        for (int k = 0; k < 8; k++) {
+            float cos_theta = 1.0 - 2.0 * (float(j) + 0.5) / 8.0;
+            float phi = 2.0 * PI * (float(k) + 0.5) / 8.0;
+            float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+            vec3 dir = vec3(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi));
+
+            float ground = ray_sphere(origin, dir, push.radii.x);
+            float distance = ground > 0.0 ? ground : ray_sphere(origin, dir, push.radii.y);
+            float dt = distance / 20.0;
+            vec3 throughput = vec3(1.0);
+            for (int i = 0; i < 20; i++) {
+                vec3 position = origin + dir * (float(i) + 0.5) * dt;
+                Medium m = medium(length(position) - push.radii.x);
+                vec3 extinction = max(m.extinction, vec3(1e-6));
+                vec3 step_transmittance = exp(-m.extinction * dt);
+                vec3 integral = (1.0 - step_transmittance) / extinction;
+                vec3 scattered = m.scattering * transmittance(position, sun) / (4.0 * PI);
+                second_order += throughput * scattered * integral;
+                transfer += throughput * m.scattering * integral;
+                throughput *= step_transmittance;
+            }
+            if (ground > 0.0) {
+                vec3 position = origin + dir * ground;
+                float lit = max(dot(normalize(position), sun), 0.0);
+                second_order += throughput * transmittance(position, sun) * lit * push.ozone.w / PI;
+            }
+        }
+    }
+    // Uniform over the sphere with an isotropic phase, the 4 pi cancels out
+    second_order /= 64.0;
+    transfer /= 64.0 * 4.0 * PI;
+    imageStore(target, id, vec4(second_order / (1.0 - transfer), 1.0));
+}
+"#;
+
+/// Radiance around the camera, by azimuth from the sun and elevation packed towards the horizon
+pub const SKY_VIEW_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(target);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    float azimuth = uv.x * PI;
+    float v = uv.y * 2.0 - 1.0;
+    float elevation = sign(v) * v * v * PI * 0.5;
+    vec3 dir = vec3(cos(elevation) * cos(azimuth), sin(elevation), cos(elevation) * sin(azimuth));
+    // Sun at zero azimuth, the sky is symmetric about its vertical plane
+    vec3 sun = zenith_dir(push.sun.y);
+    vec3 origin = vec3(0.0, push.radii.x + max(push.radii.z, 0.001), 0.0);
+
+    float ground = ray_sphere(origin, dir, push.radii.x);
+    float distance = ground > 0.0 ? ground : ray_sphere(origin, dir, push.radii.y);
+    float cos_angle = dot(dir, sun);
+    float rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + cos_angle * cos_angle);
+    float g = push.mie.z;
+    float mie_phase = (1.0 - g * g) / (4.0 * PI * pow(1.0 + g * g - 2.0 * g * cos_angle, 1.5));
+
+    float dt = distance / 32.0;
+    vec3 radiance = vec3(0.0);
+    vec3 throughput = vec3(1.0);
+    for (int i = 0; i < 32; i++) {
+        vec3 position = origin + dir * (float(i) + 0.5) * dt;
+        Medium m = medium(length(position) - push.radii.x);
+        vec3 extinction = max(m.extinction, vec3(1e-6));
+        vec3 step_transmittance = exp(-m.extinction * dt);
+        vec3 single = (m.rayleigh * rayleigh_phase + vec3(m.mie * mie_phase))
+            * transmittance(position, sun);
+        vec3 scattered = single + multi_scattering(position, sun) * m.scattering;
+        radiance += throughput * scattered * (1.0 - step_transmittance) / extinction;
+        throughput *= step_transmittance;
+    }
+    imageStore(target, id, vec4(radiance * push.sun.w, 1.0));
+}
+"#;
+
+/// Fullscreen triangle at the far plane
+pub const SKY_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_ndc;
+
+void main() {
+    vec2 ndc = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2)) * 2.0 - 1.0;
+    gl_Position = vec4(ndc, 1.0, 1.0);
+    out_ndc = ndc;
+}
+"#;
+
+/// Looks the sky-view table up along each pixel's view ray and adds the sun's disk
+pub const SKY_FRAG_GLSL: &str = r#"#version 450
+const float PI = 3.14159265359;
+
+layout(push_constant) uniform Push {
+    // Inverse of the projection times the view rotation
+    mat4 inv_view_proj;
+    // xyz: direction towards the sun, w: sun illuminance
+    vec4 sun;
+    // x: ground radius, y: atmosphere radius, z: camera altitude, w: cosine of the sun's radius
+    vec4 radii;
+} push;
+
+layout(set = 0, binding = 0) uniform texture2D transmittance_lut;
+layout(set = 0, binding = 2) uniform sampler lut_sampler;
+layout(set = 0, binding = 4) uniform texture2D sky_view_lut;
+
+layout(location = 0) in vec2 ndc;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec4 point = push.inv_view_proj * vec4(ndc, 1.0, 1.0);
+    vec3 dir = normalize(point.xyz / point.w);
+    vec3 sun = normalize(push.sun.xyz);
+
+    vec2 horizontal = dir.xz;
+    vec2 sun_horizontal = sun.xz;
+    float azimuth = 0.0;
+    if (length(horizontal) > 1e-5 && length(sun_horizontal) > 1e-5) {
+        azimuth = acos(clamp(dot(normalize(horizontal), normalize(sun_horizontal)), -1.0, 1.0));
+    }
+    float v = asin(clamp(dir.y, -1.0, 1.0)) / (PI * 0.5);
+    float packed = sign(v) * sqrt(abs(v));
+    vec2 uv = vec2(azimuth / PI, packed * 0.5 + 0.5);
+    vec3 color = textureLod(sampler2D(sky_view_lut, lut_sampler), uv, 0.0).rgb;
+
+    if (dot(dir, sun) > push.radii.w) {
+        float r = push.radii.x + max(push.radii.z, 0.001);
+        vec2 lut = vec2(dir.y * 0.5 + 0.5, (r - push.radii.x) / (push.radii.y - push.radii.x));
+        vec3 transmittance = textureLod(sampler2D(transmittance_lut, lut_sampler), lut, 0.0).rgb;
+        color += transmittance * push.sun.w;
+    }
+    out_color = vec4(color, 1.0);
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 8;
+const TRANSMITTANCE_SIZE: (u32, u32) = (256, 64);
+const MULTI_SCATTERING_SIZE: (u32, u32) = (32, 32);
+const SKY_VIEW_SIZE: (u32, u32) = (192, 108);
+
+#[derive(Debug, Error)]
+pub enum SkyError {
+    #[error("failed to compile sky shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Makeup of the planet's atmosphere, distances in kilometres and coefficients per kilometre
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Atmosphere {
+    pub ground_radius: f32,
+    pub top_radius: f32,
+    pub rayleigh_scattering: Vec3,
+    pub rayleigh_scale_height: f32,
+    pub mie_scattering: f32,
+    pub mie_absorption: f32,
+    /// Henyey-Greenstein asymmetry, how strongly haze scatters forward
+    pub mie_anisotropy: f32,
+    pub mie_scale_height: f32,
+    pub ozone_absorption: Vec3,
+    pub ground_albedo: f32,
+}
+
+impl Default for Atmosphere {
+    /// Earth's
+    fn default() -> Self {
+        Atmosphere {
+            ground_radius: 6360.0,
+            top_radius: 6460.0,
+            rayleigh_scattering: Vec3::new(5.802, 13.558, 33.1) * 1e-3,
+            rayleigh_scale_height: 8.0,
+            mie_scattering: 3.996e-3,
+            mie_absorption: 4.4e-3,
+            mie_anisotropy: 0.8,
+            mie_scale_height: 1.2,
+            ozone_absorption: Vec3::new(0.65, 1.881, 0.085) * 1e-3,
+            ground_albedo: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SkyConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+}
+
+impl SkyConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        SkyConfig {
+            render_pass,
+            subpass: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct TablePush {
+    sun: Vec4,
+    rayleigh: Vec4,
+    mie: Vec4,
+    ozone: Vec4,
+    radii: Vec4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct DrawPush {
+    inv_view_proj: Mat4,
+    sun: Vec4,
+    radii: Vec4,
+}
+
+/// Direction towards the sun for the first directional light in `scene`
+pub fn sun_direction(scene: &Scene) -> Option<Vec3> {
+    scene
+        .lights()
+        .find(|(light, _, _)| light.kind == LightKind::Directional)
+        .map(|(_, _, direction)| -direction)
+}
+
+pub struct Sky {
+    pub atmosphere: Atmosphere,
+    /// Direction towards the sun, see [`sun_direction`] to follow a light
+    pub sun: Vec3,
+    pub sun_illuminance: f32,
+    /// Angular radius of the sun's disk in radians
+    pub sun_radius: f32,
+    /// Metres above the ground, kept apart from the scene's camera position
+    pub camera_altitude: f32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    /// Writing the transmittance, multiple scattering and sky-view tables, the last also drawn with
+    sets: [vk::DescriptorSet; 3],
+    compute_layout: vk::PipelineLayout,
+    draw_layout: vk::PipelineLayout,
+    transmittance_pipeline: vk::Pipeline,
+    multi_scattering_pipeline: vk::Pipeline,
+    sky_view_pipeline: vk::Pipeline,
+    draw_pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    tables: Vec<Texture>,
+    /// Atmosphere the transmittance and multiple scattering tables were built for
+    baked: Option<Atmosphere>,
+}
+
+impl Sky {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        config: SkyConfig,
+    ) -> Result<Self, SkyError> {
+        let compile = |source: &str, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| SkyError::Compile(Box::new(err)))
+        };
+        let table = |body: &str| {
+            compile(
+                &format!("#version 450\n{SKY_COMMON_GLSL}{body}"),
+                vk::ShaderStageFlags::COMPUTE,
+            )
+        };
+        let transmittance = table(SKY_TRANSMITTANCE_GLSL)?;
+        let multi_scattering = table(SKY_MULTI_SCATTERING_GLSL)?;
+        let sky_view = table(SKY_VIEW_GLSL)?;
+        let vert = compile(SKY_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(SKY_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Linear)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = Sky {
+            atmosphere: Atmosphere::default(),
+            sun: Vec3::new(0.3, 0.5, -0.8).normalize(),
+            sun_illuminance: 20.0,
+            sun_radius: 0.00465,
+            camera_altitude: 1.0,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            sets: [vk::DescriptorSet::null(); 3],
+            compute_layout: vk::PipelineLayout::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            transmittance_pipeline: vk::Pipeline::null(),
+            multi_scattering_pipeline: vk::Pipeline::null(),
+            sky_view_pipeline: vk::Pipeline::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            sampler,
+            tables: Vec::new(),
+            baked: None,
+        };
+        let result = unsafe {
+            this.create_objects(
+                device,
+                mem_props,
+                &[&transmittance, &multi_scattering, &sky_view, &vert, &frag],
+                config,
+            )
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        code: &[&[u32]; 5],
+        config: SkyConfig,
+    ) -> Result<(), SkyError> {
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "sky descriptor set layout");
+        let sets = self.sets.len() as u32;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 3 * sets,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: sets,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: sets,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(sets)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "sky descriptor pool");
+        let layouts = [self.set_layout; 3];
+        let allocated = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&layouts),
+        )?;
+        self.sets.copy_from_slice(&allocated);
+
+        for (width, height) in [TRANSMITTANCE_SIZE, MULTI_SCATTERING_SIZE, SKY_VIEW_SIZE] {
+            let table = Texture::empty(
+                device,
+                mem_props,
+                HDR_FORMAT,
+                vk::Extent2D { width, height },
+                vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE,
+            )?;
+            self.tables.push(table);
+        }
+        // Tables stay in GENERAL, written by compute and sampled by the next pass or the draw
+        let image = |view| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: view,
+                image_layout: vk::ImageLayout::GENERAL,
+            }]
+        };
+        let sampled = [
+            image(self.tables[0].view),
+            image(self.tables[1].view),
+            image(self.tables[2].view),
+        ];
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        for (set, target) in self.sets.iter().zip(&sampled) {
+            let infos = [&sampled[0], &sampled[1], &sampler, target, &sampled[2]];
+            let writes: Vec<_> = (0..)
+                .zip(types)
+                .zip(infos)
+                .map(|((binding, ty), info)| {
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(*set)
+                        .dst_binding(binding)
+                        .descriptor_type(ty)
+                        .image_info(info)
+                        .build()
+                })
+                .collect();
+            device.update_descriptor_sets(&writes, &[]);
+        }
+
+        let compute_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: mem::size_of::<TablePush>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[compute_range]),
+        )?;
+        leaks::track(self.compute_layout, "sky pipeline layout");
+        let draw_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: mem::size_of::<DrawPush>() as u32,
+        };
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[draw_range]),
+        )?;
+        leaks::track(self.draw_layout, "sky pipeline layout");
+
+        let [transmittance, multi_scattering, sky_view, vert, frag] = code;
+        for compute in [transmittance, multi_scattering, sky_view] {
+            reflect::debug_validate(&[compute], &[&bindings], &[compute_range], &[])?;
+        }
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[draw_range], &[])?;
+        self.transmittance_pipeline = compute_pipeline(
+            device,
+            self.compute_layout,
+            transmittance,
+            "sky transmittance",
+        )?;
+        self.multi_scattering_pipeline = compute_pipeline(
+            device,
+            self.compute_layout,
+            multi_scattering,
+            "sky multi scattering",
+        )?;
+        self.sky_view_pipeline =
+            compute_pipeline(device, self.compute_layout, sky_view, "sky view")?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "sky shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "sky shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        // At the far plane, so only pixels nothing was drawn to pass
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(false)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "sky draw");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Records rebuilding the tables for the current sun, and the atmosphere if it changed
+    ///
+    /// Must be recorded outside a render pass, before [`Sky::draw`].
+    pub fn update(&mut self, device: &Device, cmd: vk::CommandBuffer) {
+        if self.tables.len() < 3 {
+            return;
+        }
+        let atmosphere = &self.atmosphere;
+        let push = TablePush {
+            sun: self.sun.normalize_or_zero().extend(self.sun_illuminance),
+            rayleigh: atmosphere
+                .rayleigh_scattering
+                .extend(atmosphere.rayleigh_scale_height),
+            mie: Vec4::new(
+                atmosphere.mie_scattering,
+                atmosphere.mie_absorption,
+                atmosphere.mie_anisotropy,
+                atmosphere.mie_scale_height,
+            ),
+            ozone: atmosphere.ozone_absorption.extend(atmosphere.ground_albedo),
+            radii: Vec4::new(
+                atmosphere.ground_radius,
+                atmosphere.top_radius,
+                self.camera_altitude / 1000.0,
+                0.0,
+            ),
+        };
+        let rebuild = self.baked != Some(self.atmosphere);
+        let passes = [
+            (self.transmittance_pipeline, TRANSMITTANCE_SIZE),
+            (self.multi_scattering_pipeline, MULTI_SCATTERING_SIZE),
+            (self.sky_view_pipeline, SKY_VIEW_SIZE),
+        ];
+        let first = if rebuild { 0 } else { 2 };
+        unsafe {
+            if self.baked.is_none() {
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                for table in &self.tables {
+                    transition(
+                        device,
+                        cmd,
+                        table.image,
+                        range,
+                        vk::ImageLayout::UNDEFINED,
+                        vk::ImageLayout::GENERAL,
+                    );
+                }
+            } else {
+                // Last frame's draw has to be done sampling the sky-view table
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            for (pass, (pipeline, (width, height))) in passes.into_iter().enumerate().skip(first) {
+                device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+                device.cmd_bind_descriptor_sets(
+                    cmd,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.compute_layout,
+                    0,
+                    &[self.sets[pass]],
+                    &[],
+                );
+                device.cmd_dispatch(
+                    cmd,
+                    width.div_ceil(WORKGROUP_SIZE),
+                    height.div_ceil(WORKGROUP_SIZE),
+                    1,
+                );
+                let (dst_stage, dst_access) = if pass + 1 < passes.len() {
+                    (
+                        vk::PipelineStageFlags::COMPUTE_SHADER,
+                        vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+                    )
+                } else {
+                    (
+                        vk::PipelineStageFlags::FRAGMENT_SHADER,
+                        vk::AccessFlags::SHADER_READ,
+                    )
+                };
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    dst_stage,
+                    vk::AccessFlags::SHADER_WRITE,
+                    dst_access,
+                );
+            }
+        }
+        self.baked = Some(self.atmosphere);
+    }
+
+    /// Records the sky behind everything drawn so far, as seen through `view` and `proj`
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, view: Mat4, proj: Mat4) {
+        if self.baked.is_none() {
+            return;
+        }
+        let rotation = Mat4::from_mat3(Mat3::from_mat4(view));
+        let push = DrawPush {
+            inv_view_proj: (proj * rotation).inverse(),
+            sun: self.sun.normalize_or_zero().extend(self.sun_illuminance),
+            radii: Vec4::new(
+                self.atmosphere.ground_radius,
+                self.atmosphere.top_radius,
+                self.camera_altitude / 1000.0,
+                self.sun_radius.cos(),
+            ),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[self.sets[2]],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.draw_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// The sky-view table, for sampling ambient light or baking reflections
+    pub fn sky_view(&self) -> Option<&Texture> {
+        self.tables.get(2)
+    }
+
+    /// # Safety
+    ///
+    /// No recorded update or draw may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for table in self.tables.drain(..) {
+            table.destroy(device);
+        }
+        for pipeline in [
+            self.transmittance_pipeline,
+            self.multi_scattering_pipeline,
+            self.sky_view_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "sky shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
@@ -0,0 +1,976 @@
+//! Screen-space reflections traced through a hierarchical depth buffer
+//!
+//! A compute pass reduces the scene's depth into a mip chain keeping the closest depth of every
+//! 2x2 block. Reflected rays march through it in screen space, skipping whole cells at coarse
+//! levels while the ray stays in front of everything in them and refining only where it could
+//! hit, so long rays take few steps. Hits pick up the lit scene's color, faded out towards the
+//! screen's edges and the end of the ray where the trace can't be trusted.
+//!
+//! The traced reflections are then blurred down their own mip chain, and the composite pass adds
+//! them to the lighting result at a level picked by roughness, filling misses from the environment
+//! cubemap at the same blur.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec4};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    memory::{usage::MemoryCategory, Buffer},
+    scene::Camera,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        hdr::HDR_FORMAT,
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        transition, Texture,
+    },
+};
+
+/// Frame parameters and inputs shared by every pass
+const SSR_COMMON_GLSL: &str = r#"
+layout(set = 0, binding = 0) uniform Frame {
+    mat4 view_proj;
+    mat4 inv_view_proj;
+    // w: longest ray in world units
+    vec4 camera;
+    // x: thickness surfaces are assumed to have, y: most steps per ray, z: near plane, w: far plane
+    vec4 params;
+    // x: roughest surface traced, y: depth levels, z: reflection levels, w: screen edge fade
+    vec4 limits;
+    // x: environment levels, y: environment intensity
+    vec4 environment;
+} frame;
+
+layout(set = 0, binding = 1) uniform texture2D scene_depth;
+// rgb: world normal packed into 0..1, a: roughness
+layout(set = 0, binding = 2) uniform texture2D normals;
+layout(set = 0, binding = 3) uniform texture2D scene_color;
+layout(set = 0, binding = 4) uniform texture2D hiz;
+layout(set = 0, binding = 5) uniform texture2D reflection;
+layout(set = 0, binding = 6) uniform textureCube environment_map;
+layout(set = 0, binding = 7) uniform sampler point_sampler;
+layout(set = 0, binding = 8) uniform sampler linear_sampler;
+
+vec3 world_position(vec2 uv, float depth) {
+    vec4 world = frame.inv_view_proj * vec4(uv * 2.0 - 1.0, depth, 1.0);
+    return world.xyz / world.w;
+}
+
+float linear_depth(float depth) {
+    float near = frame.params.z;
+    float far = frame.params.w;
+    return near * far / (far - depth * (far - near));
+}
+"#;
+
+/// Writes one level of the depth chain, copying the scene's depth into level zero
+pub const SSR_HIZ_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 9, r32f) uniform image2D src;
+layout(set = 0, binding = 10, r32f) uniform image2D dst;
+
+layout(push_constant) uniform Push {
+    uint level;
+} push;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(dst);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    if (push.level == 0u) {
+        float depth = texelFetch(sampler2D(scene_depth, point_sampler), id, 0).r;
+        imageStore(dst, id, vec4(depth));
+        return;
+    }
+    // Odd sizes fold their last row and column into the texels next to them
+    ivec2 src_size = imageSize(src);
+    int columns = (src_size.x & 1) != 0 && id.x == size.x - 1 ? 3 : 2;
+    int rows = (src_size.y & 1) != 0 && id.y == size.y - 1 ? 3 : 2;
+    float closest = 1.0;
+    for (int y = 0; y < rows; y++) {
+        for (int x = 0; x < columns; x++) {
+            ivec2 texel = min(id * 2 + ivec2(x, y), src_size - 1);
+            closest = min(closest, imageLoad(src, texel).r);
+        }
+    }
+    imageStore(dst, id, vec4(closest));
+}
+"#;
+
+/// Marches each pixel's reflected ray, storing the color it hits premultiplied by confidence
+pub const SSR_TRACE_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 10, rgba16f) uniform image2D dst;
+
+vec3 to_screen(vec3 world) {
+    vec4 clip = frame.view_proj * vec4(world, 1.0);
+    return vec3(clip.xy / clip.w * 0.5 + 0.5, clip.z / clip.w);
+}
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(dst);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id) + 0.5) / vec2(size);
+    float depth = texelFetch(sampler2D(scene_depth, point_sampler), id, 0).r;
+    vec4 surface = texelFetch(sampler2D(normals, point_sampler), id, 0);
+    float roughness = surface.a;
+    if (depth >= 1.0 || roughness > frame.limits.x) {
+        imageStore(dst, id, vec4(0.0));
+        return;
+    }
+    vec3 world = world_position(uv, depth);
+    vec3 normal = normalize(surface.xyz * 2.0 - 1.0);
+    vec3 dir = reflect(normalize(world - frame.camera.xyz), normal);
+
+    // Shortened to stay in front of the near plane, behind it the projection flips
+    float distance = frame.camera.w;
+    float start_w = (frame.view_proj * vec4(world, 1.0)).w;
+    float end_w = (frame.view_proj * vec4(world + dir * distance, 1.0)).w;
+    float near = frame.params.z * 1.01;
+    if (end_w < near) {
+        distance *= (start_w - near) / (start_w - end_w);
+    }
+    vec3 origin = vec3(uv, depth);
+    vec3 ray = to_screen(world + dir * distance) - origin;
+    vec2 safe_xy = vec2(
+        abs(ray.x) < 0.0000001 ? 0.0000001 : ray.x,
+        abs(ray.y) < 0.0000001 ? 0.0000001 : ray.y
+    );
+    float texels = length(ray.xy * vec2(size)) + 0.0001;
+
+    int max_level = int(frame.limits.y) - 1;
+    int level = 0;
+    // Starting a couple of texels out so the ray doesn't hit its own surface
+    float t = 2.0 / texels;
+    bool hit = false;
+    for (int i = 0; i < int(frame.params.y); i++) {
+        vec3 p = origin + ray * t;
+        if (t > 1.0 || p.x < 0.0 || p.x > 1.0 || p.y < 0.0 || p.y > 1.0) {
+            break;
+        }
+        vec2 cells = vec2(textureSize(sampler2D(hiz, point_sampler), level));
+        vec2 cell = floor(p.xy * cells);
+        float closest = texelFetch(sampler2D(hiz, point_sampler), ivec2(cell), level).r;
+        if (p.z < closest) {
+            // In front of everything in the cell: skip to where it leaves, or reaches the surface
+            vec2 boundary = (cell + step(vec2(0.0), ray.xy)) / cells;
+            vec2 to_boundary = (boundary - origin.xy) / safe_xy;
+            float t_cell = min(to_boundary.x, to_boundary.y) + 0.1 / texels;
+            float t_plane = ray.z > 0.0 ? (closest - origin.z) / ray.z : 2.0;
+            if (t_plane < t_cell) {
+                t = max(t_plane, t);
+                level--;
+                if (level < 0) {
+                    hit = true;
+                    break;
+                }
+            } else {
+                t = t_cell;
+                level = min(level + 1, max_level);
+            }
+        } else if (level == 0) {
+            // Behind the surface, a hit unless the ray passed behind the object entirely
+            hit = linear_depth(p.z) - linear_depth(closest) < frame.params.x;
+            break;
+        } else {
+            level--;
+        }
+    }
+    if (!hit) {
+        imageStore(dst, id, vec4(0.0));
+        return;
+    }
+
+    vec3 p = origin + ray * t;
+    float fade = frame.limits.w;
+    vec2 edges = smoothstep(vec2(0.0), vec2(fade), p.xy) * (1.0 - smoothstep(vec2(1.0 - fade), vec2(1.0), p.xy));
+    float confidence = edges.x * edges.y;
+    confidence *= 1.0 - smoothstep(0.8, 1.0, t);
+    confidence *= 1.0 - smoothstep(frame.limits.x * 0.5, frame.limits.x, roughness);
+    vec3 color = textureLod(sampler2D(scene_color, linear_sampler), p.xy, 0.0).rgb;
+    imageStore(dst, id, vec4(color * confidence, confidence));
+}
+"#;
+
+/// Halves the reflections into the next level with a 4x4 tent, keeping them premultiplied
+pub const SSR_BLUR_GLSL: &str = r#"
+layout(local_size_x = 8, local_size_y = 8) in;
+
+layout(set = 0, binding = 9, rgba16f) uniform image2D src;
+layout(set = 0, binding = 10, rgba16f) uniform image2D dst;
+
+void main() {
+    ivec2 id = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(dst);
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    ivec2 src_size = imageSize(src);
+    vec4 weights = vec4(1.0, 3.0, 3.0, 1.0);
+    vec4 sum = vec4(0.0);
+    for (int y = 0; y < 4; y++) {
+        for (int x = 0; x < 4; x++) {
+            ivec2 texel = clamp(id * 2 + ivec2(x - 1, y - 1), ivec2(0), src_size - 1);
+            sum += imageLoad(src, texel) * weights[x] * weights[y];
+        }
+    }
+    imageStore(dst, id, sum / 64.0);
+}
+"#;
+
+pub const SSR_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = uv;
+}
+"#;
+
+/// Adds the reflections at each pixel's roughness, or the environment where the trace missed
+pub const SSR_COMPOSITE_GLSL: &str = r#"
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float depth = textureLod(sampler2D(scene_depth, point_sampler), uv, 0.0).r;
+    if (depth >= 1.0) {
+        discard;
+    }
+    vec4 surface = textureLod(sampler2D(normals, point_sampler), uv, 0.0);
+    float roughness = surface.a;
+    vec3 normal = normalize(surface.xyz * 2.0 - 1.0);
+    vec3 view = normalize(world_position(uv, depth) - frame.camera.xyz);
+    vec3 dir = reflect(view, normal);
+
+    vec4 traced = textureLod(sampler2D(reflection, linear_sampler), uv, roughness * (frame.limits.z - 1.0));
+    vec3 traced_color = traced.a > 0.0 ? traced.rgb / traced.a : vec3(0.0);
+    float env_lod = roughness * (frame.environment.x - 1.0);
+    vec3 env = textureLod(samplerCube(environment_map, linear_sampler), dir, env_lod).rgb * frame.environment.y;
+    vec3 reflected = mix(env, traced_color, clamp(traced.a, 0.0, 1.0));
+
+    float fresnel = 0.04 + 0.96 * pow(1.0 - max(dot(normal, -view), 0.0), 5.0);
+    float gloss = (1.0 - roughness) * (1.0 - roughness);
+    out_color = vec4(reflected * fresnel * gloss, 0.0);
+}
+"#;
+
+const HIZ_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+/// Blur levels of the reflections, enough that the roughest traced surfaces look diffuse
+const REFLECTION_LEVELS: u32 = 6;
+
+#[derive(Debug, Error)]
+pub enum SsrError {
+    #[error("failed to compile reflection shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// What the reflections are traced against, all in `SHADER_READ_ONLY_OPTIMAL`
+#[derive(Debug, Clone, Copy)]
+pub struct SsrInputs {
+    /// Depth aspect view of the scene's depth
+    pub depth: vk::ImageView,
+    /// World normals packed into 0..1 in rgb and roughness in alpha
+    pub normals: vk::ImageView,
+    /// The lit scene before reflections are added
+    pub color: vk::ImageView,
+    /// Cube view of a prefiltered environment, rougher towards its smaller levels
+    pub environment: vk::ImageView,
+    pub environment_levels: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SsrConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Size of the inputs
+    pub extent: vk::Extent2D,
+}
+
+impl SsrConfig {
+    pub fn new(render_pass: vk::RenderPass, extent: vk::Extent2D) -> Self {
+        SsrConfig {
+            render_pass,
+            subpass: 0,
+            extent,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct FrameBlock {
+    view_proj: Mat4,
+    inv_view_proj: Mat4,
+    camera: Vec4,
+    params: Vec4,
+    limits: Vec4,
+    environment: Vec4,
+}
+
+pub struct Ssr {
+    /// Longest reflected ray in world units
+    pub max_distance: f32,
+    /// How far behind a surface a ray still counts as hitting it
+    pub thickness: f32,
+    pub max_steps: u32,
+    /// Rougher surfaces only reflect the environment
+    pub max_roughness: f32,
+    /// Fraction of the screen reflections fade out over towards its edges
+    pub edge_fade: f32,
+    pub environment_intensity: f32,
+    set_layout: vk::DescriptorSetLayout,
+    compute_layout: vk::PipelineLayout,
+    draw_layout: vk::PipelineLayout,
+    hiz_pipeline: vk::Pipeline,
+    trace_pipeline: vk::Pipeline,
+    blur_pipeline: vk::Pipeline,
+    draw_pipeline: vk::Pipeline,
+    frame: Option<Buffer>,
+    /// Owned by the [`SamplerCache`]
+    point_sampler: vk::Sampler,
+    linear_sampler: vk::Sampler,
+    /// Size dependent, recreated by [`Ssr::resize`]
+    pool: vk::DescriptorPool,
+    /// One per depth level, then one per reflection level, the first of those also drawn with
+    sets: Vec<vk::DescriptorSet>,
+    hiz: Option<Texture>,
+    reflection: Option<Texture>,
+    level_views: Vec<vk::ImageView>,
+    environment_levels: u32,
+    initialized: bool,
+}
+
+impl Ssr {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        inputs: SsrInputs,
+        config: SsrConfig,
+    ) -> Result<Self, SsrError> {
+        let compile = |body: &str, stage| {
+            compiler
+                .compile(&format!("#version 450\n{SSR_COMMON_GLSL}{body}"), stage)
+                .map_err(|err| SsrError::Compile(Box::new(err)))
+        };
+        let hiz = compile(SSR_HIZ_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let trace = compile(SSR_TRACE_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let blur = compile(SSR_BLUR_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let frag = compile(SSR_COMPOSITE_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let vert = compiler
+            .compile(SSR_VERT_GLSL, vk::ShaderStageFlags::VERTEX)
+            .map_err(|err| SsrError::Compile(Box::new(err)))?;
+        let clamped =
+            SamplerDesc::default().with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        let point_sampler = samplers.get(device, &clamped.with_filter(SamplerFilter::Nearest))?;
+        let linear_sampler = samplers.get(device, &clamped.with_filter(SamplerFilter::Linear))?;
+
+        let mut this = Ssr {
+            max_distance: 50.0,
+            thickness: 0.5,
+            max_steps: 64,
+            max_roughness: 0.6,
+            edge_fade: 0.1,
+            environment_intensity: 1.0,
+            set_layout: vk::DescriptorSetLayout::null(),
+            compute_layout: vk::PipelineLayout::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            hiz_pipeline: vk::Pipeline::null(),
+            trace_pipeline: vk::Pipeline::null(),
+            blur_pipeline: vk::Pipeline::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            frame: None,
+            point_sampler,
+            linear_sampler,
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            hiz: None,
+            reflection: None,
+            level_views: Vec::new(),
+            environment_levels: inputs.environment_levels,
+            initialized: false,
+        };
+        let result = unsafe {
+            this.create_objects(
+                device,
+                mem_props,
+                &[&hiz, &trace, &blur, &vert, &frag],
+                config,
+            )
+            .and_then(|_| Ok(this.create_targets(device, mem_props, config.extent, inputs)?))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        code: &[&[u32]; 5],
+        config: SsrConfig,
+    ) -> Result<(), SsrError> {
+        let bindings: Vec<_> = (0..)
+            .zip(binding_types())
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "ssr descriptor set layout");
+        let compute_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: mem::size_of::<u32>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[compute_range]),
+        )?;
+        leaks::track(self.compute_layout, "ssr pipeline layout");
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder().set_layouts(&[self.set_layout]),
+        )?;
+        leaks::track(self.draw_layout, "ssr pipeline layout");
+
+        let mut frame = Buffer::new(
+            device,
+            mem_props,
+            mem::size_of::<FrameBlock>() as vk::DeviceSize,
+            vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        frame.set_category(MemoryCategory::Uniform);
+        self.frame = Some(frame);
+
+        let [hiz, trace, blur, vert, frag] = code;
+        for compute in [hiz, trace, blur] {
+            reflect::debug_validate(&[compute], &[&bindings], &[compute_range], &[])?;
+        }
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[], &[])?;
+        self.hiz_pipeline = compute_pipeline(device, self.compute_layout, hiz, "ssr hiz")?;
+        self.trace_pipeline = compute_pipeline(device, self.compute_layout, trace, "ssr trace")?;
+        self.blur_pipeline = compute_pipeline(device, self.compute_layout, blur, "ssr blur")?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "ssr shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "ssr shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder();
+        // Added on top of the lighting, whose alpha is left alone
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::ONE)
+            .dst_color_blend_factor(vk::BlendFactor::ONE)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ZERO)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "ssr composite");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    unsafe fn create_targets(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        inputs: SsrInputs,
+    ) -> VkResult<()> {
+        let hiz_levels = extent.width.max(extent.height).max(1).ilog2() + 1;
+        let reflection_levels = REFLECTION_LEVELS.min(hiz_levels);
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE;
+        let hiz = Texture::empty_mipped(device, mem_props, HIZ_FORMAT, extent, hiz_levels, usage)?;
+        let hiz = self.hiz.insert(hiz);
+        let reflection = Texture::empty_mipped(
+            device,
+            mem_props,
+            HDR_FORMAT,
+            extent,
+            reflection_levels,
+            usage,
+        )?;
+        let reflection = self.reflection.insert(reflection);
+        for level in 0..hiz_levels {
+            self.level_views.push(hiz.level_view(device, level)?);
+        }
+        for level in 0..reflection_levels {
+            self.level_views.push(reflection.level_view(device, level)?);
+        }
+
+        let sets = hiz_levels + reflection_levels;
+        let pool_sizes: Vec<_> = binding_types()
+            .into_iter()
+            .map(|ty| vk::DescriptorPoolSize {
+                ty,
+                descriptor_count: sets,
+            })
+            .collect();
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(sets)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "ssr descriptor pool");
+        let layouts = vec![self.set_layout; sets as usize];
+        self.sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&layouts),
+        )?;
+
+        let frame = [vk::DescriptorBufferInfo {
+            buffer: self.frame.as_ref().map_or(vk::Buffer::null(), |b| b.buffer),
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let image = |image_view, image_layout| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view,
+                image_layout,
+            }]
+        };
+        let sampler = |sampler| {
+            [vk::DescriptorImageInfo {
+                sampler,
+                image_view: vk::ImageView::null(),
+                image_layout: vk::ImageLayout::UNDEFINED,
+            }]
+        };
+        let read_only = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        let general = vk::ImageLayout::GENERAL;
+        let shared = [
+            image(inputs.depth, read_only),
+            image(inputs.normals, read_only),
+            image(inputs.color, read_only),
+            image(hiz.view, general),
+            image(reflection.view, general),
+            image(inputs.environment, read_only),
+            sampler(self.point_sampler),
+            sampler(self.linear_sampler),
+        ];
+        let hiz_levels = hiz_levels as usize;
+        for (index, &set) in self.sets.iter().enumerate() {
+            // The first level of each chain reads from itself, its shader never loads `src`
+            let (src, dst) = match index.checked_sub(hiz_levels) {
+                None => (index.saturating_sub(1), index),
+                Some(level) => (hiz_levels + level.saturating_sub(1), index),
+            };
+            let storage = [
+                image(self.level_views[src], general),
+                image(self.level_views[dst], general),
+            ];
+            let mut writes = vec![vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&frame)
+                .build()];
+            for ((binding, ty), info) in (0..)
+                .zip(binding_types())
+                .skip(1)
+                .zip(shared.iter().chain(&storage))
+            {
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(binding)
+                        .descriptor_type(ty)
+                        .image_info(info)
+                        .build(),
+                );
+            }
+            device.update_descriptor_sets(&writes, &[]);
+        }
+        self.environment_levels = inputs.environment_levels;
+        self.initialized = false;
+        Ok(())
+    }
+
+    unsafe fn destroy_targets(&mut self, device: &Device) {
+        for view in self.level_views.drain(..) {
+            device.destroy_image_view(view, None);
+        }
+        for texture in [&mut self.hiz, &mut self.reflection] {
+            if let Some(texture) = texture.take() {
+                texture.destroy(device);
+            }
+        }
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        self.pool = vk::DescriptorPool::null();
+        self.sets.clear();
+    }
+
+    /// Recreates the depth chain and reflections at `extent` for the resized inputs
+    ///
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn resize(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        inputs: SsrInputs,
+    ) -> VkResult<()> {
+        self.destroy_targets(device);
+        self.create_targets(device, mem_props, extent, inputs)
+    }
+
+    /// Records building the depth chain, tracing and blurring the reflections
+    ///
+    /// Writes the camera into a uniform block the device must not be reading from an earlier
+    /// frame. Must be recorded outside a render pass, before [`Ssr::draw`].
+    pub fn update(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        camera: &Camera,
+        view: Mat4,
+        aspect: f32,
+    ) {
+        let (Some(frame), Some(hiz), Some(reflection)) = (&self.frame, &self.hiz, &self.reflection)
+        else {
+            return;
+        };
+        let view_proj = camera.projection(aspect) * view;
+        let block = FrameBlock {
+            view_proj,
+            inv_view_proj: view_proj.inverse(),
+            camera: view.inverse().w_axis.truncate().extend(self.max_distance),
+            params: Vec4::new(
+                self.thickness,
+                self.max_steps as f32,
+                camera.near,
+                camera.far,
+            ),
+            limits: Vec4::new(
+                self.max_roughness,
+                hiz.mip_levels as f32,
+                reflection.mip_levels as f32,
+                self.edge_fade,
+            ),
+            environment: Vec4::new(
+                self.environment_levels as f32,
+                self.environment_intensity,
+                0.0,
+                0.0,
+            ),
+        };
+        let mapped = frame.mapped().expect("frame block is host visible");
+        unsafe { block.write_to(mapped.as_ptr()) };
+
+        let compute_barrier = || unsafe {
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            )
+        };
+        let dispatch = |pipeline, set, level: u32, width: u32, height: u32| unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                level.as_bytes(),
+            );
+            device.cmd_dispatch(cmd, width.div_ceil(8), height.div_ceil(8), 1);
+        };
+        unsafe {
+            if !self.initialized {
+                for texture in [hiz, reflection] {
+                    let range = vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: texture.mip_levels,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    };
+                    transition(
+                        device,
+                        cmd,
+                        texture.image,
+                        range,
+                        vk::ImageLayout::UNDEFINED,
+                        vk::ImageLayout::GENERAL,
+                    );
+                }
+            } else {
+                // Last frame's composite has to be done sampling the reflections
+                memory_barrier(
+                    device,
+                    cmd,
+                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+        }
+        let hiz_levels = hiz.mip_levels;
+        for level in 0..hiz_levels {
+            let width = (hiz.extent.width >> level).max(1);
+            let height = (hiz.extent.height >> level).max(1);
+            dispatch(
+                self.hiz_pipeline,
+                self.sets[level as usize],
+                level,
+                width,
+                height,
+            );
+            compute_barrier();
+        }
+        let (width, height) = (reflection.extent.width, reflection.extent.height);
+        dispatch(
+            self.trace_pipeline,
+            self.sets[hiz_levels as usize],
+            0,
+            width,
+            height,
+        );
+        for level in 1..reflection.mip_levels {
+            compute_barrier();
+            dispatch(
+                self.blur_pipeline,
+                self.sets[(hiz_levels + level) as usize],
+                level,
+                (width >> level).max(1),
+                (height >> level).max(1),
+            );
+        }
+        unsafe {
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ,
+            );
+        }
+        self.initialized = true;
+    }
+
+    /// Records adding the reflections onto the lighting result, which must not be the color input
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let Some(hiz) = &self.hiz else {
+            return;
+        };
+        if !self.initialized {
+            return;
+        }
+        let set = self.sets[hiz.mip_levels as usize];
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[set],
+                &[],
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded update or composite may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.destroy_targets(device);
+        if let Some(frame) = self.frame.take() {
+            frame.destroy(device);
+        }
+        for pipeline in [
+            self.hiz_pipeline,
+            self.trace_pipeline,
+            self.blur_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Descriptor types of the bindings in [`SSR_COMMON_GLSL`] followed by the storage pair
+fn binding_types() -> [vk::DescriptorType; 11] {
+    let sampled = vk::DescriptorType::SAMPLED_IMAGE;
+    [
+        vk::DescriptorType::UNIFORM_BUFFER,
+        sampled,
+        sampled,
+        sampled,
+        sampled,
+        sampled,
+        sampled,
+        vk::DescriptorType::SAMPLER,
+        vk::DescriptorType::SAMPLER,
+        vk::DescriptorType::STORAGE_IMAGE,
+        vk::DescriptorType::STORAGE_IMAGE,
+    ]
+}
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "ssr shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
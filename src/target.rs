@@ -0,0 +1,421 @@
+//! Offscreen render targets: a color image with optional depth, plus their render pass and
+//! framebuffer
+//!
+//! Multisampled targets render into transient multisampled attachments and resolve the color into
+//! a single sampled image at the end of the pass, that one is what later passes read. The render
+//! pass only depends on the formats and sample count, so it outlives [`RenderTarget::resize`] and
+//! pipelines built against it stay valid.
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::Vec4;
+
+use crate::{assets::GpuAsset, texture::Texture};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderTargetDesc {
+    pub color_format: vk::Format,
+    pub depth_format: Option<vk::Format>,
+    pub extent: vk::Extent2D,
+    pub samples: vk::SampleCountFlags,
+    /// Usage of the color later passes read, attachment usage is always added
+    pub color_usage: vk::ImageUsageFlags,
+    /// Usage of the depth, with `SAMPLED` it's stored and left in `SHADER_READ_ONLY_OPTIMAL`
+    ///
+    /// Multisampled depth isn't resolved, so it can only be read as a multisampled image.
+    pub depth_usage: vk::ImageUsageFlags,
+    /// Layout the color is left in after the pass
+    pub color_final_layout: vk::ImageLayout,
+}
+
+impl RenderTargetDesc {
+    /// Single sampled color without depth, left sampleable after the pass
+    pub fn new(color_format: vk::Format, extent: vk::Extent2D) -> Self {
+        RenderTargetDesc {
+            color_format,
+            depth_format: None,
+            extent,
+            samples: vk::SampleCountFlags::TYPE_1,
+            color_usage: vk::ImageUsageFlags::SAMPLED,
+            depth_usage: vk::ImageUsageFlags::empty(),
+            color_final_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+
+    pub fn with_depth(mut self, depth_format: vk::Format) -> Self {
+        self.depth_format = Some(depth_format);
+        self
+    }
+
+    pub fn with_samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn with_color_usage(mut self, usage: vk::ImageUsageFlags) -> Self {
+        self.color_usage = usage;
+        self
+    }
+
+    pub fn with_depth_usage(mut self, usage: vk::ImageUsageFlags) -> Self {
+        self.depth_usage = usage;
+        self
+    }
+
+    pub fn with_color_final_layout(mut self, layout: vk::ImageLayout) -> Self {
+        self.color_final_layout = layout;
+        self
+    }
+
+    pub fn is_multisampled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+
+    fn depth_sampled(&self) -> bool {
+        self.depth_usage.contains(vk::ImageUsageFlags::SAMPLED)
+    }
+}
+
+/// Highest sample count up to `wanted` that the device supports for both color and depth
+/// attachments
+pub fn supported_samples(
+    limits: &vk::PhysicalDeviceLimits,
+    wanted: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    let supported = limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .into_iter()
+    .find(|&count| count.as_raw() <= wanted.as_raw() && supported.contains(count))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+pub struct RenderTarget {
+    desc: RenderTargetDesc,
+    render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+    /// The attachment drawn into, multisampled if the target is
+    color: Option<Texture>,
+    depth: Option<Texture>,
+    /// Single sampled color the multisampled one resolves into
+    resolve: Option<Texture>,
+}
+
+impl RenderTarget {
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        desc: RenderTargetDesc,
+    ) -> VkResult<Self> {
+        let mut this = RenderTarget {
+            desc,
+            render_pass: vk::RenderPass::null(),
+            framebuffer: vk::Framebuffer::null(),
+            color: None,
+            depth: None,
+            resolve: None,
+        };
+        let result = unsafe {
+            this.create_render_pass(device)
+                .and_then(|_| this.create_images(device, mem_props))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_render_pass(&mut self, device: &Device) -> VkResult<()> {
+        let desc = self.desc;
+        let multisampled = desc.is_multisampled();
+        let mut attachments = vec![vk::AttachmentDescription::builder()
+            .format(desc.color_format)
+            .samples(desc.samples)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(if multisampled {
+                vk::AttachmentStoreOp::DONT_CARE
+            } else {
+                vk::AttachmentStoreOp::STORE
+            })
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(if multisampled {
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            } else {
+                desc.color_final_layout
+            })
+            .build()];
+        let color_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        if let Some(depth_format) = desc.depth_format {
+            let sampled = desc.depth_sampled();
+            attachments.push(
+                vk::AttachmentDescription::builder()
+                    .format(depth_format)
+                    .samples(desc.samples)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(if sampled {
+                        vk::AttachmentStoreOp::STORE
+                    } else {
+                        vk::AttachmentStoreOp::DONT_CARE
+                    })
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(if sampled {
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                    } else {
+                        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+                    })
+                    .build(),
+            );
+        }
+        let resolve_refs = [vk::AttachmentReference {
+            attachment: attachments.len() as u32,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        if multisampled {
+            attachments.push(
+                vk::AttachmentDescription::builder()
+                    .format(desc.color_format)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(desc.color_final_layout)
+                    .build(),
+            );
+        }
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_refs);
+        if desc.depth_format.is_some() {
+            subpass = subpass.depth_stencil_attachment(&depth_ref);
+        }
+        if multisampled {
+            subpass = subpass.resolve_attachments(&resolve_refs);
+        }
+        let subpasses = [subpass.build()];
+        // Earlier readers have to be done before the target is cleared, and later passes, compute
+        // included, wait for it to be written
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+            vk::SubpassDependency {
+                src_subpass: 0,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER
+                    | vk::PipelineStageFlags::COMPUTE_SHADER,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+        self.render_pass = device.create_render_pass(
+            &vk::RenderPassCreateInfo::builder()
+                .attachments(&attachments)
+                .subpasses(&subpasses)
+                .dependencies(&dependencies),
+            None,
+        )?;
+        Ok(())
+    }
+
+    unsafe fn create_images(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> VkResult<()> {
+        let desc = self.desc;
+        let mut views = Vec::new();
+        let color = if desc.is_multisampled() {
+            Texture::multisampled(
+                device,
+                mem_props,
+                desc.color_format,
+                desc.extent,
+                desc.samples,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+            )?
+        } else {
+            Texture::empty(
+                device,
+                mem_props,
+                desc.color_format,
+                desc.extent,
+                desc.color_usage | vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            )?
+        };
+        views.push(self.color.insert(color).view);
+        if let Some(depth_format) = desc.depth_format {
+            let usage = desc.depth_usage | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+            let depth = Texture::multisampled(
+                device,
+                mem_props,
+                depth_format,
+                desc.extent,
+                desc.samples,
+                usage,
+            )?;
+            views.push(self.depth.insert(depth).view);
+        }
+        if desc.is_multisampled() {
+            let resolve = Texture::empty(
+                device,
+                mem_props,
+                desc.color_format,
+                desc.extent,
+                desc.color_usage | vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            )?;
+            views.push(self.resolve.insert(resolve).view);
+        }
+        self.framebuffer = device.create_framebuffer(
+            &vk::FramebufferCreateInfo::builder()
+                .render_pass(self.render_pass)
+                .attachments(&views)
+                .width(desc.extent.width)
+                .height(desc.extent.height)
+                .layers(1),
+            None,
+        )?;
+        Ok(())
+    }
+
+    unsafe fn destroy_images(&mut self, device: &Device) {
+        device.destroy_framebuffer(self.framebuffer, None);
+        self.framebuffer = vk::Framebuffer::null();
+        for texture in [&mut self.color, &mut self.depth, &mut self.resolve] {
+            if let Some(texture) = texture.take() {
+                texture.destroy(device);
+            }
+        }
+    }
+
+    /// Recreates the images and framebuffer at `extent`, the render pass stays the same
+    ///
+    /// # Safety
+    ///
+    /// No recorded pass rendering into or reading from the target may still be executing.
+    pub unsafe fn resize(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+    ) -> VkResult<()> {
+        self.destroy_images(device);
+        self.desc.extent = extent;
+        self.create_images(device, mem_props)
+    }
+
+    pub fn desc(&self) -> &RenderTargetDesc {
+        &self.desc
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.desc.extent
+    }
+
+    pub fn samples(&self) -> vk::SampleCountFlags {
+        self.desc.samples
+    }
+
+    /// Render pass to build pipelines against, subpass 0 writes the target
+    pub fn render_pass(&self) -> vk::RenderPass {
+        self.render_pass
+    }
+
+    pub fn framebuffer(&self) -> vk::Framebuffer {
+        self.framebuffer
+    }
+
+    /// The single sampled color later passes read, resolved if the target is multisampled
+    pub fn color(&self) -> &Texture {
+        self.resolve
+            .as_ref()
+            .or(self.color.as_ref())
+            .expect("render target images exist after creation")
+    }
+
+    pub fn depth(&self) -> Option<&Texture> {
+        self.depth.as_ref()
+    }
+
+    /// Begins the target's render pass with viewport and scissor covering it
+    pub fn begin(&self, device: &Device, cmd: vk::CommandBuffer, clear: Vec4) {
+        let color_clear = vk::ClearValue {
+            color: vk::ClearColorValue {
+                float32: clear.to_array(),
+            },
+        };
+        let depth_clear = vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0,
+            },
+        };
+        // One per attachment, the resolve's is ignored
+        let clear_values = [color_clear, depth_clear, color_clear];
+        let attachments =
+            1 + self.desc.depth_format.is_some() as usize + self.desc.is_multisampled() as usize;
+        let area = vk::Rect2D {
+            offset: vk::Offset2D::default(),
+            extent: self.desc.extent,
+        };
+        unsafe {
+            device.cmd_begin_render_pass(
+                cmd,
+                &vk::RenderPassBeginInfo::builder()
+                    .render_pass(self.render_pass)
+                    .framebuffer(self.framebuffer)
+                    .render_area(area)
+                    .clear_values(&clear_values[..attachments]),
+                vk::SubpassContents::INLINE,
+            );
+            device.cmd_set_viewport(
+                cmd,
+                0,
+                &[vk::Viewport {
+                    x: 0.0,
+                    y: 0.0,
+                    width: self.desc.extent.width as f32,
+                    height: self.desc.extent.height as f32,
+                    min_depth: 0.0,
+                    max_depth: 1.0,
+                }],
+            );
+            device.cmd_set_scissor(cmd, 0, &[area]);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded pass rendering into or reading from the target may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.destroy_images(device);
+        device.destroy_render_pass(self.render_pass, None);
+    }
+}
@@ -0,0 +1,728 @@
+//! Heightmap terrain split into chunks, each drawn at a level of detail picked by distance
+//!
+//! Every chunk is a grid of `chunk_cells` squared cells over the heightmap. Coarser levels skip
+//! every other row and column of the level before, sharing one index buffer across all chunks, so
+//! a level change only moves the draw to another index range. Neighbours at different levels
+//! would leave cracks along their shared edge, which the skirts hanging down from every chunk's
+//! border cover up. The fragment shader blends four tiled layers by the weights of a splat map.
+
+use std::{
+    error::Error,
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    animation::skinning::MeshVertex,
+    culling::CullStats,
+    geometry::{Aabb, Frustum},
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{staging::StagingBelt, Buffer},
+    mesh::upload_buffer,
+    shader::{reflect, ShaderCompiler},
+    texture::sampler::{SamplerCache, SamplerDesc},
+    vertex::VertexInput,
+};
+
+pub const TERRAIN_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    vec4 sun;
+} push;
+
+layout(location = 0) out vec3 out_normal;
+layout(location = 1) out vec2 out_uv;
+
+void main() {
+    gl_Position = push.view_proj * vec4(position, 1.0);
+    out_normal = normal;
+    out_uv = uv;
+}
+"#;
+
+/// Splat map channels weigh the four layers of the layer array
+pub const TERRAIN_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 0) uniform texture2D splat;
+layout(set = 0, binding = 1) uniform texture2DArray layers;
+layout(set = 0, binding = 2) uniform sampler terrain_sampler;
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    // xyz: direction the light travels, w: times the layers repeat across the terrain
+    vec4 sun;
+} push;
+
+layout(location = 0) in vec3 normal;
+layout(location = 1) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec4 weights = texture(sampler2D(splat, terrain_sampler), uv);
+    weights /= max(dot(weights, vec4(1.0)), 0.0001);
+    vec2 tiled = uv * push.sun.w;
+    vec3 color = vec3(0.0);
+    for (int i = 0; i < 4; i++) {
+        vec3 layer = texture(sampler2DArray(layers, terrain_sampler), vec3(tiled, float(i))).rgb;
+        color += layer * weights[i];
+    }
+    float light = max(dot(normalize(normal), -normalize(push.sun.xyz)), 0.0) * 0.8 + 0.2;
+    out_color = vec4(color * light, 1.0);
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum TerrainError {
+    #[error("failed to read {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+    #[error(transparent)]
+    Png(#[from] png::DecodingError),
+    #[error("heightmap needs at least 2x2 samples, got {0}x{1}")]
+    TooSmall(u32, u32),
+    #[error("failed to compile terrain shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Grid of heights between zero and one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub width: u32,
+    pub depth: u32,
+    /// Row by row, `width` samples each
+    pub heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, depth: u32, heights: Vec<f32>) -> Result<Self, TerrainError> {
+        if width < 2 || depth < 2 {
+            return Err(TerrainError::TooSmall(width, depth));
+        }
+        assert_eq!(
+            heights.len(),
+            width as usize * depth as usize,
+            "heights must fill the grid"
+        );
+        Ok(Heightmap {
+            width,
+            depth,
+            heights,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TerrainError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).map_err(|source| TerrainError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        Heightmap::from_png(&bytes)
+    }
+
+    /// Decodes a PNG, taking the first channel of 8 or 16 bit images as the height
+    pub fn from_png(bytes: &[u8]) -> Result<Self, TerrainError> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels)?;
+        let channels = info.color_type.samples();
+        let heights = match info.bit_depth {
+            png::BitDepth::Sixteen => pixels[..info.buffer_size()]
+                .chunks_exact(2 * channels)
+                .map(|pixel| u16::from_be_bytes([pixel[0], pixel[1]]) as f32 / 65535.0)
+                .collect(),
+            // Expanded to 8 bits
+            _ => pixels[..info.buffer_size()]
+                .chunks_exact(channels)
+                .map(|pixel| pixel[0] as f32 / 255.0)
+                .collect(),
+        };
+        Heightmap::new(info.width, info.height, heights)
+    }
+
+    /// Height at a sample, clamped to the edges
+    pub fn height(&self, x: i64, z: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let z = z.clamp(0, self.depth as i64 - 1) as usize;
+        self.heights[z * self.width as usize + x]
+    }
+
+    /// Bilinearly filtered height between samples
+    pub fn sample(&self, x: f32, z: f32) -> f32 {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (tx, tz) = (x - x0, z - z0);
+        let (x0, z0) = (x0 as i64, z0 as i64);
+        let top = self.height(x0, z0) * (1.0 - tx) + self.height(x0 + 1, z0) * tx;
+        let bottom = self.height(x0, z0 + 1) * (1.0 - tx) + self.height(x0 + 1, z0 + 1) * tx;
+        top * (1.0 - tz) + bottom * tz
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// World units between heightmap samples
+    pub cell_size: f32,
+    /// World height of a sample at one
+    pub height_scale: f32,
+    /// Cells along each side of a chunk, rounded up to a power of two
+    pub chunk_cells: u32,
+    /// Levels of detail, each halving the cells of the one before
+    pub lod_levels: u32,
+    /// Distance where chunks drop to the first coarser level, doubling for each level after
+    pub lod_distance: f32,
+    /// How far the skirts hang below the chunk edges
+    pub skirt_depth: f32,
+}
+
+impl TerrainConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        TerrainConfig {
+            render_pass,
+            subpass: 0,
+            cell_size: 1.0,
+            height_scale: 64.0,
+            chunk_cells: 64,
+            lod_levels: 4,
+            lod_distance: 128.0,
+            skirt_depth: 4.0,
+        }
+    }
+}
+
+/// Views sampled by [`TERRAIN_FRAG_GLSL`]
+#[derive(Debug, Clone, Copy)]
+pub struct TerrainTextures {
+    /// RGBA weights of the four layers, stretched over the whole terrain
+    pub splat: vk::ImageView,
+    /// 2D array view with at least four layers
+    pub layers: vk::ImageView,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct TerrainPush {
+    view_proj: Mat4,
+    sun: Vec4,
+}
+
+/// A chunk in view and the level it's drawn at, from [`Terrain::select`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkDraw {
+    pub chunk: usize,
+    pub lod: u32,
+}
+
+pub struct Terrain {
+    /// Direction the light travels
+    pub sun: Vec3,
+    /// Times the layer textures repeat across the terrain
+    pub tiling: f32,
+    heightmap: Heightmap,
+    cell_size: f32,
+    height_scale: f32,
+    lod_distance: f32,
+    /// World space corner at the first sample
+    origin: Vec3,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    vertices: Option<Buffer>,
+    indices: Option<Buffer>,
+    /// World space bounds of every chunk
+    chunks: Vec<Aabb>,
+    vertices_per_chunk: u32,
+    /// Index range of each level of detail
+    lods: Vec<Range<u32>>,
+}
+
+impl Terrain {
+    /// Builds the chunks centred on the origin and records their upload into `cmd`
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        heightmap: Heightmap,
+        textures: TerrainTextures,
+        config: TerrainConfig,
+    ) -> Result<Self, TerrainError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| TerrainError::Compile(Box::new(err)))
+        };
+        let vert = compile(TERRAIN_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(TERRAIN_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(device, &SamplerDesc::default())?;
+
+        let cells = config.chunk_cells.max(1).next_power_of_two();
+        let extent = Vec2::new((heightmap.width - 1) as f32, (heightmap.depth - 1) as f32)
+            * config.cell_size;
+        let mut this = Terrain {
+            sun: Vec3::new(-0.4, -1.0, -0.3),
+            tiling: 32.0,
+            cell_size: config.cell_size,
+            height_scale: config.height_scale,
+            lod_distance: config.lod_distance,
+            origin: Vec3::new(-extent.x / 2.0, 0.0, -extent.y / 2.0),
+            heightmap,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            vertices: None,
+            indices: None,
+            chunks: Vec::new(),
+            vertices_per_chunk: 0,
+            lods: Vec::new(),
+        };
+        let result = unsafe {
+            this.build_chunks(device, mem_props, staging, cmd, cells, config)
+                .map_err(TerrainError::from)
+                .and_then(|_| this.create_objects(device, &vert, &frag, sampler, textures, config))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    fn build_chunks(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        cells: u32,
+        config: TerrainConfig,
+    ) -> VkResult<()> {
+        let side = cells + 1;
+        let chunks_x = (self.heightmap.width - 1).div_ceil(cells);
+        let chunks_z = (self.heightmap.depth - 1).div_ceil(cells);
+        let uv_scale = Vec2::new(
+            1.0 / (self.heightmap.width - 1) as f32,
+            1.0 / (self.heightmap.depth - 1) as f32,
+        );
+
+        let mut vertices = Vec::new();
+        for chunk_z in 0..chunks_z {
+            for chunk_x in 0..chunks_x {
+                let first = vertices.len();
+                for z in 0..side {
+                    for x in 0..side {
+                        let sample = (chunk_x * cells + x, chunk_z * cells + z);
+                        vertices.push(self.vertex(sample.0 as i64, sample.1 as i64, uv_scale));
+                    }
+                }
+                // Skirts along the z = 0, x = max, z = max and x = 0 edges
+                for edge in 0..4 {
+                    for t in 0..side {
+                        let mut vertex = vertices[first + edge_vertex(edge, t, cells) as usize];
+                        vertex.position.y -= config.skirt_depth;
+                        vertices.push(vertex);
+                    }
+                }
+                let bounds = Aabb::from_points(vertices[first..].iter().map(|v| v.position))
+                    .expect("chunks have vertices");
+                self.chunks.push(bounds);
+            }
+        }
+        self.vertices_per_chunk = side * side + 4 * side;
+
+        let levels = config.lod_levels.clamp(1, cells.trailing_zeros() + 1);
+        let mut indices = Vec::new();
+        for level in 0..levels {
+            let start = indices.len() as u32;
+            lod_indices(cells, 1 << level, &mut indices);
+            self.lods.push(start..indices.len() as u32);
+        }
+
+        self.vertices = Some(upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(&vertices),
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?);
+        self.indices = Some(upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(&indices),
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?);
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ | vk::AccessFlags::INDEX_READ)
+            .build();
+        unsafe {
+            device.cmd_pipeline_barrier(
+                cmd,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            )
+        };
+        Ok(())
+    }
+
+    /// Vertex at a heightmap sample, with the normal from its neighbours' heights
+    fn vertex(&self, x: i64, z: i64, uv_scale: Vec2) -> MeshVertex {
+        let map = &self.heightmap;
+        let slope_x = (map.height(x - 1, z) - map.height(x + 1, z)) * self.height_scale;
+        let slope_z = (map.height(x, z - 1) - map.height(x, z + 1)) * self.height_scale;
+        let grid = Vec2::new(x as f32, z as f32);
+        MeshVertex {
+            position: self.origin
+                + Vec3::new(
+                    grid.x * self.cell_size,
+                    map.height(x, z) * self.height_scale,
+                    grid.y * self.cell_size,
+                ),
+            normal: Vec3::new(slope_x, 2.0 * self.cell_size, slope_z).normalize(),
+            uv: grid * uv_scale,
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        vert: &[u32],
+        frag: &[u32],
+        sampler: vk::Sampler,
+        textures: TerrainTextures,
+        config: TerrainConfig,
+    ) -> Result<(), TerrainError> {
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "terrain descriptor set layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 2,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "terrain descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+        let image = |image_view| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }]
+        };
+        let (splat, layers) = (image(textures.splat), image(textures.layers));
+        let samplers = [vk::DescriptorImageInfo {
+            sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let writes: Vec<_> = (0..)
+            .zip(types)
+            .zip([&splat, &layers, &samplers])
+            .map(|((binding, ty), info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(ty)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        device.update_descriptor_sets(&writes, &[]);
+
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: std::mem::size_of::<TerrainPush>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "terrain pipeline layout");
+
+        let attributes = MeshVertex::attributes(0, 0);
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[push_range], &attributes)?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "terrain shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "terrain shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_bindings = [MeshVertex::binding(0)];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&attributes);
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        // Skirts are seen from both sides depending on which neighbour is coarser
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "terrain");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    pub fn chunk_bounds(&self) -> &[Aabb] {
+        &self.chunks
+    }
+
+    /// World space height of the surface under `x`, `z`, for placing things on the ground
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let local = (Vec2::new(x, z) - Vec2::new(self.origin.x, self.origin.z)) / self.cell_size;
+        self.origin.y + self.heightmap.sample(local.x, local.y) * self.height_scale
+    }
+
+    /// Chunks inside `frustum`, each with the level of detail for its distance from `camera`
+    pub fn select(&self, frustum: &Frustum, camera: Vec3, out: &mut Vec<ChunkDraw>) -> CullStats {
+        out.clear();
+        let last = self.lods.len() as u32 - 1;
+        for (chunk, bounds) in self.chunks.iter().enumerate() {
+            if !frustum.intersects_aabb(bounds) {
+                continue;
+            }
+            let distance = camera.distance(camera.clamp(bounds.min, bounds.max));
+            let lod = (distance / self.lod_distance).max(1.0).log2() as u32;
+            out.push(ChunkDraw {
+                chunk,
+                lod: lod.min(last),
+            });
+        }
+        CullStats {
+            submitted: out.len() as u32,
+            culled: (self.chunks.len() - out.len()) as u32,
+        }
+    }
+
+    /// Records the chunks picked by [`Terrain::select`]
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        view_proj: Mat4,
+        chunks: &[ChunkDraw],
+    ) {
+        let (Some(vertices), Some(indices)) = (&self.vertices, &self.indices) else {
+            return;
+        };
+        let push = TerrainPush {
+            view_proj,
+            sun: self.sun.extend(self.tiling),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_bind_vertex_buffers(cmd, 0, &[vertices.buffer], &[0]);
+            device.cmd_bind_index_buffer(cmd, indices.buffer, 0, vk::IndexType::UINT32);
+            for draw in chunks {
+                let Some(range) = self.lods.get(draw.lod as usize) else {
+                    continue;
+                };
+                device.cmd_draw_indexed(
+                    cmd,
+                    range.len() as u32,
+                    1,
+                    range.start,
+                    (draw.chunk as u32 * self.vertices_per_chunk) as i32,
+                    0,
+                );
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded draw may still be executing. The textures are left to their owner.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in [&mut self.vertices, &mut self.indices] {
+            if let Some(buffer) = buffer.take() {
+                buffer.destroy(device);
+            }
+        }
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Index of the `t`-th grid vertex along one edge of a chunk
+fn edge_vertex(edge: u32, t: u32, cells: u32) -> u32 {
+    let side = cells + 1;
+    match edge {
+        0 => t,
+        1 => t * side + cells,
+        2 => cells * side + t,
+        _ => t * side,
+    }
+}
+
+/// Triangles of a chunk using every `step`-th row and column, skirts included
+fn lod_indices(cells: u32, step: u32, out: &mut Vec<u32>) {
+    let side = cells + 1;
+    for z in (0..cells).step_by(step as usize) {
+        for x in (0..cells).step_by(step as usize) {
+            let a = z * side + x;
+            let b = a + step;
+            let c = a + step * side;
+            let d = c + step;
+            out.extend_from_slice(&[a, c, b, b, c, d]);
+        }
+    }
+    let skirts = side * side;
+    for edge in 0..4 {
+        for t in (0..cells).step_by(step as usize) {
+            let (top_a, top_b) = (
+                edge_vertex(edge, t, cells),
+                edge_vertex(edge, t + step, cells),
+            );
+            let bottom_a = skirts + edge * side + t;
+            let bottom_b = bottom_a + step;
+            out.extend_from_slice(&[top_a, bottom_a, top_b, top_b, bottom_a, bottom_b]);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! A Vulkan context without a window, for testing the library's GPU code
+//!
+//! [`HeadlessContext`] creates an instance without surface extensions and a device on the best
+//! scoring adapter, software ones like lavapipe included, so buffers, descriptors and render
+//! graphs can be exercised off the main binary. Machines without a Vulkan loader or device skip
+//! the tests using [`HeadlessContext::for_test`], unless [`REQUIRE_DEVICE_ENV`] is set, as it
+//! should be on CI runners that do have one.
+
+use std::ffi::CStr;
+
+use ash::{prelude::VkResult, vk, Device, Entry, Instance};
+use thiserror::Error;
+
+use crate::device::{
+    instance_version, DeviceInfo, DeviceRequirements, EnabledFeatures, Feature, QueueFamilies,
+    Queues, ScoringPolicy,
+};
+
+/// Environment variable making a missing device fail tests instead of skipping them
+pub const REQUIRE_DEVICE_ENV: &str = "VULKAN_THING_REQUIRE_DEVICE";
+
+#[derive(Debug, Error)]
+pub enum HeadlessError {
+    #[error("no Vulkan loader is installed")]
+    NoLoader,
+    #[error("no Vulkan device meets the requirements")]
+    NoDevice,
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+pub struct HeadlessContext {
+    pub entry: Entry,
+    pub instance: Instance,
+    pub physical_device: vk::PhysicalDevice,
+    pub info: DeviceInfo,
+    pub device: Device,
+    pub enabled: EnabledFeatures,
+    /// Without a surface, presenting falls to the graphics family
+    pub queue_families: QueueFamilies,
+    pub queues: Queues,
+    pub mem_props: vk::PhysicalDeviceMemoryProperties,
+    command_pool: vk::CommandPool,
+}
+
+impl HeadlessContext {
+    /// What [`HeadlessContext::for_test`] asks of devices, the commonly used features where
+    /// supported
+    pub fn default_requirements() -> DeviceRequirements {
+        DeviceRequirements::default().request_features(&[
+            Feature::BufferDeviceAddress,
+            Feature::Synchronization2,
+            Feature::DynamicRendering,
+            Feature::TimelineSemaphore,
+        ])
+    }
+
+    pub fn new(requirements: &DeviceRequirements) -> Result<Self, HeadlessError> {
+        let entry = Entry::linked();
+        // Without a loader behind the linked library every entry point is missing
+        let create_instance = unsafe {
+            (entry.static_fn().get_instance_proc_addr)(
+                vk::Instance::null(),
+                c"vkCreateInstance".as_ptr(),
+            )
+        };
+        if create_instance.is_none() {
+            return Err(HeadlessError::NoLoader);
+        }
+
+        let api_version = instance_version(&entry)?;
+        let app_info = vk::ApplicationInfo::builder()
+            .application_name(c"vulkan-thing tests")
+            .api_version(api_version);
+        let create_info = vk::InstanceCreateInfo::builder().application_info(&app_info);
+        let instance = match unsafe { entry.create_instance(&create_info, None) } {
+            Ok(instance) => instance,
+            Err(vk::Result::ERROR_INCOMPATIBLE_DRIVER) => return Err(HeadlessError::NoDevice),
+            Err(err) => return Err(err.into()),
+        };
+        let (physical_device, info, enabled, queue_families, device) =
+            match Self::create_device(&instance, api_version, requirements) {
+                Ok(created) => created,
+                Err(err) => {
+                    unsafe { instance.destroy_instance(None) };
+                    return Err(err);
+                }
+            };
+        let queues = unsafe { Queues::get(&device, &queue_families) };
+
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(queue_families.graphics);
+        let command_pool = match unsafe { device.create_command_pool(&pool_info, None) } {
+            Ok(pool) => pool,
+            Err(err) => {
+                unsafe {
+                    device.destroy_device(None);
+                    instance.destroy_instance(None);
+                }
+                return Err(err.into());
+            }
+        };
+        let mem_props = info.memory;
+        Ok(HeadlessContext {
+            entry,
+            instance,
+            physical_device,
+            info,
+            device,
+            enabled,
+            queue_families,
+            queues,
+            mem_props,
+            command_pool,
+        })
+    }
+
+    /// Picks the best scoring device meeting `requirements` and creates it
+    fn create_device(
+        instance: &Instance,
+        api_version: u32,
+        requirements: &DeviceRequirements,
+    ) -> Result<
+        (
+            vk::PhysicalDevice,
+            DeviceInfo,
+            EnabledFeatures,
+            QueueFamilies,
+            Device,
+        ),
+        HeadlessError,
+    > {
+        let devices = unsafe { instance.enumerate_physical_devices()? };
+        let policy = ScoringPolicy::default();
+        let best = devices
+            .into_iter()
+            .filter_map(|physical_device| {
+                let info = unsafe { DeviceInfo::query(instance, api_version, physical_device) };
+                let enabled = requirements.negotiate(&info).ok()?;
+                let queue_families = QueueFamilies::select(&info.queue_families, |_| true)?;
+                Some((physical_device, info, enabled, queue_families))
+            })
+            .max_by_key(|(_, info, _, _)| policy.score(info));
+        let Some((physical_device, info, enabled, queue_families)) = best else {
+            return Err(HeadlessError::NoDevice);
+        };
+
+        let priorities = [1.0];
+        let queue_infos: Vec<_> = queue_families
+            .unique()
+            .into_iter()
+            .map(|family| {
+                vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(family)
+                    .queue_priorities(&priorities)
+                    .build()
+            })
+            .collect();
+        let extensions: Vec<_> = enabled.extensions.iter().map(|ext| ext.as_ptr()).collect();
+        let mut features = enabled.features.clone();
+        let mut features2 = features.chain();
+        let mut device_info = vk::DeviceCreateInfo::builder()
+            .queue_create_infos(&queue_infos)
+            .enabled_extension_names(&extensions);
+        // The pNext chain needs 1.1, before that only the core features can be enabled
+        device_info = if enabled.features.api_version >= vk::API_VERSION_1_1 {
+            device_info.push_next(&mut features2)
+        } else {
+            device_info.enabled_features(&enabled.features.core)
+        };
+        let device = unsafe { instance.create_device(physical_device, &device_info, None)? };
+        Ok((physical_device, info, enabled, queue_families, device))
+    }
+
+    /// A context with [`HeadlessContext::default_requirements`], `None` to skip the calling test
+    ///
+    /// # Panics
+    ///
+    /// On Vulkan errors other than a missing loader or device, and on those too when
+    /// [`REQUIRE_DEVICE_ENV`] is set.
+    #[track_caller]
+    pub fn for_test() -> Option<Self> {
+        match Self::new(&Self::default_requirements()) {
+            Ok(context) => Some(context),
+            Err(err @ (HeadlessError::NoLoader | HeadlessError::NoDevice))
+                if std::env::var_os(REQUIRE_DEVICE_ENV).is_none() =>
+            {
+                eprintln!("skipping, {err}");
+                None
+            }
+            Err(err) => panic!("couldn't create a headless context: {err}"),
+        }
+    }
+
+    /// The device's name, e.g. to tell software rasterizers apart in test output
+    pub fn device_name(&self) -> &str {
+        &self.info.name
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.enabled.has_extension(name)
+    }
+
+    /// Records commands with `record`, submits them to the graphics queue and waits for them
+    pub fn one_shot<T>(&self, record: impl FnOnce(vk::CommandBuffer) -> T) -> VkResult<T> {
+        let device = &self.device;
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let cmd = unsafe { device.allocate_command_buffers(&alloc_info)?[0] };
+        let result = unsafe {
+            let begin_info = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            device.begin_command_buffer(cmd, &begin_info).and_then(|_| {
+                let value = record(cmd);
+                device.end_command_buffer(cmd)?;
+                let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+                let cmds = [cmd];
+                let submit = vk::SubmitInfo::builder().command_buffers(&cmds);
+                let waited = device
+                    .queue_submit(self.queues.graphics, &[*submit], fence)
+                    .and_then(|_| device.wait_for_fences(&[fence], true, u64::MAX));
+                device.destroy_fence(fence, None);
+                waited.map(|_| value)
+            })
+        };
+        unsafe { device.free_command_buffers(self.command_pool, &[cmd]) };
+        result
+    }
+}
+
+impl Drop for HeadlessContext {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.device.device_wait_idle();
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device.destroy_device(None);
+            self.instance.destroy_instance(None);
+        }
+    }
+}
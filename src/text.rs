@@ -0,0 +1,347 @@
+//! Text drawn through [`Renderer2D`] from a glyph atlas that fontdue rasterizes into on demand
+//!
+//! Atlases in [`GlyphMode::Sdf`] hold distance fields instead, drawn with
+//! [`sdf::SDF_TEXT_FRAG_GLSL`] at any size from a single em size per glyph.
+
+use std::collections::HashMap;
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Vec2, Vec4};
+use thiserror::Error;
+
+use crate::{
+    memory::staging::StagingBelt,
+    render2d::{Renderer2D, Sprite},
+    texture::{
+        atlas::{Rect, RectPacker},
+        Texture, TextureData,
+    },
+};
+
+pub mod sdf;
+
+#[derive(Debug, Error)]
+pub enum TextError {
+    #[error("invalid font: {0}")]
+    Font(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FontId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    font: FontId,
+    index: u16,
+    px: u32,
+}
+
+/// Where a rasterized glyph lives in the atlas and how it sits on the baseline
+#[derive(Debug, Clone, Copy)]
+struct GlyphEntry {
+    rect: Rect,
+    xmin: i32,
+    ymin: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GlyphMode {
+    /// White RGBA glyphs with coverage in alpha, so they draw with the plain sprite pipeline
+    Coverage,
+    /// Multi-channel distance fields generated at `em_px` and scaled to any size when drawn
+    Sdf { em_px: f32, range: f32 },
+}
+
+pub struct GlyphAtlas {
+    pub texture: Texture,
+    size: u32,
+    mode: GlyphMode,
+    packer: RectPacker,
+    fonts: Vec<fontdue::Font>,
+    /// Font files kept for their outlines, distance fields are generated from those
+    sources: Vec<Vec<u8>>,
+    glyphs: HashMap<GlyphKey, Option<GlyphEntry>>,
+    pending: Vec<(Rect, Vec<u8>)>,
+}
+
+impl GlyphAtlas {
+    /// Creates an empty `size` by `size` atlas, clearing it through `cmd`
+    pub fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        size: u32,
+        mode: GlyphMode,
+    ) -> VkResult<Self> {
+        let data = TextureData {
+            format: vk::Format::R8G8B8A8_UNORM,
+            extent: vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            },
+            layers: 1,
+            cube: false,
+            levels: vec![vec![0; size as usize * size as usize * 4]],
+        };
+        Ok(GlyphAtlas {
+            texture: Texture::upload(device, mem_props, staging, cmd, &data)?,
+            size,
+            mode,
+            packer: RectPacker::new(size, size),
+            fonts: Vec::new(),
+            sources: Vec::new(),
+            glyphs: HashMap::new(),
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn add_font(&mut self, bytes: &[u8]) -> Result<FontId, TextError> {
+        let font = fontdue::Font::from_bytes(bytes, fontdue::FontSettings::default())
+            .map_err(TextError::Font)?;
+        self.fonts.push(font);
+        if let GlyphMode::Sdf { .. } = self.mode {
+            self.sources.push(bytes.to_vec());
+        }
+        Ok(FontId(self.fonts.len() - 1))
+    }
+
+    pub fn font(&self, id: FontId) -> &fontdue::Font {
+        &self.fonts[id.0]
+    }
+
+    pub fn mode(&self) -> GlyphMode {
+        self.mode
+    }
+
+    /// Size glyphs of `px` text are stored at, scaled up or down to `px` when drawn
+    fn stored_px(&self, px: f32) -> f32 {
+        match self.mode {
+            GlyphMode::Coverage => px,
+            GlyphMode::Sdf { em_px, .. } => em_px,
+        }
+    }
+
+    /// Rasterizes the glyph the first time it's seen, `None` for blank glyphs or a full atlas
+    fn glyph(&mut self, font: FontId, index: u16, px: f32) -> Option<GlyphEntry> {
+        let key = GlyphKey {
+            font,
+            index,
+            px: px.to_bits(),
+        };
+        if let Some(entry) = self.glyphs.get(&key) {
+            return *entry;
+        }
+        let (width, height, xmin, ymin, texels) = match self.mode {
+            GlyphMode::Coverage => {
+                let (metrics, coverage) = self.fonts[font.0].rasterize_indexed(index, px);
+                let texels = coverage.iter().flat_map(|&a| [255, 255, 255, a]).collect();
+                let (width, height) = (metrics.width as u32, metrics.height as u32);
+                (width, height, metrics.xmin, metrics.ymin, texels)
+            }
+            GlyphMode::Sdf { range, .. } => {
+                match sdf::generate(&self.sources[font.0], index, px, range) {
+                    Some(glyph) => (
+                        glyph.width,
+                        glyph.height,
+                        glyph.xmin,
+                        glyph.ymin,
+                        glyph.texels,
+                    ),
+                    None => (0, 0, 0, 0, Vec::new()),
+                }
+            }
+        };
+        let entry = if width == 0 || height == 0 {
+            None
+        } else {
+            // One texel of padding keeps filtering from picking up neighbours
+            let slot = self.packer.pack(width + 2, height + 2)?;
+            let rect = Rect {
+                x: slot.x + 1,
+                y: slot.y + 1,
+                width,
+                height,
+            };
+            self.pending.push((rect, texels));
+            Some(GlyphEntry { rect, xmin, ymin })
+        };
+        self.glyphs.insert(key, entry);
+        entry
+    }
+
+    /// Records uploads of glyphs rasterized since the last flush, outside of any render pass
+    pub fn flush(
+        &mut self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+    ) -> VkResult<()> {
+        for (rect, texels) in self.pending.drain(..) {
+            let rect = vk::Rect2D {
+                offset: vk::Offset2D {
+                    x: rect.x as i32,
+                    y: rect.y as i32,
+                },
+                extent: vk::Extent2D {
+                    width: rect.width,
+                    height: rect.height,
+                },
+            };
+            self.texture
+                .write_region(device, staging, cmd, 0, rect, &texels)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every glyph, for when the atlas filled up with sizes no longer used
+    pub fn clear(&mut self) {
+        self.packer = RectPacker::new(self.size, self.size);
+        self.glyphs.clear();
+        self.pending.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TextStyle {
+    pub px: f32,
+    pub color: Vec4,
+    /// Wraps at spaces, or mid word when a word alone is too wide
+    pub max_width: Option<f32>,
+    /// Multiplier on the font's line height
+    pub line_spacing: f32,
+    pub layer: i32,
+}
+
+impl TextStyle {
+    pub fn new(px: f32) -> Self {
+        TextStyle {
+            px,
+            color: Vec4::ONE,
+            max_width: None,
+            line_spacing: 1.0,
+            layer: 0,
+        }
+    }
+
+    pub fn with_color(mut self, color: Vec4) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_max_width(mut self, max_width: f32) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+}
+
+/// A glyph placed on its baseline, relative to the top left of the text block
+#[derive(Debug, Clone, Copy)]
+pub struct PositionedGlyph {
+    pub index: u16,
+    pub pen: Vec2,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TextLayout {
+    pub glyphs: Vec<PositionedGlyph>,
+    pub size: Vec2,
+}
+
+/// Lays out `text` with kerning and greedy wrapping
+pub fn layout(font: &fontdue::Font, text: &str, style: &TextStyle) -> TextLayout {
+    let (ascent, line_height) = match font.horizontal_line_metrics(style.px) {
+        Some(line) => (line.ascent, line.new_line_size * style.line_spacing),
+        None => (style.px, style.px * style.line_spacing),
+    };
+    let max_width = style.max_width.unwrap_or(f32::INFINITY);
+
+    let mut glyphs: Vec<PositionedGlyph> = Vec::new();
+    let mut pen = Vec2::new(0.0, ascent);
+    let mut width: f32 = 0.0;
+    // First glyph after the last space on this line, and the pen x it starts at
+    let mut line_break: Option<(usize, f32)> = None;
+    let mut line_start = 0;
+    let mut prev = None;
+    for c in text.chars() {
+        if c == '\n' {
+            pen = Vec2::new(0.0, pen.y + line_height);
+            line_break = None;
+            line_start = glyphs.len();
+            prev = None;
+            continue;
+        }
+        let index = font.lookup_glyph_index(c);
+        if let Some(prev) = prev {
+            pen.x += font
+                .horizontal_kern_indexed(prev, index, style.px)
+                .unwrap_or(0.0);
+        }
+        let metrics = font.metrics_indexed(index, style.px);
+
+        if c.is_whitespace() {
+            pen.x += metrics.advance_width;
+            line_break = Some((glyphs.len(), pen.x));
+            prev = Some(index);
+            continue;
+        }
+        if pen.x + metrics.advance_width > max_width && glyphs.len() > line_start {
+            // Carry the current word down, or just this glyph if the word fills the line
+            let (start, shift) = match line_break {
+                Some((start, x)) if start > line_start => (start, x),
+                _ => (glyphs.len(), pen.x),
+            };
+            for glyph in &mut glyphs[start..] {
+                glyph.pen += Vec2::new(-shift, line_height);
+            }
+            pen = Vec2::new(pen.x - shift, pen.y + line_height);
+            line_break = None;
+            line_start = start;
+        }
+        glyphs.push(PositionedGlyph { index, pen });
+        pen.x += metrics.advance_width;
+        width = width.max(pen.x);
+        prev = Some(index);
+    }
+
+    TextLayout {
+        glyphs,
+        size: Vec2::new(width, pen.y - ascent + line_height),
+    }
+}
+
+/// Queues sprites drawing `text` with its top left corner at `position`, in pixels
+///
+/// New glyphs are only in the atlas after [`GlyphAtlas::flush`] has been recorded. Text from a
+/// [`GlyphMode::Sdf`] atlas needs a renderer drawing with [`sdf::SDF_TEXT_FRAG_GLSL`].
+pub fn draw_text(
+    renderer: &mut Renderer2D,
+    atlas: &mut GlyphAtlas,
+    font: FontId,
+    text: &str,
+    position: Vec2,
+    style: &TextStyle,
+) -> TextLayout {
+    let layout = layout(atlas.font(font), text, style);
+    let size = atlas.size as f32;
+    let stored_px = atlas.stored_px(style.px);
+    let scale = style.px / stored_px;
+    for glyph in &layout.glyphs {
+        let Some(entry) = atlas.glyph(font, glyph.index, stored_px) else {
+            continue;
+        };
+        let texels = Vec2::new(entry.rect.width as f32, entry.rect.height as f32);
+        let extent = texels * scale;
+        // Bitmaps are placed from their bottom left relative to the baseline, y grows up
+        let offset = Vec2::new(entry.xmin as f32, -(entry.ymin as f32)) * scale;
+        let top_left = position + glyph.pen + offset - Vec2::new(0.0, extent.y);
+        let uv_min = Vec2::new(entry.rect.x as f32, entry.rect.y as f32) / size;
+        let sprite = Sprite::new(top_left + extent * 0.5, extent)
+            .with_uv(uv_min, uv_min + texels / size)
+            .with_color(style.color)
+            .with_layer(style.layer);
+        renderer.draw(atlas.texture.view, sprite);
+    }
+    layout
+}
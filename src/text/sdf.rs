@@ -0,0 +1,429 @@
+//! Multi-channel signed distance fields generated from glyph outlines at load, so text stays crisp
+//! at any scale
+//!
+//! Outlines are flattened and split into edges at their corners, and neighbouring edges get
+//! different colours. Each of red, green and blue holds the distance to the nearest edge of its
+//! colour, so the median of the three keeps corners sharp where a single distance would round them
+//! off. Alpha holds the true distance, which the outline and shadow effects grow from.
+
+use glam::{Vec2, Vec4};
+use ttf_parser::{Face, GlyphId, OutlineBuilder};
+
+use crate::layout::{AsBytes, ShaderLayout};
+
+/// Drop-in fragment shader for [`Renderer2D`](crate::render2d::Renderer2D), pair it with
+/// [`SdfEffects`] as the fragment parameters
+pub const SDF_TEXT_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 0) uniform texture2D glyphs;
+layout(set = 0, binding = 1) uniform sampler glyph_sampler;
+
+// Shares the vertex shader's block, whose projection comes first
+layout(push_constant) uniform Effects {
+    mat4 projection;
+    vec4 outline_color;
+    vec4 shadow_color;
+    vec2 shadow_offset;
+    float outline_width;
+    float px_range;
+} effects;
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+float median(vec3 v) {
+    return max(min(v.r, v.g), min(max(v.r, v.g), v.b));
+}
+
+vec4 over(vec4 top, vec4 bottom) {
+    float a = top.a + bottom.a * (1.0 - top.a);
+    vec3 rgb = (top.rgb * top.a + bottom.rgb * bottom.a * (1.0 - top.a)) / max(a, 0.00001);
+    return vec4(rgb, a);
+}
+
+void main() {
+    vec2 atlas_size = vec2(textureSize(sampler2D(glyphs, glyph_sampler), 0));
+    // Screen pixels per atlas texel, never below one so minified text still antialiases
+    float scale = max(0.5 * dot(vec2(1.0) / atlas_size, vec2(1.0) / fwidth(uv)), 1.0 / effects.px_range);
+
+    vec4 field = texture(sampler2D(glyphs, glyph_sampler), uv);
+    float fill = clamp((median(field.rgb) - 0.5) * effects.px_range * scale + 0.5, 0.0, 1.0);
+    float outline = 0.0;
+    if (effects.outline_width > 0.0) {
+        float dist = (field.a - 0.5) * effects.px_range + effects.outline_width;
+        outline = clamp(dist * scale + 0.5, 0.0, 1.0);
+    }
+    float shadow_field = texture(sampler2D(glyphs, glyph_sampler), uv - effects.shadow_offset / atlas_size).a;
+    float shadow_dist = (shadow_field - 0.5) * effects.px_range + effects.outline_width;
+    float shadow = clamp(shadow_dist * scale + 0.5, 0.0, 1.0);
+
+    vec4 result = vec4(effects.shadow_color.rgb, effects.shadow_color.a * shadow);
+    result = over(vec4(effects.outline_color.rgb, effects.outline_color.a * outline), result);
+    out_color = over(vec4(color.rgb, color.a * fill), result);
+}
+"#;
+
+/// Fragment parameters of [`SDF_TEXT_FRAG_GLSL`], widths and offsets are in atlas texels so they
+/// scale with the text
+#[derive(Debug, Clone, Copy, ShaderLayout, AsBytes)]
+#[layout(std430)]
+#[repr(C)]
+pub struct SdfEffects {
+    pub outline_color: Vec4,
+    pub shadow_color: Vec4,
+    pub shadow_offset: Vec2,
+    pub outline_width: f32,
+    /// Distance range the atlas was generated with
+    pub px_range: f32,
+}
+
+impl SdfEffects {
+    /// Plain fill, for an atlas generated with a distance range of `px_range`
+    pub fn new(px_range: f32) -> Self {
+        SdfEffects {
+            outline_color: Vec4::ZERO,
+            shadow_color: Vec4::ZERO,
+            shadow_offset: Vec2::ZERO,
+            outline_width: 0.0,
+            px_range,
+        }
+    }
+
+    /// Outlines are limited to half the distance range
+    pub fn with_outline(mut self, color: Vec4, width: f32) -> Self {
+        self.outline_color = color;
+        self.outline_width = width.min(self.px_range * 0.5);
+        self
+    }
+
+    /// Shadows are offset down and right for positive `offset`
+    pub fn with_shadow(mut self, color: Vec4, offset: Vec2) -> Self {
+        self.shadow_color = color;
+        self.shadow_offset = offset;
+        self
+    }
+}
+
+/// Field of one glyph, placed like a fontdue bitmap with rows top to bottom
+#[derive(Debug, Clone)]
+pub struct SdfGlyph {
+    pub width: u32,
+    pub height: u32,
+    /// Offset of the bottom left corner from the pen on the baseline
+    pub xmin: i32,
+    pub ymin: i32,
+    /// RGBA8, `0.5` on the outline and distances spanning `range` pixels around it
+    pub texels: Vec<u8>,
+}
+
+/// Generates the field of glyph `index` at `px` pixels per em, `None` for blank glyphs
+///
+/// The field reaches `range / 2` pixels outside the outline, which is also how far outlines and
+/// shadows can grow.
+pub fn generate(font: &[u8], index: u16, px: f32, range: f32) -> Option<SdfGlyph> {
+    let face = Face::parse(font, 0).ok()?;
+    let glyph = GlyphId(index);
+    let bounds = face.glyph_bounding_box(glyph)?;
+    let scale = px / face.units_per_em() as f32;
+    let mut outline = Outline {
+        scale,
+        ..Default::default()
+    };
+    face.outline_glyph(glyph, &mut outline)?;
+    outline.close();
+
+    let area: f32 = outline.contours.iter().flatten().map(Segment::area).sum();
+    // Inside is positive whichever way the font winds its outer contours
+    let orientation = if area < 0.0 { -1.0 } else { 1.0 };
+    let edges: Vec<Edge> = outline
+        .contours
+        .iter()
+        .flat_map(|contour| split_edges(contour))
+        .collect();
+    if edges.is_empty() {
+        return None;
+    }
+
+    let pad = (range * 0.5).ceil() as i32;
+    let left = (bounds.x_min as f32 * scale).floor() as i32 - pad;
+    let bottom = (bounds.y_min as f32 * scale).floor() as i32 - pad;
+    let right = (bounds.x_max as f32 * scale).ceil() as i32 + pad;
+    let top = (bounds.y_max as f32 * scale).ceil() as i32 + pad;
+    let (width, height) = ((right - left) as u32, (top - bottom) as u32);
+
+    let encode = |distance: f32| {
+        ((0.5 + orientation * distance / range).clamp(0.0, 1.0) * 255.0).round() as u8
+    };
+    let mut texels = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in 0..height {
+        for column in 0..width {
+            let point = Vec2::new(
+                left as f32 + column as f32 + 0.5,
+                top as f32 - row as f32 - 0.5,
+            );
+            let [r, g, b, a] = field_at(&edges, point);
+            texels.extend([encode(r), encode(g), encode(b), encode(a)]);
+        }
+    }
+    Some(SdfGlyph {
+        width,
+        height,
+        xmin: left,
+        ymin: bottom,
+        texels,
+    })
+}
+
+const RED: u8 = 1;
+const GREEN: u8 = 2;
+const BLUE: u8 = 4;
+const YELLOW: u8 = RED | GREEN;
+const MAGENTA: u8 = RED | BLUE;
+const CYAN: u8 = GREEN | BLUE;
+const WHITE: u8 = RED | GREEN | BLUE;
+
+/// Segments per curve, fine enough that flattened curves never bend past the corner threshold
+const CURVE_STEPS: u32 = 16;
+/// Sine of the smallest turn between segments treated as a corner, about 8 degrees
+const CORNER_SIN: f32 = 0.1411;
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    a: Vec2,
+    b: Vec2,
+}
+
+/// Distance from a point to a segment, and where along it the nearest point lies
+#[derive(Debug, Clone, Copy)]
+struct SegmentDistance {
+    distance: f32,
+    /// Distance to the segment's line, signed by the side the point is on
+    perpendicular: f32,
+    /// How head-on the nearest point is, breaks ties between segments sharing an endpoint
+    orthogonality: f32,
+    t: f32,
+}
+
+impl SegmentDistance {
+    fn signed(&self) -> f32 {
+        self.distance.copysign(self.perpendicular)
+    }
+
+    fn closer_than(&self, other: &SegmentDistance) -> bool {
+        const EPSILON: f32 = 1e-4;
+        self.distance < other.distance - EPSILON
+            || (self.distance <= other.distance + EPSILON
+                && self.orthogonality > other.orthogonality)
+    }
+}
+
+impl Segment {
+    fn direction(&self) -> Vec2 {
+        (self.b - self.a).normalize()
+    }
+
+    /// Twice the signed area this segment sweeps around the origin, counter clockwise positive
+    fn area(&self) -> f32 {
+        self.a.perp_dot(self.b)
+    }
+
+    fn distance(&self, point: Vec2) -> SegmentDistance {
+        let d = self.b - self.a;
+        let t = (point - self.a).dot(d) / d.length_squared();
+        let distance = point.distance(self.a + d * t.clamp(0.0, 1.0));
+        let perpendicular = d.perp_dot(point - self.a) / d.length();
+        SegmentDistance {
+            distance,
+            perpendicular,
+            orthogonality: if distance > 0.0 {
+                (perpendicular / distance).abs()
+            } else {
+                1.0
+            },
+            t,
+        }
+    }
+}
+
+/// Run of segments between two corners, sharing a colour
+struct Edge {
+    segments: Vec<Segment>,
+    color: u8,
+    /// Whether the ends continue as lines past the corners, false for smooth closed contours
+    extend: bool,
+}
+
+impl Edge {
+    /// Pseudo-distance, the ends extended as lines so the field stays straight past corners
+    fn pseudo_distance(&self, segment: usize, distance: &SegmentDistance) -> f32 {
+        let first = segment == 0 && distance.t < 0.0;
+        let last = segment == self.segments.len() - 1 && distance.t > 1.0;
+        if self.extend && (first || last) {
+            distance.perpendicular
+        } else {
+            distance.signed()
+        }
+    }
+}
+
+fn is_corner(a: Vec2, b: Vec2) -> bool {
+    a.dot(b) <= 0.0 || a.perp_dot(b).abs() > CORNER_SIN
+}
+
+/// Splits a closed contour at its corners and colours the edges so neighbours differ
+fn split_edges(contour: &[Segment]) -> Vec<Edge> {
+    let n = contour.len();
+    let corners: Vec<usize> = (0..n)
+        .filter(|&i| is_corner(contour[(i + n - 1) % n].direction(), contour[i].direction()))
+        .collect();
+    let rotated = |start: usize| contour[start..].iter().chain(&contour[..start]).copied();
+
+    match corners[..] {
+        [] => vec![Edge {
+            segments: contour.to_vec(),
+            color: WHITE,
+            extend: false,
+        }],
+        // A teardrop, split in three so the two sides of its corner differ
+        [corner] if n >= 3 => {
+            let segments: Vec<Segment> = rotated(corner).collect();
+            let thirds = [0, n / 3, n * 2 / 3, n];
+            [MAGENTA, WHITE, YELLOW]
+                .into_iter()
+                .zip(thirds.windows(2))
+                .map(|(color, range)| Edge {
+                    segments: segments[range[0]..range[1]].to_vec(),
+                    color,
+                    extend: true,
+                })
+                .collect()
+        }
+        [corner] => vec![Edge {
+            segments: rotated(corner).collect(),
+            color: WHITE,
+            extend: true,
+        }],
+        _ => {
+            let count = corners.len();
+            (0..count)
+                .map(|i| {
+                    let start = corners[i];
+                    let len = (corners[(i + 1) % count] + n - start - 1) % n + 1;
+                    // Odd counts end on yellow, so the last edge differs from the first as well
+                    let color = match i {
+                        i if i == count - 1 && count % 2 == 1 => YELLOW,
+                        i if i % 2 == 0 => CYAN,
+                        _ => MAGENTA,
+                    };
+                    Edge {
+                        segments: rotated(start).take(len).collect(),
+                        color,
+                        extend: true,
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// Per channel pseudo-distances in red, green and blue and the true distance in alpha
+fn field_at(edges: &[Edge], point: Vec2) -> [f32; 4] {
+    let mut nearest: Option<SegmentDistance> = None;
+    let mut channels: [Option<(usize, usize, SegmentDistance)>; 3] = [None; 3];
+    for (e, edge) in edges.iter().enumerate() {
+        for (s, segment) in edge.segments.iter().enumerate() {
+            let distance = segment.distance(point);
+            if nearest.is_none_or(|nearest| distance.closer_than(&nearest)) {
+                nearest = Some(distance);
+            }
+            for (channel, best) in [RED, GREEN, BLUE].into_iter().zip(&mut channels) {
+                if edge.color & channel != 0
+                    && best.is_none_or(|(_, _, best)| distance.closer_than(&best))
+                {
+                    *best = Some((e, s, distance));
+                }
+            }
+        }
+    }
+    let nearest = nearest.map_or(f32::MIN, |nearest| nearest.signed());
+    let [r, g, b] = channels.map(|best| match best {
+        Some((e, s, distance)) => edges[e].pseudo_distance(s, &distance),
+        None => nearest,
+    });
+    [r, g, b, nearest]
+}
+
+/// Flattens an outline into closed contours of line segments, in pixels
+#[derive(Default)]
+struct Outline {
+    scale: f32,
+    contours: Vec<Vec<Segment>>,
+    current: Vec<Segment>,
+    start: Vec2,
+    pen: Vec2,
+}
+
+impl Outline {
+    fn point(&self, x: f32, y: f32) -> Vec2 {
+        Vec2::new(x, y) * self.scale
+    }
+
+    fn segment_to(&mut self, point: Vec2) {
+        if point.distance_squared(self.pen) > 1e-8 {
+            self.current.push(Segment {
+                a: self.pen,
+                b: point,
+            });
+        }
+        self.pen = point;
+    }
+}
+
+impl OutlineBuilder for Outline {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.close();
+        self.start = self.point(x, y);
+        self.pen = self.start;
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        let point = self.point(x, y);
+        self.segment_to(point);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        let (p0, p1, p2) = (self.pen, self.point(x1, y1), self.point(x, y));
+        for step in 1..=CURVE_STEPS {
+            let t = step as f32 / CURVE_STEPS as f32;
+            self.segment_to(p0.lerp(p1, t).lerp(p1.lerp(p2, t), t));
+        }
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        let (p0, p1, p2, p3) = (
+            self.pen,
+            self.point(x1, y1),
+            self.point(x2, y2),
+            self.point(x, y),
+        );
+        for step in 1..=CURVE_STEPS {
+            let t = step as f32 / CURVE_STEPS as f32;
+            let u = 1.0 - t;
+            self.segment_to(
+                p0 * (u * u * u)
+                    + p1 * (3.0 * u * u * t)
+                    + p2 * (3.0 * u * t * t)
+                    + p3 * (t * t * t),
+            );
+        }
+    }
+
+    fn close(&mut self) {
+        let start = self.start;
+        self.segment_to(start);
+        if !self.current.is_empty() {
+            self.contours.push(std::mem::take(&mut self.current));
+        }
+    }
+}
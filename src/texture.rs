@@ -0,0 +1,765 @@
+//! Sampled images with all of their mip levels and layers uploaded through the staging belt
+
+use ash::{prelude::VkResult, vk, Device};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    graph::ImageDesc,
+    leaks,
+    memory::{find_memory_type, staging::StagingBelt, usage, usage::MemoryCategory},
+    trace::traced,
+};
+
+use self::{format::block_info, host_copy::HostImageCopy};
+
+pub mod atlas;
+pub mod basis;
+pub mod cubemap;
+pub mod format;
+pub mod hdr;
+pub mod host_copy;
+pub mod ktx2;
+pub mod lut;
+pub mod sampler;
+pub mod sparse;
+pub mod streaming;
+
+#[derive(Debug, Error)]
+pub enum TextureDataError {
+    #[error("unknown block size for format {0:?}")]
+    UnknownFormat(vk::Format),
+    #[error("mip level {level} is {actual} bytes, expected {expected}")]
+    LevelSize {
+        level: usize,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("texture has no mip levels")]
+    NoLevels,
+}
+
+/// Decoded texture contents, ready to upload
+#[derive(Debug, Clone)]
+pub struct TextureData {
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+    /// Array layers, cube faces count as separate layers
+    pub layers: u32,
+    pub cube: bool,
+    /// One entry per mip level, each holding every layer tightly packed one after the other
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl TextureData {
+    /// Checks every level holds exactly the bytes its block aligned extent needs
+    pub fn validate(&self) -> Result<(), TextureDataError> {
+        if self.levels.is_empty() {
+            return Err(TextureDataError::NoLevels);
+        }
+        let block = block_info(self.format).ok_or(TextureDataError::UnknownFormat(self.format))?;
+        for (level, bytes) in self.levels.iter().enumerate() {
+            let expected = block.image_size(mip_extent(self.extent, level as u32), self.layers);
+            if bytes.len() != expected {
+                return Err(TextureDataError::LevelSize {
+                    level,
+                    expected,
+                    actual: bytes.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn view_type(&self) -> vk::ImageViewType {
+        if self.cube {
+            if self.layers > 6 {
+                vk::ImageViewType::CUBE_ARRAY
+            } else {
+                vk::ImageViewType::CUBE
+            }
+        } else if self.extent.depth > 1 {
+            vk::ImageViewType::TYPE_3D
+        } else if self.layers > 1 {
+            vk::ImageViewType::TYPE_2D_ARRAY
+        } else {
+            vk::ImageViewType::TYPE_2D
+        }
+    }
+}
+
+pub struct Texture {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    /// Extra view for storage image writes, null unless the texture is written by compute
+    storage_view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+    pub format: vk::Format,
+    pub extent: vk::Extent3D,
+    pub mip_levels: u32,
+    pub layers: u32,
+}
+
+impl Texture {
+    /// Creates the image and records the upload of every level into `cmd`
+    ///
+    /// The image is left in `SHADER_READ_ONLY_OPTIMAL` once `cmd` has executed.
+    #[track_caller]
+    pub fn upload(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        data: &TextureData,
+    ) -> VkResult<Self> {
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        let texture = Texture::allocate(device, mem_props, data, usage)?;
+        let image = texture.image;
+        let range = texture.range();
+        transition(
+            device,
+            cmd,
+            image,
+            range,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+        // Copies must start on a whole block, and on 4 bytes for the transfer
+        let block_bytes = block_info(data.format).map_or(16, |block| block.bytes);
+        let align = (block_bytes * 4 / gcd(block_bytes, 4)) as vk::DeviceSize;
+        // Levels may land in different staging chunks, so each gets its own copy
+        for (level, bytes) in data.levels.iter().enumerate() {
+            let alloc = match staging.write(device, bytes, align) {
+                Ok(alloc) => alloc,
+                Err(err) => {
+                    unsafe { texture.destroy(device) };
+                    return Err(err);
+                }
+            };
+            let region = vk::BufferImageCopy::builder()
+                .buffer_offset(alloc.offset)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level as u32,
+                    base_array_layer: 0,
+                    layer_count: data.layers,
+                })
+                .image_extent(mip_extent(data.extent, level as u32))
+                .build();
+            unsafe {
+                traced!(
+                    "vkCmdCopyBufferToImage",
+                    src = alloc.buffer,
+                    image = image,
+                    region = region;
+                    device.cmd_copy_buffer_to_image(
+                        cmd,
+                        alloc.buffer,
+                        image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[region],
+                    )
+                )
+            };
+        }
+        transition(
+            device,
+            cmd,
+            image,
+            range,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        Ok(texture)
+    }
+
+    /// [`HostImageCopy::upload`] when `host_copy` can write the format, otherwise
+    /// [`Texture::upload`] recorded into `cmd`
+    ///
+    /// Either way the image ends in `SHADER_READ_ONLY_OPTIMAL`, but only host copied images lack
+    /// `TRANSFER_DST`, so textures written to again later should use [`Texture::upload`].
+    #[track_caller]
+    pub fn upload_with(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        host_copy: Option<&HostImageCopy>,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        data: &TextureData,
+    ) -> VkResult<Self> {
+        match host_copy {
+            Some(host_copy) if host_copy.supports(data.format) => {
+                host_copy.upload(device, mem_props, data)
+            }
+            _ => Texture::upload(device, mem_props, staging, cmd, data),
+        }
+    }
+
+    /// Creates the image with its memory and view for `data`, leaving it in `UNDEFINED`
+    #[track_caller]
+    fn allocate(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        data: &TextureData,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        let mip_levels = data.levels.len() as u32;
+        let image_type = if data.extent.depth > 1 {
+            vk::ImageType::TYPE_3D
+        } else {
+            vk::ImageType::TYPE_2D
+        };
+        let flags = if data.cube {
+            vk::ImageCreateFlags::CUBE_COMPATIBLE
+        } else {
+            vk::ImageCreateFlags::empty()
+        };
+        let info = vk::ImageCreateInfo::builder()
+            .flags(flags)
+            .image_type(image_type)
+            .format(data.format)
+            .extent(data.extent)
+            .mip_levels(mip_levels)
+            .array_layers(data.layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe {
+            traced!(
+                "vkCreateImage",
+                format = info.format,
+                extent = info.extent,
+                usage = info.usage;
+                device.create_image(&info, None)
+            )?
+        };
+
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { traced!("vkDestroyImage", image = image; device.destroy_image(image, None)) };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = match unsafe {
+            traced!(
+                "vkAllocateMemory",
+                size = alloc_info.allocation_size,
+                memory_type = alloc_info.memory_type_index;
+                device.allocate_memory(&alloc_info, None)
+            )
+        } {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe {
+                    traced!("vkDestroyImage", image = image; device.destroy_image(image, None))
+                };
+                return Err(err);
+            }
+        };
+        usage::record_allocation(MemoryCategory::Texture, requirements.size);
+        leaks::track(image, "texture image");
+        leaks::track(memory, "texture memory");
+        let mut texture = Texture {
+            image,
+            view: vk::ImageView::null(),
+            storage_view: vk::ImageView::null(),
+            memory,
+            allocation_size: requirements.size,
+            format: data.format,
+            extent: data.extent,
+            mip_levels,
+            layers: data.layers,
+        };
+
+        let range = texture.range();
+        let result = unsafe {
+            traced!(
+                "vkBindImageMemory",
+                image = image,
+                memory = memory;
+                device.bind_image_memory(image, memory, 0)
+            )
+            .and_then(|_| {
+                let view_info = vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(data.view_type())
+                    .format(data.format)
+                    .subresource_range(range);
+                traced!(
+                    "vkCreateImageView",
+                    image = view_info.image,
+                    view_type = view_info.view_type,
+                    format = view_info.format;
+                    device.create_image_view(&view_info, None)
+                )
+            })
+        };
+        match result {
+            Ok(view) => {
+                leaks::track(view, "texture view");
+                texture.view = view;
+            }
+            Err(err) => {
+                unsafe { texture.destroy(device) };
+                return Err(err);
+            }
+        }
+        Ok(texture)
+    }
+
+    /// Every level and layer
+    fn range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: self.layers,
+        }
+    }
+
+    /// Records an update of `rect` in `level` of the first layer, e.g. for atlases filled over time
+    ///
+    /// `bytes` holds tightly packed rows, and the texture stays in `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn write_region(
+        &self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        level: u32,
+        rect: vk::Rect2D,
+        bytes: &[u8],
+    ) -> VkResult<()> {
+        let block_bytes = block_info(self.format).map_or(16, |block| block.bytes);
+        let align = (block_bytes * 4 / gcd(block_bytes, 4)) as vk::DeviceSize;
+        let alloc = staging.write(device, bytes, align)?;
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: level,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        transition(
+            device,
+            cmd,
+            self.image,
+            range,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+        let region = vk::BufferImageCopy::builder()
+            .buffer_offset(alloc.offset)
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_offset(vk::Offset3D {
+                x: rect.offset.x,
+                y: rect.offset.y,
+                z: 0,
+            })
+            .image_extent(vk::Extent3D {
+                width: rect.extent.width,
+                height: rect.extent.height,
+                depth: 1,
+            })
+            .build();
+        unsafe {
+            traced!(
+                "vkCmdCopyBufferToImage",
+                src = alloc.buffer,
+                image = self.image,
+                region = region;
+                device.cmd_copy_buffer_to_image(
+                    cmd,
+                    alloc.buffer,
+                    self.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                )
+            )
+        };
+        transition(
+            device,
+            cmd,
+            self.image,
+            range,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        Ok(())
+    }
+
+    /// Creates a single level 2D image for compute or a render pass to write and shaders to sample
+    ///
+    /// Its contents are undefined and it's left in `UNDEFINED`, transition it before the first
+    /// write. Depth formats get a depth view.
+    #[track_caller]
+    pub fn empty(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        let aspect_mask = ImageDesc::new(format, extent).aspect();
+        let extent = vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        Texture::empty_image(
+            device,
+            mem_props,
+            format,
+            extent,
+            1,
+            vk::SampleCountFlags::TYPE_1,
+            usage,
+            aspect_mask,
+        )
+    }
+
+    /// Like [`Texture::empty`] with `mip_levels` levels, all covered by its view
+    ///
+    /// Compute writing the levels one by one can make views of each with [`Texture::level_view`].
+    #[track_caller]
+    pub fn empty_mipped(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        mip_levels: u32,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        let aspect_mask = ImageDesc::new(format, extent).aspect();
+        let extent = vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        Texture::empty_image(
+            device,
+            mem_props,
+            format,
+            extent,
+            mip_levels,
+            vk::SampleCountFlags::TYPE_1,
+            usage,
+            aspect_mask,
+        )
+    }
+
+    /// Like [`Texture::empty`] with `samples` per texel, for multisampled attachments
+    #[track_caller]
+    pub fn multisampled(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        samples: vk::SampleCountFlags,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        let aspect_mask = ImageDesc::new(format, extent).aspect();
+        let extent = vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        Texture::empty_image(
+            device,
+            mem_props,
+            format,
+            extent,
+            1,
+            samples,
+            usage,
+            aspect_mask,
+        )
+    }
+
+    /// Like [`Texture::empty`] but 3D, e.g. for volumes filled by compute
+    #[track_caller]
+    pub fn volume(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent3D,
+        usage: vk::ImageUsageFlags,
+    ) -> VkResult<Self> {
+        Texture::empty_image(
+            device,
+            mem_props,
+            format,
+            extent,
+            1,
+            vk::SampleCountFlags::TYPE_1,
+            usage,
+            vk::ImageAspectFlags::COLOR,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    fn empty_image(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        format: vk::Format,
+        extent: vk::Extent3D,
+        mip_levels: u32,
+        samples: vk::SampleCountFlags,
+        usage: vk::ImageUsageFlags,
+        aspect_mask: vk::ImageAspectFlags,
+    ) -> VkResult<Self> {
+        let (image_type, view_type) = if extent.depth > 1 {
+            (vk::ImageType::TYPE_3D, vk::ImageViewType::TYPE_3D)
+        } else {
+            (vk::ImageType::TYPE_2D, vk::ImageViewType::TYPE_2D)
+        };
+        let info = vk::ImageCreateInfo::builder()
+            .image_type(image_type)
+            .format(format)
+            .extent(extent)
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .samples(samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe {
+            traced!(
+                "vkCreateImage",
+                format = info.format,
+                extent = info.extent,
+                usage = info.usage;
+                device.create_image(&info, None)
+            )?
+        };
+
+        let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            unsafe { traced!("vkDestroyImage", image = image; device.destroy_image(image, None)) };
+            return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = match unsafe {
+            traced!(
+                "vkAllocateMemory",
+                size = alloc_info.allocation_size,
+                memory_type = alloc_info.memory_type_index;
+                device.allocate_memory(&alloc_info, None)
+            )
+        } {
+            Ok(memory) => memory,
+            Err(err) => {
+                unsafe {
+                    traced!("vkDestroyImage", image = image; device.destroy_image(image, None))
+                };
+                return Err(err);
+            }
+        };
+        usage::record_allocation(MemoryCategory::Texture, requirements.size);
+        leaks::track(image, "texture image");
+        leaks::track(memory, "texture memory");
+        let mut texture = Texture {
+            image,
+            view: vk::ImageView::null(),
+            storage_view: vk::ImageView::null(),
+            memory,
+            allocation_size: requirements.size,
+            format,
+            extent,
+            mip_levels,
+            layers: 1,
+        };
+        let result = unsafe {
+            traced!(
+                "vkBindImageMemory",
+                image = image,
+                memory = memory;
+                device.bind_image_memory(image, memory, 0)
+            )
+            .and_then(|_| {
+                let view_info = vk::ImageViewCreateInfo::builder()
+                    .image(image)
+                    .view_type(view_type)
+                    .format(format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask,
+                        base_mip_level: 0,
+                        level_count: mip_levels,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    });
+                traced!(
+                    "vkCreateImageView",
+                    image = view_info.image,
+                    view_type = view_info.view_type,
+                    format = view_info.format;
+                    device.create_image_view(&view_info, None)
+                )
+            })
+        };
+        match result {
+            Ok(view) => {
+                leaks::track(view, "texture view");
+                texture.view = view;
+                Ok(texture)
+            }
+            Err(err) => {
+                unsafe { texture.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    /// A 2D view of just `level`, e.g. to bind it as a storage image, destroyed by the caller
+    pub fn level_view(&self, device: &Device, level: u32) -> VkResult<vk::ImageView> {
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(self.image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(self.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: level,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        unsafe {
+            traced!(
+                "vkCreateImageView",
+                image = view_info.image,
+                view_type = view_info.view_type,
+                format = view_info.format;
+                device.create_image_view(&view_info, None)
+            )
+        }
+    }
+}
+
+impl GpuAsset for Texture {
+    unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.view);
+        leaks::untrack(self.storage_view);
+        leaks::untrack(self.image);
+        leaks::untrack(self.memory);
+        if self.view != vk::ImageView::null() {
+            traced!(
+                "vkDestroyImageView",
+                view = self.view;
+                device.destroy_image_view(self.view, None)
+            );
+        }
+        if self.storage_view != vk::ImageView::null() {
+            traced!(
+                "vkDestroyImageView",
+                view = self.storage_view;
+                device.destroy_image_view(self.storage_view, None)
+            );
+        }
+        traced!("vkDestroyImage", image = self.image; device.destroy_image(self.image, None));
+        traced!("vkFreeMemory", memory = self.memory; device.free_memory(self.memory, None));
+        usage::release_allocation(MemoryCategory::Texture, self.allocation_size);
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Size of a mip level, never smaller than a texel
+pub fn mip_extent(extent: vk::Extent3D, level: u32) -> vk::Extent3D {
+    vk::Extent3D {
+        width: (extent.width >> level).max(1),
+        height: (extent.height >> level).max(1),
+        depth: (extent.depth >> level).max(1),
+    }
+}
+
+pub(crate) fn transition(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    image: vk::Image,
+    range: vk::ImageSubresourceRange,
+    from: vk::ImageLayout,
+    to: vk::ImageLayout,
+) {
+    let (src_access, src_stage, dst_access, dst_stage) = match (from, to) {
+        // Earlier sampling only has to finish before the copy overwrites it
+        (vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL) => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        (_, vk::ImageLayout::TRANSFER_DST_OPTIMAL) => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        (_, vk::ImageLayout::GENERAL) => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        (vk::ImageLayout::GENERAL, _) => (
+            vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        _ => (
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+    };
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .old_layout(from)
+        .new_layout(to)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(range)
+        .build();
+    unsafe {
+        device.cmd_pipeline_barrier(
+            cmd,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier],
+        )
+    };
+}
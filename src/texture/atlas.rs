@@ -0,0 +1,279 @@
+//! Packing many small images into a few shared textures, so 2D drawing binds one texture for all
+//! of them
+
+use ash::vk;
+use glam::Vec2;
+use thiserror::Error;
+
+use super::{format::block_info, TextureData};
+
+#[derive(Debug, Error)]
+pub enum AtlasError {
+    #[error("atlas images must be in an uncompressed format, not {0:?}")]
+    UnsupportedFormat(vk::Format),
+    #[error("image of {width}x{height} doesn't fit a {page_size}x{page_size} page")]
+    TooLarge {
+        width: u32,
+        height: u32,
+        page_size: u32,
+    },
+    #[error("image has {actual} bytes of pixels, expected {expected}")]
+    SizeMismatch { expected: usize, actual: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Skyline bottom-left rectangle packer for a single page
+#[derive(Debug, Clone)]
+pub struct RectPacker {
+    width: u32,
+    height: u32,
+    /// `(x, y, width)` segments of the top edge of everything placed so far, left to right
+    skyline: Vec<(u32, u32, u32)>,
+}
+
+impl RectPacker {
+    pub fn new(width: u32, height: u32) -> Self {
+        RectPacker {
+            width,
+            height,
+            skyline: vec![(0, 0, width)],
+        }
+    }
+
+    /// Finds room for a `width` by `height` rectangle, placing it as low as possible
+    pub fn pack(&mut self, width: u32, height: u32) -> Option<Rect> {
+        let mut best: Option<(usize, u32, u32)> = None;
+        for start in 0..self.skyline.len() {
+            let Some(y) = self.fits(start, width, height) else {
+                continue;
+            };
+            let x = self.skyline[start].0;
+            if best.is_none_or(|(_, best_x, best_y)| (y, x) < (best_y, best_x)) {
+                best = Some((start, x, y));
+            }
+        }
+        let (start, x, y) = best?;
+        self.place(start, x, y + height, width);
+        Some(Rect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    /// Height a rectangle starting at segment `start` would rest at, if it fits
+    fn fits(&self, start: usize, width: u32, height: u32) -> Option<u32> {
+        let x = self.skyline[start].0;
+        if x + width > self.width {
+            return None;
+        }
+        let mut y = 0;
+        let mut covered = 0;
+        for &(_, segment_y, segment_width) in &self.skyline[start..] {
+            y = y.max(segment_y);
+            covered += segment_width;
+            if covered >= width {
+                break;
+            }
+        }
+        (y + height <= self.height).then_some(y)
+    }
+
+    fn place(&mut self, start: usize, x: u32, top: u32, width: u32) {
+        let end = x + width;
+        // Segments fully under the new rectangle go, a partly covered one is trimmed
+        while start < self.skyline.len() {
+            let (seg_x, seg_y, seg_width) = self.skyline[start];
+            let seg_end = seg_x + seg_width;
+            if seg_x >= end {
+                break;
+            }
+            if seg_end <= end {
+                self.skyline.remove(start);
+            } else {
+                self.skyline[start] = (end, seg_y, seg_end - end);
+                break;
+            }
+        }
+        self.skyline.insert(start, (x, top, width));
+        // Neighbours at the same height merge so later searches stay short
+        let mut i = 0;
+        while i + 1 < self.skyline.len() {
+            let (x, y, width) = self.skyline[i];
+            let (_, next_y, next_width) = self.skyline[i + 1];
+            if y == next_y {
+                self.skyline[i] = (x, y, width + next_width);
+                self.skyline.remove(i + 1);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AtlasId(usize);
+
+/// Where an image ended up, the UVs span exactly the image with its padding left out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasRegion {
+    pub page: u32,
+    pub rect: Rect,
+    pub uv_min: Vec2,
+    pub uv_max: Vec2,
+}
+
+struct Pending {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+/// Collects images and packs them into as few pages as it can
+pub struct AtlasBuilder {
+    format: vk::Format,
+    texel_size: usize,
+    page_size: u32,
+    /// Gap around each image, so bilinear filtering doesn't bleed neighbours in
+    padding: u32,
+    images: Vec<Pending>,
+}
+
+impl AtlasBuilder {
+    pub fn new(format: vk::Format, page_size: u32) -> Result<Self, AtlasError> {
+        let texel_size = block_info(format)
+            .filter(|block| !block.is_compressed())
+            .ok_or(AtlasError::UnsupportedFormat(format))?
+            .bytes as usize;
+        Ok(AtlasBuilder {
+            format,
+            texel_size,
+            page_size,
+            padding: 1,
+            images: Vec::new(),
+        })
+    }
+
+    pub fn with_padding(mut self, padding: u32) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Queues an image of tightly packed rows in the atlas format
+    pub fn add(&mut self, width: u32, height: u32, pixels: Vec<u8>) -> Result<AtlasId, AtlasError> {
+        let expected = width as usize * height as usize * self.texel_size;
+        if pixels.len() != expected {
+            return Err(AtlasError::SizeMismatch {
+                expected,
+                actual: pixels.len(),
+            });
+        }
+        if width + self.padding * 2 > self.page_size || height + self.padding * 2 > self.page_size {
+            return Err(AtlasError::TooLarge {
+                width,
+                height,
+                page_size: self.page_size,
+            });
+        }
+        self.images.push(Pending {
+            width,
+            height,
+            pixels,
+        });
+        Ok(AtlasId(self.images.len() - 1))
+    }
+
+    /// Packs everything added, tallest first, opening new pages as they fill up
+    pub fn build(self) -> Atlas {
+        let mut order: Vec<_> = (0..self.images.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse((self.images[i].height, self.images[i].width)));
+
+        let page_bytes = (self.page_size as usize).pow(2) * self.texel_size;
+        let mut packers: Vec<RectPacker> = Vec::new();
+        let mut pages: Vec<Vec<u8>> = Vec::new();
+        let mut regions = vec![None; self.images.len()];
+        for i in order {
+            let image = &self.images[i];
+            let padded = (
+                image.width + self.padding * 2,
+                image.height + self.padding * 2,
+            );
+            let placed = packers
+                .iter_mut()
+                .enumerate()
+                .find_map(|(page, packer)| Some((page, packer.pack(padded.0, padded.1)?)));
+            let (page, slot) = match placed {
+                Some(placed) => placed,
+                None => {
+                    let mut packer = RectPacker::new(self.page_size, self.page_size);
+                    let slot = packer
+                        .pack(padded.0, padded.1)
+                        .expect("image was checked to fit an empty page");
+                    packers.push(packer);
+                    pages.push(vec![0; page_bytes]);
+                    (packers.len() - 1, slot)
+                }
+            };
+
+            let rect = Rect {
+                x: slot.x + self.padding,
+                y: slot.y + self.padding,
+                width: image.width,
+                height: image.height,
+            };
+            let row = image.width as usize * self.texel_size;
+            let stride = self.page_size as usize * self.texel_size;
+            for (y, src) in image.pixels.chunks_exact(row).enumerate() {
+                let start = (rect.y as usize + y) * stride + rect.x as usize * self.texel_size;
+                pages[page][start..start + row].copy_from_slice(src);
+            }
+            let size = self.page_size as f32;
+            regions[i] = Some(AtlasRegion {
+                page: page as u32,
+                rect,
+                uv_min: Vec2::new(rect.x as f32, rect.y as f32) / size,
+                uv_max: Vec2::new((rect.x + rect.width) as f32, (rect.y + rect.height) as f32)
+                    / size,
+            });
+        }
+
+        let extent = vk::Extent3D {
+            width: self.page_size,
+            height: self.page_size,
+            depth: 1,
+        };
+        Atlas {
+            pages: pages
+                .into_iter()
+                .map(|page| TextureData {
+                    format: self.format,
+                    extent,
+                    layers: 1,
+                    cube: false,
+                    levels: vec![page],
+                })
+                .collect(),
+            regions: regions.into_iter().map(Option::unwrap).collect(),
+        }
+    }
+}
+
+/// Packed pages ready for [`Texture::upload`](super::Texture::upload), and where each image went
+pub struct Atlas {
+    pub pages: Vec<TextureData>,
+    regions: Vec<AtlasRegion>,
+}
+
+impl Atlas {
+    pub fn region(&self, id: AtlasId) -> AtlasRegion {
+        self.regions[id.0]
+    }
+}
@@ -0,0 +1,172 @@
+//! Basis Universal payloads in KTX2, transcoded at load time to the best format the device samples
+//!
+//! The transcoder itself is pluggable through [`BasisTranscoder`], the same way shader compilers
+//! are, this module takes care of unpacking the container and picking the target format. With the
+//! `basisu` feature [`universal::BasisUniversalTranscoder`] transcodes UASTC payloads.
+
+use std::error::Error;
+
+use ash::{vk, Instance};
+use thiserror::Error;
+
+use super::{format::is_sampleable, ktx2::Ktx2Error, mip_extent, TextureData, TextureDataError};
+
+#[cfg(feature = "basisu")]
+pub mod universal;
+
+#[derive(Debug, Error)]
+pub enum BasisError {
+    #[error(transparent)]
+    Ktx2(#[from] Ktx2Error),
+    #[error("transcoder output is invalid: {0}")]
+    Invalid(#[from] TextureDataError),
+    #[error("KTX2 file is not Basis Universal encoded")]
+    NotBasis,
+    #[error("failed to transcode mip level {level}: {source}")]
+    Transcode {
+        level: usize,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BasisCodec {
+    /// BasisLZ supercompressed ETC1S, smaller but lower quality
+    Etc1s,
+    Uastc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscodeTarget {
+    Bc7,
+    Astc4x4,
+    Etc2Rgba,
+    /// Uncompressed fallback every device supports
+    Rgba8,
+}
+
+impl TranscodeTarget {
+    /// Best quality per byte first
+    pub const PREFERENCE: [TranscodeTarget; 4] = [
+        TranscodeTarget::Bc7,
+        TranscodeTarget::Astc4x4,
+        TranscodeTarget::Etc2Rgba,
+        TranscodeTarget::Rgba8,
+    ];
+
+    pub fn format(self, srgb: bool) -> vk::Format {
+        match (self, srgb) {
+            (TranscodeTarget::Bc7, false) => vk::Format::BC7_UNORM_BLOCK,
+            (TranscodeTarget::Bc7, true) => vk::Format::BC7_SRGB_BLOCK,
+            (TranscodeTarget::Astc4x4, false) => vk::Format::ASTC_4X4_UNORM_BLOCK,
+            (TranscodeTarget::Astc4x4, true) => vk::Format::ASTC_4X4_SRGB_BLOCK,
+            (TranscodeTarget::Etc2Rgba, false) => vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+            (TranscodeTarget::Etc2Rgba, true) => vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+            (TranscodeTarget::Rgba8, false) => vk::Format::R8G8B8A8_UNORM,
+            (TranscodeTarget::Rgba8, true) => vk::Format::R8G8B8A8_SRGB,
+        }
+    }
+
+    /// Picks the first target the device can sample with linear filtering
+    pub fn pick(instance: &Instance, physical_device: vk::PhysicalDevice, srgb: bool) -> Self {
+        Self::PREFERENCE
+            .into_iter()
+            .find(|target| is_sampleable(instance, physical_device, target.format(srgb)))
+            .unwrap_or(TranscodeTarget::Rgba8)
+    }
+}
+
+/// One mip level, with every layer and face, to be transcoded
+#[derive(Debug, Clone, Copy)]
+pub struct TranscodeLevel<'a> {
+    pub codec: BasisCodec,
+    pub level: u32,
+    pub width: u32,
+    pub height: u32,
+    pub layers: u32,
+    pub data: &'a [u8],
+    /// BasisLZ codebooks and image descriptors, empty for UASTC
+    pub global_data: &'a [u8],
+}
+
+/// Turns Basis Universal levels into blocks of the target format
+pub trait BasisTranscoder {
+    type Error: Error + Send + Sync + 'static;
+
+    fn transcode(
+        &self,
+        level: &TranscodeLevel,
+        target: TranscodeTarget,
+    ) -> Result<Vec<u8>, Self::Error>;
+}
+
+/// Loads a Basis Universal KTX2 file, transcoding every level to `target`
+pub fn load_ktx2<T: BasisTranscoder>(
+    bytes: &[u8],
+    transcoder: &T,
+    target: TranscodeTarget,
+) -> Result<TextureData, BasisError> {
+    let reader = ::ktx2::Reader::new(bytes).map_err(Ktx2Error::from)?;
+    let header = reader.header();
+    if header.format.is_some() {
+        return Err(BasisError::NotBasis);
+    }
+
+    let dfd = reader
+        .data_format_descriptors()
+        .find(|dfd| dfd.header == ::ktx2::DataFormatDescriptorHeader::BASIC)
+        .ok_or(BasisError::NotBasis)?;
+    let basic = ::ktx2::BasicDataFormatDescriptor::parse(dfd.data).map_err(Ktx2Error::from)?;
+    let codec = match basic.color_model {
+        Some(::ktx2::ColorModel::ETC1S) => BasisCodec::Etc1s,
+        Some(::ktx2::ColorModel::UASTC) => BasisCodec::Uastc,
+        _ => return Err(BasisError::NotBasis),
+    };
+    let srgb = basic.transfer_function == Some(::ktx2::TransferFunction::SRGB);
+
+    let extent = vk::Extent3D {
+        width: header.pixel_width,
+        height: header.pixel_height.max(1),
+        depth: 1,
+    };
+    let layers = header.layer_count.max(1) * header.face_count;
+    let levels = reader
+        .levels()
+        .enumerate()
+        .map(|(level, data)| {
+            // BasisLZ levels are handed over as is, UASTC may be Zstandard compressed
+            let data = match codec {
+                BasisCodec::Etc1s => data.to_vec(),
+                BasisCodec::Uastc => {
+                    super::ktx2::decompress(header.supercompression_scheme, level, data)?
+                }
+            };
+            let size = mip_extent(extent, level as u32);
+            let input = TranscodeLevel {
+                codec,
+                level: level as u32,
+                width: size.width,
+                height: size.height,
+                layers,
+                data: &data,
+                global_data: reader.supercompression_global_data(),
+            };
+            transcoder
+                .transcode(&input, target)
+                .map_err(|err| BasisError::Transcode {
+                    level,
+                    source: Box::new(err),
+                })
+        })
+        .collect::<Result<_, _>>()?;
+
+    let data = TextureData {
+        format: target.format(srgb),
+        extent,
+        layers,
+        cube: header.face_count == 6,
+        levels,
+    };
+    data.validate()?;
+    Ok(data)
+}
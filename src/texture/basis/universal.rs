@@ -0,0 +1,197 @@
+//! A [`BasisTranscoder`] backed by the basis-universal crate
+//!
+//! The crate binds the low level UASTC transcoder but not the BasisLZ one, so only UASTC payloads
+//! are transcoded, BasisLZ/ETC1S levels are refused with [`BasisUniversalError::Etc1s`].
+
+use std::ptr;
+
+use basis_universal::{sys, DecodeFlags, TranscoderBlockFormat};
+use thiserror::Error;
+
+use super::{BasisCodec, BasisTranscoder, TranscodeLevel, TranscodeTarget};
+
+/// Bytes in one 4x4 UASTC block
+const BLOCK_BYTES: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum BasisUniversalError {
+    #[error("BasisLZ/ETC1S payloads can't be transcoded, only UASTC")]
+    Etc1s,
+    #[error("level holds {actual} bytes, its blocks need {expected}")]
+    Size { expected: usize, actual: usize },
+    #[error("basis-universal failed to transcode to {0:?}")]
+    Transcode(TranscodeTarget),
+}
+
+/// Owns the low level UASTC transcoder through the crate's bindings
+///
+/// The crate's own `transcode_slice` passes the row pitch in blocks for uncompressed targets too,
+/// which overruns its output for [`TranscodeTarget::Rgba8`], so slices are transcoded directly.
+pub struct BasisUniversalTranscoder {
+    uastc: *mut sys::LowLevelUastcTranscoder,
+}
+
+impl Default for BasisUniversalTranscoder {
+    fn default() -> Self {
+        basis_universal::transcoder_init();
+        Self {
+            uastc: unsafe { sys::low_level_uastc_transcoder_new() },
+        }
+    }
+}
+
+impl Drop for BasisUniversalTranscoder {
+    fn drop(&mut self) {
+        unsafe { sys::low_level_uastc_transcoder_delete(self.uastc) };
+    }
+}
+
+impl BasisTranscoder for BasisUniversalTranscoder {
+    type Error = BasisUniversalError;
+
+    fn transcode(
+        &self,
+        level: &TranscodeLevel,
+        target: TranscodeTarget,
+    ) -> Result<Vec<u8>, Self::Error> {
+        if level.codec != BasisCodec::Uastc {
+            return Err(BasisUniversalError::Etc1s);
+        }
+        let blocks_x = level.width.div_ceil(4);
+        let blocks_y = level.height.div_ceil(4);
+        let image_bytes = (blocks_x * blocks_y) as usize * BLOCK_BYTES;
+        let expected = image_bytes * level.layers as usize;
+        if level.data.len() != expected {
+            return Err(BasisUniversalError::Size {
+                expected,
+                actual: level.data.len(),
+            });
+        }
+
+        let format = match target {
+            TranscodeTarget::Bc7 => TranscoderBlockFormat::BC7,
+            TranscodeTarget::Astc4x4 => TranscoderBlockFormat::ASTC_4x4,
+            TranscodeTarget::Etc2Rgba => TranscoderBlockFormat::ETC2_RGBA,
+            TranscodeTarget::Rgba8 => TranscoderBlockFormat::RGBA32,
+        };
+        // Pixels per row for uncompressed targets, blocks for the rest
+        let pitch = if format.is_compressed() {
+            blocks_x
+        } else {
+            level.width
+        };
+        let output_bytes = format.calculate_minimum_output_buffer_bytes(
+            level.width,
+            level.height,
+            blocks_x * blocks_y,
+            Some(pitch),
+            Some(level.height),
+        ) as usize;
+
+        let mut output = vec![0; output_bytes * level.layers as usize];
+        for (image, out) in level
+            .data
+            .chunks_exact(image_bytes)
+            .zip(output.chunks_exact_mut(output_bytes))
+        {
+            let transcoded = unsafe {
+                sys::low_level_uastc_transcoder_transcode_slice(
+                    self.uastc,
+                    out.as_mut_ptr().cast(),
+                    blocks_x,
+                    blocks_y,
+                    image.as_ptr(),
+                    image.len() as u32,
+                    format.into(),
+                    format.bytes_per_block_or_pixel(),
+                    false,
+                    // Every target keeps alpha, so it's always decoded
+                    true,
+                    level.width,
+                    level.height,
+                    pitch,
+                    ptr::null_mut(),
+                    level.height,
+                    0,
+                    3,
+                    DecodeFlags::HIGH_QUALITY.bits(),
+                )
+            };
+            if !transcoded {
+                return Err(BasisUniversalError::Transcode(target));
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::*;
+    use crate::texture::basis::load_ktx2;
+
+    /// A 10x6 linear gradient encoded to UASTC with a full mip chain, with no supercompression
+    const GRADIENT: &[u8] = include_bytes!("../fixtures/gradient_uastc.ktx2");
+
+    fn gradient(x: u32, y: u32) -> [u8; 4] {
+        [(x * 25) as u8, (y * 40) as u8, 160, 255]
+    }
+
+    #[test]
+    fn uastc_round_trips_to_rgba8() {
+        let transcoder = BasisUniversalTranscoder::default();
+        let data = load_ktx2(GRADIENT, &transcoder, TranscodeTarget::Rgba8).unwrap();
+        assert_eq!(data.format, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!((data.extent.width, data.extent.height), (10, 6));
+        assert_eq!(data.levels.len(), 4);
+
+        let base = &data.levels[0];
+        for y in 0..6 {
+            for x in 0..10 {
+                let at = (y * 10 + x) as usize * 4;
+                let expected = gradient(x, y);
+                for (channel, (&got, want)) in base[at..at + 4].iter().zip(expected).enumerate() {
+                    assert!(
+                        got.abs_diff(want) <= 4,
+                        "pixel ({x}, {y}) channel {channel} is {got}, expected {want}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn uastc_transcodes_to_block_targets() {
+        let transcoder = BasisUniversalTranscoder::default();
+        for (target, format) in [
+            (TranscodeTarget::Bc7, vk::Format::BC7_UNORM_BLOCK),
+            (TranscodeTarget::Astc4x4, vk::Format::ASTC_4X4_UNORM_BLOCK),
+            (
+                TranscodeTarget::Etc2Rgba,
+                vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+            ),
+        ] {
+            // load_ktx2 validates every level holds the blocks its extent needs
+            let data = load_ktx2(GRADIENT, &transcoder, target).unwrap();
+            assert_eq!(data.format, format);
+            assert_eq!(data.levels[0].len(), 3 * 2 * BLOCK_BYTES);
+        }
+    }
+
+    #[test]
+    fn etc1s_levels_are_refused() {
+        let level = TranscodeLevel {
+            codec: BasisCodec::Etc1s,
+            level: 0,
+            width: 4,
+            height: 4,
+            layers: 1,
+            data: &[0; BLOCK_BYTES],
+            global_data: &[],
+        };
+        let result = BasisUniversalTranscoder::default().transcode(&level, TranscodeTarget::Rgba8);
+        assert!(matches!(result, Err(BasisUniversalError::Etc1s)));
+    }
+}
@@ -0,0 +1,413 @@
+//! Equirectangular panorama to cubemap conversion on the GPU, for skyboxes and image based lighting
+
+use std::error::Error;
+
+use ash::{prelude::VkResult, vk, Device};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    leaks,
+    memory::{find_memory_type, usage, usage::MemoryCategory},
+    shader::{reflect, ShaderCompiler},
+};
+
+use super::{
+    hdr::HDR_FORMAT,
+    sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+    transition, Texture,
+};
+
+/// Compute shader writing one texel of one face per invocation
+pub const EQUIRECT_TO_CUBE_GLSL: &str = r#"#version 450
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) uniform texture2D equirect;
+layout(set = 0, binding = 1) uniform sampler equirect_sampler;
+layout(set = 0, binding = 2, rgba16f) uniform image2DArray cube;
+
+const float PI = 3.14159265359;
+
+// Direction through a face texel, following the Vulkan cube face orientation
+vec3 direction(uint face, vec2 uv) {
+    switch (face) {
+        case 0: return vec3(1.0, -uv.y, -uv.x);
+        case 1: return vec3(-1.0, -uv.y, uv.x);
+        case 2: return vec3(uv.x, 1.0, uv.y);
+        case 3: return vec3(uv.x, -1.0, -uv.y);
+        case 4: return vec3(uv.x, -uv.y, 1.0);
+        default: return vec3(-uv.x, -uv.y, -1.0);
+    }
+}
+
+void main() {
+    ivec3 id = ivec3(gl_GlobalInvocationID);
+    ivec2 size = imageSize(cube).xy;
+    if (id.x >= size.x || id.y >= size.y) {
+        return;
+    }
+    vec2 uv = (vec2(id.xy) + 0.5) / vec2(size) * 2.0 - 1.0;
+    vec3 dir = normalize(direction(uint(id.z), uv));
+    vec2 coord = vec2(atan(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    imageStore(cube, id, textureLod(sampler2D(equirect, equirect_sampler), coord, 0.0));
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Conversions that can be recorded before the descriptor pool needs a [`EquirectToCube::reset`]
+const MAX_CONVERSIONS: u32 = 8;
+
+#[derive(Debug, Error)]
+pub enum CubemapError {
+    #[error("failed to compile equirectangular conversion shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Pipeline and descriptors for turning equirectangular textures into cubemaps
+pub struct EquirectToCube {
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    pool: vk::DescriptorPool,
+}
+
+impl EquirectToCube {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+    ) -> Result<Self, CubemapError> {
+        let code = compiler
+            .compile(EQUIRECT_TO_CUBE_GLSL, vk::ShaderStageFlags::COMPUTE)
+            .map_err(|err| CubemapError::Compile(Box::new(err)))?;
+
+        // Separate image and sampler bindings, naga has no combined image samplers in GLSL
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+            vk::DescriptorType::STORAGE_IMAGE,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            })
+            .collect();
+        reflect::debug_validate(&[&code], &[&bindings], &[], &[])?;
+        let pool_sizes = types.map(|ty| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count: MAX_CONVERSIONS,
+        });
+        let mut sampler_desc = SamplerDesc::default()
+            .with_filter(SamplerFilter::Linear)
+            .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+        sampler_desc.address_mode[0] = vk::SamplerAddressMode::REPEAT;
+        let sampler = samplers.get(device, &sampler_desc)?;
+
+        let mut this = EquirectToCube {
+            set_layout: vk::DescriptorSetLayout::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            sampler,
+            pool: vk::DescriptorPool::null(),
+        };
+        let result = unsafe {
+            (|| {
+                this.set_layout = device.create_descriptor_set_layout(
+                    &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                    None,
+                )?;
+                leaks::track(this.set_layout, "cubemap descriptor set layout");
+                this.pipeline_layout = crate::pipeline::create_pipeline_layout(
+                    device,
+                    &vk::PipelineLayoutCreateInfo::builder().set_layouts(&[this.set_layout]),
+                )?;
+                leaks::track(this.pipeline_layout, "cubemap pipeline layout");
+                this.pool = device.create_descriptor_pool(
+                    &vk::DescriptorPoolCreateInfo::builder()
+                        .max_sets(MAX_CONVERSIONS)
+                        .pool_sizes(&pool_sizes),
+                    None,
+                )?;
+                leaks::track(this.pool, "cubemap descriptor pool");
+
+                let module = device.create_shader_module(
+                    &vk::ShaderModuleCreateInfo::builder().code(&code),
+                    None,
+                )?;
+                leaks::track(module, "cubemap shader module");
+                let stage = vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(vk::ShaderStageFlags::COMPUTE)
+                    .module(module)
+                    .name(c"main");
+                let info = vk::ComputePipelineCreateInfo::builder()
+                    .stage(*stage)
+                    .layout(this.pipeline_layout);
+                let pipelines =
+                    crate::pipeline::create_compute_pipelines(device, &[*info], "equirect to cube");
+                leaks::untrack(module);
+                device.destroy_shader_module(module, None);
+                this.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+                VkResult::Ok(())
+            })()
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Records the conversion of `equirect` into a new `face_size` cubemap, sampled from compute
+    ///
+    /// `equirect` must be in `SHADER_READ_ONLY_OPTIMAL`, as [`Texture::upload`] leaves it. The
+    /// returned cube ends in the same layout. Call [`EquirectToCube::reset`] once the recorded
+    /// commands have finished to free their descriptors.
+    #[track_caller]
+    pub fn convert(
+        &self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        cmd: vk::CommandBuffer,
+        equirect: &Texture,
+        face_size: u32,
+    ) -> VkResult<Texture> {
+        let cube = create_cube(device, mem_props, face_size)?;
+        let set = match unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(self.pool)
+                    .set_layouts(&[self.set_layout]),
+            )
+        } {
+            Ok(sets) => sets[0],
+            Err(err) => {
+                unsafe { cube.destroy(device) };
+                return Err(err);
+            }
+        };
+
+        // Storage writes need a plain array view, the cube view is for sampling
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 6,
+        };
+        let array_view = match unsafe {
+            device.create_image_view(
+                &vk::ImageViewCreateInfo::builder()
+                    .image(cube.image)
+                    .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+                    .format(HDR_FORMAT)
+                    .subresource_range(range),
+                None,
+            )
+        } {
+            Ok(view) => {
+                leaks::track(view, "cubemap storage view");
+                view
+            }
+            Err(err) => {
+                unsafe { cube.destroy(device) };
+                return Err(err);
+            }
+        };
+
+        let sampled = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: equirect.view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let storage = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: array_view,
+            image_layout: vk::ImageLayout::GENERAL,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&sampled)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&storage)
+                .build(),
+        ];
+        let groups = face_size.div_ceil(WORKGROUP_SIZE);
+        unsafe {
+            device.update_descriptor_sets(&writes, &[]);
+            transition(
+                device,
+                cmd,
+                cube.image,
+                range,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::GENERAL,
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[set],
+                &[],
+            );
+            device.cmd_dispatch(cmd, groups, groups, 6);
+            transition(
+                device,
+                cmd,
+                cube.image,
+                range,
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        }
+        // The array view has to outlive the dispatch, so the cube owns it
+        Ok(Texture {
+            storage_view: array_view,
+            ..cube
+        })
+    }
+
+    /// Frees the descriptors of every recorded conversion
+    ///
+    /// # Safety
+    ///
+    /// Every command buffer recorded with [`EquirectToCube::convert`] must have finished executing.
+    pub unsafe fn reset(&self, device: &Device) -> VkResult<()> {
+        device.reset_descriptor_pool(self.pool, vk::DescriptorPoolResetFlags::empty())
+    }
+
+    /// # Safety
+    ///
+    /// No recorded conversion may still be executing.
+    pub unsafe fn destroy(&self, device: &Device) {
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+    }
+}
+
+#[track_caller]
+fn create_cube(
+    device: &Device,
+    mem_props: &vk::PhysicalDeviceMemoryProperties,
+    face_size: u32,
+) -> VkResult<Texture> {
+    let extent = vk::Extent3D {
+        width: face_size,
+        height: face_size,
+        depth: 1,
+    };
+    let info = vk::ImageCreateInfo::builder()
+        .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(HDR_FORMAT)
+        .extent(extent)
+        .mip_levels(1)
+        .array_layers(6)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+    let image = unsafe { device.create_image(&info, None)? };
+
+    let requirements = unsafe { device.get_image_memory_requirements(image) };
+    let Some(type_index) = find_memory_type(
+        mem_props,
+        requirements.memory_type_bits,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    ) else {
+        unsafe { device.destroy_image(image, None) };
+        return Err(vk::Result::ERROR_FEATURE_NOT_PRESENT);
+    };
+    let alloc_info = vk::MemoryAllocateInfo::builder()
+        .allocation_size(requirements.size)
+        .memory_type_index(type_index);
+    let memory = match unsafe { device.allocate_memory(&alloc_info, None) } {
+        Ok(memory) => memory,
+        Err(err) => {
+            unsafe { device.destroy_image(image, None) };
+            return Err(err);
+        }
+    };
+    usage::record_allocation(MemoryCategory::Texture, requirements.size);
+    leaks::track(image, "cubemap image");
+    leaks::track(memory, "cubemap memory");
+    let mut cube = Texture {
+        image,
+        view: vk::ImageView::null(),
+        storage_view: vk::ImageView::null(),
+        memory,
+        allocation_size: requirements.size,
+        format: HDR_FORMAT,
+        extent,
+        mip_levels: 1,
+        layers: 6,
+    };
+    let result = unsafe {
+        device.bind_image_memory(image, memory, 0).and_then(|_| {
+            let view_info = vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(vk::ImageViewType::CUBE)
+                .format(HDR_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 6,
+                });
+            device.create_image_view(&view_info, None)
+        })
+    };
+    match result {
+        Ok(view) => {
+            leaks::track(view, "cubemap view");
+            cube.view = view;
+            Ok(cube)
+        }
+        Err(err) => {
+            unsafe { cube.destroy(device) };
+            Err(err)
+        }
+    }
+}
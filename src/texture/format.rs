@@ -0,0 +1,130 @@
+//! Texel block sizes of uncompressed and block compressed formats, and device support queries
+
+use ash::{vk, Instance};
+
+/// Size of one texel block, 1x1 for uncompressed formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u32,
+}
+
+impl BlockInfo {
+    const fn new(width: u32, height: u32, bytes: u32) -> Self {
+        BlockInfo {
+            width,
+            height,
+            bytes,
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.width > 1 || self.height > 1
+    }
+
+    /// Bytes taken by an image of `extent` with `layers` layers, partial blocks rounded up
+    pub fn image_size(&self, extent: vk::Extent3D, layers: u32) -> usize {
+        let blocks_x = extent.width.div_ceil(self.width) as usize;
+        let blocks_y = extent.height.div_ceil(self.height) as usize;
+        blocks_x * blocks_y * extent.depth as usize * layers as usize * self.bytes as usize
+    }
+}
+
+/// Block layout of formats textures are commonly shipped in, `None` for anything else
+pub fn block_info(format: vk::Format) -> Option<BlockInfo> {
+    use vk::Format as F;
+    let info = match format {
+        F::R8_UNORM | F::R8_SNORM | F::R8_UINT | F::R8_SRGB => BlockInfo::new(1, 1, 1),
+        F::R8G8_UNORM | F::R8G8_SNORM | F::R8G8_UINT | F::R16_SFLOAT | F::R16_UNORM => {
+            BlockInfo::new(1, 1, 2)
+        }
+        F::R8G8B8_UNORM | F::R8G8B8_SRGB => BlockInfo::new(1, 1, 3),
+        F::R8G8B8A8_UNORM
+        | F::R8G8B8A8_SNORM
+        | F::R8G8B8A8_UINT
+        | F::R8G8B8A8_SRGB
+        | F::B8G8R8A8_UNORM
+        | F::B8G8R8A8_SRGB
+        | F::A2B10G10R10_UNORM_PACK32
+        | F::B10G11R11_UFLOAT_PACK32
+        | F::E5B9G9R9_UFLOAT_PACK32
+        | F::R16G16_SFLOAT
+        | F::R32_SFLOAT
+        | F::R32_UINT => BlockInfo::new(1, 1, 4),
+        F::R16G16B16A16_SFLOAT | F::R16G16B16A16_UNORM | F::R32G32_SFLOAT => {
+            BlockInfo::new(1, 1, 8)
+        }
+        F::R32G32B32A32_SFLOAT => BlockInfo::new(1, 1, 16),
+
+        F::BC1_RGB_UNORM_BLOCK
+        | F::BC1_RGB_SRGB_BLOCK
+        | F::BC1_RGBA_UNORM_BLOCK
+        | F::BC1_RGBA_SRGB_BLOCK
+        | F::BC4_UNORM_BLOCK
+        | F::BC4_SNORM_BLOCK => BlockInfo::new(4, 4, 8),
+        F::BC2_UNORM_BLOCK
+        | F::BC2_SRGB_BLOCK
+        | F::BC3_UNORM_BLOCK
+        | F::BC3_SRGB_BLOCK
+        | F::BC5_UNORM_BLOCK
+        | F::BC5_SNORM_BLOCK
+        | F::BC6H_UFLOAT_BLOCK
+        | F::BC6H_SFLOAT_BLOCK
+        | F::BC7_UNORM_BLOCK
+        | F::BC7_SRGB_BLOCK => BlockInfo::new(4, 4, 16),
+
+        F::ETC2_R8G8B8_UNORM_BLOCK
+        | F::ETC2_R8G8B8_SRGB_BLOCK
+        | F::ETC2_R8G8B8A1_UNORM_BLOCK
+        | F::ETC2_R8G8B8A1_SRGB_BLOCK
+        | F::EAC_R11_UNORM_BLOCK
+        | F::EAC_R11_SNORM_BLOCK => BlockInfo::new(4, 4, 8),
+        F::ETC2_R8G8B8A8_UNORM_BLOCK
+        | F::ETC2_R8G8B8A8_SRGB_BLOCK
+        | F::EAC_R11G11_UNORM_BLOCK
+        | F::EAC_R11G11_SNORM_BLOCK => BlockInfo::new(4, 4, 16),
+
+        // Every ASTC block is 16 bytes whatever its footprint
+        F::ASTC_4X4_UNORM_BLOCK | F::ASTC_4X4_SRGB_BLOCK => BlockInfo::new(4, 4, 16),
+        F::ASTC_5X4_UNORM_BLOCK | F::ASTC_5X4_SRGB_BLOCK => BlockInfo::new(5, 4, 16),
+        F::ASTC_5X5_UNORM_BLOCK | F::ASTC_5X5_SRGB_BLOCK => BlockInfo::new(5, 5, 16),
+        F::ASTC_6X5_UNORM_BLOCK | F::ASTC_6X5_SRGB_BLOCK => BlockInfo::new(6, 5, 16),
+        F::ASTC_6X6_UNORM_BLOCK | F::ASTC_6X6_SRGB_BLOCK => BlockInfo::new(6, 6, 16),
+        F::ASTC_8X5_UNORM_BLOCK | F::ASTC_8X5_SRGB_BLOCK => BlockInfo::new(8, 5, 16),
+        F::ASTC_8X6_UNORM_BLOCK | F::ASTC_8X6_SRGB_BLOCK => BlockInfo::new(8, 6, 16),
+        F::ASTC_8X8_UNORM_BLOCK | F::ASTC_8X8_SRGB_BLOCK => BlockInfo::new(8, 8, 16),
+        F::ASTC_10X5_UNORM_BLOCK | F::ASTC_10X5_SRGB_BLOCK => BlockInfo::new(10, 5, 16),
+        F::ASTC_10X6_UNORM_BLOCK | F::ASTC_10X6_SRGB_BLOCK => BlockInfo::new(10, 6, 16),
+        F::ASTC_10X8_UNORM_BLOCK | F::ASTC_10X8_SRGB_BLOCK => BlockInfo::new(10, 8, 16),
+        F::ASTC_10X10_UNORM_BLOCK | F::ASTC_10X10_SRGB_BLOCK => BlockInfo::new(10, 10, 16),
+        F::ASTC_12X10_UNORM_BLOCK | F::ASTC_12X10_SRGB_BLOCK => BlockInfo::new(12, 10, 16),
+        F::ASTC_12X12_UNORM_BLOCK | F::ASTC_12X12_SRGB_BLOCK => BlockInfo::new(12, 12, 16),
+        _ => return None,
+    };
+    Some(info)
+}
+
+/// Whether the device can sample `format` with linear filtering from optimal tiling images
+pub fn is_sampleable(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    format: vk::Format,
+) -> bool {
+    let needed =
+        vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+    let props = unsafe { instance.get_physical_device_format_properties(physical_device, format) };
+    props.optimal_tiling_features.contains(needed)
+}
+
+/// The first of `candidates` the device can sample, e.g. the BC, ASTC and ETC2 versions of an asset
+pub fn pick_sampleable(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    candidates: &[vk::Format],
+) -> Option<vk::Format> {
+    candidates
+        .iter()
+        .copied()
+        .find(|&format| is_sampleable(instance, physical_device, format))
+}
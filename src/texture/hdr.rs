@@ -0,0 +1,182 @@
+//! High dynamic range images, Radiance `.hdr` and OpenEXR, decoded to half float RGBA
+
+use std::io::Cursor;
+
+use ash::vk;
+use exr::prelude::{self as exr_prelude, f16, ReadChannels, ReadLayers};
+use thiserror::Error;
+
+use super::TextureData;
+
+#[derive(Debug, Error)]
+pub enum HdrError {
+    #[error("invalid Radiance HDR file: {0}")]
+    Radiance(&'static str),
+    #[error("invalid OpenEXR file: {0}")]
+    Exr(#[from] exr::error::Error),
+}
+
+/// Format every HDR loader produces, sampleable and storable on all devices
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Loads `.hdr` or `.exr` bytes, telling them apart by their magic number
+pub fn load(bytes: &[u8]) -> Result<TextureData, HdrError> {
+    if bytes.starts_with(&[0x76, 0x2f, 0x31, 0x01]) {
+        load_exr(bytes)
+    } else {
+        load_radiance(bytes)
+    }
+}
+
+/// Loads the first RGB(A) layer of an OpenEXR image, alpha defaults to one
+pub fn load_exr(bytes: &[u8]) -> Result<TextureData, HdrError> {
+    let image = exr_prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .rgba_channels(
+            |size, _| (size.width(), vec![f16::ZERO; size.area() * 4]),
+            |(width, texels): &mut (usize, Vec<f16>), pos, (r, g, b, a): (f16, f16, f16, f16)| {
+                let i = (pos.y() * *width + pos.x()) * 4;
+                texels[i..i + 4].copy_from_slice(&[r, g, b, a]);
+            },
+        )
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(Cursor::new(bytes))?;
+    let size = image.layer_data.size;
+    let (_, texels) = image.layer_data.channel_data.pixels;
+    Ok(texture_data(
+        size.width() as u32,
+        size.height() as u32,
+        &texels,
+    ))
+}
+
+/// Loads a Radiance RGBE image, flat or with new style run length encoded scanlines
+pub fn load_radiance(bytes: &[u8]) -> Result<TextureData, HdrError> {
+    let (width, height, mut data) = radiance_header(bytes)?;
+    let mut texels = Vec::with_capacity(width * height * 4);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        data = read_scanline(data, &mut scanline)?;
+        for rgbe in &scanline {
+            let [r, g, b] = rgbe_to_rgb(*rgbe);
+            texels.extend([r, g, b, 1.0].map(f16::from_f32));
+        }
+    }
+    Ok(texture_data(width as u32, height as u32, &texels))
+}
+
+fn texture_data(width: u32, height: u32, texels: &[f16]) -> TextureData {
+    let level = texels
+        .iter()
+        .flat_map(|texel| texel.to_le_bytes())
+        .collect();
+    TextureData {
+        format: HDR_FORMAT,
+        extent: vk::Extent3D {
+            width,
+            height,
+            depth: 1,
+        },
+        layers: 1,
+        cube: false,
+        levels: vec![level],
+    }
+}
+
+/// Parses the text header, returning the size and the pixel data after it
+fn radiance_header(bytes: &[u8]) -> Result<(usize, usize, &[u8]), HdrError> {
+    let mut rest = bytes;
+    let mut next_line = || {
+        let end = rest.iter().position(|&b| b == b'\n')?;
+        let line = std::str::from_utf8(&rest[..end]).ok();
+        rest = &rest[end + 1..];
+        line
+    };
+    let magic = next_line().ok_or(HdrError::Radiance("missing header"))?;
+    if !magic.starts_with("#?") {
+        return Err(HdrError::Radiance("missing #? magic"));
+    }
+    loop {
+        let line = next_line().ok_or(HdrError::Radiance("unterminated header"))?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(HdrError::Radiance("only RGBE pixels are supported"));
+            }
+        }
+    }
+    // Only the standard top to bottom, left to right orientation is supported
+    let resolution = next_line().ok_or(HdrError::Radiance("missing resolution"))?;
+    let parts: Vec<_> = resolution.split_whitespace().collect();
+    let (width, height) = match parts.as_slice() {
+        ["-Y", height, "+X", width] => (width.parse().ok(), height.parse().ok()),
+        _ => return Err(HdrError::Radiance("unsupported image orientation")),
+    };
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height, rest)),
+        _ => Err(HdrError::Radiance("invalid resolution")),
+    }
+}
+
+const TRUNCATED: HdrError = HdrError::Radiance("truncated pixel data");
+
+fn read_scanline<'a>(data: &'a [u8], out: &mut [[u8; 4]]) -> Result<&'a [u8], HdrError> {
+    let width = out.len();
+    let rle = (8..0x8000).contains(&width)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && data[2] & 0x80 == 0;
+    if !rle {
+        let bytes = data.get(..width * 4).ok_or(TRUNCATED)?;
+        for (texel, rgbe) in out.iter_mut().zip(bytes.chunks_exact(4)) {
+            texel.copy_from_slice(rgbe);
+        }
+        return Ok(&data[width * 4..]);
+    }
+    if usize::from(data[2]) << 8 | usize::from(data[3]) != width {
+        return Err(HdrError::Radiance("scanline width mismatch"));
+    }
+
+    // Each of the four components is run length encoded separately
+    let mut data = &data[4..];
+    for component in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first().ok_or(TRUNCATED)?;
+            if count > 128 {
+                let run = usize::from(count - 128);
+                let (&value, rest) = rest.split_first().ok_or(TRUNCATED)?;
+                let texels = out.get_mut(x..x + run).ok_or(TRUNCATED)?;
+                texels.iter_mut().for_each(|texel| texel[component] = value);
+                x += run;
+                data = rest;
+            } else {
+                let run = usize::from(count);
+                if run == 0 {
+                    return Err(HdrError::Radiance("empty run"));
+                }
+                let values = rest.get(..run).ok_or(TRUNCATED)?;
+                let texels = out.get_mut(x..x + run).ok_or(TRUNCATED)?;
+                for (texel, &value) in texels.iter_mut().zip(values) {
+                    texel[component] = value;
+                }
+                x += run;
+                data = &rest[run..];
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn rgbe_to_rgb([r, g, b, e]: [u8; 4]) -> [f32; 3] {
+    if e == 0 {
+        return [0.0; 3];
+    }
+    let scale = 2f32.powi(i32::from(e) - 136);
+    [r, g, b].map(|c| (f32::from(c) + 0.5) * scale)
+}
@@ -0,0 +1,213 @@
+//! Texture uploads written straight from host memory with `VK_EXT_host_image_copy`
+//!
+//! The host copies into the optimal tiling image itself, without a staging buffer, command
+//! buffer or submission. That suits integrated GPUs sharing memory with the host best, where
+//! [`HostImageCopy::is_preferred`] says the images' memory doesn't change for it. Elsewhere
+//! [`Texture::upload`] through the staging belt stays the faster path.
+//!
+//! ash 0.37 predates the extension, its declarations are in [`vk_ext`]. Needs
+//! [`Feature::HostImageCopy`], which needs a 1.3 device.
+//!
+//! [`Feature::HostImageCopy`]: crate::device::Feature::HostImageCopy
+//! [`vk_ext`]: crate::device::vk_ext
+
+use std::{ffi::CStr, mem, ptr};
+
+use ash::{prelude::VkResult, vk, Device, Instance};
+
+use super::{mip_extent, Texture, TextureData};
+use crate::{
+    assets::GpuAsset,
+    device::{
+        vk_ext::{
+            CopyMemoryToImage, CopyMemoryToImageInfoEXT, HostImageLayoutTransitionInfoEXT,
+            MemoryToImageCopyEXT, PhysicalDeviceHostImageCopyPropertiesEXT, TransitionImageLayout,
+            FORMAT_FEATURE_HOST_IMAGE_TRANSFER, IMAGE_USAGE_HOST_TRANSFER,
+        },
+        EnabledFeatures, Feature,
+    },
+};
+
+/// The extension's commands, and the layouts the device copies into
+pub struct HostImageCopy {
+    instance: Instance,
+    physical_device: vk::PhysicalDevice,
+    copy_memory_to_image: CopyMemoryToImage,
+    transition_image_layout: TransitionImageLayout,
+    /// The layout copies are done in, ideally the one textures are sampled in
+    copy_layout: vk::ImageLayout,
+    identical_memory_types: bool,
+}
+
+impl HostImageCopy {
+    /// `None` unless `device` was created with [`Feature::HostImageCopy`]
+    pub fn new(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        enabled: &EnabledFeatures,
+    ) -> Option<Self> {
+        if !enabled.has_feature(Feature::HostImageCopy) {
+            return None;
+        }
+        let load =
+            |name: &CStr| unsafe { instance.get_device_proc_addr(device.handle(), name.as_ptr()) };
+        let copy = load(c"vkCopyMemoryToImageEXT")?;
+        let transition = load(c"vkTransitionImageLayoutEXT")?;
+
+        let mut props = PhysicalDeviceHostImageCopyPropertiesEXT {
+            s_type: vk::StructureType::from_raw(1_000_270_001),
+            p_next: ptr::null_mut(),
+            copy_src_layout_count: 0,
+            p_copy_src_layouts: ptr::null_mut(),
+            copy_dst_layout_count: 0,
+            p_copy_dst_layouts: ptr::null_mut(),
+            optimal_tiling_layout_uuid: [0; vk::UUID_SIZE],
+            identical_memory_type_requirements: vk::FALSE,
+        };
+        let query = |props: &mut PhysicalDeviceHostImageCopyPropertiesEXT| unsafe {
+            let mut props2 = vk::PhysicalDeviceProperties2::builder().push_next(props);
+            instance.get_physical_device_properties2(physical_device, &mut props2);
+        };
+        // Counts first, then the layouts themselves
+        query(&mut props);
+        let mut dst_layouts =
+            vec![vk::ImageLayout::UNDEFINED; props.copy_dst_layout_count as usize];
+        props.copy_src_layout_count = 0;
+        props.p_copy_dst_layouts = dst_layouts.as_mut_ptr();
+        query(&mut props);
+        dst_layouts.truncate(props.copy_dst_layout_count as usize);
+
+        // Copying in the sampled layout saves a transition afterwards
+        let copy_layout = [
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::GENERAL,
+        ]
+        .into_iter()
+        .find(|layout| dst_layouts.contains(layout))
+        .or_else(|| dst_layouts.first().copied())?;
+        Some(HostImageCopy {
+            instance: instance.clone(),
+            physical_device,
+            copy_memory_to_image: unsafe {
+                mem::transmute::<unsafe extern "system" fn(), CopyMemoryToImage>(copy)
+            },
+            transition_image_layout: unsafe {
+                mem::transmute::<unsafe extern "system" fn(), TransitionImageLayout>(transition)
+            },
+            copy_layout,
+            identical_memory_types: props.identical_memory_type_requirements == vk::TRUE,
+        })
+    }
+
+    /// Whether host copyable images get the same memory types as others, so the upload costs
+    /// nothing at draw time; usually only on integrated GPUs
+    pub fn is_preferred(&self) -> bool {
+        self.identical_memory_types
+    }
+
+    /// Whether optimal tiling images of `format` can be copied to from the host
+    pub fn supports(&self, format: vk::Format) -> bool {
+        let mut props3 = vk::FormatProperties3::default();
+        let mut props2 = vk::FormatProperties2::builder().push_next(&mut props3);
+        unsafe {
+            self.instance.get_physical_device_format_properties2(
+                self.physical_device,
+                format,
+                &mut props2,
+            )
+        };
+        props3
+            .optimal_tiling_features
+            .contains(FORMAT_FEATURE_HOST_IMAGE_TRANSFER | vk::FormatFeatureFlags2::SAMPLED_IMAGE)
+    }
+
+    /// Creates the image and writes every level from `data` before returning
+    ///
+    /// The image is in `SHADER_READ_ONLY_OPTIMAL` and usable by any later submission, like
+    /// [`Texture::upload`] without the staging belt or command buffer. The format must pass
+    /// [`HostImageCopy::supports`].
+    #[track_caller]
+    pub fn upload(
+        &self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        data: &TextureData,
+    ) -> VkResult<Texture> {
+        let usage = vk::ImageUsageFlags::SAMPLED | IMAGE_USAGE_HOST_TRANSFER;
+        let texture = Texture::allocate(device, mem_props, data, usage)?;
+        match unsafe { self.write(device, &texture, data) } {
+            Ok(()) => Ok(texture),
+            Err(err) => {
+                unsafe { texture.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn write(&self, device: &Device, texture: &Texture, data: &TextureData) -> VkResult<()> {
+        self.transition(
+            device,
+            texture,
+            vk::ImageLayout::UNDEFINED,
+            self.copy_layout,
+        )?;
+        let regions: Vec<MemoryToImageCopyEXT> = (0..)
+            .zip(&data.levels)
+            .map(|(level, bytes)| MemoryToImageCopyEXT {
+                s_type: vk::StructureType::from_raw(1_000_270_002),
+                p_next: ptr::null(),
+                p_host_pointer: bytes.as_ptr().cast(),
+                // Tightly packed, like the staging belt copies
+                memory_row_length: 0,
+                memory_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: data.layers,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: mip_extent(data.extent, level),
+            })
+            .collect();
+        let info = CopyMemoryToImageInfoEXT {
+            s_type: vk::StructureType::from_raw(1_000_270_005),
+            p_next: ptr::null(),
+            flags: 0,
+            dst_image: texture.image,
+            dst_image_layout: self.copy_layout,
+            region_count: regions.len() as u32,
+            p_regions: regions.as_ptr(),
+        };
+        (self.copy_memory_to_image)(device.handle(), &info).result()?;
+        if self.copy_layout != vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL {
+            self.transition(
+                device,
+                texture,
+                self.copy_layout,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            )?;
+        }
+        Ok(())
+    }
+
+    unsafe fn transition(
+        &self,
+        device: &Device,
+        texture: &Texture,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+    ) -> VkResult<()> {
+        let info = HostImageLayoutTransitionInfoEXT {
+            s_type: vk::StructureType::from_raw(1_000_270_006),
+            p_next: ptr::null(),
+            image: texture.image,
+            old_layout,
+            new_layout,
+            subresource_range: texture.range(),
+        };
+        (self.transition_image_layout)(device.handle(), 1, &info).result()
+    }
+}
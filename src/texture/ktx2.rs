@@ -0,0 +1,77 @@
+//! KTX2 containers, including Zstandard and zlib supercompressed levels
+
+use std::io::Read;
+
+use ash::vk;
+use thiserror::Error;
+
+use super::{TextureData, TextureDataError};
+
+#[derive(Debug, Error)]
+pub enum Ktx2Error {
+    #[error("invalid KTX2 file: {0}")]
+    Parse(#[from] ::ktx2::ParseError),
+    #[error("KTX2 file has no Vulkan format, it needs transcoding")]
+    NeedsTranscoding,
+    #[error("unsupported supercompression scheme {0:?}")]
+    UnsupportedSupercompression(::ktx2::SupercompressionScheme),
+    #[error(transparent)]
+    Invalid(#[from] TextureDataError),
+    #[error("failed to decompress mip level {level}: {message}")]
+    Decompress { level: usize, message: String },
+}
+
+/// Reads every level, layer and face of a KTX2 file in its embedded format
+///
+/// Files without a format, i.e. Basis Universal payloads, return [`Ktx2Error::NeedsTranscoding`].
+pub fn load(bytes: &[u8]) -> Result<TextureData, Ktx2Error> {
+    let reader = ::ktx2::Reader::new(bytes)?;
+    let header = reader.header();
+    let format = header.format.ok_or(Ktx2Error::NeedsTranscoding)?;
+
+    let levels = reader
+        .levels()
+        .enumerate()
+        .map(|(level, data)| decompress(header.supercompression_scheme, level, data))
+        .collect::<Result<_, _>>()?;
+
+    let data = TextureData {
+        format: vk::Format::from_raw(format.0.get() as i32),
+        extent: vk::Extent3D {
+            width: header.pixel_width,
+            height: header.pixel_height.max(1),
+            depth: header.pixel_depth.max(1),
+        },
+        layers: header.layer_count.max(1) * header.face_count,
+        cube: header.face_count == 6,
+        levels,
+    };
+    data.validate()?;
+    Ok(data)
+}
+
+pub(super) fn decompress(
+    scheme: Option<::ktx2::SupercompressionScheme>,
+    level: usize,
+    data: &[u8],
+) -> Result<Vec<u8>, Ktx2Error> {
+    let error = |message: String| Ktx2Error::Decompress { level, message };
+    let mut out = Vec::new();
+    match scheme {
+        None => out.extend_from_slice(data),
+        Some(::ktx2::SupercompressionScheme::Zstandard) => {
+            let mut decoder =
+                ruzstd::StreamingDecoder::new(data).map_err(|err| error(err.to_string()))?;
+            decoder
+                .read_to_end(&mut out)
+                .map_err(|err| error(err.to_string()))?;
+        }
+        Some(::ktx2::SupercompressionScheme::ZLIB) => {
+            flate2::read::ZlibDecoder::new(data)
+                .read_to_end(&mut out)
+                .map_err(|err| error(err.to_string()))?;
+        }
+        Some(scheme) => return Err(Ktx2Error::UnsupportedSupercompression(scheme)),
+    }
+    Ok(out)
+}
@@ -0,0 +1,206 @@
+//! 3D color lookup tables for grading, from Adobe/Resolve `.cube` files or unwrapped PNG strips
+//!
+//! Tables become half float RGBA volumes with red along x, green along y and blue along z, the
+//! order `.cube` files list their entries in.
+
+use ash::vk;
+use exr::prelude::f16;
+use glam::Vec3;
+use thiserror::Error;
+
+use super::{hdr::HDR_FORMAT, TextureData};
+
+#[derive(Debug, Error)]
+pub enum LutError {
+    #[error("invalid .cube file at line {line}: {message}")]
+    Cube { line: usize, message: &'static str },
+    #[error("invalid LUT strip: {0}")]
+    Strip(&'static str),
+    #[error(transparent)]
+    Png(#[from] png::DecodingError),
+}
+
+/// Input range the table covers, colors are remapped from it before the lookup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LutDomain {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for LutDomain {
+    fn default() -> Self {
+        LutDomain {
+            min: Vec3::ZERO,
+            max: Vec3::ONE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Lut {
+    pub title: Option<String>,
+    /// Entries per side
+    pub size: u32,
+    pub domain: LutDomain,
+    pub data: TextureData,
+}
+
+/// Table that leaves colors unchanged, two entries per side are exact under linear filtering
+pub fn identity() -> Lut {
+    let size = 2;
+    let entries: Vec<_> = (0..size * size * size)
+        .map(|i| Vec3::new((i % 2) as f32, (i / 2 % 2) as f32, (i / 4) as f32))
+        .collect();
+    Lut {
+        title: None,
+        size,
+        domain: LutDomain::default(),
+        data: texture_data(size, &entries),
+    }
+}
+
+/// Parses a `.cube` file's 3D table, 1D tables aren't supported
+pub fn load_cube(text: &str) -> Result<Lut, LutError> {
+    let mut title = None;
+    let mut size = None;
+    let mut domain = LutDomain::default();
+    let mut entries = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message| LutError::Cube {
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let floats = || -> Result<Vec<f32>, LutError> {
+            rest.split_whitespace()
+                .map(|value| value.parse().map_err(|_| error("expected a number")))
+                .collect()
+        };
+        let vec3 = || -> Result<Vec3, LutError> {
+            match floats()?[..] {
+                [r, g, b] => Ok(Vec3::new(r, g, b)),
+                _ => Err(error("expected three numbers")),
+            }
+        };
+        match keyword {
+            "TITLE" => title = Some(rest.trim_matches('"').to_owned()),
+            "LUT_3D_SIZE" => {
+                let parsed: u32 = rest.parse().map_err(|_| error("invalid size"))?;
+                if !(2..=256).contains(&parsed) {
+                    return Err(error("size must be between 2 and 256"));
+                }
+                size = Some(parsed);
+            }
+            "LUT_1D_SIZE" => return Err(error("1D tables are not supported")),
+            "DOMAIN_MIN" => domain.min = vec3()?,
+            "DOMAIN_MAX" => domain.max = vec3()?,
+            // Resolve's spelling of the domain, the same for every channel
+            "LUT_3D_INPUT_RANGE" => match floats()?[..] {
+                [min, max] => {
+                    domain.min = Vec3::splat(min);
+                    domain.max = Vec3::splat(max);
+                }
+                _ => return Err(error("expected two numbers")),
+            },
+            _ if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {}
+            _ => {
+                let values: Vec<f32> = line
+                    .split_whitespace()
+                    .map(|value| value.parse().map_err(|_| error("expected a number")))
+                    .collect::<Result<_, _>>()?;
+                match values[..] {
+                    [r, g, b] => entries.push(Vec3::new(r, g, b)),
+                    _ => return Err(error("expected three numbers")),
+                }
+            }
+        }
+    }
+    let line = text.lines().count();
+    let size = size.ok_or(LutError::Cube {
+        line,
+        message: "missing LUT_3D_SIZE",
+    })?;
+    if entries.len() != (size * size * size) as usize {
+        return Err(LutError::Cube {
+            line,
+            message: "entry count does not match LUT_3D_SIZE",
+        });
+    }
+    Ok(Lut {
+        title,
+        size,
+        domain,
+        data: texture_data(size, &entries),
+    })
+}
+
+/// Decodes a PNG of the table's blue slices side by side, `size * size` wide and `size` high
+pub fn load_strip(bytes: &[u8]) -> Result<Lut, LutError> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    let size = info.height;
+    if size < 2 || info.width != size * size {
+        return Err(LutError::Strip("width must be the height squared"));
+    }
+    let channels = info.color_type.samples();
+    if channels < 3 {
+        return Err(LutError::Strip("expected an RGB image"));
+    }
+    let texels: Vec<Vec3> = match info.bit_depth {
+        png::BitDepth::Sixteen => pixels[..info.buffer_size()]
+            .chunks_exact(2 * channels)
+            .map(|pixel| {
+                let channel = |i: usize| {
+                    u16::from_be_bytes([pixel[2 * i], pixel[2 * i + 1]]) as f32 / 65535.0
+                };
+                Vec3::new(channel(0), channel(1), channel(2))
+            })
+            .collect(),
+        // Expanded to 8 bits
+        _ => pixels[..info.buffer_size()]
+            .chunks_exact(channels)
+            .map(|pixel| Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.0)
+            .collect(),
+    };
+    // Rows run across every slice, reorder to slice by slice
+    let n = size as usize;
+    let entries: Vec<_> = (0..n * n * n)
+        .map(|i| {
+            let (r, g, b) = (i % n, i / n % n, i / (n * n));
+            texels[g * n * n + b * n + r]
+        })
+        .collect();
+    Ok(Lut {
+        title: None,
+        size,
+        domain: LutDomain::default(),
+        data: texture_data(size, &entries),
+    })
+}
+
+fn texture_data(size: u32, entries: &[Vec3]) -> TextureData {
+    let level = entries
+        .iter()
+        .flat_map(|entry| [entry.x, entry.y, entry.z, 1.0])
+        .flat_map(|value| f16::from_f32(value).to_le_bytes())
+        .collect();
+    TextureData {
+        format: HDR_FORMAT,
+        extent: vk::Extent3D {
+            width: size,
+            height: size,
+            depth: size,
+        },
+        layers: 1,
+        cube: false,
+        levels: vec![level],
+    }
+}
@@ -0,0 +1,204 @@
+//! Samplers shared through a keyed cache, with a global texture filtering quality
+
+use std::collections::HashMap;
+
+use ash::{prelude::VkResult, vk, Device};
+
+use crate::{leaks, trace::traced};
+
+/// Filtering applied to samplers that follow the global quality setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FilterQuality {
+    Nearest,
+    Bilinear,
+    Trilinear,
+    /// Trilinear with up to this many anisotropic samples, clamped to the device limit
+    Anisotropic(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SamplerFilter {
+    Nearest,
+    Linear,
+    /// Whatever [`SamplerCache::quality`] is set to
+    Quality,
+}
+
+/// What a sampler should do, resolved against the global quality when created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplerDesc {
+    pub filter: SamplerFilter,
+    pub address_mode: [vk::SamplerAddressMode; 3],
+    pub min_lod: f32,
+    pub max_lod: f32,
+    pub lod_bias: f32,
+    /// Depth comparison for shadow map lookups
+    pub compare: Option<vk::CompareOp>,
+    pub border_color: vk::BorderColor,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        SamplerDesc {
+            filter: SamplerFilter::Quality,
+            address_mode: [vk::SamplerAddressMode::REPEAT; 3],
+            min_lod: 0.0,
+            max_lod: vk::LOD_CLAMP_NONE,
+            lod_bias: 0.0,
+            compare: None,
+            border_color: vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+        }
+    }
+}
+
+impl SamplerDesc {
+    pub fn with_filter(mut self, filter: SamplerFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_address_mode(mut self, mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode = [mode; 3];
+        self
+    }
+
+    pub fn with_lod_clamp(mut self, min_lod: f32, max_lod: f32) -> Self {
+        self.min_lod = min_lod;
+        self.max_lod = max_lod;
+        self
+    }
+
+    pub fn with_compare(mut self, op: vk::CompareOp) -> Self {
+        self.compare = Some(op);
+        self
+    }
+}
+
+/// Fully resolved sampler state, hashed by the bits of its floats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SamplerKey {
+    mag: vk::Filter,
+    min: vk::Filter,
+    mipmap: vk::SamplerMipmapMode,
+    address_mode: [vk::SamplerAddressMode; 3],
+    anisotropy: u32,
+    lod: [u32; 3],
+    compare: Option<vk::CompareOp>,
+    border_color: vk::BorderColor,
+}
+
+pub struct SamplerCache {
+    samplers: HashMap<SamplerKey, vk::Sampler>,
+    quality: FilterQuality,
+    /// `None` when `samplerAnisotropy` isn't enabled on the device
+    max_anisotropy: Option<f32>,
+    generation: u64,
+}
+
+impl SamplerCache {
+    /// `max_anisotropy` is the device limit, if the `samplerAnisotropy` feature was enabled
+    pub fn new(quality: FilterQuality, max_anisotropy: Option<f32>) -> Self {
+        SamplerCache {
+            samplers: HashMap::new(),
+            quality,
+            max_anisotropy,
+            generation: 0,
+        }
+    }
+
+    pub fn quality(&self) -> FilterQuality {
+        self.quality
+    }
+
+    /// Changes the filtering of [`SamplerFilter::Quality`] samplers created from now on
+    ///
+    /// Samplers already handed out stay valid, descriptors have to be rewritten to pick up the new
+    /// quality. [`SamplerCache::generation`] changes whenever that's needed.
+    pub fn set_quality(&mut self, quality: FilterQuality) {
+        if quality != self.quality {
+            self.quality = quality;
+            self.generation += 1;
+        }
+    }
+
+    /// Bumped by every quality change
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the sampler for `desc`, creating it the first time it's asked for
+    #[track_caller]
+    pub fn get(&mut self, device: &Device, desc: &SamplerDesc) -> VkResult<vk::Sampler> {
+        let key = self.resolve(desc);
+        if let Some(&sampler) = self.samplers.get(&key) {
+            return Ok(sampler);
+        }
+
+        let info = vk::SamplerCreateInfo::builder()
+            .mag_filter(key.mag)
+            .min_filter(key.min)
+            .mipmap_mode(key.mipmap)
+            .address_mode_u(key.address_mode[0])
+            .address_mode_v(key.address_mode[1])
+            .address_mode_w(key.address_mode[2])
+            .mip_lod_bias(desc.lod_bias)
+            .anisotropy_enable(key.anisotropy > 1)
+            .max_anisotropy(key.anisotropy as f32)
+            .compare_enable(key.compare.is_some())
+            .compare_op(key.compare.unwrap_or(vk::CompareOp::ALWAYS))
+            .min_lod(desc.min_lod)
+            .max_lod(desc.max_lod)
+            .border_color(key.border_color);
+        let sampler = unsafe {
+            traced!(
+                "vkCreateSampler",
+                mag_filter = info.mag_filter,
+                mipmap_mode = info.mipmap_mode,
+                max_anisotropy = info.max_anisotropy;
+                device.create_sampler(&info, None)
+            )?
+        };
+        leaks::track(sampler, "cached sampler");
+        self.samplers.insert(key, sampler);
+        Ok(sampler)
+    }
+
+    fn resolve(&self, desc: &SamplerDesc) -> SamplerKey {
+        let quality = match desc.filter {
+            SamplerFilter::Nearest => FilterQuality::Nearest,
+            SamplerFilter::Linear => FilterQuality::Trilinear,
+            SamplerFilter::Quality => self.quality,
+        };
+        let (filter, mipmap) = match quality {
+            FilterQuality::Nearest => (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST),
+            FilterQuality::Bilinear => (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST),
+            FilterQuality::Trilinear | FilterQuality::Anisotropic(_) => {
+                (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR)
+            }
+        };
+        let anisotropy = match (quality, self.max_anisotropy) {
+            (FilterQuality::Anisotropic(samples), Some(max)) => (samples as f32).min(max) as u32,
+            _ => 1,
+        };
+        SamplerKey {
+            mag: filter,
+            min: filter,
+            mipmap,
+            address_mode: desc.address_mode,
+            anisotropy,
+            lod: [desc.min_lod, desc.max_lod, desc.lod_bias].map(f32::to_bits),
+            compare: desc.compare,
+            border_color: desc.border_color,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No descriptor referencing a cached sampler may still be in use.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for (_, sampler) in self.samplers.drain() {
+            leaks::untrack(sampler);
+            traced!("vkDestroySampler", sampler = sampler; device.destroy_sampler(sampler, None));
+        }
+    }
+}
@@ -0,0 +1,807 @@
+//! Virtual texturing on sparse images, pages bound and uploaded as the GPU asks for them
+//!
+//! The advanced alternative to [`super::streaming`]: rather than whole mip tails, single pages
+//! of a sparse resident image get memory from a fixed pool. Shaders drawing the texture include
+//! [`VIRTUAL_TEXTURE_GLSL`] and call `vt_request` to mark the pages they touch in the texture's
+//! [`VirtualTexture::feedback`] buffer. [`VirtualTexture::update`] reads those marks back, binds
+//! the missing pages in place of the ones unused the longest and records their uploads.
+//!
+//! Sampling clamps the level of detail to what's resident, so holes never show: fetch the
+//! [`VirtualTexture::residency`] texel under the UV with a point sampler and pass
+//! `vt_lod(uv, size)` raised to `255 * residency` to `textureLod`. Each page's coarser parents
+//! are kept resident along with it, and the mip tail is always resident.
+//!
+//! Needs `sparseBinding`, `sparseResidencyImage2D` and a queue in
+//! [`QueueFamilies::sparse_binding`]. Only single layer 2D textures are handled.
+//!
+//! [`QueueFamilies::sparse_binding`]: crate::device::QueueFamilies::sparse_binding
+
+use ash::{prelude::VkResult, vk, Device, Instance};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    leaks,
+    memory::{
+        find_memory_type,
+        staging::StagingBelt,
+        usage::{self, MemoryCategory},
+        Buffer,
+    },
+    trace::traced,
+};
+
+use super::{format::block_info, gcd, mip_extent, transition, Texture, TextureData};
+
+/// Functions for shaders drawing a virtual texture
+///
+/// The including shader declares the feedback buffer as
+/// `layout(std430, ...) buffer VtFeedback { uint vt_requests[]; };` before this. `size` is the
+/// texture's extent, `page_size` [`VirtualTexture::page_size`] and `tail_lod`
+/// [`VirtualTexture::tail_lod`], in fragment shaders only since they take derivatives.
+pub const VIRTUAL_TEXTURE_GLSL: &str = r#"
+float vt_lod(vec2 uv, vec2 size) {
+    vec2 dx = dFdx(uv * size);
+    vec2 dy = dFdy(uv * size);
+    return max(0.5 * log2(max(dot(dx, dx), dot(dy, dy))), 0.0);
+}
+
+uvec2 vt_level_pages(uvec2 size, uvec2 page_size, uint level) {
+    uvec2 level_size = max(size >> level, uvec2(1u));
+    return (level_size + page_size - 1u) / page_size;
+}
+
+void vt_request(vec2 uv, vec2 size, uvec2 page_size, uint tail_lod) {
+    uint level = uint(vt_lod(uv, size));
+    if (level >= tail_lod) {
+        return;
+    }
+    uvec2 texels = uvec2(size);
+    uint index = 0u;
+    for (uint l = 0u; l < level; l++) {
+        uvec2 pages = vt_level_pages(texels, page_size, l);
+        index += pages.x * pages.y;
+    }
+    uvec2 level_size = max(texels >> level, uvec2(1u));
+    uvec2 texel = min(uvec2(fract(uv) * vec2(level_size)), level_size - 1u);
+    uvec2 page = texel / page_size;
+    vt_requests[index + page.y * vt_level_pages(texels, page_size, level).x + page.x] = 1u;
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum VirtualTextureError {
+    #[error("{0} aren't supported")]
+    Unsupported(&'static str),
+    #[error("no memory type fits the texture's pages")]
+    NoMemoryType,
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualTextureConfig {
+    /// Pages of memory shared by everything finer than the mip tail
+    pub resident_pages: u32,
+    /// Updates a page stays bound after its last request, at least the frames in flight since
+    /// those may still sample it
+    pub min_page_age: u64,
+    /// Pages uploaded per [`VirtualTexture::update`], to keep page faults from causing hitches
+    pub uploads_per_update: usize,
+}
+
+impl Default for VirtualTextureConfig {
+    fn default() -> Self {
+        VirtualTextureConfig {
+            resident_pages: 256,
+            min_page_age: 3,
+            uploads_per_update: 32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Page {
+    /// Pool slot holding the page, if resident
+    slot: Option<u32>,
+    /// Update the page was last requested in
+    last_used: u64,
+}
+
+/// Where a level's pages start in the page list, and how many it has across and down
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    first: usize,
+    pages: vk::Extent2D,
+}
+
+/// A sparse resident texture with pages bound from a fixed pool on demand
+pub struct VirtualTexture {
+    config: VirtualTextureConfig,
+    data: TextureData,
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    residency: Option<Texture>,
+    feedback: Option<Buffer>,
+    page_size: vk::Extent2D,
+    page_bytes: vk::DeviceSize,
+    tail_lod: u32,
+    tail_memory: vk::DeviceMemory,
+    tail_size: vk::DeviceSize,
+    pool: vk::DeviceMemory,
+    levels: Vec<Level>,
+    pages: Vec<Page>,
+    /// The page in each pool slot
+    slots: Vec<Option<usize>>,
+    /// Signalled by the binds of each update, the upload waits on it
+    bound: vk::Semaphore,
+    updates: u64,
+}
+
+impl VirtualTexture {
+    /// Creates the image, binds and records the upload of its mip tail into `cmd`
+    ///
+    /// Binding the tail happens on `queue` and is waited for here, like any load time work.
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    pub fn new(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        queue: vk::Queue,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        data: TextureData,
+        config: VirtualTextureConfig,
+    ) -> Result<Self, VirtualTextureError> {
+        if data.layers != 1 || data.cube || data.extent.depth != 1 {
+            return Err(VirtualTextureError::Unsupported(
+                "arrays, cubes and volumes",
+            ));
+        }
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        let format_props = unsafe {
+            instance.get_physical_device_sparse_image_format_properties(
+                physical_device,
+                data.format,
+                vk::ImageType::TYPE_2D,
+                vk::SampleCountFlags::TYPE_1,
+                usage,
+                vk::ImageTiling::OPTIMAL,
+            )
+        };
+        let Some(granularity) = format_props
+            .iter()
+            .find(|props| props.aspect_mask.contains(vk::ImageAspectFlags::COLOR))
+            .map(|props| props.image_granularity)
+        else {
+            return Err(VirtualTextureError::Unsupported(
+                "sparse images of this format",
+            ));
+        };
+        let page_size = vk::Extent2D {
+            width: granularity.width,
+            height: granularity.height,
+        };
+        let mip_levels = data.levels.len() as u32;
+        let levels: Vec<Level> = (0..mip_levels)
+            .scan(0, |first, level| {
+                let extent = mip_extent(data.extent, level);
+                let pages = vk::Extent2D {
+                    width: extent.width.div_ceil(page_size.width),
+                    height: extent.height.div_ceil(page_size.height),
+                };
+                let entry = Level {
+                    first: *first,
+                    pages,
+                };
+                *first += (pages.width * pages.height) as usize;
+                Some(entry)
+            })
+            .collect();
+
+        let info = vk::ImageCreateInfo::builder()
+            .flags(vk::ImageCreateFlags::SPARSE_BINDING | vk::ImageCreateFlags::SPARSE_RESIDENCY)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(data.format)
+            .extent(data.extent)
+            .mip_levels(mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe { device.create_image(&info, None)? };
+        leaks::track(image, "virtual texture image");
+        let mut texture = VirtualTexture {
+            config,
+            data,
+            image,
+            view: vk::ImageView::null(),
+            residency: None,
+            feedback: None,
+            page_size,
+            page_bytes: 0,
+            tail_lod: mip_levels,
+            tail_memory: vk::DeviceMemory::null(),
+            tail_size: 0,
+            pool: vk::DeviceMemory::null(),
+            levels,
+            pages: Vec::new(),
+            slots: vec![None; config.resident_pages as usize],
+            bound: vk::Semaphore::null(),
+            updates: 0,
+        };
+        match unsafe { texture.create_objects(device, mem_props, queue, staging, cmd) } {
+            Ok(()) => Ok(texture),
+            Err(err) => {
+                unsafe { texture.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        queue: vk::Queue,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+    ) -> Result<(), VirtualTextureError> {
+        let requirements = device.get_image_memory_requirements(self.image);
+        let sparse = device.get_image_sparse_memory_requirements(self.image);
+        if sparse
+            .iter()
+            .any(|req| req.format_properties.aspect_mask == vk::ImageAspectFlags::METADATA)
+        {
+            return Err(VirtualTextureError::Unsupported("images needing metadata"));
+        }
+        let color = sparse
+            .iter()
+            .find(|req| {
+                req.format_properties
+                    .aspect_mask
+                    .contains(vk::ImageAspectFlags::COLOR)
+            })
+            .ok_or(VirtualTextureError::Unsupported(
+                "sparse images of this format",
+            ))?;
+        self.page_bytes = requirements.alignment;
+        self.tail_lod = color.image_mip_tail_first_lod.min(self.levels.len() as u32);
+        let page_count = self
+            .levels
+            .get(self.tail_lod as usize)
+            .map_or(self.pages_below(self.levels.len()), |level| level.first);
+        self.pages = vec![Page::default(); page_count];
+
+        let type_index = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .ok_or(VirtualTextureError::NoMemoryType)?;
+        let allocate = |size| {
+            let info = vk::MemoryAllocateInfo::builder()
+                .allocation_size(size)
+                .memory_type_index(type_index);
+            device.allocate_memory(&info, None)
+        };
+        let pool_size = self.page_bytes * self.slots.len() as vk::DeviceSize;
+        if pool_size > 0 {
+            self.pool = allocate(pool_size)?;
+            leaks::track(self.pool, "virtual texture page pool");
+            usage::record_allocation(MemoryCategory::Texture, pool_size);
+        }
+
+        if self.tail_lod < self.levels.len() as u32 && color.image_mip_tail_size > 0 {
+            self.tail_size = color.image_mip_tail_size;
+            self.tail_memory = allocate(self.tail_size)?;
+            leaks::track(self.tail_memory, "virtual texture mip tail");
+            usage::record_allocation(MemoryCategory::Texture, self.tail_size);
+            let bind = [vk::SparseMemoryBind {
+                resource_offset: color.image_mip_tail_offset,
+                size: self.tail_size,
+                memory: self.tail_memory,
+                memory_offset: 0,
+                flags: vk::SparseMemoryBindFlags::empty(),
+            }];
+            let opaque = [vk::SparseImageOpaqueMemoryBindInfo::builder()
+                .image(self.image)
+                .binds(&bind)
+                .build()];
+            let info = vk::BindSparseInfo::builder().image_opaque_binds(&opaque);
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            leaks::track(fence, "virtual texture fence");
+            let result = traced!(
+                "vkQueueBindSparse",
+                image = self.image,
+                tail = self.tail_size;
+                device.queue_bind_sparse(queue, &[*info], fence)
+            )
+            .and_then(|_| device.wait_for_fences(&[fence], true, u64::MAX));
+            leaks::untrack(fence);
+            device.destroy_fence(fence, None);
+            result?;
+        }
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(self.image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(self.data.format)
+            .subresource_range(self.range(0, self.levels.len() as u32));
+        self.view = device.create_image_view(&view_info, None)?;
+        leaks::track(self.view, "virtual texture view");
+        self.bound = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+        leaks::track(self.bound, "virtual texture bind semaphore");
+
+        let residency = Texture::empty(
+            device,
+            mem_props,
+            vk::Format::R8_UNORM,
+            self.levels[0].pages,
+            vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        )?;
+        let residency_image = residency.image;
+        self.residency = Some(residency);
+        let feedback_size = (self.pages.len().max(1) * 4) as vk::DeviceSize;
+        self.feedback = Some(Buffer::new(
+            device,
+            mem_props,
+            feedback_size,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?);
+        self.requests().fill(0);
+
+        // Non-resident pages are never sampled, their contents don't matter
+        let levels = self.levels.len() as u32;
+        transition(
+            device,
+            cmd,
+            self.image,
+            self.range(0, levels),
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+        let align = self.copy_alignment();
+        for level in self.tail_lod..levels {
+            let alloc = staging.write(device, &self.data.levels[level as usize], align)?;
+            let region = vk::BufferImageCopy::builder()
+                .buffer_offset(alloc.offset)
+                .image_subresource(self.layers(level))
+                .image_extent(mip_extent(self.data.extent, level))
+                .build();
+            traced!(
+                "vkCmdCopyBufferToImage",
+                src = alloc.buffer,
+                image = self.image,
+                region = region;
+                device.cmd_copy_buffer_to_image(
+                    cmd,
+                    alloc.buffer,
+                    self.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                )
+            );
+        }
+        transition(
+            device,
+            cmd,
+            self.image,
+            self.range(0, levels),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        transition(
+            device,
+            cmd,
+            residency_image,
+            vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+        self.write_residency(device, staging, cmd)?;
+        Ok(())
+    }
+
+    /// The buffer `vt_requests` lives in, one `uint` per page finer than the mip tail
+    pub fn feedback(&self) -> vk::Buffer {
+        self.feedback
+            .as_ref()
+            .expect("created with the texture")
+            .buffer
+    }
+
+    /// Finest resident level under each level 0 page, as `level / 255`
+    pub fn residency(&self) -> &Texture {
+        self.residency.as_ref().expect("created with the texture")
+    }
+
+    /// Texels a page covers, the same at every level
+    pub fn page_size(&self) -> vk::Extent2D {
+        self.page_size
+    }
+
+    /// First level of the always resident mip tail
+    pub fn tail_lod(&self) -> u32 {
+        self.tail_lod
+    }
+
+    pub fn extent(&self) -> vk::Extent3D {
+        self.data.extent
+    }
+
+    /// Pages finer than the mip tail currently bound
+    pub fn resident_pages(&self) -> usize {
+        self.slots.iter().flatten().count()
+    }
+
+    /// Binds and uploads the pages requested since the last update, evicting stale ones
+    ///
+    /// Call once the frame that wrote the feedback has finished, before recording the next one
+    /// sampling the texture. Requests from frames still running may be missed and come again.
+    /// Uploads go into `cmd`, whose submission must wait on the returned semaphore at the
+    /// transfer stage, `None` when nothing was bound.
+    pub fn update(
+        &mut self,
+        device: &Device,
+        queue: vk::Queue,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+    ) -> Result<Option<vk::Semaphore>, VirtualTextureError> {
+        self.updates += 1;
+        let now = self.updates;
+        let requested: Vec<usize> = {
+            let requests = self.requests();
+            let requested = (0..requests.len()).filter(|&i| requests[i] != 0).collect();
+            requests.fill(0);
+            requested
+        };
+        // Parents come along, sampling clamps to the finest level resident all the way up
+        for page in requested {
+            let (mut level, mut x, mut y) = self.locate(page);
+            while level < self.tail_lod {
+                let index = self.index(level, x, y);
+                if self.pages[index].last_used == now {
+                    break;
+                }
+                self.pages[index].last_used = now;
+                level += 1;
+                x /= 2;
+                y /= 2;
+            }
+        }
+
+        let mut wanted: Vec<usize> = (0..self.pages.len())
+            .filter(|&i| self.pages[i].last_used == now && self.pages[i].slot.is_none())
+            .collect();
+        // Coarse pages first, so children never arrive before their parents
+        wanted.sort_by_key(|&i| std::cmp::Reverse(self.locate(i).0));
+        wanted.truncate(self.config.uploads_per_update);
+
+        // Children are used no later than their parents, and go first on ties
+        let mut stale: Vec<usize> = (0..self.pages.len())
+            .filter(|&i| {
+                let page = self.pages[i];
+                page.slot.is_some() && now - page.last_used >= self.config.min_page_age
+            })
+            .collect();
+        stale.sort_by_key(|&i| (self.pages[i].last_used, self.locate(i).0));
+        let mut stale = stale.into_iter();
+        let mut free =
+            (0..self.slots.len() as u32).filter(|&slot| self.slots[slot as usize].is_none());
+
+        let mut evicted = Vec::new();
+        let mut loaded = Vec::new();
+        for page in wanted {
+            let slot = match free.next() {
+                Some(slot) => slot,
+                None => {
+                    let Some(old) = stale.next() else {
+                        break;
+                    };
+                    evicted.push(old);
+                    self.pages[old]
+                        .slot
+                        .take()
+                        .expect("stale pages are resident")
+                }
+            };
+            loaded.push((page, slot));
+        }
+        drop(free);
+        if loaded.is_empty() {
+            return Ok(None);
+        }
+        for &(page, slot) in &loaded {
+            self.pages[page].slot = Some(slot);
+            self.slots[slot as usize] = Some(page);
+        }
+
+        // Unbinds come first so no memory is bound twice once the batch is done
+        let binds: Vec<vk::SparseImageMemoryBind> = evicted
+            .iter()
+            .map(|&page| self.bind(page, vk::DeviceMemory::null(), 0))
+            .chain(loaded.iter().map(|&(page, slot)| {
+                self.bind(page, self.pool, slot as vk::DeviceSize * self.page_bytes)
+            }))
+            .collect();
+        let image_binds = [vk::SparseImageMemoryBindInfo::builder()
+            .image(self.image)
+            .binds(&binds)
+            .build()];
+        let signal = [self.bound];
+        let info = vk::BindSparseInfo::builder()
+            .image_binds(&image_binds)
+            .signal_semaphores(&signal);
+        unsafe {
+            traced!(
+                "vkQueueBindSparse",
+                image = self.image,
+                binds = binds.len();
+                device.queue_bind_sparse(queue, &[*info], vk::Fence::null())
+            )?
+        };
+
+        let mut levels: Vec<u32> = loaded
+            .iter()
+            .map(|&(page, _)| self.locate(page).0)
+            .collect();
+        levels.sort_unstable();
+        levels.dedup();
+        let align = self.copy_alignment();
+        for level in levels {
+            let range = self.range(level, 1);
+            transition(
+                device,
+                cmd,
+                self.image,
+                range,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            );
+            for &(page, _) in &loaded {
+                let (page_level, x, y) = self.locate(page);
+                if page_level != level {
+                    continue;
+                }
+                let (region, bytes) = self.page_region(level, x, y);
+                let alloc = staging.write(device, &bytes, align)?;
+                let copy = vk::BufferImageCopy::builder()
+                    .buffer_offset(alloc.offset)
+                    .image_subresource(self.layers(level))
+                    .image_offset(region.offset)
+                    .image_extent(region.extent)
+                    .build();
+                unsafe {
+                    traced!(
+                        "vkCmdCopyBufferToImage",
+                        src = alloc.buffer,
+                        image = self.image,
+                        region = copy;
+                        device.cmd_copy_buffer_to_image(
+                            cmd,
+                            alloc.buffer,
+                            self.image,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                            &[copy],
+                        )
+                    )
+                };
+            }
+            transition(
+                device,
+                cmd,
+                self.image,
+                range,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+        }
+        self.write_residency(device, staging, cmd)?;
+        Ok(Some(self.bound))
+    }
+
+    fn requests(&mut self) -> &mut [u32] {
+        let ptr = self
+            .feedback
+            .as_ref()
+            .and_then(Buffer::mapped)
+            .expect("feedback is host visible");
+        unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr().cast(), self.pages.len()) }
+    }
+
+    fn pages_below(&self, level: usize) -> usize {
+        self.levels[..level]
+            .iter()
+            .map(|level| (level.pages.width * level.pages.height) as usize)
+            .sum()
+    }
+
+    fn index(&self, level: u32, x: u32, y: u32) -> usize {
+        let entry = self.levels[level as usize];
+        entry.first + (y * entry.pages.width + x) as usize
+    }
+
+    /// Level and page coordinates of a page index
+    fn locate(&self, page: usize) -> (u32, u32, u32) {
+        let level = self
+            .levels
+            .iter()
+            .rposition(|level| level.first <= page)
+            .expect("level 0 starts at page 0");
+        let entry = self.levels[level];
+        let offset = (page - entry.first) as u32;
+        (
+            level as u32,
+            offset % entry.pages.width,
+            offset / entry.pages.width,
+        )
+    }
+
+    fn bind(
+        &self,
+        page: usize,
+        memory: vk::DeviceMemory,
+        memory_offset: vk::DeviceSize,
+    ) -> vk::SparseImageMemoryBind {
+        let (level, x, y) = self.locate(page);
+        let (region, _) = self.page_extent(level, x, y);
+        vk::SparseImageMemoryBind {
+            subresource: vk::ImageSubresource {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                array_layer: 0,
+            },
+            offset: region.offset,
+            extent: region.extent,
+            memory,
+            memory_offset,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        }
+    }
+
+    /// Texels a page covers, cut short at the level's edge, and the level's extent
+    fn page_extent(&self, level: u32, x: u32, y: u32) -> (Region, vk::Extent3D) {
+        let extent = mip_extent(self.data.extent, level);
+        let offset = vk::Offset3D {
+            x: (x * self.page_size.width) as i32,
+            y: (y * self.page_size.height) as i32,
+            z: 0,
+        };
+        let region = Region {
+            offset,
+            extent: vk::Extent3D {
+                width: self.page_size.width.min(extent.width - offset.x as u32),
+                height: self.page_size.height.min(extent.height - offset.y as u32),
+                depth: 1,
+            },
+        };
+        (region, extent)
+    }
+
+    /// A page's region and its texel blocks, tightly packed
+    fn page_region(&self, level: u32, x: u32, y: u32) -> (Region, Vec<u8>) {
+        let (region, extent) = self.page_extent(level, x, y);
+        let block = block_info(self.data.format).expect("formats are validated on load");
+        let row_bytes = (extent.width.div_ceil(block.width) * block.bytes) as usize;
+        let first_row = region.offset.y as u32 / block.height;
+        let rows = region.extent.height.div_ceil(block.height);
+        let start = (region.offset.x as u32 / block.width * block.bytes) as usize;
+        let len = (region.extent.width.div_ceil(block.width) * block.bytes) as usize;
+        let level_bytes = &self.data.levels[level as usize];
+        let mut bytes = Vec::with_capacity(len * rows as usize);
+        for row in first_row..first_row + rows {
+            let row_start = row as usize * row_bytes + start;
+            bytes.extend_from_slice(&level_bytes[row_start..row_start + len]);
+        }
+        (region, bytes)
+    }
+
+    /// Rewrites the finest resident level under each level 0 page
+    fn write_residency(
+        &self,
+        device: &Device,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+    ) -> VkResult<()> {
+        let pages = self.levels[0].pages;
+        let mut finest = Vec::with_capacity((pages.width * pages.height) as usize);
+        for y in 0..pages.height {
+            for x in 0..pages.width {
+                let level = (0..self.tail_lod)
+                    .rev()
+                    .take_while(|&level| {
+                        let index = self.index(level, x >> level, y >> level);
+                        self.pages[index].slot.is_some()
+                    })
+                    .last()
+                    .unwrap_or(self.tail_lod);
+                finest.push(level.min(u8::MAX as u32) as u8);
+            }
+        }
+        let rect = vk::Rect2D {
+            offset: vk::Offset2D::default(),
+            extent: pages,
+        };
+        self.residency()
+            .write_region(device, staging, cmd, 0, rect, &finest)
+    }
+
+    fn copy_alignment(&self) -> vk::DeviceSize {
+        // Copies must start on a whole block, and on 4 bytes for the transfer
+        let block_bytes = block_info(self.data.format).map_or(16, |block| block.bytes);
+        (block_bytes * 4 / gcd(block_bytes, 4)) as vk::DeviceSize
+    }
+
+    fn range(&self, base_mip_level: u32, level_count: u32) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    fn layers(&self, mip_level: u32) -> vk::ImageSubresourceLayers {
+        vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No submission or bind using the texture may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        if let Some(residency) = self.residency.take() {
+            residency.destroy(device);
+        }
+        if let Some(feedback) = self.feedback.take() {
+            feedback.destroy(device);
+        }
+        if self.bound != vk::Semaphore::null() {
+            leaks::untrack(self.bound);
+            device.destroy_semaphore(self.bound, None);
+        }
+        if self.view != vk::ImageView::null() {
+            leaks::untrack(self.view);
+            device.destroy_image_view(self.view, None);
+        }
+        leaks::untrack(self.image);
+        device.destroy_image(self.image, None);
+        if self.tail_memory != vk::DeviceMemory::null() {
+            leaks::untrack(self.tail_memory);
+            device.free_memory(self.tail_memory, None);
+            usage::release_allocation(MemoryCategory::Texture, self.tail_size);
+        }
+        if self.pool != vk::DeviceMemory::null() {
+            leaks::untrack(self.pool);
+            device.free_memory(self.pool, None);
+            usage::release_allocation(
+                MemoryCategory::Texture,
+                self.page_bytes * self.slots.len() as vk::DeviceSize,
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    offset: vk::Offset3D,
+    extent: vk::Extent3D,
+}
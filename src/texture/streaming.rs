@@ -0,0 +1,337 @@
+//! Mip streaming, textures start with only their smallest levels resident and gain detail on demand
+//!
+//! Each texture keeps its full [`TextureData`] on the CPU. When a texture wants more or fewer
+//! levels, a replacement image holding just that mip tail is uploaded and swapped in, so the old
+//! view stays valid until the fence of the frame that last used it has signalled. Residency is
+//! kept under [`StreamingConfig::memory_budget`], textures nobody asked for fall back to their
+//! smallest levels and the ones wanting the most detail are streamed in first.
+
+use ash::{prelude::VkResult, vk, Device};
+
+use crate::{assets::GpuAsset, memory::staging::StagingBelt};
+
+use super::{host_copy::HostImageCopy, mip_extent, Texture, TextureData};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingConfig {
+    /// Bytes of mip data allowed to be resident across all streamed textures
+    pub memory_budget: u64,
+    /// Bytes uploaded per [`TextureStreamer::update`], to keep streaming from causing hitches
+    pub upload_budget: u64,
+    /// Smallest levels every texture keeps resident, even when over budget
+    pub min_resident_levels: u32,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            memory_budget: 256 << 20,
+            upload_budget: 16 << 20,
+            min_resident_levels: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId(usize);
+
+struct Streamed {
+    data: TextureData,
+    texture: Texture,
+    /// Most detailed level currently resident
+    resident: u32,
+    /// Most detailed level requested since the last update
+    wanted: u32,
+    /// Level to fall back to when nothing was requested
+    idle: u32,
+}
+
+impl Streamed {
+    fn levels(&self) -> u32 {
+        self.data.levels.len() as u32
+    }
+
+    fn bytes_from(&self, level: u32) -> u64 {
+        self.data.levels[level as usize..]
+            .iter()
+            .map(|level| level.len() as u64)
+            .sum()
+    }
+}
+
+pub struct TextureStreamer {
+    config: StreamingConfig,
+    textures: Vec<Option<Streamed>>,
+    free: Vec<usize>,
+    resident_bytes: u64,
+    retired: Vec<Texture>,
+    in_flight: Vec<(vk::Fence, Vec<Texture>)>,
+    host_copy: Option<HostImageCopy>,
+}
+
+impl TextureStreamer {
+    pub fn new(config: StreamingConfig) -> Self {
+        TextureStreamer {
+            config,
+            textures: Vec::new(),
+            free: Vec::new(),
+            resident_bytes: 0,
+            retired: Vec::new(),
+            in_flight: Vec::new(),
+            host_copy: None,
+        }
+    }
+
+    /// Writes textures in formats `host_copy` supports from the host instead of through `cmd`
+    pub fn with_host_copy(mut self, host_copy: HostImageCopy) -> Self {
+        self.host_copy = Some(host_copy);
+        self
+    }
+
+    /// Uploads the smallest levels of `data` and keeps the rest for streaming in later
+    pub fn add(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        data: TextureData,
+    ) -> VkResult<StreamId> {
+        let levels = data.levels.len() as u32;
+        let idle = levels.saturating_sub(self.config.min_resident_levels.max(1));
+        let texture = Texture::upload_with(
+            device,
+            mem_props,
+            self.host_copy.as_ref(),
+            staging,
+            cmd,
+            &mip_tail(&data, idle),
+        )?;
+        let streamed = Streamed {
+            data,
+            texture,
+            resident: idle,
+            wanted: idle,
+            idle,
+        };
+        self.resident_bytes += streamed.bytes_from(idle);
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.textures[index] = Some(streamed);
+                index
+            }
+            None => {
+                self.textures.push(Some(streamed));
+                self.textures.len() - 1
+            }
+        };
+        Ok(StreamId(index))
+    }
+
+    /// The currently resident version of a texture, replaced whenever [`TextureStreamer::update`]
+    /// reports it as changed
+    pub fn texture(&self, id: StreamId) -> &Texture {
+        &self.get(id).texture
+    }
+
+    /// Most detailed level resident, relative to the full texture
+    pub fn resident_level(&self, id: StreamId) -> u32 {
+        self.get(id).resident
+    }
+
+    /// Asks for `level` and everything smaller to be resident, e.g. from sampler feedback
+    pub fn request_level(&mut self, id: StreamId, level: u32) {
+        let streamed = self.textures[id.0].as_mut().expect("stream id is live");
+        let level = level.min(streamed.levels() - 1);
+        streamed.wanted = streamed.wanted.min(level);
+    }
+
+    /// Requests the level a texture covering `size` world units needs when seen from `distance`
+    /// away, through a camera with a vertical field of view of `fov_y` on a `viewport_height`
+    /// pixel tall target
+    pub fn request_for_distance(
+        &mut self,
+        id: StreamId,
+        distance: f32,
+        size: f32,
+        fov_y: f32,
+        viewport_height: u32,
+    ) {
+        let extent = self.get(id).data.extent;
+        let texels = extent.width.max(extent.height) as f32;
+        let level = mip_for_distance(texels, distance, size, fov_y, viewport_height);
+        self.request_level(id, level);
+    }
+
+    /// Streams levels in or out according to this frame's requests
+    ///
+    /// Uploads not written from the host are recorded into `cmd`, and textures whose view changed
+    /// are returned so their descriptors can be rewritten. Replaced images are destroyed once the
+    /// fence passed to [`TextureStreamer::finish`] has signalled.
+    pub fn update(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+    ) -> VkResult<Vec<StreamId>> {
+        let mut changed = Vec::new();
+        // Dropping detail first frees room for the textures that want more
+        for index in 0..self.textures.len() {
+            let Some(streamed) = &self.textures[index] else {
+                continue;
+            };
+            if streamed.wanted > streamed.resident {
+                let wanted = streamed.wanted;
+                self.set_resident(device, mem_props, staging, cmd, index, wanted)?;
+                changed.push(StreamId(index));
+            }
+        }
+
+        // Textures wanting the most extra levels go first
+        let mut growing: Vec<_> = self
+            .textures
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|streamed| (i, streamed)))
+            .filter(|(_, streamed)| streamed.wanted < streamed.resident)
+            .map(|(i, streamed)| (i, streamed.resident - streamed.wanted))
+            .collect();
+        growing.sort_by_key(|&(_, gain)| std::cmp::Reverse(gain));
+
+        let mut uploaded = 0;
+        for (index, _) in growing {
+            let streamed = self.textures[index].as_ref().unwrap();
+            // The whole tail is uploaded again, but only the new levels add to residency
+            let upload = streamed.bytes_from(streamed.wanted);
+            let extra = upload - streamed.bytes_from(streamed.resident);
+            if uploaded > 0 && uploaded + upload > self.config.upload_budget {
+                break;
+            }
+            if self.resident_bytes + extra > self.config.memory_budget {
+                continue;
+            }
+            uploaded += upload;
+            let wanted = streamed.wanted;
+            self.set_resident(device, mem_props, staging, cmd, index, wanted)?;
+            changed.push(StreamId(index));
+        }
+
+        for streamed in self.textures.iter_mut().flatten() {
+            streamed.wanted = streamed.idle;
+        }
+        Ok(changed)
+    }
+
+    fn set_resident(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        index: usize,
+        level: u32,
+    ) -> VkResult<()> {
+        let streamed = self.textures[index].as_mut().unwrap();
+        let texture = Texture::upload_with(
+            device,
+            mem_props,
+            self.host_copy.as_ref(),
+            staging,
+            cmd,
+            &mip_tail(&streamed.data, level),
+        )?;
+        let old_bytes = streamed.bytes_from(streamed.resident);
+        let new_bytes = streamed.bytes_from(level);
+        streamed.resident = level;
+        self.retired
+            .push(std::mem::replace(&mut streamed.texture, texture));
+        self.resident_bytes = self.resident_bytes - old_bytes + new_bytes;
+        Ok(())
+    }
+
+    /// Stops streaming a texture, its image is destroyed once the next finished fence signals
+    pub fn remove(&mut self, id: StreamId) {
+        if let Some(streamed) = self.textures[id.0].take() {
+            self.resident_bytes -= streamed.bytes_from(streamed.resident);
+            self.retired.push(streamed.texture);
+            self.free.push(id.0);
+        }
+    }
+
+    /// Bytes of mip data currently resident
+    pub fn resident_bytes(&self) -> u64 {
+        self.resident_bytes
+    }
+
+    /// Textures replaced since the last call are destroyed once `fence` signals
+    pub fn finish(&mut self, fence: vk::Fence) {
+        if !self.retired.is_empty() {
+            self.in_flight
+                .push((fence, std::mem::take(&mut self.retired)));
+        }
+    }
+
+    /// Destroys replaced textures whose last use has completed
+    pub fn recall(&mut self, device: &Device) -> VkResult<()> {
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if unsafe { device.get_fence_status(self.in_flight[i].0)? } {
+                let (_, textures) = self.in_flight.swap_remove(i);
+                for texture in textures {
+                    unsafe { texture.destroy(device) };
+                }
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// No submission using the streamed textures may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let textures = self
+            .textures
+            .drain(..)
+            .flatten()
+            .map(|streamed| streamed.texture)
+            .chain(self.retired.drain(..))
+            .chain(self.in_flight.drain(..).flat_map(|(_, textures)| textures));
+        for texture in textures {
+            texture.destroy(device);
+        }
+        self.free.clear();
+        self.resident_bytes = 0;
+    }
+
+    fn get(&self, id: StreamId) -> &Streamed {
+        self.textures[id.0].as_ref().expect("stream id is live")
+    }
+}
+
+/// Level whose texels are about one pixel on screen for a texture `texels` wide covering `size`
+/// world units `distance` away
+pub fn mip_for_distance(
+    texels: f32,
+    distance: f32,
+    size: f32,
+    fov_y: f32,
+    viewport_height: u32,
+) -> u32 {
+    let view_height = 2.0 * distance.max(f32::EPSILON) * (fov_y * 0.5).tan();
+    let pixels = size / view_height * viewport_height as f32;
+    (texels / pixels.max(1.0)).log2().max(0.0) as u32
+}
+
+/// `data` without its levels more detailed than `level`
+fn mip_tail(data: &TextureData, level: u32) -> TextureData {
+    TextureData {
+        format: data.format,
+        extent: mip_extent(data.extent, level),
+        layers: data.layers,
+        cube: data.cube,
+        levels: data.levels[level as usize..].to_vec(),
+    }
+}
@@ -0,0 +1,100 @@
+//! Simulation at a fixed rate, independent of how fast frames are rendered
+//!
+//! Frame time is accumulated and spent in whole steps, so animation and particles advance the
+//! same way at 30 or 240 fps. What is left over becomes [`Frame::alpha`], for rendering between
+//! the last two simulated states, e.g. with [`crate::scene::Transform::lerp`]. In lockstep every
+//! frame is one step instead, so runs repeat exactly however long frames take.
+
+use std::{
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+/// Steps to run before rendering a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub steps: u32,
+    /// How far between the previous and the latest step the frame falls, 0 to 1
+    pub alpha: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FixedTimestep {
+    step: Duration,
+    max_steps: u32,
+    lockstep: bool,
+    accumulator: Duration,
+    last: Option<Instant>,
+}
+
+impl FixedTimestep {
+    /// Rates worth simulating at, past them a step rounds to nothing or overflows a [`Duration`]
+    pub const RATES: RangeInclusive<f64> = 1.0..=1000.0;
+
+    /// Steps `rate` times a second, which must be within [`Self::RATES`]
+    pub fn new(rate: f64) -> Self {
+        FixedTimestep {
+            step: Duration::from_secs_f64(1.0 / rate),
+            max_steps: 8,
+            lockstep: false,
+            accumulator: Duration::ZERO,
+            last: None,
+        }
+    }
+
+    /// Most steps run for one frame, time beyond them is dropped so a hitch or breakpoint
+    /// doesn't leave the simulation catching up for seconds
+    pub fn with_max_steps(mut self, max_steps: u32) -> Self {
+        self.max_steps = max_steps.max(1);
+        self
+    }
+
+    /// Runs exactly one step per frame whatever the time between them, for deterministic
+    /// replays where the simulation has to advance the same way every run
+    pub fn with_lockstep(mut self, lockstep: bool) -> Self {
+        self.lockstep = lockstep;
+        self
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    /// Length of a step in seconds, the `dt` to simulate with
+    pub fn dt(&self) -> f32 {
+        self.step.as_secs_f32()
+    }
+
+    /// Adds the time since the previous call, the first call runs no steps unless in lockstep
+    pub fn advance(&mut self, now: Instant) -> Frame {
+        if self.lockstep {
+            self.last = Some(now);
+            return Frame {
+                steps: 1,
+                alpha: 1.0,
+            };
+        }
+        if let Some(last) = self.last.replace(now) {
+            self.accumulator += now.saturating_duration_since(last);
+        }
+        let mut steps = 0;
+        while self.accumulator >= self.step {
+            if steps == self.max_steps {
+                self.accumulator = Duration::ZERO;
+                break;
+            }
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+        Frame {
+            steps,
+            alpha: self.accumulator.as_secs_f32() / self.step.as_secs_f32(),
+        }
+    }
+
+    /// Forgets the time since the last frame, e.g. after the window was hidden for a while
+    pub fn reset(&mut self) {
+        self.accumulator = Duration::ZERO;
+        self.last = None;
+    }
+}
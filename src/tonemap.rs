@@ -0,0 +1,490 @@
+//! Tonemapping of the HDR scene to display range, graded through a 3D lookup table
+//!
+//! The scene is exposed and mapped with a fitted ACES curve, then looked up in the selected
+//! [`Lut`](crate::texture::lut::Lut) in sRGB encoding, the space grading tools author tables in.
+//! Every table gets its own descriptor set when added, so switching looks between frames is only a
+//! different bind and never waits for the device.
+
+use std::error::Error;
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::Vec4;
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    memory::staging::StagingBelt,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        lut::{self, LutDomain},
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        Texture,
+    },
+};
+
+pub const TONEMAP_VERT_GLSL: &str = r#"#version 450
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    vec2 uv = vec2(float((gl_VertexIndex << 1) & 2), float(gl_VertexIndex & 2));
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = uv;
+}
+"#;
+
+/// Writes linear color, meant for an `_SRGB` target that encodes it again on store
+pub const TONEMAP_FRAG_GLSL: &str = r#"#version 450
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+layout(set = 0, binding = 0) uniform texture2D scene_color;
+layout(set = 0, binding = 1) uniform sampler linear_sampler;
+layout(set = 1, binding = 0) uniform texture3D lut;
+
+layout(push_constant) uniform Push {
+    // x: exposure, y: how much of the graded color to use
+    vec4 params;
+    vec4 domain_min;
+    vec4 domain_max;
+} push;
+
+// Narkowicz's fit of the ACES reference rendering transform
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 to_srgb(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), c));
+}
+
+vec3 to_linear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(vec3(0.04045), c));
+}
+
+void main() {
+    vec4 hdr = textureLod(sampler2D(scene_color, linear_sampler), uv, 0.0);
+    vec3 encoded = to_srgb(aces(hdr.rgb * push.params.x));
+    vec3 coord = clamp((encoded - push.domain_min.xyz) / (push.domain_max.xyz - push.domain_min.xyz), 0.0, 1.0);
+    // Onto the centres of the outermost entries so the ends aren't blended with the border
+    float size = float(textureSize(sampler3D(lut, linear_sampler), 0).x);
+    vec3 graded = textureLod(sampler3D(lut, linear_sampler), (coord * (size - 1.0) + 0.5) / size, 0.0).rgb;
+    out_color = vec4(to_linear(mix(encoded, graded, push.params.y)), hdr.a);
+}
+"#;
+
+#[derive(Debug, Error)]
+pub enum TonemapError {
+    #[error("failed to compile tonemap shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TonemapConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Most tables that can be added, besides the identity
+    pub max_luts: u32,
+}
+
+impl TonemapConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        TonemapConfig {
+            render_pass,
+            subpass: 0,
+            max_luts: 16,
+        }
+    }
+
+    pub fn with_max_luts(mut self, max_luts: u32) -> Self {
+        self.max_luts = max_luts;
+        self
+    }
+}
+
+/// Table added to a [`Tonemap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LutId(u32);
+
+impl LutId {
+    /// The table that leaves colors unchanged, always present
+    pub const IDENTITY: LutId = LutId(0);
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct TonemapPush {
+    params: Vec4,
+    domain_min: Vec4,
+    domain_max: Vec4,
+}
+
+pub struct Tonemap {
+    /// Multiplies the scene before it's mapped
+    pub exposure: f32,
+    /// Blends from the ungraded result at zero to the fully graded one at one
+    pub lut_strength: f32,
+    input_layout: vk::DescriptorSetLayout,
+    lut_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    input_set: vk::DescriptorSet,
+    /// Set and domain of every table, indexed by [`LutId`]
+    luts: Vec<(vk::DescriptorSet, LutDomain)>,
+    selected: LutId,
+    max_luts: u32,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    identity: Option<Texture>,
+}
+
+impl Tonemap {
+    /// Records the identity table's upload into `cmd`, which must execute before the first draw
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        color: vk::ImageView,
+        config: TonemapConfig,
+    ) -> Result<Self, TonemapError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| TonemapError::Compile(Box::new(err)))
+        };
+        let vert = compile(TONEMAP_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(TONEMAP_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Linear)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = Tonemap {
+            exposure: 1.0,
+            lut_strength: 1.0,
+            input_layout: vk::DescriptorSetLayout::null(),
+            lut_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            input_set: vk::DescriptorSet::null(),
+            luts: Vec::new(),
+            selected: LutId::IDENTITY,
+            max_luts: config.max_luts,
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            sampler,
+            identity: None,
+        };
+        let result = unsafe {
+            this.create_objects(device, &vert, &frag, config)
+                .and_then(|_| {
+                    let identity = lut::identity();
+                    let texture = Texture::upload(device, mem_props, staging, cmd, &identity.data)?;
+                    let view = this.identity.insert(texture).view;
+                    Ok(this.add_lut(device, view, identity.domain)?)
+                })
+                .map(|_| this.set_input(device, color))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        vert: &[u32],
+        frag: &[u32],
+        config: TonemapConfig,
+    ) -> Result<(), TonemapError> {
+        let input_bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ];
+        self.input_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&input_bindings),
+            None,
+        )?;
+        leaks::track(self.input_layout, "tonemap descriptor set layout");
+        let lut_binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .build();
+        self.lut_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&[lut_binding]),
+            None,
+        )?;
+        leaks::track(self.lut_layout, "tonemap descriptor set layout");
+        // The input set, the identity table and every added one
+        let max_sets = config.max_luts + 2;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: max_sets,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(max_sets)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "tonemap descriptor pool");
+        self.input_set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.input_layout]),
+        )?[0];
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: std::mem::size_of::<TonemapPush>() as u32,
+        };
+        reflect::debug_validate(
+            &[vert, frag],
+            &[&input_bindings, &[lut_binding]],
+            &[push_range],
+            &[],
+        )?;
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.input_layout, self.lut_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "tonemap pipeline layout");
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "tonemap shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "tonemap shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder();
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "tonemap");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Points the pass at a new HDR scene in `SHADER_READ_ONLY_OPTIMAL`, e.g. after a resize
+    ///
+    /// # Safety
+    ///
+    /// No recorded draw of the tonemap may still be executing.
+    pub unsafe fn set_input(&self, device: &Device, color: vk::ImageView) {
+        let image = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: color,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.input_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.input_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler)
+                .build(),
+        ];
+        device.update_descriptor_sets(&writes, &[]);
+    }
+
+    /// Makes an uploaded table available for [`Tonemap::select_lut`], failing with
+    /// `ERROR_OUT_OF_POOL_MEMORY` past [`TonemapConfig::max_luts`]
+    ///
+    /// The view stays owned by the caller and must outlive the tonemap, or at least any frame
+    /// drawn with the table selected.
+    pub fn add_lut(
+        &mut self,
+        device: &Device,
+        view: vk::ImageView,
+        domain: LutDomain,
+    ) -> VkResult<LutId> {
+        if self.luts.len() as u32 > self.max_luts {
+            return Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY);
+        }
+        let set = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(self.pool)
+                    .set_layouts(&[self.lut_layout]),
+            )?[0]
+        };
+        let image = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image)
+            .build();
+        unsafe { device.update_descriptor_sets(&[write], &[]) };
+        self.luts.push((set, domain));
+        Ok(LutId(self.luts.len() as u32 - 1))
+    }
+
+    /// Grades with `lut` from the next recorded draw on
+    pub fn select_lut(&mut self, lut: LutId) {
+        self.selected = lut;
+    }
+
+    pub fn selected_lut(&self) -> LutId {
+        self.selected
+    }
+
+    /// Records the tonemapped scene into the current target
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer) {
+        let Some(&(lut_set, domain)) = self.luts.get(self.selected.0 as usize) else {
+            return;
+        };
+        let push = TonemapPush {
+            params: Vec4::new(self.exposure, self.lut_strength, 0.0, 0.0),
+            domain_min: domain.min.extend(0.0),
+            domain_max: domain.max.extend(0.0),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.input_set, lut_set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 3, 1, 0, 0);
+        }
+    }
+
+    /// Destroys the identity table with the pass, added tables are left to their owners
+    ///
+    /// # Safety
+    ///
+    /// No recorded draw of the tonemap may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        if let Some(identity) = self.identity.take() {
+            identity.destroy(device);
+        }
+        self.luts.clear();
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.input_layout);
+        device.destroy_descriptor_set_layout(self.input_layout, None);
+        leaks::untrack(self.lut_layout);
+        device.destroy_descriptor_set_layout(self.lut_layout, None);
+    }
+}
@@ -0,0 +1,139 @@
+//! A log of the Vulkan calls made through the crate's wrappers, with the `api-trace` feature
+//!
+//! Calls are wrapped in [`traced`], which records each one's name, parameters and result as a
+//! line of JSON once an output has been installed with [`set_output`]. Parameters and results are
+//! their `Debug` forms, so the log is easy to grep or load but not meant to be replayed. Without
+//! the feature the wrapper expands to the bare call.
+//!
+//! Covered are allocations, images and samplers, pipelines and their layouts, descriptor pools,
+//! sets and updates, staging copies, sparse binds, interop exports, video sessions and coding,
+//! the render graph's barriers, and queue submits and presents.
+
+#[cfg(feature = "api-trace")]
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+#[cfg(feature = "api-trace")]
+static OUTPUT: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+/// Whether [`OUTPUT`] is set, checked before formatting anything
+#[cfg(feature = "api-trace")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "api-trace")]
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Starts recording calls to `output`, replacing and flushing any previous output
+#[cfg(feature = "api-trace")]
+pub fn set_output(output: impl Write + Send + 'static) {
+    let mut current = OUTPUT.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(mut previous) = current.replace(Box::new(output)) {
+        let _ = previous.flush();
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Records to a newly created file at `path`
+#[cfg(feature = "api-trace")]
+pub fn to_file(path: &Path) -> io::Result<()> {
+    set_output(BufWriter::new(File::create(path)?));
+    Ok(())
+}
+
+/// Stops recording, flushing what was written
+#[cfg(feature = "api-trace")]
+pub fn finish() -> io::Result<()> {
+    ENABLED.store(false, Ordering::Release);
+    let previous = OUTPUT.lock().unwrap_or_else(|err| err.into_inner()).take();
+    match previous {
+        Some(mut output) => output.flush(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "api-trace")]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// A call in progress, made by [`traced`]
+#[cfg(feature = "api-trace")]
+pub struct Call {
+    name: &'static str,
+    params: Vec<(&'static str, String)>,
+    start: Instant,
+}
+
+#[cfg(feature = "api-trace")]
+impl Call {
+    /// `None` when not recording, so parameters are only formatted when they'll be written
+    pub fn begin(
+        name: &'static str,
+        params: impl FnOnce() -> Vec<(&'static str, String)>,
+    ) -> Option<Self> {
+        enabled().then(|| Call {
+            name,
+            params: params(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Writes the record, write errors are ignored rather than failing the call
+    pub fn end(self, result: &dyn Debug) {
+        let micros = self.start.elapsed().as_secs_f64() * 1e6;
+        let params: serde_json::Map<_, _> = self
+            .params
+            .into_iter()
+            .map(|(name, value)| (name.to_owned(), value.into()))
+            .collect();
+        let record = serde_json::json!({
+            "seq": SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            "thread": std::thread::current().name().unwrap_or("unnamed"),
+            "call": self.name,
+            "params": params,
+            "result": format!("{result:?}"),
+            "micros": (micros * 10.0).round() / 10.0,
+        });
+        let mut output = OUTPUT.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(output) = output.as_mut() {
+            let _ = writeln!(output, "{record}");
+        }
+    }
+}
+
+/// Makes a Vulkan call, recording it when tracing
+///
+/// Parameters are given as `name = value` pairs before the call, e.g.
+/// `traced!("vkCreateBuffer", size = info.size; device.create_buffer(&info, None))`. Exported so
+/// the application's own calls, like its submits and presents, land in the same log.
+#[cfg(feature = "api-trace")]
+#[macro_export]
+macro_rules! traced {
+    ($name:literal $(, $param:ident = $value:expr)* ; $call:expr) => {{
+        let traced_call = $crate::trace::Call::begin($name, || {
+            vec![$((stringify!($param), format!("{:?}", $value))),*]
+        });
+        let result = $call;
+        if let Some(traced_call) = traced_call {
+            traced_call.end(&result);
+        }
+        result
+    }};
+}
+
+#[cfg(not(feature = "api-trace"))]
+#[macro_export]
+macro_rules! traced {
+    ($name:literal $(, $param:ident = $value:expr)* ; $call:expr) => {
+        $call
+    };
+}
+
+pub use crate::traced;
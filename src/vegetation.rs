@@ -0,0 +1,936 @@
+//! Grass blades scattered over the terrain, culled on the GPU and swaying in the wind
+//!
+//! [`scatter`] places blades on a jittered grid across the terrain, skipping slopes too steep and
+//! heights out of range. Each frame a cull kernel tests every blade against the frustum and a
+//! draw distance, gathering the visible ones per workgroup, and a compact kernel joins those into
+//! one list and writes its length into an indirect draw, the same way the particle system finds
+//! its live particles. Blades are built in the vertex shader from nothing but their index, bent
+//! further the higher up they are by a gusting wind.
+
+use std::{error::Error, mem};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    geometry::Frustum,
+    layout::{slice_as_bytes, AsBytes},
+    leaks,
+    memory::{staging::StagingBelt, Buffer},
+    mesh::upload_buffer,
+    shader::{reflect, ShaderCompiler},
+    terrain::Terrain,
+};
+
+/// Blade storage shared by every stage, and the push constants of the compute kernels
+///
+/// The vertex stage only reads, writing from it would need `vertexPipelineStoresAndAtomics`.
+const VEGETATION_BUFFERS_GLSL: &str = r#"
+#ifdef VERTEX_STAGE
+#define ACCESS readonly
+#else
+#define ACCESS
+#endif
+
+struct Blade {
+    // w: rotation about the up axis
+    vec4 position;
+    // x: height, y: width at the root, z: forward lean, w: wind phase
+    vec4 shape;
+};
+
+layout(set = 0, binding = 0) readonly buffer Blades {
+    Blade items[];
+} blades;
+
+layout(set = 0, binding = 1) ACCESS buffer Visible {
+    uint indices[];
+} visible;
+
+#ifndef VERTEX_STAGE
+layout(set = 0, binding = 2) buffer Draw {
+    uint vertex_count;
+    uint instance_count;
+    uint first_vertex;
+    uint first_instance;
+} draw;
+
+// Visible blades of each cull workgroup, packed at the start of the workgroup's slots
+layout(set = 0, binding = 3) buffer Groups {
+    uint counts[];
+} groups;
+
+layout(set = 0, binding = 4) buffer Gathered {
+    uint indices[];
+} gathered;
+
+layout(push_constant) uniform Push {
+    vec4 planes[6];
+    // w: draw distance
+    vec4 camera;
+    uint count;
+} push;
+#endif
+
+const uint SEGMENTS = 4u;
+"#;
+
+/// Gathers each workgroup's blades inside the frustum and draw distance
+pub const VEGETATION_CULL_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+shared uint inside[64];
+
+void main() {
+    uint i = gl_GlobalInvocationID.x;
+    uint local = gl_LocalInvocationID.x;
+    inside[local] = 0u;
+    if (i < push.count) {
+        Blade blade = blades.items[i];
+        // The blade fits in a sphere as big as it's tall however far it bends
+        float radius = blade.shape.x;
+        vec3 center = blade.position.xyz + vec3(0.0, radius * 0.5, 0.0);
+        bool visible = distance(center, push.camera.xyz) - radius < push.camera.w;
+        for (int p = 0; p < 6; p++) {
+            if (dot(push.planes[p].xyz, center) + push.planes[p].w < -radius) {
+                visible = false;
+            }
+        }
+        if (visible) {
+            inside[local] = 1u;
+        }
+    }
+    barrier();
+    if (local == 0u) {
+        uint first = gl_WorkGroupID.x * 64u;
+        uint count = 0u;
+        for (uint j = 0u; j < 64u; j++) {
+            if (inside[j] != 0u) {
+                gathered.indices[first + count] = first + j;
+                count++;
+            }
+        }
+        groups.counts[gl_WorkGroupID.x] = count;
+    }
+}
+"#;
+
+/// Joins the gathered blades of every cull workgroup into the visible list and draw command
+pub const VEGETATION_COMPACT_GLSL: &str = r#"
+layout(local_size_x = 64) in;
+
+shared uint offset;
+
+void main() {
+    uint group = gl_WorkGroupID.x;
+    uint local = gl_LocalInvocationID.x;
+    if (local == 0u) {
+        uint sum = 0u;
+        for (uint g = 0u; g < group; g++) {
+            sum += groups.counts[g];
+        }
+        offset = sum;
+        if (group == (push.count + 63u) / 64u - 1u) {
+            draw.vertex_count = SEGMENTS * 2u + 1u;
+            draw.instance_count = sum + groups.counts[group];
+            draw.first_vertex = 0u;
+            draw.first_instance = 0u;
+        }
+    }
+    barrier();
+    if (local < groups.counts[group]) {
+        visible.indices[offset + local] = gathered.indices[group * 64u + local];
+    }
+}
+"#;
+
+/// Builds a tapering strip for each visible blade, bent by its lean and the wind
+pub const VEGETATION_VERT_GLSL: &str = r#"
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    // xy: direction times strength, z: seconds, w: gusts per second
+    vec4 wind;
+    vec4 sun;
+    vec4 base_color;
+    vec4 tip_color;
+} push;
+
+layout(location = 0) out vec3 out_normal;
+layout(location = 1) out float out_height;
+
+void main() {
+    Blade blade = blades.items[visible.indices[gl_InstanceIndex]];
+    uint segment = uint(gl_VertexIndex) / 2u;
+    float t = float(segment) / float(SEGMENTS);
+    float side = (gl_VertexIndex & 1) == 0 ? -0.5 : 0.5;
+    if (segment >= SEGMENTS) {
+        side = 0.0;
+    }
+
+    float yaw = blade.position.w;
+    vec3 across = vec3(cos(yaw), 0.0, sin(yaw));
+    vec3 facing = vec3(-across.z, 0.0, across.x);
+    float gust = sin(6.2831853 * push.wind.z * push.wind.w + blade.shape.w
+        + dot(blade.position.xz, push.wind.xy) * 0.1);
+    vec2 wind = push.wind.xy * (0.6 + 0.4 * gust);
+    vec3 bend = facing * blade.shape.z + vec3(wind.x, 0.0, wind.y);
+
+    float height = blade.shape.x;
+    vec3 offset = across * side * blade.shape.y * (1.0 - t)
+        + vec3(0.0, t * height, 0.0)
+        + bend * t * t * height;
+    gl_Position = push.view_proj * vec4(blade.position.xyz + offset, 1.0);
+    // Leaning back along the bend, as the blade curves away from its face
+    out_normal = normalize(facing - bend * t + vec3(0.0, 0.3, 0.0));
+    out_height = t;
+}
+"#;
+
+pub const VEGETATION_FRAG_GLSL: &str = r#"#version 450
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    vec4 wind;
+    // xyz: direction the light travels
+    vec4 sun;
+    vec4 base_color;
+    vec4 tip_color;
+} push;
+
+layout(location = 0) in vec3 normal;
+layout(location = 1) in float height;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    vec3 color = mix(push.base_color.rgb, push.tip_color.rgb, height);
+    // Blades are thin enough to light from either side
+    float light = abs(dot(normalize(normal), -normalize(push.sun.xyz))) * 0.7 + 0.3;
+    out_color = vec4(color * light, 1.0);
+}
+"#;
+
+const WORKGROUP_SIZE: u32 = 64;
+
+#[derive(Debug, Error)]
+pub enum VegetationError {
+    #[error("failed to compile vegetation shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// One blade as the shaders read it
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, AsBytes)]
+pub struct BladeInstance {
+    /// Root of the blade, w is its rotation about the up axis
+    pub position: Vec4,
+    /// Height, width at the root, forward lean and wind phase
+    pub shape: Vec4,
+}
+
+/// Where blades grow and how they're shaped, for [`scatter`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScatterParams {
+    /// Blades per square world unit
+    pub density: f32,
+    pub seed: u64,
+    /// Steepest slope in radians blades grow on
+    pub max_slope: f32,
+    /// World heights blades grow between
+    pub altitude: (f32, f32),
+    pub height: (f32, f32),
+    pub width: (f32, f32),
+}
+
+impl Default for ScatterParams {
+    fn default() -> Self {
+        ScatterParams {
+            density: 16.0,
+            seed: 0,
+            max_slope: 0.6,
+            altitude: (f32::NEG_INFINITY, f32::INFINITY),
+            height: (0.3, 0.7),
+            width: (0.03, 0.06),
+        }
+    }
+}
+
+impl ScatterParams {
+    pub fn with_density(mut self, density: f32) -> Self {
+        self.density = density;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_max_slope(mut self, max_slope: f32) -> Self {
+        self.max_slope = max_slope;
+        self
+    }
+
+    pub fn with_altitude(mut self, min: f32, max: f32) -> Self {
+        self.altitude = (min, max);
+        self
+    }
+
+    pub fn with_height(mut self, min: f32, max: f32) -> Self {
+        self.height = (min, max);
+        self
+    }
+
+    pub fn with_width(mut self, min: f32, max: f32) -> Self {
+        self.width = (min, max);
+        self
+    }
+}
+
+/// Places blades over the whole terrain, the same ones every time for the same parameters
+pub fn scatter(terrain: &Terrain, params: &ScatterParams) -> Vec<BladeInstance> {
+    let Some(bounds) = terrain
+        .chunk_bounds()
+        .iter()
+        .copied()
+        .reduce(|a, b| a.union(&b))
+    else {
+        return Vec::new();
+    };
+    let spacing = 1.0 / params.density.max(f32::EPSILON).sqrt();
+    let cells_x = ((bounds.max.x - bounds.min.x) / spacing) as u64;
+    let cells_z = ((bounds.max.z - bounds.min.z) / spacing) as u64;
+    let min_up = params.max_slope.cos();
+    let step = spacing * 0.5;
+
+    let mut blades = Vec::new();
+    for z in 0..cells_z {
+        for x in 0..cells_x {
+            let mut state = params.seed ^ (z << 32 | x).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            let cell = Vec2::new(x as f32, z as f32);
+            let jitter = Vec2::new(random(&mut state), random(&mut state));
+            let ground = Vec2::new(bounds.min.x, bounds.min.z) + (cell + jitter) * spacing;
+            let height = terrain.height_at(ground.x, ground.y);
+            if height < params.altitude.0 || height > params.altitude.1 {
+                continue;
+            }
+            let slope = Vec2::new(
+                terrain.height_at(ground.x - step, ground.y)
+                    - terrain.height_at(ground.x + step, ground.y),
+                terrain.height_at(ground.x, ground.y - step)
+                    - terrain.height_at(ground.x, ground.y + step),
+            );
+            let normal = Vec3::new(slope.x, 2.0 * step, slope.y).normalize();
+            if normal.y < min_up {
+                continue;
+            }
+            let mut between = |(min, max): (f32, f32)| min + (max - min) * random(&mut state);
+            let yaw = between((0.0, std::f32::consts::TAU));
+            let shape = Vec4::new(
+                between(params.height),
+                between(params.width),
+                between((0.0, 0.3)),
+                between((0.0, std::f32::consts::TAU)),
+            );
+            blades.push(BladeInstance {
+                position: Vec3::new(ground.x, height, ground.y).extend(yaw),
+                shape,
+            });
+        }
+    }
+    blades
+}
+
+/// Uniform in `0..1` from a splitmix64 step
+fn random(state: &mut u64) -> f32 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wind {
+    /// Horizontal direction the wind blows in, its length the strength
+    pub direction: Vec2,
+    /// Gusts per second
+    pub frequency: f32,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Wind {
+            direction: Vec2::new(0.3, 0.1),
+            frequency: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VegetationConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+}
+
+impl VegetationConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        VegetationConfig {
+            render_pass,
+            subpass: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct CullPush {
+    planes: [Vec4; 6],
+    camera: Vec4,
+    count: u32,
+    padding: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct DrawPush {
+    view_proj: Mat4,
+    wind: Vec4,
+    sun: Vec4,
+    base_color: Vec4,
+    tip_color: Vec4,
+}
+
+pub struct Vegetation {
+    pub wind: Wind,
+    /// Direction the light travels
+    pub sun: Vec3,
+    pub base_color: Vec3,
+    pub tip_color: Vec3,
+    /// Blades further than this from the camera aren't drawn
+    pub draw_distance: f32,
+    time: f32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    compute_layout: vk::PipelineLayout,
+    cull_pipeline: vk::Pipeline,
+    compact_pipeline: vk::Pipeline,
+    draw_layout: vk::PipelineLayout,
+    draw_pipeline: vk::Pipeline,
+    blades: Option<Buffer>,
+    visible: Option<Buffer>,
+    draw: Option<Buffer>,
+    groups: Option<Buffer>,
+    gathered: Option<Buffer>,
+    count: u32,
+}
+
+impl Vegetation {
+    /// Records the upload of `blades` into `cmd`
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        compiler: &C,
+        blades: &[BladeInstance],
+        config: VegetationConfig,
+    ) -> Result<Self, VegetationError> {
+        let compile = |body: &str, stage| {
+            let define = match stage {
+                vk::ShaderStageFlags::VERTEX => "#define VERTEX_STAGE\n",
+                _ => "",
+            };
+            compiler
+                .compile(
+                    &format!("#version 450\n{define}{VEGETATION_BUFFERS_GLSL}{body}"),
+                    stage,
+                )
+                .map_err(|err| VegetationError::Compile(Box::new(err)))
+        };
+        let cull = compile(VEGETATION_CULL_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let compact = compile(VEGETATION_COMPACT_GLSL, vk::ShaderStageFlags::COMPUTE)?;
+        let vert = compile(VEGETATION_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compiler
+            .compile(VEGETATION_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)
+            .map_err(|err| VegetationError::Compile(Box::new(err)))?;
+
+        let mut this = Vegetation {
+            wind: Wind::default(),
+            sun: Vec3::new(-0.4, -1.0, -0.3),
+            base_color: Vec3::new(0.05, 0.15, 0.02),
+            tip_color: Vec3::new(0.35, 0.55, 0.15),
+            draw_distance: 80.0,
+            time: 0.0,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            compute_layout: vk::PipelineLayout::null(),
+            cull_pipeline: vk::Pipeline::null(),
+            compact_pipeline: vk::Pipeline::null(),
+            draw_layout: vk::PipelineLayout::null(),
+            draw_pipeline: vk::Pipeline::null(),
+            blades: None,
+            visible: None,
+            draw: None,
+            groups: None,
+            gathered: None,
+            count: blades.len() as u32,
+        };
+        let result = unsafe {
+            this.create_objects(
+                device,
+                mem_props,
+                staging,
+                cmd,
+                blades,
+                &[&cull, &compact, &vert, &frag],
+                config,
+            )
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    pub fn with_wind(mut self, wind: Wind) -> Self {
+        self.wind = wind;
+        self
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        staging: &mut StagingBelt,
+        cmd: vk::CommandBuffer,
+        blades: &[BladeInstance],
+        code: &[&[u32]; 4],
+        config: VegetationConfig,
+    ) -> Result<(), VegetationError> {
+        let bindings: Vec<_> = (0..5)
+            .map(|binding| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::VERTEX)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "vegetation descriptor set layout");
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 5,
+        };
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&[pool_size]),
+            None,
+        )?;
+        leaks::track(self.pool, "vegetation descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+
+        // Empty buffers aren't allowed, so an empty field still gets one slot
+        let placeholder = [BladeInstance {
+            position: Vec4::ZERO,
+            shape: Vec4::ZERO,
+        }];
+        let uploaded = if blades.is_empty() {
+            &placeholder[..]
+        } else {
+            blades
+        };
+        self.blades = Some(upload_buffer(
+            device,
+            mem_props,
+            staging,
+            cmd,
+            slice_as_bytes(uploaded),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?);
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build();
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::VERTEX_SHADER,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+
+        let capacity = self.count.max(1) as vk::DeviceSize;
+        let groups = self.count.max(1).div_ceil(WORKGROUP_SIZE) as vk::DeviceSize;
+        let index_size = mem::size_of::<u32>() as u64;
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let buffers = [
+            (capacity * index_size, storage, &mut self.visible),
+            (
+                mem::size_of::<vk::DrawIndirectCommand>() as u64,
+                storage | vk::BufferUsageFlags::INDIRECT_BUFFER,
+                &mut self.draw,
+            ),
+            (groups * index_size, storage, &mut self.groups),
+            (capacity * index_size, storage, &mut self.gathered),
+        ];
+        let mut infos = vec![[vk::DescriptorBufferInfo {
+            buffer: self
+                .blades
+                .as_ref()
+                .map_or(vk::Buffer::null(), |b| b.buffer),
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }]];
+        for (size, usage, slot) in buffers {
+            let buffer = Buffer::new(
+                device,
+                mem_props,
+                size,
+                usage,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            infos.push([vk::DescriptorBufferInfo {
+                buffer: buffer.buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            }]);
+            *slot = Some(buffer);
+        }
+        let writes: Vec<_> = (0..)
+            .zip(&infos)
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info)
+                    .build()
+            })
+            .collect();
+        device.update_descriptor_sets(&writes, &[]);
+
+        let compute_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: mem::size_of::<CullPush>() as u32,
+        };
+        self.compute_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[compute_range]),
+        )?;
+        leaks::track(self.compute_layout, "vegetation pipeline layout");
+        let draw_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: mem::size_of::<DrawPush>() as u32,
+        };
+        self.draw_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[draw_range]),
+        )?;
+        leaks::track(self.draw_layout, "vegetation pipeline layout");
+
+        let [cull, compact, vert, frag] = code;
+        for compute in [cull, compact] {
+            reflect::debug_validate(&[compute], &[&bindings], &[compute_range], &[])?;
+        }
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[draw_range], &[])?;
+        self.cull_pipeline =
+            compute_pipeline(device, self.compute_layout, cull, "vegetation cull")?;
+        self.compact_pipeline =
+            compute_pipeline(device, self.compute_layout, compact, "vegetation compact")?;
+
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "vegetation shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "vegetation shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        // Blades are seen from both sides
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.draw_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "vegetation draw");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.draw_pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    /// Blades scattered, visible or not
+    pub fn blade_count(&self) -> u32 {
+        self.count
+    }
+
+    /// Advances the wind by `dt` seconds
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+    }
+
+    /// Records culling the blades against `view_proj` and the draw distance from `camera`
+    ///
+    /// Must be recorded outside a render pass, before [`Vegetation::draw`].
+    pub fn cull(&self, device: &Device, cmd: vk::CommandBuffer, view_proj: Mat4, camera: Vec3) {
+        if self.count == 0 || self.draw.is_none() {
+            return;
+        }
+        let push = CullPush {
+            planes: Frustum::from_view_proj(view_proj).planes,
+            camera: camera.extend(self.draw_distance),
+            count: self.count,
+            padding: [0; 3],
+        };
+        let groups = self.count.div_ceil(WORKGROUP_SIZE);
+        unsafe {
+            // Last frame's draw has to be done reading before the lists are rebuilt
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.compute_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.compute_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.cull_pipeline);
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.compact_pipeline);
+            device.cmd_dispatch(cmd, groups, 1, 1);
+            memory_barrier(
+                device,
+                cmd,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::AccessFlags::SHADER_WRITE,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::INDIRECT_COMMAND_READ,
+            );
+        }
+    }
+
+    /// Records the blades left by [`Vegetation::cull`]
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&self, device: &Device, cmd: vk::CommandBuffer, view_proj: Mat4) {
+        let Some(draw) = &self.draw else {
+            return;
+        };
+        if self.count == 0 {
+            return;
+        }
+        let push = DrawPush {
+            view_proj,
+            wind: Vec4::new(
+                self.wind.direction.x,
+                self.wind.direction.y,
+                self.time,
+                self.wind.frequency,
+            ),
+            sun: self.sun.extend(0.0),
+            base_color: self.base_color.extend(1.0),
+            tip_color: self.tip_color.extend(1.0),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.draw_pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.draw_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.draw_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw_indirect(cmd, draw.buffer, 0, 1, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded cull or draw may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for buffer in [
+            &mut self.blades,
+            &mut self.visible,
+            &mut self.draw,
+            &mut self.groups,
+            &mut self.gathered,
+        ] {
+            if let Some(buffer) = buffer.take() {
+                buffer.destroy(device);
+            }
+        }
+        for pipeline in [
+            self.cull_pipeline,
+            self.compact_pipeline,
+            self.draw_pipeline,
+        ] {
+            leaks::untrack(pipeline);
+            device.destroy_pipeline(pipeline, None);
+        }
+        leaks::untrack(self.compute_layout);
+        device.destroy_pipeline_layout(self.compute_layout, None);
+        leaks::untrack(self.draw_layout);
+        device.destroy_pipeline_layout(self.draw_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+unsafe fn compute_pipeline(
+    device: &Device,
+    layout: vk::PipelineLayout,
+    code: &[u32],
+    label: &str,
+) -> VkResult<vk::Pipeline> {
+    let module =
+        device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+    leaks::track(module, "vegetation shader module");
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(module)
+        .name(c"main");
+    let info = vk::ComputePipelineCreateInfo::builder()
+        .stage(*stage)
+        .layout(layout);
+    let pipelines = crate::pipeline::create_compute_pipelines(device, &[*info], label);
+    leaks::untrack(module);
+    device.destroy_shader_module(module, None);
+    Ok(pipelines.map_err(|(_, err)| err)?[0])
+}
+
+unsafe fn memory_barrier(
+    device: &Device,
+    cmd: vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    dst_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src_access)
+        .dst_access_mask(dst_access)
+        .build();
+    device.cmd_pipeline_barrier(
+        cmd,
+        src_stage,
+        dst_stage,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[],
+        &[],
+    );
+}
@@ -0,0 +1,129 @@
+//! Vertex input descriptions generated from `#[repr(C)]` structs
+
+use std::mem;
+
+use ash::vk;
+
+pub use vulkan_thing_derive::VertexInput;
+
+/// Format a field type is read with in the vertex shader
+pub trait VertexFormat {
+    const FORMAT: vk::Format;
+    /// Consecutive locations taken, matrices use one per column
+    const LOCATIONS: u32 = 1;
+}
+
+/// Binding and attribute descriptions for a vertex buffer layout, usually derived
+pub trait VertexInput: Sized {
+    const INPUT_RATE: vk::VertexInputRate;
+    /// Total locations used by every attribute
+    const LOCATIONS: u32;
+
+    fn attributes(binding: u32, first_location: u32) -> Vec<vk::VertexInputAttributeDescription>;
+
+    fn binding(binding: u32) -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding,
+            stride: mem::size_of::<Self>() as u32,
+            input_rate: Self::INPUT_RATE,
+        }
+    }
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use ash::vk;
+
+    use super::VertexFormat;
+
+    pub fn push_attribute(
+        attributes: &mut Vec<vk::VertexInputAttributeDescription>,
+        binding: u32,
+        location: &mut u32,
+        offset: u32,
+        format: vk::Format,
+    ) {
+        attributes.push(vk::VertexInputAttributeDescription {
+            location: *location,
+            binding,
+            format,
+            offset,
+        });
+        *location += 1;
+    }
+
+    pub fn push_field<T: VertexFormat>(
+        attributes: &mut Vec<vk::VertexInputAttributeDescription>,
+        binding: u32,
+        location: &mut u32,
+        offset: u32,
+    ) {
+        let column = (std::mem::size_of::<T>() as u32) / T::LOCATIONS;
+        for i in 0..T::LOCATIONS {
+            push_attribute(
+                attributes,
+                binding,
+                location,
+                offset + i * column,
+                T::FORMAT,
+            );
+        }
+    }
+}
+
+macro_rules! vertex_format {
+    ( $($ty:ty => $format:ident $(* $locations:literal)?),* $(,)? ) => {
+        $(
+            impl VertexFormat for $ty {
+                const FORMAT: vk::Format = vk::Format::$format;
+                $(const LOCATIONS: u32 = $locations;)?
+            }
+        )*
+    };
+}
+
+vertex_format! {
+    f32 => R32_SFLOAT,
+    [f32; 1] => R32_SFLOAT,
+    [f32; 2] => R32G32_SFLOAT,
+    [f32; 3] => R32G32B32_SFLOAT,
+    [f32; 4] => R32G32B32A32_SFLOAT,
+    i32 => R32_SINT,
+    [i32; 2] => R32G32_SINT,
+    [i32; 3] => R32G32B32_SINT,
+    [i32; 4] => R32G32B32A32_SINT,
+    u32 => R32_UINT,
+    [u32; 2] => R32G32_UINT,
+    [u32; 3] => R32G32B32_UINT,
+    [u32; 4] => R32G32B32A32_UINT,
+    [[f32; 4]; 4] => R32G32B32A32_SFLOAT * 4,
+    glam::Vec2 => R32G32_SFLOAT,
+    glam::Vec3 => R32G32B32_SFLOAT,
+    glam::Vec3A => R32G32B32_SFLOAT,
+    glam::Vec4 => R32G32B32A32_SFLOAT,
+    glam::IVec2 => R32G32_SINT,
+    glam::IVec3 => R32G32B32_SINT,
+    glam::IVec4 => R32G32B32A32_SINT,
+    glam::UVec2 => R32G32_UINT,
+    glam::UVec3 => R32G32B32_UINT,
+    glam::UVec4 => R32G32B32A32_UINT,
+    glam::Mat2 => R32G32_SFLOAT * 2,
+    glam::Mat3 => R32G32B32_SFLOAT * 3,
+    glam::Mat4 => R32G32B32A32_SFLOAT * 4,
+}
+
+#[cfg(feature = "mint")]
+vertex_format! {
+    mint::Vector2<f32> => R32G32_SFLOAT,
+    mint::Vector3<f32> => R32G32B32_SFLOAT,
+    mint::Vector4<f32> => R32G32B32A32_SFLOAT,
+    mint::Point2<f32> => R32G32_SFLOAT,
+    mint::Point3<f32> => R32G32B32_SFLOAT,
+    mint::Vector2<i32> => R32G32_SINT,
+    mint::Vector3<i32> => R32G32B32_SINT,
+    mint::Vector4<i32> => R32G32B32A32_SINT,
+    mint::Vector2<u32> => R32G32_UINT,
+    mint::Vector3<u32> => R32G32B32_UINT,
+    mint::Vector4<u32> => R32G32B32A32_UINT,
+    mint::ColumnMatrix4<f32> => R32G32B32A32_SFLOAT * 4,
+}
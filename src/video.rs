@@ -0,0 +1,164 @@
+//! Decoding H.264 and H.265 on the GPU through Vulkan Video
+//!
+//! [`h264`] and [`h265`] read the headers the application is responsible for, [`dpb`] and
+//! [`h265::dpb`] track which decoded pictures stay as references and when each is shown,
+//! [`decode::VideoDecoder`] records the decodes on a `VIDEO_DECODE` queue and
+//! [`player::VideoPlayer`] puts the result on a textured quad. Everything needs
+//! [`DECODE_EXTENSIONS`] plus the [`Codec::extension`] of each codec to decode,
+//! [`Feature::Synchronization2`] and a queue in [`QueueFamilies::video_decode`]. Only progressive
+//! 8-bit 4:2:0 streams are handled, field pictures are rejected.
+//!
+//! Going the other way, [`encode::VideoEncoder`] turns captured frames into an H.264 stream on
+//! a queue in [`QueueFamilies::video_encode`], with [`ENCODE_EXTENSIONS`] in place of the decode
+//! ones.
+//!
+//! [`QueueFamilies::video_decode`]: crate::device::QueueFamilies::video_decode
+//! [`QueueFamilies::video_encode`]: crate::device::QueueFamilies::video_encode
+
+use std::{
+    error::Error as StdError,
+    ffi::{c_void, CStr},
+};
+
+use ash::{vk, Entry, Instance};
+use thiserror::Error;
+
+use crate::device::{vk_ext, EnabledFeatures, Feature};
+
+pub mod decode;
+pub mod dpb;
+pub mod encode;
+pub mod h264;
+pub mod h265;
+pub mod player;
+mod session;
+
+/// Device extensions the decoder needs, along with those of the codecs it decodes
+pub const DECODE_EXTENSIONS: [&CStr; 2] = [
+    vk::KhrVideoQueueFn::name(),
+    vk::KhrVideoDecodeQueueFn::name(),
+];
+
+/// Device extensions the encoder needs
+///
+/// The final revisions, declared in [`vk_ext`] since ash 0.37 only has the provisional ones.
+pub const ENCODE_EXTENSIONS: [&CStr; 3] = [
+    vk::KhrVideoQueueFn::name(),
+    vk_ext::KhrVideoEncodeQueueFn::name(),
+    vk_ext::KhrVideoEncodeH264Fn::name(),
+];
+
+/// The codecs [`decode::VideoDecoder`] reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 2] = [Codec::H264, Codec::H265];
+
+    /// The device extension decoding this codec needs
+    pub fn extension(self) -> &'static CStr {
+        match self {
+            Codec::H264 => vk::KhrVideoDecodeH264Fn::name(),
+            Codec::H265 => vk::KhrVideoDecodeH265Fn::name(),
+        }
+    }
+
+    pub fn decode_operation(self) -> vk::VideoCodecOperationFlagsKHR {
+        match self {
+            Codec::H264 => vk::VideoCodecOperationFlagsKHR::DECODE_H264,
+            Codec::H265 => vk::VideoCodecOperationFlagsKHR::DECODE_H265,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VideoError {
+    #[error("the stream ends in the middle of a header")]
+    Truncated,
+    #[error("malformed stream: {0}")]
+    Malformed(&'static str),
+    #[error("{0} aren't supported")]
+    Unsupported(&'static str),
+    #[error("a slice refers to a parameter set the stream hasn't sent")]
+    MissingParameterSet,
+    #[error("the device can't decode this stream: {0}")]
+    DeviceUnsupported(&'static str),
+    #[error("no memory type fits the video session")]
+    NoMemoryType,
+    #[error("the stream holds more pictures than the decoder has slots for")]
+    DpbFull,
+    #[error("failed to compile the video shaders")]
+    Compile(#[source] Box<dyn StdError + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] crate::shader::reflect::ReflectError),
+}
+
+/// `VK_KHR_video_queue` and `VK_KHR_video_decode_queue` entry points
+#[derive(Clone)]
+pub struct VideoFns {
+    pub queue: vk::KhrVideoQueueFn,
+    pub decode: vk::KhrVideoDecodeQueueFn,
+    /// Codecs whose extension is enabled
+    pub codecs: Vec<Codec>,
+}
+
+impl VideoFns {
+    /// `None` unless [`DECODE_EXTENSIONS`], [`Feature::Synchronization2`] and at least one
+    /// [`Codec::extension`] are enabled
+    ///
+    /// Loaded through the instance, the capability queries are physical device functions.
+    pub fn new(entry: &Entry, instance: &Instance, enabled: &EnabledFeatures) -> Option<Self> {
+        let codecs: Vec<Codec> = Codec::ALL
+            .into_iter()
+            .filter(|codec| enabled.has_extension(codec.extension()))
+            .collect();
+        if codecs.is_empty() {
+            return None;
+        }
+        let mut load = loader(entry, instance, enabled, &DECODE_EXTENSIONS)?;
+        Some(VideoFns {
+            queue: vk::KhrVideoQueueFn::load(&mut load),
+            decode: vk::KhrVideoDecodeQueueFn::load(load),
+            codecs,
+        })
+    }
+}
+
+/// `VK_KHR_video_queue` and `VK_KHR_video_encode_queue` entry points
+#[derive(Clone)]
+pub struct VideoEncodeFns {
+    pub queue: vk::KhrVideoQueueFn,
+    pub encode: vk_ext::KhrVideoEncodeQueueFn,
+}
+
+impl VideoEncodeFns {
+    /// `None` unless [`ENCODE_EXTENSIONS`] and [`Feature::Synchronization2`] are enabled
+    pub fn new(entry: &Entry, instance: &Instance, enabled: &EnabledFeatures) -> Option<Self> {
+        let mut load = loader(entry, instance, enabled, &ENCODE_EXTENSIONS)?;
+        Some(VideoEncodeFns {
+            queue: vk::KhrVideoQueueFn::load(&mut load),
+            encode: vk_ext::KhrVideoEncodeQueueFn::load(load)?,
+        })
+    }
+}
+
+fn loader<'a>(
+    entry: &'a Entry,
+    instance: &'a Instance,
+    enabled: &EnabledFeatures,
+    extensions: &[&CStr],
+) -> Option<impl FnMut(&CStr) -> *const c_void + 'a> {
+    if !enabled.has_feature(Feature::Synchronization2)
+        || !extensions.iter().all(|&name| enabled.has_extension(name))
+    {
+        return None;
+    }
+    Some(|name: &CStr| unsafe {
+        std::mem::transmute(entry.get_instance_proc_addr(instance.handle(), name.as_ptr()))
+    })
+}
@@ -0,0 +1,1529 @@
+//! Recording H.264 and H.265 decodes on a video decode queue
+//!
+//! NAL units go in one at a time, pictures come out through a callback in display order as
+//! layers of the decoder's images. Decodes and the copies out of those images run on different
+//! queues, so each [`OutputBatch`] comes with the semaphores its copy waits on and signals, and
+//! the next decode waits on that in turn.
+
+use std::{collections::BTreeMap, ffi::c_void, ptr};
+
+use ash::{
+    prelude::VkResult,
+    vk::{
+        self,
+        native::{
+            StdVideoDecodeH264PictureInfo, StdVideoDecodeH264PictureInfoFlags,
+            StdVideoDecodeH264ReferenceInfo, StdVideoDecodeH264ReferenceInfoFlags,
+            StdVideoDecodeH265PictureInfo, StdVideoDecodeH265PictureInfoFlags,
+            StdVideoDecodeH265ReferenceInfo, StdVideoDecodeH265ReferenceInfoFlags,
+        },
+    },
+    Device, Instance,
+};
+
+use super::{
+    dpb::{Dpb, Output, Reference},
+    h264::{self, Mmco, SliceType, Vui},
+    h265::{self, dpb::References},
+    session::{align, bind_session_memory, supports_format, Bitstream, PictureArray},
+    Codec, VideoError, VideoFns,
+};
+use crate::{
+    device::{QueueFamilies, Queues},
+    leaks,
+    trace::traced,
+};
+
+/// The only picture format decoded into, 8-bit 4:2:0 with interleaved chroma
+pub const PICTURE_FORMAT: vk::Format = vk::Format::G8_B8R8_2PLANE_420_UNORM;
+
+/// Bitstream buffer size to start with, grown for pictures that don't fit
+const INITIAL_BITSTREAM_SIZE: vk::DeviceSize = 1 << 20;
+
+/// A decoded picture to copy out, valid until the callback it was given to returns
+#[derive(Debug, Clone, Copy)]
+pub struct DecodedFrame {
+    pub image: vk::Image,
+    pub layer: u32,
+    /// The layout the layer is in, and has to be left in after copying
+    pub layout: vk::ImageLayout,
+    /// The part of the layer to show
+    pub rect: vk::Rect2D,
+    pub poc: i32,
+}
+
+/// Pictures due for display and how their copy synchronizes with the decodes
+///
+/// The frames are read by exactly one submission, which waits on `wait` at the transfer stage
+/// and signals `signal`.
+pub struct OutputBatch<'a> {
+    pub frames: &'a [DecodedFrame],
+    pub wait: &'a [vk::Semaphore],
+    pub signal: vk::Semaphore,
+}
+
+/// H.265's `STD_VIDEO_H265_NO_REFERENCE_PICTURE`, an empty entry of a reference list
+const NO_REFERENCE_PICTURE: u8 = 0xff;
+
+/// The decode profile, boxed so the chain of pointers stays put
+struct Profile {
+    h264: vk::VideoDecodeH264ProfileInfoKHR,
+    h265: vk::VideoDecodeH265ProfileInfoKHR,
+    info: vk::VideoProfileInfoKHR,
+    list: vk::VideoProfileListInfoKHR,
+}
+
+impl Profile {
+    fn new(codec: Codec, profile_idc: u8) -> Box<Self> {
+        let mut profile = Box::new(Profile {
+            h264: vk::VideoDecodeH264ProfileInfoKHR {
+                std_profile_idc: profile_idc as u32,
+                picture_layout: vk::VideoDecodeH264PictureLayoutFlagsKHR::PROGRESSIVE,
+                ..Default::default()
+            },
+            h265: vk::VideoDecodeH265ProfileInfoKHR {
+                std_profile_idc: profile_idc as u32,
+                ..Default::default()
+            },
+            info: vk::VideoProfileInfoKHR {
+                video_codec_operation: codec.decode_operation(),
+                chroma_subsampling: vk::VideoChromaSubsamplingFlagsKHR::TYPE_420,
+                luma_bit_depth: vk::VideoComponentBitDepthFlagsKHR::TYPE_8,
+                chroma_bit_depth: vk::VideoComponentBitDepthFlagsKHR::TYPE_8,
+                ..Default::default()
+            },
+            list: vk::VideoProfileListInfoKHR::default(),
+        });
+        profile.info.p_next = match codec {
+            Codec::H264 => &profile.h264 as *const _ as *const c_void,
+            Codec::H265 => &profile.h265 as *const _ as *const c_void,
+        };
+        profile.list.profile_count = 1;
+        profile.list.p_profiles = &profile.info;
+        profile
+    }
+}
+
+/// What a session is sized by, from the SPS a sequence starts with
+#[derive(Debug, Clone, Copy)]
+struct Sequence {
+    profile_idc: u8,
+    extent: vk::Extent2D,
+    slots: usize,
+    max_references: usize,
+    std_level_idc: u32,
+}
+
+impl Sequence {
+    fn h264(sps: &h264::Sps) -> Self {
+        let (width, height) = sps.coded_extent();
+        Sequence {
+            profile_idc: sps.profile_idc,
+            extent: vk::Extent2D { width, height },
+            slots: Dpb::slots_for(sps),
+            max_references: sps.max_num_ref_frames.max(1) as usize,
+            std_level_idc: sps.std_level_idc(),
+        }
+    }
+
+    fn h265(sps: &h265::Sps) -> Self {
+        let (width, height) = sps.coded_extent();
+        let slots = h265::dpb::Dpb::slots_for(sps);
+        Sequence {
+            profile_idc: sps.profile_tier_level.profile_idc,
+            extent: vk::Extent2D { width, height },
+            slots,
+            max_references: (slots - 1).max(1),
+            std_level_idc: sps.std_level_idc(),
+        }
+    }
+}
+
+/// A video session for one sequence's profile, size and DPB depth
+struct Session {
+    profile_idc: u8,
+    max_extent: vk::Extent2D,
+    slots: usize,
+    session: vk::VideoSessionKHR,
+    memory: Vec<vk::DeviceMemory>,
+    parameters: vk::VideoSessionParametersKHR,
+    dpb: PictureArray,
+    /// Separate output pictures when the decoder can't output to DPB pictures
+    output: Option<PictureArray>,
+    bitstream: Bitstream,
+    bitstream_alignment: vk::DeviceSize,
+    max_references: usize,
+    reset: bool,
+}
+
+struct PendingPicture<H> {
+    header: H,
+    /// Slices with three byte start codes, the way they go into the bitstream buffer
+    data: Vec<u8>,
+    offsets: Vec<u32>,
+}
+
+impl<H> PendingPicture<H> {
+    fn new(header: H) -> Self {
+        PendingPicture {
+            header,
+            data: Vec::new(),
+            offsets: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, nal: &[u8]) {
+        self.offsets.push(self.data.len() as u32);
+        self.data.extend_from_slice(&[0, 0, 1]);
+        self.data.extend_from_slice(nal);
+    }
+}
+
+/// Parameter sets and picture bookkeeping of an H.264 stream
+struct H264Stream {
+    sps: BTreeMap<u8, h264::Sps>,
+    pps: BTreeMap<u8, h264::Pps>,
+    dpb: Dpb,
+    poc: h264::PocState,
+    pending: Option<PendingPicture<h264::SliceHeader>>,
+}
+
+/// Parameter sets and picture bookkeeping of an H.265 stream
+struct H265Stream {
+    vps: BTreeMap<u8, h265::Vps>,
+    sps: BTreeMap<u8, h265::Sps>,
+    pps: BTreeMap<u8, h265::Pps>,
+    dpb: h265::dpb::Dpb,
+    poc: h265::PocState,
+    pending: Option<PendingPicture<h265::SliceHeader>>,
+    /// Set at the start and after an end of sequence, the next IRAP picture starts afresh
+    at_sequence_start: bool,
+    /// Set after a CRA or BLA picture decoding started from, whose RASL pictures are dropped
+    skip_rasl: bool,
+}
+
+/// A decode with the codec's structures already built
+struct DecodeInfo<'a> {
+    data: &'a [u8],
+    slot: usize,
+    extent: vk::Extent2D,
+    /// Slots of the references the decode binds
+    references: &'a [usize],
+    /// The codec's DPB slot infos, one per reference followed by the current picture's
+    slot_infos: &'a [*const c_void],
+    /// The codec's picture info, chained to `VkVideoDecodeInfoKHR`
+    picture_info: *const c_void,
+}
+
+/// Decodes an H.264 or H.265 stream on the device's video decode queue
+pub struct VideoDecoder {
+    fns: VideoFns,
+    codec: Codec,
+    physical_device: vk::PhysicalDevice,
+    decode_family: u32,
+    /// Families sharing the pictures, the decode family and the one copying them out
+    families: Vec<u32>,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    decoded: vk::Semaphore,
+    released: vk::Semaphore,
+    decoded_pending: bool,
+    released_pending: bool,
+    h264: H264Stream,
+    h265: H265Stream,
+    parameters_changed: bool,
+    session: Option<Session>,
+    display_rect: vk::Rect2D,
+}
+
+impl VideoDecoder {
+    /// A decoder of `codec` streams whose pictures are copied out on `copy_family`
+    ///
+    /// Fails when the device has no video decode queue or it can't decode `codec`.
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    pub fn new(
+        fns: &VideoFns,
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        queue_families: &QueueFamilies,
+        queues: &Queues,
+        copy_family: u32,
+        codec: Codec,
+    ) -> Result<Self, VideoError> {
+        let (Some(decode_family), Some(queue)) = (queue_families.video_decode, queues.video_decode)
+        else {
+            return Err(VideoError::DeviceUnsupported("no video decode queue"));
+        };
+        if !fns.codecs.contains(&codec) {
+            return Err(VideoError::DeviceUnsupported(
+                "the codec's extension isn't enabled",
+            ));
+        }
+        let family_count =
+            unsafe { instance.get_physical_device_queue_family_properties2_len(physical_device) };
+        let mut video_props = vec![vk::QueueFamilyVideoPropertiesKHR::default(); family_count];
+        let mut props: Vec<_> = video_props
+            .iter_mut()
+            .map(|video| vk::QueueFamilyProperties2 {
+                p_next: video as *mut _ as *mut c_void,
+                ..Default::default()
+            })
+            .collect();
+        unsafe {
+            instance.get_physical_device_queue_family_properties2(physical_device, &mut props)
+        };
+        if !video_props[decode_family as usize]
+            .video_codec_operations
+            .contains(codec.decode_operation())
+        {
+            return Err(VideoError::DeviceUnsupported(
+                "no decode queue for the codec",
+            ));
+        }
+
+        let mut families = vec![decode_family, copy_family];
+        families.dedup();
+        let mut decoder = VideoDecoder {
+            fns: fns.clone(),
+            codec,
+            physical_device,
+            decode_family,
+            families,
+            queue,
+            command_pool: vk::CommandPool::null(),
+            command_buffer: vk::CommandBuffer::null(),
+            fence: vk::Fence::null(),
+            decoded: vk::Semaphore::null(),
+            released: vk::Semaphore::null(),
+            decoded_pending: false,
+            released_pending: false,
+            h264: H264Stream {
+                sps: BTreeMap::new(),
+                pps: BTreeMap::new(),
+                dpb: Dpb::new(0),
+                poc: h264::PocState::default(),
+                pending: None,
+            },
+            h265: H265Stream {
+                vps: BTreeMap::new(),
+                sps: BTreeMap::new(),
+                pps: BTreeMap::new(),
+                dpb: h265::dpb::Dpb::new(0),
+                poc: h265::PocState::default(),
+                pending: None,
+                at_sequence_start: true,
+                skip_rasl: false,
+            },
+            parameters_changed: false,
+            session: None,
+            display_rect: vk::Rect2D::default(),
+        };
+        match unsafe { decoder.create_objects(device) } {
+            Ok(()) => Ok(decoder),
+            Err(err) => {
+                unsafe { decoder.destroy(device) };
+                Err(err.into())
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(&mut self, device: &Device) -> VkResult<()> {
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(self.decode_family)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        self.command_pool = device.create_command_pool(&pool_info, None)?;
+        leaks::track(self.command_pool, "video decode command pool");
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        self.command_buffer = device.allocate_command_buffers(&alloc_info)?[0];
+        self.fence = device.create_fence(
+            &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
+            None,
+        )?;
+        leaks::track(self.fence, "video decode fence");
+        self.decoded = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+        leaks::track(self.decoded, "video decoded semaphore");
+        self.released = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+        leaks::track(self.released, "video released semaphore");
+        Ok(())
+    }
+
+    /// The family pictures are shared with, where the graphics queue copies them out
+    pub fn copy_family(&self) -> u32 {
+        *self
+            .families
+            .last()
+            .expect("the decode family is always listed")
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Frames per second the stream asks for, if its SPS or VPS says
+    pub fn frame_rate(&self) -> Option<f64> {
+        match self.codec {
+            Codec::H264 => self.h264.sps.values().find_map(|sps| sps.vui.frame_rate),
+            Codec::H265 => self
+                .h265
+                .sps
+                .values()
+                .find_map(|sps| sps.vui.frame_rate)
+                .or_else(|| self.h265.vps.values().find_map(h265::Vps::frame_rate)),
+        }
+    }
+
+    /// The VUI of the lowest numbered SPS, for the colour description of the pictures
+    pub fn vui(&self) -> Option<&Vui> {
+        match self.codec {
+            Codec::H264 => self.h264.sps.values().next().map(|sps| &sps.vui),
+            Codec::H265 => self.h265.sps.values().next().map(|sps| &sps.vui),
+        }
+    }
+
+    /// Feeds every NAL unit of an Annex B `stream`
+    pub fn push_stream(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        stream: &[u8],
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        for nal in h264::nal_units(stream) {
+            self.push(device, mem_props, nal, on_output)?;
+        }
+        Ok(())
+    }
+
+    /// Feeds one NAL unit, without its start code
+    ///
+    /// A picture is decoded once the first NAL unit after it arrives, the last one needs
+    /// [`VideoDecoder::finish`]. Pictures before the first IDR picture, or for H.265 the first
+    /// IRAP picture, are dropped.
+    pub fn push(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        nal: &[u8],
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        match self.codec {
+            Codec::H264 => self.push_h264(device, mem_props, nal, on_output),
+            Codec::H265 => self.push_h265(device, mem_props, nal, on_output),
+        }
+    }
+
+    fn push_h264(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        nal: &[u8],
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        let header = h264::NalHeader::parse(nal)?;
+        match header.nal_unit_type {
+            h264::NAL_SLICE | h264::NAL_IDR_SLICE => {
+                let rbsp = h264::rbsp(nal);
+                let slice =
+                    h264::SliceHeader::parse(header, &rbsp, &self.h264.sps, &self.h264.pps)?;
+                if slice.first_mb_in_slice == 0 {
+                    self.finish_picture(device, mem_props, on_output)?;
+                }
+                let pending = self
+                    .h264
+                    .pending
+                    .get_or_insert_with(|| PendingPicture::new(slice));
+                pending.push(nal);
+            }
+            h264::NAL_SPS => {
+                self.finish_picture(device, mem_props, on_output)?;
+                let sps = h264::Sps::parse(&h264::rbsp(nal))?;
+                self.parameters_changed |= insert_changed(&mut self.h264.sps, sps.id, sps);
+            }
+            h264::NAL_PPS => {
+                self.finish_picture(device, mem_props, on_output)?;
+                let pps = h264::Pps::parse(&h264::rbsp(nal), &self.h264.sps)?;
+                self.parameters_changed |= insert_changed(&mut self.h264.pps, pps.id, pps);
+            }
+            // SEI, access unit delimiters and end of sequence or stream start a new access unit
+            6 | 9..=11 => self.finish_picture(device, mem_props, on_output)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn push_h265(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        nal: &[u8],
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        let header = h265::NalHeader::parse(nal)?;
+        // Only the base layer is decoded
+        if header.layer_id > 0 {
+            return Ok(());
+        }
+        match header.nal_unit_type {
+            _ if header.is_slice() => {
+                let rbsp = h265::rbsp(nal);
+                let slice =
+                    h265::SliceHeader::parse(header, &rbsp, &self.h265.sps, &self.h265.pps)?;
+                if slice.first_slice_segment_in_pic {
+                    self.finish_picture(device, mem_props, on_output)?;
+                } else if self.h265.pending.is_none() {
+                    // The rest of a picture whose first segment went missing
+                    return Ok(());
+                }
+                let pending = self
+                    .h265
+                    .pending
+                    .get_or_insert_with(|| PendingPicture::new(slice));
+                pending.push(nal);
+            }
+            h265::NAL_VPS => {
+                self.finish_picture(device, mem_props, on_output)?;
+                let vps = h265::Vps::parse(&h265::rbsp(nal))?;
+                self.parameters_changed |= insert_changed(&mut self.h265.vps, vps.id, vps);
+            }
+            h265::NAL_SPS => {
+                self.finish_picture(device, mem_props, on_output)?;
+                let sps = h265::Sps::parse(&h265::rbsp(nal))?;
+                self.parameters_changed |= insert_changed(&mut self.h265.sps, sps.id, sps);
+            }
+            h265::NAL_PPS => {
+                self.finish_picture(device, mem_props, on_output)?;
+                let pps = h265::Pps::parse(&h265::rbsp(nal))?;
+                self.parameters_changed |= insert_changed(&mut self.h265.pps, pps.id, pps);
+            }
+            h265::NAL_EOS | h265::NAL_EOB => {
+                self.finish_picture(device, mem_props, on_output)?;
+                self.h265.at_sequence_start = true;
+            }
+            // Access unit delimiters, prefix SEI and the reserved types before slices start a new
+            // access unit
+            h265::NAL_AUD | h265::NAL_PREFIX_SEI | 41..=44 => {
+                self.finish_picture(device, mem_props, on_output)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Decodes the last picture and gives every picture still waiting, at the end of the stream
+    pub fn finish(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        self.finish_picture(device, mem_props, on_output)?;
+        let outputs = match self.codec {
+            Codec::H264 => self.h264.dpb.flush(),
+            Codec::H265 => self.h265.dpb.flush(),
+        };
+        self.emit(&outputs, on_output)
+    }
+
+    /// Drops the pictures being decoded and waiting, for seeking to the next IDR or IRAP picture
+    pub fn reset(&mut self) {
+        self.h264.pending = None;
+        self.h264.dpb.clear();
+        self.h264.poc = h264::PocState::default();
+        self.h265.pending = None;
+        self.h265.dpb.clear();
+        self.h265.poc = h265::PocState::default();
+        self.h265.at_sequence_start = true;
+    }
+
+    fn finish_picture(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        match self.codec {
+            Codec::H264 => self.finish_h264_picture(device, mem_props, on_output),
+            Codec::H265 => self.finish_h265_picture(device, mem_props, on_output),
+        }
+    }
+
+    fn finish_h264_picture(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        let Some(picture) = self.h264.pending.take() else {
+            return Ok(());
+        };
+        let header = &picture.header;
+        let sps_id = self
+            .h264
+            .pps
+            .get(&header.pps_id)
+            .ok_or(VideoError::MissingParameterSet)?
+            .sps_id;
+        let sps = self
+            .h264
+            .sps
+            .get(&sps_id)
+            .ok_or(VideoError::MissingParameterSet)?
+            .clone();
+        if header.is_idr() {
+            check_format(
+                sps.chroma_format_idc,
+                sps.bit_depth_luma_minus8,
+                sps.bit_depth_chroma_minus8,
+            )?;
+            let sequence = Sequence::h264(&sps);
+            if !self.session_fits(&sequence) {
+                // Everything from the old sequence is shown before its images go
+                let outputs = self.h264.dpb.flush();
+                self.emit(&outputs, on_output)?;
+                self.h264.dpb = Dpb::new(sequence.slots);
+                self.replace_session(device, mem_props, &sequence)?;
+            }
+        } else if self.session.is_none() {
+            return Ok(());
+        }
+        self.refresh_parameters(device)?;
+
+        let poc = self.h264.poc.compute(&sps, header);
+        let (slot, outputs) = self.h264.dpb.begin_picture(header)?;
+        self.emit(&outputs, on_output)?;
+        if header.is_idr() {
+            // Pictures of the previous sequence are all out, its cropping goes with them
+            self.h264.dpb.configure(&sps);
+            self.display_rect = sps.display_rect();
+        }
+        let references = self.h264.dpb.references(slot);
+        unsafe { self.decode_h264(device, mem_props, &picture, slot, poc, &references)? };
+        let outputs = self.h264.dpb.finish_picture(slot, header, poc);
+        self.emit(&outputs, on_output)
+    }
+
+    fn finish_h265_picture(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        let Some(picture) = self.h265.pending.take() else {
+            return Ok(());
+        };
+        let header = &picture.header;
+        let nal = header.nal;
+        let sps_id = self
+            .h265
+            .pps
+            .get(&header.pps_id)
+            .ok_or(VideoError::MissingParameterSet)?
+            .sps_id;
+        let sps = self
+            .h265
+            .sps
+            .get(&sps_id)
+            .ok_or(VideoError::MissingParameterSet)?
+            .clone();
+        // NoRaslOutputFlag, decoding starts over at this picture
+        let fresh_start =
+            nal.is_irap() && (nal.is_idr() || nal.is_bla() || self.h265.at_sequence_start);
+        if nal.is_irap() {
+            self.h265.skip_rasl = fresh_start;
+        } else if nal.is_rasl() && self.h265.skip_rasl {
+            // Their references are from before the picture decoding started at
+            return Ok(());
+        }
+        if fresh_start {
+            check_format(
+                sps.chroma_format_idc,
+                sps.bit_depth_luma_minus8,
+                sps.bit_depth_chroma_minus8,
+            )?;
+            let sequence = Sequence::h265(&sps);
+            if !self.session_fits(&sequence) {
+                let outputs = self.h265.dpb.flush();
+                self.emit(&outputs, on_output)?;
+                self.h265.dpb = h265::dpb::Dpb::new(sequence.slots);
+                self.replace_session(device, mem_props, &sequence)?;
+            }
+        } else if self.session.is_none() {
+            return Ok(());
+        }
+        self.refresh_parameters(device)?;
+
+        let poc = self.h265.poc.compute(&sps, header, fresh_start);
+        let pocs = header.reference_pocs(&sps, poc);
+        let (slot, references, outputs) = self.h265.dpb.begin_picture(
+            &pocs,
+            sps.max_pic_order_cnt_lsb(),
+            fresh_start,
+            header.no_output_of_prior_pics,
+        )?;
+        self.emit(&outputs, on_output)?;
+        if fresh_start {
+            self.h265.dpb.configure(&sps);
+            self.display_rect = sps.display_rect();
+            self.h265.at_sequence_start = false;
+        }
+        unsafe { self.decode_h265(device, mem_props, &picture, &sps, slot, poc, &references)? };
+        let outputs = self.h265.dpb.finish_picture(slot, poc, header.pic_output);
+        self.emit(&outputs, on_output)
+    }
+
+    fn session_fits(&self, sequence: &Sequence) -> bool {
+        let Some(session) = &self.session else {
+            return false;
+        };
+        session.profile_idc == sequence.profile_idc
+            && sequence.extent.width <= session.max_extent.width
+            && sequence.extent.height <= session.max_extent.height
+            && sequence.slots <= session.slots
+    }
+
+    /// Swaps the session for one sized for `sequence`, once the device is done with the old one
+    fn replace_session(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        sequence: &Sequence,
+    ) -> Result<(), VideoError> {
+        unsafe {
+            device.device_wait_idle()?;
+            if let Some(session) = self.session.take() {
+                self.destroy_session(device, &session);
+            }
+        }
+        self.session = Some(self.create_session(device, mem_props, sequence)?);
+        self.parameters_changed = false;
+        Ok(())
+    }
+
+    /// Brings the session parameters up to date with the parameter sets seen so far
+    fn refresh_parameters(&mut self, device: &Device) -> Result<(), VideoError> {
+        if self.parameters_changed {
+            unsafe {
+                device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+                self.update_parameters(device)?;
+            }
+            self.parameters_changed = false;
+        }
+        Ok(())
+    }
+
+    /// Hands `outputs` to `on_output` along with the semaphores to synchronize on
+    fn emit(
+        &mut self,
+        outputs: &[Output],
+        on_output: &mut impl FnMut(&OutputBatch) -> VkResult<()>,
+    ) -> Result<(), VideoError> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        if outputs.is_empty() {
+            return Ok(());
+        }
+        let (pictures, layout) = match &session.output {
+            Some(output) => (output, vk::ImageLayout::VIDEO_DECODE_DST_KHR),
+            None => (&session.dpb, vk::ImageLayout::VIDEO_DECODE_DPB_KHR),
+        };
+        let frames: Vec<DecodedFrame> = outputs
+            .iter()
+            .map(|output| DecodedFrame {
+                image: pictures.image,
+                layer: output.slot as u32,
+                layout,
+                rect: self.display_rect,
+                poc: output.poc,
+            })
+            .collect();
+        let wait: Vec<vk::Semaphore> = [
+            (self.decoded_pending, self.decoded),
+            (self.released_pending, self.released),
+        ]
+        .iter()
+        .filter(|(pending, _)| *pending)
+        .map(|&(_, semaphore)| semaphore)
+        .collect();
+        on_output(&OutputBatch {
+            frames: &frames,
+            wait: &wait,
+            signal: self.released,
+        })?;
+        self.decoded_pending = false;
+        self.released_pending = true;
+        Ok(())
+    }
+
+    #[track_caller]
+    fn create_session(
+        &self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        sequence: &Sequence,
+    ) -> Result<Session, VideoError> {
+        let profile = Profile::new(self.codec, sequence.profile_idc);
+        let mut h264_caps = vk::VideoDecodeH264CapabilitiesKHR::default();
+        let mut h265_caps = vk::VideoDecodeH265CapabilitiesKHR::default();
+        let mut decode_caps = vk::VideoDecodeCapabilitiesKHR {
+            p_next: match self.codec {
+                Codec::H264 => &mut h264_caps as *mut _ as *mut c_void,
+                Codec::H265 => &mut h265_caps as *mut _ as *mut c_void,
+            },
+            ..Default::default()
+        };
+        let mut caps = vk::VideoCapabilitiesKHR {
+            p_next: &mut decode_caps as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        let result = unsafe {
+            (self.fns.queue.get_physical_device_video_capabilities_khr)(
+                self.physical_device,
+                &profile.info,
+                &mut caps,
+            )
+        };
+        match result {
+            vk::Result::SUCCESS => {}
+            vk::Result::ERROR_VIDEO_PROFILE_OPERATION_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PROFILE_FORMAT_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PICTURE_LAYOUT_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PROFILE_CODEC_NOT_SUPPORTED_KHR => {
+                return Err(VideoError::DeviceUnsupported("the stream's profile"));
+            }
+            err => return Err(err.into()),
+        }
+        let max_extent = sequence.extent;
+        if max_extent.width > caps.max_coded_extent.width
+            || max_extent.height > caps.max_coded_extent.height
+        {
+            return Err(VideoError::DeviceUnsupported("pictures this large"));
+        }
+        let max_level_idc = match self.codec {
+            Codec::H264 => h264_caps.max_level_idc,
+            Codec::H265 => h265_caps.max_level_idc,
+        };
+        if sequence.std_level_idc > max_level_idc {
+            return Err(VideoError::DeviceUnsupported("the stream's level"));
+        }
+        let slots = sequence.slots as u32;
+        if slots > caps.max_dpb_slots {
+            return Err(VideoError::DeviceUnsupported(
+                "this many reference pictures",
+            ));
+        }
+        let coincide = decode_caps
+            .flags
+            .contains(vk::VideoDecodeCapabilityFlagsKHR::DPB_AND_OUTPUT_COINCIDE);
+        let (dpb_usage, output_usage) = if coincide {
+            (
+                vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR
+                    | vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+                None,
+            )
+        } else {
+            (
+                vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
+                Some(vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR | vk::ImageUsageFlags::TRANSFER_SRC),
+            )
+        };
+        for usage in [Some(dpb_usage), output_usage].into_iter().flatten() {
+            if !supports_format(&self.fns.queue, self.physical_device, &profile.list, usage)? {
+                return Err(VideoError::DeviceUnsupported(
+                    "pictures the player can copy",
+                ));
+            }
+        }
+
+        let max_references =
+            (sequence.max_references as u32).min(caps.max_active_reference_pictures) as usize;
+        let info = vk::VideoSessionCreateInfoKHR {
+            queue_family_index: self.decode_family,
+            p_video_profile: &profile.info,
+            picture_format: PICTURE_FORMAT,
+            max_coded_extent: max_extent,
+            reference_picture_format: PICTURE_FORMAT,
+            max_dpb_slots: slots,
+            max_active_reference_pictures: max_references as u32,
+            p_std_header_version: &caps.std_header_version,
+            ..Default::default()
+        };
+        let mut session = vk::VideoSessionKHR::null();
+        unsafe {
+            traced!(
+                "vkCreateVideoSessionKHR",
+                extent = max_extent,
+                slots = sequence.slots;
+                (self.fns.queue.create_video_session_khr)(
+                    device.handle(),
+                    &info,
+                    ptr::null(),
+                    &mut session,
+                )
+                .result()
+            )?
+        };
+        leaks::track(session, "video session");
+        // Filled in as it goes, so a failure part way releases what was made
+        let mut this = Session {
+            profile_idc: sequence.profile_idc,
+            max_extent,
+            slots: sequence.slots,
+            session,
+            memory: Vec::new(),
+            parameters: vk::VideoSessionParametersKHR::null(),
+            dpb: PictureArray::NULL,
+            output: None,
+            bitstream: Bitstream::NULL,
+            bitstream_alignment: caps
+                .min_bitstream_buffer_offset_alignment
+                .max(caps.min_bitstream_buffer_size_alignment)
+                .max(1),
+            max_references,
+            reset: true,
+        };
+        let result = unsafe {
+            bind_session_memory(
+                &self.fns.queue,
+                device,
+                mem_props,
+                session,
+                &mut this.memory,
+            )
+            .and_then(|_| {
+                this.dpb = PictureArray::new(
+                    device,
+                    mem_props,
+                    &profile.list,
+                    max_extent,
+                    slots,
+                    dpb_usage,
+                    &self.families,
+                )?;
+                if let Some(usage) = output_usage {
+                    this.output = Some(PictureArray::new(
+                        device,
+                        mem_props,
+                        &profile.list,
+                        max_extent,
+                        slots,
+                        usage,
+                        &self.families,
+                    )?);
+                }
+                let size = align(INITIAL_BITSTREAM_SIZE, this.bitstream_alignment);
+                this.bitstream = Bitstream::new(
+                    device,
+                    mem_props,
+                    &profile.list,
+                    size,
+                    vk::BufferUsageFlags::VIDEO_DECODE_SRC_KHR,
+                )?;
+                this.parameters = self.create_parameters(device, session)?;
+                Ok(())
+            })
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { self.destroy_session(device, &this) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Session parameters holding every parameter set seen so far
+    #[track_caller]
+    unsafe fn create_parameters(
+        &self,
+        device: &Device,
+        session: vk::VideoSessionKHR,
+    ) -> VkResult<vk::VideoSessionParametersKHR> {
+        match self.codec {
+            Codec::H264 => self.create_h264_parameters(device, session),
+            Codec::H265 => self.create_h265_parameters(device, session),
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_h264_parameters(
+        &self,
+        device: &Device,
+        session: vk::VideoSessionKHR,
+    ) -> VkResult<vk::VideoSessionParametersKHR> {
+        let sps_scaling: Vec<_> = self
+            .h264
+            .sps
+            .values()
+            .map(|sps| sps.scaling_lists.as_ref().map(h264::ScalingLists::to_std))
+            .collect();
+        let std_sps: Vec<_> = self
+            .h264
+            .sps
+            .values()
+            .zip(&sps_scaling)
+            .map(|(sps, scaling)| sps.to_std(scaling.as_ref()))
+            .collect();
+        let pps_scaling: Vec<_> = self
+            .h264
+            .pps
+            .values()
+            .map(|pps| pps.scaling_lists.as_ref().map(h264::ScalingLists::to_std))
+            .collect();
+        let std_pps: Vec<_> = self
+            .h264
+            .pps
+            .values()
+            .zip(&pps_scaling)
+            .map(|(pps, scaling)| pps.to_std(scaling.as_ref()))
+            .collect();
+        let add_info = vk::VideoDecodeH264SessionParametersAddInfoKHR {
+            std_sps_count: std_sps.len() as u32,
+            p_std_sp_ss: std_sps.as_ptr(),
+            std_pps_count: std_pps.len() as u32,
+            p_std_pp_ss: std_pps.as_ptr(),
+            ..Default::default()
+        };
+        let h264_info = vk::VideoDecodeH264SessionParametersCreateInfoKHR {
+            max_std_sps_count: std_sps.len() as u32,
+            max_std_pps_count: std_pps.len() as u32,
+            p_parameters_add_info: &add_info,
+            ..Default::default()
+        };
+        self.create_session_parameters(device, session, &h264_info as *const _ as *const c_void)
+    }
+
+    #[track_caller]
+    unsafe fn create_h265_parameters(
+        &self,
+        device: &Device,
+        session: vk::VideoSessionKHR,
+    ) -> VkResult<vk::VideoSessionParametersKHR> {
+        let vps: Vec<_> = self.h265.vps.values().map(h265::Vps::to_std).collect();
+        let sps: Vec<_> = self.h265.sps.values().map(h265::Sps::to_std).collect();
+        // A PPS is keyed by the VPS of its SPS as well, those whose SPS hasn't come wait for it
+        let pps: Vec<_> = self
+            .h265
+            .pps
+            .values()
+            .filter_map(|pps| {
+                let sps = self.h265.sps.get(&pps.sps_id)?;
+                Some(pps.to_std(sps.vps_id))
+            })
+            .collect();
+        let std_vps: Vec<_> = vps.iter().map(|vps| vps.set).collect();
+        let std_sps: Vec<_> = sps.iter().map(|sps| sps.set).collect();
+        let std_pps: Vec<_> = pps.iter().map(|pps| pps.set).collect();
+        let add_info = vk::VideoDecodeH265SessionParametersAddInfoKHR {
+            std_vps_count: std_vps.len() as u32,
+            p_std_vp_ss: std_vps.as_ptr(),
+            std_sps_count: std_sps.len() as u32,
+            p_std_sp_ss: std_sps.as_ptr(),
+            std_pps_count: std_pps.len() as u32,
+            p_std_pp_ss: std_pps.as_ptr(),
+            ..Default::default()
+        };
+        let h265_info = vk::VideoDecodeH265SessionParametersCreateInfoKHR {
+            max_std_vps_count: std_vps.len() as u32,
+            max_std_sps_count: std_sps.len() as u32,
+            max_std_pps_count: std_pps.len() as u32,
+            p_parameters_add_info: &add_info,
+            ..Default::default()
+        };
+        self.create_session_parameters(device, session, &h265_info as *const _ as *const c_void)
+    }
+
+    /// Session parameters from the codec's create info `codec_info`
+    #[track_caller]
+    unsafe fn create_session_parameters(
+        &self,
+        device: &Device,
+        session: vk::VideoSessionKHR,
+        codec_info: *const c_void,
+    ) -> VkResult<vk::VideoSessionParametersKHR> {
+        let info = vk::VideoSessionParametersCreateInfoKHR {
+            p_next: codec_info,
+            video_session: session,
+            ..Default::default()
+        };
+        let mut parameters = vk::VideoSessionParametersKHR::null();
+        traced!(
+            "vkCreateVideoSessionParametersKHR",
+            session = session;
+            (self.fns.queue.create_video_session_parameters_khr)(
+                device.handle(),
+                &info,
+                ptr::null(),
+                &mut parameters,
+            )
+            .result()
+        )?;
+        leaks::track(parameters, "video session parameters");
+        Ok(parameters)
+    }
+
+    /// Replaces the session parameters after a parameter set changed, once no decode uses them
+    unsafe fn update_parameters(&mut self, device: &Device) -> VkResult<()> {
+        let Some(session) = &self.session else {
+            return Ok(());
+        };
+        let parameters = self.create_parameters(device, session.session)?;
+        let session = self.session.as_mut().unwrap();
+        let old = std::mem::replace(&mut session.parameters, parameters);
+        leaks::untrack(old);
+        (self.fns.queue.destroy_video_session_parameters_khr)(device.handle(), old, ptr::null());
+        Ok(())
+    }
+
+    /// Records and submits the decode of an H.264 `picture` into `slot`
+    unsafe fn decode_h264(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        picture: &PendingPicture<h264::SliceHeader>,
+        slot: usize,
+        poc: [i32; 2],
+        references: &[Reference],
+    ) -> Result<(), VideoError> {
+        let session = self.session.as_ref().unwrap();
+        let header = &picture.header;
+        let pps = &self.h264.pps[&header.pps_id];
+        let extent = {
+            let (width, height) = self.h264.sps[&pps.sps_id].coded_extent();
+            vk::Extent2D { width, height }
+        };
+        let references = &references[..references.len().min(session.max_references)];
+
+        let mut std_references: Vec<StdVideoDecodeH264ReferenceInfo> = references
+            .iter()
+            .map(|reference| {
+                reference_info(reference.frame_num, reference.poc, reference.long_term)
+            })
+            .collect();
+        let long_term = (header.is_idr() && header.long_term_reference)
+            || header
+                .mmcos
+                .iter()
+                .any(|mmco| matches!(mmco, Mmco::CurrentToLongTerm { .. }));
+        std_references.push(reference_info(header.frame_num, poc, long_term));
+        let dpb_infos: Vec<vk::VideoDecodeH264DpbSlotInfoKHR> = std_references
+            .iter()
+            .map(|info| vk::VideoDecodeH264DpbSlotInfoKHR {
+                p_std_reference_info: info,
+                ..Default::default()
+            })
+            .collect();
+
+        let mut flags: StdVideoDecodeH264PictureInfoFlags = std::mem::zeroed();
+        flags.set_IdrPicFlag(header.is_idr() as u32);
+        flags.set_is_intra(matches!(header.slice_type, SliceType::I | SliceType::Si) as u32);
+        flags.set_is_reference(header.is_reference() as u32);
+        let std_picture = StdVideoDecodeH264PictureInfo {
+            flags,
+            seq_parameter_set_id: pps.sps_id,
+            pic_parameter_set_id: pps.id,
+            reserved1: 0,
+            reserved2: 0,
+            frame_num: header.frame_num as u16,
+            idr_pic_id: header.idr_pic_id as u16,
+            PicOrderCnt: poc,
+        };
+        let h264_picture = vk::VideoDecodeH264PictureInfoKHR {
+            p_std_picture_info: &std_picture,
+            slice_count: picture.offsets.len() as u32,
+            p_slice_offsets: picture.offsets.as_ptr(),
+            ..Default::default()
+        };
+        let slots: Vec<usize> = references.iter().map(|reference| reference.slot).collect();
+        let slot_infos: Vec<*const c_void> = dpb_infos
+            .iter()
+            .map(|info| info as *const _ as *const c_void)
+            .collect();
+        self.submit_decode(
+            device,
+            mem_props,
+            &DecodeInfo {
+                data: &picture.data,
+                slot,
+                extent,
+                references: &slots,
+                slot_infos: &slot_infos,
+                picture_info: &h264_picture as *const _ as *const c_void,
+            },
+        )
+    }
+
+    /// Records and submits the decode of an H.265 `picture` into `slot`
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn decode_h265(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        picture: &PendingPicture<h265::SliceHeader>,
+        sps: &h265::Sps,
+        slot: usize,
+        poc: i32,
+        references: &References,
+    ) -> Result<(), VideoError> {
+        let session = self.session.as_ref().unwrap();
+        let header = &picture.header;
+        let pps = &self.h265.pps[&header.pps_id];
+        let extent = {
+            let (width, height) = sps.coded_extent();
+            vk::Extent2D { width, height }
+        };
+        // References past what the session binds are left out of the lists as well
+        let active = &references.active[..references.active.len().min(session.max_references)];
+        let list = |slots: &[Option<usize>]| {
+            let mut list = [NO_REFERENCE_PICTURE; 8];
+            for (entry, &slot) in list.iter_mut().zip(slots) {
+                if let Some(slot) =
+                    slot.filter(|&slot| active.iter().any(|reference| reference.slot == slot))
+                {
+                    *entry = slot as u8;
+                }
+            }
+            list
+        };
+
+        let std_references: Vec<StdVideoDecodeH265ReferenceInfo> = active
+            .iter()
+            .map(|reference| h265_reference_info(reference.poc, reference.long_term))
+            .chain(std::iter::once(h265_reference_info(poc, false)))
+            .collect();
+        let dpb_infos: Vec<vk::VideoDecodeH265DpbSlotInfoKHR> = std_references
+            .iter()
+            .map(|info| vk::VideoDecodeH265DpbSlotInfoKHR {
+                p_std_reference_info: info,
+                ..Default::default()
+            })
+            .collect();
+
+        let nal = header.nal;
+        let mut flags: StdVideoDecodeH265PictureInfoFlags = std::mem::zeroed();
+        flags.set_IrapPicFlag(nal.is_irap() as u32);
+        flags.set_IdrPicFlag(nal.is_idr() as u32);
+        flags.set_IsReference(!nal.is_sub_layer_non_reference() as u32);
+        flags.set_short_term_ref_pic_set_sps_flag(header.short_term_rps.is_none() as u32);
+        let std_picture = StdVideoDecodeH265PictureInfo {
+            flags,
+            sps_video_parameter_set_id: sps.vps_id,
+            pps_seq_parameter_set_id: sps.id,
+            pps_pic_parameter_set_id: pps.id,
+            NumDeltaPocsOfRefRpsIdx: header.num_delta_pocs_of_ref_rps(sps),
+            PicOrderCntVal: poc,
+            NumBitsForSTRefPicSetInSlice: header.short_term_rps_bits as u16,
+            reserved: 0,
+            RefPicSetStCurrBefore: list(&references.st_curr_before),
+            RefPicSetStCurrAfter: list(&references.st_curr_after),
+            RefPicSetLtCurr: list(&references.lt_curr),
+        };
+        let h265_picture = vk::VideoDecodeH265PictureInfoKHR {
+            p_std_picture_info: &std_picture,
+            slice_segment_count: picture.offsets.len() as u32,
+            p_slice_segment_offsets: picture.offsets.as_ptr(),
+            ..Default::default()
+        };
+        let slots: Vec<usize> = active.iter().map(|reference| reference.slot).collect();
+        let slot_infos: Vec<*const c_void> = dpb_infos
+            .iter()
+            .map(|info| info as *const _ as *const c_void)
+            .collect();
+        self.submit_decode(
+            device,
+            mem_props,
+            &DecodeInfo {
+                data: &picture.data,
+                slot,
+                extent,
+                references: &slots,
+                slot_infos: &slot_infos,
+                picture_info: &h265_picture as *const _ as *const c_void,
+            },
+        )
+    }
+
+    /// Uploads the bitstream of `info` and records and submits its decode
+    unsafe fn submit_decode(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        info: &DecodeInfo,
+    ) -> Result<(), VideoError> {
+        device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+        let codec = self.codec;
+        let session = self.session.as_mut().unwrap();
+        let data = info.data;
+        let size = align(data.len() as vk::DeviceSize, session.bitstream_alignment);
+        if size > session.bitstream.size {
+            let profile = Profile::new(codec, session.profile_idc);
+            let bitstream = Bitstream::new(
+                device,
+                mem_props,
+                &profile.list,
+                size.next_power_of_two(),
+                vk::BufferUsageFlags::VIDEO_DECODE_SRC_KHR,
+            )?;
+            std::mem::replace(&mut session.bitstream, bitstream).destroy(device);
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), session.bitstream.mapped, data.len());
+        // Padding up to the alignment is read too, zeros are harmless trailing data
+        ptr::write_bytes(
+            session.bitstream.mapped.add(data.len()),
+            0,
+            (size - data.len() as vk::DeviceSize) as usize,
+        );
+
+        let (slot, extent) = (info.slot, info.extent);
+        let mut resources: Vec<vk::VideoPictureResourceInfoKHR> = info
+            .references
+            .iter()
+            .map(|&reference| session.dpb.resource(reference, extent))
+            .collect();
+        resources.push(session.dpb.resource(slot, extent));
+        let mut slots: Vec<vk::VideoReferenceSlotInfoKHR> = info
+            .references
+            .iter()
+            .enumerate()
+            .map(|(index, &reference)| vk::VideoReferenceSlotInfoKHR {
+                p_next: info.slot_infos[index],
+                slot_index: reference as i32,
+                p_picture_resource: &resources[index],
+                ..Default::default()
+            })
+            .collect();
+        let setup = vk::VideoReferenceSlotInfoKHR {
+            p_next: info.slot_infos[info.references.len()],
+            slot_index: slot as i32,
+            p_picture_resource: resources.last().unwrap(),
+            ..Default::default()
+        };
+        // The picture being set up is bound too, as not yet active
+        slots.push(vk::VideoReferenceSlotInfoKHR {
+            slot_index: -1,
+            ..setup
+        });
+
+        let dst_picture = match &session.output {
+            Some(output) => output.resource(slot, extent),
+            None => session.dpb.resource(slot, extent),
+        };
+        let decode_info = vk::VideoDecodeInfoKHR {
+            p_next: info.picture_info,
+            src_buffer: session.bitstream.buffer,
+            src_buffer_offset: 0,
+            src_buffer_range: size,
+            dst_picture_resource: dst_picture,
+            p_setup_reference_slot: &setup,
+            reference_slot_count: info.references.len() as u32,
+            p_reference_slots: slots.as_ptr(),
+            ..Default::default()
+        };
+
+        let cmd = self.command_buffer;
+        device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+        device.begin_command_buffer(
+            cmd,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+        // The slot's old contents are discarded, references only need the earlier writes
+        let layer = |image, layout| {
+            vk::ImageMemoryBarrier2::builder()
+                .dst_stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+                .dst_access_mask(vk::AccessFlags2::VIDEO_DECODE_WRITE_KHR)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: slot as u32,
+                    layer_count: 1,
+                })
+                .build()
+        };
+        let mut image_barriers = vec![layer(
+            session.dpb.image,
+            vk::ImageLayout::VIDEO_DECODE_DPB_KHR,
+        )];
+        if let Some(output) = &session.output {
+            image_barriers.push(layer(output.image, vk::ImageLayout::VIDEO_DECODE_DST_KHR));
+        }
+        let memory_barrier = vk::MemoryBarrier2::builder()
+            .src_stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+            .src_access_mask(vk::AccessFlags2::VIDEO_DECODE_WRITE_KHR)
+            .dst_stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+            .dst_access_mask(vk::AccessFlags2::VIDEO_DECODE_READ_KHR);
+        device.cmd_pipeline_barrier2(
+            cmd,
+            &vk::DependencyInfo::builder()
+                .memory_barriers(&[*memory_barrier])
+                .image_memory_barriers(&image_barriers),
+        );
+        let begin = vk::VideoBeginCodingInfoKHR {
+            video_session: session.session,
+            video_session_parameters: session.parameters,
+            reference_slot_count: slots.len() as u32,
+            p_reference_slots: slots.as_ptr(),
+            ..Default::default()
+        };
+        traced!(
+            "vkCmdBeginVideoCodingKHR",
+            session = session.session,
+            slots = slots.len();
+            (self.fns.queue.cmd_begin_video_coding_khr)(cmd, &begin)
+        );
+        if session.reset {
+            let control = vk::VideoCodingControlInfoKHR {
+                flags: vk::VideoCodingControlFlagsKHR::RESET,
+                ..Default::default()
+            };
+            traced!(
+                "vkCmdControlVideoCodingKHR",
+                flags = control.flags;
+                (self.fns.queue.cmd_control_video_coding_khr)(cmd, &control)
+            );
+            session.reset = false;
+        }
+        traced!(
+            "vkCmdDecodeVideoKHR",
+            src_offset = decode_info.src_buffer_offset,
+            src_range = decode_info.src_buffer_range;
+            (self.fns.decode.cmd_decode_video_khr)(cmd, &decode_info)
+        );
+        traced!(
+            "vkCmdEndVideoCodingKHR";
+            (self.fns.queue.cmd_end_video_coding_khr)(cmd, &vk::VideoEndCodingInfoKHR::default())
+        );
+        device.end_command_buffer(cmd)?;
+
+        let waits: Vec<vk::SemaphoreSubmitInfo> = [
+            (self.released_pending, self.released),
+            (self.decoded_pending, self.decoded),
+        ]
+        .iter()
+        .filter(|(pending, _)| *pending)
+        .map(|&(_, semaphore)| {
+            *vk::SemaphoreSubmitInfo::builder()
+                .semaphore(semaphore)
+                .stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+        })
+        .collect();
+        let signal = vk::SemaphoreSubmitInfo::builder()
+            .semaphore(self.decoded)
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS);
+        let cmd_info = vk::CommandBufferSubmitInfo::builder().command_buffer(cmd);
+        let submit = vk::SubmitInfo2::builder()
+            .wait_semaphore_infos(&waits)
+            .command_buffer_infos(std::slice::from_ref(&cmd_info))
+            .signal_semaphore_infos(std::slice::from_ref(&signal));
+        device.reset_fences(&[self.fence])?;
+        traced!(
+            "vkQueueSubmit2",
+            queue = self.queue,
+            waits = waits.len();
+            device.queue_submit2(self.queue, &[*submit], self.fence)
+        )?;
+        self.released_pending = false;
+        self.decoded_pending = true;
+        Ok(())
+    }
+
+    unsafe fn destroy_session(&self, device: &Device, session: &Session) {
+        if session.parameters != vk::VideoSessionParametersKHR::null() {
+            leaks::untrack(session.parameters);
+            (self.fns.queue.destroy_video_session_parameters_khr)(
+                device.handle(),
+                session.parameters,
+                ptr::null(),
+            );
+        }
+        leaks::untrack(session.session);
+        (self.fns.queue.destroy_video_session_khr)(device.handle(), session.session, ptr::null());
+        for &memory in &session.memory {
+            leaks::untrack(memory);
+            device.free_memory(memory, None);
+        }
+        session.dpb.destroy(device);
+        if let Some(output) = &session.output {
+            output.destroy(device);
+        }
+        session.bitstream.destroy(device);
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with every decode and every copy out of the decoder's pictures.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        if let Some(session) = self.session.take() {
+            self.destroy_session(device, &session);
+        }
+        leaks::untrack(self.decoded);
+        leaks::untrack(self.released);
+        device.destroy_semaphore(self.decoded, None);
+        device.destroy_semaphore(self.released, None);
+        leaks::untrack(self.fence);
+        device.destroy_fence(self.fence, None);
+        leaks::untrack(self.command_pool);
+        device.destroy_command_pool(self.command_pool, None);
+    }
+}
+
+/// Stores `value` under `id`, telling whether that changed anything
+fn insert_changed<T: PartialEq>(sets: &mut BTreeMap<u8, T>, id: u8, value: T) -> bool {
+    if sets.get(&id) == Some(&value) {
+        return false;
+    }
+    sets.insert(id, value);
+    true
+}
+
+/// Rejects streams other than the 8-bit 4:2:0 ones [`PICTURE_FORMAT`] holds
+fn check_format(
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+) -> Result<(), VideoError> {
+    if chroma_format_idc != 1 || bit_depth_luma_minus8 != 0 || bit_depth_chroma_minus8 != 0 {
+        return Err(VideoError::Unsupported("streams other than 8-bit 4:2:0"));
+    }
+    Ok(())
+}
+
+fn reference_info(
+    frame_num: u32,
+    poc: [i32; 2],
+    long_term: bool,
+) -> StdVideoDecodeH264ReferenceInfo {
+    // Plain bitfields, all zero is every flag unset
+    let mut flags: StdVideoDecodeH264ReferenceInfoFlags = unsafe { std::mem::zeroed() };
+    flags.set_used_for_long_term_reference(long_term as u32);
+    StdVideoDecodeH264ReferenceInfo {
+        flags,
+        FrameNum: frame_num as u16,
+        reserved: 0,
+        PicOrderCnt: poc,
+    }
+}
+
+fn h265_reference_info(poc: i32, long_term: bool) -> StdVideoDecodeH265ReferenceInfo {
+    let mut flags: StdVideoDecodeH265ReferenceInfoFlags = unsafe { std::mem::zeroed() };
+    flags.set_used_for_long_term_reference(long_term as u32);
+    StdVideoDecodeH265ReferenceInfo {
+        flags,
+        PicOrderCntVal: poc,
+    }
+}
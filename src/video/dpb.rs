@@ -0,0 +1,451 @@
+//! Decoded picture buffer bookkeeping, clause 8.2.5 and annex C.4 of H.264
+//!
+//! Each slot is an image the decoder writes into and later reads references from. A picture
+//! holds its slot while it's a reference or still waiting to be shown, pictures are shown in
+//! picture order count order once more than the stream's reorder depth are waiting. Gaps in
+//! `frame_num` aren't concealed, streams are expected to be complete.
+
+use super::{
+    h264::{Mmco, SliceHeader, Sps},
+    VideoError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marking {
+    Unused,
+    ShortTerm,
+    LongTerm { frame_idx: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    marking: Marking,
+    needed_for_output: bool,
+    frame_num: u32,
+    poc: [i32; 2],
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        marking: Marking::Unused,
+        needed_for_output: false,
+        frame_num: 0,
+        poc: [0; 2],
+    };
+
+    fn is_free(&self) -> bool {
+        self.marking == Marking::Unused && !self.needed_for_output
+    }
+
+    fn order(&self) -> i32 {
+        self.poc[0].min(self.poc[1])
+    }
+}
+
+/// A reference picture the current picture may predict from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub slot: usize,
+    /// `FrameNum` for short term references, `LongTermFrameIdx` for long term ones
+    pub frame_num: u32,
+    pub long_term: bool,
+    pub poc: [i32; 2],
+}
+
+/// A decoded picture due to be shown, valid until the next picture starts decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Output {
+    pub slot: usize,
+    pub poc: i32,
+}
+
+pub struct Dpb {
+    slots: Vec<Slot>,
+    max_num_ref_frames: usize,
+    max_frame_num: u32,
+    reorder_depth: usize,
+    /// `MaxLongTermFrameIdx`, `None` for "no long-term frame indices"
+    max_long_term_frame_idx: Option<u32>,
+}
+
+impl Dpb {
+    pub fn new(slot_count: usize) -> Self {
+        Dpb {
+            slots: vec![Slot::EMPTY; slot_count],
+            max_num_ref_frames: 1,
+            max_frame_num: 16,
+            reorder_depth: 0,
+            max_long_term_frame_idx: None,
+        }
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Slots `sps` needs: its references, the pictures it reorders and the one being decoded
+    pub fn slots_for(sps: &Sps) -> usize {
+        sps.max_num_ref_frames.max(1) as usize + sps.reorder_depth() as usize + 1
+    }
+
+    /// Takes the limits of the sequence `sps` starts, at an IDR picture
+    pub fn configure(&mut self, sps: &Sps) {
+        self.max_num_ref_frames = sps.max_num_ref_frames.max(1) as usize;
+        self.max_frame_num = sps.max_frame_num();
+        self.reorder_depth = sps.reorder_depth() as usize;
+    }
+
+    /// Picks the slot to decode the picture `header` starts into
+    ///
+    /// IDR pictures first drop every reference and show everything waiting. Otherwise pictures
+    /// are shown early only when no slot is free.
+    pub fn begin_picture(
+        &mut self,
+        header: &SliceHeader,
+    ) -> Result<(usize, Vec<Output>), VideoError> {
+        let mut outputs = Vec::new();
+        if header.is_idr() {
+            for slot in &mut self.slots {
+                slot.marking = Marking::Unused;
+            }
+            self.max_long_term_frame_idx = None;
+            outputs.extend(self.flush());
+        }
+        loop {
+            if let Some(free) = self.slots.iter().position(Slot::is_free) {
+                return Ok((free, outputs));
+            }
+            outputs.push(self.bump().ok_or(VideoError::DpbFull)?);
+        }
+    }
+
+    /// References for the picture being decoded into `current`, short term ones first
+    pub fn references(&self, current: usize) -> Vec<Reference> {
+        let mut references: Vec<Reference> = (0..self.slots.len())
+            .filter(|&index| index != current)
+            .filter_map(|index| {
+                let slot = &self.slots[index];
+                let (frame_num, long_term) = match slot.marking {
+                    Marking::Unused => return None,
+                    Marking::ShortTerm => (slot.frame_num, false),
+                    Marking::LongTerm { frame_idx } => (frame_idx, true),
+                };
+                Some(Reference {
+                    slot: index,
+                    frame_num,
+                    long_term,
+                    poc: slot.poc,
+                })
+            })
+            .collect();
+        references.sort_by_key(|reference| reference.long_term);
+        references
+    }
+
+    /// Marks the picture just decoded into `current` and gives the pictures now due
+    pub fn finish_picture(
+        &mut self,
+        current: usize,
+        header: &SliceHeader,
+        poc: [i32; 2],
+    ) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        let mut marking = Marking::ShortTerm;
+        let mut poc = poc;
+        let mut frame_num = header.frame_num;
+        if !header.is_reference() {
+            marking = Marking::Unused;
+        } else if header.is_idr() {
+            if header.long_term_reference {
+                marking = Marking::LongTerm { frame_idx: 0 };
+                self.max_long_term_frame_idx = Some(0);
+            }
+        } else if header.mmcos.is_empty() {
+            self.sliding_window(current, header.frame_num);
+        } else {
+            for &mmco in &header.mmcos {
+                if let Some(long_term) = self.apply(mmco, current, header.frame_num) {
+                    marking = long_term;
+                }
+            }
+            if header.mmcos.contains(&Mmco::ForgetAll) {
+                // Everything before is shown first, later pictures see this one as the first of a
+                // new sequence
+                outputs.extend(self.flush());
+                let order = poc[0].min(poc[1]);
+                poc = [poc[0] - order, poc[1] - order];
+                frame_num = 0;
+            }
+        }
+        self.slots[current] = Slot {
+            marking,
+            needed_for_output: true,
+            frame_num,
+            poc,
+        };
+        while self.waiting() > self.reorder_depth {
+            outputs.extend(self.bump());
+        }
+        outputs
+    }
+
+    /// Gives every picture still waiting, at the end of the stream
+    pub fn flush(&mut self) -> Vec<Output> {
+        std::iter::from_fn(|| self.bump()).collect()
+    }
+
+    /// Forgets every picture, for seeking
+    pub fn clear(&mut self) {
+        self.slots.fill(Slot::EMPTY);
+        self.max_long_term_frame_idx = None;
+    }
+
+    fn waiting(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.needed_for_output)
+            .count()
+    }
+
+    /// Shows the waiting picture with the lowest order count
+    fn bump(&mut self) -> Option<Output> {
+        let (index, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, slot)| slot.needed_for_output)
+            .min_by_key(|(_, slot)| slot.order())?;
+        slot.needed_for_output = false;
+        Some(Output {
+            slot: index,
+            poc: slot.order(),
+        })
+    }
+
+    /// `FrameNumWrap` of a short term reference as seen from `current_frame_num`
+    fn pic_num(&self, slot: &Slot, current_frame_num: u32) -> i64 {
+        if slot.frame_num > current_frame_num {
+            slot.frame_num as i64 - self.max_frame_num as i64
+        } else {
+            slot.frame_num as i64
+        }
+    }
+
+    fn sliding_window(&mut self, current: usize, current_frame_num: u32) {
+        let references = self
+            .slots
+            .iter()
+            .filter(|slot| slot.marking != Marking::Unused)
+            .count();
+        if references < self.max_num_ref_frames {
+            return;
+        }
+        let oldest = (0..self.slots.len())
+            .filter(|&index| index != current && self.slots[index].marking == Marking::ShortTerm)
+            .min_by_key(|&index| self.pic_num(&self.slots[index], current_frame_num));
+        if let Some(oldest) = oldest {
+            self.slots[oldest].marking = Marking::Unused;
+        }
+    }
+
+    fn short_term_by_difference(&self, current: usize, frame_num: u32, diff: u32) -> Option<usize> {
+        let pic_num = frame_num as i64 - (diff as i64 + 1);
+        (0..self.slots.len()).find(|&index| {
+            index != current
+                && self.slots[index].marking == Marking::ShortTerm
+                && self.pic_num(&self.slots[index], frame_num) == pic_num
+        })
+    }
+
+    fn forget_long_term(&mut self, frame_idx: u32) {
+        for slot in &mut self.slots {
+            if slot.marking == (Marking::LongTerm { frame_idx }) {
+                slot.marking = Marking::Unused;
+            }
+        }
+    }
+
+    /// Applies one operation, giving the current picture's marking if it sets one
+    fn apply(&mut self, mmco: Mmco, current: usize, frame_num: u32) -> Option<Marking> {
+        match mmco {
+            Mmco::ForgetShortTerm {
+                difference_of_pic_nums_minus1,
+            } => {
+                if let Some(index) =
+                    self.short_term_by_difference(current, frame_num, difference_of_pic_nums_minus1)
+                {
+                    self.slots[index].marking = Marking::Unused;
+                }
+            }
+            Mmco::ForgetLongTerm { long_term_pic_num } => self.forget_long_term(long_term_pic_num),
+            Mmco::ShortToLongTerm {
+                difference_of_pic_nums_minus1,
+                long_term_frame_idx,
+            } => {
+                if let Some(index) =
+                    self.short_term_by_difference(current, frame_num, difference_of_pic_nums_minus1)
+                {
+                    self.forget_long_term(long_term_frame_idx);
+                    self.slots[index].marking = Marking::LongTerm {
+                        frame_idx: long_term_frame_idx,
+                    };
+                }
+            }
+            Mmco::MaxLongTermIdx {
+                max_long_term_frame_idx_plus1,
+            } => {
+                self.max_long_term_frame_idx = max_long_term_frame_idx_plus1.checked_sub(1);
+                for slot in &mut self.slots {
+                    if let Marking::LongTerm { frame_idx } = slot.marking {
+                        if self
+                            .max_long_term_frame_idx
+                            .is_none_or(|max| frame_idx > max)
+                        {
+                            slot.marking = Marking::Unused;
+                        }
+                    }
+                }
+            }
+            Mmco::ForgetAll => {
+                for (index, slot) in self.slots.iter_mut().enumerate() {
+                    if index != current {
+                        slot.marking = Marking::Unused;
+                    }
+                }
+                self.max_long_term_frame_idx = None;
+            }
+            Mmco::CurrentToLongTerm {
+                long_term_frame_idx,
+            } => {
+                self.forget_long_term(long_term_frame_idx);
+                return Some(Marking::LongTerm {
+                    frame_idx: long_term_frame_idx,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::h264::{NalHeader, SliceType, NAL_IDR_SLICE, NAL_SLICE};
+
+    fn dpb(max_num_ref_frames: usize, reorder_depth: usize) -> Dpb {
+        let mut dpb = Dpb::new(max_num_ref_frames + reorder_depth + 1);
+        dpb.max_num_ref_frames = max_num_ref_frames;
+        dpb.reorder_depth = reorder_depth;
+        dpb
+    }
+
+    fn header(frame_num: u32, mmcos: Vec<Mmco>) -> SliceHeader {
+        SliceHeader {
+            nal: NalHeader {
+                nal_ref_idc: 1,
+                nal_unit_type: NAL_SLICE,
+            },
+            first_mb_in_slice: 0,
+            slice_type: SliceType::P,
+            pps_id: 0,
+            frame_num,
+            idr_pic_id: 0,
+            pic_order_cnt_lsb: 0,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0; 2],
+            long_term_reference: false,
+            mmcos,
+        }
+    }
+
+    fn idr() -> SliceHeader {
+        let mut header = header(0, Vec::new());
+        header.nal.nal_unit_type = NAL_IDR_SLICE;
+        header
+    }
+
+    /// Decodes a frame, giving the order counts shown meanwhile
+    fn decode(dpb: &mut Dpb, header: &SliceHeader, poc: i32) -> Vec<i32> {
+        let (slot, mut outputs) = dpb.begin_picture(header).unwrap();
+        outputs.extend(dpb.finish_picture(slot, header, [poc; 2]));
+        outputs.iter().map(|output| output.poc).collect()
+    }
+
+    /// `FrameNum` or `LongTermFrameIdx` of each reference, and whether it's long term
+    fn references(dpb: &Dpb) -> Vec<(u32, bool)> {
+        let mut references: Vec<_> = dpb
+            .references(usize::MAX)
+            .iter()
+            .map(|reference| (reference.frame_num, reference.long_term))
+            .collect();
+        references.sort();
+        references
+    }
+
+    #[test]
+    fn sliding_window_drops_the_oldest_frame_across_a_frame_num_wrap() {
+        let mut dpb = dpb(2, 0);
+        decode(&mut dpb, &idr(), 0);
+        for frame_num in 1..18 {
+            decode(
+                &mut dpb,
+                &header(frame_num % 16, Vec::new()),
+                2 * frame_num as i32,
+            );
+        }
+        // Frame 15 came before frame 0 of the next cycle and goes first
+        assert_eq!(references(&dpb), [(0, false), (1, false)]);
+    }
+
+    #[test]
+    fn mmcos_forget_and_promote_references() {
+        let mut dpb = dpb(3, 0);
+        decode(&mut dpb, &idr(), 0);
+        for frame_num in 1..3 {
+            decode(
+                &mut dpb,
+                &header(frame_num, Vec::new()),
+                2 * frame_num as i32,
+            );
+        }
+        let mmcos = vec![
+            Mmco::ForgetShortTerm {
+                difference_of_pic_nums_minus1: 2,
+            },
+            Mmco::ShortToLongTerm {
+                difference_of_pic_nums_minus1: 0,
+                long_term_frame_idx: 0,
+            },
+        ];
+        decode(&mut dpb, &header(3, mmcos), 6);
+        assert_eq!(references(&dpb), [(0, true), (1, false), (3, false)]);
+
+        let mmcos = vec![
+            Mmco::MaxLongTermIdx {
+                max_long_term_frame_idx_plus1: 0,
+            },
+            Mmco::CurrentToLongTerm {
+                long_term_frame_idx: 1,
+            },
+        ];
+        decode(&mut dpb, &header(4, mmcos), 8);
+        assert_eq!(references(&dpb), [(1, false), (1, true), (3, false)]);
+    }
+
+    #[test]
+    fn pictures_come_out_past_the_reorder_depth_and_on_forget_all() {
+        let mut dpb = dpb(2, 2);
+        let mut shown = Vec::new();
+        shown.extend(decode(&mut dpb, &idr(), 0));
+        for (frame_num, poc) in [(1, 4), (2, 2)] {
+            shown.extend(decode(&mut dpb, &header(frame_num, Vec::new()), poc));
+        }
+        assert_eq!(shown, [0]);
+        // Everything before is shown, the picture itself counts from 0 again
+        shown.extend(decode(&mut dpb, &header(3, vec![Mmco::ForgetAll]), 6));
+        assert_eq!(shown, [0, 2, 4]);
+        assert_eq!(references(&dpb), [(0, false)]);
+        shown.extend(dpb.flush().iter().map(|output| output.poc));
+        assert_eq!(shown, [0, 2, 4, 0]);
+    }
+}
@@ -0,0 +1,1602 @@
+//! Encoding presented frames to H.264 on a video encode queue, for recording
+//!
+//! [`VideoEncoder::record_capture`] copies a presented image, converts it to 4:2:0 Y'CbCr in a
+//! compute pass and copies the planes into a picture the encoder reads, all in the frame's own
+//! command buffer. [`VideoEncoder::submit`] encodes it on the encode queue once that command
+//! buffer has run, and [`VideoEncoder::poll`] gives back the finished Annex B bytes. Only the
+//! encoded slices are read back, kilobytes a frame where [`crate::capture::FrameReadback`] reads
+//! whole images.
+//!
+//! The stream is I and P pictures only, each P picture predicting from the one before, with an
+//! IDR picture every [`VideoEncoderConfig::idr_period`] frames and the parameter sets in front of
+//! each. It's Main profile, narrow range BT.709, which [`super::player::VideoPlayer`] plays back.
+
+use std::{collections::VecDeque, ffi::c_void, io::Cursor, ptr};
+
+use ash::{
+    prelude::VkResult,
+    util::read_spv,
+    vk::{
+        self,
+        native::{
+            StdVideoH264PictureType, StdVideoH264PictureType_STD_VIDEO_H264_PICTURE_TYPE_IDR,
+            StdVideoH264PictureType_STD_VIDEO_H264_PICTURE_TYPE_P,
+            StdVideoH264SliceType_STD_VIDEO_H264_SLICE_TYPE_I,
+            StdVideoH264SliceType_STD_VIDEO_H264_SLICE_TYPE_P,
+        },
+    },
+    Device, Instance,
+};
+
+use super::{
+    decode::PICTURE_FORMAT,
+    h264::{self, NalHeader, Pps, Sps, Vui},
+    session::{align, bind_session_memory, supports_format, Bitstream, PictureArray},
+    VideoEncodeFns, VideoError,
+};
+use crate::{
+    assets::GpuAsset,
+    device::{
+        vk_ext::{
+            QueryPoolVideoEncodeFeedbackCreateInfoKHR, StdVideoEncodeH264PictureInfo,
+            StdVideoEncodeH264ReferenceInfo, StdVideoEncodeH264ReferenceListsInfo,
+            StdVideoEncodeH264SliceHeader, VideoEncodeCapabilitiesKHR,
+            VideoEncodeH264CapabilitiesKHR, VideoEncodeH264DpbSlotInfoKHR,
+            VideoEncodeH264NaluSliceInfoKHR, VideoEncodeH264PictureInfoKHR,
+            VideoEncodeH264ProfileInfoKHR, VideoEncodeH264RateControlInfoKHR,
+            VideoEncodeH264SessionParametersAddInfoKHR,
+            VideoEncodeH264SessionParametersCreateInfoKHR,
+            VideoEncodeH264SessionParametersFeedbackInfoKHR,
+            VideoEncodeH264SessionParametersGetInfoKHR, VideoEncodeInfoKHR,
+            VideoEncodeRateControlInfoKHR, VideoEncodeRateControlLayerInfoKHR,
+            VideoEncodeSessionParametersFeedbackInfoKHR, VideoEncodeSessionParametersGetInfoKHR,
+            VideoEncodeUsageInfoKHR, STD_VIDEO_ENCODE_H264_PICTURE_IDR,
+            STD_VIDEO_ENCODE_H264_PICTURE_IS_REFERENCE, STD_VIDEO_H264_NO_REFERENCE_PICTURE,
+            VIDEO_CODEC_OPERATION_ENCODE_H264, VIDEO_CODING_CONTROL_ENCODE_RATE_CONTROL,
+            VIDEO_ENCODE_H264_RATE_CONTROL_REFERENCE_PATTERN_FLAT,
+            VIDEO_ENCODE_H264_RATE_CONTROL_REGULAR_GOP,
+            VIDEO_ENCODE_H264_STD_ENTROPY_CODING_MODE_FLAG_SET,
+        },
+        QueueFamilies, Queues,
+    },
+    leaks,
+    shader::reflect,
+    texture::{
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        Texture,
+    },
+    trace::traced,
+};
+
+/// Converts a captured frame to narrow range BT.709 luma and chroma, a 2x2 block per invocation
+///
+/// Shipped compiled, so encoding doesn't need the `naga` feature
+pub const ENCODE_CONVERT_SPV: &[u8] = include_bytes!("shaders/encode_convert.comp.spv");
+
+/// The source [`ENCODE_CONVERT_SPV`] is compiled from
+pub const ENCODE_CONVERT_GLSL: &str = include_str!("shaders/encode_convert.comp");
+
+/// `profile_idc` of the streams written, Main for the widest playback support
+const PROFILE_IDC: u8 = 77;
+
+/// Frames a picture's `frame_num` counts to before wrapping, `log2_max_frame_num_minus4` of 4
+const LOG2_MAX_FRAME_NUM_MINUS4: u8 = 4;
+
+/// The DPB holds the picture being encoded and the one it predicts from
+const DPB_SLOTS: u32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VideoEncoderConfig {
+    /// Frames that can be encoding at once, more are dropped until [`VideoEncoder::poll`] catches
+    /// up
+    pub frames_in_flight: usize,
+    pub frame_rate: u32,
+    /// Frames from one IDR picture to the next, where playback can start
+    pub idr_period: u32,
+    /// Average bits a second, left to the implementation when `None`
+    pub bitrate: Option<u64>,
+}
+
+impl VideoEncoderConfig {
+    /// An IDR picture every two seconds, at the implementation's default bitrate
+    pub fn new(frame_rate: u32) -> Self {
+        VideoEncoderConfig {
+            frames_in_flight: 3,
+            frame_rate,
+            idr_period: frame_rate.max(1) * 2,
+            bitrate: None,
+        }
+    }
+
+    pub fn with_bitrate(mut self, bitrate: u64) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    pub fn with_idr_period(mut self, frames: u32) -> Self {
+        self.idr_period = frames.max(1);
+        self
+    }
+}
+
+/// The encode profile, boxed so the chain of pointers stays put
+struct Profile {
+    usage: VideoEncodeUsageInfoKHR,
+    h264: VideoEncodeH264ProfileInfoKHR,
+    info: vk::VideoProfileInfoKHR,
+    list: vk::VideoProfileListInfoKHR,
+}
+
+impl Profile {
+    fn new() -> Box<Self> {
+        let mut profile = Box::new(Profile {
+            usage: VideoEncodeUsageInfoKHR {
+                video_usage_hints: vk::VideoEncodeUsageFlagsKHR::RECORDING,
+                video_content_hints: vk::VideoEncodeContentFlagsKHR::RENDERED,
+                tuning_mode: vk::VideoEncodeTuningModeKHR::DEFAULT,
+                ..Default::default()
+            },
+            h264: VideoEncodeH264ProfileInfoKHR {
+                std_profile_idc: PROFILE_IDC as u32,
+                ..Default::default()
+            },
+            info: vk::VideoProfileInfoKHR {
+                video_codec_operation: VIDEO_CODEC_OPERATION_ENCODE_H264,
+                chroma_subsampling: vk::VideoChromaSubsamplingFlagsKHR::TYPE_420,
+                luma_bit_depth: vk::VideoComponentBitDepthFlagsKHR::TYPE_8,
+                chroma_bit_depth: vk::VideoComponentBitDepthFlagsKHR::TYPE_8,
+                ..Default::default()
+            },
+            list: vk::VideoProfileListInfoKHR::default(),
+        });
+        profile.h264.p_next = &profile.usage as *const _ as *const c_void;
+        profile.info.p_next = &profile.h264 as *const _ as *const c_void;
+        profile.list.profile_count = 1;
+        profile.list.p_profiles = &profile.info;
+        profile
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Idle,
+    /// Converted in a command buffer the caller hasn't necessarily submitted yet
+    Recorded,
+    Encoding {
+        idr: bool,
+    },
+}
+
+/// The last picture encoded, which the next P picture references
+#[derive(Debug, Clone, Copy)]
+struct Previous {
+    dpb_slot: u32,
+    picture_type: StdVideoH264PictureType,
+    frame_num: u32,
+    poc: i32,
+}
+
+/// Encodes captured frames to an H.264 elementary stream
+///
+/// Frames go through slots, one per frame in flight. Each slot's resources sit at its index in
+/// the vectors below, which also hold whatever was made when creation fails part way.
+pub struct VideoEncoder {
+    fns: VideoEncodeFns,
+    queue: vk::Queue,
+    /// Graphics and encode families, deduplicated
+    families: Vec<u32>,
+    extent: vk::Extent2D,
+    coded_extent: vk::Extent2D,
+    /// The capture format's UNORM twin, so the shader reads the encoded values
+    frame_format: vk::Format,
+    config: VideoEncoderConfig,
+    rate_control_mode: vk::VideoEncodeRateControlModeFlagsKHR,
+    bitstream_alignment: vk::DeviceSize,
+    /// SPS and PPS NAL units, written before every IDR picture
+    headers: Vec<u8>,
+    session: vk::VideoSessionKHR,
+    session_memory: Vec<vk::DeviceMemory>,
+    parameters: vk::VideoSessionParametersKHR,
+    /// One layer per slot
+    source: PictureArray,
+    dpb: PictureArray,
+    query_pool: vk::QueryPool,
+    command_pool: vk::CommandPool,
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    descriptor_pool: vk::DescriptorPool,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    frames: Vec<Texture>,
+    lumas: Vec<Texture>,
+    chromas: Vec<Texture>,
+    sets: Vec<vk::DescriptorSet>,
+    bitstreams: Vec<Bitstream>,
+    command_buffers: Vec<vk::CommandBuffer>,
+    fences: Vec<vk::Fence>,
+    /// Signalled by the caller's submission of the frame's command buffer
+    converted: Vec<vk::Semaphore>,
+    states: Vec<SlotState>,
+    /// Slots recorded or encoding, oldest first
+    order: VecDeque<usize>,
+    reset: bool,
+    /// Pictures encoded since the start
+    encoded: u64,
+    previous: Option<Previous>,
+}
+
+impl VideoEncoder {
+    /// An encoder for frames of `format` and `extent` captured on the graphics queue
+    ///
+    /// The captured images need `TRANSFER_SRC` usage, like for [`crate::capture::FrameReadback`].
+    /// Fails when the device has no H.264 encode queue or can't encode this size.
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    pub fn new(
+        fns: &VideoEncodeFns,
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        queue_families: &QueueFamilies,
+        queues: &Queues,
+        samplers: &mut SamplerCache,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        config: VideoEncoderConfig,
+    ) -> Result<Self, VideoError> {
+        let (Some(encode_family), Some(queue)) = (queue_families.video_encode, queues.video_encode)
+        else {
+            return Err(VideoError::DeviceUnsupported("no video encode queue"));
+        };
+        let frame_format = match format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => vk::Format::B8G8R8A8_UNORM,
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => vk::Format::R8G8B8A8_UNORM,
+            vk::Format::A8B8G8R8_UNORM_PACK32 | vk::Format::A8B8G8R8_SRGB_PACK32 => {
+                vk::Format::A8B8G8R8_UNORM_PACK32
+            }
+            _ => {
+                return Err(VideoError::Unsupported(
+                    "captures other than 8 bit RGBA and BGRA",
+                ))
+            }
+        };
+        let family_count =
+            unsafe { instance.get_physical_device_queue_family_properties2_len(physical_device) };
+        let mut video_props = vec![vk::QueueFamilyVideoPropertiesKHR::default(); family_count];
+        let mut props: Vec<_> = video_props
+            .iter_mut()
+            .map(|video| vk::QueueFamilyProperties2 {
+                p_next: video as *mut _ as *mut c_void,
+                ..Default::default()
+            })
+            .collect();
+        unsafe {
+            instance.get_physical_device_queue_family_properties2(physical_device, &mut props)
+        };
+        if !video_props[encode_family as usize]
+            .video_codec_operations
+            .contains(VIDEO_CODEC_OPERATION_ENCODE_H264)
+        {
+            return Err(VideoError::DeviceUnsupported("no H.264 encode queue"));
+        }
+
+        let profile = Profile::new();
+        let mut h264_caps = VideoEncodeH264CapabilitiesKHR::default();
+        let mut encode_caps = VideoEncodeCapabilitiesKHR {
+            p_next: &mut h264_caps as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        let mut caps = vk::VideoCapabilitiesKHR {
+            p_next: &mut encode_caps as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        let result = unsafe {
+            (fns.queue.get_physical_device_video_capabilities_khr)(
+                physical_device,
+                &profile.info,
+                &mut caps,
+            )
+        };
+        match result {
+            vk::Result::SUCCESS => {}
+            vk::Result::ERROR_VIDEO_PROFILE_OPERATION_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PROFILE_FORMAT_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PICTURE_LAYOUT_NOT_SUPPORTED_KHR
+            | vk::Result::ERROR_VIDEO_PROFILE_CODEC_NOT_SUPPORTED_KHR => {
+                return Err(VideoError::DeviceUnsupported("Main profile encoding"));
+            }
+            err => return Err(err.into()),
+        }
+        let coded_extent = vk::Extent2D {
+            width: align(extent.width as u64, 16) as u32,
+            height: align(extent.height as u64, 16) as u32,
+        };
+        if coded_extent.width > caps.max_coded_extent.width
+            || coded_extent.height > caps.max_coded_extent.height
+            || coded_extent.width < caps.min_coded_extent.width
+            || coded_extent.height < caps.min_coded_extent.height
+        {
+            return Err(VideoError::DeviceUnsupported("encoding pictures this size"));
+        }
+        if caps.max_dpb_slots < DPB_SLOTS
+            || caps.max_active_reference_pictures < 1
+            || h264_caps.max_p_picture_l0_reference_count < 1
+        {
+            return Err(VideoError::DeviceUnsupported("P pictures"));
+        }
+        let rate_control_mode = match config.bitrate {
+            None => vk::VideoEncodeRateControlModeFlagsKHR::DEFAULT,
+            Some(_) => [
+                vk::VideoEncodeRateControlModeFlagsKHR::VBR,
+                vk::VideoEncodeRateControlModeFlagsKHR::CBR,
+            ]
+            .into_iter()
+            .find(|&mode| encode_caps.rate_control_modes.contains(mode))
+            .ok_or(VideoError::DeviceUnsupported("a set bitrate"))?,
+        };
+        let source_usage =
+            vk::ImageUsageFlags::VIDEO_ENCODE_SRC_KHR | vk::ImageUsageFlags::TRANSFER_DST;
+        let dpb_usage = vk::ImageUsageFlags::VIDEO_ENCODE_DPB_KHR;
+        for usage in [source_usage, dpb_usage] {
+            if !supports_format(&fns.queue, physical_device, &profile.list, usage)? {
+                return Err(VideoError::DeviceUnsupported(
+                    "4:2:0 pictures with two planes",
+                ));
+            }
+        }
+
+        let cabac =
+            h264_caps.std_syntax_flags & VIDEO_ENCODE_H264_STD_ENTROPY_CODING_MODE_FLAG_SET != 0;
+        let sps = sequence(extent, coded_extent, config.frame_rate);
+        let pps = picture(cabac);
+        let mut headers = h264::nal_unit(
+            NalHeader {
+                nal_ref_idc: 3,
+                nal_unit_type: h264::NAL_SPS,
+            },
+            &sps.write(),
+        );
+        headers.extend(h264::nal_unit(
+            NalHeader {
+                nal_ref_idc: 3,
+                nal_unit_type: h264::NAL_PPS,
+            },
+            &pps.write(&sps),
+        ));
+
+        let session_info = vk::VideoSessionCreateInfoKHR {
+            queue_family_index: encode_family,
+            p_video_profile: &profile.info,
+            picture_format: PICTURE_FORMAT,
+            max_coded_extent: coded_extent,
+            reference_picture_format: PICTURE_FORMAT,
+            max_dpb_slots: DPB_SLOTS,
+            max_active_reference_pictures: 1,
+            p_std_header_version: &caps.std_header_version,
+            ..Default::default()
+        };
+        let mut session = vk::VideoSessionKHR::null();
+        unsafe {
+            traced!(
+                "vkCreateVideoSessionKHR",
+                extent = coded_extent;
+                (fns.queue.create_video_session_khr)(
+                    device.handle(),
+                    &session_info,
+                    ptr::null(),
+                    &mut session,
+                )
+                .result()
+            )?
+        };
+        leaks::track(session, "video encode session");
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Nearest)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        );
+
+        let mut families = vec![queue_families.graphics, encode_family];
+        families.dedup();
+        let mut this = VideoEncoder {
+            fns: fns.clone(),
+            queue,
+            families,
+            extent,
+            coded_extent,
+            frame_format,
+            config,
+            rate_control_mode,
+            bitstream_alignment: caps
+                .min_bitstream_buffer_offset_alignment
+                .max(caps.min_bitstream_buffer_size_alignment)
+                .max(1),
+            headers,
+            session,
+            session_memory: Vec::new(),
+            parameters: vk::VideoSessionParametersKHR::null(),
+            source: PictureArray::NULL,
+            dpb: PictureArray::NULL,
+            query_pool: vk::QueryPool::null(),
+            command_pool: vk::CommandPool::null(),
+            set_layout: vk::DescriptorSetLayout::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            sampler: vk::Sampler::null(),
+            frames: Vec::new(),
+            lumas: Vec::new(),
+            chromas: Vec::new(),
+            sets: Vec::new(),
+            bitstreams: Vec::new(),
+            command_buffers: Vec::new(),
+            fences: Vec::new(),
+            converted: Vec::new(),
+            states: Vec::new(),
+            order: VecDeque::new(),
+            reset: true,
+            encoded: 0,
+            previous: None,
+        };
+        let result = sampler.map_err(VideoError::from).and_then(|sampler| {
+            this.sampler = sampler;
+            let code = read_spv(&mut Cursor::new(ENCODE_CONVERT_SPV))
+                .map_err(|err| VideoError::Compile(Box::new(err)))?;
+            unsafe {
+                this.create_objects(
+                    device,
+                    mem_props,
+                    &profile,
+                    encode_family,
+                    &sps,
+                    &pps,
+                    &code,
+                )
+            }
+        });
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        profile: &Profile,
+        encode_family: u32,
+        sps: &Sps,
+        pps: &Pps,
+        code: &[u32],
+    ) -> Result<(), VideoError> {
+        bind_session_memory(
+            &self.fns.queue,
+            device,
+            mem_props,
+            self.session,
+            &mut self.session_memory,
+        )?;
+        self.create_parameters(device, sps, pps)?;
+        let slots = self.config.frames_in_flight.max(1) as u32;
+        self.source = PictureArray::new(
+            device,
+            mem_props,
+            &profile.list,
+            self.coded_extent,
+            slots,
+            vk::ImageUsageFlags::VIDEO_ENCODE_SRC_KHR | vk::ImageUsageFlags::TRANSFER_DST,
+            &self.families,
+        )?;
+        self.dpb = PictureArray::new(
+            device,
+            mem_props,
+            &profile.list,
+            self.coded_extent,
+            DPB_SLOTS,
+            vk::ImageUsageFlags::VIDEO_ENCODE_DPB_KHR,
+            &[encode_family],
+        )?;
+
+        let feedback = QueryPoolVideoEncodeFeedbackCreateInfoKHR {
+            p_next: &profile.info as *const _ as *const c_void,
+            encode_feedback_flags: vk::VideoEncodeFeedbackFlagsKHR::BITSTREAM_BUFFER_OFFSET
+                | vk::VideoEncodeFeedbackFlagsKHR::BITSTREAM_BYTES_WRITTEN,
+            ..Default::default()
+        };
+        let query_info = vk::QueryPoolCreateInfo {
+            p_next: &feedback as *const _ as *const c_void,
+            query_type: vk::QueryType::VIDEO_ENCODE_FEEDBACK_KHR,
+            query_count: slots,
+            ..Default::default()
+        };
+        self.query_pool = device.create_query_pool(&query_info, None)?;
+        leaks::track(self.query_pool, "video encode feedback queries");
+
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(encode_family)
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        self.command_pool = device.create_command_pool(&pool_info, None)?;
+        leaks::track(self.command_pool, "video encode command pool");
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(slots);
+        self.command_buffers = device.allocate_command_buffers(&alloc_info)?;
+
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::STORAGE_IMAGE,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "video encode descriptor set layout");
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder().set_layouts(&[self.set_layout]),
+        )?;
+        leaks::track(self.pipeline_layout, "video encode pipeline layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: slots,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: slots,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: 2 * slots,
+            },
+        ];
+        self.descriptor_pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(slots)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.descriptor_pool, "video encode descriptor pool");
+        reflect::debug_validate(&[code], &[&bindings], &[], &[])?;
+        let module =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(code), None)?;
+        leaks::track(module, "video encode shader module");
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module)
+            .name(c"main");
+        let info = vk::ComputePipelineCreateInfo::builder()
+            .stage(*stage)
+            .layout(self.pipeline_layout);
+        let pipelines =
+            crate::pipeline::create_compute_pipelines(device, &[*info], "encode convert");
+        leaks::untrack(module);
+        device.destroy_shader_module(module, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+
+        let chroma_extent = vk::Extent2D {
+            width: self.coded_extent.width / 2,
+            height: self.coded_extent.height / 2,
+        };
+        let planes = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC;
+        let bitstream_size = align(
+            // A raw picture's size, more than any encoded one needs
+            self.coded_extent.width as u64 * self.coded_extent.height as u64 * 3 / 2,
+            self.bitstream_alignment,
+        );
+        for _ in 0..slots {
+            self.frames.push(Texture::empty(
+                device,
+                mem_props,
+                self.frame_format,
+                self.extent,
+                vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            )?);
+            self.lumas.push(Texture::empty(
+                device,
+                mem_props,
+                vk::Format::R8_UNORM,
+                self.coded_extent,
+                planes,
+            )?);
+            self.chromas.push(Texture::empty(
+                device,
+                mem_props,
+                vk::Format::R8G8_UNORM,
+                chroma_extent,
+                planes,
+            )?);
+            self.bitstreams.push(Bitstream::new(
+                device,
+                mem_props,
+                &profile.list,
+                bitstream_size,
+                vk::BufferUsageFlags::VIDEO_ENCODE_DST_KHR,
+            )?);
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            leaks::track(fence, "video encode fence");
+            self.fences.push(fence);
+            let semaphore = device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?;
+            leaks::track(semaphore, "video capture converted semaphore");
+            self.converted.push(semaphore);
+            self.states.push(SlotState::Idle);
+        }
+        self.sets = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.descriptor_pool)
+                .set_layouts(&vec![self.set_layout; slots as usize]),
+        )?;
+        for (index, &set) in self.sets.iter().enumerate() {
+            let image = |view, layout| {
+                [vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: view,
+                    image_layout: layout,
+                }]
+            };
+            let frame = image(
+                self.frames[index].view,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            );
+            let sampler = [vk::DescriptorImageInfo {
+                sampler: self.sampler,
+                image_view: vk::ImageView::null(),
+                image_layout: vk::ImageLayout::UNDEFINED,
+            }];
+            let luma = image(self.lumas[index].view, vk::ImageLayout::GENERAL);
+            let chroma = image(self.chromas[index].view, vk::ImageLayout::GENERAL);
+            let writes = [
+                (0, vk::DescriptorType::SAMPLED_IMAGE, &frame),
+                (1, vk::DescriptorType::SAMPLER, &sampler),
+                (2, vk::DescriptorType::STORAGE_IMAGE, &luma),
+                (3, vk::DescriptorType::STORAGE_IMAGE, &chroma),
+            ]
+            .map(|(binding, ty, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(binding)
+                    .descriptor_type(ty)
+                    .image_info(info)
+                    .build()
+            });
+            device.update_descriptor_sets(&writes, &[]);
+        }
+        Ok(())
+    }
+
+    /// Session parameters holding the one SPS and PPS the stream uses
+    ///
+    /// The implementation may override parts of them it can't encode with, the headers are then
+    /// replaced by the ones it writes.
+    #[track_caller]
+    unsafe fn create_parameters(&mut self, device: &Device, sps: &Sps, pps: &Pps) -> VkResult<()> {
+        let std_sps = sps.to_std(None);
+        let std_pps = pps.to_std(None);
+        let add_info = VideoEncodeH264SessionParametersAddInfoKHR {
+            std_sps_count: 1,
+            p_std_sp_ss: &std_sps,
+            std_pps_count: 1,
+            p_std_pp_ss: &std_pps,
+            ..Default::default()
+        };
+        let h264_info = VideoEncodeH264SessionParametersCreateInfoKHR {
+            max_std_sps_count: 1,
+            max_std_pps_count: 1,
+            p_parameters_add_info: &add_info,
+            ..Default::default()
+        };
+        let info = vk::VideoSessionParametersCreateInfoKHR {
+            p_next: &h264_info as *const _ as *const c_void,
+            video_session: self.session,
+            ..Default::default()
+        };
+        traced!(
+            "vkCreateVideoSessionParametersKHR",
+            session = self.session;
+            (self.fns.queue.create_video_session_parameters_khr)(
+                device.handle(),
+                &info,
+                ptr::null(),
+                &mut self.parameters,
+            )
+            .result()
+        )?;
+        leaks::track(self.parameters, "video encode session parameters");
+
+        let h264_get = VideoEncodeH264SessionParametersGetInfoKHR {
+            write_std_sps: vk::TRUE,
+            write_std_pps: vk::TRUE,
+            ..Default::default()
+        };
+        let get = VideoEncodeSessionParametersGetInfoKHR {
+            p_next: &h264_get as *const _ as *const c_void,
+            video_session_parameters: self.parameters,
+            ..Default::default()
+        };
+        let mut h264_feedback = VideoEncodeH264SessionParametersFeedbackInfoKHR::default();
+        let mut feedback = VideoEncodeSessionParametersFeedbackInfoKHR {
+            p_next: &mut h264_feedback as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        let get_parameters = self.fns.encode.get_encoded_video_session_parameters_khr;
+        let mut size = 0;
+        traced!(
+            "vkGetEncodedVideoSessionParametersKHR",
+            parameters = self.parameters;
+            get_parameters(
+                device.handle(),
+                &get,
+                &mut feedback,
+                &mut size,
+                ptr::null_mut(),
+            )
+            .result()
+        )?;
+        if feedback.has_overrides == vk::FALSE {
+            return Ok(());
+        }
+        let mut headers = vec![0; size];
+        traced!(
+            "vkGetEncodedVideoSessionParametersKHR",
+            parameters = self.parameters,
+            size = size;
+            get_parameters(
+                device.handle(),
+                &get,
+                ptr::null_mut(),
+                &mut size,
+                headers.as_mut_ptr() as *mut c_void,
+            )
+            .result()
+        )?;
+        headers.truncate(size);
+        self.headers = headers;
+        Ok(())
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Records converting `image` for encoding into `cmd`, after the last pass writing it
+    ///
+    /// The image is left in `layout`, usually `PRESENT_SRC_KHR`, which it must be in already.
+    /// Gives the semaphore the submission of `cmd` has to signal, or `None` when every slot is
+    /// still busy and the frame is skipped. Call [`VideoEncoder::submit`] after submitting `cmd`.
+    pub fn record_capture(
+        &mut self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        image: vk::Image,
+        layout: vk::ImageLayout,
+    ) -> Option<vk::Semaphore> {
+        let slot = self
+            .states
+            .iter()
+            .position(|&state| state == SlotState::Idle)?;
+        let range = |layer| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: layer,
+            layer_count: 1,
+        };
+        let barrier = |image, layer, old_layout, new_layout| {
+            vk::ImageMemoryBarrier2::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range(layer))
+        };
+        let frame = &self.frames[slot];
+        let luma = &self.lumas[slot];
+        let chroma = &self.chromas[slot];
+        let layers = |aspect_mask, layer| vk::ImageSubresourceLayers {
+            aspect_mask,
+            mip_level: 0,
+            base_array_layer: layer,
+            layer_count: 1,
+        };
+        let extent3d = |extent: vk::Extent2D| vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        unsafe {
+            let to_copy = [
+                // Whatever pass wrote the image last
+                barrier(image, 0, layout, vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                    .build(),
+                barrier(
+                    frame.image,
+                    0,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .build(),
+            ];
+            device.cmd_pipeline_barrier2(
+                cmd,
+                &vk::DependencyInfo::builder().image_memory_barriers(&to_copy),
+            );
+            let region = vk::ImageCopy {
+                src_subresource: layers(vk::ImageAspectFlags::COLOR, 0),
+                src_offset: vk::Offset3D::default(),
+                dst_subresource: layers(vk::ImageAspectFlags::COLOR, 0),
+                dst_offset: vk::Offset3D::default(),
+                extent: extent3d(self.extent),
+            };
+            device.cmd_copy_image(
+                cmd,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                frame.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+
+            let to_convert = [
+                barrier(image, 0, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, layout)
+                    .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .build(),
+                barrier(
+                    frame.image,
+                    0,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags2::SHADER_SAMPLED_READ)
+                .build(),
+                barrier(
+                    luma.image,
+                    0,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::GENERAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                .build(),
+                barrier(
+                    chroma.image,
+                    0,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::GENERAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .dst_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                .build(),
+            ];
+            device.cmd_pipeline_barrier2(
+                cmd,
+                &vk::DependencyInfo::builder().image_memory_barriers(&to_convert),
+            );
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[self.sets[slot]],
+                &[],
+            );
+            device.cmd_dispatch(
+                cmd,
+                (self.coded_extent.width / 2).div_ceil(8),
+                (self.coded_extent.height / 2).div_ceil(8),
+                1,
+            );
+
+            let to_source = [
+                barrier(
+                    luma.image,
+                    0,
+                    vk::ImageLayout::GENERAL,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                .build(),
+                barrier(
+                    chroma.image,
+                    0,
+                    vk::ImageLayout::GENERAL,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                .build(),
+                // The slot's last encode finished before it went idle
+                barrier(
+                    self.source.image,
+                    slot as u32,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                )
+                .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .build(),
+            ];
+            device.cmd_pipeline_barrier2(
+                cmd,
+                &vk::DependencyInfo::builder().image_memory_barriers(&to_source),
+            );
+            let planes = [
+                (luma, vk::ImageAspectFlags::PLANE_0, self.coded_extent),
+                (
+                    chroma,
+                    vk::ImageAspectFlags::PLANE_1,
+                    vk::Extent2D {
+                        width: self.coded_extent.width / 2,
+                        height: self.coded_extent.height / 2,
+                    },
+                ),
+            ];
+            for (plane, aspect, extent) in planes {
+                let region = vk::ImageCopy {
+                    src_subresource: layers(vk::ImageAspectFlags::COLOR, 0),
+                    src_offset: vk::Offset3D::default(),
+                    dst_subresource: layers(aspect, slot as u32),
+                    dst_offset: vk::Offset3D::default(),
+                    extent: extent3d(extent),
+                };
+                device.cmd_copy_image(
+                    cmd,
+                    plane.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    self.source.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                );
+            }
+            // Made visible to the encode queue by the semaphore
+            let to_encode = barrier(
+                self.source.image,
+                slot as u32,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::VIDEO_ENCODE_SRC_KHR,
+            )
+            .src_stage_mask(vk::PipelineStageFlags2::COPY)
+            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE);
+            device.cmd_pipeline_barrier2(
+                cmd,
+                &vk::DependencyInfo::builder().image_memory_barriers(&[*to_encode]),
+            );
+        }
+        self.states[slot] = SlotState::Recorded;
+        self.order.push_back(slot);
+        Some(self.converted[slot])
+    }
+
+    /// Encodes the frames recorded since the last call, once their command buffers are submitted
+    pub fn submit(&mut self, device: &Device) -> Result<(), VideoError> {
+        let recorded: Vec<usize> = self
+            .order
+            .iter()
+            .copied()
+            .filter(|&slot| self.states[slot] == SlotState::Recorded)
+            .collect();
+        for slot in recorded {
+            let idr = unsafe { self.encode(device, slot)? };
+            self.states[slot] = SlotState::Encoding { idr };
+        }
+        Ok(())
+    }
+
+    /// Records and submits the encode of `slot`'s picture, giving whether it's an IDR picture
+    unsafe fn encode(&mut self, device: &Device, slot: usize) -> Result<bool, VideoError> {
+        let since_idr = (self.encoded % self.config.idr_period.max(1) as u64) as u32;
+        let idr = since_idr == 0;
+        let max_frame_num = 1 << (LOG2_MAX_FRAME_NUM_MINUS4 + 4);
+        let frame_num = since_idr % max_frame_num;
+        // pic_order_cnt_type 2, the order count follows decoding order
+        let poc = 2 * since_idr as i32;
+        let dpb_slot = (self.encoded % DPB_SLOTS as u64) as u32;
+        let picture_type = if idr {
+            StdVideoH264PictureType_STD_VIDEO_H264_PICTURE_TYPE_IDR
+        } else {
+            StdVideoH264PictureType_STD_VIDEO_H264_PICTURE_TYPE_P
+        };
+        let reference = self.previous.filter(|_| !idr);
+
+        let reference_info = |picture_type, frame_num, poc| StdVideoEncodeH264ReferenceInfo {
+            flags: 0,
+            primary_pic_type: picture_type,
+            FrameNum: frame_num,
+            PicOrderCnt: poc,
+            long_term_pic_num: 0,
+            long_term_frame_idx: 0,
+            temporal_id: 0,
+        };
+        let setup_std = reference_info(picture_type, frame_num, poc);
+        let reference_std = reference.map(|previous| {
+            reference_info(previous.picture_type, previous.frame_num, previous.poc)
+        });
+        let setup_dpb = VideoEncodeH264DpbSlotInfoKHR {
+            p_std_reference_info: &setup_std,
+            ..Default::default()
+        };
+        let reference_dpb = reference_std
+            .as_ref()
+            .map(|info| VideoEncodeH264DpbSlotInfoKHR {
+                p_std_reference_info: info,
+                ..Default::default()
+            });
+        let setup_resource = self.dpb.resource(dpb_slot as usize, self.coded_extent);
+        let reference_resource = reference.map(|previous| {
+            self.dpb
+                .resource(previous.dpb_slot as usize, self.coded_extent)
+        });
+        let setup = vk::VideoReferenceSlotInfoKHR {
+            p_next: &setup_dpb as *const _ as *const c_void,
+            slot_index: dpb_slot as i32,
+            p_picture_resource: &setup_resource,
+            ..Default::default()
+        };
+        let mut slots: Vec<vk::VideoReferenceSlotInfoKHR> = reference
+            .iter()
+            .zip(&reference_dpb)
+            .zip(&reference_resource)
+            .map(
+                |((previous, dpb_info), resource)| vk::VideoReferenceSlotInfoKHR {
+                    p_next: dpb_info as *const _ as *const c_void,
+                    slot_index: previous.dpb_slot as i32,
+                    p_picture_resource: resource,
+                    ..Default::default()
+                },
+            )
+            .collect();
+        let reference_count = slots.len();
+        // The picture being set up is bound too, as not yet active
+        slots.push(vk::VideoReferenceSlotInfoKHR {
+            slot_index: -1,
+            ..setup
+        });
+
+        let mut list0 = [STD_VIDEO_H264_NO_REFERENCE_PICTURE; 32];
+        if let Some(previous) = reference {
+            list0[0] = previous.dpb_slot as u8;
+        }
+        let lists = StdVideoEncodeH264ReferenceListsInfo {
+            flags: 0,
+            num_ref_idx_l0_active_minus1: 0,
+            num_ref_idx_l1_active_minus1: 0,
+            RefPicList0: list0,
+            RefPicList1: [STD_VIDEO_H264_NO_REFERENCE_PICTURE; 32],
+            refList0ModOpCount: 0,
+            refList1ModOpCount: 0,
+            refPicMarkingOpCount: 0,
+            reserved1: [0; 7],
+            pRefList0ModOperations: ptr::null(),
+            pRefList1ModOperations: ptr::null(),
+            pRefPicMarkingOperations: ptr::null(),
+        };
+        let slice_header = StdVideoEncodeH264SliceHeader {
+            flags: 0,
+            first_mb_in_slice: 0,
+            slice_type: if idr {
+                StdVideoH264SliceType_STD_VIDEO_H264_SLICE_TYPE_I
+            } else {
+                StdVideoH264SliceType_STD_VIDEO_H264_SLICE_TYPE_P
+            },
+            slice_alpha_c0_offset_div2: 0,
+            slice_beta_offset_div2: 0,
+            slice_qp_delta: 0,
+            reserved1: 0,
+            cabac_init_idc: 0,
+            // disable_deblocking_filter_idc 0, the filter runs
+            disable_deblocking_filter_idc: 0,
+            pWeightTable: ptr::null(),
+        };
+        let slice = VideoEncodeH264NaluSliceInfoKHR {
+            p_std_slice_header: &slice_header,
+            ..Default::default()
+        };
+        let mut picture_flags = STD_VIDEO_ENCODE_H264_PICTURE_IS_REFERENCE;
+        if idr {
+            picture_flags |= STD_VIDEO_ENCODE_H264_PICTURE_IDR;
+        }
+        let std_picture = StdVideoEncodeH264PictureInfo {
+            flags: picture_flags,
+            seq_parameter_set_id: 0,
+            pic_parameter_set_id: 0,
+            idr_pic_id: (self.encoded / self.config.idr_period.max(1) as u64 % 2) as u16,
+            primary_pic_type: picture_type,
+            frame_num,
+            PicOrderCnt: poc,
+            temporal_id: 0,
+            reserved1: [0; 3],
+            pRefLists: &lists,
+        };
+        let h264_picture = VideoEncodeH264PictureInfoKHR {
+            nalu_slice_entry_count: 1,
+            p_nalu_slice_entries: &slice,
+            p_std_picture_info: &std_picture,
+            ..Default::default()
+        };
+        let bitstream = &self.bitstreams[slot];
+        let encode_info = VideoEncodeInfoKHR {
+            p_next: &h264_picture as *const _ as *const c_void,
+            dst_buffer: bitstream.buffer,
+            dst_buffer_offset: 0,
+            dst_buffer_range: bitstream.size,
+            src_picture_resource: self.source.resource(slot, self.coded_extent),
+            p_setup_reference_slot: &setup,
+            reference_slot_count: reference_count as u32,
+            p_reference_slots: slots.as_ptr(),
+            ..Default::default()
+        };
+
+        // Rate control is session state, every begin restates what the reset set
+        let layer = VideoEncodeRateControlLayerInfoKHR {
+            average_bitrate: self.config.bitrate.unwrap_or_default(),
+            max_bitrate: if self.rate_control_mode == vk::VideoEncodeRateControlModeFlagsKHR::CBR {
+                self.config.bitrate.unwrap_or_default()
+            } else {
+                self.config.bitrate.unwrap_or_default() * 2
+            },
+            frame_rate_numerator: self.config.frame_rate,
+            frame_rate_denominator: 1,
+            ..Default::default()
+        };
+        let h264_rate_control = VideoEncodeH264RateControlInfoKHR {
+            flags: VIDEO_ENCODE_H264_RATE_CONTROL_REGULAR_GOP
+                | VIDEO_ENCODE_H264_RATE_CONTROL_REFERENCE_PATTERN_FLAT,
+            gop_frame_count: self.config.idr_period,
+            idr_period: self.config.idr_period,
+            consecutive_b_frame_count: 0,
+            temporal_layer_count: 1,
+            ..Default::default()
+        };
+        let rate_control = VideoEncodeRateControlInfoKHR {
+            p_next: &h264_rate_control as *const _ as *const c_void,
+            rate_control_mode: self.rate_control_mode,
+            layer_count: 1,
+            p_layers: &layer,
+            virtual_buffer_size_in_ms: 1000,
+            initial_virtual_buffer_size_in_ms: 500,
+            ..Default::default()
+        };
+        let rate_control_next = if self.config.bitrate.is_some() {
+            &rate_control as *const _ as *const c_void
+        } else {
+            ptr::null()
+        };
+
+        let cmd = self.command_buffers[slot];
+        device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+        device.begin_command_buffer(
+            cmd,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+        device.cmd_reset_query_pool(cmd, self.query_pool, slot as u32, 1);
+        // The setup slot's old contents are discarded, the reference only needs the last write
+        let dpb_layer = |layer, old_layout| {
+            vk::ImageMemoryBarrier2::builder()
+                .src_stage_mask(vk::PipelineStageFlags2::VIDEO_ENCODE_KHR)
+                .src_access_mask(vk::AccessFlags2::VIDEO_ENCODE_WRITE_KHR)
+                .dst_stage_mask(vk::PipelineStageFlags2::VIDEO_ENCODE_KHR)
+                .dst_access_mask(
+                    vk::AccessFlags2::VIDEO_ENCODE_READ_KHR
+                        | vk::AccessFlags2::VIDEO_ENCODE_WRITE_KHR,
+                )
+                .old_layout(old_layout)
+                .new_layout(vk::ImageLayout::VIDEO_ENCODE_DPB_KHR)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(self.dpb.image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: layer,
+                    layer_count: 1,
+                })
+                .build()
+        };
+        let mut barriers = vec![dpb_layer(dpb_slot, vk::ImageLayout::UNDEFINED)];
+        barriers.extend(
+            reference.map(|previous| {
+                dpb_layer(previous.dpb_slot, vk::ImageLayout::VIDEO_ENCODE_DPB_KHR)
+            }),
+        );
+        device.cmd_pipeline_barrier2(
+            cmd,
+            &vk::DependencyInfo::builder().image_memory_barriers(&barriers),
+        );
+        let begin = vk::VideoBeginCodingInfoKHR {
+            p_next: if self.reset {
+                ptr::null()
+            } else {
+                rate_control_next
+            },
+            video_session: self.session,
+            video_session_parameters: self.parameters,
+            reference_slot_count: slots.len() as u32,
+            p_reference_slots: slots.as_ptr(),
+            ..Default::default()
+        };
+        traced!(
+            "vkCmdBeginVideoCodingKHR",
+            session = self.session,
+            slots = slots.len();
+            (self.fns.queue.cmd_begin_video_coding_khr)(cmd, &begin)
+        );
+        if self.reset {
+            let mut flags = vk::VideoCodingControlFlagsKHR::RESET;
+            if !rate_control_next.is_null() {
+                flags |= VIDEO_CODING_CONTROL_ENCODE_RATE_CONTROL;
+            }
+            let control = vk::VideoCodingControlInfoKHR {
+                p_next: rate_control_next,
+                flags,
+                ..Default::default()
+            };
+            traced!(
+                "vkCmdControlVideoCodingKHR",
+                flags = control.flags;
+                (self.fns.queue.cmd_control_video_coding_khr)(cmd, &control)
+            );
+            self.reset = false;
+        }
+        device.cmd_begin_query(
+            cmd,
+            self.query_pool,
+            slot as u32,
+            vk::QueryControlFlags::empty(),
+        );
+        traced!(
+            "vkCmdEncodeVideoKHR",
+            dst = encode_info.dst_buffer,
+            dst_range = encode_info.dst_buffer_range;
+            (self.fns.encode.cmd_encode_video_khr)(cmd, &encode_info)
+        );
+        device.cmd_end_query(cmd, self.query_pool, slot as u32);
+        traced!(
+            "vkCmdEndVideoCodingKHR";
+            (self.fns.queue.cmd_end_video_coding_khr)(cmd, &vk::VideoEndCodingInfoKHR::default())
+        );
+        device.end_command_buffer(cmd)?;
+
+        let wait = vk::SemaphoreSubmitInfo::builder()
+            .semaphore(self.converted[slot])
+            .stage_mask(vk::PipelineStageFlags2::VIDEO_ENCODE_KHR);
+        let cmd_info = vk::CommandBufferSubmitInfo::builder().command_buffer(cmd);
+        let submit = vk::SubmitInfo2::builder()
+            .wait_semaphore_infos(std::slice::from_ref(&wait))
+            .command_buffer_infos(std::slice::from_ref(&cmd_info));
+        device.reset_fences(&[self.fences[slot]])?;
+        traced!(
+            "vkQueueSubmit2",
+            queue = self.queue,
+            slot = slot;
+            device.queue_submit2(self.queue, &[*submit], self.fences[slot])
+        )?;
+
+        self.encoded += 1;
+        self.previous = Some(Previous {
+            dpb_slot,
+            picture_type,
+            frame_num,
+            poc,
+        });
+        Ok(idr)
+    }
+
+    /// Appends the pictures finished encoding to `out` in order, giving how many
+    ///
+    /// Call once a frame, slots only free up here.
+    pub fn poll(&mut self, device: &Device, out: &mut Vec<u8>) -> Result<usize, VideoError> {
+        self.collect(device, out, false)
+    }
+
+    /// Encodes the frames still recorded, waits for every encode and appends the rest of the
+    /// stream to `out`
+    ///
+    /// The command buffers of every frame recorded have to be submitted, the encodes wait on
+    /// their semaphores, which would otherwise stay signalled for the slot's next frame.
+    pub fn finish(&mut self, device: &Device, out: &mut Vec<u8>) -> Result<usize, VideoError> {
+        self.submit(device)?;
+        self.collect(device, out, true)
+    }
+
+    fn collect(
+        &mut self,
+        device: &Device,
+        out: &mut Vec<u8>,
+        wait: bool,
+    ) -> Result<usize, VideoError> {
+        let mut written = 0;
+        while let Some(&slot) = self.order.front() {
+            let SlotState::Encoding { idr } = self.states[slot] else {
+                break;
+            };
+            let fence = self.fences[slot];
+            unsafe {
+                if wait {
+                    device.wait_for_fences(&[fence], true, u64::MAX)?;
+                } else if !device.get_fence_status(fence)? {
+                    break;
+                }
+                // Offset and size of what was written into the bitstream buffer
+                let mut feedback = [[0u32; 2]];
+                device.get_query_pool_results(
+                    self.query_pool,
+                    slot as u32,
+                    1,
+                    &mut feedback,
+                    vk::QueryResultFlags::WAIT,
+                )?;
+                let [offset, size] = feedback[0];
+                let bitstream = &self.bitstreams[slot];
+                let end = (offset as vk::DeviceSize + size as vk::DeviceSize).min(bitstream.size);
+                let bytes = std::slice::from_raw_parts(
+                    bitstream.mapped.add(offset as usize),
+                    (end - offset as vk::DeviceSize) as usize,
+                );
+                if idr {
+                    out.extend_from_slice(&self.headers);
+                }
+                out.extend_from_slice(bytes);
+            }
+            self.states[slot] = SlotState::Idle;
+            self.order.pop_front();
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with every capture and encode. Slots recorded but not submitted
+    /// leave their semaphores signalled, wait for the device to go idle first.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        for texture in self
+            .frames
+            .drain(..)
+            .chain(self.lumas.drain(..))
+            .chain(self.chromas.drain(..))
+        {
+            texture.destroy(device);
+        }
+        for bitstream in self.bitstreams.drain(..) {
+            bitstream.destroy(device);
+        }
+        for fence in self.fences.drain(..) {
+            leaks::untrack(fence);
+            device.destroy_fence(fence, None);
+        }
+        for semaphore in self.converted.drain(..) {
+            leaks::untrack(semaphore);
+            device.destroy_semaphore(semaphore, None);
+        }
+        self.command_buffers.clear();
+        self.sets.clear();
+        if self.command_pool != vk::CommandPool::null() {
+            leaks::untrack(self.command_pool);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+        if self.query_pool != vk::QueryPool::null() {
+            leaks::untrack(self.query_pool);
+            device.destroy_query_pool(self.query_pool, None);
+        }
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.descriptor_pool);
+        device.destroy_descriptor_pool(self.descriptor_pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+        self.source.destroy(device);
+        self.dpb.destroy(device);
+        if self.parameters != vk::VideoSessionParametersKHR::null() {
+            leaks::untrack(self.parameters);
+            (self.fns.queue.destroy_video_session_parameters_khr)(
+                device.handle(),
+                self.parameters,
+                ptr::null(),
+            );
+        }
+        leaks::untrack(self.session);
+        (self.fns.queue.destroy_video_session_khr)(device.handle(), self.session, ptr::null());
+        for memory in self.session_memory.drain(..) {
+            leaks::untrack(memory);
+            device.free_memory(memory, None);
+        }
+    }
+}
+
+/// The sequence written: Main profile, one reference frame, order counts following decoding
+fn sequence(extent: vk::Extent2D, coded_extent: vk::Extent2D, frame_rate: u32) -> Sps {
+    let width_in_mbs = coded_extent.width / 16;
+    let height_in_mbs = coded_extent.height / 16;
+    // Cropping counts 4:2:0 chroma samples, an odd width or height loses its last row of pixels
+    let crop_right = (coded_extent.width - extent.width) / 2;
+    let crop_bottom = (coded_extent.height - extent.height) / 2;
+    Sps {
+        profile_idc: PROFILE_IDC,
+        // constraint_set1_flag, the stream keeps to Main
+        constraint_flags: 0b0100_0000,
+        level_idc: level_for(width_in_mbs * height_in_mbs, frame_rate),
+        id: 0,
+        chroma_format_idc: 1,
+        separate_colour_plane: false,
+        bit_depth_luma_minus8: 0,
+        bit_depth_chroma_minus8: 0,
+        qpprime_y_zero_transform_bypass: false,
+        scaling_lists: None,
+        log2_max_frame_num_minus4: LOG2_MAX_FRAME_NUM_MINUS4,
+        pic_order_cnt_type: 2,
+        log2_max_pic_order_cnt_lsb_minus4: 0,
+        delta_pic_order_always_zero: false,
+        offset_for_non_ref_pic: 0,
+        offset_for_top_to_bottom_field: 0,
+        offsets_for_ref_frame: Vec::new(),
+        max_num_ref_frames: 1,
+        gaps_in_frame_num_allowed: false,
+        pic_width_in_mbs_minus1: width_in_mbs - 1,
+        pic_height_in_map_units_minus1: height_in_mbs - 1,
+        frame_mbs_only: true,
+        mb_adaptive_frame_field: false,
+        direct_8x8_inference: true,
+        frame_crop: (crop_right > 0 || crop_bottom > 0).then_some([0, crop_right, 0, crop_bottom]),
+        vui: Vui {
+            full_range: false,
+            matrix_coefficients: 1,
+            frame_rate: Some(frame_rate as f64),
+            max_num_reorder_frames: Some(0),
+        },
+    }
+}
+
+fn picture(cabac: bool) -> Pps {
+    Pps {
+        id: 0,
+        sps_id: 0,
+        entropy_coding_mode: cabac,
+        bottom_field_pic_order_in_frame_present: false,
+        num_ref_idx_l0_default_active_minus1: 0,
+        num_ref_idx_l1_default_active_minus1: 0,
+        weighted_pred: false,
+        weighted_bipred_idc: 0,
+        pic_init_qp_minus26: 0,
+        pic_init_qs_minus26: 0,
+        chroma_qp_index_offset: 0,
+        deblocking_filter_control_present: true,
+        constrained_intra_pred: false,
+        redundant_pic_cnt_present: false,
+        transform_8x8_mode: false,
+        scaling_lists: None,
+        second_chroma_qp_index_offset: 0,
+    }
+}
+
+/// The lowest level, from table A-1, whose frame size and macroblock rate fit
+fn level_for(frame_mbs: u32, frame_rate: u32) -> u8 {
+    // level_idc, MaxFS and MaxMBPS, from 3 since smaller levels cap bitrates too low
+    const LEVELS: [(u8, u32, u32); 11] = [
+        (30, 1620, 40500),
+        (31, 3600, 108000),
+        (32, 5120, 216000),
+        (41, 8192, 245760),
+        (42, 8704, 522240),
+        (50, 22080, 589824),
+        (51, 36864, 983040),
+        (52, 36864, 2073600),
+        (60, 139264, 4177920),
+        (61, 139264, 8355840),
+        (62, 139264, 16711680),
+    ];
+    let rate = frame_mbs as u64 * frame_rate as u64;
+    LEVELS
+        .iter()
+        .find(|&&(_, max_fs, max_mbps)| frame_mbs <= max_fs && rate <= max_mbps as u64)
+        .map_or(62, |&(level, _, _)| level)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn headers_parse_back_cropped_to_the_frame() {
+        let extent = vk::Extent2D {
+            width: 1918,
+            height: 1080,
+        };
+        let coded_extent = vk::Extent2D {
+            width: 1920,
+            height: 1088,
+        };
+        let sps = sequence(extent, coded_extent, 60);
+        let pps = picture(true);
+        let mut stream = h264::nal_unit(
+            NalHeader {
+                nal_ref_idc: 3,
+                nal_unit_type: h264::NAL_SPS,
+            },
+            &sps.write(),
+        );
+        stream.extend(h264::nal_unit(
+            NalHeader {
+                nal_ref_idc: 3,
+                nal_unit_type: h264::NAL_PPS,
+            },
+            &pps.write(&sps),
+        ));
+
+        let nals = h264::nal_units(&stream);
+        assert_eq!(nals.len(), 2);
+        let parsed_sps = Sps::parse(&h264::rbsp(nals[0])).unwrap();
+        assert_eq!(parsed_sps, sps);
+        assert_eq!(parsed_sps.coded_extent(), (1920, 1088));
+        assert_eq!(parsed_sps.display_rect().extent, extent);
+        assert_eq!(parsed_sps.vui.frame_rate, Some(60.0));
+        // 8160 macroblocks 60 times a second
+        assert_eq!(parsed_sps.level_idc, 42);
+        let sets = BTreeMap::from([(sps.id, parsed_sps)]);
+        assert_eq!(Pps::parse(&h264::rbsp(nals[1]), &sets).unwrap(), pps);
+    }
+
+    #[cfg(feature = "naga")]
+    #[test]
+    fn shipped_spirv_is_current() {
+        use crate::shader::{
+            runtime::{NagaCompiler, SourceLanguage},
+            ShaderCompiler,
+        };
+
+        let compiled = NagaCompiler::new(SourceLanguage::Glsl)
+            .compile(ENCODE_CONVERT_GLSL, vk::ShaderStageFlags::COMPUTE)
+            .expect("shader compiles");
+        assert_eq!(
+            compiled,
+            read_spv(&mut Cursor::new(ENCODE_CONVERT_SPV)).unwrap(),
+            "SPIR-V is stale, recompile src/video/shaders"
+        );
+    }
+}
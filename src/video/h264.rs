@@ -0,0 +1,1439 @@
+//! Parsing the parts of an H.264 Annex B stream the decoder needs, and writing parameter sets
+//!
+//! Vulkan Video decodes slice data but leaves the headers to the application: parameter sets go
+//! into the session parameters, and picture order counts and reference marking come from slice
+//! headers. Only what those need is read, the slice header stops after `dec_ref_pic_marking`.
+//! The encoder writes slices itself but not parameter sets, [`Sps::write`] and [`Pps::write`]
+//! code those. Annex B framing and [`BitReader`] serve [`super::h265`] as well.
+
+use std::collections::BTreeMap;
+
+use ash::vk::{
+    self,
+    native::{
+        StdVideoH264PictureParameterSet, StdVideoH264PpsFlags, StdVideoH264ScalingLists,
+        StdVideoH264SequenceParameterSet, StdVideoH264SpsFlags,
+    },
+};
+
+use super::VideoError;
+
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_IDR_SLICE: u8 = 5;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+
+/// Profiles whose SPS carries chroma format, bit depths and scaling matrices
+const HIGH_PROFILES: [u8; 12] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+/// Splits an Annex B stream on its start codes, giving each NAL unit without them
+pub fn nal_units(stream: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= stream.len() {
+        if stream[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &start)| {
+            let mut end = starts.get(n + 1).map_or(stream.len(), |&next| next - 3);
+            // Trailing zeros belong to the next four byte start code
+            while end > start && stream[end - 1] == 0 {
+                end -= 1;
+            }
+            &stream[start..end]
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalHeader {
+    pub nal_ref_idc: u8,
+    pub nal_unit_type: u8,
+}
+
+impl NalHeader {
+    pub fn parse(nal: &[u8]) -> Result<Self, VideoError> {
+        let &byte = nal.first().ok_or(VideoError::Truncated)?;
+        Ok(NalHeader {
+            nal_ref_idc: (byte >> 5) & 3,
+            nal_unit_type: byte & 0x1f,
+        })
+    }
+}
+
+/// The payload of `nal` after its header, with emulation prevention bytes removed
+pub fn rbsp(nal: &[u8]) -> Vec<u8> {
+    unescape(nal.get(1..).unwrap_or_default())
+}
+
+/// `payload` with emulation prevention bytes removed, whatever the length of the NAL header
+pub(super) fn unescape(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len());
+    let mut zeros = 0;
+    for &byte in payload {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// `rbsp` as an Annex B NAL unit, with a start code and emulation prevention bytes
+pub fn nal_unit(header: NalHeader, rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rbsp.len() + rbsp.len() / 64 + 5);
+    out.extend([0, 0, 0, 1, header.nal_ref_idc << 5 | header.nal_unit_type]);
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            out.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+    out
+}
+
+/// Reads bits and Exp-Golomb codes, most significant bit first
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Bits read so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn flag(&mut self) -> Result<bool, VideoError> {
+        let byte = self.data.get(self.pos / 8).ok_or(VideoError::Truncated)?;
+        let bit = byte >> (7 - self.pos % 8) & 1;
+        self.pos += 1;
+        Ok(bit == 1)
+    }
+
+    pub fn bits(&mut self, count: u32) -> Result<u32, VideoError> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.flag()? as u32;
+        }
+        Ok(value)
+    }
+
+    /// `ue(v)`
+    pub fn ue(&mut self) -> Result<u32, VideoError> {
+        let mut zeros = 0;
+        while !self.flag()? {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(VideoError::Malformed("Exp-Golomb code longer than 32 bits"));
+            }
+        }
+        Ok(((1u64 << zeros) - 1 + self.bits(zeros)? as u64) as u32)
+    }
+
+    /// `se(v)`
+    pub fn se(&mut self) -> Result<i32, VideoError> {
+        let code = self.ue()? as i64;
+        Ok(if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -(code / 2)
+        } as i32)
+    }
+
+    /// Whether anything but the stop bit and its alignment zeros is left
+    pub fn more_rbsp_data(&self) -> bool {
+        let Some(last) = self.data.iter().rposition(|&byte| byte != 0) else {
+            return false;
+        };
+        let stop_bit = last * 8 + 7 - self.data[last].trailing_zeros() as usize;
+        self.pos < stop_bit
+    }
+}
+
+/// Writes bits and Exp-Golomb codes, most significant bit first
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter::default()
+    }
+
+    pub fn flag(&mut self, value: bool) {
+        if self.pos.is_multiple_of(8) {
+            self.data.push(0);
+        }
+        *self.data.last_mut().unwrap() |= (value as u8) << (7 - self.pos % 8);
+        self.pos += 1;
+    }
+
+    pub fn bits(&mut self, count: u32, value: u32) {
+        for bit in (0..count).rev() {
+            self.flag(value >> bit & 1 == 1);
+        }
+    }
+
+    /// `ue(v)`
+    pub fn ue(&mut self, value: u32) {
+        let code = value as u64 + 1;
+        let len = 64 - code.leading_zeros();
+        for bit in (0..2 * len - 1).rev() {
+            self.flag(bit < len && code >> bit & 1 == 1);
+        }
+    }
+
+    /// `se(v)`
+    pub fn se(&mut self, value: i32) {
+        let value = value as i64;
+        self.ue(if value > 0 { 2 * value - 1 } else { -2 * value } as u32);
+    }
+
+    /// The RBSP written, ended with the stop bit and alignment zeros
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flag(true);
+        self.data
+    }
+}
+
+/// Scaling matrices as coded, `Default` meaning a flat or fallback list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalingLists {
+    pub present_mask: u16,
+    pub use_default_mask: u16,
+    pub lists_4x4: [[u8; 16]; 6],
+    pub lists_8x8: [[u8; 64]; 6],
+}
+
+impl ScalingLists {
+    fn parse(reader: &mut BitReader, count: usize) -> Result<Self, VideoError> {
+        let mut lists = ScalingLists {
+            present_mask: 0,
+            use_default_mask: 0,
+            lists_4x4: [[16; 16]; 6],
+            lists_8x8: [[16; 64]; 6],
+        };
+        for i in 0..count {
+            if !reader.flag()? {
+                continue;
+            }
+            lists.present_mask |= 1 << i;
+            let list: &mut [u8] = if i < 6 {
+                &mut lists.lists_4x4[i]
+            } else {
+                &mut lists.lists_8x8[i - 6]
+            };
+            let mut last = 8;
+            let mut next = 8;
+            for (j, entry) in list.iter_mut().enumerate() {
+                if next != 0 {
+                    next = (last + reader.se()? + 256) % 256;
+                    if j == 0 && next == 0 {
+                        lists.use_default_mask |= 1 << i;
+                        break;
+                    }
+                }
+                *entry = if next == 0 { last } else { next } as u8;
+                last = *entry as i32;
+            }
+        }
+        Ok(lists)
+    }
+
+    fn write(&self, writer: &mut BitWriter, count: usize) {
+        for i in 0..count {
+            let present = self.present_mask >> i & 1 == 1;
+            writer.flag(present);
+            if !present {
+                continue;
+            }
+            if self.use_default_mask >> i & 1 == 1 {
+                // A first delta making the next scale zero
+                writer.se(-8);
+                continue;
+            }
+            let list: &[u8] = if i < 6 {
+                &self.lists_4x4[i]
+            } else {
+                &self.lists_8x8[i - 6]
+            };
+            let mut last = 8;
+            for &entry in list {
+                writer.se((entry as i32 - last + 128).rem_euclid(256) - 128);
+                last = entry as i32;
+            }
+        }
+    }
+
+    pub fn to_std(&self) -> StdVideoH264ScalingLists {
+        StdVideoH264ScalingLists {
+            scaling_list_present_mask: self.present_mask,
+            use_default_scaling_matrix_mask: self.use_default_mask,
+            ScalingList4x4: self.lists_4x4,
+            ScalingList8x8: self.lists_8x8,
+        }
+    }
+}
+
+/// Sequence parameter set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub id: u8,
+    pub chroma_format_idc: u8,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub qpprime_y_zero_transform_bypass: bool,
+    pub scaling_lists: Option<ScalingLists>,
+    pub log2_max_frame_num_minus4: u8,
+    pub pic_order_cnt_type: u8,
+    pub log2_max_pic_order_cnt_lsb_minus4: u8,
+    pub delta_pic_order_always_zero: bool,
+    pub offset_for_non_ref_pic: i32,
+    pub offset_for_top_to_bottom_field: i32,
+    pub offsets_for_ref_frame: Vec<i32>,
+    pub max_num_ref_frames: u8,
+    pub gaps_in_frame_num_allowed: bool,
+    pub pic_width_in_mbs_minus1: u32,
+    pub pic_height_in_map_units_minus1: u32,
+    pub frame_mbs_only: bool,
+    pub mb_adaptive_frame_field: bool,
+    pub direct_8x8_inference: bool,
+    /// Left, right, top and bottom, in crop units
+    pub frame_crop: Option<[u32; 4]>,
+    pub vui: Vui,
+}
+
+/// The parts of the VUI the player uses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vui {
+    pub full_range: bool,
+    /// `matrix_coefficients`, 2 when unspecified
+    pub matrix_coefficients: u8,
+    /// Frames per second from the timing info
+    pub frame_rate: Option<f64>,
+    /// Frames held back to reorder B pictures
+    pub max_num_reorder_frames: Option<u32>,
+}
+
+impl Sps {
+    pub fn parse(rbsp: &[u8]) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let profile_idc = r.bits(8)? as u8;
+        let constraint_flags = r.bits(8)? as u8;
+        let level_idc = r.bits(8)? as u8;
+        let id = r.ue()? as u8;
+        let mut sps = Sps {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            id,
+            chroma_format_idc: 1,
+            separate_colour_plane: false,
+            bit_depth_luma_minus8: 0,
+            bit_depth_chroma_minus8: 0,
+            qpprime_y_zero_transform_bypass: false,
+            scaling_lists: None,
+            log2_max_frame_num_minus4: 0,
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb_minus4: 0,
+            delta_pic_order_always_zero: false,
+            offset_for_non_ref_pic: 0,
+            offset_for_top_to_bottom_field: 0,
+            offsets_for_ref_frame: Vec::new(),
+            max_num_ref_frames: 0,
+            gaps_in_frame_num_allowed: false,
+            pic_width_in_mbs_minus1: 0,
+            pic_height_in_map_units_minus1: 0,
+            frame_mbs_only: true,
+            mb_adaptive_frame_field: false,
+            direct_8x8_inference: false,
+            frame_crop: None,
+            vui: Vui {
+                matrix_coefficients: 2,
+                ..Vui::default()
+            },
+        };
+        if HIGH_PROFILES.contains(&profile_idc) {
+            sps.chroma_format_idc = r.ue()? as u8;
+            if sps.chroma_format_idc == 3 {
+                sps.separate_colour_plane = r.flag()?;
+            }
+            sps.bit_depth_luma_minus8 = r.ue()? as u8;
+            sps.bit_depth_chroma_minus8 = r.ue()? as u8;
+            sps.qpprime_y_zero_transform_bypass = r.flag()?;
+            if r.flag()? {
+                let count = if sps.chroma_format_idc == 3 { 12 } else { 8 };
+                sps.scaling_lists = Some(ScalingLists::parse(r, count)?);
+            }
+        }
+        sps.log2_max_frame_num_minus4 = r.ue()? as u8;
+        sps.pic_order_cnt_type = r.ue()? as u8;
+        match sps.pic_order_cnt_type {
+            0 => sps.log2_max_pic_order_cnt_lsb_minus4 = r.ue()? as u8,
+            1 => {
+                sps.delta_pic_order_always_zero = r.flag()?;
+                sps.offset_for_non_ref_pic = r.se()?;
+                sps.offset_for_top_to_bottom_field = r.se()?;
+                let count = r.ue()?;
+                if count > 255 {
+                    return Err(VideoError::Malformed("too many reference frame offsets"));
+                }
+                sps.offsets_for_ref_frame = (0..count).map(|_| r.se()).collect::<Result<_, _>>()?;
+            }
+            2 => {}
+            _ => return Err(VideoError::Malformed("pic_order_cnt_type above 2")),
+        }
+        sps.max_num_ref_frames = r.ue()? as u8;
+        sps.gaps_in_frame_num_allowed = r.flag()?;
+        sps.pic_width_in_mbs_minus1 = r.ue()?;
+        sps.pic_height_in_map_units_minus1 = r.ue()?;
+        sps.frame_mbs_only = r.flag()?;
+        if !sps.frame_mbs_only {
+            sps.mb_adaptive_frame_field = r.flag()?;
+        }
+        sps.direct_8x8_inference = r.flag()?;
+        if r.flag()? {
+            sps.frame_crop = Some([r.ue()?, r.ue()?, r.ue()?, r.ue()?]);
+        }
+        if r.flag()? {
+            sps.vui = Vui::parse(r)?;
+        }
+        Ok(sps)
+    }
+
+    /// The RBSP coding this set, always with a VUI
+    pub fn write(&self) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.bits(8, self.profile_idc as u32);
+        w.bits(8, self.constraint_flags as u32);
+        w.bits(8, self.level_idc as u32);
+        w.ue(self.id as u32);
+        if HIGH_PROFILES.contains(&self.profile_idc) {
+            w.ue(self.chroma_format_idc as u32);
+            if self.chroma_format_idc == 3 {
+                w.flag(self.separate_colour_plane);
+            }
+            w.ue(self.bit_depth_luma_minus8 as u32);
+            w.ue(self.bit_depth_chroma_minus8 as u32);
+            w.flag(self.qpprime_y_zero_transform_bypass);
+            w.flag(self.scaling_lists.is_some());
+            if let Some(lists) = &self.scaling_lists {
+                lists.write(&mut w, if self.chroma_format_idc == 3 { 12 } else { 8 });
+            }
+        }
+        w.ue(self.log2_max_frame_num_minus4 as u32);
+        w.ue(self.pic_order_cnt_type as u32);
+        match self.pic_order_cnt_type {
+            0 => w.ue(self.log2_max_pic_order_cnt_lsb_minus4 as u32),
+            1 => {
+                w.flag(self.delta_pic_order_always_zero);
+                w.se(self.offset_for_non_ref_pic);
+                w.se(self.offset_for_top_to_bottom_field);
+                w.ue(self.offsets_for_ref_frame.len() as u32);
+                for &offset in &self.offsets_for_ref_frame {
+                    w.se(offset);
+                }
+            }
+            _ => {}
+        }
+        w.ue(self.max_num_ref_frames as u32);
+        w.flag(self.gaps_in_frame_num_allowed);
+        w.ue(self.pic_width_in_mbs_minus1);
+        w.ue(self.pic_height_in_map_units_minus1);
+        w.flag(self.frame_mbs_only);
+        if !self.frame_mbs_only {
+            w.flag(self.mb_adaptive_frame_field);
+        }
+        w.flag(self.direct_8x8_inference);
+        w.flag(self.frame_crop.is_some());
+        for offset in self.frame_crop.into_iter().flatten() {
+            w.ue(offset);
+        }
+        w.flag(true);
+        self.vui.write(&mut w, self.max_num_ref_frames as u32);
+        w.finish()
+    }
+
+    pub fn max_frame_num(&self) -> u32 {
+        1 << (self.log2_max_frame_num_minus4 + 4)
+    }
+
+    /// Size of the decoded pictures, before cropping
+    pub fn coded_extent(&self) -> (u32, u32) {
+        let map_units = self.pic_height_in_map_units_minus1 + 1;
+        let height_in_mbs = if self.frame_mbs_only {
+            map_units
+        } else {
+            map_units * 2
+        };
+        ((self.pic_width_in_mbs_minus1 + 1) * 16, height_in_mbs * 16)
+    }
+
+    /// The part of the decoded picture to show, the coded size less the cropping
+    pub fn display_rect(&self) -> vk::Rect2D {
+        let (width, height) = self.coded_extent();
+        let [left, right, top, bottom] = self.frame_crop.unwrap_or_default();
+        let (unit_x, unit_y) = if self.separate_colour_plane || self.chroma_format_idc == 0 {
+            (1, 1)
+        } else {
+            match self.chroma_format_idc {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            }
+        };
+        let unit_y = unit_y * (2 - self.frame_mbs_only as u32);
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: (left * unit_x) as i32,
+                y: (top * unit_y) as i32,
+            },
+            extent: vk::Extent2D {
+                width: width.saturating_sub((left + right) * unit_x),
+                height: height.saturating_sub((top + bottom) * unit_y),
+            },
+        }
+    }
+
+    /// `StdVideoH264LevelIdc` of the stream
+    pub fn std_level_idc(&self) -> u32 {
+        level_idc(self.level_idc, self.constraint_flags)
+    }
+
+    /// Pictures held back for reordering before output
+    pub fn reorder_depth(&self) -> u32 {
+        self.vui.max_num_reorder_frames.unwrap_or(
+            // Baseline has no B slices, others may reorder up to the whole DPB
+            if self.profile_idc == 66 {
+                0
+            } else {
+                self.max_num_ref_frames as u32
+            },
+        )
+    }
+
+    /// The Vulkan form, pointing into `self` and `scaling`, this set's [`ScalingLists::to_std`]
+    pub fn to_std(
+        &self,
+        scaling: Option<&StdVideoH264ScalingLists>,
+    ) -> StdVideoH264SequenceParameterSet {
+        // Plain bitfields, all zero is every flag unset
+        let mut flags: StdVideoH264SpsFlags = unsafe { std::mem::zeroed() };
+        for (bit, set) in [
+            (
+                7,
+                StdVideoH264SpsFlags::set_constraint_set0_flag as fn(&mut _, u32),
+            ),
+            (6, StdVideoH264SpsFlags::set_constraint_set1_flag),
+            (5, StdVideoH264SpsFlags::set_constraint_set2_flag),
+            (4, StdVideoH264SpsFlags::set_constraint_set3_flag),
+            (3, StdVideoH264SpsFlags::set_constraint_set4_flag),
+            (2, StdVideoH264SpsFlags::set_constraint_set5_flag),
+        ] {
+            set(&mut flags, (self.constraint_flags >> bit & 1) as u32);
+        }
+        flags.set_direct_8x8_inference_flag(self.direct_8x8_inference as u32);
+        flags.set_mb_adaptive_frame_field_flag(self.mb_adaptive_frame_field as u32);
+        flags.set_frame_mbs_only_flag(self.frame_mbs_only as u32);
+        flags.set_delta_pic_order_always_zero_flag(self.delta_pic_order_always_zero as u32);
+        flags.set_separate_colour_plane_flag(self.separate_colour_plane as u32);
+        flags.set_gaps_in_frame_num_value_allowed_flag(self.gaps_in_frame_num_allowed as u32);
+        flags.set_qpprime_y_zero_transform_bypass_flag(self.qpprime_y_zero_transform_bypass as u32);
+        flags.set_frame_cropping_flag(self.frame_crop.is_some() as u32);
+        flags.set_seq_scaling_matrix_present_flag(self.scaling_lists.is_some() as u32);
+        let [left, right, top, bottom] = self.frame_crop.unwrap_or_default();
+        StdVideoH264SequenceParameterSet {
+            flags,
+            profile_idc: self.profile_idc as u32,
+            level_idc: self.std_level_idc(),
+            chroma_format_idc: self.chroma_format_idc as u32,
+            seq_parameter_set_id: self.id,
+            bit_depth_luma_minus8: self.bit_depth_luma_minus8,
+            bit_depth_chroma_minus8: self.bit_depth_chroma_minus8,
+            log2_max_frame_num_minus4: self.log2_max_frame_num_minus4,
+            pic_order_cnt_type: self.pic_order_cnt_type as u32,
+            offset_for_non_ref_pic: self.offset_for_non_ref_pic,
+            offset_for_top_to_bottom_field: self.offset_for_top_to_bottom_field,
+            log2_max_pic_order_cnt_lsb_minus4: self.log2_max_pic_order_cnt_lsb_minus4,
+            num_ref_frames_in_pic_order_cnt_cycle: self.offsets_for_ref_frame.len() as u8,
+            max_num_ref_frames: self.max_num_ref_frames,
+            reserved1: 0,
+            pic_width_in_mbs_minus1: self.pic_width_in_mbs_minus1,
+            pic_height_in_map_units_minus1: self.pic_height_in_map_units_minus1,
+            frame_crop_left_offset: left,
+            frame_crop_right_offset: right,
+            frame_crop_top_offset: top,
+            frame_crop_bottom_offset: bottom,
+            reserved2: 0,
+            pOffsetForRefFrame: self.offsets_for_ref_frame.as_ptr(),
+            pScalingLists: scaling.map_or(std::ptr::null(), |lists| lists as *const _),
+            // The VUI isn't needed for decoding
+            pSequenceParameterSetVui: std::ptr::null(),
+        }
+    }
+}
+
+/// `StdVideoH264LevelIdc` from `level_idc`, level 1b being 11 with constraint_set3
+fn level_idc(level: u8, constraint_flags: u8) -> u32 {
+    match level {
+        9 => 1,
+        11 if constraint_flags >> 4 & 1 == 1 => 1,
+        10 => 0,
+        11 => 2,
+        12 => 3,
+        13 => 4,
+        20 => 5,
+        21 => 6,
+        22 => 7,
+        30 => 8,
+        31 => 9,
+        32 => 10,
+        40 => 11,
+        41 => 12,
+        42 => 13,
+        50 => 14,
+        51 => 15,
+        52 => 16,
+        60 => 17,
+        61 => 18,
+        _ => 19,
+    }
+}
+
+impl Vui {
+    fn parse(r: &mut BitReader) -> Result<Self, VideoError> {
+        let mut vui = Vui {
+            matrix_coefficients: 2,
+            ..Vui::default()
+        };
+        // aspect_ratio_info_present_flag, Extended_SAR carries an explicit ratio
+        if r.flag()? && r.bits(8)? == 255 {
+            r.bits(32)?;
+        }
+        // overscan_info_present_flag
+        if r.flag()? {
+            r.flag()?;
+        }
+        // video_signal_type_present_flag
+        if r.flag()? {
+            r.bits(3)?;
+            vui.full_range = r.flag()?;
+            if r.flag()? {
+                r.bits(16)?;
+                vui.matrix_coefficients = r.bits(8)? as u8;
+            }
+        }
+        // chroma_loc_info_present_flag
+        if r.flag()? {
+            r.ue()?;
+            r.ue()?;
+        }
+        // timing_info_present_flag
+        if r.flag()? {
+            let num_units_in_tick = r.bits(32)?;
+            let time_scale = r.bits(32)?;
+            r.flag()?;
+            if num_units_in_tick > 0 {
+                vui.frame_rate = Some(time_scale as f64 / (2.0 * num_units_in_tick as f64));
+            }
+        }
+        let nal_hrd = r.flag()?;
+        if nal_hrd {
+            skip_hrd(r)?;
+        }
+        let vcl_hrd = r.flag()?;
+        if vcl_hrd {
+            skip_hrd(r)?;
+        }
+        if nal_hrd || vcl_hrd {
+            // low_delay_hrd_flag
+            r.flag()?;
+        }
+        // pic_struct_present_flag
+        r.flag()?;
+        // bitstream_restriction_flag
+        if r.flag()? {
+            r.flag()?;
+            for _ in 0..4 {
+                r.ue()?;
+            }
+            vui.max_num_reorder_frames = Some(r.ue()?);
+            r.ue()?;
+        }
+        Ok(vui)
+    }
+
+    /// Colour primaries and transfer are written as unspecified, they aren't kept
+    fn write(&self, w: &mut BitWriter, max_num_ref_frames: u32) {
+        // aspect_ratio_info_present_flag, overscan_info_present_flag
+        w.flag(false);
+        w.flag(false);
+        // video_signal_type_present_flag, video_format unspecified
+        w.flag(true);
+        w.bits(3, 5);
+        w.flag(self.full_range);
+        w.flag(true);
+        w.bits(8, 2);
+        w.bits(8, 2);
+        w.bits(8, self.matrix_coefficients as u32);
+        // chroma_loc_info_present_flag
+        w.flag(false);
+        w.flag(self.frame_rate.is_some());
+        if let Some(rate) = self.frame_rate {
+            // A field tick of a thousandth of a second
+            w.bits(32, 1000);
+            w.bits(32, (rate * 2000.0).round() as u32);
+            w.flag(true);
+        }
+        // nal_hrd_parameters_present_flag, vcl_hrd_parameters_present_flag,
+        // pic_struct_present_flag
+        w.flag(false);
+        w.flag(false);
+        w.flag(false);
+        w.flag(self.max_num_reorder_frames.is_some());
+        if let Some(reorder) = self.max_num_reorder_frames {
+            w.flag(true);
+            // max_bytes_per_pic_denom, max_bits_per_mb_denom and the motion vector lengths at
+            // their defaults
+            w.ue(2);
+            w.ue(1);
+            w.ue(16);
+            w.ue(16);
+            w.ue(reorder);
+            w.ue(reorder.max(max_num_ref_frames));
+        }
+    }
+}
+
+fn skip_hrd(r: &mut BitReader) -> Result<(), VideoError> {
+    let cpb_count = r.ue()? + 1;
+    r.bits(8)?;
+    for _ in 0..cpb_count {
+        r.ue()?;
+        r.ue()?;
+        r.flag()?;
+    }
+    r.bits(20)?;
+    Ok(())
+}
+
+/// Picture parameter set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub id: u8,
+    pub sps_id: u8,
+    pub entropy_coding_mode: bool,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    pub num_ref_idx_l0_default_active_minus1: u8,
+    pub num_ref_idx_l1_default_active_minus1: u8,
+    pub weighted_pred: bool,
+    pub weighted_bipred_idc: u8,
+    pub pic_init_qp_minus26: i8,
+    pub pic_init_qs_minus26: i8,
+    pub chroma_qp_index_offset: i8,
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+    pub transform_8x8_mode: bool,
+    pub scaling_lists: Option<ScalingLists>,
+    pub second_chroma_qp_index_offset: i8,
+}
+
+impl Pps {
+    /// Needs the SPS it refers to for the number of scaling lists
+    pub fn parse(rbsp: &[u8], sps: &BTreeMap<u8, Sps>) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let id = r.ue()? as u8;
+        let sps_id = r.ue()? as u8;
+        let entropy_coding_mode = r.flag()?;
+        let bottom_field_pic_order_in_frame_present = r.flag()?;
+        if r.ue()? != 0 {
+            return Err(VideoError::Unsupported("slice groups"));
+        }
+        let mut pps = Pps {
+            id,
+            sps_id,
+            entropy_coding_mode,
+            bottom_field_pic_order_in_frame_present,
+            num_ref_idx_l0_default_active_minus1: r.ue()? as u8,
+            num_ref_idx_l1_default_active_minus1: r.ue()? as u8,
+            weighted_pred: r.flag()?,
+            weighted_bipred_idc: r.bits(2)? as u8,
+            pic_init_qp_minus26: r.se()? as i8,
+            pic_init_qs_minus26: r.se()? as i8,
+            chroma_qp_index_offset: r.se()? as i8,
+            deblocking_filter_control_present: r.flag()?,
+            constrained_intra_pred: r.flag()?,
+            redundant_pic_cnt_present: r.flag()?,
+            transform_8x8_mode: false,
+            scaling_lists: None,
+            second_chroma_qp_index_offset: 0,
+        };
+        pps.second_chroma_qp_index_offset = pps.chroma_qp_index_offset;
+        if r.more_rbsp_data() {
+            pps.transform_8x8_mode = r.flag()?;
+            if r.flag()? {
+                let chroma_format_idc = sps
+                    .get(&sps_id)
+                    .ok_or(VideoError::MissingParameterSet)?
+                    .chroma_format_idc;
+                let count = 6 + if !pps.transform_8x8_mode {
+                    0
+                } else if chroma_format_idc == 3 {
+                    6
+                } else {
+                    2
+                };
+                pps.scaling_lists = Some(ScalingLists::parse(r, count)?);
+            }
+            pps.second_chroma_qp_index_offset = r.se()? as i8;
+        }
+        Ok(pps)
+    }
+
+    /// The RBSP coding this set, `sps` being the set it refers to
+    pub fn write(&self, sps: &Sps) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.ue(self.id as u32);
+        w.ue(self.sps_id as u32);
+        w.flag(self.entropy_coding_mode);
+        w.flag(self.bottom_field_pic_order_in_frame_present);
+        // num_slice_groups_minus1
+        w.ue(0);
+        w.ue(self.num_ref_idx_l0_default_active_minus1 as u32);
+        w.ue(self.num_ref_idx_l1_default_active_minus1 as u32);
+        w.flag(self.weighted_pred);
+        w.bits(2, self.weighted_bipred_idc as u32);
+        w.se(self.pic_init_qp_minus26 as i32);
+        w.se(self.pic_init_qs_minus26 as i32);
+        w.se(self.chroma_qp_index_offset as i32);
+        w.flag(self.deblocking_filter_control_present);
+        w.flag(self.constrained_intra_pred);
+        w.flag(self.redundant_pic_cnt_present);
+        if self.transform_8x8_mode
+            || self.scaling_lists.is_some()
+            || self.second_chroma_qp_index_offset != self.chroma_qp_index_offset
+        {
+            w.flag(self.transform_8x8_mode);
+            w.flag(self.scaling_lists.is_some());
+            if let Some(lists) = &self.scaling_lists {
+                let count = 6 + if !self.transform_8x8_mode {
+                    0
+                } else if sps.chroma_format_idc == 3 {
+                    6
+                } else {
+                    2
+                };
+                lists.write(&mut w, count);
+            }
+            w.se(self.second_chroma_qp_index_offset as i32);
+        }
+        w.finish()
+    }
+
+    /// The Vulkan form, pointing into `scaling`, this set's [`ScalingLists::to_std`]
+    pub fn to_std(
+        &self,
+        scaling: Option<&StdVideoH264ScalingLists>,
+    ) -> StdVideoH264PictureParameterSet {
+        let mut flags: StdVideoH264PpsFlags = unsafe { std::mem::zeroed() };
+        flags.set_transform_8x8_mode_flag(self.transform_8x8_mode as u32);
+        flags.set_redundant_pic_cnt_present_flag(self.redundant_pic_cnt_present as u32);
+        flags.set_constrained_intra_pred_flag(self.constrained_intra_pred as u32);
+        flags.set_deblocking_filter_control_present_flag(
+            self.deblocking_filter_control_present as u32,
+        );
+        flags.set_weighted_pred_flag(self.weighted_pred as u32);
+        flags.set_bottom_field_pic_order_in_frame_present_flag(
+            self.bottom_field_pic_order_in_frame_present as u32,
+        );
+        flags.set_entropy_coding_mode_flag(self.entropy_coding_mode as u32);
+        flags.set_pic_scaling_matrix_present_flag(self.scaling_lists.is_some() as u32);
+        StdVideoH264PictureParameterSet {
+            flags,
+            seq_parameter_set_id: self.sps_id,
+            pic_parameter_set_id: self.id,
+            num_ref_idx_l0_default_active_minus1: self.num_ref_idx_l0_default_active_minus1,
+            num_ref_idx_l1_default_active_minus1: self.num_ref_idx_l1_default_active_minus1,
+            weighted_bipred_idc: self.weighted_bipred_idc as u32,
+            pic_init_qp_minus26: self.pic_init_qp_minus26,
+            pic_init_qs_minus26: self.pic_init_qs_minus26,
+            chroma_qp_index_offset: self.chroma_qp_index_offset,
+            second_chroma_qp_index_offset: self.second_chroma_qp_index_offset,
+            pScalingLists: scaling.map_or(std::ptr::null(), |lists| lists as *const _),
+        }
+    }
+}
+
+/// Memory management control operations of `dec_ref_pic_marking`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mmco {
+    ForgetShortTerm {
+        difference_of_pic_nums_minus1: u32,
+    },
+    ForgetLongTerm {
+        long_term_pic_num: u32,
+    },
+    ShortToLongTerm {
+        difference_of_pic_nums_minus1: u32,
+        long_term_frame_idx: u32,
+    },
+    MaxLongTermIdx {
+        max_long_term_frame_idx_plus1: u32,
+    },
+    ForgetAll,
+    CurrentToLongTerm {
+        long_term_frame_idx: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceType {
+    P,
+    B,
+    I,
+    Sp,
+    Si,
+}
+
+/// The start of a slice header, up to and including reference marking
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    pub nal: NalHeader,
+    pub first_mb_in_slice: u32,
+    pub slice_type: SliceType,
+    pub pps_id: u8,
+    pub frame_num: u32,
+    pub idr_pic_id: u32,
+    pub pic_order_cnt_lsb: u32,
+    pub delta_pic_order_cnt_bottom: i32,
+    pub delta_pic_order_cnt: [i32; 2],
+    /// Set on IDR pictures to be kept as long term references
+    pub long_term_reference: bool,
+    pub mmcos: Vec<Mmco>,
+}
+
+impl SliceHeader {
+    pub fn is_idr(&self) -> bool {
+        self.nal.nal_unit_type == NAL_IDR_SLICE
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.nal.nal_ref_idc != 0
+    }
+
+    pub fn parse(
+        nal: NalHeader,
+        rbsp: &[u8],
+        sps: &BTreeMap<u8, Sps>,
+        pps: &BTreeMap<u8, Pps>,
+    ) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let first_mb_in_slice = r.ue()?;
+        let slice_type = match r.ue()? % 5 {
+            0 => SliceType::P,
+            1 => SliceType::B,
+            2 => SliceType::I,
+            3 => SliceType::Sp,
+            _ => SliceType::Si,
+        };
+        let pps_id = r.ue()? as u8;
+        let pps = pps.get(&pps_id).ok_or(VideoError::MissingParameterSet)?;
+        let sps = sps
+            .get(&pps.sps_id)
+            .ok_or(VideoError::MissingParameterSet)?;
+        if sps.separate_colour_plane {
+            r.bits(2)?;
+        }
+        let frame_num = r.bits(sps.log2_max_frame_num_minus4 as u32 + 4)?;
+        if !sps.frame_mbs_only && r.flag()? {
+            return Err(VideoError::Unsupported("field pictures"));
+        }
+        let mut header = SliceHeader {
+            nal,
+            first_mb_in_slice,
+            slice_type,
+            pps_id,
+            frame_num,
+            idr_pic_id: 0,
+            pic_order_cnt_lsb: 0,
+            delta_pic_order_cnt_bottom: 0,
+            delta_pic_order_cnt: [0; 2],
+            long_term_reference: false,
+            mmcos: Vec::new(),
+        };
+        if header.is_idr() {
+            header.idr_pic_id = r.ue()?;
+        }
+        if sps.pic_order_cnt_type == 0 {
+            header.pic_order_cnt_lsb = r.bits(sps.log2_max_pic_order_cnt_lsb_minus4 as u32 + 4)?;
+            if pps.bottom_field_pic_order_in_frame_present {
+                header.delta_pic_order_cnt_bottom = r.se()?;
+            }
+        }
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero {
+            header.delta_pic_order_cnt[0] = r.se()?;
+            if pps.bottom_field_pic_order_in_frame_present {
+                header.delta_pic_order_cnt[1] = r.se()?;
+            }
+        }
+        if pps.redundant_pic_cnt_present {
+            r.ue()?;
+        }
+        let is_b = slice_type == SliceType::B;
+        let is_p = matches!(slice_type, SliceType::P | SliceType::Sp);
+        if is_b {
+            // direct_spatial_mv_pred_flag
+            r.flag()?;
+        }
+        let mut active = [
+            pps.num_ref_idx_l0_default_active_minus1 as u32,
+            pps.num_ref_idx_l1_default_active_minus1 as u32,
+        ];
+        if (is_p || is_b) && r.flag()? {
+            active[0] = r.ue()?;
+            if is_b {
+                active[1] = r.ue()?;
+            }
+        }
+        let lists = if is_b { 2 } else { is_p as usize };
+        for _ in 0..lists {
+            // ref_pic_list_modification_flag
+            if r.flag()? {
+                loop {
+                    match r.ue()? {
+                        0..=2 => {
+                            r.ue()?;
+                        }
+                        3 => break,
+                        _ => return Err(VideoError::Malformed("modification_of_pic_nums_idc")),
+                    }
+                }
+            }
+        }
+        if (pps.weighted_pred && is_p) || (pps.weighted_bipred_idc == 1 && is_b) {
+            let chroma = !sps.separate_colour_plane && sps.chroma_format_idc != 0;
+            r.ue()?;
+            if chroma {
+                r.ue()?;
+            }
+            for &active in &active[..lists] {
+                for _ in 0..=active {
+                    if r.flag()? {
+                        r.se()?;
+                        r.se()?;
+                    }
+                    if chroma && r.flag()? {
+                        for _ in 0..4 {
+                            r.se()?;
+                        }
+                    }
+                }
+            }
+        }
+        if header.is_reference() {
+            if header.is_idr() {
+                // no_output_of_prior_pics_flag
+                r.flag()?;
+                header.long_term_reference = r.flag()?;
+            } else if r.flag()? {
+                loop {
+                    let mmco = match r.ue()? {
+                        0 => break,
+                        1 => Mmco::ForgetShortTerm {
+                            difference_of_pic_nums_minus1: r.ue()?,
+                        },
+                        2 => Mmco::ForgetLongTerm {
+                            long_term_pic_num: r.ue()?,
+                        },
+                        3 => Mmco::ShortToLongTerm {
+                            difference_of_pic_nums_minus1: r.ue()?,
+                            long_term_frame_idx: r.ue()?,
+                        },
+                        4 => Mmco::MaxLongTermIdx {
+                            max_long_term_frame_idx_plus1: r.ue()?,
+                        },
+                        5 => Mmco::ForgetAll,
+                        6 => Mmco::CurrentToLongTerm {
+                            long_term_frame_idx: r.ue()?,
+                        },
+                        _ => return Err(VideoError::Malformed("memory_management_control")),
+                    };
+                    header.mmcos.push(mmco);
+                }
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// Picture order count state carried from picture to picture, clause 8.2.1
+#[derive(Debug, Clone, Default)]
+pub struct PocState {
+    prev_poc_msb: i32,
+    prev_poc_lsb: i32,
+    prev_frame_num: u32,
+    prev_frame_num_offset: i32,
+}
+
+impl PocState {
+    /// Top and bottom field order counts of the picture `header` starts
+    pub fn compute(&mut self, sps: &Sps, header: &SliceHeader) -> [i32; 2] {
+        let idr = header.is_idr();
+        let max_frame_num = sps.max_frame_num() as i32;
+        let frame_num = header.frame_num as i32;
+        let frame_num_offset = if idr {
+            0
+        } else if self.prev_frame_num as i32 > frame_num {
+            self.prev_frame_num_offset + max_frame_num
+        } else {
+            self.prev_frame_num_offset
+        };
+        let poc = match sps.pic_order_cnt_type {
+            0 => {
+                if idr {
+                    self.prev_poc_msb = 0;
+                    self.prev_poc_lsb = 0;
+                }
+                let max_lsb = 1 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+                let lsb = header.pic_order_cnt_lsb as i32;
+                let msb = if lsb < self.prev_poc_lsb && self.prev_poc_lsb - lsb >= max_lsb / 2 {
+                    self.prev_poc_msb + max_lsb
+                } else if lsb > self.prev_poc_lsb && lsb - self.prev_poc_lsb > max_lsb / 2 {
+                    self.prev_poc_msb - max_lsb
+                } else {
+                    self.prev_poc_msb
+                };
+                let top = msb + lsb;
+                if header.is_reference() {
+                    self.prev_poc_msb = msb;
+                    self.prev_poc_lsb = lsb;
+                }
+                [top, top + header.delta_pic_order_cnt_bottom]
+            }
+            1 => {
+                let cycle = &sps.offsets_for_ref_frame;
+                let mut abs_frame_num = if cycle.is_empty() {
+                    0
+                } else {
+                    frame_num_offset + frame_num
+                };
+                if !header.is_reference() && abs_frame_num > 0 {
+                    abs_frame_num -= 1;
+                }
+                let mut expected = 0;
+                if abs_frame_num > 0 {
+                    let len = cycle.len() as i32;
+                    let cycle_count = (abs_frame_num - 1) / len;
+                    let in_cycle = ((abs_frame_num - 1) % len) as usize;
+                    let delta: i32 = cycle.iter().sum();
+                    expected = cycle_count * delta + cycle[..=in_cycle].iter().sum::<i32>();
+                }
+                if !header.is_reference() {
+                    expected += sps.offset_for_non_ref_pic;
+                }
+                let top = expected + header.delta_pic_order_cnt[0];
+                [
+                    top,
+                    top + sps.offset_for_top_to_bottom_field + header.delta_pic_order_cnt[1],
+                ]
+            }
+            _ => {
+                let poc = if idr {
+                    0
+                } else if header.is_reference() {
+                    2 * (frame_num_offset + frame_num)
+                } else {
+                    2 * (frame_num_offset + frame_num) - 1
+                };
+                [poc, poc]
+            }
+        };
+        self.prev_frame_num = header.frame_num;
+        self.prev_frame_num_offset = frame_num_offset;
+        if header.mmcos.contains(&Mmco::ForgetAll) {
+            // The picture counts as having order count zero from here on
+            let min = poc[0].min(poc[1]);
+            self.prev_poc_msb = 0;
+            self.prev_poc_lsb = poc[0] - min;
+            self.prev_frame_num = 0;
+            self.prev_frame_num_offset = 0;
+        }
+        poc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_bits_read_back() {
+        let unsigned = [0, 1, 2, 3, 7, 254, 255, 1 << 16, u32::MAX - 1];
+        let signed = [0, 1, -1, 2, -2, 127, -128, i32::MAX, -i32::MAX];
+        let mut w = BitWriter::new();
+        w.flag(true);
+        w.bits(3, 0b101);
+        w.bits(32, 0xdead_beef);
+        for value in unsigned {
+            w.ue(value);
+        }
+        for value in signed {
+            w.se(value);
+        }
+        w.flag(false);
+        let data = w.finish();
+
+        let mut r = BitReader::new(&data);
+        assert!(r.flag().unwrap());
+        assert_eq!(r.bits(3).unwrap(), 0b101);
+        assert_eq!(r.bits(32).unwrap(), 0xdead_beef);
+        for value in unsigned {
+            assert_eq!(r.ue().unwrap(), value);
+        }
+        for value in signed {
+            assert_eq!(r.se().unwrap(), value);
+        }
+        assert!(r.more_rbsp_data());
+        assert!(!r.flag().unwrap());
+        assert!(!r.more_rbsp_data());
+    }
+
+    #[test]
+    fn nal_units_escape_start_codes_and_split_back() {
+        let header = NalHeader {
+            nal_ref_idc: 2,
+            nal_unit_type: NAL_SLICE,
+        };
+        let payload = [0x42, 0, 0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 3, 0, 0, 4, 0x80];
+        let first = nal_unit(header, &payload);
+        // Nothing after the start code reads as one
+        assert!(first[4..]
+            .windows(3)
+            .all(|bytes| bytes[..2] != [0, 0] || bytes[2] > 2));
+        let mut stream = first.clone();
+        stream.extend(nal_unit(
+            NalHeader {
+                nal_ref_idc: 0,
+                nal_unit_type: NAL_IDR_SLICE,
+            },
+            &[0x80],
+        ));
+
+        let nals = nal_units(&stream);
+        assert_eq!(nals.len(), 2);
+        assert_eq!(NalHeader::parse(nals[0]).unwrap(), header);
+        assert_eq!(rbsp(nals[0]), payload);
+        assert_eq!(
+            NalHeader::parse(nals[1]).unwrap().nal_unit_type,
+            NAL_IDR_SLICE
+        );
+        assert_eq!(rbsp(nals[1]), [0x80]);
+    }
+
+    /// Packs a string of ones and zeros, the last byte padded with zeros
+    fn pack(bits: &str) -> Vec<u8> {
+        let bits: Vec<u8> = bits.bytes().filter(|&bit| bit != b' ').collect();
+        bits.chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0, |byte, (i, &bit)| byte | ((bit == b'1') as u8) << (7 - i))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exp_golomb_codes_read_as_in_table_9_2() {
+        let data = pack("1 010 011 00100 00111 0001000 000011111");
+        let mut r = BitReader::new(&data);
+        for value in [0, 1, 2, 3, 6, 7, 30] {
+            assert_eq!(r.ue().unwrap(), value);
+        }
+        assert_eq!(r.position(), 33);
+
+        // se(v) alternates positive and negative, table 9-3
+        let data = pack("1 010 011 00100 00101 00110");
+        let mut r = BitReader::new(&data);
+        for value in [0, 1, -1, 2, -2, 3] {
+            assert_eq!(r.se().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn bit_reader_stops_at_the_end_and_on_long_codes() {
+        let mut r = BitReader::new(&[0b1010_0000]);
+        assert_eq!(r.bits(4).unwrap(), 0b1010);
+        assert!(matches!(r.bits(5), Err(VideoError::Truncated)));
+        assert!(matches!(
+            BitReader::new(&[0]).ue(),
+            Err(VideoError::Truncated)
+        ));
+        assert!(matches!(
+            BitReader::new(&[0; 5]).ue(),
+            Err(VideoError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn more_rbsp_data_ignores_the_stop_bit_and_trailing_zeros() {
+        // Three bits of data, the stop bit, then alignment and a cabac_zero_word
+        let data = [0b1011_0000, 0, 0];
+        let mut r = BitReader::new(&data);
+        assert!(r.more_rbsp_data());
+        r.bits(2).unwrap();
+        assert!(r.more_rbsp_data());
+        r.flag().unwrap();
+        assert!(!r.more_rbsp_data());
+        assert!(!BitReader::new(&[0, 0]).more_rbsp_data());
+    }
+
+    #[test]
+    fn rbsp_drops_only_emulation_prevention_bytes() {
+        let nal = [0x65, 0, 0, 3, 1, 0, 3, 0, 0, 3, 3, 0, 0, 3];
+        // A 3 after a single zero is data, one after two zeros is always dropped
+        assert_eq!(rbsp(&nal), [0, 0, 1, 0, 3, 0, 0, 3, 0, 0]);
+        assert!(rbsp(&[0x65]).is_empty());
+        assert!(rbsp(&[]).is_empty());
+    }
+
+    fn scaling_lists(present_mask: u16, use_default_mask: u16) -> ScalingLists {
+        let mut lists = ScalingLists {
+            present_mask,
+            use_default_mask,
+            lists_4x4: [[16; 16]; 6],
+            lists_8x8: [[16; 64]; 6],
+        };
+        // Lists left out parse as flat
+        if present_mask & 1 != 0 {
+            for (i, entry) in lists.lists_4x4[0].iter_mut().enumerate() {
+                *entry = 4 + 3 * i as u8;
+            }
+        }
+        if present_mask & 1 << 6 != 0 {
+            for (i, entry) in lists.lists_8x8[0].iter_mut().enumerate() {
+                *entry = 255 - 2 * i as u8;
+            }
+        }
+        lists
+    }
+
+    fn high_sps() -> Sps {
+        Sps {
+            profile_idc: 100,
+            constraint_flags: 0,
+            level_idc: 41,
+            id: 1,
+            chroma_format_idc: 1,
+            separate_colour_plane: false,
+            bit_depth_luma_minus8: 2,
+            bit_depth_chroma_minus8: 2,
+            qpprime_y_zero_transform_bypass: false,
+            // The first 4x4 list coded, the third at its default
+            scaling_lists: Some(scaling_lists(0b101, 0b100)),
+            log2_max_frame_num_minus4: 3,
+            pic_order_cnt_type: 1,
+            log2_max_pic_order_cnt_lsb_minus4: 0,
+            delta_pic_order_always_zero: false,
+            offset_for_non_ref_pic: -3,
+            offset_for_top_to_bottom_field: 1,
+            offsets_for_ref_frame: vec![2, -2, 5],
+            max_num_ref_frames: 4,
+            gaps_in_frame_num_allowed: false,
+            pic_width_in_mbs_minus1: 119,
+            pic_height_in_map_units_minus1: 67,
+            frame_mbs_only: true,
+            mb_adaptive_frame_field: false,
+            direct_8x8_inference: true,
+            frame_crop: Some([0, 0, 0, 4]),
+            vui: Vui {
+                full_range: true,
+                matrix_coefficients: 1,
+                frame_rate: Some(30.0),
+                max_num_reorder_frames: Some(2),
+            },
+        }
+    }
+
+    #[test]
+    fn written_sps_parses_back() {
+        let sps = high_sps();
+        assert_eq!(Sps::parse(&sps.write()).unwrap(), sps);
+
+        // Main profile leaves out everything High adds
+        let main = Sps {
+            profile_idc: 77,
+            bit_depth_luma_minus8: 0,
+            bit_depth_chroma_minus8: 0,
+            scaling_lists: None,
+            pic_order_cnt_type: 0,
+            log2_max_pic_order_cnt_lsb_minus4: 2,
+            offset_for_non_ref_pic: 0,
+            offset_for_top_to_bottom_field: 0,
+            offsets_for_ref_frame: Vec::new(),
+            frame_crop: None,
+            ..sps
+        };
+        assert_eq!(Sps::parse(&main.write()).unwrap(), main);
+    }
+
+    #[test]
+    fn written_pps_parses_back() {
+        let sps = high_sps();
+        let sets = BTreeMap::from([(sps.id, sps.clone())]);
+        let plain = Pps {
+            id: 3,
+            sps_id: sps.id,
+            entropy_coding_mode: true,
+            bottom_field_pic_order_in_frame_present: false,
+            num_ref_idx_l0_default_active_minus1: 2,
+            num_ref_idx_l1_default_active_minus1: 0,
+            weighted_pred: true,
+            weighted_bipred_idc: 2,
+            pic_init_qp_minus26: -4,
+            pic_init_qs_minus26: 3,
+            chroma_qp_index_offset: -2,
+            deblocking_filter_control_present: true,
+            constrained_intra_pred: false,
+            redundant_pic_cnt_present: false,
+            transform_8x8_mode: false,
+            scaling_lists: None,
+            second_chroma_qp_index_offset: -2,
+        };
+        assert_eq!(Pps::parse(&plain.write(&sps), &sets).unwrap(), plain);
+
+        // With the trailing fields, the first 8x8 list coded
+        let extended = Pps {
+            transform_8x8_mode: true,
+            scaling_lists: Some(scaling_lists(0b100_0001, 0)),
+            second_chroma_qp_index_offset: 5,
+            ..plain
+        };
+        assert_eq!(Pps::parse(&extended.write(&sps), &sets).unwrap(), extended);
+    }
+}
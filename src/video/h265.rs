@@ -0,0 +1,2073 @@
+//! Parsing the parts of an H.265 Annex B stream the decoder needs
+//!
+//! As with [`h264`], Vulkan Video leaves the headers to the application. Parameter sets are read
+//! up to their extensions, slice segment headers as far as the long term references and only for
+//! the first segment of a picture. Annex B framing is the same as H.264's, [`h264::nal_units`]
+//! splits the stream and [`BitReader`] reads the fields.
+
+use std::collections::BTreeMap;
+
+use ash::vk::{
+    self,
+    native::{
+        StdVideoH265DecPicBufMgr, StdVideoH265LongTermRefPicsSps, StdVideoH265PictureParameterSet,
+        StdVideoH265PpsFlags, StdVideoH265ProfileTierLevel, StdVideoH265ProfileTierLevelFlags,
+        StdVideoH265ScalingLists, StdVideoH265SequenceParameterSet, StdVideoH265ShortTermRefPicSet,
+        StdVideoH265ShortTermRefPicSetFlags, StdVideoH265SpsFlags, StdVideoH265VideoParameterSet,
+        StdVideoH265VpsFlags,
+    },
+};
+
+use super::{
+    h264::{self, BitReader, Vui},
+    VideoError,
+};
+
+pub mod dpb;
+
+pub const NAL_TRAIL_R: u8 = 1;
+pub const NAL_IDR_W_RADL: u8 = 19;
+pub const NAL_CRA: u8 = 21;
+pub const NAL_VPS: u8 = 32;
+pub const NAL_SPS: u8 = 33;
+pub const NAL_PPS: u8 = 34;
+pub const NAL_AUD: u8 = 35;
+pub const NAL_EOS: u8 = 36;
+pub const NAL_EOB: u8 = 37;
+pub const NAL_PREFIX_SEI: u8 = 39;
+
+/// Sub-layers a stream can have, `sps_max_sub_layers_minus1` being at most 6
+const MAX_SUB_LAYERS: usize = 7;
+
+/// Table 7-6, the default 8x8 and larger intra lists in coded order
+const DEFAULT_INTRA: [u8; 64] = [
+    16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 17, 16, 17, 16, 17, 18, 17, 18, 18, 17, 18, 21, 19, 20,
+    21, 20, 19, 21, 24, 22, 22, 24, 24, 22, 22, 24, 25, 25, 27, 30, 27, 25, 25, 29, 31, 35, 35, 31,
+    29, 36, 41, 44, 41, 36, 47, 54, 54, 47, 65, 70, 65, 88, 88, 115,
+];
+
+/// Table 7-6, the default 8x8 and larger inter lists in coded order
+const DEFAULT_INTER: [u8; 64] = [
+    16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 17, 17, 17, 17, 17, 18, 18, 18, 18, 18, 18, 20, 20, 20,
+    20, 20, 20, 20, 24, 24, 24, 24, 24, 24, 24, 24, 25, 25, 25, 25, 25, 25, 25, 28, 28, 28, 28, 28,
+    28, 33, 33, 33, 33, 33, 41, 41, 41, 41, 54, 54, 54, 71, 71, 91,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NalHeader {
+    pub nal_unit_type: u8,
+    pub layer_id: u8,
+    pub temporal_id: u8,
+}
+
+impl NalHeader {
+    pub fn parse(nal: &[u8]) -> Result<Self, VideoError> {
+        let &[high, low, ..] = nal else {
+            return Err(VideoError::Truncated);
+        };
+        Ok(NalHeader {
+            nal_unit_type: high >> 1 & 0x3f,
+            layer_id: (high & 1) << 5 | low >> 3,
+            temporal_id: (low & 7).saturating_sub(1),
+        })
+    }
+
+    /// Coded slice segments, the other types below 32 are reserved
+    pub fn is_slice(&self) -> bool {
+        matches!(self.nal_unit_type, 0..=9 | 16..=21)
+    }
+
+    /// Intra random access points, where decoding can start
+    pub fn is_irap(&self) -> bool {
+        (16..=23).contains(&self.nal_unit_type)
+    }
+
+    pub fn is_idr(&self) -> bool {
+        matches!(self.nal_unit_type, 19 | 20)
+    }
+
+    pub fn is_bla(&self) -> bool {
+        (16..=18).contains(&self.nal_unit_type)
+    }
+
+    /// Leading pictures that may predict from pictures before their IRAP picture
+    pub fn is_rasl(&self) -> bool {
+        matches!(self.nal_unit_type, 8 | 9)
+    }
+
+    pub fn is_radl(&self) -> bool {
+        matches!(self.nal_unit_type, 6 | 7)
+    }
+
+    /// Pictures no later picture of the same sub-layer predicts from
+    pub fn is_sub_layer_non_reference(&self) -> bool {
+        self.nal_unit_type <= 14 && self.nal_unit_type.is_multiple_of(2)
+    }
+}
+
+/// The payload of `nal` after its two byte header, with emulation prevention bytes removed
+pub fn rbsp(nal: &[u8]) -> Vec<u8> {
+    h264::unescape(nal.get(2..).unwrap_or_default())
+}
+
+/// `Ceil(Log2(n))`, the width of an index into `n` entries
+fn ceil_log2(n: usize) -> u32 {
+    usize::BITS - n.saturating_sub(1).leading_zeros()
+}
+
+/// The general part of `profile_tier_level`, sub-layer profiles and levels are skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileTierLevel {
+    pub profile_idc: u8,
+    pub tier: bool,
+    pub progressive_source: bool,
+    pub interlaced_source: bool,
+    pub non_packed_constraint: bool,
+    pub frame_only_constraint: bool,
+    /// Thirty times the level
+    pub level_idc: u8,
+}
+
+impl ProfileTierLevel {
+    fn parse(r: &mut BitReader, max_sub_layers_minus1: u8) -> Result<Self, VideoError> {
+        // general_profile_space
+        r.bits(2)?;
+        let tier = r.flag()?;
+        let profile_idc = r.bits(5)? as u8;
+        // general_profile_compatibility_flag
+        r.bits(32)?;
+        let progressive_source = r.flag()?;
+        let interlaced_source = r.flag()?;
+        let non_packed_constraint = r.flag()?;
+        let frame_only_constraint = r.flag()?;
+        // The other constraint flags
+        r.bits(32)?;
+        r.bits(12)?;
+        let level_idc = r.bits(8)? as u8;
+        let mut present = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            present.push((r.flag()?, r.flag()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            for _ in max_sub_layers_minus1..8 {
+                r.bits(2)?;
+            }
+        }
+        for (profile, level) in present {
+            if profile {
+                r.bits(32)?;
+                r.bits(32)?;
+                r.bits(24)?;
+            }
+            if level {
+                r.bits(8)?;
+            }
+        }
+        Ok(ProfileTierLevel {
+            profile_idc,
+            tier,
+            progressive_source,
+            interlaced_source,
+            non_packed_constraint,
+            frame_only_constraint,
+            level_idc,
+        })
+    }
+
+    /// `StdVideoH265LevelIdc` of the stream
+    pub fn std_level_idc(&self) -> u32 {
+        match self.level_idc {
+            30 => 0,
+            60 => 1,
+            63 => 2,
+            90 => 3,
+            93 => 4,
+            120 => 5,
+            123 => 6,
+            150 => 7,
+            153 => 8,
+            156 => 9,
+            180 => 10,
+            183 => 11,
+            _ => 12,
+        }
+    }
+
+    fn to_std(self) -> StdVideoH265ProfileTierLevel {
+        // Plain bitfields, all zero is every flag unset
+        let mut flags: StdVideoH265ProfileTierLevelFlags = unsafe { std::mem::zeroed() };
+        flags.set_general_tier_flag(self.tier as u32);
+        flags.set_general_progressive_source_flag(self.progressive_source as u32);
+        flags.set_general_interlaced_source_flag(self.interlaced_source as u32);
+        flags.set_general_non_packed_constraint_flag(self.non_packed_constraint as u32);
+        flags.set_general_frame_only_constraint_flag(self.frame_only_constraint as u32);
+        StdVideoH265ProfileTierLevel {
+            flags,
+            general_profile_idc: self.profile_idc as u32,
+            general_level_idc: self.std_level_idc(),
+        }
+    }
+}
+
+/// Picture buffering limits of one temporal sub-layer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubLayerOrdering {
+    pub max_dec_pic_buffering_minus1: u8,
+    pub max_num_reorder_pics: u8,
+    pub max_latency_increase_plus1: u32,
+}
+
+/// One entry per sub-layer, those left out taking the highest one's limits
+fn parse_ordering(
+    r: &mut BitReader,
+    max_sub_layers_minus1: u8,
+    info_present: bool,
+) -> Result<Vec<SubLayerOrdering>, VideoError> {
+    let first = if info_present {
+        0
+    } else {
+        max_sub_layers_minus1 as usize
+    };
+    let mut ordering = vec![SubLayerOrdering::default(); first];
+    for _ in first..=max_sub_layers_minus1 as usize {
+        let max_dec_pic_buffering_minus1 = r.ue()?;
+        let max_num_reorder_pics = r.ue()?;
+        if max_dec_pic_buffering_minus1 > 15 || max_num_reorder_pics > max_dec_pic_buffering_minus1
+        {
+            return Err(VideoError::Malformed("sub-layer buffering"));
+        }
+        ordering.push(SubLayerOrdering {
+            max_dec_pic_buffering_minus1: max_dec_pic_buffering_minus1 as u8,
+            max_num_reorder_pics: max_num_reorder_pics as u8,
+            max_latency_increase_plus1: r.ue()?,
+        });
+    }
+    let highest = ordering[ordering.len() - 1];
+    ordering[..first].fill(highest);
+    Ok(ordering)
+}
+
+fn dec_pic_buf_mgr(ordering: &[SubLayerOrdering]) -> StdVideoH265DecPicBufMgr {
+    let mut std = StdVideoH265DecPicBufMgr {
+        max_latency_increase_plus1: [0; MAX_SUB_LAYERS],
+        max_dec_pic_buffering_minus1: [0; MAX_SUB_LAYERS],
+        max_num_reorder_pics: [0; MAX_SUB_LAYERS],
+    };
+    for (i, layer) in ordering.iter().enumerate() {
+        std.max_latency_increase_plus1[i] = layer.max_latency_increase_plus1;
+        std.max_dec_pic_buffering_minus1[i] = layer.max_dec_pic_buffering_minus1;
+        std.max_num_reorder_pics[i] = layer.max_num_reorder_pics;
+    }
+    std
+}
+
+fn max_sub_layers_minus1(r: &mut BitReader) -> Result<u8, VideoError> {
+    let value = r.bits(3)? as u8;
+    if value as usize >= MAX_SUB_LAYERS {
+        return Err(VideoError::Malformed("more than seven sub-layers"));
+    }
+    Ok(value)
+}
+
+/// Video parameter set, as far as its timing information
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vps {
+    pub id: u8,
+    pub max_sub_layers_minus1: u8,
+    pub temporal_id_nesting: bool,
+    pub profile_tier_level: ProfileTierLevel,
+    pub sub_layer_ordering_info_present: bool,
+    pub sub_layer_ordering: Vec<SubLayerOrdering>,
+    /// `vps_num_units_in_tick` and `vps_time_scale`
+    pub timing: Option<(u32, u32)>,
+    /// Set when order counts are proportional to output times
+    pub num_ticks_poc_diff_one_minus1: Option<u32>,
+}
+
+/// A VPS in its Vulkan form, with the structs it points to kept alongside
+pub struct StdVps {
+    pub set: StdVideoH265VideoParameterSet,
+    _profile_tier_level: Box<StdVideoH265ProfileTierLevel>,
+    _dec_pic_buf_mgr: Box<StdVideoH265DecPicBufMgr>,
+}
+
+impl Vps {
+    pub fn parse(rbsp: &[u8]) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let id = r.bits(4)? as u8;
+        // vps_base_layer_internal_flag, vps_base_layer_available_flag, vps_max_layers_minus1
+        r.bits(8)?;
+        let max_sub_layers_minus1 = max_sub_layers_minus1(r)?;
+        let temporal_id_nesting = r.flag()?;
+        // vps_reserved_0xffff_16bits
+        r.bits(16)?;
+        let profile_tier_level = ProfileTierLevel::parse(r, max_sub_layers_minus1)?;
+        let sub_layer_ordering_info_present = r.flag()?;
+        let sub_layer_ordering =
+            parse_ordering(r, max_sub_layers_minus1, sub_layer_ordering_info_present)?;
+        let max_layer_id = r.bits(6)?;
+        let num_layer_sets_minus1 = r.ue()?;
+        if num_layer_sets_minus1 > 1023 {
+            return Err(VideoError::Malformed(
+                "vps_num_layer_sets_minus1 above 1023",
+            ));
+        }
+        // layer_id_included_flag
+        for _ in 0..num_layer_sets_minus1 * (max_layer_id + 1) {
+            r.flag()?;
+        }
+        let mut vps = Vps {
+            id,
+            max_sub_layers_minus1,
+            temporal_id_nesting,
+            profile_tier_level,
+            sub_layer_ordering_info_present,
+            sub_layer_ordering,
+            timing: None,
+            num_ticks_poc_diff_one_minus1: None,
+        };
+        if r.flag()? {
+            vps.timing = Some((r.bits(32)?, r.bits(32)?));
+            if r.flag()? {
+                vps.num_ticks_poc_diff_one_minus1 = Some(r.ue()?);
+            }
+        }
+        Ok(vps)
+    }
+
+    /// Frames per second from the timing info
+    pub fn frame_rate(&self) -> Option<f64> {
+        let (num_units_in_tick, time_scale) = self.timing?;
+        (num_units_in_tick > 0).then(|| time_scale as f64 / num_units_in_tick as f64)
+    }
+
+    pub fn to_std(&self) -> StdVps {
+        let profile_tier_level = Box::new(self.profile_tier_level.to_std());
+        let dec_pic_buf_mgr = Box::new(dec_pic_buf_mgr(&self.sub_layer_ordering));
+        let mut flags: StdVideoH265VpsFlags = unsafe { std::mem::zeroed() };
+        flags.set_vps_temporal_id_nesting_flag(self.temporal_id_nesting as u32);
+        flags.set_vps_sub_layer_ordering_info_present_flag(
+            self.sub_layer_ordering_info_present as u32,
+        );
+        flags.set_vps_timing_info_present_flag(self.timing.is_some() as u32);
+        flags.set_vps_poc_proportional_to_timing_flag(
+            self.num_ticks_poc_diff_one_minus1.is_some() as u32
+        );
+        let (num_units_in_tick, time_scale) = self.timing.unwrap_or_default();
+        StdVps {
+            set: StdVideoH265VideoParameterSet {
+                flags,
+                vps_video_parameter_set_id: self.id,
+                vps_max_sub_layers_minus1: self.max_sub_layers_minus1,
+                reserved1: 0,
+                reserved2: 0,
+                vps_num_units_in_tick: num_units_in_tick,
+                vps_time_scale: time_scale,
+                vps_num_ticks_poc_diff_one_minus1: self
+                    .num_ticks_poc_diff_one_minus1
+                    .unwrap_or_default(),
+                reserved3: 0,
+                pDecPicBufMgr: &*dec_pic_buf_mgr,
+                // HRD parameters aren't needed for decoding
+                pHrdParameters: std::ptr::null(),
+                pProfileTierLevel: &*profile_tier_level,
+            },
+            _profile_tier_level: profile_tier_level,
+            _dec_pic_buf_mgr: dec_pic_buf_mgr,
+        }
+    }
+}
+
+/// Scaling factors in coded order, with the DC factors of the 16x16 and 32x32 lists
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScalingLists {
+    pub lists_4x4: [[u8; 16]; 6],
+    pub lists_8x8: [[u8; 64]; 6],
+    pub lists_16x16: [[u8; 64]; 6],
+    pub lists_32x32: [[u8; 64]; 2],
+    pub dc_16x16: [u8; 6],
+    pub dc_32x32: [u8; 2],
+}
+
+impl ScalingLists {
+    /// Tables 7-5 and 7-6, used when scaling lists are enabled but none are sent
+    pub const DEFAULT: ScalingLists = {
+        let intra_inter = [
+            DEFAULT_INTRA,
+            DEFAULT_INTRA,
+            DEFAULT_INTRA,
+            DEFAULT_INTER,
+            DEFAULT_INTER,
+            DEFAULT_INTER,
+        ];
+        ScalingLists {
+            lists_4x4: [[16; 16]; 6],
+            lists_8x8: intra_inter,
+            lists_16x16: intra_inter,
+            lists_32x32: [DEFAULT_INTRA, DEFAULT_INTER],
+            dc_16x16: [16; 6],
+            dc_32x32: [16; 2],
+        }
+    };
+
+    /// The list `matrix_id` of size `size_id` and its DC factor, 16 for lists without one
+    fn list(&self, size_id: usize, matrix_id: usize) -> (&[u8], u8) {
+        match size_id {
+            0 => (&self.lists_4x4[matrix_id], 16),
+            1 => (&self.lists_8x8[matrix_id], 16),
+            2 => (&self.lists_16x16[matrix_id], self.dc_16x16[matrix_id]),
+            _ => (
+                &self.lists_32x32[matrix_id / 3],
+                self.dc_32x32[matrix_id / 3],
+            ),
+        }
+    }
+
+    fn list_mut(&mut self, size_id: usize, matrix_id: usize) -> (&mut [u8], Option<&mut u8>) {
+        match size_id {
+            0 => (&mut self.lists_4x4[matrix_id], None),
+            1 => (&mut self.lists_8x8[matrix_id], None),
+            2 => (
+                &mut self.lists_16x16[matrix_id],
+                Some(&mut self.dc_16x16[matrix_id]),
+            ),
+            _ => (
+                &mut self.lists_32x32[matrix_id / 3],
+                Some(&mut self.dc_32x32[matrix_id / 3]),
+            ),
+        }
+    }
+
+    /// `scaling_list_data`, lists predicted from others resolved to their values
+    fn parse(r: &mut BitReader) -> Result<Self, VideoError> {
+        let mut lists = ScalingLists::DEFAULT;
+        for size_id in 0..4 {
+            // Only intra and inter luma lists for 32x32
+            let step = if size_id == 3 { 3 } else { 1 };
+            for matrix_id in (0..6).step_by(step) {
+                // scaling_list_pred_mode_flag
+                if !r.flag()? {
+                    let delta = r.ue()? as usize * step;
+                    if delta > matrix_id {
+                        return Err(VideoError::Malformed("scaling_list_pred_matrix_id_delta"));
+                    }
+                    let source = if delta == 0 {
+                        &ScalingLists::DEFAULT
+                    } else {
+                        &lists
+                    };
+                    let (values, dc) = source.list(size_id, matrix_id - delta);
+                    let values = values.to_vec();
+                    let (list, list_dc) = lists.list_mut(size_id, matrix_id);
+                    list.copy_from_slice(&values);
+                    if let Some(list_dc) = list_dc {
+                        *list_dc = dc;
+                    }
+                    continue;
+                }
+                let (list, dc) = lists.list_mut(size_id, matrix_id);
+                let mut next = 8;
+                if let Some(dc) = dc {
+                    next = r.se()? + 8;
+                    if !(1..=255).contains(&next) {
+                        return Err(VideoError::Malformed("scaling_list_dc_coef_minus8"));
+                    }
+                    *dc = next as u8;
+                }
+                for entry in list {
+                    next = (next + r.se()?).rem_euclid(256);
+                    *entry = next as u8;
+                }
+            }
+        }
+        Ok(lists)
+    }
+
+    pub fn to_std(&self) -> StdVideoH265ScalingLists {
+        StdVideoH265ScalingLists {
+            ScalingList4x4: self.lists_4x4,
+            ScalingList8x8: self.lists_8x8,
+            ScalingList16x16: self.lists_16x16,
+            ScalingList32x32: self.lists_32x32,
+            ScalingListDCCoef16x16: self.dc_16x16,
+            ScalingListDCCoef32x32: self.dc_32x32,
+        }
+    }
+}
+
+/// A short term reference picture set, `st_ref_pic_set`, and the deltas it derives to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShortTermRps {
+    pub inter_ref_pic_set_prediction: bool,
+    pub delta_idx_minus1: u32,
+    pub delta_rps_sign: bool,
+    pub abs_delta_rps_minus1: u32,
+    /// `used_by_curr_pic_flag` of each entry of the set predicted from, bit `j` for entry `j`
+    pub used_by_curr_pic_flags: u32,
+    /// `use_delta_flag` likewise, set where not coded
+    pub use_delta_flags: u32,
+    /// `DeltaPocS0` and `UsedByCurrPicS0`, closest first
+    pub negative: Vec<(i32, bool)>,
+    /// `DeltaPocS1` and `UsedByCurrPicS1`, closest first
+    pub positive: Vec<(i32, bool)>,
+}
+
+impl ShortTermRps {
+    /// Set number `sets.len()`, predicted from one of `sets` when coded that way
+    ///
+    /// A slice header passes every set of its SPS and codes which it predicts from.
+    fn parse(
+        r: &mut BitReader,
+        sets: &[ShortTermRps],
+        in_slice_header: bool,
+    ) -> Result<Self, VideoError> {
+        let mut rps = ShortTermRps::default();
+        if !sets.is_empty() {
+            rps.inter_ref_pic_set_prediction = r.flag()?;
+        }
+        if !rps.inter_ref_pic_set_prediction {
+            let negatives = r.ue()?;
+            let positives = r.ue()?;
+            if negatives > 16 || positives > 16 - negatives {
+                return Err(VideoError::Malformed("more than 16 short term references"));
+            }
+            for (count, sign, list) in [
+                (negatives, -1, &mut rps.negative),
+                (positives, 1, &mut rps.positive),
+            ] {
+                let mut delta = 0;
+                for _ in 0..count {
+                    let step = r.ue()?;
+                    if step > 1 << 15 {
+                        return Err(VideoError::Malformed("delta_poc_minus1 above 32767"));
+                    }
+                    delta += sign * (step as i32 + 1);
+                    list.push((delta, r.flag()?));
+                }
+            }
+            return Ok(rps);
+        }
+
+        if in_slice_header {
+            rps.delta_idx_minus1 = r.ue()?;
+        }
+        let reference = (sets.len())
+            .checked_sub(rps.delta_idx_minus1 as usize + 1)
+            .map(|index| &sets[index])
+            .ok_or(VideoError::Malformed("delta_idx_minus1"))?;
+        rps.delta_rps_sign = r.flag()?;
+        rps.abs_delta_rps_minus1 = r.ue()?;
+        if rps.abs_delta_rps_minus1 >= 1 << 15 {
+            return Err(VideoError::Malformed("abs_delta_rps_minus1 above 32767"));
+        }
+        let count = reference.negative.len() + reference.positive.len();
+        let mut used = [false; 17];
+        let mut use_delta = [true; 17];
+        for j in 0..=count {
+            used[j] = r.flag()?;
+            if !used[j] {
+                use_delta[j] = r.flag()?;
+            }
+            rps.used_by_curr_pic_flags |= (used[j] as u32) << j;
+            rps.use_delta_flags |= (use_delta[j] as u32) << j;
+        }
+        let delta_rps = (1 - 2 * rps.delta_rps_sign as i32) * (rps.abs_delta_rps_minus1 as i32 + 1);
+        // Each entry of the set predicted from moved by `delta_rps`, and `delta_rps` itself, entry
+        // `count` of the flags; walked so each list comes out closest first, equations 7-61, 7-62
+        let shifted = |list: &[(i32, bool)], first: usize| -> Vec<(i32, usize)> {
+            list.iter()
+                .enumerate()
+                .map(|(j, &(delta, _))| (delta + delta_rps, first + j))
+                .collect()
+        };
+        let negative = shifted(&reference.negative, 0);
+        let positive = shifted(&reference.positive, reference.negative.len());
+        let itself = (delta_rps, count);
+        let pick = |&(delta, j): &(i32, usize)| use_delta[j].then_some((delta, used[j]));
+        rps.negative = positive
+            .iter()
+            .rev()
+            .chain([&itself])
+            .chain(&negative)
+            .filter(|&&(delta, _)| delta < 0)
+            .filter_map(pick)
+            .collect();
+        rps.positive = negative
+            .iter()
+            .rev()
+            .chain([&itself])
+            .chain(&positive)
+            .filter(|&&(delta, _)| delta > 0)
+            .filter_map(pick)
+            .collect();
+        if rps.negative.len() + rps.positive.len() > 16 {
+            return Err(VideoError::Malformed("more than 16 short term references"));
+        }
+        Ok(rps)
+    }
+
+    pub fn to_std(&self) -> StdVideoH265ShortTermRefPicSet {
+        let mut flags: StdVideoH265ShortTermRefPicSetFlags = unsafe { std::mem::zeroed() };
+        flags.set_inter_ref_pic_set_prediction_flag(self.inter_ref_pic_set_prediction as u32);
+        flags.set_delta_rps_sign(self.delta_rps_sign as u32);
+        let mut std = StdVideoH265ShortTermRefPicSet {
+            flags,
+            delta_idx_minus1: self.delta_idx_minus1,
+            use_delta_flag: self.use_delta_flags as u16,
+            abs_delta_rps_minus1: self.abs_delta_rps_minus1 as u16,
+            used_by_curr_pic_flag: self.used_by_curr_pic_flags as u16,
+            used_by_curr_pic_s0_flag: 0,
+            used_by_curr_pic_s1_flag: 0,
+            reserved1: 0,
+            reserved2: 0,
+            reserved3: 0,
+            num_negative_pics: self.negative.len() as u8,
+            num_positive_pics: self.positive.len() as u8,
+            delta_poc_s0_minus1: [0; 16],
+            delta_poc_s1_minus1: [0; 16],
+        };
+        // Derived sets are given in the explicit form, each delta from the one before
+        let mut last = 0;
+        for (i, &(delta, used)) in self.negative.iter().enumerate() {
+            std.delta_poc_s0_minus1[i] = (last - delta - 1) as u16;
+            std.used_by_curr_pic_s0_flag |= (used as u16) << i;
+            last = delta;
+        }
+        let mut last = 0;
+        for (i, &(delta, used)) in self.positive.iter().enumerate() {
+            std.delta_poc_s1_minus1[i] = (delta - last - 1) as u16;
+            std.used_by_curr_pic_s1_flag |= (used as u16) << i;
+            last = delta;
+        }
+        std
+    }
+}
+
+/// PCM sample coding parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcm {
+    pub sample_bit_depth_luma_minus1: u8,
+    pub sample_bit_depth_chroma_minus1: u8,
+    pub log2_min_coding_block_size_minus3: u8,
+    pub log2_diff_max_min_coding_block_size: u8,
+    pub loop_filter_disabled: bool,
+}
+
+/// Sequence parameter set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sps {
+    pub vps_id: u8,
+    pub max_sub_layers_minus1: u8,
+    pub temporal_id_nesting: bool,
+    pub profile_tier_level: ProfileTierLevel,
+    pub id: u8,
+    pub chroma_format_idc: u8,
+    pub separate_colour_plane: bool,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    /// Left, right, top and bottom, in chroma samples
+    pub conformance_window: Option<[u32; 4]>,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub log2_max_pic_order_cnt_lsb_minus4: u8,
+    pub sub_layer_ordering_info_present: bool,
+    pub sub_layer_ordering: Vec<SubLayerOrdering>,
+    pub log2_min_luma_coding_block_size_minus3: u8,
+    pub log2_diff_max_min_luma_coding_block_size: u8,
+    pub log2_min_luma_transform_block_size_minus2: u8,
+    pub log2_diff_max_min_luma_transform_block_size: u8,
+    pub max_transform_hierarchy_depth_inter: u8,
+    pub max_transform_hierarchy_depth_intra: u8,
+    pub scaling_list_enabled: bool,
+    /// Lists sent in the SPS, [`ScalingLists::DEFAULT`] applies when enabled without them
+    pub scaling_lists: Option<ScalingLists>,
+    pub amp_enabled: bool,
+    pub sample_adaptive_offset_enabled: bool,
+    pub pcm: Option<Pcm>,
+    pub short_term_ref_pic_sets: Vec<ShortTermRps>,
+    /// `lt_ref_pic_poc_lsb_sps` and `used_by_curr_pic_lt_sps_flag` of each, `None` when long
+    /// term references aren't used
+    pub long_term_ref_pics: Option<Vec<(u32, bool)>>,
+    pub temporal_mvp_enabled: bool,
+    pub strong_intra_smoothing_enabled: bool,
+    pub vui: Vui,
+    /// The nine `sps_range_extension` flags in syntax order, the first in the lowest bit
+    pub range_extension: Option<u16>,
+}
+
+/// An SPS in its Vulkan form, with the structs it points to kept alongside
+pub struct StdSps {
+    pub set: StdVideoH265SequenceParameterSet,
+    _profile_tier_level: Box<StdVideoH265ProfileTierLevel>,
+    _dec_pic_buf_mgr: Box<StdVideoH265DecPicBufMgr>,
+    _scaling_lists: Option<Box<StdVideoH265ScalingLists>>,
+    _short_term_ref_pic_sets: Vec<StdVideoH265ShortTermRefPicSet>,
+    _long_term_ref_pics: Box<StdVideoH265LongTermRefPicsSps>,
+}
+
+impl Sps {
+    pub fn parse(rbsp: &[u8]) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let vps_id = r.bits(4)? as u8;
+        let max_sub_layers_minus1 = max_sub_layers_minus1(r)?;
+        let temporal_id_nesting = r.flag()?;
+        let profile_tier_level = ProfileTierLevel::parse(r, max_sub_layers_minus1)?;
+        let id = r.ue()?;
+        if id > 15 {
+            return Err(VideoError::Malformed("sps_seq_parameter_set_id above 15"));
+        }
+        let chroma_format_idc = r.ue()? as u8;
+        let separate_colour_plane = chroma_format_idc == 3 && r.flag()?;
+        let pic_width_in_luma_samples = r.ue()?;
+        let pic_height_in_luma_samples = r.ue()?;
+        let conformance_window = if r.flag()? {
+            Some([r.ue()?, r.ue()?, r.ue()?, r.ue()?])
+        } else {
+            None
+        };
+        let bit_depth_luma_minus8 = r.ue()? as u8;
+        let bit_depth_chroma_minus8 = r.ue()? as u8;
+        let log2_max_pic_order_cnt_lsb_minus4 = r.ue()?;
+        if log2_max_pic_order_cnt_lsb_minus4 > 12 {
+            return Err(VideoError::Malformed(
+                "log2_max_pic_order_cnt_lsb_minus4 above 12",
+            ));
+        }
+        let sub_layer_ordering_info_present = r.flag()?;
+        let sub_layer_ordering =
+            parse_ordering(r, max_sub_layers_minus1, sub_layer_ordering_info_present)?;
+        let mut sps = Sps {
+            vps_id,
+            max_sub_layers_minus1,
+            temporal_id_nesting,
+            profile_tier_level,
+            id: id as u8,
+            chroma_format_idc,
+            separate_colour_plane,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            log2_max_pic_order_cnt_lsb_minus4: log2_max_pic_order_cnt_lsb_minus4 as u8,
+            sub_layer_ordering_info_present,
+            sub_layer_ordering,
+            log2_min_luma_coding_block_size_minus3: r.ue()? as u8,
+            log2_diff_max_min_luma_coding_block_size: r.ue()? as u8,
+            log2_min_luma_transform_block_size_minus2: r.ue()? as u8,
+            log2_diff_max_min_luma_transform_block_size: r.ue()? as u8,
+            max_transform_hierarchy_depth_inter: r.ue()? as u8,
+            max_transform_hierarchy_depth_intra: r.ue()? as u8,
+            scaling_list_enabled: r.flag()?,
+            scaling_lists: None,
+            amp_enabled: false,
+            sample_adaptive_offset_enabled: false,
+            pcm: None,
+            short_term_ref_pic_sets: Vec::new(),
+            long_term_ref_pics: None,
+            temporal_mvp_enabled: false,
+            strong_intra_smoothing_enabled: false,
+            vui: Vui {
+                matrix_coefficients: 2,
+                ..Vui::default()
+            },
+            range_extension: None,
+        };
+        if sps.scaling_list_enabled && r.flag()? {
+            sps.scaling_lists = Some(ScalingLists::parse(r)?);
+        }
+        sps.amp_enabled = r.flag()?;
+        sps.sample_adaptive_offset_enabled = r.flag()?;
+        if r.flag()? {
+            sps.pcm = Some(Pcm {
+                sample_bit_depth_luma_minus1: r.bits(4)? as u8,
+                sample_bit_depth_chroma_minus1: r.bits(4)? as u8,
+                log2_min_coding_block_size_minus3: r.ue()? as u8,
+                log2_diff_max_min_coding_block_size: r.ue()? as u8,
+                loop_filter_disabled: r.flag()?,
+            });
+        }
+        let num_short_term_ref_pic_sets = r.ue()?;
+        if num_short_term_ref_pic_sets > 64 {
+            return Err(VideoError::Malformed(
+                "more than 64 short term reference sets",
+            ));
+        }
+        for _ in 0..num_short_term_ref_pic_sets {
+            let rps = ShortTermRps::parse(r, &sps.short_term_ref_pic_sets, false)?;
+            sps.short_term_ref_pic_sets.push(rps);
+        }
+        if r.flag()? {
+            let count = r.ue()?;
+            if count > 32 {
+                return Err(VideoError::Malformed("more than 32 long term references"));
+            }
+            let lsb_bits = sps.log2_max_pic_order_cnt_lsb_minus4 as u32 + 4;
+            let pics = (0..count)
+                .map(|_| Ok((r.bits(lsb_bits)?, r.flag()?)))
+                .collect::<Result<_, VideoError>>()?;
+            sps.long_term_ref_pics = Some(pics);
+        }
+        sps.temporal_mvp_enabled = r.flag()?;
+        sps.strong_intra_smoothing_enabled = r.flag()?;
+        if r.flag()? {
+            sps.vui = parse_vui(r, max_sub_layers_minus1)?;
+        }
+        // sps_extension_present_flag
+        if r.flag()? {
+            let range = r.flag()?;
+            // sps_multilayer_extension_flag, sps_3d_extension_flag
+            r.bits(2)?;
+            if r.flag()? {
+                return Err(VideoError::Unsupported("screen content coding extensions"));
+            }
+            // sps_extension_4bits
+            r.bits(4)?;
+            if range {
+                sps.range_extension = Some((r.bits(9)?.reverse_bits() >> 23) as u16);
+            }
+        }
+        Ok(sps)
+    }
+
+    pub fn max_pic_order_cnt_lsb(&self) -> u32 {
+        1 << (self.log2_max_pic_order_cnt_lsb_minus4 + 4)
+    }
+
+    /// Buffering limits of the highest sub-layer, which everything is decoded up to
+    pub fn highest_ordering(&self) -> SubLayerOrdering {
+        self.sub_layer_ordering[self.sub_layer_ordering.len() - 1]
+    }
+
+    /// Size of the decoded pictures, before the conformance window
+    pub fn coded_extent(&self) -> (u32, u32) {
+        (
+            self.pic_width_in_luma_samples,
+            self.pic_height_in_luma_samples,
+        )
+    }
+
+    /// The part of the decoded picture to show, the conformance window
+    pub fn display_rect(&self) -> vk::Rect2D {
+        let (width, height) = self.coded_extent();
+        let [left, right, top, bottom] = self.conformance_window.unwrap_or_default();
+        let (unit_x, unit_y) = match self.chroma_format_idc {
+            _ if self.separate_colour_plane => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: (left * unit_x) as i32,
+                y: (top * unit_y) as i32,
+            },
+            extent: vk::Extent2D {
+                width: width.saturating_sub((left + right) * unit_x),
+                height: height.saturating_sub((top + bottom) * unit_y),
+            },
+        }
+    }
+
+    /// `StdVideoH265LevelIdc` of the stream
+    pub fn std_level_idc(&self) -> u32 {
+        self.profile_tier_level.std_level_idc()
+    }
+
+    pub fn to_std(&self) -> StdSps {
+        let profile_tier_level = Box::new(self.profile_tier_level.to_std());
+        let dec_pic_buf_mgr = Box::new(dec_pic_buf_mgr(&self.sub_layer_ordering));
+        let scaling_lists = self.scaling_list_enabled.then(|| {
+            Box::new(
+                self.scaling_lists
+                    .as_ref()
+                    .unwrap_or(&ScalingLists::DEFAULT)
+                    .to_std(),
+            )
+        });
+        let short_term_ref_pic_sets: Vec<_> = self
+            .short_term_ref_pic_sets
+            .iter()
+            .map(ShortTermRps::to_std)
+            .collect();
+        let long_term = self.long_term_ref_pics.as_deref().unwrap_or_default();
+        let mut long_term_ref_pics = Box::new(StdVideoH265LongTermRefPicsSps {
+            used_by_curr_pic_lt_sps_flag: 0,
+            lt_ref_pic_poc_lsb_sps: [0; 32],
+        });
+        for (i, &(poc_lsb, used)) in long_term.iter().enumerate() {
+            long_term_ref_pics.lt_ref_pic_poc_lsb_sps[i] = poc_lsb;
+            long_term_ref_pics.used_by_curr_pic_lt_sps_flag |= (used as u32) << i;
+        }
+
+        let mut flags: StdVideoH265SpsFlags = unsafe { std::mem::zeroed() };
+        flags.set_sps_temporal_id_nesting_flag(self.temporal_id_nesting as u32);
+        flags.set_separate_colour_plane_flag(self.separate_colour_plane as u32);
+        flags.set_conformance_window_flag(self.conformance_window.is_some() as u32);
+        flags.set_sps_sub_layer_ordering_info_present_flag(
+            self.sub_layer_ordering_info_present as u32,
+        );
+        flags.set_scaling_list_enabled_flag(self.scaling_list_enabled as u32);
+        flags.set_sps_scaling_list_data_present_flag(self.scaling_lists.is_some() as u32);
+        flags.set_amp_enabled_flag(self.amp_enabled as u32);
+        flags.set_sample_adaptive_offset_enabled_flag(self.sample_adaptive_offset_enabled as u32);
+        flags.set_pcm_enabled_flag(self.pcm.is_some() as u32);
+        flags.set_pcm_loop_filter_disabled_flag(
+            self.pcm.is_some_and(|pcm| pcm.loop_filter_disabled) as u32,
+        );
+        flags.set_long_term_ref_pics_present_flag(self.long_term_ref_pics.is_some() as u32);
+        flags.set_sps_temporal_mvp_enabled_flag(self.temporal_mvp_enabled as u32);
+        flags.set_strong_intra_smoothing_enabled_flag(self.strong_intra_smoothing_enabled as u32);
+        flags.set_sps_extension_present_flag(self.range_extension.is_some() as u32);
+        flags.set_sps_range_extension_flag(self.range_extension.is_some() as u32);
+        let range = self.range_extension.unwrap_or_default();
+        for (bit, set) in [
+            (
+                0,
+                StdVideoH265SpsFlags::set_transform_skip_rotation_enabled_flag as fn(&mut _, u32),
+            ),
+            (
+                1,
+                StdVideoH265SpsFlags::set_transform_skip_context_enabled_flag,
+            ),
+            (2, StdVideoH265SpsFlags::set_implicit_rdpcm_enabled_flag),
+            (3, StdVideoH265SpsFlags::set_explicit_rdpcm_enabled_flag),
+            (
+                4,
+                StdVideoH265SpsFlags::set_extended_precision_processing_flag,
+            ),
+            (5, StdVideoH265SpsFlags::set_intra_smoothing_disabled_flag),
+            (
+                6,
+                StdVideoH265SpsFlags::set_high_precision_offsets_enabled_flag,
+            ),
+            (
+                7,
+                StdVideoH265SpsFlags::set_persistent_rice_adaptation_enabled_flag,
+            ),
+            (
+                8,
+                StdVideoH265SpsFlags::set_cabac_bypass_alignment_enabled_flag,
+            ),
+        ] {
+            set(&mut flags, (range >> bit & 1) as u32);
+        }
+        let pcm = self.pcm.unwrap_or(Pcm {
+            sample_bit_depth_luma_minus1: 0,
+            sample_bit_depth_chroma_minus1: 0,
+            log2_min_coding_block_size_minus3: 0,
+            log2_diff_max_min_coding_block_size: 0,
+            loop_filter_disabled: false,
+        });
+        let [left, right, top, bottom] = self.conformance_window.unwrap_or_default();
+        StdSps {
+            set: StdVideoH265SequenceParameterSet {
+                flags,
+                chroma_format_idc: self.chroma_format_idc as u32,
+                pic_width_in_luma_samples: self.pic_width_in_luma_samples,
+                pic_height_in_luma_samples: self.pic_height_in_luma_samples,
+                sps_video_parameter_set_id: self.vps_id,
+                sps_max_sub_layers_minus1: self.max_sub_layers_minus1,
+                sps_seq_parameter_set_id: self.id,
+                bit_depth_luma_minus8: self.bit_depth_luma_minus8,
+                bit_depth_chroma_minus8: self.bit_depth_chroma_minus8,
+                log2_max_pic_order_cnt_lsb_minus4: self.log2_max_pic_order_cnt_lsb_minus4,
+                log2_min_luma_coding_block_size_minus3: self.log2_min_luma_coding_block_size_minus3,
+                log2_diff_max_min_luma_coding_block_size: self
+                    .log2_diff_max_min_luma_coding_block_size,
+                log2_min_luma_transform_block_size_minus2: self
+                    .log2_min_luma_transform_block_size_minus2,
+                log2_diff_max_min_luma_transform_block_size: self
+                    .log2_diff_max_min_luma_transform_block_size,
+                max_transform_hierarchy_depth_inter: self.max_transform_hierarchy_depth_inter,
+                max_transform_hierarchy_depth_intra: self.max_transform_hierarchy_depth_intra,
+                num_short_term_ref_pic_sets: short_term_ref_pic_sets.len() as u8,
+                num_long_term_ref_pics_sps: long_term.len() as u8,
+                pcm_sample_bit_depth_luma_minus1: pcm.sample_bit_depth_luma_minus1,
+                pcm_sample_bit_depth_chroma_minus1: pcm.sample_bit_depth_chroma_minus1,
+                log2_min_pcm_luma_coding_block_size_minus3: pcm.log2_min_coding_block_size_minus3,
+                log2_diff_max_min_pcm_luma_coding_block_size: pcm
+                    .log2_diff_max_min_coding_block_size,
+                reserved1: 0,
+                reserved2: 0,
+                palette_max_size: 0,
+                delta_palette_max_predictor_size: 0,
+                motion_vector_resolution_control_idc: 0,
+                sps_num_palette_predictor_initializers_minus1: 0,
+                conf_win_left_offset: left,
+                conf_win_right_offset: right,
+                conf_win_top_offset: top,
+                conf_win_bottom_offset: bottom,
+                pProfileTierLevel: &*profile_tier_level,
+                pDecPicBufMgr: &*dec_pic_buf_mgr,
+                pScalingLists: scaling_lists
+                    .as_deref()
+                    .map_or(std::ptr::null(), |lists| lists as *const _),
+                pShortTermRefPicSet: short_term_ref_pic_sets.as_ptr(),
+                pLongTermRefPicsSps: &*long_term_ref_pics,
+                // The VUI isn't needed for decoding
+                pSequenceParameterSetVui: std::ptr::null(),
+                pPredictorPaletteEntries: std::ptr::null(),
+            },
+            _profile_tier_level: profile_tier_level,
+            _dec_pic_buf_mgr: dec_pic_buf_mgr,
+            _scaling_lists: scaling_lists,
+            _short_term_ref_pic_sets: short_term_ref_pic_sets,
+            _long_term_ref_pics: long_term_ref_pics,
+        }
+    }
+}
+
+/// `vui_parameters`, keeping what [`Vui`] holds
+fn parse_vui(r: &mut BitReader, max_sub_layers_minus1: u8) -> Result<Vui, VideoError> {
+    let mut vui = Vui {
+        matrix_coefficients: 2,
+        ..Vui::default()
+    };
+    // aspect_ratio_info_present_flag, EXTENDED_SAR carries an explicit ratio
+    if r.flag()? && r.bits(8)? == 255 {
+        r.bits(32)?;
+    }
+    // overscan_info_present_flag
+    if r.flag()? {
+        r.flag()?;
+    }
+    // video_signal_type_present_flag
+    if r.flag()? {
+        r.bits(3)?;
+        vui.full_range = r.flag()?;
+        if r.flag()? {
+            r.bits(16)?;
+            vui.matrix_coefficients = r.bits(8)? as u8;
+        }
+    }
+    // chroma_loc_info_present_flag
+    if r.flag()? {
+        r.ue()?;
+        r.ue()?;
+    }
+    // neutral_chroma_indication_flag, then field_seq_flag
+    r.flag()?;
+    if r.flag()? {
+        return Err(VideoError::Unsupported("field pictures"));
+    }
+    // frame_field_info_present_flag
+    r.flag()?;
+    // default_display_window_flag
+    if r.flag()? {
+        for _ in 0..4 {
+            r.ue()?;
+        }
+    }
+    // vui_timing_info_present_flag
+    if r.flag()? {
+        let num_units_in_tick = r.bits(32)?;
+        let time_scale = r.bits(32)?;
+        if num_units_in_tick > 0 {
+            vui.frame_rate = Some(time_scale as f64 / num_units_in_tick as f64);
+        }
+        // vui_poc_proportional_to_timing_flag
+        if r.flag()? {
+            r.ue()?;
+        }
+        // vui_hrd_parameters_present_flag
+        if r.flag()? {
+            skip_hrd(r, max_sub_layers_minus1)?;
+        }
+    }
+    // bitstream_restriction_flag
+    if r.flag()? {
+        r.bits(3)?;
+        for _ in 0..5 {
+            r.ue()?;
+        }
+    }
+    Ok(vui)
+}
+
+/// `hrd_parameters` with the common information
+fn skip_hrd(r: &mut BitReader, max_sub_layers_minus1: u8) -> Result<(), VideoError> {
+    let nal = r.flag()?;
+    let vcl = r.flag()?;
+    let mut sub_pic = false;
+    if nal || vcl {
+        sub_pic = r.flag()?;
+        if sub_pic {
+            r.bits(19)?;
+        }
+        // bit_rate_scale, cpb_size_scale and cpb_size_du_scale
+        r.bits(if sub_pic { 12 } else { 8 })?;
+        // The lengths of the delays
+        r.bits(15)?;
+    }
+    for _ in 0..=max_sub_layers_minus1 {
+        let fixed_general = r.flag()?;
+        let fixed_within_cvs = fixed_general || r.flag()?;
+        let mut low_delay = false;
+        if fixed_within_cvs {
+            r.ue()?;
+        } else {
+            low_delay = r.flag()?;
+        }
+        let cpb_count = if low_delay { 1 } else { r.ue()? + 1 };
+        if cpb_count > 32 {
+            return Err(VideoError::Malformed("cpb_cnt_minus1 above 31"));
+        }
+        for _ in 0..nal as u32 + vcl as u32 {
+            for _ in 0..cpb_count {
+                for _ in 0..if sub_pic { 4 } else { 2 } {
+                    r.ue()?;
+                }
+                // cbr_flag
+                r.flag()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tile columns and rows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tiles {
+    pub num_tile_columns_minus1: u8,
+    pub num_tile_rows_minus1: u8,
+    /// `column_width_minus1` and `row_height_minus1`, empty with uniform spacing
+    pub column_width_minus1: Vec<u16>,
+    pub row_height_minus1: Vec<u16>,
+    pub uniform_spacing: bool,
+    pub loop_filter_across_tiles_enabled: bool,
+}
+
+/// Deblocking filter controls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deblocking {
+    pub override_enabled: bool,
+    pub disabled: bool,
+    pub beta_offset_div2: i8,
+    pub tc_offset_div2: i8,
+}
+
+/// `pps_range_extension`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpsRangeExtension {
+    pub log2_max_transform_skip_block_size_minus2: u8,
+    pub cross_component_prediction_enabled: bool,
+    /// `diff_cu_chroma_qp_offset_depth` and the Cb and Cr offsets, when the list is enabled
+    pub chroma_qp_offset_list: Option<(u8, Vec<(i8, i8)>)>,
+    pub log2_sao_offset_scale_luma: u8,
+    pub log2_sao_offset_scale_chroma: u8,
+}
+
+/// Picture parameter set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pps {
+    pub id: u8,
+    pub sps_id: u8,
+    pub dependent_slice_segments_enabled: bool,
+    pub output_flag_present: bool,
+    pub num_extra_slice_header_bits: u8,
+    pub sign_data_hiding_enabled: bool,
+    pub cabac_init_present: bool,
+    pub num_ref_idx_l0_default_active_minus1: u8,
+    pub num_ref_idx_l1_default_active_minus1: u8,
+    pub init_qp_minus26: i8,
+    pub constrained_intra_pred: bool,
+    pub transform_skip_enabled: bool,
+    /// `diff_cu_qp_delta_depth`, `None` without `cu_qp_delta_enabled_flag`
+    pub cu_qp_delta_depth: Option<u8>,
+    pub cb_qp_offset: i8,
+    pub cr_qp_offset: i8,
+    pub slice_chroma_qp_offsets_present: bool,
+    pub weighted_pred: bool,
+    pub weighted_bipred: bool,
+    pub transquant_bypass_enabled: bool,
+    pub tiles: Option<Tiles>,
+    pub entropy_coding_sync_enabled: bool,
+    pub loop_filter_across_slices_enabled: bool,
+    pub deblocking: Option<Deblocking>,
+    pub scaling_lists: Option<ScalingLists>,
+    pub lists_modification_present: bool,
+    pub log2_parallel_merge_level_minus2: u8,
+    pub slice_segment_header_extension_present: bool,
+    pub range_extension: Option<PpsRangeExtension>,
+}
+
+/// A PPS in its Vulkan form, with the scaling lists it points to kept alongside
+pub struct StdPps {
+    pub set: StdVideoH265PictureParameterSet,
+    _scaling_lists: Option<Box<StdVideoH265ScalingLists>>,
+}
+
+impl Pps {
+    pub fn parse(rbsp: &[u8]) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let id = r.ue()?;
+        let sps_id = r.ue()?;
+        if id > 63 || sps_id > 15 {
+            return Err(VideoError::Malformed("parameter set id out of range"));
+        }
+        let mut pps = Pps {
+            id: id as u8,
+            sps_id: sps_id as u8,
+            dependent_slice_segments_enabled: r.flag()?,
+            output_flag_present: r.flag()?,
+            num_extra_slice_header_bits: r.bits(3)? as u8,
+            sign_data_hiding_enabled: r.flag()?,
+            cabac_init_present: r.flag()?,
+            num_ref_idx_l0_default_active_minus1: r.ue()? as u8,
+            num_ref_idx_l1_default_active_minus1: r.ue()? as u8,
+            init_qp_minus26: r.se()? as i8,
+            constrained_intra_pred: r.flag()?,
+            transform_skip_enabled: r.flag()?,
+            cu_qp_delta_depth: None,
+            cb_qp_offset: 0,
+            cr_qp_offset: 0,
+            slice_chroma_qp_offsets_present: false,
+            weighted_pred: false,
+            weighted_bipred: false,
+            transquant_bypass_enabled: false,
+            tiles: None,
+            entropy_coding_sync_enabled: false,
+            loop_filter_across_slices_enabled: false,
+            deblocking: None,
+            scaling_lists: None,
+            lists_modification_present: false,
+            log2_parallel_merge_level_minus2: 0,
+            slice_segment_header_extension_present: false,
+            range_extension: None,
+        };
+        if r.flag()? {
+            pps.cu_qp_delta_depth = Some(r.ue()? as u8);
+        }
+        pps.cb_qp_offset = r.se()? as i8;
+        pps.cr_qp_offset = r.se()? as i8;
+        pps.slice_chroma_qp_offsets_present = r.flag()?;
+        pps.weighted_pred = r.flag()?;
+        pps.weighted_bipred = r.flag()?;
+        pps.transquant_bypass_enabled = r.flag()?;
+        let tiles_enabled = r.flag()?;
+        pps.entropy_coding_sync_enabled = r.flag()?;
+        if tiles_enabled {
+            let columns = r.ue()?;
+            let rows = r.ue()?;
+            // The sizes Vulkan has room for, more than any level allows
+            if columns > 19 || rows > 21 {
+                return Err(VideoError::Malformed("too many tiles"));
+            }
+            let uniform_spacing = r.flag()?;
+            let mut sizes = |count| {
+                (0..if uniform_spacing { 0 } else { count })
+                    .map(|_| Ok(r.ue()? as u16))
+                    .collect::<Result<Vec<_>, VideoError>>()
+            };
+            let column_width_minus1 = sizes(columns)?;
+            let row_height_minus1 = sizes(rows)?;
+            pps.tiles = Some(Tiles {
+                num_tile_columns_minus1: columns as u8,
+                num_tile_rows_minus1: rows as u8,
+                column_width_minus1,
+                row_height_minus1,
+                uniform_spacing,
+                loop_filter_across_tiles_enabled: r.flag()?,
+            });
+        }
+        pps.loop_filter_across_slices_enabled = r.flag()?;
+        if r.flag()? {
+            let override_enabled = r.flag()?;
+            let disabled = r.flag()?;
+            let (beta_offset_div2, tc_offset_div2) = if disabled {
+                (0, 0)
+            } else {
+                (r.se()? as i8, r.se()? as i8)
+            };
+            pps.deblocking = Some(Deblocking {
+                override_enabled,
+                disabled,
+                beta_offset_div2,
+                tc_offset_div2,
+            });
+        }
+        if r.flag()? {
+            pps.scaling_lists = Some(ScalingLists::parse(r)?);
+        }
+        pps.lists_modification_present = r.flag()?;
+        pps.log2_parallel_merge_level_minus2 = r.ue()? as u8;
+        pps.slice_segment_header_extension_present = r.flag()?;
+        // pps_extension_present_flag
+        if r.flag()? {
+            let range = r.flag()?;
+            // pps_multilayer_extension_flag, pps_3d_extension_flag
+            r.bits(2)?;
+            if r.flag()? {
+                return Err(VideoError::Unsupported("screen content coding extensions"));
+            }
+            // pps_extension_4bits
+            r.bits(4)?;
+            if range {
+                let log2_max_transform_skip_block_size_minus2 = if pps.transform_skip_enabled {
+                    r.ue()? as u8
+                } else {
+                    0
+                };
+                let cross_component_prediction_enabled = r.flag()?;
+                let mut chroma_qp_offset_list = None;
+                if r.flag()? {
+                    let depth = r.ue()? as u8;
+                    let len = r.ue()? + 1;
+                    if len > 6 {
+                        return Err(VideoError::Malformed("chroma_qp_offset_list_len_minus1"));
+                    }
+                    let offsets = (0..len)
+                        .map(|_| Ok((r.se()? as i8, r.se()? as i8)))
+                        .collect::<Result<_, VideoError>>()?;
+                    chroma_qp_offset_list = Some((depth, offsets));
+                }
+                pps.range_extension = Some(PpsRangeExtension {
+                    log2_max_transform_skip_block_size_minus2,
+                    cross_component_prediction_enabled,
+                    chroma_qp_offset_list,
+                    log2_sao_offset_scale_luma: r.ue()? as u8,
+                    log2_sao_offset_scale_chroma: r.ue()? as u8,
+                });
+            }
+        }
+        Ok(pps)
+    }
+
+    /// The Vulkan form, `vps_id` being the VPS of the SPS this set refers to
+    pub fn to_std(&self, vps_id: u8) -> StdPps {
+        let scaling_lists = self
+            .scaling_lists
+            .as_ref()
+            .map(|lists| Box::new(lists.to_std()));
+        let mut flags: StdVideoH265PpsFlags = unsafe { std::mem::zeroed() };
+        flags.set_dependent_slice_segments_enabled_flag(
+            self.dependent_slice_segments_enabled as u32,
+        );
+        flags.set_output_flag_present_flag(self.output_flag_present as u32);
+        flags.set_sign_data_hiding_enabled_flag(self.sign_data_hiding_enabled as u32);
+        flags.set_cabac_init_present_flag(self.cabac_init_present as u32);
+        flags.set_constrained_intra_pred_flag(self.constrained_intra_pred as u32);
+        flags.set_transform_skip_enabled_flag(self.transform_skip_enabled as u32);
+        flags.set_cu_qp_delta_enabled_flag(self.cu_qp_delta_depth.is_some() as u32);
+        flags.set_pps_slice_chroma_qp_offsets_present_flag(
+            self.slice_chroma_qp_offsets_present as u32,
+        );
+        flags.set_weighted_pred_flag(self.weighted_pred as u32);
+        flags.set_weighted_bipred_flag(self.weighted_bipred as u32);
+        flags.set_transquant_bypass_enabled_flag(self.transquant_bypass_enabled as u32);
+        flags.set_tiles_enabled_flag(self.tiles.is_some() as u32);
+        flags.set_entropy_coding_sync_enabled_flag(self.entropy_coding_sync_enabled as u32);
+        flags.set_pps_loop_filter_across_slices_enabled_flag(
+            self.loop_filter_across_slices_enabled as u32,
+        );
+        flags.set_pps_scaling_list_data_present_flag(self.scaling_lists.is_some() as u32);
+        flags.set_lists_modification_present_flag(self.lists_modification_present as u32);
+        flags.set_slice_segment_header_extension_present_flag(
+            self.slice_segment_header_extension_present as u32,
+        );
+        let mut set = StdVideoH265PictureParameterSet {
+            flags,
+            pps_pic_parameter_set_id: self.id,
+            pps_seq_parameter_set_id: self.sps_id,
+            sps_video_parameter_set_id: vps_id,
+            num_extra_slice_header_bits: self.num_extra_slice_header_bits,
+            num_ref_idx_l0_default_active_minus1: self.num_ref_idx_l0_default_active_minus1,
+            num_ref_idx_l1_default_active_minus1: self.num_ref_idx_l1_default_active_minus1,
+            init_qp_minus26: self.init_qp_minus26,
+            diff_cu_qp_delta_depth: self.cu_qp_delta_depth.unwrap_or_default(),
+            pps_cb_qp_offset: self.cb_qp_offset,
+            pps_cr_qp_offset: self.cr_qp_offset,
+            pps_beta_offset_div2: 0,
+            pps_tc_offset_div2: 0,
+            log2_parallel_merge_level_minus2: self.log2_parallel_merge_level_minus2,
+            log2_max_transform_skip_block_size_minus2: 0,
+            diff_cu_chroma_qp_offset_depth: 0,
+            chroma_qp_offset_list_len_minus1: 0,
+            cb_qp_offset_list: [0; 6],
+            cr_qp_offset_list: [0; 6],
+            log2_sao_offset_scale_luma: 0,
+            log2_sao_offset_scale_chroma: 0,
+            pps_act_y_qp_offset_plus5: 0,
+            pps_act_cb_qp_offset_plus5: 0,
+            pps_act_cr_qp_offset_plus3: 0,
+            pps_num_palette_predictor_initializers: 0,
+            luma_bit_depth_entry_minus8: 0,
+            chroma_bit_depth_entry_minus8: 0,
+            num_tile_columns_minus1: 0,
+            num_tile_rows_minus1: 0,
+            reserved1: 0,
+            reserved2: 0,
+            column_width_minus1: [0; 19],
+            row_height_minus1: [0; 21],
+            reserved3: 0,
+            pScalingLists: scaling_lists
+                .as_deref()
+                .map_or(std::ptr::null(), |lists| lists as *const _),
+            pPredictorPaletteEntries: std::ptr::null(),
+        };
+        if let Some(tiles) = &self.tiles {
+            set.flags
+                .set_uniform_spacing_flag(tiles.uniform_spacing as u32);
+            set.flags.set_loop_filter_across_tiles_enabled_flag(
+                tiles.loop_filter_across_tiles_enabled as u32,
+            );
+            set.num_tile_columns_minus1 = tiles.num_tile_columns_minus1;
+            set.num_tile_rows_minus1 = tiles.num_tile_rows_minus1;
+            set.column_width_minus1[..tiles.column_width_minus1.len()]
+                .copy_from_slice(&tiles.column_width_minus1);
+            set.row_height_minus1[..tiles.row_height_minus1.len()]
+                .copy_from_slice(&tiles.row_height_minus1);
+        }
+        if let Some(deblocking) = self.deblocking {
+            set.flags.set_deblocking_filter_control_present_flag(1);
+            set.flags
+                .set_deblocking_filter_override_enabled_flag(deblocking.override_enabled as u32);
+            set.flags
+                .set_pps_deblocking_filter_disabled_flag(deblocking.disabled as u32);
+            set.pps_beta_offset_div2 = deblocking.beta_offset_div2;
+            set.pps_tc_offset_div2 = deblocking.tc_offset_div2;
+        }
+        if let Some(range) = &self.range_extension {
+            set.flags.set_pps_extension_present_flag(1);
+            set.flags.set_pps_range_extension_flag(1);
+            set.flags.set_cross_component_prediction_enabled_flag(
+                range.cross_component_prediction_enabled as u32,
+            );
+            set.log2_max_transform_skip_block_size_minus2 =
+                range.log2_max_transform_skip_block_size_minus2;
+            if let Some((depth, offsets)) = &range.chroma_qp_offset_list {
+                set.flags.set_chroma_qp_offset_list_enabled_flag(1);
+                set.diff_cu_chroma_qp_offset_depth = *depth;
+                set.chroma_qp_offset_list_len_minus1 = offsets.len() as u8 - 1;
+                for (i, &(cb, cr)) in offsets.iter().enumerate() {
+                    set.cb_qp_offset_list[i] = cb;
+                    set.cr_qp_offset_list[i] = cr;
+                }
+            }
+            set.log2_sao_offset_scale_luma = range.log2_sao_offset_scale_luma;
+            set.log2_sao_offset_scale_chroma = range.log2_sao_offset_scale_chroma;
+        }
+        StdPps {
+            set,
+            _scaling_lists: scaling_lists,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceType {
+    B,
+    P,
+    I,
+}
+
+/// A long term reference picture named in a slice header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongTermRef {
+    /// `PocLsbLt`
+    pub poc_lsb: u32,
+    pub used_by_curr_pic: bool,
+    /// `DeltaPocMsbCycleLt`, when the most significant bits are sent
+    pub delta_poc_msb_cycle: Option<u32>,
+}
+
+/// The start of a slice segment header, up to the long term references
+///
+/// Only the first segment of a picture is read past its PPS id, later ones repeat what the
+/// decoder needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SliceHeader {
+    pub nal: NalHeader,
+    pub first_slice_segment_in_pic: bool,
+    pub no_output_of_prior_pics: bool,
+    pub pps_id: u8,
+    pub slice_type: SliceType,
+    pub pic_output: bool,
+    pub pic_order_cnt_lsb: u32,
+    /// The set coded in the header, `None` when one of the SPS's is picked
+    pub short_term_rps: Option<ShortTermRps>,
+    /// `short_term_ref_pic_set_idx`
+    pub short_term_rps_idx: u8,
+    /// `NumBitsForSTRefPicSetInSlice`, what the set coded in the header took
+    pub short_term_rps_bits: u32,
+    pub long_term: Vec<LongTermRef>,
+}
+
+/// Order counts of the pictures a picture keeps as references, clause 8.3.2
+///
+/// Long term pictures come with whether the whole count is known, otherwise only its LSBs are.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReferencePocs {
+    pub st_curr_before: Vec<i32>,
+    pub st_curr_after: Vec<i32>,
+    pub st_foll: Vec<i32>,
+    pub lt_curr: Vec<(i32, bool)>,
+    pub lt_foll: Vec<(i32, bool)>,
+}
+
+impl SliceHeader {
+    pub fn parse(
+        nal: NalHeader,
+        rbsp: &[u8],
+        sps: &BTreeMap<u8, Sps>,
+        pps: &BTreeMap<u8, Pps>,
+    ) -> Result<Self, VideoError> {
+        let r = &mut BitReader::new(rbsp);
+        let first_slice_segment_in_pic = r.flag()?;
+        let no_output_of_prior_pics = nal.is_irap() && r.flag()?;
+        let pps_id = r.ue()? as u8;
+        let mut header = SliceHeader {
+            nal,
+            first_slice_segment_in_pic,
+            no_output_of_prior_pics,
+            pps_id,
+            slice_type: SliceType::I,
+            pic_output: true,
+            pic_order_cnt_lsb: 0,
+            short_term_rps: None,
+            short_term_rps_idx: 0,
+            short_term_rps_bits: 0,
+            long_term: Vec::new(),
+        };
+        if !first_slice_segment_in_pic {
+            return Ok(header);
+        }
+        let pps = pps.get(&pps_id).ok_or(VideoError::MissingParameterSet)?;
+        let sps = sps
+            .get(&pps.sps_id)
+            .ok_or(VideoError::MissingParameterSet)?;
+        // slice_reserved_flag
+        r.bits(pps.num_extra_slice_header_bits as u32)?;
+        header.slice_type = match r.ue()? {
+            0 => SliceType::B,
+            1 => SliceType::P,
+            2 => SliceType::I,
+            _ => return Err(VideoError::Malformed("slice_type above 2")),
+        };
+        if pps.output_flag_present {
+            header.pic_output = r.flag()?;
+        }
+        if sps.separate_colour_plane {
+            r.bits(2)?;
+        }
+        if nal.is_idr() {
+            return Ok(header);
+        }
+        let lsb_bits = sps.log2_max_pic_order_cnt_lsb_minus4 as u32 + 4;
+        header.pic_order_cnt_lsb = r.bits(lsb_bits)?;
+        let sets = &sps.short_term_ref_pic_sets;
+        if !r.flag()? {
+            let start = r.position();
+            header.short_term_rps = Some(ShortTermRps::parse(r, sets, true)?);
+            header.short_term_rps_bits = (r.position() - start) as u32;
+        } else if sets.is_empty() {
+            return Err(VideoError::Malformed("short_term_ref_pic_set_sps_flag"));
+        } else {
+            header.short_term_rps_idx = r.bits(ceil_log2(sets.len()))? as u8;
+            if header.short_term_rps_idx as usize >= sets.len() {
+                return Err(VideoError::Malformed("short_term_ref_pic_set_idx"));
+            }
+        }
+        if let Some(sps_pics) = &sps.long_term_ref_pics {
+            let from_sps = if sps_pics.is_empty() { 0 } else { r.ue()? };
+            let coded = r.ue()?;
+            if from_sps > 32 || coded > 32 - from_sps {
+                return Err(VideoError::Malformed("more than 32 long term references"));
+            }
+            let mut cycle = 0u32;
+            for i in 0..from_sps + coded {
+                let (poc_lsb, used_by_curr_pic) = if i < from_sps {
+                    let index = r.bits(ceil_log2(sps_pics.len()))? as usize;
+                    *sps_pics
+                        .get(index)
+                        .ok_or(VideoError::Malformed("lt_idx_sps"))?
+                } else {
+                    (r.bits(lsb_bits)?, r.flag()?)
+                };
+                let msb_present = r.flag()?;
+                let delta = if msb_present { r.ue()? } else { 0 };
+                // Accumulated within the SPS's pictures and within the header's, equation 7-52
+                cycle = if i == 0 || i == from_sps {
+                    delta
+                } else {
+                    cycle.saturating_add(delta)
+                };
+                header.long_term.push(LongTermRef {
+                    poc_lsb,
+                    used_by_curr_pic,
+                    delta_poc_msb_cycle: msb_present.then_some(cycle),
+                });
+            }
+        }
+        Ok(header)
+    }
+
+    /// The short term set in use, coded in the header or picked from the SPS
+    pub fn short_term_rps<'a>(&'a self, sps: &'a Sps) -> Option<&'a ShortTermRps> {
+        if self.nal.is_idr() {
+            return None;
+        }
+        self.short_term_rps.as_ref().or_else(|| {
+            sps.short_term_ref_pic_sets
+                .get(self.short_term_rps_idx as usize)
+        })
+    }
+
+    /// `NumDeltaPocs` of the SPS set the header's own set is predicted from, 0 otherwise
+    pub fn num_delta_pocs_of_ref_rps(&self, sps: &Sps) -> u8 {
+        let Some(rps) = self
+            .short_term_rps
+            .as_ref()
+            .filter(|rps| rps.inter_ref_pic_set_prediction)
+        else {
+            return 0;
+        };
+        let sets = &sps.short_term_ref_pic_sets;
+        sets.len()
+            .checked_sub(rps.delta_idx_minus1 as usize + 1)
+            .map_or(0, |index| {
+                (sets[index].negative.len() + sets[index].positive.len()) as u8
+            })
+    }
+
+    /// The reference picture set of the picture `header` starts, whose order count is `poc`
+    pub fn reference_pocs(&self, sps: &Sps, poc: i32) -> ReferencePocs {
+        let mut pocs = ReferencePocs::default();
+        if let Some(rps) = self.short_term_rps(sps) {
+            for &(delta, used) in &rps.negative {
+                let list = if used {
+                    &mut pocs.st_curr_before
+                } else {
+                    &mut pocs.st_foll
+                };
+                list.push(poc + delta);
+            }
+            for &(delta, used) in &rps.positive {
+                let list = if used {
+                    &mut pocs.st_curr_after
+                } else {
+                    &mut pocs.st_foll
+                };
+                list.push(poc + delta);
+            }
+        }
+        if self.nal.is_idr() {
+            return pocs;
+        }
+        let max_lsb = sps.max_pic_order_cnt_lsb() as i32;
+        for long_term in &self.long_term {
+            let mut lt_poc = long_term.poc_lsb as i32;
+            if let Some(cycle) = long_term.delta_poc_msb_cycle {
+                lt_poc += poc - (cycle as i32).wrapping_mul(max_lsb) - (poc & (max_lsb - 1));
+            }
+            let list = if long_term.used_by_curr_pic {
+                &mut pocs.lt_curr
+            } else {
+                &mut pocs.lt_foll
+            };
+            list.push((lt_poc, long_term.delta_poc_msb_cycle.is_some()));
+        }
+        pocs
+    }
+}
+
+/// Picture order count state carried from picture to picture, clause 8.3.1
+#[derive(Debug, Clone, Default)]
+pub struct PocState {
+    /// `PicOrderCntVal` of `prevTid0Pic`
+    prev_tid0_poc: i32,
+}
+
+impl PocState {
+    /// `PicOrderCntVal` of the picture `header` starts
+    ///
+    /// `fresh_start` is `NoRaslOutputFlag`, set for IRAP pictures decoding starts again from.
+    pub fn compute(&mut self, sps: &Sps, header: &SliceHeader, fresh_start: bool) -> i32 {
+        let max_lsb = sps.max_pic_order_cnt_lsb() as i32;
+        let lsb = header.pic_order_cnt_lsb as i32;
+        let msb = if header.nal.is_irap() && fresh_start {
+            0
+        } else {
+            let prev_lsb = self.prev_tid0_poc & (max_lsb - 1);
+            let prev_msb = self.prev_tid0_poc - prev_lsb;
+            if lsb < prev_lsb && prev_lsb - lsb >= max_lsb / 2 {
+                prev_msb + max_lsb
+            } else if lsb > prev_lsb && lsb - prev_lsb > max_lsb / 2 {
+                prev_msb - max_lsb
+            } else {
+                prev_msb
+            }
+        };
+        let poc = msb + lsb;
+        let nal = header.nal;
+        if nal.temporal_id == 0
+            && !nal.is_rasl()
+            && !nal.is_radl()
+            && !nal.is_sub_layer_non_reference()
+        {
+            self.prev_tid0_poc = poc;
+        }
+        poc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::video::h264::BitWriter;
+
+    fn profile_tier_level(w: &mut BitWriter, level_idc: u32) {
+        // Main profile, main tier
+        w.bits(2, 0);
+        w.flag(false);
+        w.bits(5, 1);
+        w.bits(32, 0x6000_0000);
+        // Progressive and frame only
+        w.bits(4, 0b1001);
+        w.bits(32, 0);
+        w.bits(12, 0);
+        w.bits(8, level_idc);
+    }
+
+    /// A 1080p SPS with two short term sets, the second predicted, and one long term picture
+    fn sps_rbsp() -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.bits(4, 0);
+        w.bits(3, 0);
+        w.flag(true);
+        profile_tier_level(&mut w, 93);
+        w.ue(0);
+        w.ue(1);
+        w.ue(1920);
+        w.ue(1088);
+        // Conformance window, 4 chroma rows off the bottom
+        w.flag(true);
+        for offset in [0, 0, 0, 4] {
+            w.ue(offset);
+        }
+        w.ue(0);
+        w.ue(0);
+        // 8-bit order count LSBs
+        w.ue(4);
+        w.flag(true);
+        for value in [4, 2, 0] {
+            w.ue(value);
+        }
+        for value in [0, 3, 0, 3, 1, 1] {
+            w.ue(value);
+        }
+        // No scaling lists, AMP and SAO, no PCM
+        w.bits(4, 0b0110);
+        w.ue(2);
+        // Set 0: -1 and -3, the second not used by the picture, and +2
+        w.ue(2);
+        w.ue(1);
+        for (delta_minus1, used) in [(0, true), (1, false), (1, true)] {
+            w.ue(delta_minus1);
+            w.flag(used);
+        }
+        // Set 1: set 0 moved by -1, -4 left out
+        w.flag(true);
+        w.flag(true);
+        w.ue(0);
+        for (used, use_delta) in [(true, true), (false, false), (true, true), (true, true)] {
+            w.flag(used);
+            if !used {
+                w.flag(use_delta);
+            }
+        }
+        // One long term picture
+        w.flag(true);
+        w.ue(1);
+        w.bits(8, 5);
+        w.flag(true);
+        w.flag(true);
+        w.flag(true);
+        // VUI: full range BT.709 at 60000/1001 frames per second
+        w.flag(true);
+        w.bits(3, 0b001);
+        w.bits(3, 5);
+        w.bits(2, 0b11);
+        w.bits(24, 0x01_01_01);
+        w.bits(5, 0);
+        w.flag(true);
+        w.bits(32, 1001);
+        w.bits(32, 60000);
+        w.bits(3, 0);
+        w.flag(false);
+        w.finish()
+    }
+
+    /// A PPS with two tile columns and deblocking offsets
+    fn pps_rbsp() -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.ue(0);
+        w.ue(0);
+        w.bits(2, 0);
+        w.bits(3, 0);
+        w.bits(2, 0);
+        w.ue(0);
+        w.ue(0);
+        w.se(0);
+        w.bits(3, 0);
+        w.se(0);
+        w.se(0);
+        w.bits(4, 0);
+        // Tiles, without wavefronts
+        w.bits(2, 0b10);
+        w.ue(1);
+        w.ue(0);
+        w.flag(false);
+        w.ue(9);
+        w.flag(true);
+        w.flag(true);
+        // Deblocking control, not overridden or disabled
+        w.bits(3, 0b100);
+        w.se(-1);
+        w.se(2);
+        w.bits(2, 0);
+        w.ue(0);
+        w.bits(2, 0);
+        w.finish()
+    }
+
+    fn sets() -> (BTreeMap<u8, Sps>, BTreeMap<u8, Pps>) {
+        let sps = Sps::parse(&sps_rbsp()).unwrap();
+        let pps = Pps::parse(&pps_rbsp()).unwrap();
+        (BTreeMap::from([(0, sps)]), BTreeMap::from([(0, pps)]))
+    }
+
+    fn nal(nal_unit_type: u8, temporal_id: u8) -> NalHeader {
+        NalHeader {
+            nal_unit_type,
+            layer_id: 0,
+            temporal_id,
+        }
+    }
+
+    #[test]
+    fn nal_headers_split_type_layer_and_temporal_id() {
+        let header = NalHeader::parse(&[0x40, 0x01]).unwrap();
+        assert_eq!(header, nal(NAL_VPS, 0));
+        let header = NalHeader::parse(&[0x2b, 0x0b]).unwrap();
+        assert_eq!(header.nal_unit_type, NAL_CRA);
+        assert_eq!((header.layer_id, header.temporal_id), (33, 2));
+        assert!(header.is_irap() && !header.is_idr() && !header.is_bla());
+        assert!(nal(0, 1).is_sub_layer_non_reference());
+        assert!(!nal(NAL_TRAIL_R, 0).is_sub_layer_non_reference());
+        assert!(matches!(
+            NalHeader::parse(&[0x40]),
+            Err(VideoError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn sps_parses_sets_long_term_pictures_and_vui() {
+        let (sps, _) = sets();
+        let sps = &sps[&0];
+        assert_eq!(sps.profile_tier_level.profile_idc, 1);
+        assert!(sps.profile_tier_level.progressive_source);
+        assert_eq!(sps.std_level_idc(), 4);
+        assert_eq!(sps.max_pic_order_cnt_lsb(), 256);
+        assert_eq!(
+            sps.display_rect().extent,
+            vk::Extent2D {
+                width: 1920,
+                height: 1080
+            }
+        );
+        assert_eq!(sps.highest_ordering().max_num_reorder_pics, 2);
+        assert!(sps.amp_enabled && sps.sample_adaptive_offset_enabled && sps.pcm.is_none());
+
+        let sets = &sps.short_term_ref_pic_sets;
+        assert_eq!(sets[0].negative, [(-1, true), (-3, false)]);
+        assert_eq!(sets[0].positive, [(2, true)]);
+        assert_eq!(sets[1].negative, [(-1, true), (-2, true)]);
+        assert_eq!(sets[1].positive, [(1, true)]);
+        assert_eq!(sets[1].used_by_curr_pic_flags, 0b1101);
+        assert_eq!(sets[1].use_delta_flags, 0b1101);
+        assert_eq!(sps.long_term_ref_pics.as_deref(), Some(&[(5, true)][..]));
+
+        assert!(sps.vui.full_range);
+        assert_eq!(sps.vui.matrix_coefficients, 1);
+        assert!((sps.vui.frame_rate.unwrap() - 59.94).abs() < 0.01);
+
+        let std = sps.to_std();
+        assert_eq!(std.set.num_short_term_ref_pic_sets, 2);
+        assert_eq!(std.set.conf_win_bottom_offset, 4);
+        let first = &std._short_term_ref_pic_sets[0];
+        assert_eq!(first.delta_poc_s0_minus1[..2], [0, 1]);
+        assert_eq!(first.used_by_curr_pic_s0_flag, 0b01);
+        assert_eq!(first.delta_poc_s1_minus1[0], 1);
+        let second = &std._short_term_ref_pic_sets[1];
+        assert_eq!(second.delta_poc_s0_minus1[..2], [0, 0]);
+        assert_eq!(second.use_delta_flag, 0b1101);
+    }
+
+    #[test]
+    fn pps_parses_tiles_and_deblocking() {
+        let (_, pps) = sets();
+        let pps = &pps[&0];
+        let tiles = pps.tiles.as_ref().unwrap();
+        assert_eq!(
+            (tiles.num_tile_columns_minus1, tiles.num_tile_rows_minus1),
+            (1, 0)
+        );
+        assert_eq!(tiles.column_width_minus1, [9]);
+        assert!(tiles.row_height_minus1.is_empty());
+        assert!(pps.loop_filter_across_slices_enabled);
+        let deblocking = pps.deblocking.unwrap();
+        assert_eq!(
+            (deblocking.beta_offset_div2, deblocking.tc_offset_div2),
+            (-1, 2)
+        );
+
+        let std = pps.to_std(0);
+        assert_eq!(std.set.flags.tiles_enabled_flag(), 1);
+        assert_eq!(std.set.column_width_minus1[0], 9);
+        assert_eq!(std.set.pps_beta_offset_div2, -1);
+    }
+
+    #[test]
+    fn slice_headers_give_the_reference_picture_set() {
+        let (sps, pps) = sets();
+        let mut w = BitWriter::new();
+        w.flag(true);
+        w.ue(0);
+        // A P slice at order count LSB 10
+        w.ue(1);
+        w.bits(8, 10);
+        // Its own set, predicted from set 0 moved by +1: 0 left out, -2, +3 kept for later, +1
+        w.flag(false);
+        w.flag(true);
+        w.ue(1);
+        w.flag(false);
+        w.ue(0);
+        for (used, use_delta) in [(false, false), (true, true), (false, true), (true, true)] {
+            w.flag(used);
+            if !used {
+                w.flag(use_delta);
+            }
+        }
+        // The SPS's long term picture with its MSBs a cycle back, and one of its own
+        w.ue(1);
+        w.ue(1);
+        w.flag(true);
+        w.ue(1);
+        w.bits(8, 200);
+        w.flag(false);
+        w.flag(false);
+        let rbsp = w.finish();
+
+        let header = SliceHeader::parse(nal(NAL_TRAIL_R, 0), &rbsp, &sps, &pps).unwrap();
+        let sps = &sps[&0];
+        assert_eq!(header.slice_type, SliceType::P);
+        assert_eq!(header.pic_order_cnt_lsb, 10);
+        assert_eq!(header.short_term_rps_bits, 12);
+        assert_eq!(header.num_delta_pocs_of_ref_rps(sps), 3);
+        let rps = header.short_term_rps(sps).unwrap();
+        assert_eq!(rps.negative, [(-2, true)]);
+        assert_eq!(rps.positive, [(1, true), (3, false)]);
+
+        let poc = PocState::default().compute(sps, &header, false);
+        assert_eq!(poc, 10);
+        let pocs = header.reference_pocs(sps, poc);
+        assert_eq!(pocs.st_curr_before, [8]);
+        assert_eq!(pocs.st_curr_after, [11]);
+        assert_eq!(pocs.st_foll, [13]);
+        assert_eq!(pocs.lt_curr, [(5 - 256, true)]);
+        assert_eq!(pocs.lt_foll, [(200, false)]);
+    }
+
+    #[test]
+    fn later_slice_segments_stop_after_the_pps_id() {
+        let (sps, pps) = sets();
+        let mut w = BitWriter::new();
+        w.flag(false);
+        w.ue(3);
+        let header = SliceHeader::parse(nal(NAL_TRAIL_R, 0), &w.finish(), &sps, &pps).unwrap();
+        assert!(!header.first_slice_segment_in_pic);
+        assert_eq!(header.pps_id, 3);
+    }
+
+    #[test]
+    fn order_counts_wrap_from_the_last_temporal_layer_0_picture() {
+        let (sps, _) = sets();
+        let sps = &sps[&0];
+        let header = |nal_unit_type, temporal_id, pic_order_cnt_lsb| SliceHeader {
+            nal: nal(nal_unit_type, temporal_id),
+            first_slice_segment_in_pic: true,
+            no_output_of_prior_pics: false,
+            pps_id: 0,
+            slice_type: SliceType::P,
+            pic_output: true,
+            pic_order_cnt_lsb,
+            short_term_rps: None,
+            short_term_rps_idx: 0,
+            short_term_rps_bits: 0,
+            long_term: Vec::new(),
+        };
+        let mut state = PocState::default();
+        let mut poc = |nal_unit_type, temporal_id, lsb, fresh_start| {
+            state.compute(sps, &header(nal_unit_type, temporal_id, lsb), fresh_start)
+        };
+        assert_eq!(poc(NAL_IDR_W_RADL, 0, 0, true), 0);
+        assert_eq!(poc(NAL_TRAIL_R, 0, 100, false), 100);
+        assert_eq!(poc(NAL_TRAIL_R, 0, 200, false), 200);
+        // Higher temporal layers don't move the base the next picture is counted from
+        assert_eq!(poc(NAL_TRAIL_R, 1, 130, false), 130);
+        assert_eq!(poc(NAL_TRAIL_R, 0, 10, false), 266);
+        assert_eq!(poc(NAL_CRA, 0, 20, false), 276);
+        assert_eq!(poc(NAL_CRA, 0, 20, true), 20);
+    }
+
+    #[test]
+    fn scaling_lists_predict_from_earlier_lists_and_defaults() {
+        let mut w = BitWriter::new();
+        // 4x4: an explicit ramp, a copy of it, then defaults
+        w.flag(true);
+        w.se(8);
+        for _ in 1..16 {
+            w.se(1);
+        }
+        w.flag(false);
+        w.ue(1);
+        for _ in 2..6 {
+            w.flag(false);
+            w.ue(0);
+        }
+        // 8x8 defaults
+        for _ in 0..6 {
+            w.flag(false);
+            w.ue(0);
+        }
+        // 16x16: a flat 12 with its DC, then defaults
+        w.flag(true);
+        w.se(4);
+        for _ in 0..64 {
+            w.se(0);
+        }
+        for _ in 1..6 {
+            w.flag(false);
+            w.ue(0);
+        }
+        // 32x32: the default intra list, then the inter one copies it
+        w.flag(false);
+        w.ue(0);
+        w.flag(false);
+        w.ue(1);
+        let data = w.finish();
+
+        let lists = ScalingLists::parse(&mut BitReader::new(&data)).unwrap();
+        let ramp: Vec<u8> = (16..32).collect();
+        assert_eq!(lists.lists_4x4[0][..], ramp[..]);
+        assert_eq!(lists.lists_4x4[1], lists.lists_4x4[0]);
+        assert_eq!(lists.lists_4x4[2], [16; 16]);
+        assert_eq!(lists.lists_8x8, ScalingLists::DEFAULT.lists_8x8);
+        assert_eq!((lists.lists_16x16[0], lists.dc_16x16[0]), ([12; 64], 12));
+        assert_eq!(lists.lists_16x16[3], DEFAULT_INTER);
+        assert_eq!(lists.lists_32x32, [DEFAULT_INTRA; 2]);
+        assert_eq!(lists.dc_32x32, [16; 2]);
+    }
+}
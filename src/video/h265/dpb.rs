@@ -0,0 +1,416 @@
+//! Decoded picture buffer bookkeeping, clause 8.3.2 and annex C.5.2 of H.265
+//!
+//! Unlike H.264, every picture lists the references it keeps, so marking comes down to
+//! matching order counts against its reference picture set. Pictures are shown in order count
+//! order once too many are waiting, one has waited too long or the buffer is full. References
+//! missing from the buffer aren't generated, they're left out of the picture's lists.
+
+use super::{ReferencePocs, Sps};
+use crate::video::{dpb::Output, VideoError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marking {
+    Unused,
+    ShortTerm,
+    LongTerm,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    marking: Marking,
+    needed_for_output: bool,
+    poc: i32,
+    /// `PicLatencyCount`, pictures decoded since this one while it waits
+    latency: u32,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot {
+        marking: Marking::Unused,
+        needed_for_output: false,
+        poc: 0,
+        latency: 0,
+    };
+
+    fn is_free(&self) -> bool {
+        self.marking == Marking::Unused && !self.needed_for_output
+    }
+}
+
+/// A reference picture the current picture may predict from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference {
+    pub slot: usize,
+    pub poc: i32,
+    pub long_term: bool,
+}
+
+/// Slots of `RefPicSetStCurrBefore`, `RefPicSetStCurrAfter` and `RefPicSetLtCurr`
+///
+/// `None` stands for a reference the stream names but the buffer doesn't hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct References {
+    pub st_curr_before: Vec<Option<usize>>,
+    pub st_curr_after: Vec<Option<usize>>,
+    pub lt_curr: Vec<Option<usize>>,
+    /// Each picture of the three lists once, the ones the decode binds
+    pub active: Vec<Reference>,
+}
+
+pub struct Dpb {
+    slots: Vec<Slot>,
+    /// `sps_max_dec_pic_buffering_minus1 + 1`, the current picture included
+    max_dec_pic_buffering: usize,
+    max_num_reorder: usize,
+    /// `SpsMaxLatencyPictures`, when the stream limits it
+    max_latency: Option<u32>,
+}
+
+impl Dpb {
+    pub fn new(slot_count: usize) -> Self {
+        Dpb {
+            slots: vec![Slot::EMPTY; slot_count],
+            max_dec_pic_buffering: slot_count.max(1),
+            max_num_reorder: 0,
+            max_latency: None,
+        }
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Slots `sps` needs, its references and the pictures it reorders count the current one
+    pub fn slots_for(sps: &Sps) -> usize {
+        sps.highest_ordering().max_dec_pic_buffering_minus1 as usize + 1
+    }
+
+    /// Takes the limits of the sequence `sps` starts, at an IRAP picture
+    pub fn configure(&mut self, sps: &Sps) {
+        let ordering = sps.highest_ordering();
+        self.max_dec_pic_buffering = ordering.max_dec_pic_buffering_minus1 as usize + 1;
+        self.max_num_reorder = ordering.max_num_reorder_pics as usize;
+        self.max_latency = ordering
+            .max_latency_increase_plus1
+            .checked_sub(1)
+            .map(|increase| ordering.max_num_reorder_pics as u32 + increase);
+    }
+
+    /// Marks references by the set of the picture about to be decoded and picks its slot
+    ///
+    /// `fresh_start` is set for IRAP pictures decoding starts again from, which drop every
+    /// reference and show everything waiting unless `no_output_of_prior_pics` says to drop
+    /// that too. Long term references with only their LSBs known are matched modulo
+    /// `max_poc_lsb`.
+    pub fn begin_picture(
+        &mut self,
+        pocs: &ReferencePocs,
+        max_poc_lsb: u32,
+        fresh_start: bool,
+        no_output_of_prior_pics: bool,
+    ) -> Result<(usize, References, Vec<Output>), VideoError> {
+        let mut outputs = Vec::new();
+        let mut references = References::default();
+        if fresh_start {
+            if no_output_of_prior_pics {
+                self.clear();
+            } else {
+                for slot in &mut self.slots {
+                    slot.marking = Marking::Unused;
+                }
+                outputs.extend(self.flush());
+            }
+        } else {
+            references = self.mark(pocs, max_poc_lsb);
+            while self.waiting() > self.max_num_reorder
+                || self.latency_exceeded()
+                || self.occupied() >= self.max_dec_pic_buffering
+            {
+                let Some(output) = self.bump() else {
+                    break;
+                };
+                outputs.push(output);
+            }
+        }
+        let free = self
+            .slots
+            .iter()
+            .position(Slot::is_free)
+            .ok_or(VideoError::DpbFull)?;
+        Ok((free, references, outputs))
+    }
+
+    /// Marks the picture just decoded into `current` and gives the pictures now due
+    ///
+    /// `output` is `PicOutputFlag`, pictures that aren't shown only stay as references.
+    pub fn finish_picture(&mut self, current: usize, poc: i32, output: bool) -> Vec<Output> {
+        for slot in &mut self.slots {
+            if slot.needed_for_output {
+                slot.latency += 1;
+            }
+        }
+        self.slots[current] = Slot {
+            marking: Marking::ShortTerm,
+            needed_for_output: output,
+            poc,
+            latency: 0,
+        };
+        let mut outputs = Vec::new();
+        while self.waiting() > self.max_num_reorder || self.latency_exceeded() {
+            outputs.extend(self.bump());
+        }
+        outputs
+    }
+
+    /// Gives every picture still waiting, at the end of the stream
+    pub fn flush(&mut self) -> Vec<Output> {
+        std::iter::from_fn(|| self.bump()).collect()
+    }
+
+    /// Forgets every picture, for seeking
+    pub fn clear(&mut self) {
+        self.slots.fill(Slot::EMPTY);
+    }
+
+    /// Applies the reference picture set, long term pictures first as equation 8-5 has it
+    fn mark(&mut self, pocs: &ReferencePocs, max_poc_lsb: u32) -> References {
+        let mut kept = vec![false; self.slots.len()];
+        let mut long_term = |slots: &mut [Slot], &(poc, full): &(i32, bool)| {
+            let mask = if full { -1 } else { max_poc_lsb as i32 - 1 };
+            let found = (0..slots.len()).find(|&index| {
+                slots[index].marking != Marking::Unused && slots[index].poc & mask == poc & mask
+            });
+            if let Some(index) = found {
+                slots[index].marking = Marking::LongTerm;
+                kept[index] = true;
+            }
+            found
+        };
+        let lt_curr: Vec<_> = pocs
+            .lt_curr
+            .iter()
+            .map(|entry| long_term(&mut self.slots, entry))
+            .collect();
+        for entry in &pocs.lt_foll {
+            long_term(&mut self.slots, entry);
+        }
+        let mut short_term = |slots: &[Slot], poc: i32| {
+            let found = (0..slots.len()).find(|&index| {
+                slots[index].marking == Marking::ShortTerm && slots[index].poc == poc
+            });
+            if let Some(index) = found {
+                kept[index] = true;
+            }
+            found
+        };
+        let st_curr_before: Vec<_> = pocs
+            .st_curr_before
+            .iter()
+            .map(|&poc| short_term(&self.slots, poc))
+            .collect();
+        let st_curr_after: Vec<_> = pocs
+            .st_curr_after
+            .iter()
+            .map(|&poc| short_term(&self.slots, poc))
+            .collect();
+        for &poc in &pocs.st_foll {
+            short_term(&self.slots, poc);
+        }
+        for (slot, kept) in self.slots.iter_mut().zip(kept) {
+            if !kept {
+                slot.marking = Marking::Unused;
+            }
+        }
+
+        let mut active: Vec<Reference> = Vec::new();
+        for &index in st_curr_before
+            .iter()
+            .chain(&st_curr_after)
+            .chain(&lt_curr)
+            .flatten()
+        {
+            if active.iter().all(|reference| reference.slot != index) {
+                active.push(Reference {
+                    slot: index,
+                    poc: self.slots[index].poc,
+                    long_term: self.slots[index].marking == Marking::LongTerm,
+                });
+            }
+        }
+        References {
+            st_curr_before,
+            st_curr_after,
+            lt_curr,
+            active,
+        }
+    }
+
+    fn waiting(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| slot.needed_for_output)
+            .count()
+    }
+
+    fn occupied(&self) -> usize {
+        self.slots.iter().filter(|slot| !slot.is_free()).count()
+    }
+
+    fn latency_exceeded(&self) -> bool {
+        self.max_latency.is_some_and(|max| {
+            self.slots
+                .iter()
+                .any(|slot| slot.needed_for_output && slot.latency >= max)
+        })
+    }
+
+    /// Shows the waiting picture with the lowest order count
+    fn bump(&mut self) -> Option<Output> {
+        let (index, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, slot)| slot.needed_for_output)
+            .min_by_key(|(_, slot)| slot.poc)?;
+        slot.needed_for_output = false;
+        Some(Output {
+            slot: index,
+            poc: slot.poc,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dpb(slot_count: usize, max_num_reorder: usize) -> Dpb {
+        let mut dpb = Dpb::new(slot_count);
+        dpb.max_num_reorder = max_num_reorder;
+        dpb
+    }
+
+    fn short_term(before: &[i32], after: &[i32], foll: &[i32]) -> ReferencePocs {
+        ReferencePocs {
+            st_curr_before: before.to_vec(),
+            st_curr_after: after.to_vec(),
+            st_foll: foll.to_vec(),
+            ..ReferencePocs::default()
+        }
+    }
+
+    /// Decodes a picture, giving its slot, references and the order counts shown meanwhile
+    fn decode(dpb: &mut Dpb, pocs: &ReferencePocs, poc: i32) -> (usize, References, Vec<i32>) {
+        let (slot, references, mut outputs) = dpb.begin_picture(pocs, 16, poc == 0, false).unwrap();
+        outputs.extend(dpb.finish_picture(slot, poc, true));
+        (
+            slot,
+            references,
+            outputs.iter().map(|output| output.poc).collect(),
+        )
+    }
+
+    #[test]
+    fn pictures_left_out_of_the_set_stop_being_references() {
+        let mut dpb = dpb(4, 0);
+        let (first, _, _) = decode(&mut dpb, &ReferencePocs::default(), 0);
+        let (second, _, _) = decode(&mut dpb, &short_term(&[0], &[], &[]), 1);
+        let (_, references, _) = decode(&mut dpb, &short_term(&[1], &[], &[0]), 2);
+        assert_eq!(references.st_curr_before, [Some(second)]);
+        assert_eq!(references.active.len(), 1);
+
+        // POC 0 was only kept as a following reference, a set without it frees its slot
+        let (slot, references, _) = decode(&mut dpb, &short_term(&[2, 1], &[], &[]), 3);
+        assert_eq!(slot, first);
+        assert_eq!(references.st_curr_before.len(), 2);
+    }
+
+    #[test]
+    fn long_term_references_match_on_lsbs_unless_the_msbs_are_sent() {
+        let mut dpb = dpb(4, 0);
+        decode(&mut dpb, &ReferencePocs::default(), 0);
+        decode(&mut dpb, &short_term(&[0], &[], &[]), 17);
+        let pocs = ReferencePocs {
+            st_curr_before: vec![17],
+            lt_curr: vec![(16, false)],
+            ..ReferencePocs::default()
+        };
+        let (_, references, _) = decode(&mut dpb, &pocs, 18);
+        assert_eq!(references.lt_curr, [Some(0)]);
+        assert!(references
+            .active
+            .iter()
+            .any(|reference| reference.long_term));
+
+        // With the full count given, 16 is no longer POC 0
+        let pocs = ReferencePocs {
+            lt_curr: vec![(16, true)],
+            ..ReferencePocs::default()
+        };
+        let (_, references, _) = decode(&mut dpb, &pocs, 19);
+        assert_eq!(references.lt_curr, [None]);
+        assert!(references.active.is_empty());
+    }
+
+    #[test]
+    fn pictures_come_out_in_order_count_order_past_the_reorder_depth() {
+        let mut dpb = dpb(5, 2);
+        let mut shown = Vec::new();
+        shown.extend(decode(&mut dpb, &ReferencePocs::default(), 0).2);
+        shown.extend(decode(&mut dpb, &short_term(&[0], &[], &[]), 4).2);
+        shown.extend(decode(&mut dpb, &short_term(&[0], &[4], &[]), 2).2);
+        shown.extend(decode(&mut dpb, &short_term(&[0], &[2, 4], &[]), 1).2);
+        assert_eq!(shown, [0, 1]);
+        shown.extend(decode(&mut dpb, &short_term(&[2], &[4], &[]), 3).2);
+        shown.extend(dpb.flush().iter().map(|output| output.poc));
+        assert_eq!(shown, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_full_buffer_shows_pictures_to_free_slots() {
+        let mut dpb = dpb(2, 2);
+        decode(&mut dpb, &ReferencePocs::default(), 0);
+        decode(&mut dpb, &short_term(&[0], &[], &[]), 1);
+        // Both slots are taken, showing POC 0 frees the one it no longer needs as a reference
+        let (slot, _, shown) = decode(&mut dpb, &short_term(&[1], &[], &[]), 2);
+        assert_eq!((slot, shown), (0, vec![0]));
+
+        // Showing everything doesn't help when both stay references
+        let result = dpb.begin_picture(&short_term(&[2], &[], &[1]), 16, false, false);
+        assert!(matches!(result, Err(VideoError::DpbFull)));
+    }
+
+    #[test]
+    fn irap_pictures_flush_or_drop_what_waits() {
+        let mut dpb = dpb(4, 2);
+        decode(&mut dpb, &ReferencePocs::default(), 0);
+        decode(&mut dpb, &short_term(&[0], &[], &[]), 2);
+        let (_, references, outputs) = dpb
+            .begin_picture(&ReferencePocs::default(), 16, true, false)
+            .unwrap();
+        assert!(references.active.is_empty());
+        assert_eq!(
+            outputs.iter().map(|output| output.poc).collect::<Vec<_>>(),
+            [0, 2]
+        );
+
+        decode(&mut dpb, &ReferencePocs::default(), 0);
+        decode(&mut dpb, &short_term(&[0], &[], &[]), 2);
+        let (_, _, outputs) = dpb
+            .begin_picture(&ReferencePocs::default(), 16, true, true)
+            .unwrap();
+        assert!(outputs.is_empty());
+        assert!(dpb.flush().is_empty());
+    }
+
+    #[test]
+    fn latency_limits_how_long_a_picture_waits() {
+        let mut dpb = dpb(5, 3);
+        dpb.max_latency = Some(2);
+        decode(&mut dpb, &ReferencePocs::default(), 0);
+        let (_, _, shown) = decode(&mut dpb, &short_term(&[0], &[], &[]), 8);
+        assert!(shown.is_empty());
+        let (_, _, shown) = decode(&mut dpb, &short_term(&[0], &[8], &[]), 4);
+        assert_eq!(shown, [0]);
+    }
+}
@@ -0,0 +1,889 @@
+//! Playing a decoded stream on a textured quad
+//!
+//! The luma and chroma planes of each picture due are copied into a ring of plain `R8` and `R8G8`
+//! images, handing the decoder's picture straight back, and converted to RGB in the fragment
+//! shader with the stream's colour matrix. The quad spans -1 to 1 on x and y, the top of the
+//! picture at y = 1, and is placed by the transform given to [`VideoPlayer::draw`].
+
+use std::{collections::VecDeque, mem, ops::Range};
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec4};
+
+use super::{
+    decode::{OutputBatch, VideoDecoder},
+    h264::{self, Vui},
+    h265, Codec, VideoError,
+};
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        Texture,
+    },
+    trace::traced,
+};
+
+pub const VIDEO_VERT_GLSL: &str = r#"#version 450
+layout(push_constant) uniform Push {
+    mat4 transform;
+    vec2 uv_scale;
+    vec2 chroma_offset;
+    vec4 to_rgb[3];
+} push;
+
+layout(location = 0) out vec2 out_uv;
+
+void main() {
+    // Strip corners (0, 0), (1, 0), (0, 1), (1, 1), the top row of the picture at y = 1
+    vec2 corner = vec2(float(gl_VertexIndex & 1), float((gl_VertexIndex >> 1) & 1));
+    gl_Position = push.transform * vec4(corner * 2.0 - 1.0, 0.0, 1.0);
+    out_uv = vec2(corner.x, 1.0 - corner.y) * push.uv_scale;
+}
+"#;
+
+pub const VIDEO_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 0) uniform texture2D luma;
+layout(set = 0, binding = 1) uniform texture2D chroma;
+layout(set = 0, binding = 2) uniform sampler linear_sampler;
+
+layout(push_constant) uniform Push {
+    mat4 transform;
+    vec2 uv_scale;
+    vec2 chroma_offset;
+    vec4 to_rgb[3];
+} push;
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 out_color;
+
+// Video transfer functions are close enough to sRGB to decode this way
+vec3 to_linear(vec3 c) {
+    return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(vec3(0.04045), c));
+}
+
+void main() {
+    vec4 ycbcr = vec4(
+        texture(sampler2D(luma, linear_sampler), uv).r,
+        texture(sampler2D(chroma, linear_sampler), uv + push.chroma_offset).rg,
+        1.0
+    );
+    vec3 rgb = vec3(
+        dot(push.to_rgb[0], ycbcr),
+        dot(push.to_rgb[1], ycbcr),
+        dot(push.to_rgb[2], ycbcr)
+    );
+    out_color = vec4(to_linear(clamp(rgb, 0.0, 1.0)), 1.0);
+}
+"#;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VideoPlayerConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// How long a shown picture may still be read by frames the GPU hasn't finished
+    pub frames_in_flight: usize,
+    /// Starts over at the end of the stream, which has to begin with an IDR picture
+    pub looping: bool,
+}
+
+impl VideoPlayerConfig {
+    pub fn new(render_pass: vk::RenderPass) -> Self {
+        VideoPlayerConfig {
+            render_pass,
+            subpass: 0,
+            frames_in_flight: 2,
+            looping: true,
+        }
+    }
+
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct VideoPush {
+    transform: Mat4,
+    uv_scale: Vec2,
+    chroma_offset: Vec2,
+    to_rgb: [Vec4; 3],
+}
+
+struct DisplayImage {
+    luma: Texture,
+    chroma: Texture,
+    set: vk::DescriptorSet,
+    /// [`Ring::frame`] of the last draw reading it
+    last_drawn: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ShownFrame {
+    image: usize,
+    rect: vk::Rect2D,
+}
+
+/// The display images, which hold pictures waiting or shown and which may still be read
+struct Ring {
+    images: Vec<DisplayImage>,
+    queued: VecDeque<ShownFrame>,
+    current: Option<ShownFrame>,
+    /// Counts [`VideoPlayer::update`] calls, one per rendered frame
+    frame: u64,
+    frames_in_flight: u64,
+}
+
+impl Ring {
+    /// An image to copy the next picture into
+    ///
+    /// When every image is taken the oldest picture waiting is dropped for it.
+    fn take_free(&mut self) -> usize {
+        let busy = |index: usize| {
+            self.current.is_some_and(|frame| frame.image == index)
+                || self.queued.iter().any(|frame| frame.image == index)
+                || self.images[index]
+                    .last_drawn
+                    .is_some_and(|drawn| self.frame - drawn <= self.frames_in_flight)
+        };
+        if let Some(free) = (0..self.images.len()).find(|&index| !busy(index)) {
+            return free;
+        }
+        self.queued
+            .pop_front()
+            .expect("the ring has more images than can be shown or in flight")
+            .image
+    }
+}
+
+/// Records and submits the copies out of the decoder's pictures
+struct Copier {
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+}
+
+impl Copier {
+    unsafe fn copy(&self, device: &Device, batch: &OutputBatch, ring: &mut Ring) -> VkResult<()> {
+        device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+        let cmd = self.command_buffer;
+        device.reset_command_buffer(cmd, vk::CommandBufferResetFlags::empty())?;
+        device.begin_command_buffer(
+            cmd,
+            &vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+
+        let targets: Vec<usize> = batch
+            .frames
+            .iter()
+            .map(|frame| {
+                let image = ring.take_free();
+                ring.queued.push_back(ShownFrame {
+                    image,
+                    rect: frame.rect,
+                });
+                image
+            })
+            .collect();
+        let range = |layer| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: layer,
+            layer_count: 1,
+        };
+        let barrier = |image, layer, old_layout, new_layout| {
+            vk::ImageMemoryBarrier2::builder()
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image)
+                .subresource_range(range(layer))
+        };
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for (frame, &target) in batch.frames.iter().zip(&targets) {
+            // Chains onto the semaphore waits at the copy stage
+            before.push(
+                barrier(
+                    frame.image,
+                    frame.layer,
+                    frame.layout,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                .build(),
+            );
+            after.push(
+                barrier(
+                    frame.image,
+                    frame.layer,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    frame.layout,
+                )
+                .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                .build(),
+            );
+            let display = &ring.images[target];
+            for plane in [&display.luma, &display.chroma] {
+                before.push(
+                    barrier(
+                        plane.image,
+                        0,
+                        vk::ImageLayout::UNDEFINED,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    )
+                    .src_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .build(),
+                );
+                after.push(
+                    barrier(
+                        plane.image,
+                        0,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                    .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                    .dst_access_mask(vk::AccessFlags2::SHADER_SAMPLED_READ)
+                    .build(),
+                );
+            }
+        }
+        device.cmd_pipeline_barrier2(
+            cmd,
+            &vk::DependencyInfo::builder().image_memory_barriers(&before),
+        );
+        for (frame, &target) in batch.frames.iter().zip(&targets) {
+            let display = &ring.images[target];
+            let width = frame.rect.extent.width.min(display.luma.extent.width);
+            let height = frame.rect.extent.height.min(display.luma.extent.height);
+            let planes = [
+                (vk::ImageAspectFlags::PLANE_0, &display.luma, 1),
+                (vk::ImageAspectFlags::PLANE_1, &display.chroma, 2),
+            ];
+            for (aspect, plane, subsampling) in planes {
+                let region = vk::ImageCopy {
+                    src_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: aspect,
+                        mip_level: 0,
+                        base_array_layer: frame.layer,
+                        layer_count: 1,
+                    },
+                    src_offset: vk::Offset3D {
+                        x: frame.rect.offset.x / subsampling,
+                        y: frame.rect.offset.y / subsampling,
+                        z: 0,
+                    },
+                    dst_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    },
+                    dst_offset: vk::Offset3D::default(),
+                    extent: vk::Extent3D {
+                        width: width.div_ceil(subsampling as u32),
+                        height: height.div_ceil(subsampling as u32),
+                        depth: 1,
+                    },
+                };
+                device.cmd_copy_image(
+                    cmd,
+                    frame.image,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    plane.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                );
+            }
+        }
+        device.cmd_pipeline_barrier2(
+            cmd,
+            &vk::DependencyInfo::builder().image_memory_barriers(&after),
+        );
+        device.end_command_buffer(cmd)?;
+
+        let waits: Vec<vk::SemaphoreSubmitInfo> = batch
+            .wait
+            .iter()
+            .map(|&semaphore| {
+                *vk::SemaphoreSubmitInfo::builder()
+                    .semaphore(semaphore)
+                    .stage_mask(vk::PipelineStageFlags2::COPY)
+            })
+            .collect();
+        let signal = vk::SemaphoreSubmitInfo::builder()
+            .semaphore(batch.signal)
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS);
+        let cmd_info = vk::CommandBufferSubmitInfo::builder().command_buffer(cmd);
+        let submit = vk::SubmitInfo2::builder()
+            .wait_semaphore_infos(&waits)
+            .command_buffer_infos(std::slice::from_ref(&cmd_info))
+            .signal_semaphore_infos(std::slice::from_ref(&signal));
+        device.reset_fences(&[self.fence])?;
+        traced!(
+            "vkQueueSubmit2",
+            queue = self.queue,
+            waits = waits.len();
+            device.queue_submit2(self.queue, &[*submit], self.fence)
+        )
+    }
+}
+
+/// Decodes an H.264 or H.265 stream as time passes and draws the picture due
+pub struct VideoPlayer {
+    decoder: VideoDecoder,
+    stream: Vec<u8>,
+    nals: Vec<Range<usize>>,
+    next_nal: usize,
+    finished: bool,
+    looping: bool,
+    frame_rate: f64,
+    /// Pictures shown since the start
+    shown: u64,
+    extent: vk::Extent2D,
+    to_rgb: [Vec4; 3],
+    set_layout: vk::DescriptorSetLayout,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    pool: vk::DescriptorPool,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    ring: Ring,
+    copier: Copier,
+}
+
+impl VideoPlayer {
+    /// Plays the Annex B `stream` through `decoder`, copying pictures out on `queue`
+    ///
+    /// `stream` is in the decoder's [`Codec`], `queue` is one of its copy family. Display images
+    /// are sized for the stream's first sequence, later larger ones are cropped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<C: ShaderCompiler>(
+        decoder: VideoDecoder,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        queue: vk::Queue,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        stream: Vec<u8>,
+        config: VideoPlayerConfig,
+    ) -> Result<Self, VideoError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| VideoError::Compile(Box::new(err)))
+        };
+        let vert = compile(VIDEO_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(VIDEO_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+
+        let nals: Vec<Range<usize>> = h264::nal_units(&stream)
+            .into_iter()
+            .map(|nal| {
+                let start = nal.as_ptr() as usize - stream.as_ptr() as usize;
+                start..start + nal.len()
+            })
+            .collect();
+        let (vui, rect) = first_sequence(
+            decoder.codec(),
+            nals.iter().map(|range| &stream[range.clone()]),
+        )?;
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default()
+                .with_filter(SamplerFilter::Linear)
+                .with_address_mode(vk::SamplerAddressMode::CLAMP_TO_EDGE),
+        )?;
+
+        let mut this = VideoPlayer {
+            decoder,
+            stream,
+            nals,
+            next_nal: 0,
+            finished: false,
+            looping: config.looping,
+            frame_rate: vui.frame_rate.unwrap_or(30.0),
+            shown: 0,
+            extent: rect.extent,
+            to_rgb: ycbcr_to_rgb(&vui, rect),
+            set_layout: vk::DescriptorSetLayout::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            pool: vk::DescriptorPool::null(),
+            sampler,
+            ring: Ring {
+                images: Vec::new(),
+                queued: VecDeque::new(),
+                current: None,
+                frame: 0,
+                frames_in_flight: config.frames_in_flight as u64,
+            },
+            copier: Copier {
+                queue,
+                command_pool: vk::CommandPool::null(),
+                command_buffer: vk::CommandBuffer::null(),
+                fence: vk::Fence::null(),
+            },
+        };
+        let result = unsafe { this.create_objects(device, mem_props, &vert, &frag, config) };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    #[track_caller]
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        vert: &[u32],
+        frag: &[u32],
+        config: VideoPlayerConfig,
+    ) -> Result<(), VideoError> {
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(self.decoder.copy_family())
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
+        self.copier.command_pool = device.create_command_pool(&pool_info, None)?;
+        leaks::track(self.copier.command_pool, "video copy command pool");
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.copier.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        self.copier.command_buffer = device.allocate_command_buffers(&alloc_info)?[0];
+        self.copier.fence = device.create_fence(
+            &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
+            None,
+        )?;
+        leaks::track(self.copier.fence, "video copy fence");
+
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "video player descriptor set layout");
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: mem::size_of::<VideoPush>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "video player pipeline layout");
+
+        // One picture shown, one due next and one being copied besides those in flight
+        let image_count = config.frames_in_flight as u32 + 3;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 2 * image_count,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: image_count,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(image_count)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "video player descriptor pool");
+        let chroma_extent = vk::Extent2D {
+            width: self.extent.width.div_ceil(2),
+            height: self.extent.height.div_ceil(2),
+        };
+        let usage = vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST;
+        for _ in 0..image_count {
+            let luma = Texture::empty(device, mem_props, vk::Format::R8_UNORM, self.extent, usage)?;
+            let chroma = match Texture::empty(
+                device,
+                mem_props,
+                vk::Format::R8G8_UNORM,
+                chroma_extent,
+                usage,
+            ) {
+                Ok(chroma) => chroma,
+                Err(err) => {
+                    luma.destroy(device);
+                    return Err(err.into());
+                }
+            };
+            let set = allocate_set(
+                device,
+                self.pool,
+                self.set_layout,
+                self.sampler,
+                &luma,
+                &chroma,
+            );
+            // Pushed first so a failure part way still destroys the textures
+            self.ring.images.push(DisplayImage {
+                luma,
+                chroma,
+                set: set.unwrap_or_default(),
+                last_drawn: None,
+            });
+            set?;
+        }
+
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[push_range], &[])?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "video player shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "video player shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines =
+            crate::pipeline::create_graphics_pipelines(device, &[*info], "video player");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.frame_rate
+    }
+
+    /// Width over height of the pictures, to scale the quad by
+    pub fn aspect(&self) -> f32 {
+        self.extent.width as f32 / self.extent.height.max(1) as f32
+    }
+
+    /// Decodes up to the picture due `time` seconds into playback, once per rendered frame
+    ///
+    /// Pictures whose time has passed by the next call are skipped, so playback keeps to the
+    /// stream's frame rate however fast frames render.
+    pub fn update(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        time: f64,
+    ) -> Result<(), VideoError> {
+        self.ring.frame += 1;
+        let due = (time.max(0.0) * self.frame_rate) as u64;
+        while self.shown <= due {
+            if self.ring.queued.is_empty() && !self.decode_more(device, mem_props)? {
+                break;
+            }
+            self.ring.current = self.ring.queued.pop_front();
+            self.shown += 1;
+        }
+        Ok(())
+    }
+
+    /// Feeds NAL units until a picture is waiting, `false` once the stream has nothing more
+    fn decode_more(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> Result<bool, VideoError> {
+        let VideoPlayer {
+            decoder,
+            stream,
+            nals,
+            next_nal,
+            finished,
+            ring,
+            copier,
+            ..
+        } = self;
+        let mut restarted = false;
+        while ring.queued.is_empty() {
+            let mut on_output = |batch: &OutputBatch| unsafe { copier.copy(device, batch, ring) };
+            if let Some(range) = nals.get(*next_nal) {
+                *next_nal += 1;
+                decoder.push(device, mem_props, &stream[range.clone()], &mut on_output)?;
+            } else if !*finished {
+                decoder.finish(device, mem_props, &mut on_output)?;
+                *finished = true;
+            } else if self.looping && !restarted {
+                decoder.reset();
+                *next_nal = 0;
+                *finished = false;
+                restarted = true;
+            } else {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Draws the current picture on the quad, nothing before the first one is decoded
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(&mut self, device: &Device, cmd: vk::CommandBuffer, transform: Mat4) {
+        let Some(frame) = self.ring.current else {
+            return;
+        };
+        let image = &mut self.ring.images[frame.image];
+        image.last_drawn = Some(self.ring.frame);
+        let extent = image.luma.extent;
+        let push = VideoPush {
+            transform,
+            uv_scale: Vec2::new(
+                frame.rect.extent.width.min(extent.width) as f32 / extent.width as f32,
+                frame.rect.extent.height.min(extent.height) as f32 / extent.height as f32,
+            ),
+            // H.264 and H.265 site chroma on the left luma sample of each pair by default
+            chroma_offset: Vec2::new(0.5 / extent.width as f32, 0.0),
+            to_rgb: self.to_rgb,
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[image.set],
+                &[],
+            );
+            device.cmd_draw(cmd, 4, 1, 0, 0);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The device must be done with the player's decodes, copies and draws.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.decoder.destroy(device);
+        for image in self.ring.images.drain(..) {
+            image.luma.destroy(device);
+            image.chroma.destroy(device);
+        }
+        leaks::untrack(self.copier.fence);
+        device.destroy_fence(self.copier.fence, None);
+        leaks::untrack(self.copier.command_pool);
+        device.destroy_command_pool(self.copier.command_pool, None);
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+fn allocate_set(
+    device: &Device,
+    pool: vk::DescriptorPool,
+    layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    luma: &Texture,
+    chroma: &Texture,
+) -> VkResult<vk::DescriptorSet> {
+    let set = unsafe {
+        device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(pool)
+                .set_layouts(&[layout]),
+        )?[0]
+    };
+    let image = |texture: &Texture| {
+        [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: texture.view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }]
+    };
+    let (luma, chroma) = (image(luma), image(chroma));
+    let samplers = [vk::DescriptorImageInfo {
+        sampler,
+        image_view: vk::ImageView::null(),
+        image_layout: vk::ImageLayout::UNDEFINED,
+    }];
+    let writes = [
+        (0, vk::DescriptorType::SAMPLED_IMAGE, &luma),
+        (1, vk::DescriptorType::SAMPLED_IMAGE, &chroma),
+        (2, vk::DescriptorType::SAMPLER, &samplers),
+    ]
+    .map(|(binding, ty, info)| {
+        vk::WriteDescriptorSet::builder()
+            .dst_set(set)
+            .dst_binding(binding)
+            .descriptor_type(ty)
+            .image_info(info)
+            .build()
+    });
+    unsafe { device.update_descriptor_sets(&writes, &[]) };
+    Ok(set)
+}
+
+/// The VUI and shown part of the pictures of the first SPS among `nals`
+///
+/// An H.265 SPS without timing takes the frame rate of the first VPS that has it.
+fn first_sequence<'a>(
+    codec: Codec,
+    mut nals: impl Iterator<Item = &'a [u8]>,
+) -> Result<(Vui, vk::Rect2D), VideoError> {
+    match codec {
+        Codec::H264 => {
+            let sps = nals
+                .find(|nal| {
+                    h264::NalHeader::parse(nal)
+                        .is_ok_and(|header| header.nal_unit_type == h264::NAL_SPS)
+                })
+                .ok_or(VideoError::MissingParameterSet)?;
+            let sps = h264::Sps::parse(&h264::rbsp(sps))?;
+            Ok((sps.vui.clone(), sps.display_rect()))
+        }
+        Codec::H265 => {
+            let mut frame_rate = None;
+            for nal in nals {
+                let Ok(header) = h265::NalHeader::parse(nal) else {
+                    continue;
+                };
+                match header.nal_unit_type {
+                    h265::NAL_VPS if frame_rate.is_none() => {
+                        frame_rate = h265::Vps::parse(&h265::rbsp(nal))?.frame_rate();
+                    }
+                    h265::NAL_SPS => {
+                        let sps = h265::Sps::parse(&h265::rbsp(nal))?;
+                        let mut vui = sps.vui.clone();
+                        vui.frame_rate = vui.frame_rate.or(frame_rate);
+                        return Ok((vui, sps.display_rect()));
+                    }
+                    _ => {}
+                }
+            }
+            Err(VideoError::MissingParameterSet)
+        }
+    }
+}
+
+/// Rows of the affine transform from sampled Y'CbCr to R'G'B', table E-5 of H.264 and E.5 of
+/// H.265
+///
+/// `rect` is the shown part of the pictures, for guessing the matrix streams leave out.
+fn ycbcr_to_rgb(vui: &Vui, rect: vk::Rect2D) -> [Vec4; 3] {
+    let (kr, kb) = match vui.matrix_coefficients {
+        1 => (0.2126, 0.0722),
+        4 => (0.30, 0.11),
+        5 | 6 => (0.299, 0.114),
+        7 => (0.212, 0.087),
+        9 | 10 => (0.2627, 0.0593),
+        // Unspecified, guessed from the size the way players do
+        _ if rect.extent.height > 576 => (0.2126, 0.0722),
+        _ => (0.299, 0.114),
+    };
+    let kg = 1.0 - kr - kb;
+    let (y_scale, y_offset, c_scale) = if vui.full_range {
+        (1.0, 0.0, 1.0)
+    } else {
+        (255.0 / 219.0, 16.0 / 255.0, 255.0 / 224.0)
+    };
+    let mid = 128.0 / 255.0;
+    let cr_r = 2.0 * (1.0 - kr) * c_scale;
+    let cb_g = -2.0 * kb * (1.0 - kb) / kg * c_scale;
+    let cr_g = -2.0 * kr * (1.0 - kr) / kg * c_scale;
+    let cb_b = 2.0 * (1.0 - kb) * c_scale;
+    let y_base = -y_offset * y_scale;
+    [
+        Vec4::new(y_scale, 0.0, cr_r, y_base - cr_r * mid),
+        Vec4::new(y_scale, cb_g, cr_g, y_base - (cb_g + cr_g) * mid),
+        Vec4::new(y_scale, cb_b, 0.0, y_base - cb_b * mid),
+    ]
+}
@@ -0,0 +1,330 @@
+//! Pieces the decoder and the encoder share: picture arrays, bitstream buffers and session memory
+
+use std::{ffi::c_void, ptr};
+
+use ash::{vk, Device};
+
+use super::{decode::PICTURE_FORMAT, VideoError};
+use crate::{
+    leaks,
+    memory::{
+        find_memory_type,
+        usage::{self, MemoryCategory},
+    },
+    trace::traced,
+};
+
+/// An image with one layer per DPB slot, or per picture in flight
+pub(super) struct PictureArray {
+    pub(super) image: vk::Image,
+    pub(super) view: vk::ImageView,
+    memory: vk::DeviceMemory,
+    allocation_size: vk::DeviceSize,
+}
+
+impl PictureArray {
+    pub(super) const NULL: PictureArray = PictureArray {
+        image: vk::Image::null(),
+        view: vk::ImageView::null(),
+        memory: vk::DeviceMemory::null(),
+        allocation_size: 0,
+    };
+
+    #[allow(clippy::too_many_arguments)]
+    #[track_caller]
+    pub(super) unsafe fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        profiles: &vk::VideoProfileListInfoKHR,
+        extent: vk::Extent2D,
+        layers: u32,
+        usage: vk::ImageUsageFlags,
+        families: &[u32],
+    ) -> Result<Self, VideoError> {
+        let mut profile_list = *profiles;
+        let mut info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(PICTURE_FORMAT)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(layers)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(usage)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .push_next(&mut profile_list);
+        info = if families.len() > 1 {
+            info.sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(families)
+        } else {
+            info.sharing_mode(vk::SharingMode::EXCLUSIVE)
+        };
+        let image = device.create_image(&info, None)?;
+
+        let requirements = device.get_image_memory_requirements(image);
+        let Some(type_index) = find_memory_type(
+            mem_props,
+            requirements.memory_type_bits,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) else {
+            device.destroy_image(image, None);
+            return Err(VideoError::NoMemoryType);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = device
+            .allocate_memory(&alloc_info, None)
+            .and_then(|memory| {
+                device
+                    .bind_image_memory(image, memory, 0)
+                    .map(|_| memory)
+                    .inspect_err(|_| device.free_memory(memory, None))
+            });
+        let memory = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                device.destroy_image(image, None);
+                return Err(err.into());
+            }
+        };
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+            .format(PICTURE_FORMAT)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: layers,
+            });
+        let view = match device.create_image_view(&view_info, None) {
+            Ok(view) => view,
+            Err(err) => {
+                device.destroy_image(image, None);
+                device.free_memory(memory, None);
+                return Err(err.into());
+            }
+        };
+        usage::record_allocation(MemoryCategory::Texture, requirements.size);
+        leaks::track(image, "video picture image");
+        leaks::track(memory, "video picture memory");
+        leaks::track(view, "video picture view");
+        Ok(PictureArray {
+            image,
+            view,
+            memory,
+            allocation_size: requirements.size,
+        })
+    }
+
+    /// Does nothing for [`PictureArray::NULL`]
+    pub(super) unsafe fn destroy(&self, device: &Device) {
+        if self.image == vk::Image::null() {
+            return;
+        }
+        leaks::untrack(self.view);
+        leaks::untrack(self.image);
+        leaks::untrack(self.memory);
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
+        usage::release_allocation(MemoryCategory::Texture, self.allocation_size);
+    }
+
+    pub(super) fn resource(
+        &self,
+        layer: usize,
+        extent: vk::Extent2D,
+    ) -> vk::VideoPictureResourceInfoKHR {
+        vk::VideoPictureResourceInfoKHR {
+            coded_extent: extent,
+            base_array_layer: layer as u32,
+            image_view_binding: self.view,
+            ..Default::default()
+        }
+    }
+}
+
+/// Host visible memory slices are written to or read back from
+pub(super) struct Bitstream {
+    pub(super) buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    pub(super) size: vk::DeviceSize,
+    pub(super) mapped: *mut u8,
+}
+
+impl Bitstream {
+    pub(super) const NULL: Bitstream = Bitstream {
+        buffer: vk::Buffer::null(),
+        memory: vk::DeviceMemory::null(),
+        size: 0,
+        mapped: ptr::null_mut(),
+    };
+
+    /// Cached memory is preferred for `VIDEO_ENCODE_DST` buffers, which the host reads
+    #[track_caller]
+    pub(super) unsafe fn new(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        profiles: &vk::VideoProfileListInfoKHR,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Self, VideoError> {
+        let mut profile_list = *profiles;
+        let info = vk::BufferCreateInfo::builder()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .push_next(&mut profile_list);
+        let buffer = device.create_buffer(&info, None)?;
+        let requirements = device.get_buffer_memory_requirements(buffer);
+        let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let cached = usage
+            .contains(vk::BufferUsageFlags::VIDEO_ENCODE_DST_KHR)
+            .then(|| {
+                find_memory_type(
+                    mem_props,
+                    requirements.memory_type_bits,
+                    host | vk::MemoryPropertyFlags::HOST_CACHED,
+                )
+            })
+            .flatten();
+        let Some(type_index) =
+            cached.or_else(|| find_memory_type(mem_props, requirements.memory_type_bits, host))
+        else {
+            device.destroy_buffer(buffer, None);
+            return Err(VideoError::NoMemoryType);
+        };
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirements.size)
+            .memory_type_index(type_index);
+        let memory = device
+            .allocate_memory(&alloc_info, None)
+            .and_then(|memory| {
+                device
+                    .bind_buffer_memory(buffer, memory, 0)
+                    .and_then(|_| device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty()))
+                    .map(|mapped| (memory, mapped))
+                    .inspect_err(|_| device.free_memory(memory, None))
+            });
+        let (memory, mapped) = match memory {
+            Ok(memory) => memory,
+            Err(err) => {
+                device.destroy_buffer(buffer, None);
+                return Err(err.into());
+            }
+        };
+        usage::record_allocation(MemoryCategory::Staging, size);
+        leaks::track(buffer, "video bitstream buffer");
+        leaks::track(memory, "video bitstream memory");
+        Ok(Bitstream {
+            buffer,
+            memory,
+            size,
+            mapped: mapped.cast(),
+        })
+    }
+
+    /// Does nothing for [`Bitstream::NULL`]
+    pub(super) unsafe fn destroy(&self, device: &Device) {
+        if self.buffer == vk::Buffer::null() {
+            return;
+        }
+        leaks::untrack(self.buffer);
+        leaks::untrack(self.memory);
+        device.destroy_buffer(self.buffer, None);
+        device.free_memory(self.memory, None);
+        usage::release_allocation(MemoryCategory::Staging, self.size);
+    }
+}
+
+/// Whether [`PICTURE_FORMAT`] pictures with `usage` work with the profiles in `profiles`
+pub(super) fn supports_format(
+    fns: &vk::KhrVideoQueueFn,
+    physical_device: vk::PhysicalDevice,
+    profiles: &vk::VideoProfileListInfoKHR,
+    usage: vk::ImageUsageFlags,
+) -> Result<bool, VideoError> {
+    let info = vk::PhysicalDeviceVideoFormatInfoKHR {
+        p_next: profiles as *const _ as *const c_void,
+        image_usage: usage,
+        ..Default::default()
+    };
+    let query = fns.get_physical_device_video_format_properties_khr;
+    let mut count = 0;
+    unsafe { query(physical_device, &info, &mut count, ptr::null_mut()).result()? };
+    let mut formats = vec![vk::VideoFormatPropertiesKHR::default(); count as usize];
+    unsafe { query(physical_device, &info, &mut count, formats.as_mut_ptr()).result()? };
+    Ok(formats
+        .iter()
+        .take(count as usize)
+        .any(|props| props.format == PICTURE_FORMAT))
+}
+
+/// Allocates and binds what `session` asks for, pushing each allocation to `memory`
+///
+/// Allocations made before a failure are still in `memory` for the caller to free.
+#[track_caller]
+pub(super) unsafe fn bind_session_memory(
+    fns: &vk::KhrVideoQueueFn,
+    device: &Device,
+    mem_props: &vk::PhysicalDeviceMemoryProperties,
+    session: vk::VideoSessionKHR,
+    memory: &mut Vec<vk::DeviceMemory>,
+) -> Result<(), VideoError> {
+    let query = fns.get_video_session_memory_requirements_khr;
+    let mut count = 0;
+    query(device.handle(), session, &mut count, ptr::null_mut()).result()?;
+    let mut requirements = vec![vk::VideoSessionMemoryRequirementsKHR::default(); count as usize];
+    query(
+        device.handle(),
+        session,
+        &mut count,
+        requirements.as_mut_ptr(),
+    )
+    .result()?;
+
+    let mut binds = Vec::with_capacity(requirements.len());
+    for requirement in &requirements {
+        let bits = requirement.memory_requirements.memory_type_bits;
+        let type_index = find_memory_type(mem_props, bits, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            .or_else(|| find_memory_type(mem_props, bits, vk::MemoryPropertyFlags::empty()))
+            .ok_or(VideoError::NoMemoryType)?;
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(requirement.memory_requirements.size)
+            .memory_type_index(type_index);
+        let allocation = device.allocate_memory(&alloc_info, None)?;
+        leaks::track(allocation, "video session memory");
+        memory.push(allocation);
+        binds.push(vk::BindVideoSessionMemoryInfoKHR {
+            memory_bind_index: requirement.memory_bind_index,
+            memory: allocation,
+            memory_offset: 0,
+            memory_size: requirement.memory_requirements.size,
+            ..Default::default()
+        });
+    }
+    traced!(
+        "vkBindVideoSessionMemoryKHR",
+        session = session,
+        binds = binds.len();
+        (fns.bind_video_session_memory_khr)(
+            device.handle(),
+            session,
+            binds.len() as u32,
+            binds.as_ptr(),
+        )
+        .result()
+    )?;
+    Ok(())
+}
+
+pub(super) fn align(size: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    size.div_ceil(alignment) * alignment
+}
@@ -0,0 +1,72 @@
+//! Polling files for changes, to reload them while running
+//!
+//! Modification times are compared at most once per interval, which is cheap enough to do from the
+//! frame loop for a handful of files and needs no platform notification APIs. Editors that save by
+//! replacing the file are caught too, as are files that appear or go away.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Debug, Clone)]
+pub struct FileWatcher {
+    /// Last seen modification time, `None` while the file doesn't exist
+    files: HashMap<PathBuf, Option<SystemTime>>,
+    interval: Duration,
+    last_poll: Option<Instant>,
+}
+
+impl FileWatcher {
+    /// Checks every `interval` at most
+    pub fn new(interval: Duration) -> Self {
+        FileWatcher {
+            files: HashMap::new(),
+            interval,
+            last_poll: None,
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    /// Starts watching `path` from its current state, which need not exist yet
+    pub fn watch(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        let modified = Self::modified(&path);
+        self.files.insert(path, modified);
+    }
+
+    pub fn unwatch(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    pub fn is_watched(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Files changed since they were last seen, empty until the interval has passed
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        if self
+            .last_poll
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return Vec::new();
+        }
+        self.last_poll = Some(now);
+        self.files
+            .iter_mut()
+            .filter_map(|(path, seen)| {
+                let modified = Self::modified(path);
+                (modified != *seen).then(|| {
+                    *seen = modified;
+                    path.clone()
+                })
+            })
+            .collect()
+    }
+}
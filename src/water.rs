@@ -0,0 +1,708 @@
+//! A water plane reflecting a mirrored render of the scene and refracting what's under it
+//!
+//! The scene is drawn a second time into the water's own reflection target, seen through
+//! [`Water::reflection_view`] with everything under the surface clipped by
+//! [`Water::reflection_projection`]. The water itself is drawn after the opaque pass, sampling
+//! copies of the scene's color and depth for refraction, and fading into the shore where the
+//! water gets shallow. Two scrolling samples of a normal map ripple both.
+
+use std::error::Error;
+
+use ash::{prelude::VkResult, vk, Device};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use thiserror::Error;
+
+use crate::{
+    assets::GpuAsset,
+    layout::AsBytes,
+    leaks,
+    scene::Camera,
+    shader::{reflect, ShaderCompiler},
+    texture::{
+        hdr::HDR_FORMAT,
+        sampler::{SamplerCache, SamplerDesc, SamplerFilter},
+        Texture,
+    },
+};
+
+pub const WATER_VERT_GLSL: &str = r#"#version 450
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    vec4 camera;
+    // xy: centre on the xz plane, z: height, w: half size
+    vec4 plane;
+    vec4 params;
+    vec4 color;
+} push;
+
+layout(location = 0) out vec3 out_world;
+
+void main() {
+    vec2 corner = vec2(float(gl_VertexIndex & 1), float(gl_VertexIndex >> 1)) * 2.0 - 1.0;
+    vec3 world = vec3(push.plane.x, push.plane.z, push.plane.y)
+        + vec3(corner.x, 0.0, corner.y) * push.plane.w;
+    gl_Position = push.view_proj * vec4(world, 1.0);
+    out_world = world;
+}
+"#;
+
+pub const WATER_FRAG_GLSL: &str = r#"#version 450
+layout(set = 0, binding = 0) uniform texture2D reflection;
+layout(set = 0, binding = 1) uniform texture2D scene_color;
+layout(set = 0, binding = 2) uniform texture2D scene_depth;
+layout(set = 0, binding = 3) uniform texture2D normal_map;
+layout(set = 0, binding = 4) uniform sampler water_sampler;
+
+layout(push_constant) uniform Push {
+    mat4 view_proj;
+    // xyz: camera position, w: how far the waves have scrolled
+    vec4 camera;
+    vec4 plane;
+    // x: near plane, y: far plane, z: wave tiling, w: distortion
+    vec4 params;
+    // rgb: color of deep water, w: depth it becomes opaque at
+    vec4 color;
+} push;
+
+layout(location = 0) in vec3 world;
+
+layout(location = 0) out vec4 out_color;
+
+float linear_depth(float depth) {
+    float near = push.params.x;
+    float far = push.params.y;
+    return near * far / (far - depth * (far - near));
+}
+
+void main() {
+    vec2 screen = gl_FragCoord.xy / vec2(textureSize(sampler2D(scene_color, water_sampler), 0));
+    vec2 uv = world.xz * push.params.z;
+    float scroll = push.camera.w;
+    vec3 first = texture(sampler2D(normal_map, water_sampler), uv + vec2(scroll, scroll * 0.5)).xyz;
+    vec3 second = texture(sampler2D(normal_map, water_sampler), uv * 1.7 - vec2(scroll * 0.6, scroll)).xyz;
+    vec3 tangent = first + second - 1.0;
+    vec3 normal = normalize(vec3(tangent.x, tangent.z, tangent.y));
+    vec2 offset = tangent.xy * push.params.w;
+
+    float surface = linear_depth(gl_FragCoord.z);
+    vec2 refracted = clamp(screen + offset, vec2(0.0), vec2(1.0));
+    // Don't pull in things standing in front of the water
+    if (linear_depth(texture(sampler2D(scene_depth, water_sampler), refracted).r) < surface) {
+        refracted = screen;
+    }
+    float thickness = max(linear_depth(texture(sampler2D(scene_depth, water_sampler), refracted).r) - surface, 0.0);
+    vec3 below = texture(sampler2D(scene_color, water_sampler), refracted).rgb;
+    vec3 water = mix(below, push.color.rgb, clamp(thickness / push.color.w, 0.0, 1.0));
+
+    vec2 reflected = clamp(screen + offset, vec2(0.0), vec2(1.0));
+    vec3 above = texture(sampler2D(reflection, water_sampler), reflected).rgb;
+    vec3 view = normalize(push.camera.xyz - world);
+    float fresnel = 0.02 + 0.98 * pow(1.0 - max(dot(normal, view), 0.0), 5.0);
+    vec3 color = mix(water, above, fresnel);
+
+    float shore = smoothstep(0.0, 0.5, thickness);
+    out_color = vec4(mix(below, color, shore), 1.0);
+}
+"#;
+
+const REFLECTION_DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+
+#[derive(Debug, Error)]
+pub enum WaterError {
+    #[error("failed to compile water shader: {0}")]
+    Compile(Box<dyn Error + Send + Sync>),
+    #[error(transparent)]
+    Vulkan(#[from] vk::Result),
+    #[error("pipeline layout doesn't match its shaders: {0}")]
+    Layout(#[from] reflect::ReflectError),
+}
+
+/// Images the water samples besides its reflection, all in `SHADER_READ_ONLY_OPTIMAL`
+#[derive(Debug, Clone, Copy)]
+pub struct WaterInputs {
+    /// Copy of the scene's color from before the water is drawn
+    pub scene_color: vk::ImageView,
+    /// Copy of the scene's depth, through a depth aspect view
+    pub scene_depth: vk::ImageView,
+    /// Tangent space ripples, tiled across the surface
+    pub normal_map: vk::ImageView,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WaterConfig {
+    pub render_pass: vk::RenderPass,
+    pub subpass: u32,
+    /// Usually the swapchain's or a fraction of it
+    pub reflection_extent: vk::Extent2D,
+}
+
+impl WaterConfig {
+    pub fn new(render_pass: vk::RenderPass, reflection_extent: vk::Extent2D) -> Self {
+        WaterConfig {
+            render_pass,
+            subpass: 0,
+            reflection_extent,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, AsBytes)]
+struct WaterPush {
+    view_proj: Mat4,
+    camera: Vec4,
+    plane: Vec4,
+    params: Vec4,
+    color: Vec4,
+}
+
+pub struct Water {
+    /// Height of the surface
+    pub height: f32,
+    /// Middle of the square on the xz plane
+    pub center: Vec2,
+    pub half_size: f32,
+    pub deep_color: Vec3,
+    /// Depth of water that fully hides what's under it
+    pub opaque_depth: f32,
+    /// Normal map repeats per world unit
+    pub wave_tiling: f32,
+    /// Normal map widths scrolled per second
+    pub wave_speed: f32,
+    /// How far ripples bend the reflection and refraction, in screen fractions
+    pub distortion: f32,
+    time: f32,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    /// Owned by the [`SamplerCache`]
+    sampler: vk::Sampler,
+    reflection_pass: vk::RenderPass,
+    reflection: Option<Texture>,
+    reflection_depth: Option<Texture>,
+    framebuffer: vk::Framebuffer,
+    reflection_extent: vk::Extent2D,
+}
+
+impl Water {
+    pub fn new<C: ShaderCompiler>(
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        compiler: &C,
+        samplers: &mut SamplerCache,
+        inputs: WaterInputs,
+        config: WaterConfig,
+    ) -> Result<Self, WaterError> {
+        let compile = |source, stage| {
+            compiler
+                .compile(source, stage)
+                .map_err(|err| WaterError::Compile(Box::new(err)))
+        };
+        let vert = compile(WATER_VERT_GLSL, vk::ShaderStageFlags::VERTEX)?;
+        let frag = compile(WATER_FRAG_GLSL, vk::ShaderStageFlags::FRAGMENT)?;
+        // Repeats for the normal map, screen lookups are clamped in the shader
+        let sampler = samplers.get(
+            device,
+            &SamplerDesc::default().with_filter(SamplerFilter::Linear),
+        )?;
+
+        let mut this = Water {
+            height: 0.0,
+            center: Vec2::ZERO,
+            half_size: 100.0,
+            deep_color: Vec3::new(0.02, 0.12, 0.15),
+            opaque_depth: 4.0,
+            wave_tiling: 0.1,
+            wave_speed: 0.03,
+            distortion: 0.02,
+            time: 0.0,
+            set_layout: vk::DescriptorSetLayout::null(),
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipeline: vk::Pipeline::null(),
+            sampler,
+            reflection_pass: vk::RenderPass::null(),
+            reflection: None,
+            reflection_depth: None,
+            framebuffer: vk::Framebuffer::null(),
+            reflection_extent: config.reflection_extent,
+        };
+        let result = unsafe {
+            this.create_objects(device, &vert, &frag, config)
+                .and_then(|_| Ok(this.create_reflection(device, mem_props)?))
+                .map(|_| this.set_inputs(device, inputs))
+        };
+        match result {
+            Ok(()) => Ok(this),
+            Err(err) => {
+                unsafe { this.destroy(device) };
+                Err(err)
+            }
+        }
+    }
+
+    unsafe fn create_objects(
+        &mut self,
+        device: &Device,
+        vert: &[u32],
+        frag: &[u32],
+        config: WaterConfig,
+    ) -> Result<(), WaterError> {
+        let types = [
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::SAMPLER,
+        ];
+        let bindings: Vec<_> = (0..)
+            .zip(types)
+            .map(|(binding, ty)| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(binding)
+                    .descriptor_type(ty)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                    .build()
+            })
+            .collect();
+        self.set_layout = device.create_descriptor_set_layout(
+            &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+            None,
+        )?;
+        leaks::track(self.set_layout, "water descriptor set layout");
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 4,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+        ];
+        self.pool = device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .max_sets(1)
+                .pool_sizes(&pool_sizes),
+            None,
+        )?;
+        leaks::track(self.pool, "water descriptor pool");
+        self.set = device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.pool)
+                .set_layouts(&[self.set_layout]),
+        )?[0];
+        let push_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            offset: 0,
+            size: std::mem::size_of::<WaterPush>() as u32,
+        };
+        self.pipeline_layout = crate::pipeline::create_pipeline_layout(
+            device,
+            &vk::PipelineLayoutCreateInfo::builder()
+                .set_layouts(&[self.set_layout])
+                .push_constant_ranges(&[push_range]),
+        )?;
+        leaks::track(self.pipeline_layout, "water pipeline layout");
+
+        let attachments = [
+            vk::AttachmentDescription::builder()
+                .format(HDR_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .build(),
+            vk::AttachmentDescription::builder()
+                .format(REFLECTION_DEPTH_FORMAT)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .build(),
+        ];
+        let color_refs = [vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        }];
+        let depth_ref = vk::AttachmentReference {
+            attachment: 1,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        let subpasses = [vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_refs)
+            .depth_stencil_attachment(&depth_ref)
+            .build()];
+        // Last frame's water has to be done sampling before the reflection is cleared
+        let dependencies = [
+            vk::SubpassDependency {
+                src_subpass: vk::SUBPASS_EXTERNAL,
+                dst_subpass: 0,
+                src_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+                src_access_mask: vk::AccessFlags::empty(),
+                dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+            vk::SubpassDependency {
+                src_subpass: 0,
+                dst_subpass: vk::SUBPASS_EXTERNAL,
+                src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                dst_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                src_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                dst_access_mask: vk::AccessFlags::SHADER_READ,
+                dependency_flags: vk::DependencyFlags::empty(),
+            },
+        ];
+        self.reflection_pass = device.create_render_pass(
+            &vk::RenderPassCreateInfo::builder()
+                .attachments(&attachments)
+                .subpasses(&subpasses)
+                .dependencies(&dependencies),
+            None,
+        )?;
+
+        reflect::debug_validate(&[vert, frag], &[&bindings], &[push_range], &[])?;
+        let vert =
+            device.create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(vert), None)?;
+        leaks::track(vert, "water shader module");
+        let frag = match device
+            .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(frag), None)
+        {
+            Ok(frag) => frag,
+            Err(err) => {
+                leaks::untrack(vert);
+                device.destroy_shader_module(vert, None);
+                return Err(err.into());
+            }
+        };
+        leaks::track(frag, "water shader module");
+        let stages = [
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(vert)
+                .name(c"main")
+                .build(),
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(vk::ShaderStageFlags::FRAGMENT)
+                .module(frag)
+                .name(c"main")
+                .build(),
+        ];
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(vk::PrimitiveTopology::TRIANGLE_STRIP);
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(vk::PolygonMode::FILL)
+            .cull_mode(vk::CullModeFlags::NONE)
+            .line_width(1.0);
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(true)
+            .depth_write_enable(true)
+            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+        let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::RGBA)
+            .build()];
+        let blend =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&blend)
+            .dynamic_state(&dynamic)
+            .layout(self.pipeline_layout)
+            .render_pass(config.render_pass)
+            .subpass(config.subpass);
+        let pipelines = crate::pipeline::create_graphics_pipelines(device, &[*info], "water");
+        leaks::untrack(vert);
+        device.destroy_shader_module(vert, None);
+        leaks::untrack(frag);
+        device.destroy_shader_module(frag, None);
+        self.pipeline = pipelines.map_err(|(_, err)| err)?[0];
+        Ok(())
+    }
+
+    unsafe fn create_reflection(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+    ) -> VkResult<()> {
+        let color = Texture::empty(
+            device,
+            mem_props,
+            HDR_FORMAT,
+            self.reflection_extent,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        )?;
+        let color_view = color.view;
+        self.reflection = Some(color);
+        let depth = Texture::empty(
+            device,
+            mem_props,
+            REFLECTION_DEPTH_FORMAT,
+            self.reflection_extent,
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        )?;
+        let depth_view = depth.view;
+        self.reflection_depth = Some(depth);
+        self.framebuffer = device.create_framebuffer(
+            &vk::FramebufferCreateInfo::builder()
+                .render_pass(self.reflection_pass)
+                .attachments(&[color_view, depth_view])
+                .width(self.reflection_extent.width)
+                .height(self.reflection_extent.height)
+                .layers(1),
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Points the water at new scene copies, e.g. after a resize
+    ///
+    /// # Safety
+    ///
+    /// No recorded draw of the water may still be executing.
+    pub unsafe fn set_inputs(&self, device: &Device, inputs: WaterInputs) {
+        let Some(reflection) = &self.reflection else {
+            return;
+        };
+        let image = |image_view| {
+            [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }]
+        };
+        let images = [
+            image(reflection.view),
+            image(inputs.scene_color),
+            image(inputs.scene_depth),
+            image(inputs.normal_map),
+        ];
+        let sampler = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: vk::ImageView::null(),
+            image_layout: vk::ImageLayout::UNDEFINED,
+        }];
+        let mut writes: Vec<_> = (0..)
+            .zip(&images)
+            .map(|(binding, info)| {
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(info)
+                    .build()
+            })
+            .collect();
+        writes.push(
+            vk::WriteDescriptorSet::builder()
+                .dst_set(self.set)
+                .dst_binding(4)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler)
+                .build(),
+        );
+        device.update_descriptor_sets(&writes, &[]);
+    }
+
+    /// Recreates the reflection target at `extent` and takes the resized scene copies
+    ///
+    /// # Safety
+    ///
+    /// No recorded reflection pass or draw of the water may still be executing.
+    pub unsafe fn resize(
+        &mut self,
+        device: &Device,
+        mem_props: &vk::PhysicalDeviceMemoryProperties,
+        extent: vk::Extent2D,
+        inputs: WaterInputs,
+    ) -> VkResult<()> {
+        self.destroy_reflection(device);
+        self.reflection_extent = extent;
+        self.create_reflection(device, mem_props)?;
+        self.set_inputs(device, inputs);
+        Ok(())
+    }
+
+    /// Render pass the scene's pipelines for the reflection have to be compatible with
+    pub fn reflection_render_pass(&self) -> vk::RenderPass {
+        self.reflection_pass
+    }
+
+    pub fn reflection_extent(&self) -> vk::Extent2D {
+        self.reflection_extent
+    }
+
+    /// Advances the ripples by `dt` seconds
+    pub fn update(&mut self, dt: f32) {
+        self.time += dt;
+    }
+
+    /// `view` mirrored in the surface, which flips the winding of everything drawn with it
+    pub fn reflection_view(&self, view: Mat4) -> Mat4 {
+        let mirror = Mat4::from_translation(Vec3::Y * self.height)
+            * Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0))
+            * Mat4::from_translation(Vec3::NEG_Y * self.height);
+        view * mirror
+    }
+
+    /// `proj` with its near plane on the surface, so nothing under the water gets reflected
+    pub fn reflection_projection(&self, reflection_view: Mat4, proj: Mat4) -> Mat4 {
+        let plane = Vec4::new(0.0, 1.0, 0.0, -self.height);
+        oblique_projection(proj, reflection_view.inverse().transpose() * plane)
+    }
+
+    /// Begins the reflection pass with viewport and scissor covering the target
+    ///
+    /// Draw the scene with [`Water::reflection_view`] and [`Water::reflection_projection`], then
+    /// end the render pass before the water is drawn.
+    pub fn begin_reflection(&self, device: &Device, cmd: vk::CommandBuffer, clear: Vec4) {
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: clear.to_array(),
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        ];
+        let area = vk::Rect2D {
+            offset: vk::Offset2D::default(),
+            extent: self.reflection_extent,
+        };
+        unsafe {
+            device.cmd_begin_render_pass(
+                cmd,
+                &vk::RenderPassBeginInfo::builder()
+                    .render_pass(self.reflection_pass)
+                    .framebuffer(self.framebuffer)
+                    .render_area(area)
+                    .clear_values(&clear_values),
+                vk::SubpassContents::INLINE,
+            );
+            device.cmd_set_viewport(
+                cmd,
+                0,
+                &[vk::Viewport {
+                    x: 0.0,
+                    y: 0.0,
+                    width: self.reflection_extent.width as f32,
+                    height: self.reflection_extent.height as f32,
+                    min_depth: 0.0,
+                    max_depth: 1.0,
+                }],
+            );
+            device.cmd_set_scissor(cmd, 0, &[area]);
+        }
+    }
+
+    /// Records the surface as seen by `camera` through `view`
+    ///
+    /// Viewport and scissor are dynamic and left to the caller.
+    pub fn draw(
+        &self,
+        device: &Device,
+        cmd: vk::CommandBuffer,
+        camera: &Camera,
+        view: Mat4,
+        aspect: f32,
+    ) {
+        let push = WaterPush {
+            view_proj: camera.projection(aspect) * view,
+            camera: view
+                .inverse()
+                .transform_point3(Vec3::ZERO)
+                .extend((self.time * self.wave_speed).fract()),
+            plane: Vec4::new(self.center.x, self.center.y, self.height, self.half_size),
+            params: Vec4::new(camera.near, camera.far, self.wave_tiling, self.distortion),
+            color: self.deep_color.extend(self.opaque_depth),
+        };
+        unsafe {
+            device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(
+                cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.set],
+                &[],
+            );
+            device.cmd_push_constants(
+                cmd,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                0,
+                push.as_bytes(),
+            );
+            device.cmd_draw(cmd, 4, 1, 0, 0);
+        }
+    }
+
+    unsafe fn destroy_reflection(&mut self, device: &Device) {
+        device.destroy_framebuffer(self.framebuffer, None);
+        self.framebuffer = vk::Framebuffer::null();
+        for texture in [&mut self.reflection, &mut self.reflection_depth] {
+            if let Some(texture) = texture.take() {
+                texture.destroy(device);
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No recorded reflection pass or draw of the water may still be executing.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.destroy_reflection(device);
+        device.destroy_render_pass(self.reflection_pass, None);
+        leaks::untrack(self.pipeline);
+        device.destroy_pipeline(self.pipeline, None);
+        leaks::untrack(self.pipeline_layout);
+        device.destroy_pipeline_layout(self.pipeline_layout, None);
+        leaks::untrack(self.pool);
+        device.destroy_descriptor_pool(self.pool, None);
+        leaks::untrack(self.set_layout);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// `proj` with the near plane replaced by `plane`, given in view space
+///
+/// Keeps depth precision, unlike a user clip plane it needs no shader support. The camera has to
+/// be on the plane's negative side.
+pub fn oblique_projection(proj: Mat4, plane: Vec4) -> Mat4 {
+    let clip = proj.inverse().transpose() * plane;
+    let corner = proj.inverse() * Vec4::new(clip.x.signum(), clip.y.signum(), 1.0, 1.0);
+    let row = plane / plane.dot(corner);
+    let mut oblique = proj;
+    oblique.x_axis.z = row.x;
+    oblique.y_axis.z = row.y;
+    oblique.z_axis.z = row.z;
+    oblique.w_axis.z = row.w;
+    oblique
+}
@@ -0,0 +1,260 @@
+//! Renders a triangle into a 64×64 offscreen target, reads it back and checks pixels
+//!
+//! The smoke test for the whole stack: instance and device creation, shader compilation,
+//! pipeline creation, render targets, command submission and readback. Skipped without a Vulkan
+//! device unless `VULKAN_THING_REQUIRE_DEVICE` is set.
+//!
+//! The shaders are shipped compiled so the test runs without the `naga` feature. With it,
+//! `shipped_spirv_is_current` checks they still match their GLSL in `tests/shaders`.
+
+use std::io::Cursor;
+
+use ash::{util::read_spv, vk, Device};
+use glam::Vec4;
+use vulkan_thing::{
+    leaks::{self, LeakReport},
+    memory::Buffer,
+    pipeline::{create_graphics_pipelines, dynamic::RasterState},
+    target::{RenderTarget, RenderTargetDesc},
+    test_support::HeadlessContext,
+};
+
+const SIZE: u32 = 64;
+const FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const CLEAR: [u8; 4] = [0, 0, 255, 255];
+const TRIANGLE: [u8; 4] = [255, 0, 0, 255];
+
+/// Apex at y = 8, the base from x = 8 to 56 at y = 56, in pixels
+const VERTEX_SPV: &[u8] = include_bytes!("shaders/triangle.vert.spv");
+const FRAGMENT_SPV: &[u8] = include_bytes!("shaders/triangle.frag.spv");
+
+unsafe fn create_pipeline(
+    device: &Device,
+    render_pass: vk::RenderPass,
+    layout: vk::PipelineLayout,
+) -> vk::Pipeline {
+    let vert = read_spv(&mut Cursor::new(VERTEX_SPV)).expect("vertex shader is SPIR-V");
+    let frag = read_spv(&mut Cursor::new(FRAGMENT_SPV)).expect("fragment shader is SPIR-V");
+    let vert = device
+        .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&vert), None)
+        .unwrap();
+    let frag = device
+        .create_shader_module(&vk::ShaderModuleCreateInfo::builder().code(&frag), None)
+        .unwrap();
+    let stages = [
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(vert)
+            .name(c"main")
+            .build(),
+        vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::FRAGMENT)
+            .module(frag)
+            .name(c"main")
+            .build(),
+    ];
+    let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder();
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+        .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+    let viewport = vk::PipelineViewportStateCreateInfo::builder()
+        .viewport_count(1)
+        .scissor_count(1);
+    let raster = RasterState {
+        cull_mode: vk::CullModeFlags::NONE,
+        depth_test: false,
+        depth_write: false,
+        ..RasterState::default()
+    };
+    let rasterization = raster.rasterization();
+    let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+        .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+    let blend_attachments = [vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(vk::ColorComponentFlags::RGBA)
+        .build()];
+    let blend = vk::PipelineColorBlendStateCreateInfo::builder().attachments(&blend_attachments);
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
+    let info = vk::GraphicsPipelineCreateInfo::builder()
+        .stages(&stages)
+        .vertex_input_state(&vertex_input)
+        .input_assembly_state(&input_assembly)
+        .viewport_state(&viewport)
+        .rasterization_state(&rasterization)
+        .multisample_state(&multisample)
+        .color_blend_state(&blend)
+        .dynamic_state(&dynamic)
+        .layout(layout)
+        .render_pass(render_pass)
+        .subpass(0);
+    let pipelines = create_graphics_pipelines(device, &[*info], "offscreen triangle");
+    device.destroy_shader_module(vert, None);
+    device.destroy_shader_module(frag, None);
+    pipelines.map_err(|(_, err)| err).unwrap()[0]
+}
+
+#[test]
+fn triangle_pixels() {
+    let Some(ctx) = HeadlessContext::for_test() else {
+        return;
+    };
+    let device = &ctx.device;
+    let extent = vk::Extent2D {
+        width: SIZE,
+        height: SIZE,
+    };
+    let desc = RenderTargetDesc::new(FORMAT, extent)
+        .with_color_usage(vk::ImageUsageFlags::TRANSFER_SRC)
+        .with_color_final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+    let mut target = RenderTarget::new(device, &ctx.mem_props, desc).unwrap();
+    let readback = Buffer::new(
+        device,
+        &ctx.mem_props,
+        (SIZE * SIZE * 4) as vk::DeviceSize,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )
+    .unwrap();
+    let layout = unsafe {
+        device
+            .create_pipeline_layout(&vk::PipelineLayoutCreateInfo::default(), None)
+            .unwrap()
+    };
+    let pipeline = unsafe { create_pipeline(device, target.render_pass(), layout) };
+
+    let image = target.color().image;
+    ctx.one_shot(|cmd| unsafe {
+        target.begin(device, cmd, Vec4::new(0.0, 0.0, 1.0, 1.0));
+        device.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::GRAPHICS, pipeline);
+        device.cmd_draw(cmd, 3, 1, 0, 0);
+        device.cmd_end_render_pass(cmd);
+
+        // The pass leaves the image in its final layout, only the writes need waiting on
+        let to_copy = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            });
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[*to_copy],
+        );
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(vk::Extent3D {
+                width: SIZE,
+                height: SIZE,
+                depth: 1,
+            });
+        device.cmd_copy_image_to_buffer(
+            cmd,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            readback.buffer,
+            &[*region],
+        );
+        let to_host = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ);
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::HOST,
+            vk::DependencyFlags::empty(),
+            &[*to_host],
+            &[],
+            &[],
+        );
+    })
+    .unwrap();
+
+    let mapped = readback.mapped().expect("readback buffer is host visible");
+    let pixels = unsafe {
+        std::slice::from_raw_parts(mapped.as_ptr().cast::<u8>(), (SIZE * SIZE * 4) as usize)
+    };
+    let pixel = |x: u32, y: u32| {
+        let offset = ((y * SIZE + x) * 4) as usize;
+        <[u8; 4]>::try_from(&pixels[offset..offset + 4]).unwrap()
+    };
+    let name = ctx.device_name();
+    for (x, y) in [(32, 32), (32, 48), (16, 52), (48, 52)] {
+        assert_eq!(
+            pixel(x, y),
+            TRIANGLE,
+            "({x}, {y}) inside the triangle on {name}"
+        );
+    }
+    for (x, y) in [
+        (0, 0),
+        (63, 0),
+        (0, 63),
+        (63, 63),
+        (32, 2),
+        (8, 32),
+        (56, 32),
+    ] {
+        assert_eq!(
+            pixel(x, y),
+            CLEAR,
+            "({x}, {y}) outside the triangle on {name}"
+        );
+    }
+
+    unsafe {
+        leaks::untrack(pipeline);
+        device.destroy_pipeline(pipeline, None);
+        device.destroy_pipeline_layout(layout, None);
+        readback.destroy(device);
+        target.destroy(device);
+    }
+    LeakReport::capture_thread().assert_empty();
+}
+
+#[cfg(feature = "naga")]
+#[test]
+fn shipped_spirv_is_current() {
+    use vulkan_thing::shader::{
+        runtime::{NagaCompiler, SourceLanguage},
+        ShaderCompiler,
+    };
+
+    let compiler = NagaCompiler::new(SourceLanguage::Glsl);
+    for (source, stage, shipped) in [
+        (
+            include_str!("shaders/triangle.vert"),
+            vk::ShaderStageFlags::VERTEX,
+            VERTEX_SPV,
+        ),
+        (
+            include_str!("shaders/triangle.frag"),
+            vk::ShaderStageFlags::FRAGMENT,
+            FRAGMENT_SPV,
+        ),
+    ] {
+        let compiled = compiler.compile(source, stage).expect("shader compiles");
+        assert_eq!(
+            compiled,
+            read_spv(&mut Cursor::new(shipped)).unwrap(),
+            "{stage:?} SPIR-V is stale, recompile tests/shaders"
+        );
+    }
+}